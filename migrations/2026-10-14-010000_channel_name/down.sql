@@ -0,0 +1,2 @@
+ALTER TABLE recovered_msgs DROP COLUMN channel_name;
+ALTER TABLE pending_msgs DROP COLUMN channel_name;
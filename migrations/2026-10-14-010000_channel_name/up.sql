@@ -0,0 +1,4 @@
+-- Rows with a null channel name were stored before the channel
+-- was recorded, and are considered part of any channel using the database.
+ALTER TABLE pending_msgs ADD COLUMN channel_name varchar(32) null;
+ALTER TABLE recovered_msgs ADD COLUMN channel_name varchar(32) null;
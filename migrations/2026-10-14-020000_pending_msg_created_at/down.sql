@@ -0,0 +1,2 @@
+DROP INDEX pending_msgs_created_at_idx;
+ALTER TABLE pending_msgs DROP COLUMN created_at;
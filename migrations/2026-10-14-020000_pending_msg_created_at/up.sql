@@ -0,0 +1,4 @@
+-- Existing rows are assigned the time of the migration, so that
+-- they are not immediately discarded by the max age check.
+ALTER TABLE pending_msgs ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+CREATE INDEX pending_msgs_created_at_idx ON pending_msgs (created_at);
@@ -0,0 +1 @@
+ALTER TABLE pending_msgs DROP COLUMN encrypted;
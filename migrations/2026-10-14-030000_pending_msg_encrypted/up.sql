@@ -0,0 +1,2 @@
+-- Rows stored before share encryption was enabled are not encrypted.
+ALTER TABLE pending_msgs ADD COLUMN encrypted boolean NOT NULL DEFAULT false;
@@ -0,0 +1,8 @@
+-- Last Kafka offset processed in each partition of the encrypted topic.
+-- Only maintained by the aggregator in lake-first mode.
+CREATE TABLE processed_offsets (
+  channel_name varchar(32) NOT NULL,
+  kafka_partition integer NOT NULL,
+  last_offset bigint NOT NULL,
+  PRIMARY KEY (channel_name, kafka_partition)
+);
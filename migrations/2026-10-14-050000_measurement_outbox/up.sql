@@ -0,0 +1,8 @@
+-- Measurements are stored in the outbox within the transaction that updates
+-- recovered message counts, and are deleted once they are produced to Kafka.
+CREATE TABLE measurement_outbox (
+  id bigserial PRIMARY KEY,
+  channel_name varchar(32) NOT NULL,
+  measurement bytea NOT NULL
+);
+CREATE INDEX measurement_outbox_channel_name_id ON measurement_outbox (channel_name, id);
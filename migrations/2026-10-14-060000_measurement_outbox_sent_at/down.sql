@@ -0,0 +1,3 @@
+DROP INDEX measurement_outbox_sent_at;
+DROP INDEX measurement_outbox_unsent;
+ALTER TABLE measurement_outbox DROP COLUMN sent_at;
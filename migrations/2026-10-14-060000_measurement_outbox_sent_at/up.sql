@@ -0,0 +1,5 @@
+-- Measurements are marked as sent by the outbox relay once they are produced
+-- to Kafka, and are pruned after a retention period.
+ALTER TABLE measurement_outbox ADD COLUMN sent_at timestamptz;
+CREATE INDEX measurement_outbox_unsent ON measurement_outbox (channel_name, id) WHERE sent_at IS NULL;
+CREATE INDEX measurement_outbox_sent_at ON measurement_outbox (sent_at) WHERE sent_at IS NOT NULL;
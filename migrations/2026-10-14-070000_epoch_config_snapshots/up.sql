@@ -0,0 +1,11 @@
+-- Effective aggregator configuration used for each epoch. A row is recorded for
+-- each distinct configuration used to process messages of the epoch, and the
+-- configuration used to finalize the epoch is marked as finalized.
+CREATE TABLE epoch_config_snapshots (
+  channel_name varchar(32) NOT NULL,
+  epoch smallint NOT NULL,
+  config text NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  finalized boolean NOT NULL DEFAULT false,
+  PRIMARY KEY (channel_name, epoch, config)
+);
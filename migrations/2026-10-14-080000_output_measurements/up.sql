@@ -0,0 +1,9 @@
+-- Measurements written by the Postgres output sink. The outbox id is stored so that
+-- measurements produced again after an interrupted flush are not duplicated.
+CREATE TABLE output_measurements (
+  channel_name varchar(32) NOT NULL,
+  outbox_id bigint NOT NULL,
+  measurement text NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (channel_name, outbox_id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE recovered_msgs DROP COLUMN last_submitted_at;
+ALTER TABLE recovered_msgs DROP COLUMN first_submitted_at;
+ALTER TABLE pending_msgs DROP COLUMN last_submitted_at;
+ALTER TABLE pending_msgs DROP COLUMN first_submitted_at;
@@ -0,0 +1,6 @@
+-- Earliest and latest client submission times of the messages that
+-- contributed to each row. Rows created before this migration have no times.
+ALTER TABLE pending_msgs ADD COLUMN first_submitted_at timestamptz;
+ALTER TABLE pending_msgs ADD COLUMN last_submitted_at timestamptz;
+ALTER TABLE recovered_msgs ADD COLUMN first_submitted_at timestamptz;
+ALTER TABLE recovered_msgs ADD COLUMN last_submitted_at timestamptz;
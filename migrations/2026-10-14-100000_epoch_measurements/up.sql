@@ -0,0 +1,9 @@
+-- Measurements retained until their epoch is finalized, so that the complete set
+-- of an epoch's measurements can be exported to the data lake upon finalization.
+CREATE TABLE epoch_measurements (
+  id bigserial PRIMARY KEY,
+  channel_name varchar(32) NOT NULL,
+  epoch smallint NOT NULL,
+  measurement bytea NOT NULL
+);
+CREATE INDEX epoch_measurements_channel_epoch ON epoch_measurements (channel_name, epoch);
@@ -0,0 +1,3 @@
+-- Serialization format of each pending message. Existing rows use
+-- the bincode encoding of submitted messages (format 0).
+ALTER TABLE pending_msgs ADD COLUMN message_format smallint NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Message archive objects that have been fully processed by the aggregator
+-- in lake-first mode, keyed by object checksum, so that an interrupted
+-- replay of the archive resumes after the processed objects.
+CREATE TABLE processed_archive_objects (
+  channel_name varchar(32) NOT NULL,
+  checksum text NOT NULL,
+  object_key text NOT NULL,
+  processed_at timestamp with time zone NOT NULL DEFAULT now(),
+  PRIMARY KEY (channel_name, checksum)
+);
@@ -0,0 +1,8 @@
+-- Keeps the highest offset of each partition number
+DELETE FROM processed_offsets a USING processed_offsets b
+  WHERE a.channel_name = b.channel_name
+    AND a.kafka_partition = b.kafka_partition
+    AND (a.last_offset, a.topic) < (b.last_offset, b.topic);
+ALTER TABLE processed_offsets DROP CONSTRAINT processed_offsets_pkey;
+ALTER TABLE processed_offsets DROP COLUMN topic;
+ALTER TABLE processed_offsets ADD PRIMARY KEY (channel_name, kafka_partition);
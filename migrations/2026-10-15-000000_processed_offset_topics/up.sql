@@ -0,0 +1,6 @@
+-- Partition numbers are only unique within a topic, so offsets are tracked
+-- for each input topic. Existing offsets are kept with an empty topic, which
+-- is assigned to the input topic of channels that consume a single topic.
+ALTER TABLE processed_offsets ADD COLUMN topic text NOT NULL DEFAULT '';
+ALTER TABLE processed_offsets DROP CONSTRAINT processed_offsets_pkey;
+ALTER TABLE processed_offsets ADD PRIMARY KEY (channel_name, topic, kafka_partition);
@@ -51,6 +51,9 @@ struct CliArgs {
 
   #[clap(long, help = "Omit threshold header in requests")]
   omit_threshold_header: bool,
+
+  #[clap(long, help = "Send raw binary messages instead of base64")]
+  binary: bool,
 }
 
 #[derive(Serialize)]
@@ -243,7 +246,13 @@ async fn send_request(client: &reqwest::Client, msg: String, cli_args: &CliArgs)
   if !cli_args.omit_threshold_header {
     builder = builder.header(THRESHOLD_HEADER_NAME, cli_args.threshold);
   }
-  let result = builder.body(msg).send().await.unwrap();
+  builder = match cli_args.binary {
+    true => builder
+      .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+      .body(base64_engine::STANDARD.decode(msg).unwrap()),
+    false => builder.body(msg),
+  };
+  let result = builder.send().await.unwrap();
   assert!(
     result.status().is_success(),
     "status is {}",
@@ -0,0 +1,109 @@
+//! Handling of an unavailable database at startup. The aggregator waits until the
+//! database of the channel is reachable and writable, so that it does not consume
+//! messages that it cannot persist. If the database is still read-only or unreachable
+//! at the end of `AGGREGATOR_STARTUP_TIMEOUT_SECS`, `AGG_DB_UNAVAILABLE_ACTION` decides
+//! the outcome of the run:
+//!
+//! - `exit`: the run fails with `DATABASE_UNAVAILABLE_EXIT_CODE`, so that orchestration
+//!   can retry it later. Nothing is consumed.
+//! - `collect_only`: messages are consumed and grouped as usual, but nothing is persisted
+//!   and consumption is not committed, so the messages will be consumed again by the
+//!   next run. This keeps the consumer group active and reports the pending backlog.
+
+use super::consume::consume_and_group;
+use super::run_summary::RunSummary;
+use super::AggregatorError;
+use crate::record_stream::RecordStreamArc;
+use crate::util::parse_env_var;
+use std::str::FromStr;
+
+const DB_UNAVAILABLE_ACTION_ENV_KEY: &str = "AGG_DB_UNAVAILABLE_ACTION";
+const DEFAULT_DB_UNAVAILABLE_ACTION: &str = "exit";
+/// Exit code of runs that failed because the database was unavailable, and may
+/// be retried. Matches `EX_TEMPFAIL` of sysexits.h.
+pub const DATABASE_UNAVAILABLE_EXIT_CODE: i32 = 75;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatabaseUnavailableAction {
+  Exit,
+  CollectOnly,
+}
+
+impl FromStr for DatabaseUnavailableAction {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "exit" => Ok(Self::Exit),
+      "collect_only" => Ok(Self::CollectOnly),
+      _ => Err(format!("unknown database unavailable action '{}'", s)),
+    }
+  }
+}
+
+impl DatabaseUnavailableAction {
+  pub fn from_env() -> Self {
+    parse_env_var(DB_UNAVAILABLE_ACTION_ENV_KEY, DEFAULT_DB_UNAVAILABLE_ACTION)
+  }
+}
+
+/// Consumes and groups messages for each iteration, without processing them
+/// or committing consumption.
+#[allow(clippy::too_many_arguments)]
+pub async fn collect_only(
+  in_streams: &Vec<RecordStreamArc>,
+  in_stream_topics: &[String],
+  channel_name: &str,
+  target_epoch: Option<u8>,
+  msg_collect_count: usize,
+  iterations: usize,
+  default_k_threshold: usize,
+  summary: &mut RunSummary,
+) -> Result<(), AggregatorError> {
+  summary.collect_only = true;
+  for i in 0..iterations {
+    info!("Starting collect-only iteration {}", i);
+    let (grouped_msgs, count) = consume_and_group(
+      in_streams,
+      in_stream_topics,
+      None,
+      None,
+      channel_name,
+      target_epoch,
+      msg_collect_count,
+      default_k_threshold,
+      None,
+    )
+    .await?;
+    if count == 0 {
+      info!("No messages consumed");
+      break;
+    }
+    summary.consumed_count += count;
+    info!(
+      "Collected {} messages for {} tags, by epoch: {:?}; discarding without committing consumption",
+      count,
+      grouped_msgs.tag_count(),
+      grouped_msgs.epoch_message_counts()
+    );
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn actions() {
+    assert_eq!(
+      "exit".parse::<DatabaseUnavailableAction>().unwrap(),
+      DatabaseUnavailableAction::Exit
+    );
+    assert_eq!(
+      "collect_only".parse::<DatabaseUnavailableAction>().unwrap(),
+      DatabaseUnavailableAction::CollectOnly
+    );
+    assert!("retry".parse::<DatabaseUnavailableAction>().is_err());
+  }
+}
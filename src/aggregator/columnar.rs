@@ -0,0 +1,370 @@
+//! Columnar collection of shares. By default, each consumed message is parsed and added
+//! to the grouped messages as soon as it is received, so the collect phase holds a parsed
+//! message, along with its own allocations, for every consumed record. If `COLUMNAR_COLLECT`
+//! is enabled, only the epoch and tag of each message are read when it is collected,
+//! and the collected shares are stored in Arrow arrays: the epoch, tag, threshold and
+//! submission time of each share. The serialized messages are kept as they were received.
+//!
+//! Once collection is finished, the shares are sorted by epoch and tag, and the messages
+//! are parsed in order to build the grouped messages. Each serialized message is freed
+//! as soon as it is parsed, so the serialized and parsed messages are not all held at once.
+//! Shares of the same tag keep the order in which they were collected, so share retention
+//! limits are applied to the same messages as in the default mode. Messages that are
+//! identical to a previous message of the same tag, i.e. redelivered records or repeated
+//! submissions, are dropped, since duplicate shares prevent key recovery.
+
+use super::group::GroupedMessages;
+use super::AggregatorError;
+use crate::models::{MessageWithThreshold, SubmissionTimeRange};
+use crate::record_stream::{datetime_from_unix_millis, datetime_to_unix_millis};
+use crate::star::parse_message;
+use crate::util::parse_env_var;
+use arrow::array::{
+  Array, ArrayBuilder, BinaryBuilder, Int64Builder, UInt64Array, UInt64Builder, UInt8Builder,
+};
+use arrow::compute::{lexsort_to_indices, SortColumn};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::mem::take;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+const COLUMNAR_COLLECT_ENV_KEY: &str = "COLUMNAR_COLLECT";
+const DEFAULT_COLUMNAR_COLLECT: &str = "false";
+
+/// Share read by a parsing task, with the serialized message.
+pub struct CollectedShare {
+  pub epoch: u8,
+  pub tag: Vec<u8>,
+  pub threshold: usize,
+  pub submitted_at: Option<OffsetDateTime>,
+  pub payload: Vec<u8>,
+}
+
+/// Builders of the share columns, in collection order.
+#[derive(Default)]
+pub struct ColumnarShares {
+  epochs: UInt8Builder,
+  tags: BinaryBuilder,
+  thresholds: UInt64Builder,
+  /// Submission times in Unix milliseconds
+  submitted_at: Int64Builder,
+  /// Serialized messages, which are taken as they are parsed
+  payloads: Vec<Vec<u8>>,
+  payload_size: usize,
+}
+
+impl ColumnarShares {
+  pub fn is_enabled() -> bool {
+    parse_env_var::<bool>(COLUMNAR_COLLECT_ENV_KEY, DEFAULT_COLUMNAR_COLLECT)
+  }
+
+  pub fn append(&mut self, share: CollectedShare) {
+    self.epochs.append_value(share.epoch);
+    self.tags.append_value(&share.tag);
+    self.thresholds.append_value(share.threshold as u64);
+    self
+      .submitted_at
+      .append_option(share.submitted_at.map(datetime_to_unix_millis));
+    self.payload_size += share.payload.len();
+    self.payloads.push(share.payload);
+  }
+
+  pub fn len(&self) -> usize {
+    self.epochs.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Sorts the shares by epoch and tag, and adds their parsed messages to the grouped
+  /// messages, skipping duplicate messages. Returns the amount of skipped duplicates.
+  pub fn group_into(
+    mut self,
+    grouped_msgs: &mut GroupedMessages,
+  ) -> Result<usize, AggregatorError> {
+    let share_count = self.len();
+    let epochs = self.epochs.finish();
+    let tags = self.tags.finish();
+    let thresholds = self.thresholds.finish();
+    let submitted_at = self.submitted_at.finish();
+    debug!(
+      "Collected {} shares in {} bytes of columnar buffers and {} bytes of messages",
+      share_count,
+      epochs.get_array_memory_size()
+        + tags.get_array_memory_size()
+        + thresholds.get_array_memory_size()
+        + submitted_at.get_array_memory_size(),
+      self.payload_size
+    );
+    // The collection order breaks ties, so that the sort is stable
+    let order = UInt64Array::from_iter_values(0..share_count as u64);
+    let indices = lexsort_to_indices(
+      &[
+        SortColumn {
+          values: Arc::new(epochs.clone()),
+          options: None,
+        },
+        SortColumn {
+          values: Arc::new(tags.clone()),
+          options: None,
+        },
+        SortColumn {
+          values: Arc::new(order),
+          options: None,
+        },
+      ],
+      None,
+    )?;
+
+    // Digests of the messages of the current tag. Since shares are sorted by epoch
+    // and tag, the digests are cleared whenever the tag changes.
+    let mut tag_digests = HashSet::new();
+    let mut prev_index = None;
+    let mut duplicate_count = 0;
+    for index in indices.values().iter().map(|v| *v as usize) {
+      let new_tag = match prev_index {
+        Some(prev) => {
+          epochs.value(prev) != epochs.value(index) || tags.value(prev) != tags.value(index)
+        }
+        None => true,
+      };
+      if new_tag {
+        tag_digests.clear();
+      }
+      prev_index = Some(index);
+
+      let payload = take(&mut self.payloads[index]);
+      if !tag_digests.insert(Sha256::digest(&payload)) {
+        duplicate_count += 1;
+        continue;
+      }
+      let msg = parse_message(&payload)?;
+      drop(payload);
+
+      let submitted_at = match submitted_at.is_valid(index) {
+        true => datetime_from_unix_millis(submitted_at.value(index)),
+        false => None,
+      };
+      grouped_msgs.add(
+        MessageWithThreshold {
+          msg,
+          threshold: thresholds.value(index) as usize,
+          submission_range: SubmissionTimeRange::at(submitted_at),
+        },
+        None,
+      );
+    }
+    if duplicate_count > 0 {
+      info!("Skipped {} duplicate collected messages", duplicate_count);
+    }
+    Ok(duplicate_count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::star::parse_message_epoch_tag;
+  use crate::star::tests::generate_test_message;
+  use star_constellation::api::SerializableNestedMessage;
+  use star_constellation::randomness::testing::LocalFetcher;
+
+  fn share(epoch: u8, measurement: &str, submitted_at: Option<i64>) -> CollectedShare {
+    let msg = generate_test_message(
+      epoch,
+      &[measurement.as_bytes().to_vec()],
+      &LocalFetcher::new(),
+    );
+    let payload = bincode::serialize(&SerializableNestedMessage::from(msg)).unwrap();
+    CollectedShare {
+      epoch,
+      tag: parse_message_epoch_tag(&payload).unwrap().1.to_vec(),
+      threshold: 2,
+      submitted_at: submitted_at.and_then(datetime_from_unix_millis),
+      payload,
+    }
+  }
+
+  #[test]
+  fn group_sorted_shares() {
+    let mut shares = ColumnarShares::default();
+    shares.append(share(5, "test|1", Some(1700000000000)));
+    shares.append(share(4, "test|1", None));
+    shares.append(share(5, "test|1", Some(1700000005000)));
+    shares.append(share(5, "test|3", None));
+    assert_eq!(shares.len(), 4);
+
+    let mut grouped_msgs = GroupedMessages::default();
+    shares.group_into(&mut grouped_msgs).unwrap();
+    assert_eq!(grouped_msgs.msg_chunks[&4].len(), 1);
+    assert_eq!(grouped_msgs.msg_chunks[&5].len(), 2);
+    assert_eq!(grouped_msgs.tag_count(), 3);
+    let chunk = grouped_msgs.msg_chunks[&5]
+      .values()
+      .find(|v| v.new_msgs[&2].len() == 2)
+      .unwrap();
+    assert_eq!(
+      chunk.submission_range.earliest,
+      datetime_from_unix_millis(1700000000000)
+    );
+    assert_eq!(
+      chunk.submission_range.latest,
+      datetime_from_unix_millis(1700000005000)
+    );
+  }
+
+  #[test]
+  fn skip_duplicates() {
+    let first_share = share(5, "test|1", Some(1700000000000));
+    let duplicate_share = CollectedShare {
+      submitted_at: datetime_from_unix_millis(1700000005000),
+      payload: first_share.payload.clone(),
+      tag: first_share.tag.clone(),
+      ..first_share
+    };
+    let mut shares = ColumnarShares::default();
+    shares.append(first_share);
+    shares.append(share(5, "test|1", None));
+    shares.append(duplicate_share);
+    shares.append(share(5, "test|3", None));
+
+    let mut grouped_msgs = GroupedMessages::default();
+    assert_eq!(shares.group_into(&mut grouped_msgs).unwrap(), 1);
+    let msg_counts: Vec<usize> = grouped_msgs.msg_chunks[&5]
+      .values()
+      .map(|v| v.new_msgs[&2].len())
+      .collect();
+    assert_eq!(msg_counts.iter().sum::<usize>(), 3);
+    assert!(msg_counts.contains(&2));
+  }
+
+  const PEAK_RSS_MODE_ENV_KEY: &str = "COLUMNAR_PEAK_RSS_MODE";
+
+  /// Returns the peak RSS of the process in kB, since the last reset.
+  fn peak_rss_kb() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+      .lines()
+      .find_map(|line| line.strip_prefix("VmHWM:"))
+      .and_then(|v| v.trim().strip_suffix("kB"))
+      .and_then(|v| v.trim().parse().ok())
+      .unwrap()
+  }
+
+  /// Groups consumed records in the process with the mode of `COLUMNAR_PEAK_RSS_MODE`,
+  /// i.e. `row` or `columnar`, followed by `distinct` or `duplicates`, and prints
+  /// the peak RSS of grouping. Run by `peak_rss`.
+  #[test]
+  #[ignore]
+  fn peak_rss_mode() {
+    const RECORD_COUNT: usize = 200_000;
+    const TAG_COUNT: usize = 10_000;
+    let Ok(mode) = std::env::var(PEAK_RSS_MODE_ENV_KEY) else {
+      return;
+    };
+    let (collect_mode, records_mode) = mode.split_once('-').unwrap();
+    let fetcher = LocalFetcher::new();
+    let tag_payloads: Vec<Vec<u8>> = (0..TAG_COUNT)
+      .map(|i| {
+        let msg = generate_test_message(
+          5,
+          &[format!("test|{}", i).into_bytes(), b"nested|1".to_vec()],
+          &fetcher,
+        );
+        bincode::serialize(&SerializableNestedMessage::from(msg)).unwrap()
+      })
+      .collect();
+    // Each consumed record is received in its own buffer. Distinct records are derived
+    // by writing the record index to the ciphertext of the unencrypted layer, which
+    // follows the epoch and the ciphertext length.
+    let record_payload = |i: usize| {
+      let id = match records_mode {
+        "distinct" => i,
+        _ => i / 2,
+      };
+      let mut payload = tag_payloads[id % TAG_COUNT].clone();
+      payload[9..17].copy_from_slice(&(id as u64).to_le_bytes());
+      payload
+    };
+
+    std::fs::write("/proc/self/clear_refs", "5").unwrap();
+    let baseline_kb = peak_rss_kb();
+    let mut grouped_msgs = GroupedMessages::default();
+    match collect_mode {
+      "row" => {
+        for i in 0..RECORD_COUNT {
+          let payload = record_payload(i);
+          grouped_msgs.add(
+            MessageWithThreshold {
+              msg: parse_message(&payload).unwrap(),
+              threshold: 2,
+              submission_range: SubmissionTimeRange::at(None),
+            },
+            None,
+          );
+        }
+      }
+      _ => {
+        let mut shares = ColumnarShares::default();
+        for i in 0..RECORD_COUNT {
+          let payload = record_payload(i);
+          let (epoch, tag) = parse_message_epoch_tag(&payload).unwrap();
+          shares.append(CollectedShare {
+            epoch,
+            tag: tag.to_vec(),
+            threshold: 2,
+            submitted_at: None,
+            payload,
+          });
+        }
+        shares.group_into(&mut grouped_msgs).unwrap();
+      }
+    }
+    println!("peak_rss_kb={}", peak_rss_kb() - baseline_kb);
+  }
+
+  /// Measures the peak RSS of grouping via the row and columnar collections, each in a
+  /// separate process, with distinct records and with records that are received twice.
+  /// Run with `cargo test peak_rss -- --ignored --nocapture`.
+  ///
+  /// Both collections end up holding the parsed messages, so with distinct records
+  /// the columnar collection peaks at the parsed messages along with the share columns
+  /// and the payload memory retained by the allocator, i.e. above the row collection. Duplicates are dropped
+  /// before they are parsed, so with redelivered records its peak is lower.
+  #[test]
+  #[ignore]
+  fn peak_rss() {
+    let measure = |mode: &str| -> u64 {
+      let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([
+          "--ignored",
+          "--exact",
+          "aggregator::columnar::tests::peak_rss_mode",
+          "--nocapture",
+        ])
+        .env(PEAK_RSS_MODE_ENV_KEY, mode)
+        .output()
+        .unwrap();
+      String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|line| Some(line.split_once("peak_rss_kb=")?.1))
+        .unwrap()
+        .parse()
+        .unwrap()
+    };
+    for records_mode in ["distinct", "duplicates"] {
+      let row_kb = measure(&format!("row-{}", records_mode));
+      let columnar_kb = measure(&format!("columnar-{}", records_mode));
+      println!(
+        "{} records: row peak RSS {} kB, columnar peak RSS {} kB",
+        records_mode, row_kb, columnar_kb
+      );
+      if records_mode == "duplicates" {
+        assert!(columnar_kb < row_kb);
+      }
+    }
+  }
+}
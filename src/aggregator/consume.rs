@@ -1,8 +1,15 @@
+use super::columnar::{CollectedShare, ColumnarShares};
 use super::group::GroupedMessages;
+use super::lake_first::{LakeFirstSource, ProcessedOffsets};
+use super::partition_quota::PartitionQuotas;
+use super::watermark::PartitionWatermarks;
 use super::AggregatorError;
-use crate::models::MessageWithThreshold;
+use crate::memory_watchdog::{memory_pressure, record_intervention, Intervention};
+use crate::models::{MessageWithThreshold, SubmissionTimeRange};
+use crate::progress::record_progress;
+use crate::prometheus::{AggregatorMetricLabels, AggregatorMetrics};
 use crate::record_stream::{ConsumedRecord, RecordStreamArc};
-use crate::star::parse_message;
+use crate::star::{parse_message, parse_message_epoch_tag};
 use crate::util::parse_env_var;
 use futures::future::try_join_all;
 use futures::FutureExt;
@@ -11,46 +18,122 @@ use std::time::Duration;
 use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::time::{sleep, sleep_until, Instant};
 
 const MAX_INIT_RECV_TIMEOUT_MS_ENV_KEY: &str = "MAX_INIT_RECV_TIMEOUT_MS";
 const DEFAULT_MAX_INIT_RECV_TIMEOUT_MS: &str = "30000";
 const MIN_RECV_RATE_ENV_KEY: &str = "MIN_RECV_RATE_PER_SEC";
 const DEFAULT_MIN_RECV_RATE: &str = "100";
+const CONSUME_IDLE_TIMEOUT_MS_ENV_KEY: &str = "CONSUME_IDLE_TIMEOUT_MS";
+const DEFAULT_CONSUME_IDLE_TIMEOUT_MS: &str = "2000";
+const CONSUME_BATCH_SIZE_ENV_KEY: &str = "CONSUME_BATCH_SIZE";
+const DEFAULT_CONSUME_BATCH_SIZE: &str = "500";
 
 const RATE_CHECK_INTERVAL_SECS: u64 = 5;
 
+/// Parsed message, or a share of the columnar collection. Messages are not boxed,
+/// since the default collection sends a message for every record.
+#[allow(clippy::large_enum_variant)]
+enum ParsedRecord {
+  Message(MessageWithThreshold),
+  Share(CollectedShare),
+}
+
+type ParsingTask = (
+  mpsc::UnboundedSender<ConsumedRecord>,
+  JoinHandle<Result<(), AggregatorError>>,
+);
+
+#[allow(clippy::too_many_arguments)]
 async fn run_recv_task(
   rec_stream: RecordStreamArc,
+  topic: String,
   parsing_task_tx: mpsc::UnboundedSender<ConsumedRecord>,
   msg_count: Arc<Mutex<usize>>,
   msgs_to_collect_count: usize,
+  processed_offsets: Option<Arc<ProcessedOffsets>>,
+  watermarks: Option<Arc<PartitionWatermarks>>,
+  quotas: Option<Arc<PartitionQuotas>>,
 ) -> Result<(), AggregatorError> {
   let max_init_recv_timeout = Duration::from_millis(parse_env_var::<u64>(
     MAX_INIT_RECV_TIMEOUT_MS_ENV_KEY,
     DEFAULT_MAX_INIT_RECV_TIMEOUT_MS,
   ));
   let min_recv_rate = parse_env_var::<u64>(MIN_RECV_RATE_ENV_KEY, DEFAULT_MIN_RECV_RATE);
+  let idle_timeout = Duration::from_millis(parse_env_var::<u64>(
+    CONSUME_IDLE_TIMEOUT_MS_ENV_KEY,
+    DEFAULT_CONSUME_IDLE_TIMEOUT_MS,
+  ));
+  let consume_batch_size =
+    parse_env_var::<usize>(CONSUME_BATCH_SIZE_ENV_KEY, DEFAULT_CONSUME_BATCH_SIZE).max(1);
   let rate_check_interval = Duration::from_secs(RATE_CHECK_INTERVAL_SECS);
 
   let mut total_init_wait_time = Duration::from_secs(0);
   let mut rate_frame_sleep = sleep(rate_check_interval).boxed();
   let mut stream_started = false;
   let mut msgs_recvd_in_frame = 0;
+  let mut processed_skip_count = 0;
+  let mut last_recv_instant = Instant::now();
 
   loop {
+    // Batches are bounded by the remaining collect count, so that the
+    // iteration does not consume more records than it collects
+    let batch_size = {
+      let msg_count = msg_count.lock().await;
+      msgs_to_collect_count
+        .saturating_sub(*msg_count)
+        .min(consume_batch_size)
+    };
+    if batch_size == 0 {
+      break;
+    }
     tokio::select! {
-      msg_res = rec_stream.consume() => {
-        parsing_task_tx.send(msg_res?).unwrap();
+      records_res = rec_stream.consume_batch(batch_size, idle_timeout) => {
+        let records = records_res?;
+        if records.is_empty() {
+          continue;
+        }
+        last_recv_instant = Instant::now();
+        let mut collected_count = 0;
+        for mut record in records {
+          record.topic = Some(topic.clone());
+          if let (Some(quotas), Some(topic_partition), Some(offset)) =
+            (quotas.as_ref(), record.topic_partition(), record.offset)
+          {
+            if !quotas.check_record(&rec_stream, topic_partition, offset)? {
+              continue;
+            }
+          }
+          if let Some(watermarks) = watermarks.as_ref() {
+            watermarks.observe(&record);
+          }
+          if let Some(processed_offsets) = processed_offsets.as_ref() {
+            if !processed_offsets.check_and_mark(&record, false) {
+              processed_skip_count += 1;
+              continue;
+            }
+          }
+          parsing_task_tx.send(record).unwrap();
+          collected_count += 1;
+        }
+        if collected_count == 0 {
+          continue;
+        }
+        record_progress(collected_count as u64);
 
         let mut msg_count = msg_count.lock().await;
-        *msg_count += 1;
+        *msg_count += collected_count;
         if *msg_count >= msgs_to_collect_count {
           break;
         }
         drop(msg_count);
+        if memory_pressure() {
+          // Process the collected messages, instead of growing the iteration further
+          record_intervention(Intervention::EndCollection);
+          break;
+        }
 
-        msgs_recvd_in_frame += 1;
+        msgs_recvd_in_frame += collected_count as u64;
         if !stream_started {
           // If the stream has just started (aka the first message was just received),
           // reset the sleep so that we get a proper, unskewed rate measurement.
@@ -58,6 +141,15 @@ async fn run_recv_task(
           stream_started = true;
         }
       },
+      // If no records were received for the idle timeout after the stream has started,
+      // assume that the partitions are drained, instead of waiting for the next rate check.
+      _ = sleep_until(last_recv_instant + idle_timeout), if stream_started => {
+        info!(
+          "No records received for {}ms, assuming the stream is drained",
+          idle_timeout.as_millis()
+        );
+        break;
+      },
       _ = &mut rate_frame_sleep => {
         rate_frame_sleep = sleep(rate_check_interval).boxed();
         if !rec_stream.has_assigned_partitions()? {
@@ -80,31 +172,51 @@ async fn run_recv_task(
       },
     }
   }
+  if processed_skip_count > 0 {
+    info!(
+      "Skipped {} Kafka records that were already processed",
+      processed_skip_count
+    );
+  }
   info!("Kafka consume task finished");
   Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_recv_tasks(
-  rec_streams: &Vec<RecordStreamArc>,
-  parsing_tasks: &Vec<(
-    mpsc::UnboundedSender<ConsumedRecord>,
-    JoinHandle<Result<(), AggregatorError>>,
-  )>,
+  rec_streams: &[RecordStreamArc],
+  rec_stream_topics: &[String],
+  parsing_tasks: &[ParsingTask],
   msg_count: Arc<Mutex<usize>>,
   msgs_to_collect_count: usize,
+  processed_offsets: Option<Arc<ProcessedOffsets>>,
+  watermarks: Option<Arc<PartitionWatermarks>>,
+  quotas: Option<Arc<PartitionQuotas>>,
 ) -> Vec<JoinHandle<Result<(), AggregatorError>>> {
   parsing_tasks
     .iter()
-    .zip(rec_streams.iter().cloned().into_iter())
-    .map(|((parsing_task_tx, _), rec_stream)| {
+    .zip(
+      rec_streams
+        .iter()
+        .cloned()
+        .zip(rec_stream_topics.iter().cloned()),
+    )
+    .map(|((parsing_task_tx, _), (rec_stream, topic))| {
       let parsing_task_tx = parsing_task_tx.clone();
       let msg_count = msg_count.clone();
+      let processed_offsets = processed_offsets.clone();
+      let watermarks = watermarks.clone();
+      let quotas = quotas.clone();
       tokio::spawn(async move {
         run_recv_task(
           rec_stream,
+          topic,
           parsing_task_tx,
           msg_count,
           msgs_to_collect_count,
+          processed_offsets,
+          watermarks,
+          quotas,
         )
         .await
       })
@@ -114,24 +226,76 @@ fn create_recv_tasks(
 
 fn create_parsing_tasks(
   task_count: usize,
-  parsed_tx: UnboundedSender<MessageWithThreshold>,
+  parsed_tx: UnboundedSender<ParsedRecord>,
+  channel_name: &str,
+  target_epoch: Option<u8>,
   default_k_threshold: usize,
-) -> Vec<(
-  mpsc::UnboundedSender<ConsumedRecord>,
-  JoinHandle<Result<(), AggregatorError>>,
-)> {
+  columnar: bool,
+) -> Vec<ParsingTask> {
   (0..task_count)
     .map(|_| {
       let parsed_tx = parsed_tx.clone();
+      let channel_name = channel_name.to_string();
       let (raw_tx, mut raw_rx) = mpsc::unbounded_channel::<ConsumedRecord>();
       let task = tokio::spawn(async move {
+        let mut foreign_channel_count = 0;
+        let mut other_epoch_count = 0;
         while let Some(record) = raw_rx.recv().await {
-          parsed_tx
-            .send(MessageWithThreshold {
+          // Records without a channel were produced before channels were
+          // stamped at ingest, and are assumed to belong to the current channel.
+          if let Some(record_channel_name) = record.channel_name.as_ref() {
+            if *record_channel_name != channel_name {
+              foreign_channel_count += 1;
+              continue;
+            }
+          }
+          // Use the epoch header to skip messages before parsing, if available
+          if let (Some(target_epoch), Some(record_epoch)) = (target_epoch, record.epoch) {
+            if record_epoch != target_epoch {
+              other_epoch_count += 1;
+              continue;
+            }
+          }
+          let threshold = record.request_threshold.unwrap_or(default_k_threshold);
+          // Columnar shares are parsed once collection is finished
+          let parsed = match columnar {
+            true => {
+              let (epoch, tag) = parse_message_epoch_tag(&record.data)?;
+              ParsedRecord::Share(CollectedShare {
+                epoch,
+                tag: tag.to_vec(),
+                threshold,
+                submitted_at: record.submitted_at,
+                payload: record.data,
+              })
+            }
+            false => ParsedRecord::Message(MessageWithThreshold {
               msg: parse_message(&record.data)?,
-              threshold: record.request_threshold.unwrap_or(default_k_threshold),
-            })
-            .unwrap();
+              threshold,
+              submission_range: SubmissionTimeRange::at(record.submitted_at),
+            }),
+          };
+          let epoch = match &parsed {
+            ParsedRecord::Message(parsed_msg) => parsed_msg.msg.epoch,
+            ParsedRecord::Share(share) => share.epoch,
+          };
+          if target_epoch.is_some_and(|target_epoch| epoch != target_epoch) {
+            other_epoch_count += 1;
+            continue;
+          }
+          parsed_tx.send(parsed).unwrap();
+        }
+        if foreign_channel_count > 0 {
+          warn!(
+            "Skipped {} messages from other data channels",
+            foreign_channel_count
+          );
+        }
+        if other_epoch_count > 0 {
+          info!(
+            "Skipped {} messages outside of the target epoch",
+            other_epoch_count
+          );
         }
         info!("Parsing task finished");
         Ok(())
@@ -141,29 +305,106 @@ fn create_parsing_tasks(
     .collect()
 }
 
+/// Sends archived records to the parsing tasks, until the archive is exhausted
+/// or enough messages have been collected.
+async fn recv_archived_records(
+  lake_first: &mut LakeFirstSource,
+  parsing_tasks: &[ParsingTask],
+  msg_count: &Mutex<usize>,
+  msgs_to_collect_count: usize,
+) -> Result<(), AggregatorError> {
+  let mut processed_skip_count = 0;
+  let mut msg_count = msg_count.lock().await;
+  while *msg_count < msgs_to_collect_count {
+    let record = match lake_first.next_record().await? {
+      Some(record) => record,
+      None => break,
+    };
+    if !lake_first.offsets.check_and_mark(&record, true) {
+      processed_skip_count += 1;
+      continue;
+    }
+    let (parsing_task_tx, _) = &parsing_tasks[*msg_count % parsing_tasks.len()];
+    parsing_task_tx.send(record).unwrap();
+    record_progress(1);
+    *msg_count += 1;
+  }
+  info!(
+    "Received {} archived records, skipped {} already processed records",
+    *msg_count, processed_skip_count
+  );
+  Ok(())
+}
+
+/// If a target epoch is provided, messages from other epochs will be consumed
+/// and discarded. If a lake-first source is provided, records will be read
+/// from the message archive before consuming from the record streams.
+/// If processed offsets are provided without a lake-first source, consumed
+/// records at or below the processed offsets will be skipped.
+/// If partition watermarks are provided, the timestamps of the consumed
+/// records will be observed. `rec_stream_topics` lists the configured
+/// topic of each record stream.
+#[allow(clippy::too_many_arguments)]
 pub async fn consume_and_group(
   rec_streams: &Vec<RecordStreamArc>,
+  rec_stream_topics: &[String],
+  mut lake_first: Option<&mut LakeFirstSource>,
+  mut processed_offsets: Option<Arc<ProcessedOffsets>>,
+  channel_name: &str,
+  target_epoch: Option<u8>,
   msgs_to_collect_count: usize,
   default_k_threshold: usize,
+  watermarks: Option<Arc<PartitionWatermarks>>,
 ) -> Result<(GroupedMessages, usize), AggregatorError> {
-  let mut grouped_msgs = GroupedMessages::default();
+  let mut grouped_msgs = GroupedMessages::from_env();
 
-  let (parsed_tx, mut parsed_rx) = mpsc::unbounded_channel::<MessageWithThreshold>();
+  let (parsed_tx, mut parsed_rx) = mpsc::unbounded_channel::<ParsedRecord>();
   let msg_count = Arc::new(Mutex::new(0));
+  let mut columnar_shares = ColumnarShares::default();
 
-  let parsing_tasks = create_parsing_tasks(rec_streams.len(), parsed_tx, default_k_threshold);
-  let recv_tasks = create_recv_tasks(
-    rec_streams,
-    &parsing_tasks,
-    msg_count.clone(),
-    msgs_to_collect_count,
+  let parsing_tasks = create_parsing_tasks(
+    rec_streams.len(),
+    parsed_tx,
+    channel_name,
+    target_epoch,
+    default_k_threshold,
+    ColumnarShares::is_enabled(),
   );
+  if let Some(lake_first) = lake_first.as_mut() {
+    recv_archived_records(
+      lake_first,
+      &parsing_tasks,
+      &msg_count,
+      msgs_to_collect_count,
+    )
+    .await?;
+    processed_offsets = Some(lake_first.offsets.clone());
+  }
+  let archived_count = *msg_count.lock().await;
+  let quotas =
+    PartitionQuotas::from_env(msgs_to_collect_count.saturating_sub(archived_count)).map(Arc::new);
+  let recv_tasks = match archived_count >= msgs_to_collect_count {
+    true => Vec::new(),
+    false => create_recv_tasks(
+      rec_streams,
+      rec_stream_topics,
+      &parsing_tasks,
+      msg_count.clone(),
+      msgs_to_collect_count,
+      processed_offsets,
+      watermarks,
+      quotas.clone(),
+    ),
+  };
 
   let mut task_handles = recv_tasks;
   task_handles.extend(parsing_tasks.into_iter().map(|(_, handle)| handle));
 
-  while let Some(parsed_msg) = parsed_rx.recv().await {
-    grouped_msgs.add(parsed_msg, None);
+  while let Some(parsed) = parsed_rx.recv().await {
+    match parsed {
+      ParsedRecord::Message(parsed_msg) => grouped_msgs.add(parsed_msg, None),
+      ParsedRecord::Share(share) => columnar_shares.append(share),
+    }
   }
 
   try_join_all(task_handles)
@@ -171,12 +412,50 @@ pub async fn consume_and_group(
     .into_iter()
     .collect::<Result<Vec<()>, AggregatorError>>()?;
 
+  if !columnar_shares.is_empty() {
+    columnar_shares.group_into(&mut grouped_msgs)?;
+  }
+
+  if let Some(quotas) = quotas {
+    if quotas.paused_count() > 0 {
+      info!(
+        "{} partitions reached their collect quota, resuming",
+        quotas.paused_count()
+      );
+      for rec_stream in rec_streams {
+        rec_stream.resume()?;
+      }
+    }
+  }
+
   info!("Messages grouped");
 
   let msg_count = *msg_count.lock().await;
   Ok((grouped_msgs, msg_count))
 }
 
+/// Reports the lag of each record stream consumer to the metrics, and returns the total
+/// amount of uncommitted records in the assigned partitions, or None if no partitions
+/// are assigned.
+pub fn report_consumer_lag(
+  rec_streams: &[RecordStreamArc],
+  channel_name: &str,
+  metrics: &AggregatorMetrics,
+) -> Result<Option<i64>, AggregatorError> {
+  let mut total_lag = None;
+  for (consumer_index, rec_stream) in rec_streams.iter().enumerate() {
+    let partition_lags = rec_stream.partition_lags()?;
+    metrics.set_consumer_lag(
+      &AggregatorMetricLabels::new(channel_name, consumer_index),
+      &partition_lags,
+    );
+    if !partition_lags.is_empty() {
+      *total_lag.get_or_insert(0) += partition_lags.iter().map(|(_, lag)| lag).sum::<i64>();
+    }
+  }
+  Ok(total_lag)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -184,6 +463,7 @@ mod tests {
   use crate::star::tests::generate_test_message;
   use star_constellation::api::SerializableNestedMessage;
   use star_constellation::randomness::testing::LocalFetcher;
+  use std::collections::HashMap;
 
   const THRESHOLD: usize = 50;
 
@@ -191,9 +471,19 @@ mod tests {
   async fn consume_and_group_all() {
     let record_stream = prepare_record_stream().await;
 
-    let (grouped_msgs, count) = consume_and_group(&record_stream, 1024, THRESHOLD)
-      .await
-      .unwrap();
+    let (grouped_msgs, count) = consume_and_group(
+      &record_stream,
+      &topics(),
+      None,
+      None,
+      "typical",
+      None,
+      1024,
+      THRESHOLD,
+      None,
+    )
+    .await
+    .unwrap();
 
     assert_eq!(count, 7);
     assert_eq!(grouped_msgs.msg_chunks.get(&4).unwrap().len(), 1);
@@ -205,9 +495,19 @@ mod tests {
   async fn consume_and_group_some() {
     let record_stream = prepare_record_stream().await;
 
-    let (grouped_msgs, count) = consume_and_group(&record_stream, 3, THRESHOLD)
-      .await
-      .unwrap();
+    let (grouped_msgs, count) = consume_and_group(
+      &record_stream,
+      &topics(),
+      None,
+      None,
+      "typical",
+      None,
+      3,
+      THRESHOLD,
+      None,
+    )
+    .await
+    .unwrap();
 
     assert_eq!(count, 3);
     assert_eq!(grouped_msgs.msg_chunks.get(&4).unwrap().len(), 1);
@@ -215,7 +515,97 @@ mod tests {
     assert!(grouped_msgs.msg_chunks.get(&6).is_none());
   }
 
+  #[tokio::test]
+  async fn consume_and_group_target_epoch() {
+    let test_record_stream = prepare_test_record_stream().await;
+    // Payload is invalid, so consumption would fail if the
+    // message was not skipped via the epoch header
+    test_record_stream
+      .records_to_consume
+      .lock()
+      .await
+      .push(ConsumedRecord {
+        data: vec![1, 2, 3],
+        epoch: Some(4),
+        ..Default::default()
+      });
+    let record_stream: Vec<RecordStreamArc> = vec![test_record_stream];
+
+    let (grouped_msgs, count) = consume_and_group(
+      &record_stream,
+      &topics(),
+      None,
+      None,
+      "typical",
+      Some(5),
+      1024,
+      THRESHOLD,
+      None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(count, 8);
+    assert!(!grouped_msgs.msg_chunks.contains_key(&4));
+    assert_eq!(grouped_msgs.msg_chunks.get(&5).unwrap().len(), 2);
+    assert!(!grouped_msgs.msg_chunks.contains_key(&6));
+  }
+
+  #[tokio::test]
+  async fn consume_and_group_skip_processed_offsets() {
+    let test_record_stream = prepare_test_record_stream().await;
+    for (offset, record) in test_record_stream
+      .records_to_consume
+      .lock()
+      .await
+      .iter_mut()
+      .enumerate()
+    {
+      record.partition = Some(0);
+      record.offset = Some(offset as i64);
+    }
+    let record_stream: Vec<RecordStreamArc> = vec![test_record_stream];
+    let processed_offsets = Arc::new(
+      ProcessedOffsets::new(
+        "typical",
+        HashMap::from([(("p3a-star-enc".to_string(), 0), 2)]),
+      )
+      .without_gap_warnings(),
+    );
+
+    let (_, count) = consume_and_group(
+      &record_stream,
+      &topics(),
+      None,
+      Some(processed_offsets.clone()),
+      "typical",
+      None,
+      1024,
+      THRESHOLD,
+      None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(count, 4);
+    let consumed_record = ConsumedRecord {
+      topic: Some("p3a-star-enc".to_string()),
+      partition: Some(0),
+      offset: Some(6),
+      ..Default::default()
+    };
+    assert!(!processed_offsets.check_and_mark(&consumed_record, false));
+  }
+
+  fn topics() -> Vec<String> {
+    vec!["p3a-star-enc".to_string()]
+  }
+
   async fn prepare_record_stream() -> Vec<RecordStreamArc> {
+    vec![prepare_test_record_stream().await]
+  }
+
+  async fn prepare_test_record_stream() -> Arc<TestRecordStream> {
     let record_stream = Arc::new(TestRecordStream::default());
 
     let fetcher = LocalFetcher::new();
@@ -238,9 +628,12 @@ mod tests {
         &fetcher,
       )))
       .unwrap();
-      records_to_consume.push(msg);
+      records_to_consume.push(ConsumedRecord {
+        data: msg,
+        ..Default::default()
+      });
     }
     drop(records_to_consume);
-    vec![record_stream]
+    record_stream
   }
 }
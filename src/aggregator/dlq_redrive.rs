@@ -0,0 +1,304 @@
+//! Re-drive of the dead letter topic. Records in the dead letter topic of the main
+//! channel are consumed, validated against the current message parser, and produced
+//! to the encrypted topic so that they may be aggregated again. This is useful after
+//! fixing a parsing bug, or after extending the max age of pending messages.
+//!
+//! Records that still fail to parse, or that belong to expired epochs, are skipped.
+//! Re-driven records keep the threshold, channel and epoch headers of the dead letter
+//! record, and receive provenance headers containing the dead letter topic, partition
+//! and offset, along with the re-drive time. Consumption is committed as records are
+//! re-driven, so an interrupted re-drive resumes from the last committed record.
+
+use super::AggregatorError;
+use crate::epoch::EpochConfig;
+use crate::message_key::MessageKeyMode;
+use crate::record_stream::{
+  datetime_to_unix_millis, get_data_channel_dlq_topic_from_env, get_data_channel_topic_from_env,
+  ConsumedRecord, DynRecordStream, KafkaComponent, KafkaRecordStreamConfig, RecordStreamFactory,
+  RecordStreamOptions,
+};
+use crate::star::parse_message;
+use crate::util::parse_env_var;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::timeout;
+
+const DLQ_REDRIVE_IDLE_TIMEOUT_SECS_ENV_KEY: &str = "DLQ_REDRIVE_IDLE_TIMEOUT_SECS";
+const DEFAULT_DLQ_REDRIVE_IDLE_TIMEOUT_SECS: &str = "30";
+const COMMIT_INTERVAL: usize = 1000;
+
+const REDRIVEN_FROM_TOPIC_HEADER_NAME: &str = "redriven-from-topic";
+const REDRIVEN_FROM_PARTITION_HEADER_NAME: &str = "redriven-from-partition";
+const REDRIVEN_FROM_OFFSET_HEADER_NAME: &str = "redriven-from-offset";
+const REDRIVEN_AT_HEADER_NAME: &str = "redriven-at";
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct RedriveSummary {
+  pub consumed_count: usize,
+  pub redriven_count: usize,
+  /// Records that could not be parsed by the current parser
+  pub invalid_count: usize,
+  /// Records of epochs that have expired since they were discarded
+  pub expired_count: usize,
+}
+
+/// Returns the provenance headers of a re-driven record. Numeric values
+/// are little endian, and the time is in Unix milliseconds.
+fn provenance_headers(
+  source_topic: &str,
+  record: &ConsumedRecord,
+  redriven_at: OffsetDateTime,
+) -> Vec<(&'static str, Vec<u8>)> {
+  let mut headers = vec![(
+    REDRIVEN_FROM_TOPIC_HEADER_NAME,
+    source_topic.as_bytes().to_vec(),
+  )];
+  if let Some(partition) = record.partition {
+    headers.push((
+      REDRIVEN_FROM_PARTITION_HEADER_NAME,
+      partition.to_le_bytes().to_vec(),
+    ));
+  }
+  if let Some(offset) = record.offset {
+    headers.push((
+      REDRIVEN_FROM_OFFSET_HEADER_NAME,
+      offset.to_le_bytes().to_vec(),
+    ));
+  }
+  headers.push((
+    REDRIVEN_AT_HEADER_NAME,
+    datetime_to_unix_millis(redriven_at).to_le_bytes().to_vec(),
+  ));
+  headers
+}
+
+async fn commit_offsets(
+  dlq_stream: &DynRecordStream,
+  offsets: &mut HashMap<i32, i64>,
+) -> Result<(), AggregatorError> {
+  if !offsets.is_empty() {
+    let offsets_vec: Vec<_> = offsets.drain().collect();
+    dlq_stream.commit_offsets(&offsets_vec).await?;
+  }
+  Ok(())
+}
+
+/// Consumes the dead letter stream until no records are received within the idle
+/// timeout, and produces the recoverable records to the output stream.
+pub async fn redrive_records(
+  dlq_stream: &DynRecordStream,
+  out_stream: &DynRecordStream,
+  epoch_config: &EpochConfig,
+  source_topic: &str,
+  idle_timeout: Duration,
+) -> Result<RedriveSummary, AggregatorError> {
+  let mut summary = RedriveSummary::default();
+  let mut offsets = HashMap::new();
+  // Re-driven records are keyed in the same way as submitted messages
+  let message_key_mode = MessageKeyMode::from_env();
+  while let Ok(record) = timeout(idle_timeout, dlq_stream.consume()).await {
+    let record = record?;
+    summary.consumed_count += 1;
+    match parse_message(&record.data) {
+      Err(e) => {
+        debug!("Skipping dead letter record that failed to parse: {}", e);
+        summary.invalid_count += 1;
+      }
+      Ok(msg) if epoch_config.is_epoch_expired(msg.epoch) => {
+        summary.expired_count += 1;
+      }
+      Ok(msg) => {
+        out_stream
+          .produce_with_headers(
+            &record.data,
+            message_key_mode.message_key(&msg).as_deref(),
+            record.request_threshold,
+            Some(
+              record
+                .channel_name
+                .as_deref()
+                .unwrap_or(&epoch_config.channel_name),
+            ),
+            Some(msg.epoch),
+            &[
+              provenance_headers(source_topic, &record, OffsetDateTime::now_utc()),
+              record.metadata_headers(),
+            ]
+            .concat(),
+          )
+          .await?;
+        summary.redriven_count += 1;
+      }
+    }
+    if let (Some(partition), Some(offset)) = (record.partition, record.offset) {
+      offsets.insert(partition, offset);
+    }
+    if summary.consumed_count % COMMIT_INTERVAL == 0 {
+      commit_offsets(dlq_stream, &mut offsets).await?;
+    }
+  }
+  commit_offsets(dlq_stream, &mut offsets).await?;
+  Ok(summary)
+}
+
+/// Re-drives the dead letter topic of the main channel to its encrypted topic.
+pub async fn redrive_dlq(
+  epoch_config: &EpochConfig,
+  tenant: Option<&str>,
+) -> Result<RedriveSummary, AggregatorError> {
+  let channel_name = &epoch_config.channel_name;
+  let dlq_topic = get_data_channel_dlq_topic_from_env(channel_name).unwrap_or_else(|| {
+    panic!(
+      "No dead letter topic configured for channel {}",
+      channel_name
+    )
+  });
+  let stream_factory = RecordStreamFactory::from_env();
+  let dlq_stream = stream_factory.create(
+    KafkaRecordStreamConfig {
+      component: KafkaComponent::Aggregator,
+      enable_producer: false,
+      enable_consumer: true,
+      topic: dlq_topic.clone(),
+      use_output_group_id: false,
+      group_id_suffix: None,
+      tenant: tenant.map(|v| v.to_string()),
+    },
+    RecordStreamOptions::default(),
+  );
+  let out_stream = stream_factory.create(
+    KafkaRecordStreamConfig {
+      component: KafkaComponent::Aggregator,
+      enable_producer: true,
+      enable_consumer: false,
+      topic: get_data_channel_topic_from_env(false, channel_name),
+      use_output_group_id: false,
+      group_id_suffix: None,
+      tenant: tenant.map(|v| v.to_string()),
+    },
+    RecordStreamOptions::default(),
+  );
+  let idle_timeout = Duration::from_secs(parse_env_var(
+    DLQ_REDRIVE_IDLE_TIMEOUT_SECS_ENV_KEY,
+    DEFAULT_DLQ_REDRIVE_IDLE_TIMEOUT_SECS,
+  ));
+
+  info!("Re-driving dead letter topic {}", dlq_topic);
+  let summary = redrive_records(
+    dlq_stream.as_ref(),
+    out_stream.as_ref(),
+    epoch_config,
+    &dlq_topic,
+    idle_timeout,
+  )
+  .await?;
+  info!(
+    "Re-drove {} of {} dead letter records ({} invalid, {} expired)",
+    summary.redriven_count, summary.consumed_count, summary.invalid_count, summary.expired_count
+  );
+  Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::record_stream::TestRecordStream;
+  use crate::rollup::RollupRules;
+  use crate::star::tests::generate_test_message;
+  use calendar_duration::CalendarDuration;
+  use star_constellation::api::SerializableNestedMessage;
+  use star_constellation::randomness::testing::LocalFetcher;
+
+  #[test]
+  fn record_provenance() {
+    let record = ConsumedRecord {
+      partition: Some(2),
+      offset: Some(300),
+      ..Default::default()
+    };
+    let redriven_at = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    assert_eq!(
+      provenance_headers("p3a-star-dlq", &record, redriven_at),
+      vec![
+        (REDRIVEN_FROM_TOPIC_HEADER_NAME, b"p3a-star-dlq".to_vec()),
+        (
+          REDRIVEN_FROM_PARTITION_HEADER_NAME,
+          2i32.to_le_bytes().to_vec()
+        ),
+        (
+          REDRIVEN_FROM_OFFSET_HEADER_NAME,
+          300i64.to_le_bytes().to_vec()
+        ),
+        (
+          REDRIVEN_AT_HEADER_NAME,
+          1_700_000_000_000i64.to_le_bytes().to_vec()
+        ),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn redrive_valid_records() {
+    let epoch_length = CalendarDuration::from("1w");
+    let epoch_config = EpochConfig {
+      channel_name: "typical".to_string(),
+      current_epoch: CurrentEpochInfo::test_info(4, epoch_length),
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    };
+    let fetcher = LocalFetcher::new();
+    let message = |epoch: u8| {
+      bincode::serialize(&SerializableNestedMessage::from(generate_test_message(
+        epoch,
+        &[b"test|1".to_vec()],
+        &fetcher,
+      )))
+      .unwrap()
+    };
+    let valid_msg = message(4);
+
+    let dlq_stream = TestRecordStream::default();
+    dlq_stream.records_to_consume.lock().await.extend([
+      ConsumedRecord {
+        data: valid_msg.clone(),
+        ..Default::default()
+      },
+      ConsumedRecord {
+        data: vec![1, 2, 3],
+        ..Default::default()
+      },
+      ConsumedRecord {
+        data: message(1),
+        ..Default::default()
+      },
+    ]);
+    let out_stream = TestRecordStream::default();
+
+    let summary = redrive_records(
+      &dlq_stream,
+      &out_stream,
+      &epoch_config,
+      "p3a-star-dlq",
+      Duration::from_millis(100),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+      summary,
+      RedriveSummary {
+        consumed_count: 3,
+        redriven_count: 1,
+        invalid_count: 1,
+        expired_count: 1,
+      }
+    );
+    assert_eq!(*out_stream.records_produced.lock().await, vec![valid_msg]);
+  }
+}
@@ -0,0 +1,169 @@
+//! Per-epoch message counts, computed from the current state of the database
+//! without running an aggregation. Intended for dashboards, so that expected
+//! measurement volumes can be reviewed before an epoch closes.
+
+use super::AggregatorError;
+use crate::epoch::EpochConfig;
+use crate::models::{
+  DBConnection, PendingEpochCounts, PendingMessage, RecoveredEpochCounts, RecoveredMessage,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EpochCounts {
+  pub data_channel: String,
+  pub epoch: u8,
+  pub epoch_date: String,
+  pub expired: bool,
+  pub pending_msg_count: i64,
+  pub pending_tag_count: i64,
+  /// Amount of pending messages in tags that have already met their threshold
+  pub threshold_met_pending_msg_count: i64,
+  pub recovered_tag_count: i64,
+  /// Amount of recovered messages that have not been reported yet
+  pub unreported_msg_count: i64,
+  /// Estimated fraction of pending messages that will be recovered by the next
+  /// aggregation, i.e. messages in tags that have met their threshold. Other pending
+  /// messages will only be recovered if their tags receive enough shares before
+  /// the epoch expires. None if there are no pending messages.
+  pub estimated_recoverable_fraction: Option<f64>,
+}
+
+impl EpochCounts {
+  fn empty(epoch_config: &EpochConfig, epoch: u8) -> Self {
+    Self {
+      data_channel: epoch_config.channel_name.clone(),
+      epoch,
+      epoch_date: epoch_config.get_epoch_survey_date(epoch),
+      expired: epoch_config.is_epoch_expired(epoch),
+      pending_msg_count: 0,
+      pending_tag_count: 0,
+      threshold_met_pending_msg_count: 0,
+      recovered_tag_count: 0,
+      unreported_msg_count: 0,
+      estimated_recoverable_fraction: None,
+    }
+  }
+
+  pub fn from_counts(
+    epoch_config: &EpochConfig,
+    pending_counts: &[PendingEpochCounts],
+    recovered_counts: &[RecoveredEpochCounts],
+  ) -> Vec<Self> {
+    let mut counts: BTreeMap<u8, Self> = BTreeMap::new();
+    for pending in pending_counts {
+      let entry = counts
+        .entry(pending.epoch_tag as u8)
+        .or_insert_with(|| Self::empty(epoch_config, pending.epoch_tag as u8));
+      entry.pending_msg_count = pending.msg_count;
+      entry.pending_tag_count = pending.tag_count;
+      entry.threshold_met_pending_msg_count = pending.threshold_met_msg_count;
+      if pending.msg_count > 0 {
+        entry.estimated_recoverable_fraction =
+          Some(pending.threshold_met_msg_count as f64 / pending.msg_count as f64);
+      }
+    }
+    for recovered in recovered_counts {
+      let entry = counts
+        .entry(recovered.epoch_tag as u8)
+        .or_insert_with(|| Self::empty(epoch_config, recovered.epoch_tag as u8));
+      entry.recovered_tag_count = recovered.tag_count;
+      entry.unreported_msg_count = recovered.unreported_msg_count;
+    }
+    counts.into_values().collect()
+  }
+
+  /// Returns the counts of each epoch with pending or recovered messages.
+  pub async fn generate(
+    conn: Arc<Mutex<DBConnection>>,
+    epoch_config: &EpochConfig,
+  ) -> Result<Vec<Self>, AggregatorError> {
+    let pending_counts =
+      PendingMessage::counts_by_epoch(conn.clone(), &epoch_config.channel_name).await?;
+    let recovered_counts =
+      RecoveredMessage::counts_by_epoch(conn, &epoch_config.channel_name).await?;
+    Ok(Self::from_counts(
+      epoch_config,
+      &pending_counts,
+      &recovered_counts,
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::models::{DBConnectionType, DBPool};
+  use crate::rollup::RollupRules;
+  use calendar_duration::CalendarDuration;
+  use dotenvy::dotenv;
+
+  fn test_epoch_config() -> EpochConfig {
+    let epoch_length = CalendarDuration::from("1w");
+    EpochConfig {
+      channel_name: "typical".to_string(),
+      current_epoch: CurrentEpochInfo::test_info(4, epoch_length),
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    }
+  }
+
+  #[test]
+  fn merge_counts() {
+    let epoch_config = test_epoch_config();
+    let counts = EpochCounts::from_counts(
+      &epoch_config,
+      &[PendingEpochCounts {
+        epoch_tag: 3,
+        msg_count: 40,
+        tag_count: 12,
+        threshold_met_msg_count: 10,
+      }],
+      &[
+        RecoveredEpochCounts {
+          epoch_tag: 1,
+          tag_count: 2,
+          unreported_msg_count: 7,
+        },
+        RecoveredEpochCounts {
+          epoch_tag: 3,
+          tag_count: 5,
+          unreported_msg_count: 0,
+        },
+      ],
+    );
+    assert_eq!(
+      counts
+        .iter()
+        .map(|v| (
+          v.epoch,
+          v.expired,
+          v.pending_msg_count,
+          v.recovered_tag_count
+        ))
+        .collect::<Vec<_>>(),
+      vec![(1, true, 0, 2), (3, false, 40, 5)]
+    );
+    assert_eq!(counts[0].estimated_recoverable_fraction, None);
+    assert_eq!(counts[1].estimated_recoverable_fraction, Some(0.25));
+    assert_eq!(counts[1].epoch_date, epoch_config.get_epoch_survey_date(3));
+  }
+
+  #[tokio::test]
+  async fn generate_from_db() {
+    dotenv().ok();
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+    // Checks that the count queries match the schema and result types
+    EpochCounts::generate(conn, &test_epoch_config())
+      .await
+      .unwrap();
+  }
+}
@@ -0,0 +1,173 @@
+//! Lake export of finalized epochs. If `EXPORT_FINALIZED_EPOCHS` is set, reported
+//! measurements are retained in the database along with their epoch, and the complete
+//! set of an epoch's measurements is exported to the data lake once the epoch is finalized.
+//! This provides a canonical archival copy that does not depend on the retention of
+//! the output topic.
+//!
+//! Measurements are stored as JSON lines in one or more parts of up to
+//! `EPOCH_EXPORT_PART_SIZE_MB`, followed by a manifest containing the SHA-256 checksum
+//! of each part. The manifest is stored last, so that an export is only complete
+//! if the manifest exists. Exports are stored again with the same keys if
+//! finalization is interrupted.
+
+use super::measurement::OUTPUT_SCHEMA_VERSION;
+use super::AggregatorError;
+use crate::epoch::EpochConfig;
+use crate::lake::DataLake;
+use crate::models::{DBConnection, EpochMeasurement};
+use crate::util::parse_env_var;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::str::from_utf8;
+use std::sync::{Arc, Mutex};
+
+const EXPORT_FINALIZED_EPOCHS_ENV_KEY: &str = "EXPORT_FINALIZED_EPOCHS";
+const DEFAULT_EXPORT_FINALIZED_EPOCHS: &str = "false";
+const EPOCH_EXPORT_PART_SIZE_MB_ENV_KEY: &str = "EPOCH_EXPORT_PART_SIZE_MB";
+const DEFAULT_EPOCH_EXPORT_PART_SIZE_MB: &str = "128";
+const EXPORT_MANIFEST_FILE_NAME: &str = "_manifest.json";
+
+pub fn epoch_export_enabled() -> bool {
+  parse_env_var(
+    EXPORT_FINALIZED_EPOCHS_ENV_KEY,
+    DEFAULT_EXPORT_FINALIZED_EPOCHS,
+  )
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EpochExportPart {
+  pub file_name: String,
+  pub measurement_count: usize,
+  pub size: usize,
+  pub sha256: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EpochExportManifest {
+  pub data_channel: String,
+  pub epoch: u8,
+  pub epoch_date: String,
+  pub schema_version: u32,
+  pub measurement_count: usize,
+  pub parts: Vec<EpochExportPart>,
+}
+
+/// Splits the measurements into JSON lines parts of up to `part_size` bytes.
+/// A part may exceed the size if it only contains a single measurement.
+fn build_export_parts(
+  measurements: &[Vec<u8>],
+  part_size: usize,
+) -> Result<Vec<(EpochExportPart, String)>, AggregatorError> {
+  let mut parts = Vec::new();
+  let mut contents = String::new();
+  let mut measurement_count = 0;
+  for measurement in measurements {
+    let line = from_utf8(measurement)?;
+    if measurement_count > 0 && contents.len() + line.len() + 1 > part_size {
+      parts.push((measurement_count, contents));
+      contents = String::new();
+      measurement_count = 0;
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    measurement_count += 1;
+  }
+  if measurement_count > 0 {
+    parts.push((measurement_count, contents));
+  }
+  Ok(
+    parts
+      .into_iter()
+      .enumerate()
+      .map(|(index, (measurement_count, contents))| {
+        let part = EpochExportPart {
+          file_name: format!("part-{:04}.jsonl", index),
+          measurement_count,
+          size: contents.len(),
+          sha256: hex::encode(Sha256::digest(contents.as_bytes())),
+        };
+        (part, contents)
+      })
+      .collect(),
+  )
+}
+
+/// Exports the retained measurements of the epoch to the lake. Should be called
+/// within the finalization transaction, after the final measurements of the epoch
+/// are stored, and before the retained measurements are deleted. Returns the amount of exported measurements.
+pub async fn export_epoch(
+  conn: Arc<Mutex<DBConnection>>,
+  lake: &DataLake,
+  epoch_config: &EpochConfig,
+  epoch: u8,
+) -> Result<usize, AggregatorError> {
+  let channel_name = &epoch_config.channel_name;
+  let epoch_date = epoch_config.get_epoch_survey_date(epoch);
+  let measurements = EpochMeasurement::list_epoch(conn, channel_name, epoch as i16).await?;
+  let part_size = parse_env_var::<usize>(
+    EPOCH_EXPORT_PART_SIZE_MB_ENV_KEY,
+    DEFAULT_EPOCH_EXPORT_PART_SIZE_MB,
+  ) * 1024
+    * 1024;
+
+  let mut manifest = EpochExportManifest {
+    data_channel: channel_name.clone(),
+    epoch,
+    epoch_date: epoch_date.clone(),
+    schema_version: OUTPUT_SCHEMA_VERSION,
+    measurement_count: measurements.len(),
+    parts: Vec::new(),
+  };
+  for (part, contents) in build_export_parts(&measurements, part_size)? {
+    lake
+      .store_epoch_export(channel_name, &epoch_date, epoch, &part.file_name, &contents)
+      .await?;
+    manifest.parts.push(part);
+  }
+  lake
+    .store_epoch_export(
+      channel_name,
+      &epoch_date,
+      epoch,
+      EXPORT_MANIFEST_FILE_NAME,
+      &serde_json::to_string(&manifest)?,
+    )
+    .await?;
+
+  info!(
+    "Exported {} measurements of epoch '{}' in {} parts",
+    manifest.measurement_count,
+    epoch,
+    manifest.parts.len()
+  );
+  Ok(manifest.measurement_count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn export_parts() {
+    let measurements: Vec<Vec<u8>> = ["{\"a\":1}", "{\"a\":2}", "{\"a\":33}"]
+      .iter()
+      .map(|v| v.as_bytes().to_vec())
+      .collect();
+    let parts = build_export_parts(&measurements, 16).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].1, "{\"a\":1}\n{\"a\":2}\n");
+    assert_eq!(parts[1].1, "{\"a\":33}\n");
+    assert_eq!(
+      parts[0].0,
+      EpochExportPart {
+        file_name: "part-0000.jsonl".to_string(),
+        measurement_count: 2,
+        size: 16,
+        sha256: hex::encode(Sha256::digest(b"{\"a\":1}\n{\"a\":2}\n")),
+      }
+    );
+    assert_eq!(parts[1].0.file_name, "part-0001.jsonl");
+
+    assert!(build_export_parts(&[], 16).unwrap().is_empty());
+  }
+}
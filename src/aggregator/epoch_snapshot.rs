@@ -0,0 +1,228 @@
+//! Epoch-scoped configuration snapshots. The effective configuration used to
+//! process the messages of each epoch is recorded in the database, so that
+//! the output semantics of each epoch can be reproduced. An epoch is not finalized
+//! if its messages were processed with different configurations, unless forced.
+//! Finalized snapshots are also used to detect the reprocessing of finalized epochs.
+
+use super::AggregatorError;
+use crate::epoch::EpochConfig;
+use crate::models::{DBConnection, EpochConfigSnapshot, NewEpochConfigSnapshot};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+  k_threshold: usize,
+  epoch_length: String,
+  epoch_lifetime_count: usize,
+  epoch_date_field_name: &'a str,
+  sampling_rate: f64,
+  /// Omitted unless enabled, so that existing snapshots remain comparable
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  counting_only: bool,
+}
+
+/// Returns the serialized effective configuration for the channel.
+pub fn effective_config_json(epoch_config: &EpochConfig, k_threshold: usize) -> String {
+  serde_json::to_string(&EffectiveConfig {
+    k_threshold,
+    epoch_length: epoch_config.epoch_length.to_string(),
+    epoch_lifetime_count: epoch_config.epoch_lifetime_count,
+    epoch_date_field_name: &epoch_config.epoch_date_field_name,
+    sampling_rate: epoch_config.sampling_rate,
+    counting_only: epoch_config.counting_only,
+  })
+  .unwrap()
+}
+
+/// Records the configuration used to process messages for the epochs.
+pub async fn record_epoch_configs(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  config_json: &str,
+  epochs: impl IntoIterator<Item = u8>,
+) -> Result<(), AggregatorError> {
+  let snapshots = epochs
+    .into_iter()
+    .map(|epoch| NewEpochConfigSnapshot {
+      channel_name: channel_name.to_string(),
+      epoch: epoch as i16,
+      config: config_json.to_string(),
+      finalized: false,
+    })
+    .collect();
+  Ok(EpochConfigSnapshot::record_batch(conn, snapshots).await?)
+}
+
+/// Returns true if the epoch may be finalized with the configuration.
+/// Finalization is refused if the epoch was processed with multiple
+/// configurations, or with a configuration that differs from the given one,
+/// unless `force` is set.
+pub async fn check_epoch_finalization(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  config_json: &str,
+  epoch: i16,
+  force: bool,
+) -> Result<bool, AggregatorError> {
+  let mut configs: HashSet<String> = EpochConfigSnapshot::list_for_epoch(conn, channel_name, epoch)
+    .await?
+    .into_iter()
+    .map(|v| v.config)
+    .collect();
+  configs.insert(config_json.to_string());
+  if configs.len() > 1 {
+    if !force {
+      return Ok(false);
+    }
+    warn!(
+      "Forcing finalization of epoch {} with {} configurations",
+      epoch,
+      configs.len()
+    );
+  }
+  Ok(true)
+}
+
+/// Marks the configuration used to finalize the epoch. Should be called
+/// within the transaction that finalizes the epoch.
+pub async fn record_finalized_config(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  config_json: &str,
+  epoch: i16,
+) -> Result<(), AggregatorError> {
+  let snapshot = NewEpochConfigSnapshot {
+    channel_name: channel_name.to_string(),
+    epoch,
+    config: config_json.to_string(),
+    finalized: true,
+  };
+  Ok(EpochConfigSnapshot::record_batch(conn, vec![snapshot]).await?)
+}
+
+/// Returns the epochs that have already been finalized. Epoch numbers wrap around,
+/// so only finalizations recorded after the start of the most recent occurrence
+/// of the epoch are considered.
+pub async fn finalized_epochs(
+  conn: Arc<Mutex<DBConnection>>,
+  epoch_config: &EpochConfig,
+  epochs: &[u8],
+) -> Result<Vec<u8>, AggregatorError> {
+  let mut result = Vec::new();
+  for epoch in epochs {
+    let epoch_start_time = epoch_config.get_epoch_start_time(*epoch);
+    let snapshots =
+      EpochConfigSnapshot::list_for_epoch(conn.clone(), &epoch_config.channel_name, *epoch as i16)
+        .await?;
+    if snapshots
+      .iter()
+      .any(|v| v.finalized && v.created_at >= epoch_start_time)
+    {
+      result.push(*epoch);
+    }
+  }
+  Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::models::{DBConnectionType, DBPool};
+  use crate::rollup::RollupRules;
+  use calendar_duration::CalendarDuration;
+  use dotenvy::dotenv;
+
+  const TEST_CHANNEL_NAME: &str = "typical";
+
+  #[tokio::test]
+  async fn mixed_configs() {
+    dotenv().ok();
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+
+    record_epoch_configs(conn.clone(), TEST_CHANNEL_NAME, "config_a", [3, 4])
+      .await
+      .unwrap();
+    record_epoch_configs(conn.clone(), TEST_CHANNEL_NAME, "config_a", [3])
+      .await
+      .unwrap();
+    assert!(
+      check_epoch_finalization(conn.clone(), TEST_CHANNEL_NAME, "config_a", 3, false)
+        .await
+        .unwrap()
+    );
+
+    record_epoch_configs(conn.clone(), TEST_CHANNEL_NAME, "config_b", [4])
+      .await
+      .unwrap();
+    assert!(
+      !check_epoch_finalization(conn.clone(), TEST_CHANNEL_NAME, "config_a", 4, false)
+        .await
+        .unwrap()
+    );
+    assert!(
+      check_epoch_finalization(conn.clone(), TEST_CHANNEL_NAME, "config_a", 4, true)
+        .await
+        .unwrap()
+    );
+    // A changed configuration is also detected at finalization time
+    assert!(
+      !check_epoch_finalization(conn.clone(), TEST_CHANNEL_NAME, "config_c", 3, false)
+        .await
+        .unwrap()
+    );
+
+    record_finalized_config(conn.clone(), TEST_CHANNEL_NAME, "config_a", 3)
+      .await
+      .unwrap();
+    let snapshots = EpochConfigSnapshot::list_for_epoch(conn, TEST_CHANNEL_NAME, 3)
+      .await
+      .unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert!(snapshots[0].finalized);
+  }
+
+  #[tokio::test]
+  async fn detect_finalized_epochs() {
+    dotenv().ok();
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+    let epoch_length = CalendarDuration::from("1w");
+    let epoch_config = EpochConfig {
+      channel_name: "refinalize".to_string(),
+      current_epoch: CurrentEpochInfo::test_info(8, epoch_length),
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    };
+
+    record_epoch_configs(conn.clone(), "refinalize", "config_a", [4, 5])
+      .await
+      .unwrap();
+    record_finalized_config(conn.clone(), "refinalize", "config_a", 4)
+      .await
+      .unwrap();
+    assert_eq!(
+      finalized_epochs(conn.clone(), &epoch_config, &[4, 5, 8])
+        .await
+        .unwrap(),
+      vec![4]
+    );
+
+    // The finalization predates the current occurrence of the epoch
+    let epoch_config = EpochConfig {
+      current_epoch: CurrentEpochInfo::test_info(4, epoch_length),
+      ..epoch_config
+    };
+    assert!(finalized_epochs(conn, &epoch_config, &[4])
+      .await
+      .unwrap()
+      .is_empty());
+  }
+}
@@ -1,11 +1,14 @@
+use super::key_cache::RecoveredKeyCache;
 use super::recovered::RecoveredMessages;
 use super::AggregatorError;
+use crate::encryption::encrypt_share;
 use crate::models::{
   BatchInsert, DBPool, DBStorageConnections, MessageWithThreshold, NewPendingMessage,
-  PendingMessage,
+  PendingMessage, SubmissionTimeRange,
 };
-use crate::profiler::Profiler;
-use crate::star::serialize_message_bincode;
+use crate::profiler::{Profiler, ProfilerStat};
+use crate::star::StoredMessageFormat;
+use crate::util::parse_env_var;
 use futures::future::try_join_all;
 use star_constellation::api::NestedMessage;
 use std::cmp::max;
@@ -15,6 +18,8 @@ use tokio::task::JoinHandle;
 
 const DB_WORKERS: usize = 4;
 const INSERT_BATCH_SIZE: usize = 10000;
+const SHARE_RETENTION_MULTIPLE_ENV_KEY: &str = "SHARE_RETENTION_THRESHOLD_MULTIPLE";
+const DEFAULT_SHARE_RETENTION_MULTIPLE: &str = "0";
 
 #[derive(Default, Clone)]
 pub struct MessageChunk {
@@ -23,9 +28,23 @@ pub struct MessageChunk {
   /// Threshold mapped to pending messages
   pub pending_msgs: HashMap<usize, Vec<PendingMessage>>,
   pub parent_msg_tag: Option<Vec<u8>>,
+  /// Submission times of the new messages
+  pub submission_range: SubmissionTimeRange,
+  /// Amount of new messages that were counted, but not retained,
+  /// since the share retention limit was reached
+  pub excess_count: i64,
 }
 
 impl MessageChunk {
+  /// Returns the submission times of both new and pending messages.
+  pub fn full_submission_range(&self) -> SubmissionTimeRange {
+    let mut range = self.submission_range;
+    for pending_msg in self.pending_msgs.values().flatten() {
+      range.merge(&pending_msg.submission_range());
+    }
+    range
+  }
+
   pub fn recoverable_threshold(&self) -> Option<usize> {
     for (threshold, new_msgs) in &self.new_msgs {
       let pending_msgs_len = self
@@ -47,22 +66,89 @@ type ChunksMap = HashMap<u8, EpochChunksMap>;
 
 type PendingMessageMap = HashMap<Vec<u8>, Vec<PendingMessage>>;
 
+/// Tags may accumulate share counts far beyond the threshold. If a share retention
+/// multiple is set, at most `threshold * multiple` new messages are retained for each
+/// tag and threshold, and any further messages are only counted. Only the retained
+/// messages are used for key and measurement recovery, and the excess count is added
+/// to the count of the recovered tag.
+///
+/// Excess messages are not decrypted, so their nested layers are not recovered.
+/// As a result, the excess is reported as part of a partial measurement for the tag
+/// once the epoch expires, instead of the full measurements of the nested tags.
+/// If the key for the tag cannot be recovered, the excess is discarded, since only
+/// retained messages are stored as pending messages.
 #[derive(Default)]
 pub struct GroupedMessages {
   pub msg_chunks: ChunksMap,
+  share_retention_multiple: Option<usize>,
 }
 
 impl GroupedMessages {
+  /// Creates grouped messages with the share retention multiple
+  /// from `SHARE_RETENTION_THRESHOLD_MULTIPLE`. Zero disables the limit.
+  pub fn from_env() -> Self {
+    let multiple = parse_env_var::<usize>(
+      SHARE_RETENTION_MULTIPLE_ENV_KEY,
+      DEFAULT_SHARE_RETENTION_MULTIPLE,
+    );
+    Self {
+      msg_chunks: Default::default(),
+      share_retention_multiple: (multiple > 0).then_some(multiple),
+    }
+  }
+
+  /// Creates empty grouped messages for the next layer, with the same retention limit.
+  pub fn next_layer(&self) -> Self {
+    Self {
+      msg_chunks: Default::default(),
+      share_retention_multiple: self.share_retention_multiple,
+    }
+  }
+
+  pub fn tag_count(&self) -> usize {
+    self.msg_chunks.values().map(|c| c.len()).sum()
+  }
+
+  /// Returns the amount of new messages in each epoch, including messages
+  /// that were counted but not retained.
+  pub fn epoch_message_counts(&self) -> HashMap<u8, i64> {
+    self
+      .msg_chunks
+      .iter()
+      .map(|(epoch, chunks)| {
+        let count = chunks
+          .values()
+          .map(|v| v.new_msgs.values().map(|m| m.len() as i64).sum::<i64>() + v.excess_count)
+          .sum();
+        (*epoch, count)
+      })
+      .collect()
+  }
+
+  /// Returns the amount of counted messages that were not retained.
+  pub fn excess_count(&self) -> i64 {
+    self
+      .msg_chunks
+      .values()
+      .flat_map(|v| v.values())
+      .map(|v| v.excess_count)
+      .sum()
+  }
+
   pub fn add(&mut self, mwt: MessageWithThreshold, parent_msg_tag: Option<&[u8]>) {
     let epoch_chunk = self.msg_chunks.entry(mwt.msg.epoch).or_default();
     let chunk = epoch_chunk
       .entry(mwt.msg.unencrypted_layer.tag.clone())
       .or_default();
-    chunk
-      .new_msgs
-      .entry(mwt.threshold)
-      .or_default()
-      .push(mwt.msg);
+    let retention_limit = self
+      .share_retention_multiple
+      .map(|multiple| mwt.threshold.max(1) * multiple);
+    let new_msgs = chunk.new_msgs.entry(mwt.threshold).or_default();
+    match retention_limit {
+      Some(limit) if new_msgs.len() >= limit => chunk.excess_count += 1,
+      _ => new_msgs.push(mwt.msg),
+    }
+    chunk.submission_range.merge(&mwt.submission_range);
     if chunk.parent_msg_tag.is_none() {
       if let Some(tag) = parent_msg_tag {
         chunk.parent_msg_tag = Some(tag.to_vec());
@@ -73,14 +159,32 @@ impl GroupedMessages {
   pub async fn fetch_recovered(
     &mut self,
     db_pool: Arc<DBPool>,
+    channel_name: &str,
     rec_msgs: &mut RecoveredMessages,
+    key_cache: Option<&RecoveredKeyCache>,
     profiler: Arc<Profiler>,
   ) -> Result<(), AggregatorError> {
     let conn = Arc::new(Mutex::new(db_pool.get().await?));
     for (epoch, epoch_chunks) in self.msg_chunks.iter() {
-      let msg_tags: Vec<_> = epoch_chunks.keys().cloned().collect();
+      let mut msg_tags: Vec<_> = epoch_chunks.keys().cloned().collect();
+      if let Some(key_cache) = key_cache {
+        let (cached_msgs, uncached_tags) = key_cache.lookup(*epoch, msg_tags);
+        for rec_msg in cached_msgs {
+          rec_msgs.add(rec_msg);
+        }
+        msg_tags = uncached_tags;
+      }
+      if msg_tags.is_empty() {
+        continue;
+      }
       rec_msgs
-        .fetch_recovered(conn.clone(), *epoch, msg_tags, profiler.clone())
+        .fetch_recovered(
+          conn.clone(),
+          channel_name,
+          *epoch,
+          msg_tags,
+          profiler.clone(),
+        )
         .await?;
     }
     Ok(())
@@ -89,6 +193,7 @@ impl GroupedMessages {
   pub async fn fetch_pending(
     &mut self,
     db_pool: Arc<DBPool>,
+    channel_name: &str,
     rec_msgs: &mut RecoveredMessages,
     profiler: Arc<Profiler>,
   ) -> Result<(), AggregatorError> {
@@ -106,14 +211,21 @@ impl GroupedMessages {
             let db_pool = db_pool.clone();
             let tags = tags.to_vec();
             let epoch = *epoch as i16;
+            let channel_name = channel_name.to_string();
             let profiler = profiler.clone();
             tokio::spawn(async move {
               let conn = Arc::new(Mutex::new(db_pool.get().await?));
               let mut pending_msgs = PendingMessageMap::new();
 
               for tag in tags {
-                let msgs =
-                  PendingMessage::list(conn.clone(), epoch, tag.clone(), profiler.clone()).await?;
+                let msgs = PendingMessage::list(
+                  conn.clone(),
+                  &channel_name,
+                  epoch,
+                  tag.clone(),
+                  profiler.clone(),
+                )
+                .await?;
                 pending_msgs.insert(tag, msgs);
               }
               Ok(pending_msgs)
@@ -141,8 +253,24 @@ impl GroupedMessages {
   pub async fn store_new_pending_msgs(
     self,
     store_conns: &Arc<DBStorageConnections>,
+    channel_name: &str,
     profiler: Arc<Profiler>,
   ) -> Result<(), AggregatorError> {
+    let discarded_count = self.excess_count();
+    if discarded_count > 0 {
+      warn!(
+        "Discarding {} excess messages for tags without recovered keys",
+        discarded_count
+      );
+      profiler
+        .record_range(
+          ProfilerStat::ExcessSharesDiscarded,
+          discarded_count as u32,
+          " msgs",
+        )
+        .await;
+    }
+    let format = StoredMessageFormat::from_env();
     for (epoch, mut epoch_chunks) in self.msg_chunks {
       for chunk in epoch_chunks.values_mut() {
         chunk.pending_msgs.clear();
@@ -151,13 +279,22 @@ impl GroupedMessages {
       let mut new_pending_msgs = Vec::new();
 
       for (tag, chunk) in epoch_chunks {
+        // Submission times are tracked per chunk, so each stored message
+        // is assigned the times of all new messages in the chunk
+        let submission_range = chunk.submission_range;
         for (threshold, msgs) in chunk.new_msgs {
           for msg in msgs {
+            let (message, encrypted) = encrypt_share(format.serialize(msg)?);
             new_pending_msgs.push(NewPendingMessage {
               msg_tag: tag.clone(),
               epoch_tag: epoch as i16,
-              message: serialize_message_bincode(msg)?,
+              message,
               threshold: i16::try_from(threshold).map_err(|_| AggregatorError::ThresholdTooBig)?,
+              channel_name: Some(channel_name.to_string()),
+              encrypted,
+              first_submitted_at: submission_range.earliest,
+              last_submitted_at: submission_range.latest,
+              message_format: format.tag(),
             });
           }
         }
@@ -176,7 +313,7 @@ impl GroupedMessages {
   }
 
   pub fn split(mut self, chunk_count: usize) -> Vec<Self> {
-    let mut result: Vec<Self> = (0..chunk_count).map(|_| Default::default()).collect();
+    let mut result: Vec<Self> = (0..chunk_count).map(|_| self.next_layer()).collect();
     for (epoch, old_epoch_chunk) in &mut self.msg_chunks {
       let msg_tags: Vec<Vec<u8>> = old_epoch_chunk.keys().cloned().collect();
       let msg_tag_chunks: Vec<Vec<Vec<u8>>> = msg_tags
@@ -185,7 +322,7 @@ impl GroupedMessages {
         .collect();
 
       for (mut i, tag_chunk) in msg_tag_chunks.into_iter().enumerate() {
-        i = i % chunk_count;
+        i %= chunk_count;
         let new_epoch_chunk = result[i].msg_chunks.entry(*epoch).or_default();
         for tag in tag_chunk {
           let msg_chunk = old_epoch_chunk.remove(&tag).unwrap();
@@ -204,8 +341,10 @@ mod tests {
   use crate::star::tests::generate_test_message;
   use dotenvy::dotenv;
   use star_constellation::randomness::testing::LocalFetcher;
+  use time::{Duration, OffsetDateTime};
 
   const THRESHOLD: usize = 50;
+  const TEST_CHANNEL_NAME: &str = "typical";
 
   #[tokio::test]
   async fn basic_group_and_pending_storage() {
@@ -231,6 +370,7 @@ mod tests {
         MessageWithThreshold {
           msg: generate_test_message(epoch, &vec![measurement.as_bytes().to_vec()], &fetcher),
           threshold: THRESHOLD,
+          submission_range: Default::default(),
         },
         None,
       );
@@ -258,7 +398,7 @@ mod tests {
 
     let store_conns = Arc::new(DBStorageConnections::new(&db_pool, true).await.unwrap());
     grouped_msgs
-      .store_new_pending_msgs(&store_conns, profiler.clone())
+      .store_new_pending_msgs(&store_conns, TEST_CHANNEL_NAME, profiler.clone())
       .await
       .unwrap();
     drop(store_conns);
@@ -272,6 +412,7 @@ mod tests {
         MessageWithThreshold {
           msg: generate_test_message(epoch, &vec![measurement.as_bytes().to_vec()], &fetcher),
           threshold: THRESHOLD,
+          submission_range: Default::default(),
         },
         None,
       );
@@ -279,7 +420,12 @@ mod tests {
 
     // Should fetch pending messages for new message tags
     grouped_msgs
-      .fetch_pending(db_pool.clone(), &mut rec_msgs, profiler.clone())
+      .fetch_pending(
+        db_pool.clone(),
+        TEST_CHANNEL_NAME,
+        &mut rec_msgs,
+        profiler.clone(),
+      )
       .await
       .unwrap();
 
@@ -303,6 +449,143 @@ mod tests {
     }
   }
 
+  #[tokio::test]
+  async fn delete_aged_pending_msgs() {
+    dotenv().ok();
+    let mut grouped_msgs = GroupedMessages::default();
+    let profiler = Arc::new(Profiler::default());
+    let fetcher = LocalFetcher::new();
+
+    for measurement in ["a|0", "a|1", "a|1"] {
+      grouped_msgs.add(
+        MessageWithThreshold {
+          msg: generate_test_message(0, &[measurement.as_bytes().to_vec()], &fetcher),
+          threshold: THRESHOLD,
+          submission_range: Default::default(),
+        },
+        None,
+      );
+    }
+
+    let db_pool = Arc::new(DBPool::new(DBConnectionType::Test));
+    let store_conns = Arc::new(DBStorageConnections::new(&db_pool, true).await.unwrap());
+    grouped_msgs
+      .store_new_pending_msgs(&store_conns, TEST_CHANNEL_NAME, profiler.clone())
+      .await
+      .unwrap();
+    drop(store_conns);
+
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+    let mut counts_by_tag =
+      PendingMessage::count_by_tag(conn.clone(), TEST_CHANNEL_NAME, 0, profiler.clone())
+        .await
+        .unwrap();
+    counts_by_tag.sort();
+    assert_eq!(counts_by_tag, vec![1, 2]);
+
+    let now = OffsetDateTime::now_utc();
+    let discarded = PendingMessage::delete_older_than(
+      conn.clone(),
+      TEST_CHANNEL_NAME,
+      now - Duration::hours(1),
+      profiler.clone(),
+    )
+    .await
+    .unwrap();
+    assert!(discarded.is_empty());
+
+    let discarded = PendingMessage::delete_older_than(
+      conn.clone(),
+      TEST_CHANNEL_NAME,
+      now + Duration::hours(1),
+      profiler.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(discarded.len(), 3);
+    assert!(discarded.iter().all(|v| v.threshold == THRESHOLD as i16));
+  }
+
+  #[tokio::test]
+  async fn pending_submission_range() {
+    dotenv().ok();
+    let mut grouped_msgs = GroupedMessages::default();
+    let mut rec_msgs = RecoveredMessages::default();
+    let profiler = Arc::new(Profiler::default());
+    let fetcher = LocalFetcher::new();
+    let stored_time = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+    let new_time = stored_time + Duration::hours(2);
+
+    let add_msg = |grouped_msgs: &mut GroupedMessages, time: OffsetDateTime| {
+      grouped_msgs.add(
+        MessageWithThreshold {
+          msg: generate_test_message(6, &[b"a|0".to_vec()], &fetcher),
+          threshold: THRESHOLD,
+          submission_range: SubmissionTimeRange::at(Some(time)),
+        },
+        None,
+      );
+    };
+    add_msg(&mut grouped_msgs, stored_time);
+
+    let db_pool = Arc::new(DBPool::new(DBConnectionType::Test));
+    let store_conns = Arc::new(DBStorageConnections::new(&db_pool, true).await.unwrap());
+    grouped_msgs
+      .store_new_pending_msgs(&store_conns, TEST_CHANNEL_NAME, profiler.clone())
+      .await
+      .unwrap();
+    drop(store_conns);
+
+    grouped_msgs = GroupedMessages::default();
+    add_msg(&mut grouped_msgs, new_time);
+    grouped_msgs
+      .fetch_pending(
+        db_pool.clone(),
+        TEST_CHANNEL_NAME,
+        &mut rec_msgs,
+        profiler.clone(),
+      )
+      .await
+      .unwrap();
+
+    let chunk = grouped_msgs.msg_chunks[&6].values().next().unwrap();
+    assert_eq!(
+      chunk.full_submission_range(),
+      SubmissionTimeRange {
+        earliest: Some(stored_time),
+        latest: Some(new_time),
+      }
+    );
+  }
+
+  #[test]
+  fn share_retention_limit() {
+    let mut grouped_msgs = GroupedMessages {
+      share_retention_multiple: Some(2),
+      ..Default::default()
+    };
+    let fetcher = LocalFetcher::new();
+    for threshold in [3, 3, 3, 3, 3, 3, 3, 3, 4] {
+      grouped_msgs.add(
+        MessageWithThreshold {
+          msg: generate_test_message(0, &[b"a|0".to_vec()], &fetcher),
+          threshold,
+          submission_range: Default::default(),
+        },
+        None,
+      );
+    }
+
+    let chunk = grouped_msgs.msg_chunks[&0].values().next().unwrap();
+    assert_eq!(chunk.new_msgs[&3].len(), 6);
+    assert_eq!(chunk.new_msgs[&4].len(), 1);
+    assert_eq!(chunk.excess_count, 2);
+    assert_eq!(grouped_msgs.excess_count(), 2);
+    for split_msgs in grouped_msgs.split(2) {
+      assert_eq!(split_msgs.share_retention_multiple, Some(2));
+    }
+  }
+
   #[test]
   fn chunk_split() {
     let mut grouped_msgs = GroupedMessages::default();
@@ -320,6 +603,7 @@ mod tests {
                 &fetcher,
               ),
               threshold: THRESHOLD,
+              submission_range: Default::default(),
             },
             None,
           );
@@ -363,8 +647,9 @@ mod tests {
     for (epoch, measurement) in msg_infos {
       grouped_msgs.add(
         MessageWithThreshold {
-          msg: generate_test_message(epoch, &vec![measurement.as_bytes().to_vec()], &fetcher),
+          msg: generate_test_message(epoch, &[measurement.as_bytes().to_vec()], &fetcher),
           threshold: THRESHOLD,
+          submission_range: Default::default(),
         },
         None,
       );
@@ -387,6 +672,9 @@ mod tests {
         count: 1,
         key: Vec::new(),
         has_children: true,
+        channel_name: Some(TEST_CHANNEL_NAME.to_string()),
+        first_submitted_at: None,
+        last_submitted_at: None,
       })
       .collect();
 
@@ -401,7 +689,13 @@ mod tests {
     let mut recovered_msgs = RecoveredMessages::default();
 
     grouped_msgs
-      .fetch_recovered(db_pool, &mut recovered_msgs, profiler.clone())
+      .fetch_recovered(
+        db_pool,
+        TEST_CHANNEL_NAME,
+        &mut recovered_msgs,
+        None,
+        profiler.clone(),
+      )
       .await
       .unwrap();
 
@@ -0,0 +1,149 @@
+//! Warm-start cache of recovered messages. If enabled, the recovered messages
+//! of the target epochs are loaded into memory before the first iteration, so that
+//! recovered keys do not need to be fetched from the database for each tag.
+//!
+//! Saved recovered messages are written back to the cache, so that cached counts
+//! remain accurate across iterations. Newly inserted messages are fetched from the
+//! database when they are next needed, since their ids are not known until then.
+
+use super::recovered::RecoveredMessages;
+use super::AggregatorError;
+use crate::models::{DBConnection, RecoveredMessage};
+use crate::profiler::Profiler;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct CachedEpoch {
+  msgs: HashMap<Vec<u8>, RecoveredMessage>,
+  /// Tags inserted since the epoch was loaded, which must be fetched from the database
+  inserted_tags: HashSet<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct RecoveredKeyCache {
+  epochs: Mutex<HashMap<u8, CachedEpoch>>,
+}
+
+impl RecoveredKeyCache {
+  /// Loads all recovered messages for the epochs. Returns the loaded message count.
+  pub async fn load(
+    &self,
+    conn: Arc<Mutex<DBConnection>>,
+    channel_name: &str,
+    epochs: &[u8],
+    profiler: Arc<Profiler>,
+  ) -> Result<usize, AggregatorError> {
+    let mut loaded_count = 0;
+    for epoch in epochs {
+      let msgs: HashMap<Vec<u8>, RecoveredMessage> =
+        RecoveredMessage::list_epoch(conn.clone(), channel_name, *epoch as i16, profiler.clone())
+          .await?
+          .into_iter()
+          .map(|v| (v.msg_tag.clone(), v))
+          .collect();
+      loaded_count += msgs.len();
+      self.epochs.lock().unwrap().insert(
+        *epoch,
+        CachedEpoch {
+          msgs,
+          inserted_tags: HashSet::new(),
+        },
+      );
+    }
+    Ok(loaded_count)
+  }
+
+  /// Returns the cached recovered messages for the tags, along with
+  /// the tags that must be fetched from the database. Tags in a loaded epoch
+  /// that are not cached and were not inserted do not have recovered messages.
+  pub fn lookup(&self, epoch: u8, msg_tags: Vec<Vec<u8>>) -> (Vec<RecoveredMessage>, Vec<Vec<u8>>) {
+    let epochs = self.epochs.lock().unwrap();
+    let cached_epoch = match epochs.get(&epoch) {
+      Some(cached_epoch) => cached_epoch,
+      None => return (Vec::new(), msg_tags),
+    };
+    let mut cached_msgs = Vec::new();
+    let mut uncached_tags = Vec::new();
+    for msg_tag in msg_tags {
+      if let Some(msg) = cached_epoch.msgs.get(&msg_tag) {
+        cached_msgs.push(msg.clone());
+      } else if cached_epoch.inserted_tags.contains(&msg_tag) {
+        uncached_tags.push(msg_tag);
+      }
+    }
+    (cached_msgs, uncached_tags)
+  }
+
+  /// Writes the recovered messages back to the cache. Should be
+  /// called with the state of the messages that will be saved.
+  pub fn update(&self, rec_msgs: &RecoveredMessages) {
+    let mut epochs = self.epochs.lock().unwrap();
+    for (epoch, epoch_map) in &rec_msgs.map {
+      let cached_epoch = match epochs.get_mut(epoch) {
+        Some(cached_epoch) => cached_epoch,
+        None => continue,
+      };
+      for (msg_tag, rec_msg) in epoch_map {
+        if rec_msg.id == 0 {
+          cached_epoch.msgs.remove(msg_tag);
+          cached_epoch.inserted_tags.insert(msg_tag.clone());
+        } else {
+          cached_epoch.inserted_tags.remove(msg_tag);
+          cached_epoch.msgs.insert(msg_tag.clone(), rec_msg.clone());
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rec_msg(id: i64, msg_tag: &[u8], count: i64) -> RecoveredMessage {
+    RecoveredMessage {
+      id,
+      msg_tag: msg_tag.to_vec(),
+      epoch_tag: 2,
+      metric_name: "name".to_string(),
+      metric_value: "value".to_string(),
+      parent_recovered_msg_tag: None,
+      count,
+      key: vec![1, 2, 3],
+      has_children: false,
+      channel_name: None,
+      first_submitted_at: None,
+      last_submitted_at: None,
+    }
+  }
+
+  #[test]
+  fn lookup_and_update() {
+    let cache = RecoveredKeyCache::default();
+    cache.epochs.lock().unwrap().insert(
+      2,
+      CachedEpoch {
+        msgs: HashMap::from([(b"a".to_vec(), rec_msg(1, b"a", 5))]),
+        inserted_tags: HashSet::new(),
+      },
+    );
+
+    let (cached, uncached) = cache.lookup(2, vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(cached.len(), 1);
+    assert!(uncached.is_empty());
+    // Epochs that were not loaded are always fetched from the database
+    let (cached, uncached) = cache.lookup(3, vec![b"a".to_vec()]);
+    assert!(cached.is_empty());
+    assert_eq!(uncached, vec![b"a".to_vec()]);
+
+    let mut rec_msgs = RecoveredMessages::default();
+    rec_msgs.add(rec_msg(1, b"a", 8));
+    rec_msgs.add(rec_msg(0, b"b", 3));
+    cache.update(&rec_msgs);
+
+    let (cached, uncached) = cache.lookup(2, vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(cached[0].count, 8);
+    assert_eq!(uncached, vec![b"b".to_vec()]);
+  }
+}
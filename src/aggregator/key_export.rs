@@ -0,0 +1,254 @@
+//! Export of the recovered keys of an epoch, for authorized reprocessing. If
+//! `EPOCH_KEY_EXPORT_KMS_KEY_ID` is set, the keys recovered for each metric of an epoch
+//! are exported to the lake before the epoch is finalized, since the recovered messages
+//! are deleted during finalization. With an export, the archived messages of the epoch
+//! can be decrypted offline, i.e. for audits, without rerunning threshold recovery.
+//!
+//! Keys are stored as an encrypted bundle under `key-exports/<channel>/<epoch date>-<epoch>.json`.
+//! The bundle contains the keys encrypted with AES-256-GCM using a data key generated by
+//! AWS KMS, along with the data key encrypted by the configured KMS key. Only principals
+//! that may decrypt with the KMS key can read the exported keys. Keys of an epoch that
+//! has not been finalized yet can also be exported via `--export-epoch-keys`.
+
+use super::AggregatorError;
+use crate::encryption::ShareCipher;
+use crate::epoch::EpochConfig;
+use crate::lake::DataLake;
+use crate::models::{DBConnection, RecoveredMessage};
+use crate::profiler::Profiler;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error};
+use rusoto_core::{Region, RusotoError};
+use rusoto_kms::{GenerateDataKeyError, GenerateDataKeyRequest, Kms, KmsClient};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+
+const EPOCH_KEY_EXPORT_KMS_KEY_ID_ENV_KEY: &str = "EPOCH_KEY_EXPORT_KMS_KEY_ID";
+const DATA_KEY_SPEC: &str = "AES_256";
+
+#[derive(Debug, Display, Error)]
+pub enum KeyExportError {
+  #[display(fmt = "KMS data key generation error: {}", _0)]
+  GenerateDataKey(Box<RusotoError<GenerateDataKeyError>>),
+  #[display(fmt = "KMS data key response is incomplete")]
+  IncompleteDataKey,
+  #[display(
+    fmt = "{} must be set to export epoch keys",
+    EPOCH_KEY_EXPORT_KMS_KEY_ID_ENV_KEY
+  )]
+  NotConfigured,
+}
+
+/// The recovered key of a metric. Tags are hex-encoded, and the key is base64-encoded.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct EpochKeyRecord {
+  pub msg_tag: String,
+  pub parent_msg_tag: Option<String>,
+  pub metric_name: String,
+  pub metric_value: String,
+  pub key: String,
+}
+
+impl From<&RecoveredMessage> for EpochKeyRecord {
+  fn from(msg: &RecoveredMessage) -> Self {
+    Self {
+      msg_tag: hex::encode(&msg.msg_tag),
+      parent_msg_tag: msg.parent_recovered_msg_tag.as_ref().map(hex::encode),
+      metric_name: msg.metric_name.clone(),
+      metric_value: msg.metric_value.clone(),
+      key: base64_engine::STANDARD.encode(&msg.key),
+    }
+  }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EpochKeyBundle {
+  pub data_channel: String,
+  pub epoch: u8,
+  pub epoch_date: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub exported_at: OffsetDateTime,
+  pub key_count: usize,
+  pub kms_key_id: String,
+  /// Data key encrypted by KMS, base64-encoded
+  pub encrypted_data_key: String,
+  /// JSON array of `EpochKeyRecord`s encrypted with the data key, base64-encoded.
+  /// The first 12 bytes are the nonce.
+  pub encrypted_keys: String,
+}
+
+struct EpochKeys<'a> {
+  epoch_config: &'a EpochConfig,
+  epoch: u8,
+  records: Vec<EpochKeyRecord>,
+}
+
+impl EpochKeys<'_> {
+  fn seal(self, kms_key_id: &str, data_key: &[u8], encrypted_data_key: &[u8]) -> EpochKeyBundle {
+    let plaintext = serde_json::to_vec(&self.records).expect("key records should serialize");
+    EpochKeyBundle {
+      data_channel: self.epoch_config.channel_name.clone(),
+      epoch: self.epoch,
+      epoch_date: self.epoch_config.get_epoch_survey_date(self.epoch),
+      exported_at: OffsetDateTime::now_utc(),
+      key_count: self.records.len(),
+      kms_key_id: kms_key_id.to_string(),
+      encrypted_data_key: base64_engine::STANDARD.encode(encrypted_data_key),
+      encrypted_keys: base64_engine::STANDARD
+        .encode(ShareCipher::new(data_key).encrypt(&plaintext)),
+    }
+  }
+}
+
+pub struct EpochKeyExporter {
+  lake: DataLake,
+  kms: KmsClient,
+  kms_key_id: String,
+}
+
+impl EpochKeyExporter {
+  /// Returns an exporter for the lake, if `EPOCH_KEY_EXPORT_KMS_KEY_ID` is set.
+  pub fn from_env(lake: DataLake) -> Option<Self> {
+    let kms_key_id = env::var(EPOCH_KEY_EXPORT_KMS_KEY_ID_ENV_KEY).ok()?;
+    Some(Self {
+      lake,
+      kms: KmsClient::new(Region::default()),
+      kms_key_id,
+    })
+  }
+
+  /// Returns a new data key, and the data key encrypted by KMS.
+  async fn generate_data_key(&self) -> Result<(Vec<u8>, Vec<u8>), KeyExportError> {
+    let output = self
+      .kms
+      .generate_data_key(GenerateDataKeyRequest {
+        key_id: self.kms_key_id.clone(),
+        key_spec: Some(DATA_KEY_SPEC.to_string()),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| KeyExportError::GenerateDataKey(Box::new(e)))?;
+    match (output.plaintext, output.ciphertext_blob) {
+      (Some(plaintext), Some(ciphertext_blob)) => {
+        Ok((plaintext.to_vec(), ciphertext_blob.to_vec()))
+      }
+      _ => Err(KeyExportError::IncompleteDataKey),
+    }
+  }
+
+  /// Exports the recovered keys of the epoch to the lake. Must be called before
+  /// the recovered messages of the epoch are deleted. Returns the amount of exported keys.
+  pub async fn export(
+    &self,
+    conn: Arc<Mutex<DBConnection>>,
+    epoch_config: &EpochConfig,
+    epoch: u8,
+    profiler: Arc<Profiler>,
+  ) -> Result<usize, AggregatorError> {
+    let channel_name = &epoch_config.channel_name;
+    let records = RecoveredMessage::list_epoch(conn, channel_name, epoch as i16, profiler)
+      .await?
+      .iter()
+      .map(EpochKeyRecord::from)
+      .collect();
+    let keys = EpochKeys {
+      epoch_config,
+      epoch,
+      records,
+    };
+    let (data_key, encrypted_data_key) = self.generate_data_key().await?;
+    let bundle = keys.seal(&self.kms_key_id, &data_key, &encrypted_data_key);
+    self
+      .lake
+      .store_key_export(
+        channel_name,
+        &bundle.epoch_date,
+        epoch,
+        &serde_json::to_string(&bundle)?,
+      )
+      .await?;
+    info!(
+      "Exported {} recovered keys of epoch '{}'",
+      bundle.key_count, epoch
+    );
+    Ok(bundle.key_count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::rollup::RollupRules;
+  use calendar_duration::CalendarDuration;
+
+  fn recovered_msg(msg_tag: &[u8], parent_msg_tag: Option<&[u8]>, key: &[u8]) -> RecoveredMessage {
+    RecoveredMessage {
+      id: 1,
+      msg_tag: msg_tag.to_vec(),
+      epoch_tag: 3,
+      metric_name: "country".to_string(),
+      metric_value: "US".to_string(),
+      parent_recovered_msg_tag: parent_msg_tag.map(|v| v.to_vec()),
+      count: 20,
+      key: key.to_vec(),
+      has_children: false,
+      channel_name: Some("typical".to_string()),
+      first_submitted_at: None,
+      last_submitted_at: None,
+    }
+  }
+
+  #[test]
+  fn sealed_bundle() {
+    let epoch_length = CalendarDuration::from("1w");
+    let epoch_config = EpochConfig {
+      channel_name: "typical".to_string(),
+      current_epoch: CurrentEpochInfo::test_info(4, epoch_length),
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    };
+    let records: Vec<EpochKeyRecord> = [
+      recovered_msg(&[1, 2], None, &[9; 16]),
+      recovered_msg(&[3, 4], Some(&[1, 2]), &[10; 16]),
+    ]
+    .iter()
+    .map(EpochKeyRecord::from)
+    .collect();
+    assert_eq!(records[1].msg_tag, "0304");
+    assert_eq!(records[1].parent_msg_tag.as_deref(), Some("0102"));
+    assert_eq!(records[0].key, base64_engine::STANDARD.encode([9; 16]));
+
+    let data_key = [7u8; 32];
+    let keys = EpochKeys {
+      epoch_config: &epoch_config,
+      epoch: 3,
+      records,
+    };
+    let bundle = keys.seal("alias/keys", &data_key, b"wrapped");
+    assert_eq!(bundle.key_count, 2);
+    assert_eq!(
+      bundle.encrypted_data_key,
+      base64_engine::STANDARD.encode(b"wrapped")
+    );
+
+    let encrypted_keys = base64_engine::STANDARD
+      .decode(&bundle.encrypted_keys)
+      .unwrap();
+    let plaintext = ShareCipher::new(&data_key)
+      .decrypt(&encrypted_keys)
+      .unwrap();
+    let decrypted: Vec<EpochKeyRecord> = serde_json::from_slice(&plaintext).unwrap();
+    assert_eq!(decrypted.len(), 2);
+    assert_eq!(decrypted[0].msg_tag, "0102");
+    assert!(ShareCipher::new(&[8u8; 32])
+      .decrypt(&encrypted_keys)
+      .is_err());
+  }
+}
@@ -0,0 +1,169 @@
+//! Knobs of a running aggregator, which may be adjusted via the admin endpoints of
+//! the background metrics listener without restarting the aggregator. This allows
+//! operators to throttle long-running aggregators during database maintenance windows.
+//! Adjusted values are applied from the next iteration onwards.
+
+use crate::server::{is_admin_request, ADMIN_API_KEY_ENV_KEY};
+use crate::util::parse_env_var;
+use actix_web::web::{self, Data, Json, ServiceConfig};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const ITERATION_DELAY_MS_ENV_KEY: &str = "AGG_ITERATION_DELAY_MS";
+const DEFAULT_ITERATION_DELAY_MS: &str = "0";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct KnobValues {
+  pub worker_count: usize,
+  pub msg_collect_count: usize,
+  /// Time to wait between iterations
+  pub iteration_delay_ms: u64,
+}
+
+/// Partial update of the knobs. Unset values are left unchanged.
+#[derive(Deserialize, Debug, Default)]
+pub struct KnobUpdate {
+  pub worker_count: Option<usize>,
+  pub msg_collect_count: Option<usize>,
+  pub iteration_delay_ms: Option<u64>,
+}
+
+pub struct AggregatorKnobs {
+  worker_count: AtomicUsize,
+  msg_collect_count: AtomicUsize,
+  iteration_delay_ms: AtomicU64,
+}
+
+impl AggregatorKnobs {
+  /// Creates the knobs with the initial counts, and the iteration
+  /// delay from `AGG_ITERATION_DELAY_MS`.
+  pub fn new(worker_count: usize, msg_collect_count: usize) -> Self {
+    Self {
+      worker_count: AtomicUsize::new(worker_count),
+      msg_collect_count: AtomicUsize::new(msg_collect_count),
+      iteration_delay_ms: AtomicU64::new(parse_env_var(
+        ITERATION_DELAY_MS_ENV_KEY,
+        DEFAULT_ITERATION_DELAY_MS,
+      )),
+    }
+  }
+
+  pub fn values(&self) -> KnobValues {
+    KnobValues {
+      worker_count: self.worker_count.load(Ordering::Relaxed),
+      msg_collect_count: self.msg_collect_count.load(Ordering::Relaxed),
+      iteration_delay_ms: self.iteration_delay_ms.load(Ordering::Relaxed),
+    }
+  }
+
+  pub fn iteration_delay(&self) -> Duration {
+    Duration::from_millis(self.iteration_delay_ms.load(Ordering::Relaxed))
+  }
+
+  /// Applies the update, and returns the resulting values. Counts must be positive.
+  pub fn update(&self, update: &KnobUpdate) -> Result<KnobValues, String> {
+    if update.worker_count == Some(0) || update.msg_collect_count == Some(0) {
+      return Err("worker_count and msg_collect_count must be positive".to_string());
+    }
+    if let Some(worker_count) = update.worker_count {
+      self.worker_count.store(worker_count, Ordering::Relaxed);
+    }
+    if let Some(msg_collect_count) = update.msg_collect_count {
+      self
+        .msg_collect_count
+        .store(msg_collect_count, Ordering::Relaxed);
+    }
+    if let Some(iteration_delay_ms) = update.iteration_delay_ms {
+      self
+        .iteration_delay_ms
+        .store(iteration_delay_ms, Ordering::Relaxed);
+    }
+    let values = self.values();
+    info!("Aggregator knobs updated: {:?}", values);
+    Ok(values)
+  }
+}
+
+struct KnobsAdminState {
+  knobs: Arc<AggregatorKnobs>,
+  admin_api_key: String,
+}
+
+async fn get_knobs_handler(request: HttpRequest, state: Data<KnobsAdminState>) -> HttpResponse {
+  if !is_admin_request(&request, &state.admin_api_key) {
+    return HttpResponse::Unauthorized().finish();
+  }
+  HttpResponse::Ok().json(state.knobs.values())
+}
+
+async fn update_knobs_handler(
+  request: HttpRequest,
+  update: Json<KnobUpdate>,
+  state: Data<KnobsAdminState>,
+) -> HttpResponse {
+  if !is_admin_request(&request, &state.admin_api_key) {
+    return HttpResponse::Unauthorized().finish();
+  }
+  match state.knobs.update(&update) {
+    Ok(values) => HttpResponse::Ok().json(values),
+    Err(e) => HttpResponse::BadRequest().body(e),
+  }
+}
+
+/// Registers `GET` and `PATCH` handlers for `/admin/aggregator/knobs`,
+/// if `ADMIN_API_KEY` is set.
+pub fn configure_knobs_admin(config: &mut ServiceConfig, knobs: Arc<AggregatorKnobs>) {
+  let admin_api_key = match env::var(ADMIN_API_KEY_ENV_KEY) {
+    Ok(admin_api_key) => admin_api_key,
+    Err(_) => return,
+  };
+  config
+    .app_data(Data::new(KnobsAdminState {
+      knobs,
+      admin_api_key,
+    }))
+    .service(
+      web::resource("/admin/aggregator/knobs")
+        .route(web::get().to(get_knobs_handler))
+        .route(web::patch().to(update_knobs_handler)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn update_knobs() {
+    let knobs = AggregatorKnobs::new(16, 1000);
+    let values = knobs
+      .update(&KnobUpdate {
+        msg_collect_count: Some(500),
+        iteration_delay_ms: Some(30000),
+        ..Default::default()
+      })
+      .unwrap();
+    assert_eq!(
+      values,
+      KnobValues {
+        worker_count: 16,
+        msg_collect_count: 500,
+        iteration_delay_ms: 30000,
+      }
+    );
+    assert_eq!(knobs.iteration_delay(), Duration::from_secs(30));
+
+    assert!(knobs
+      .update(&KnobUpdate {
+        worker_count: Some(0),
+        msg_collect_count: Some(200),
+        ..Default::default()
+      })
+      .is_err());
+    assert_eq!(knobs.values(), values);
+  }
+}
@@ -0,0 +1,245 @@
+//! Lake-first mode, where messages are sourced from the lake message archive
+//! before the remaining messages are consumed from Kafka. This allows Kafka
+//! retention to be shorter than the period of time needed for aggregation.
+//!
+//! Since the archive and Kafka may contain the same records, the last processed
+//! offset of each partition is tracked in the database by input topic, and records at
+//! or below that offset are skipped. Archived records for partitions without a tracked
+//! offset are skipped, since it cannot be known whether they were already consumed from
+//! Kafka. Older archives do not record the topic of their records, so they can only be
+//! replayed for channels with a single input topic.
+//!
+//! The processed offsets are also tracked without lake-first mode if `AGG_EXACTLY_ONCE`
+//! is enabled, so that records consumed again after an interrupted Kafka commit are skipped.
+//!
+//! Archive objects are recorded by checksum once all of their records have been
+//! processed, so that an interrupted replay of the archive does not read them again.
+
+use super::AggregatorError;
+use crate::lake::{DataLake, MessageArchiveReader};
+use crate::models::{DBConnection, ProcessedArchiveObject, ProcessedOffset};
+use crate::record_stream::{ConsumedRecord, TopicPartition};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+pub struct ProcessedOffsets {
+  channel_name: String,
+  /// Topic & partition mapped to the last processed offset
+  offsets: Mutex<HashMap<TopicPartition, i64>>,
+  /// Partitions with offsets that have not been saved
+  updated_partitions: Mutex<HashSet<TopicPartition>>,
+  /// Warn about gaps between processed Kafka offsets, which indicate that the archive is behind
+  warn_on_gaps: bool,
+}
+
+impl ProcessedOffsets {
+  pub fn new(channel_name: &str, offsets: HashMap<TopicPartition, i64>) -> Self {
+    Self {
+      channel_name: channel_name.to_string(),
+      offsets: Mutex::new(offsets),
+      updated_partitions: Mutex::new(HashSet::new()),
+      warn_on_gaps: true,
+    }
+  }
+
+  /// Disables gap warnings, for offsets that are tracked without the archive.
+  /// Gaps are expected if records are skipped before tracking, i.e. for other tenants.
+  pub fn without_gap_warnings(mut self) -> Self {
+    self.warn_on_gaps = false;
+    self
+  }
+
+  /// Loads the offsets of the channel. Offsets stored without a topic are
+  /// assigned to the input topic, if the channel has a single input topic.
+  pub async fn load(
+    conn: Arc<Mutex<DBConnection>>,
+    channel_name: &str,
+    topics: &[String],
+  ) -> Result<Self, AggregatorError> {
+    let single_topic = match topics {
+      [topic] => Some(topic),
+      _ => None,
+    };
+    let offsets = ProcessedOffset::list(conn, channel_name)
+      .await?
+      .into_iter()
+      .filter_map(|v| {
+        let topic = match v.topic.is_empty() {
+          true => single_topic?.clone(),
+          false => v.topic,
+        };
+        Some(((topic, v.kafka_partition), v.last_offset))
+      })
+      .collect();
+    Ok(Self::new(channel_name, offsets))
+  }
+
+  /// Returns true if the record has not been processed yet, and marks the
+  /// record as processed. Records without a topic, partition & offset are
+  /// always processed, unless they were archived.
+  pub fn check_and_mark(&self, record: &ConsumedRecord, from_archive: bool) -> bool {
+    let (topic_partition, offset) = match (record.topic_partition(), record.offset) {
+      (Some(topic_partition), Some(offset)) => (topic_partition, offset),
+      _ => return !from_archive,
+    };
+    let mut offsets = self.offsets.lock().unwrap();
+    match offsets.get(&topic_partition).copied() {
+      None if from_archive => return false,
+      Some(last_offset) if offset <= last_offset => return false,
+      Some(last_offset) if self.warn_on_gaps && !from_archive && offset > last_offset + 1 => {
+        warn!(
+          "Kafka offset gap in partition {} of {} ({} to {}); the archive may be behind",
+          topic_partition.1, topic_partition.0, last_offset, offset
+        );
+      }
+      _ => (),
+    }
+    offsets.insert(topic_partition.clone(), offset);
+    self
+      .updated_partitions
+      .lock()
+      .unwrap()
+      .insert(topic_partition);
+    true
+  }
+
+  /// Saves the updated offsets. Should be called within the
+  /// transaction that stores the processed messages.
+  pub async fn save(&self, conn: Arc<Mutex<DBConnection>>) -> Result<(), AggregatorError> {
+    let updated_offsets: Vec<ProcessedOffset> = {
+      let offsets = self.offsets.lock().unwrap();
+      self
+        .updated_partitions
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|topic_partition| ProcessedOffset {
+          channel_name: self.channel_name.clone(),
+          last_offset: offsets[&topic_partition],
+          kafka_partition: topic_partition.1,
+          topic: topic_partition.0,
+        })
+        .collect()
+    };
+    if !updated_offsets.is_empty() {
+      ProcessedOffset::upsert_batch(conn, updated_offsets).await?;
+    }
+    Ok(())
+  }
+}
+
+pub struct LakeFirstSource {
+  pub archive_reader: MessageArchiveReader,
+  pub offsets: Arc<ProcessedOffsets>,
+  channel_name: String,
+  /// Topic of archived records that do not record their topic
+  default_topic: Option<String>,
+}
+
+impl LakeFirstSource {
+  pub async fn load(
+    lake: DataLake,
+    conn: Arc<Mutex<DBConnection>>,
+    channel_name: &str,
+    topics: &[String],
+  ) -> Result<Self, AggregatorError> {
+    let offsets = ProcessedOffsets::load(conn.clone(), channel_name, topics).await?;
+    let processed_checksums: HashSet<String> =
+      ProcessedArchiveObject::list_checksums(conn, channel_name)
+        .await?
+        .into_iter()
+        .collect();
+    Ok(Self {
+      archive_reader: MessageArchiveReader::new(lake, channel_name, &processed_checksums).await?,
+      offsets: Arc::new(offsets),
+      channel_name: channel_name.to_string(),
+      default_topic: match topics {
+        [topic] => Some(topic.clone()),
+        _ => None,
+      },
+    })
+  }
+
+  /// Returns the next archived record. Records archived without their topic are
+  /// assigned the input topic, if the channel has a single input topic.
+  pub async fn next_record(&mut self) -> Result<Option<ConsumedRecord>, AggregatorError> {
+    let mut record = self.archive_reader.next().await?;
+    if let Some(record) = record.as_mut() {
+      if record.topic.is_none() {
+        record.topic = self.default_topic.clone();
+      }
+    }
+    Ok(record)
+  }
+
+  /// Saves the updated offsets, and the archive objects that have been fully read.
+  /// Should be called within the transaction that stores the processed messages.
+  pub async fn save(&mut self, conn: Arc<Mutex<DBConnection>>) -> Result<(), AggregatorError> {
+    self.offsets.save(conn.clone()).await?;
+    let completed_objects: Vec<_> = self
+      .archive_reader
+      .take_completed_objects()
+      .into_iter()
+      .map(|v| ProcessedArchiveObject {
+        channel_name: self.channel_name.clone(),
+        checksum: v.checksum().to_string(),
+        object_key: v.key,
+      })
+      .collect();
+    if !completed_objects.is_empty() {
+      ProcessedArchiveObject::insert_batch(conn, completed_objects).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(topic: &str, partition: i32, offset: i64) -> ConsumedRecord {
+    ConsumedRecord {
+      topic: Some(topic.to_string()),
+      partition: Some(partition),
+      offset: Some(offset),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn skip_processed_records() {
+    let offsets =
+      ProcessedOffsets::new("typical", HashMap::from([(("desktop".to_string(), 0), 10)]));
+
+    assert!(!offsets.check_and_mark(&record("desktop", 0, 9), true));
+    assert!(!offsets.check_and_mark(&record("desktop", 0, 10), true));
+    assert!(offsets.check_and_mark(&record("desktop", 0, 11), true));
+    assert!(!offsets.check_and_mark(&record("desktop", 0, 11), false));
+    assert!(offsets.check_and_mark(&record("desktop", 0, 12), false));
+
+    // Archived records for untracked partitions are skipped
+    assert!(!offsets.check_and_mark(&record("desktop", 1, 5), true));
+    assert!(offsets.check_and_mark(&record("desktop", 1, 5), false));
+    assert!(offsets.check_and_mark(&record("desktop", 1, 6), true));
+
+    // Partition numbers of other topics are tracked separately
+    assert!(!offsets.check_and_mark(&record("android", 0, 11), true));
+    assert!(offsets.check_and_mark(&record("android", 0, 2), false));
+    assert!(!offsets.check_and_mark(&record("android", 0, 2), true));
+
+    // Archived records without a topic cannot be matched with a tracked offset
+    let mut untracked = record("desktop", 0, 20);
+    untracked.topic = None;
+    assert!(!offsets.check_and_mark(&untracked, true));
+
+    assert!(offsets.check_and_mark(&ConsumedRecord::default(), false));
+    assert_eq!(
+      *offsets.updated_partitions.lock().unwrap(),
+      HashSet::from([
+        ("desktop".to_string(), 0),
+        ("desktop".to_string(), 1),
+        ("android".to_string(), 0)
+      ])
+    );
+  }
+}
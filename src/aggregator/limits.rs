@@ -0,0 +1,38 @@
+//! Concurrency limits for the phases of aggregation. Each phase is bounded
+//! by a separate semaphore, so that phases can be tuned independently
+//! of the worker count.
+
+use crate::util::parse_env_var;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+const MAX_CONCURRENT_DB_WRITES_ENV_KEY: &str = "AGG_MAX_CONCURRENT_DB_WRITES";
+const MAX_CONCURRENT_RECOVERIES_ENV_KEY: &str = "AGG_MAX_CONCURRENT_RECOVERIES";
+const MAX_CONCURRENT_PRODUCES_ENV_KEY: &str = "AGG_MAX_CONCURRENT_PRODUCES";
+const MAX_CONCURRENT_PRODUCES_DEFAULT: &str = "64";
+
+pub struct ConcurrencyLimits {
+  /// Bounds the tasks writing to the database within a transaction
+  pub db_writes: Semaphore,
+  /// Bounds the tasks recovering keys and measurements
+  pub recoveries: Semaphore,
+  /// Bounds the concurrent sends to the output and dead letter streams
+  pub produces: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimits {
+  /// Loads the limits from the environment. The DB write
+  /// and recovery limits default to the worker count.
+  pub fn from_env(worker_count: usize) -> Self {
+    let worker_count = worker_count.to_string();
+    let limit = |env_key: &str, default: &str| parse_env_var::<usize>(env_key, default).max(1);
+    Self {
+      db_writes: Semaphore::new(limit(MAX_CONCURRENT_DB_WRITES_ENV_KEY, &worker_count)),
+      recoveries: Semaphore::new(limit(MAX_CONCURRENT_RECOVERIES_ENV_KEY, &worker_count)),
+      produces: Arc::new(Semaphore::new(limit(
+        MAX_CONCURRENT_PRODUCES_ENV_KEY,
+        MAX_CONCURRENT_PRODUCES_DEFAULT,
+      ))),
+    }
+  }
+}
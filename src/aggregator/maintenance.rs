@@ -0,0 +1,90 @@
+//! Optional table maintenance after bulk mutations. Aggregation inserts and deletes
+//! large amounts of pending & recovered messages, which can leave the planner statistics
+//! stale for the rest of the run. If enabled, the mutated tables are analyzed and
+//! their dead tuple estimates are logged. Operators that rely on external maintenance
+//! (i.e. autovacuum tuning or scheduled jobs) can leave this disabled.
+
+use super::AggregatorError;
+use crate::models::{DBPool, TableStats};
+use crate::util::parse_env_var;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const DB_ANALYZE_AFTER_MUTATIONS_ENV_KEY: &str = "DB_ANALYZE_AFTER_MUTATIONS";
+const DEFAULT_DB_ANALYZE_AFTER_MUTATIONS: &str = "false";
+/// Dead tuple ratio above which a table is reported as bloated
+const BLOATED_DEAD_RATIO: f64 = 0.2;
+
+fn table_maintenance_enabled() -> bool {
+  parse_env_var::<bool>(
+    DB_ANALYZE_AFTER_MUTATIONS_ENV_KEY,
+    DEFAULT_DB_ANALYZE_AFTER_MUTATIONS,
+  )
+}
+
+/// Analyzes the given tables and logs their bloat stats, if maintenance is enabled.
+/// Must be called after the mutating transactions are committed.
+pub async fn maintain_tables(
+  db_pool: &DBPool,
+  tables: &'static [&'static str],
+) -> Result<(), AggregatorError> {
+  if !table_maintenance_enabled() {
+    return Ok(());
+  }
+  let conn = Arc::new(Mutex::new(db_pool.get().await?));
+  let start_instant = Instant::now();
+  TableStats::analyze(conn.clone(), tables).await?;
+  info!(
+    "Analyzed {} in {:.1}s",
+    tables.join(", "),
+    start_instant.elapsed().as_secs_f64()
+  );
+
+  for stats in TableStats::list(conn, tables).await? {
+    let dead_ratio = stats.dead_ratio();
+    let message = format!(
+      "Table {}: {} live tuples, {} dead tuples ({:.1}% dead)",
+      stats.table_name,
+      stats.live_tuples,
+      stats.dead_tuples,
+      dead_ratio * 100.0
+    );
+    match dead_ratio > BLOATED_DEAD_RATIO {
+      true => warn!("{}; consider vacuuming", message),
+      false => info!("{}", message),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::{DBConnectionType, MAINTAINED_TABLES};
+  use dotenvy::dotenv;
+
+  #[tokio::test]
+  async fn analyze_and_list_stats() {
+    dotenv().ok();
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+
+    TableStats::analyze(conn.clone(), &MAINTAINED_TABLES)
+      .await
+      .unwrap();
+    let stats = TableStats::list(conn, &MAINTAINED_TABLES).await.unwrap();
+    assert_eq!(
+      stats
+        .iter()
+        .map(|v| v.table_name.as_str())
+        .collect::<Vec<_>>(),
+      vec![
+        "epoch_measurements",
+        "measurement_outbox",
+        "pending_msgs",
+        "recovered_msgs"
+      ]
+    );
+    assert!(stats.iter().all(|v| (0.0..=1.0).contains(&v.dead_ratio())));
+  }
+}
@@ -0,0 +1,216 @@
+//! Versioned schema for the aggregated measurement records emitted by the aggregator.
+//! The JSON Schema document describing the record format can be found at
+//! `misc/measurement.schema.json`. Any change to the format of emitted records
+//! must be accompanied by an increment of `OUTPUT_SCHEMA_VERSION` and an update
+//! to the schema document.
+
+use crate::models::SubmissionTimeRange;
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const OUTPUT_SCHEMA_VERSION: u32 = 4;
+
+const SCHEMA_VERSION_FIELD_NAME: &str = "schema_version";
+const TOTAL_FIELD_NAME: &str = "total";
+const DATA_CHANNEL_FIELD_NAME: &str = "data_channel";
+const SAMPLING_RATE_FIELD_NAME: &str = "sampling_rate";
+const FIRST_SUBMITTED_AT_FIELD_NAME: &str = "first_submitted_at";
+const LAST_SUBMITTED_AT_FIELD_NAME: &str = "last_submitted_at";
+
+/// A measurement that does not conform to the output schema.
+#[derive(Error, Display, Debug)]
+#[display(fmt = "measurement failed schema validation: {}", reason)]
+pub struct InvalidMeasurement {
+  pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MeasurementRecord {
+  pub schema_version: u32,
+  /// Name of the data channel that the measurement was collected from
+  pub data_channel: String,
+  /// Fraction of submissions accepted by the server for the channel.
+  /// Totals may be divided by this value to estimate the full population count.
+  pub sampling_rate: f64,
+  pub total: i64,
+  /// Earliest and latest submission times of the counted reports, in RFC 3339 format.
+  /// Omitted if the submission times are unknown.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub first_submitted_at: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub last_submitted_at: Option<String>,
+  /// Recovered attributes, along with the epoch date field
+  #[serde(flatten)]
+  pub fields: BTreeMap<String, Value>,
+}
+
+impl MeasurementRecord {
+  pub fn new(
+    metric_chain: Vec<(String, Value)>,
+    data_channel: &str,
+    sampling_rate: f64,
+    epoch_date_field_name: &str,
+    epoch_start_date: &str,
+    count: i64,
+  ) -> Self {
+    let mut fields = BTreeMap::new();
+    for (name, value) in metric_chain {
+      fields.insert(name, value);
+    }
+    fields.insert(
+      epoch_date_field_name.to_string(),
+      epoch_start_date.to_string().into(),
+    );
+    Self {
+      schema_version: OUTPUT_SCHEMA_VERSION,
+      data_channel: data_channel.to_string(),
+      sampling_rate,
+      total: count,
+      first_submitted_at: None,
+      last_submitted_at: None,
+      fields,
+    }
+  }
+
+  pub fn with_submission_range(mut self, range: &SubmissionTimeRange) -> Self {
+    let format = |time: OffsetDateTime| time.format(&Rfc3339).ok();
+    self.first_submitted_at = range.earliest.and_then(format);
+    self.last_submitted_at = range.latest.and_then(format);
+    self
+  }
+
+  /// Checks that the record conforms to the current output schema.
+  /// Returns a description of the first violation found, if any.
+  pub fn validate(&self, expected_field_count: usize) -> Result<(), String> {
+    if self.schema_version != OUTPUT_SCHEMA_VERSION {
+      return Err(format!("unexpected schema version {}", self.schema_version));
+    }
+    if !(self.sampling_rate > 0.0 && self.sampling_rate <= 1.0) {
+      return Err(format!("invalid sampling rate {}", self.sampling_rate));
+    }
+    if self.total <= 0 {
+      return Err(format!("total must be positive, got {}", self.total));
+    }
+    // If a recovered metric name collides with another field name, the field
+    // count will be lower than expected.
+    if self.fields.len() != expected_field_count {
+      return Err("duplicate field name in measurement".to_string());
+    }
+    for (name, value) in &self.fields {
+      if name.is_empty() {
+        return Err("empty field name in measurement".to_string());
+      }
+      if [
+        SCHEMA_VERSION_FIELD_NAME,
+        DATA_CHANNEL_FIELD_NAME,
+        SAMPLING_RATE_FIELD_NAME,
+        TOTAL_FIELD_NAME,
+        FIRST_SUBMITTED_AT_FIELD_NAME,
+        LAST_SUBMITTED_AT_FIELD_NAME,
+      ]
+      .contains(&name.as_str())
+      {
+        return Err(format!("reserved field name '{}' in measurement", name));
+      }
+      if !value.is_string() {
+        return Err(format!("field '{}' is not a string", name));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn serialize_record() {
+    let record = MeasurementRecord::new(
+      vec![("a".to_string(), "1".into()), ("b".to_string(), "2".into())],
+      "typical",
+      1.0,
+      "wos",
+      "2023-05-01",
+      12,
+    );
+    assert!(record.validate(3).is_ok());
+    assert_eq!(
+      serde_json::to_value(&record).unwrap(),
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "b": "2", "total": 12, "wos": "2023-05-01" })
+    );
+  }
+
+  #[test]
+  fn serialize_submission_range() {
+    let range = SubmissionTimeRange {
+      earliest: OffsetDateTime::from_unix_timestamp(1682899200).ok(),
+      latest: OffsetDateTime::from_unix_timestamp(1682985600).ok(),
+    };
+    let record = MeasurementRecord::new(
+      vec![("a".to_string(), "1".into())],
+      "typical",
+      1.0,
+      "wos",
+      "2023-05-01",
+      12,
+    )
+    .with_submission_range(&range);
+    assert!(record.validate(2).is_ok());
+    let value = serde_json::to_value(&record).unwrap();
+    assert_eq!(value["first_submitted_at"], "2023-05-01T00:00:00Z");
+    assert_eq!(value["last_submitted_at"], "2023-05-02T00:00:00Z");
+    assert_eq!(
+      serde_json::from_value::<MeasurementRecord>(value).unwrap(),
+      record
+    );
+  }
+
+  #[test]
+  fn invalid_records() {
+    let reserved = MeasurementRecord::new(
+      vec![("total".to_string(), "1".into())],
+      "typical",
+      1.0,
+      "wos",
+      "2023-05-01",
+      12,
+    );
+    assert!(reserved.validate(2).is_err());
+
+    let duplicate = MeasurementRecord::new(
+      vec![("wos".to_string(), "1".into())],
+      "typical",
+      1.0,
+      "wos",
+      "2023-05-01",
+      12,
+    );
+    assert!(duplicate.validate(2).is_err());
+
+    let zero_total = MeasurementRecord::new(
+      vec![("a".to_string(), "1".into())],
+      "typical",
+      1.0,
+      "wos",
+      "2023-05-01",
+      0,
+    );
+    assert!(zero_total.validate(2).is_err());
+
+    let zero_sampling_rate = MeasurementRecord::new(
+      vec![("a".to_string(), "1".into())],
+      "typical",
+      0.0,
+      "wos",
+      "2023-05-01",
+      12,
+    );
+    assert!(zero_sampling_rate.validate(2).is_err());
+  }
+}
@@ -1,153 +1,583 @@
+mod collect_only;
+mod columnar;
 mod consume;
+mod dlq_redrive;
+mod epoch_counts;
+mod epoch_export;
+mod epoch_snapshot;
 mod group;
+mod key_cache;
+mod key_export;
+mod knobs;
+mod lake_first;
+mod limits;
+mod maintenance;
+mod measurement;
+mod outbox;
+mod output;
+mod output_file;
+mod output_warehouse;
+mod partition_assignment;
+mod partition_quota;
+mod pending_rewrite;
+mod privacy_report;
 mod processing;
 mod recovered;
 mod report;
+mod run_diff;
+mod run_metadata;
+mod run_summary;
 mod spot;
+mod watermark;
+mod worker_failure;
 
 use crate::aggregator::spot::check_spot_termination_status;
+use crate::canary::{canary_k_threshold, is_canary_channel};
+use crate::channel::get_data_channel_map_from_env;
+use crate::encryption::init_share_encryption;
 use crate::epoch::EpochConfig;
-use crate::models::{DBConnectionType, DBPool, DBStorageConnections, PgStoreError};
+use crate::lake::{DataLake, DataLakeError};
+use crate::memory_watchdog::{memory_pressure, record_intervention, Intervention};
+use crate::models::{
+  DBConnectionType, DBPool, DBStorageConnections, PgStoreError, RecoveredMessage, MAINTAINED_TABLES,
+};
 use crate::profiler::{Profiler, ProfilerStat};
+use crate::progress::{end_phase, start_phase};
+use crate::prometheus::AggregatorMetrics;
 use crate::record_stream::{
-  get_data_channel_topic_from_env, KafkaRecordStream, KafkaRecordStreamConfig, RecordStream,
-  RecordStreamArc, RecordStreamError,
+  get_data_channel_dlq_topic_from_env, get_data_channel_input_topics_from_env,
+  get_data_channel_input_topics_map_from_env, KafkaComponent, KafkaRecordStreamConfig,
+  RecordStreamArc, RecordStreamError, RecordStreamFactory, RecordStreamOptions,
 };
-use crate::star::AppSTARError;
+use crate::star::{AppSTARError, StoredMessageFormat};
+use crate::startup::{wait_for_database, StartupError};
 use crate::util::parse_env_var;
-use consume::consume_and_group;
+use arrow::error::ArrowError;
+use calendar_duration::CalendarDuration;
+pub use collect_only::DATABASE_UNAVAILABLE_EXIT_CODE;
+use collect_only::{collect_only, DatabaseUnavailableAction};
+use consume::{consume_and_group, report_consumer_lag};
 use derive_more::{Display, Error, From};
-use futures::future::try_join_all;
-use processing::{process_expired_epochs, start_subtask};
+use dlq_redrive::redrive_dlq;
+use epoch_counts::EpochCounts;
+use epoch_export::epoch_export_enabled;
+use epoch_snapshot::{effective_config_json, finalized_epochs, record_epoch_configs};
+use key_cache::RecoveredKeyCache;
+use key_export::{EpochKeyExporter, KeyExportError};
+pub use knobs::{configure_knobs_admin, AggregatorKnobs};
+use knobs::{KnobUpdate, KnobValues};
+use lake_first::{LakeFirstSource, ProcessedOffsets};
+use limits::ConcurrencyLimits;
+use maintenance::maintain_tables;
+use measurement::InvalidMeasurement;
+use outbox::OutboxRelay;
+use output::{create_output_sinks, reprocess_lake};
+pub use output::{DynOutputSink, REPROCESS_OUTPUT_PREFIX};
+use output_warehouse::WarehouseError;
+use partition_assignment::{assigned_partitions_from_env, split_partitions, SeekTarget};
+use pending_rewrite::rewrite_pending_msgs;
+use privacy_report::EpochPrivacyReport;
+use processing::{discard_aged_pending_msgs, process_expired_epochs, start_subtask};
+pub use run_diff::diff_runs;
+use run_metadata::RunMetadata;
+use run_summary::RunSummary;
 use star_constellation::Error as ConstellationError;
+use std::collections::HashMap;
 use std::str::Utf8Error;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use time::OffsetDateTime;
 use tokio::task::JoinError;
+use watermark::{EpochCloseCheck, PartitionWatermarks};
+use worker_failure::{join_subtasks, worker_failure_tolerance, WorkerFailures};
 
 pub const DEFAULT_K_THRESHOLD_ENV_KEY: &str = "K_THRESHOLD";
 pub const DEFAULT_K_THRESHOLD_DEFAULT: &str = "50";
 pub const MIN_MSGS_TO_PROCESS_ENV_KEY: &str = "MIN_MSGS_TO_PROCESS";
 pub const MIN_MSGS_TO_PROCESS_DEFAULT: &str = "1000";
+const PENDING_MSG_MAX_AGES_ENV_KEY: &str = "PENDING_MSG_MAX_AGES";
+const STORE_PRIVACY_REPORTS_ENV_KEY: &str = "STORE_PRIVACY_REPORTS";
+const STORE_PRIVACY_REPORTS_DEFAULT: &str = "false";
+
+const EXACTLY_ONCE_ENV_KEY: &str = "AGG_EXACTLY_ONCE";
+const EXACTLY_ONCE_DEFAULT: &str = "false";
 
-const CONSUMER_COUNT: usize = 4;
+const CONSUMER_COUNT_ENV_KEY: &str = "AGGREGATOR_CONSUMER_COUNT";
+const CONSUMER_COUNT_DEFAULT: &str = "4";
 
 #[derive(Error, From, Display, Debug)]
-#[display(fmt = "Aggregator error: {}")]
 pub enum AggregatorError {
+  #[display(fmt = "Aggregator error: {}", "_0")]
   Utf8(Utf8Error),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   AppSTAR(AppSTARError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   Constellation(ConstellationError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   Database(PgStoreError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   RecordStream(RecordStreamError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   Join(JoinError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
   JSONSerialize(serde_json::Error),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  InvalidMeasurement(InvalidMeasurement),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  DataLake(DataLakeError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  Webhook(reqwest::Error),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  FileOutput(std::io::Error),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  Warehouse(WarehouseError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  KeyExport(KeyExportError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  WorkerFailures(WorkerFailures),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  DatabaseUnavailable(StartupError),
+  #[display(fmt = "Aggregator error: {}", "_0")]
+  Columnar(ArrowError),
+  #[display(fmt = "Aggregator error: ThresholdTooBig")]
   ThresholdTooBig,
+  #[display(fmt = "Aggregator error: RefinalizeNotAllowed")]
+  RefinalizeNotAllowed,
+  #[display(fmt = "Aggregator error: SpotTermination")]
   SpotTermination,
+  #[display(fmt = "Aggregator error: IMDSRequestFail")]
   IMDSRequestFail,
+  #[display(fmt = "Aggregator error: invalid configuration: {}", "_0")]
+  #[from(ignore)]
+  InvalidConfig(#[error(ignore)] String),
 }
 
-fn create_output_stream(
-  output_measurements_to_stdout: bool,
-  channel_name: &str,
-) -> Result<Option<RecordStreamArc>, AggregatorError> {
-  let topic = get_data_channel_topic_from_env(true, channel_name);
-  Ok(if output_measurements_to_stdout {
-    None
-  } else {
-    let out_stream = Arc::new(KafkaRecordStream::new(KafkaRecordStreamConfig {
-      enable_producer: true,
-      enable_consumer: false,
-      topic,
-      use_output_group_id: true,
-    }));
-    out_stream.init_producer_transactions()?;
-    Some(out_stream)
-  })
+impl AggregatorError {
+  /// Returns a stable name for the kind of failure, for logs and the run summary.
+  /// Record stream and data lake failures are categorized by their own kind.
+  pub fn category(&self) -> &'static str {
+    match self {
+      Self::Utf8(_) => "record_encoding",
+      Self::AppSTAR(_) => "star",
+      Self::Constellation(_) => "constellation",
+      Self::Database(_) => "database",
+      Self::RecordStream(e) => e.category(),
+      Self::Join(_) => "task_join",
+      Self::JSONSerialize(_) => "serialize",
+      Self::InvalidMeasurement(_) => "invalid_measurement",
+      Self::DataLake(e) => e.category(),
+      Self::Webhook(_) => "webhook",
+      Self::FileOutput(_) => "file_output",
+      Self::Warehouse(_) => "warehouse",
+      Self::KeyExport(_) => "key_export",
+      Self::WorkerFailures(_) => "worker_failures",
+      Self::DatabaseUnavailable(_) => "database_unavailable",
+      Self::Columnar(_) => "columnar",
+      Self::ThresholdTooBig => "threshold_too_big",
+      Self::RefinalizeNotAllowed => "refinalize_not_allowed",
+      Self::SpotTermination => "spot_termination",
+      Self::IMDSRequestFail => "imds_request",
+      Self::InvalidConfig(_) => "invalid_config",
+    }
+  }
+}
+
+/// Returns the k threshold used for messages of the channel
+/// that do not specify a threshold.
+pub fn default_k_threshold(channel_name: &str) -> usize {
+  match is_canary_channel(channel_name) {
+    true => canary_k_threshold(),
+    false => parse_env_var::<usize>(DEFAULT_K_THRESHOLD_ENV_KEY, DEFAULT_K_THRESHOLD_DEFAULT),
+  }
 }
 
-async fn wait_and_commit_producer(out_stream: &RecordStreamArc) -> Result<(), AggregatorError> {
-  info!("Waiting for Kafka producer queues to finish...");
+async fn wait_for_producer(out_stream: &RecordStreamArc) -> Result<(), AggregatorError> {
+  debug!("Waiting for Kafka producer queues to finish...");
   out_stream.join_produce_queues().await?;
 
   check_spot_termination_status(false).await?;
-
-  info!("Committing Kafka output transaction");
-  out_stream.commit_producer_transaction()?;
   Ok(())
 }
 
+/// Returns the suffix of the aggregator consumer group for the input topic of the channel.
+/// Records of other channels, and of other epochs if an epoch is targeted, are skipped
+/// without being processed, but their consumption is committed along with the processed
+/// records. Channels that share the topic with other channels therefore consume it with
+/// their own group, and so do runs that target an epoch.
+fn input_group_id_suffix(
+  channel_topics: &HashMap<String, Vec<String>>,
+  channel_name: &str,
+  topic: &str,
+  target_epoch: Option<u8>,
+) -> Option<String> {
+  let is_shared_topic = channel_topics.iter().any(|(other_channel, topics)| {
+    other_channel != channel_name && topics.iter().any(|v| v == topic)
+  });
+  let mut suffixes = Vec::new();
+  if is_shared_topic {
+    suffixes.push(channel_name.to_string());
+  }
+  if let Some(target_epoch) = target_epoch {
+    suffixes.push(format!("epoch-{}", target_epoch));
+  }
+  (!suffixes.is_empty()).then(|| suffixes.join("-"))
+}
+
+/// Creates the consumer streams of the input topics of the channel, and seeks them
+/// if requested. Returns the streams, along with the configured topic of each stream.
+fn create_in_streams(
+  stream_factory: &RecordStreamFactory,
+  channel_name: &str,
+  target_epoch: Option<u8>,
+  tenant: Option<&str>,
+) -> Result<(Vec<RecordStreamArc>, Vec<String>), AggregatorError> {
+  let channel_topics = get_data_channel_input_topics_map_from_env();
+  let in_stream_topics = get_data_channel_input_topics_from_env(channel_name);
+  let mut in_streams: Vec<RecordStreamArc> = Vec::new();
+  let mut stream_topics = Vec::new();
+  let configured_consumer_count =
+    parse_env_var::<usize>(CONSUMER_COUNT_ENV_KEY, CONSUMER_COUNT_DEFAULT).max(1);
+  let assigned_partitions = assigned_partitions_from_env();
+  let seek_target = SeekTarget::from_env();
+  if seek_target.is_some() && assigned_partitions.is_none() {
+    return Err(AggregatorError::InvalidConfig(
+      "AGGREGATOR_PARTITIONS must be set to seek the consumers".to_string(),
+    ));
+  }
+  if in_stream_topics.len() > 1 && assigned_partitions.is_some() {
+    return Err(AggregatorError::InvalidConfig(
+      "AGGREGATOR_PARTITIONS requires a single input topic".to_string(),
+    ));
+  }
+  for in_stream_topic in in_stream_topics {
+    let group_id_suffix = input_group_id_suffix(
+      &channel_topics,
+      channel_name,
+      &in_stream_topic,
+      target_epoch,
+    );
+    if let Some(group_id_suffix) = group_id_suffix.as_ref() {
+      info!(
+        "Consuming {} with the consumer group suffixed with {}",
+        in_stream_topic, group_id_suffix
+      );
+    }
+    let consumer_assignments: Vec<Option<Vec<i32>>> = match assigned_partitions.as_ref() {
+      Some(partitions) => split_partitions(partitions, configured_consumer_count)
+        .into_iter()
+        .map(Some)
+        .collect(),
+      None => {
+        let consumer_count = stream_factory.consumer_count(
+          KafkaComponent::Aggregator,
+          &in_stream_topic,
+          tenant,
+          configured_consumer_count,
+        );
+        vec![None; consumer_count]
+      }
+    };
+    for assigned_partitions in consumer_assignments {
+      stream_topics.push(in_stream_topic.clone());
+      in_streams.push(stream_factory.create(
+        KafkaRecordStreamConfig {
+          component: KafkaComponent::Aggregator,
+          enable_producer: false,
+          enable_consumer: true,
+          topic: in_stream_topic.clone(),
+          use_output_group_id: false,
+          group_id_suffix: group_id_suffix.clone(),
+          tenant: tenant.map(|v| v.to_string()),
+        },
+        RecordStreamOptions {
+          assigned_partitions,
+          ..Default::default()
+        },
+      ));
+    }
+  }
+  if let Some(seek_target) = seek_target {
+    seek_target.apply(&in_streams)?;
+  }
+
+  Ok((in_streams, stream_topics))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn start_aggregation(
   channel_name: &str,
-  worker_count: usize,
-  msg_collect_count: usize,
+  knobs: Arc<AggregatorKnobs>,
+  metrics: Arc<AggregatorMetrics>,
+  iterations: usize,
+  output_measurements_to_stdout: bool,
+  output_measurements_to_dir: Option<&str>,
+  epoch_config: Arc<EpochConfig>,
+  tenant: Option<&str>,
+  target_epoch: Option<u8>,
+  lake_first: bool,
+  force_epoch_finalization: bool,
+  warm_start: bool,
+  allow_refinalize: bool,
+) -> Result<(), AggregatorError> {
+  let mut summary = RunSummary::new(channel_name);
+  let result = aggregate(
+    channel_name,
+    knobs,
+    metrics,
+    iterations,
+    output_measurements_to_stdout,
+    output_measurements_to_dir,
+    epoch_config,
+    tenant,
+    target_epoch,
+    lake_first,
+    force_epoch_finalization,
+    warm_start,
+    allow_refinalize,
+    &mut summary,
+  )
+  .await;
+  summary.finish(&result);
+  let write_result = summary.write(tenant).await;
+  // The error of the run takes precedence over a failure to write the summary
+  result?;
+  write_result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn aggregate(
+  channel_name: &str,
+  knobs: Arc<AggregatorKnobs>,
+  metrics: Arc<AggregatorMetrics>,
   iterations: usize,
   output_measurements_to_stdout: bool,
+  output_measurements_to_dir: Option<&str>,
   epoch_config: Arc<EpochConfig>,
+  tenant: Option<&str>,
+  target_epoch: Option<u8>,
+  lake_first: bool,
+  force_epoch_finalization: bool,
+  warm_start: bool,
+  allow_refinalize: bool,
+  summary: &mut RunSummary,
 ) -> Result<(), AggregatorError> {
   info!("Current epoch is {}", epoch_config.current_epoch.epoch);
+  if allow_refinalize {
+    warn!("Reprocessing of finalized epochs is allowed, output will be sent to the reprocessing destinations");
+  }
 
-  let default_k_threshold =
-    parse_env_var::<usize>(DEFAULT_K_THRESHOLD_ENV_KEY, DEFAULT_K_THRESHOLD_DEFAULT);
+  let default_k_threshold = default_k_threshold(channel_name);
+  let config_json = effective_config_json(&epoch_config, default_k_threshold);
+  let run_metadata = RunMetadata::new(&epoch_config, default_k_threshold, allow_refinalize);
+  info!("Run ID is {}", run_metadata.run_id);
+  summary.run_id = Some(run_metadata.run_id.clone());
   let min_msgs_to_process =
     parse_env_var::<usize>(MIN_MSGS_TO_PROCESS_ENV_KEY, MIN_MSGS_TO_PROCESS_DEFAULT);
 
-  let db_pool = Arc::new(DBPool::new(DBConnectionType::Normal { channel_name }));
+  if let Err(e) = wait_for_database(channel_name, tenant).await {
+    if DatabaseUnavailableAction::from_env() == DatabaseUnavailableAction::Exit {
+      return Err(AggregatorError::DatabaseUnavailable(e));
+    }
+    warn!(
+      "{}; collecting messages without persisting them or committing consumption",
+      e
+    );
+    let stream_factory = RecordStreamFactory::from_env();
+    let (in_streams, in_stream_topics) =
+      create_in_streams(&stream_factory, channel_name, target_epoch, tenant)?;
+    return collect_only(
+      &in_streams,
+      &in_stream_topics,
+      channel_name,
+      target_epoch,
+      knobs.values().msg_collect_count,
+      iterations,
+      default_k_threshold,
+      summary,
+    )
+    .await;
+  }
+
+  let db_pool = Arc::new(DBPool::new(DBConnectionType::Normal {
+    channel_name,
+    tenant,
+  }));
+  init_share_encryption().await;
 
   info!("Starting aggregation...");
 
-  let out_stream = create_output_stream(output_measurements_to_stdout, channel_name)?;
+  let limits = Arc::new(ConcurrencyLimits::from_env(knobs.values().worker_count));
+  let stream_factory = RecordStreamFactory::from_env();
+  info!("Using {} record stream backend", stream_factory.backend());
+  let output_sinks = create_output_sinks(
+    output_measurements_to_stdout,
+    output_measurements_to_dir,
+    channel_name,
+    &epoch_config.epoch_date_field_name,
+    tenant,
+    &db_pool,
+    &limits,
+    &run_metadata,
+    &stream_factory,
+  )?;
+
+  let topics = get_data_channel_input_topics_from_env(channel_name);
+  let (in_streams, in_stream_topics) =
+    create_in_streams(&stream_factory, channel_name, target_epoch, tenant)?;
+
+  // Measurements may remain in the outbox if a previous run was interrupted,
+  // which will be published by the relay's first flush
+  let outbox_relay = OutboxRelay::start(db_pool.clone(), channel_name, output_sinks);
+
+  let mut lake_first_source = None;
+  if lake_first {
+    info!("Lake-first mode enabled, reading message archive");
+    let lake = DataLake::new(tenant.map(|v| v.to_string()), None);
+    lake_first_source = Some(
+      LakeFirstSource::load(
+        lake,
+        Arc::new(Mutex::new(db_pool.get().await?)),
+        channel_name,
+        &topics,
+      )
+      .await?,
+    );
+  }
+  let mut processed_offsets = None;
+  if !lake_first && parse_env_var::<bool>(EXACTLY_ONCE_ENV_KEY, EXACTLY_ONCE_DEFAULT) {
+    match target_epoch {
+      // Target epoch runs consume with their own consumer group, and would mark
+      // the skipped records of other epochs as processed for regular runs
+      Some(_) => warn!("Exactly-once processing is not supported in target epoch mode"),
+      None => {
+        info!("Exactly-once processing enabled, loading processed offsets");
+        let conn = Arc::new(Mutex::new(db_pool.get().await?));
+        let offsets = ProcessedOffsets::load(conn, channel_name, &topics)
+          .await?
+          .without_gap_warnings();
+        processed_offsets = Some(Arc::new(offsets));
+      }
+    }
+  }
 
-  let mut in_streams: Vec<RecordStreamArc> = Vec::new();
-  let in_stream_topic = get_data_channel_topic_from_env(false, channel_name);
-  for _ in 0..CONSUMER_COUNT {
-    in_streams.push(Arc::new(KafkaRecordStream::new(KafkaRecordStreamConfig {
-      enable_producer: false,
-      enable_consumer: true,
-      topic: in_stream_topic.clone(),
-      use_output_group_id: false,
-    })));
+  let mut key_cache = None;
+  if warm_start {
+    // Load recovered messages for the epochs that may be aggregated
+    let conn = Arc::new(Mutex::new(db_pool.get().await?));
+    let epochs: Vec<u8> = match target_epoch {
+      Some(epoch) => vec![epoch],
+      None => RecoveredMessage::list_distinct_epochs(conn.clone(), channel_name)
+        .await?
+        .into_iter()
+        .map(|epoch| epoch as u8)
+        .filter(|epoch| !epoch_config.is_epoch_expired(*epoch))
+        .collect(),
+    };
+    let start_instant = Instant::now();
+    let cache = RecoveredKeyCache::default();
+    let loaded_count = cache
+      .load(conn, channel_name, &epochs, Arc::new(Profiler::default()))
+      .await?;
+    info!(
+      "Warm start: loaded {} recovered messages for {} epochs in {:.1}s",
+      loaded_count,
+      epochs.len(),
+      start_instant.elapsed().as_secs_f64()
+    );
+    key_cache = Some(Arc::new(cache));
   }
 
+  let watermarks = PartitionWatermarks::from_env().map(Arc::new);
+
   for i in 0..iterations {
     let profiler = Arc::new(Profiler::default());
+    if memory_pressure() {
+      let msg_collect_count = knobs.values().msg_collect_count;
+      if msg_collect_count > 1 {
+        let shrunk_values = knobs
+          .update(&KnobUpdate {
+            msg_collect_count: Some(msg_collect_count / 2),
+            ..Default::default()
+          })
+          .expect("halved collect count should be positive");
+        info!(
+          "Reduced collect count to {} due to memory pressure",
+          shrunk_values.msg_collect_count
+        );
+        record_intervention(Intervention::ShrinkCollectCount);
+      }
+    }
+    let KnobValues {
+      worker_count,
+      msg_collect_count,
+      ..
+    } = knobs.values();
 
     info!("Starting iteration {}", i);
 
-    if let Some(out_stream) = out_stream.as_ref() {
-      out_stream.init_producer_queues().await;
-    }
-
     info!("Consuming messages from stream");
     let download_start_instant = Instant::now();
+    start_phase("consume", Some(msg_collect_count as u64));
     // Consume & group as much data from Kafka as possible
-    let (grouped_msgs, count) =
-      consume_and_group(&in_streams, msg_collect_count, default_k_threshold).await?;
-
+    let (grouped_msgs, count) = consume_and_group(
+      &in_streams,
+      &in_stream_topics,
+      lake_first_source.as_mut(),
+      processed_offsets.clone(),
+      channel_name,
+      target_epoch,
+      msg_collect_count,
+      default_k_threshold,
+      watermarks.clone(),
+    )
+    .await?;
+    end_phase();
+
+    // Collection may also end early due to memory pressure
+    if count < msg_collect_count && !memory_pressure() {
+      summary.drained_early = true;
+    }
     if count == 0 {
       info!("No messages consumed");
       break;
     }
     info!("Consumed {} messages", count);
+    summary.consumed_count += count;
 
     if count < min_msgs_to_process {
       info!("Message count too low, finished aggregation");
       break;
     }
 
+    let processed_epochs: Vec<u8> = grouped_msgs.msg_chunks.keys().copied().collect();
+    let epoch_message_counts = grouped_msgs.epoch_message_counts();
+    let refinalized_epochs = finalized_epochs(
+      Arc::new(Mutex::new(db_pool.get().await?)),
+      &epoch_config,
+      &processed_epochs,
+    )
+    .await?;
+    if !refinalized_epochs.is_empty() {
+      if !allow_refinalize {
+        error!(
+          "Consumed messages for finalized epochs {:?}; use --allow-refinalize to reprocess them",
+          refinalized_epochs
+        );
+        return Err(AggregatorError::RefinalizeNotAllowed);
+      }
+      warn!("Reprocessing finalized epochs {:?}", refinalized_epochs);
+    }
+
     profiler
       .record_total_time(ProfilerStat::DownloadTime, download_start_instant)
       .await;
 
-    if let Some(out_stream) = out_stream.as_ref() {
-      out_stream.begin_producer_transaction()?;
-    }
-
     // Split message tags/grouped messages into multiple chunks
     // Process each one in a separate task/thread
     let mut tasks = Vec::new();
 
     let processing_start_instant = Instant::now();
+    start_phase("process", Some(grouped_msgs.tag_count() as u64));
 
     let store_conns = Arc::new(DBStorageConnections::new(&db_pool, false).await?);
 
@@ -157,43 +587,86 @@ pub async fn start_aggregation(
         id,
         store_conns.clone(),
         db_pool.clone(),
-        out_stream.clone(),
+        true,
         grouped_msgs,
         epoch_config.clone(),
+        limits.clone(),
+        key_cache.clone(),
         profiler.clone(),
       ));
     }
 
-    let measurement_counts = tokio::select! {
-      measurement_counts_res = try_join_all(tasks) => {
-        measurement_counts_res?
-      },
+    let (measurement_counts, failures) = tokio::select! {
+      join_res = join_subtasks(tasks) => join_res,
       termination_res = check_spot_termination_status(true) => {
         return Err(termination_res.unwrap_err());
       }
     };
 
+    end_phase();
+
+    if !failures.is_empty() {
+      for failure in &failures {
+        error!("Iteration {}: {}", i, failure);
+      }
+      let tolerance = worker_failure_tolerance();
+      if failures.len() > tolerance {
+        return Err(WorkerFailures(failures).into());
+      }
+      // Storage transactions and consumption are not committed, so the messages
+      // of the iteration will be consumed again by the next run
+      warn!(
+        "{} tasks failed, within the tolerance of {}; rolling back iteration {} and skipping remaining iterations",
+        failures.len(),
+        tolerance,
+        i
+      );
+      summary.failed_task_count = failures.len();
+      break;
+    }
+
     let total_measurement_count = measurement_counts.iter().map(|(c, _)| c).sum::<i64>();
     let total_error_count = measurement_counts.iter().map(|(_, e)| e).sum::<usize>();
 
-    if let Some(out_stream) = out_stream.as_ref() {
-      wait_and_commit_producer(out_stream).await?;
+    if let Some(lake_first_source) = lake_first_source.as_mut() {
+      lake_first_source.save(store_conns.get()).await?;
+    }
+    if let Some(processed_offsets) = processed_offsets.as_ref() {
+      processed_offsets.save(store_conns.get()).await?;
     }
+    record_epoch_configs(
+      store_conns.get(),
+      channel_name,
+      &config_json,
+      processed_epochs,
+    )
+    .await?;
 
     info!("Committing DB transactions");
     store_conns.commit()?;
 
+    outbox_relay.notify();
+
     // Commit consumption to Kafka cluster, to mark messages as "already read"
     info!("Committing Kafka consumption");
     for in_stream in &in_streams {
       in_stream.commit_last_consume().await.unwrap();
     }
+    if let Some(watermarks) = watermarks.as_ref() {
+      watermarks.commit();
+    }
+
+    maintain_tables(&db_pool, &MAINTAINED_TABLES).await?;
 
     profiler
       .record_total_time(ProfilerStat::TotalProcessingTime, processing_start_instant)
       .await;
 
     info!("Reported {} final measurements", total_measurement_count);
+    summary.iterations += 1;
+    summary.measurement_count += total_measurement_count;
+    summary.recovery_error_count += total_error_count;
+    summary.add_epoch_counts(epoch_message_counts);
     if total_error_count > 0 {
       error!(
         "Failed to recover {} measurements due to bincode deserialization errors",
@@ -202,17 +675,260 @@ pub async fn start_aggregation(
     }
 
     info!("Profiler summary:\n{}", profiler.summary().await);
+
+    let uncommitted_count = report_consumer_lag(&in_streams, channel_name, &metrics)?;
+    if let Some(uncommitted_count) = uncommitted_count {
+      info!("{} records remaining in the input topic", uncommitted_count);
+    }
+    if target_epoch.is_some() && uncommitted_count == Some(0) {
+      // Further iterations would only wait for the consume timeouts
+      info!("Drained the input backlog for the target epoch, finished aggregation");
+      break;
+    }
+
+    let iteration_delay = knobs.iteration_delay();
+    if i + 1 < iterations && !iteration_delay.is_zero() {
+      info!(
+        "Waiting {:.1}s before the next iteration",
+        iteration_delay.as_secs_f64()
+      );
+      tokio::time::sleep(iteration_delay).await;
+    }
   }
 
+  info!("{}", summary);
+
   // Check for expired epochs. Send off partial measurements.
   // Delete pending/recovered messages from DB.
   info!("Checking/processing expired epochs");
   let profiler = Arc::new(Profiler::default());
-  let out_stream = create_output_stream(output_measurements_to_stdout, channel_name)?;
   let db_conn = Arc::new(Mutex::new(db_pool.get().await?));
-  process_expired_epochs(db_conn.clone(), &epoch_config, out_stream, profiler.clone()).await?;
+
+  if let Some(max_age) = get_data_channel_map_from_env(PENDING_MSG_MAX_AGES_ENV_KEY, "")
+    .get(channel_name)
+    .map(|v| CalendarDuration::from(v.as_str()))
+  {
+    info!("Discarding pending messages older than {}", max_age);
+    let dlq_stream = get_data_channel_dlq_topic_from_env(channel_name).map(|topic| {
+      stream_factory.create(
+        KafkaRecordStreamConfig {
+          component: KafkaComponent::Aggregator,
+          enable_producer: true,
+          enable_consumer: false,
+          topic,
+          use_output_group_id: false,
+          group_id_suffix: None,
+          tenant: tenant.map(|v| v.to_string()),
+        },
+        RecordStreamOptions {
+          send_limit: Some(limits.produces.clone()),
+          ..Default::default()
+        },
+      )
+    });
+    let discarded_count = discard_aged_pending_msgs(
+      db_conn.clone(),
+      &epoch_config,
+      max_age,
+      dlq_stream.as_deref(),
+      profiler.clone(),
+    )
+    .await?;
+    info!("Discarded {} aged pending messages", discarded_count);
+    summary.discarded_count = discarded_count;
+  }
+
+  let privacy_report_lake =
+    parse_env_var::<bool>(STORE_PRIVACY_REPORTS_ENV_KEY, STORE_PRIVACY_REPORTS_DEFAULT).then(
+      || {
+        reprocess_lake(
+          DataLake::new(tenant.map(|v| v.to_string()), None),
+          allow_refinalize,
+        )
+      },
+    );
+  let export_lake = epoch_export_enabled().then(|| {
+    reprocess_lake(
+      DataLake::new(tenant.map(|v| v.to_string()), None),
+      allow_refinalize,
+    )
+  });
+  let key_exporter = EpochKeyExporter::from_env(reprocess_lake(
+    DataLake::new(tenant.map(|v| v.to_string()), None),
+    allow_refinalize,
+  ));
+  let close_check = match watermarks.as_ref() {
+    Some(watermarks) => {
+      let mut positions = Vec::new();
+      for (in_stream, topic) in in_streams.iter().zip(&in_stream_topics) {
+        positions.extend(
+          in_stream
+            .partition_positions()?
+            .into_iter()
+            .map(|v| (topic.clone(), v)),
+        );
+      }
+      let low_watermark = watermarks.low_watermark(&positions, OffsetDateTime::now_utc());
+      match low_watermark {
+        Some(low_watermark) => info!("Record low-watermark is {}", low_watermark),
+        None => info!("Record low-watermark is unknown"),
+      }
+      Some(EpochCloseCheck::new(low_watermark))
+    }
+    None => None,
+  };
+  let finalized_epochs = match summary.failed_task_count {
+    0 => {
+      process_expired_epochs(
+        &db_pool,
+        &epoch_config,
+        &config_json,
+        force_epoch_finalization,
+        &limits,
+        Some(&outbox_relay),
+        privacy_report_lake.as_ref(),
+        export_lake.as_ref(),
+        key_exporter.as_ref(),
+        close_check.as_ref(),
+        profiler.clone(),
+      )
+      .await?
+    }
+    _ => {
+      // The messages of the rolled back iteration will be consumed again by the next run,
+      // so their epochs must not be finalized before then
+      warn!("Skipping finalization of expired epochs, since tasks of the last iteration failed");
+      Vec::new()
+    }
+  };
+  for (epoch, duration) in finalized_epochs {
+    summary.epoch_finalized(epoch, duration);
+  }
   info!("Profiler summary:\n{}", profiler.summary().await);
 
+  info!("Waiting for outbox relay to finish...");
+  let produced_count = outbox_relay.finish().await?;
+  info!("Outbox relay sent {} measurements", produced_count);
+  summary.published_count = produced_count;
+
+  // Expired epoch processing and outbox pruning delete messages in bulk
+  maintain_tables(&db_pool, &MAINTAINED_TABLES).await?;
+
   info!("Finished aggregation");
   Ok(())
 }
+
+/// Prints the privacy report for an epoch, using the current state of the database.
+pub async fn print_privacy_report(
+  epoch_config: &EpochConfig,
+  epoch: u8,
+  tenant: Option<&str>,
+) -> Result<(), AggregatorError> {
+  let db_pool = DBPool::new(DBConnectionType::Normal {
+    channel_name: &epoch_config.channel_name,
+    tenant,
+  });
+  let db_conn = Arc::new(Mutex::new(db_pool.get().await?));
+  let profiler = Arc::new(Profiler::default());
+  let report = EpochPrivacyReport::generate(db_conn, epoch_config, epoch, profiler).await?;
+  println!("{}", serde_json::to_string_pretty(&report)?);
+  Ok(())
+}
+
+/// Prints the message counts of each epoch as JSON lines, using the current state of the database.
+pub async fn print_epoch_counts(
+  epoch_config: &EpochConfig,
+  tenant: Option<&str>,
+) -> Result<(), AggregatorError> {
+  let db_pool = DBPool::new(DBConnectionType::Normal {
+    channel_name: &epoch_config.channel_name,
+    tenant,
+  });
+  let db_conn = Arc::new(Mutex::new(db_pool.get().await?));
+  for counts in EpochCounts::generate(db_conn, epoch_config).await? {
+    println!("{}", serde_json::to_string(&counts)?);
+  }
+  Ok(())
+}
+
+/// Exports the recovered keys of an epoch to the lake, using the current state of the database.
+pub async fn export_epoch_keys(
+  epoch_config: &EpochConfig,
+  epoch: u8,
+  tenant: Option<&str>,
+) -> Result<(), AggregatorError> {
+  let key_exporter = EpochKeyExporter::from_env(DataLake::new(tenant.map(|v| v.to_string()), None))
+    .ok_or(KeyExportError::NotConfigured)?;
+  let db_pool = DBPool::new(DBConnectionType::Normal {
+    channel_name: &epoch_config.channel_name,
+    tenant,
+  });
+  let db_conn = Arc::new(Mutex::new(db_pool.get().await?));
+  let profiler = Arc::new(Profiler::default());
+  key_exporter
+    .export(db_conn, epoch_config, epoch, profiler)
+    .await?;
+  Ok(())
+}
+
+/// Re-drives the dead letter topic of the main channel, and prints the summary as JSON.
+pub async fn redrive_dead_letters(
+  epoch_config: &EpochConfig,
+  tenant: Option<&str>,
+) -> Result<(), AggregatorError> {
+  let summary = redrive_dlq(epoch_config, tenant).await?;
+  println!("{}", serde_json::to_string(&summary)?);
+  Ok(())
+}
+
+/// Rewrites the pending messages of the main channel that are stored in formats other
+/// than the configured format, and prints the summary as JSON.
+pub async fn rewrite_pending_messages(
+  channel_name: &str,
+  tenant: Option<&str>,
+) -> Result<(), AggregatorError> {
+  let db_pool = DBPool::new(DBConnectionType::Normal {
+    channel_name,
+    tenant,
+  });
+  let db_conn = Arc::new(Mutex::new(db_pool.get().await?));
+  let summary =
+    rewrite_pending_msgs(db_conn, channel_name, StoredMessageFormat::from_env()).await?;
+  println!("{}", serde_json::to_string(&summary)?);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn input_group_id_suffixes() {
+    let channel_topics = HashMap::from([
+      ("typical".to_string(), vec!["p3a-star-enc".to_string()]),
+      (
+        "express".to_string(),
+        vec![
+          "p3a-star-enc".to_string(),
+          "p3a-star-enc-express".to_string(),
+        ],
+      ),
+    ]);
+    assert_eq!(
+      input_group_id_suffix(&channel_topics, "express", "p3a-star-enc-express", None),
+      None
+    );
+    assert_eq!(
+      input_group_id_suffix(&channel_topics, "express", "p3a-star-enc", None).as_deref(),
+      Some("express")
+    );
+    assert_eq!(
+      input_group_id_suffix(&channel_topics, "express", "p3a-star-enc-express", Some(5)).as_deref(),
+      Some("epoch-5")
+    );
+    assert_eq!(
+      input_group_id_suffix(&channel_topics, "typical", "p3a-star-enc", Some(5)).as_deref(),
+      Some("typical-epoch-5")
+    );
+  }
+}
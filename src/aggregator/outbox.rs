@@ -0,0 +1,271 @@
+//! Measurement outbox. Reported measurements are stored in the database within
+//! the transaction that updates recovered message counts, so that measurements
+//! are never marked as reported without being stored for output, or vice versa.
+//!
+//! A relay task publishes committed outbox measurements to the output sinks,
+//! and marks them as sent once delivery is confirmed. Each measurement is produced
+//! with its outbox id as the record key, so that consumers can discard the duplicates
+//! that may be produced if the process is interrupted before the marking.
+
+use super::epoch_export::epoch_export_enabled;
+use super::output::{send_to_sinks, OutputSinks};
+use super::AggregatorError;
+use crate::models::{
+  BatchInsert, DBConnection, DBPool, NewEpochMeasurement, NewOutboxMeasurement, OutboxMeasurement,
+};
+use crate::profiler::{Profiler, ProfilerStat};
+use crate::util::parse_env_var;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const OUTPUT_BATCH_SIZE_ENV_KEY: &str = "OUTPUT_BATCH_SIZE";
+const DEFAULT_OUTPUT_BATCH_SIZE: &str = "10000";
+const OUTBOX_RELAY_INTERVAL_SECS_ENV_KEY: &str = "OUTBOX_RELAY_INTERVAL_SECS";
+const DEFAULT_OUTBOX_RELAY_INTERVAL_SECS: &str = "10";
+const OUTBOX_SENT_RETENTION_HOURS_ENV_KEY: &str = "OUTBOX_SENT_RETENTION_HOURS";
+const DEFAULT_OUTBOX_SENT_RETENTION_HOURS: &str = "24";
+const INSERT_BATCH_SIZE: usize = 10000;
+
+/// Measurements reported during processing, which have not been stored in the outbox yet
+pub type MeasurementBuffer = Mutex<Vec<Vec<u8>>>;
+
+/// Stores the reported measurements of the epoch in the outbox. If finalized epochs
+/// are exported, the measurements are also retained until the epoch is exported.
+pub async fn store_outbox_measurements(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  epoch: u8,
+  buffer: MeasurementBuffer,
+  profiler: Arc<Profiler>,
+) -> Result<(), AggregatorError> {
+  let measurements: Vec<NewOutboxMeasurement> = buffer
+    .into_inner()
+    .unwrap()
+    .into_iter()
+    .map(|measurement| NewOutboxMeasurement {
+      channel_name: channel_name.to_string(),
+      measurement,
+    })
+    .collect();
+  for measurements in measurements.chunks(INSERT_BATCH_SIZE) {
+    measurements
+      .to_vec()
+      .insert_batch(conn.clone(), profiler.clone())
+      .await?;
+  }
+  if epoch_export_enabled() {
+    let measurements: Vec<NewEpochMeasurement> = measurements
+      .into_iter()
+      .map(|v| NewEpochMeasurement {
+        channel_name: v.channel_name,
+        epoch: epoch as i16,
+        measurement: v.measurement,
+      })
+      .collect();
+    for measurements in measurements.chunks(INSERT_BATCH_SIZE) {
+      measurements
+        .to_vec()
+        .insert_batch(conn.clone(), profiler.clone())
+        .await?;
+    }
+  }
+  Ok(())
+}
+
+/// Sends the unsent measurements in the outbox to the sinks, and returns the amount of
+/// sent measurements. Each batch is marked as sent once all sinks have accepted it.
+/// The connection must not be within a DB transaction.
+pub async fn flush_outbox(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  sinks: &OutputSinks,
+  profiler: Arc<Profiler>,
+) -> Result<usize, AggregatorError> {
+  let batch_size = parse_env_var::<i64>(OUTPUT_BATCH_SIZE_ENV_KEY, DEFAULT_OUTPUT_BATCH_SIZE);
+  let mut produced_count = 0;
+  loop {
+    let batch =
+      OutboxMeasurement::list_batch(conn.clone(), channel_name, batch_size, profiler.clone())
+        .await?;
+    let max_id = match batch.last() {
+      Some(measurement) => measurement.id,
+      None => break,
+    };
+    let batch_len = batch.len();
+
+    let start_instant = Instant::now();
+    send_to_sinks(sinks, &batch).await?;
+    profiler
+      .record_range_time(ProfilerStat::OutStreamProduceTime, start_instant)
+      .await;
+
+    // If the process is interrupted before the marking, the batch
+    // will be produced again when the outbox is next flushed.
+    OutboxMeasurement::mark_sent_up_to(conn.clone(), channel_name, max_id, profiler.clone())
+      .await?;
+    produced_count += batch_len;
+    debug!("Flushed {} measurements from outbox", batch_len);
+  }
+  Ok(produced_count)
+}
+
+/// Deletes sent measurements that are older than the configured retention period.
+pub async fn prune_sent_measurements(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  profiler: Arc<Profiler>,
+) -> Result<usize, AggregatorError> {
+  let retention_hours = parse_env_var::<u64>(
+    OUTBOX_SENT_RETENTION_HOURS_ENV_KEY,
+    DEFAULT_OUTBOX_SENT_RETENTION_HOURS,
+  );
+  let cutoff = OffsetDateTime::now_utc() - Duration::from_secs(retention_hours * 3600);
+  Ok(OutboxMeasurement::delete_sent_before(conn, channel_name, cutoff, profiler).await?)
+}
+
+/// Background task that periodically flushes the outbox to the output sinks.
+/// The relay can be notified after a DB transaction is committed,
+/// so that new measurements are published without waiting for the next interval.
+pub struct OutboxRelay {
+  notify: Arc<Notify>,
+  cancel_token: CancellationToken,
+  handle: JoinHandle<Result<usize, AggregatorError>>,
+}
+
+impl OutboxRelay {
+  pub fn start(db_pool: Arc<DBPool>, channel_name: &str, sinks: OutputSinks) -> Self {
+    let interval = Duration::from_secs(parse_env_var::<u64>(
+      OUTBOX_RELAY_INTERVAL_SECS_ENV_KEY,
+      DEFAULT_OUTBOX_RELAY_INTERVAL_SECS,
+    ));
+    let notify = Arc::new(Notify::new());
+    let cancel_token = CancellationToken::new();
+    let channel_name = channel_name.to_string();
+
+    let task_notify = notify.clone();
+    let task_cancel_token = cancel_token.clone();
+    let handle = tokio::spawn(async move {
+      let profiler = Arc::new(Profiler::default());
+      let mut produced_count = 0;
+      loop {
+        let conn = Arc::new(Mutex::new(db_pool.get().await?));
+        produced_count +=
+          flush_outbox(conn.clone(), &channel_name, &sinks, profiler.clone()).await?;
+        if task_cancel_token.is_cancelled() {
+          let pruned_count = prune_sent_measurements(conn, &channel_name, profiler).await?;
+          debug!("Pruned {} sent measurements from outbox", pruned_count);
+          return Ok(produced_count);
+        }
+        tokio::select! {
+          _ = task_notify.notified() => (),
+          _ = tokio::time::sleep(interval) => (),
+          _ = task_cancel_token.cancelled() => (),
+        }
+      }
+    });
+    Self {
+      notify,
+      cancel_token,
+      handle,
+    }
+  }
+
+  /// Wakes the relay, so that recently committed measurements are published.
+  pub fn notify(&self) {
+    self.notify.notify_one();
+  }
+
+  /// Stops the relay after a final flush, and returns the
+  /// total amount of measurements produced by the relay.
+  pub async fn finish(self) -> Result<usize, AggregatorError> {
+    self.cancel_token.cancel();
+    self.handle.await?
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::aggregator::output::KafkaOutputSink;
+  use crate::models::{DBConnectionType, DBPool};
+  use crate::record_stream::{RecordStreamArc, TestRecordStream};
+  use dotenvy::dotenv;
+
+  const TEST_CHANNEL_NAME: &str = "typical";
+
+  #[tokio::test]
+  async fn store_and_flush() {
+    dotenv().ok();
+    let profiler = Arc::new(Profiler::default());
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+
+    let buffer = MeasurementBuffer::new(vec![vec![1], vec![2], vec![3]]);
+    store_outbox_measurements(conn.clone(), TEST_CHANNEL_NAME, 1, buffer, profiler.clone())
+      .await
+      .unwrap();
+    store_outbox_measurements(
+      conn.clone(),
+      "other",
+      1,
+      MeasurementBuffer::new(vec![vec![4]]),
+      profiler.clone(),
+    )
+    .await
+    .unwrap();
+
+    let test_stream = Arc::new(TestRecordStream::default());
+    let out_stream: RecordStreamArc = test_stream.clone();
+    let sinks: OutputSinks = vec![Box::new(KafkaOutputSink::new(out_stream).unwrap())];
+    let produced_count = flush_outbox(conn.clone(), TEST_CHANNEL_NAME, &sinks, profiler.clone())
+      .await
+      .unwrap();
+
+    assert_eq!(produced_count, 3);
+    assert_eq!(
+      *test_stream.records_produced.lock().await,
+      vec![vec![1], vec![2], vec![3]]
+    );
+    assert!(
+      OutboxMeasurement::list_batch(conn.clone(), TEST_CHANNEL_NAME, 10, profiler.clone())
+        .await
+        .unwrap()
+        .is_empty()
+    );
+
+    // Sent measurements are retained until the retention period has passed
+    assert_eq!(
+      OutboxMeasurement::delete_sent_before(
+        conn.clone(),
+        TEST_CHANNEL_NAME,
+        OffsetDateTime::now_utc() - Duration::from_secs(3600),
+        profiler.clone()
+      )
+      .await
+      .unwrap(),
+      0
+    );
+    assert_eq!(
+      OutboxMeasurement::delete_sent_before(
+        conn.clone(),
+        TEST_CHANNEL_NAME,
+        OffsetDateTime::now_utc() + Duration::from_secs(1),
+        profiler.clone()
+      )
+      .await
+      .unwrap(),
+      3
+    );
+    assert_eq!(
+      OutboxMeasurement::list_batch(conn, "other", 10, profiler)
+        .await
+        .unwrap()
+        .len(),
+      1
+    );
+  }
+}
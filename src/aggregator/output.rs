@@ -0,0 +1,397 @@
+//! Output sinks for reported measurements. Measurements are published from the
+//! outbox to each configured sink, and a batch is only marked as sent once
+//! all sinks have accepted it. Sinks may receive a batch more than once if a
+//! flush is interrupted, so each measurement is sent along with its outbox id.
+//!
+//! Each batch is attached the metadata of the run that produced it, if supported
+//! by the sink. See the `run_metadata` module for details.
+//!
+//! Reprocessing runs send measurements to separate destinations, so that they are
+//! not mixed with published output: a separate output topic, and the `reprocess`
+//! prefix for the lake and file sinks. Sinks without a separate destination
+//! cannot be used for reprocessing.
+
+use super::limits::ConcurrencyLimits;
+use super::output_file::FileOutputSink;
+use super::output_warehouse::{BigQueryOutputSink, RedshiftOutputSink};
+use super::run_metadata::RunMetadata;
+use super::{wait_for_producer, AggregatorError};
+use crate::lake::DataLake;
+use crate::models::{DBPool, NewOutputMeasurement, OutboxMeasurement};
+use crate::pipeline_io::{observe, Endpoint, Sink};
+use crate::prometheus::IoOperation;
+use crate::record_stream::{
+  get_data_channel_reprocess_topic_from_env, get_data_channel_topic_from_env, KafkaComponent,
+  KafkaRecordStreamConfig, RecordStreamArc, RecordStreamFactory, RecordStreamOptions,
+};
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use reqwest::header::CONTENT_TYPE;
+use std::env;
+use std::path::Path;
+use std::str::from_utf8;
+use std::sync::{Arc, Mutex};
+
+const OUTPUT_SINKS_ENV_KEY: &str = "OUTPUT_SINKS";
+const DEFAULT_OUTPUT_SINKS: &str = "kafka";
+const OUTPUT_WEBHOOK_URL_ENV_KEY: &str = "OUTPUT_WEBHOOK_URL";
+const OUTPUT_FILE_DIR_ENV_KEY: &str = "OUTPUT_FILE_DIR";
+pub const REPROCESS_OUTPUT_PREFIX: &str = "reprocess";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// Header containing the outbox id of the first measurement in a webhook batch
+const WEBHOOK_FIRST_ID_HEADER: &str = "x-first-outbox-id";
+
+#[async_trait]
+pub trait OutputSink {
+  fn name(&self) -> &'static str;
+
+  /// Sends the measurements, and returns once all measurements are delivered.
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError>;
+}
+
+pub type DynOutputSink = dyn OutputSink + Send + Sync;
+pub type OutputSinks = Vec<Box<DynOutputSink>>;
+
+/// Sends the batch to all sinks concurrently.
+pub async fn send_to_sinks(
+  sinks: &OutputSinks,
+  measurements: &[OutboxMeasurement],
+) -> Result<(), AggregatorError> {
+  try_join_all(sinks.iter().map(|sink| {
+    observe(
+      sink.endpoint_name(),
+      IoOperation::Send,
+      Sink::send(sink.as_ref(), measurements),
+    )
+  }))
+  .await?;
+  Ok(())
+}
+
+/// Produces measurements to the channel's output topic, keyed by outbox id.
+/// Each batch is produced within a Kafka transaction, so that consumers reading
+/// committed records never see a partially delivered batch.
+pub struct KafkaOutputSink {
+  out_stream: RecordStreamArc,
+}
+
+impl KafkaOutputSink {
+  pub fn new(out_stream: RecordStreamArc) -> Result<Self, AggregatorError> {
+    out_stream.init_producer_transactions()?;
+    Ok(Self { out_stream })
+  }
+
+  async fn produce_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    self.out_stream.init_producer_queues().await;
+    for measurement in measurements {
+      self
+        .out_stream
+        .queue_produce(
+          measurement.measurement.clone(),
+          Some(measurement.id.to_string()),
+        )
+        .await?;
+    }
+    wait_for_producer(&self.out_stream).await
+  }
+}
+
+#[async_trait]
+impl OutputSink for KafkaOutputSink {
+  fn name(&self) -> &'static str {
+    "kafka"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    self.out_stream.begin_producer_transaction()?;
+    if let Err(e) = self.produce_batch(measurements).await {
+      self.out_stream.abort_producer_transaction()?;
+      return Err(e);
+    }
+    info!("Committing Kafka output transaction");
+    Ok(self.out_stream.commit_producer_transaction()?)
+  }
+}
+
+pub struct StdoutOutputSink;
+
+#[async_trait]
+impl OutputSink for StdoutOutputSink {
+  fn name(&self) -> &'static str {
+    "stdout"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    for measurement in measurements {
+      println!("{}", from_utf8(&measurement.measurement)?);
+    }
+    Ok(())
+  }
+}
+
+/// Applies the reprocessed output prefix to the lake, if `reprocess` is set.
+pub fn reprocess_lake(lake: DataLake, reprocess: bool) -> DataLake {
+  match reprocess {
+    true => lake.with_output_prefix(REPROCESS_OUTPUT_PREFIX),
+    false => lake,
+  }
+}
+
+/// Stores each batch as a JSON lines object in the channel's date partition,
+/// like the lake sink does for the output topic.
+pub struct LakeOutputSink {
+  lake: DataLake,
+  channel_name: String,
+}
+
+#[async_trait]
+impl OutputSink for LakeOutputSink {
+  fn name(&self) -> &'static str {
+    "lake"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    let lines = measurements
+      .iter()
+      .map(|v| from_utf8(&v.measurement))
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(
+      self
+        .lake
+        .store(&self.channel_name, &lines.join("\n"))
+        .await?,
+    )
+  }
+}
+
+/// Inserts measurements into the `output_measurements` table.
+pub struct PostgresOutputSink {
+  db_pool: Arc<DBPool>,
+  channel_name: String,
+}
+
+#[async_trait]
+impl OutputSink for PostgresOutputSink {
+  fn name(&self) -> &'static str {
+    "postgres"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    let measurements = measurements
+      .iter()
+      .map(|v| {
+        Ok(NewOutputMeasurement {
+          channel_name: self.channel_name.clone(),
+          outbox_id: v.id,
+          measurement: from_utf8(&v.measurement)?.to_string(),
+        })
+      })
+      .collect::<Result<Vec<_>, AggregatorError>>()?;
+    let conn = Arc::new(Mutex::new(self.db_pool.get().await?));
+    Ok(NewOutputMeasurement::insert_new_batch(conn, measurements).await?)
+  }
+}
+
+/// Posts each batch to a webhook as newline-delimited JSON.
+/// The run metadata is sent in `x-` prefixed headers.
+pub struct WebhookOutputSink {
+  client: reqwest::Client,
+  url: String,
+  run_metadata: RunMetadata,
+}
+
+#[async_trait]
+impl OutputSink for WebhookOutputSink {
+  fn name(&self) -> &'static str {
+    "webhook"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    let first_id = match measurements.first() {
+      Some(measurement) => measurement.id,
+      None => return Ok(()),
+    };
+    let mut body = Vec::new();
+    for measurement in measurements {
+      body.extend(&measurement.measurement);
+      body.push(b'\n');
+    }
+    let mut request = self
+      .client
+      .post(&self.url)
+      .header(CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+      .header(WEBHOOK_FIRST_ID_HEADER, first_id.to_string());
+    for (name, value) in self.run_metadata.headers() {
+      request = request.header(format!("x-{}", name), value);
+    }
+    request.body(body).send().await?.error_for_status()?;
+    Ok(())
+  }
+}
+
+fn output_dir(dir: &str, reprocess: bool) -> String {
+  match reprocess {
+    true => Path::new(dir)
+      .join(REPROCESS_OUTPUT_PREFIX)
+      .to_string_lossy()
+      .to_string(),
+    false => dir.to_string(),
+  }
+}
+
+/// Creates the sinks listed in `OUTPUT_SINKS`. If measurements should be output
+/// to stdout or a local directory, that will be the only sink. If the run may
+/// reprocess finalized epochs, sinks will use the destinations for reprocessed output.
+#[allow(clippy::too_many_arguments)]
+pub fn create_output_sinks(
+  output_measurements_to_stdout: bool,
+  output_measurements_to_dir: Option<&str>,
+  channel_name: &str,
+  epoch_date_field_name: &str,
+  tenant: Option<&str>,
+  db_pool: &Arc<DBPool>,
+  limits: &ConcurrencyLimits,
+  run_metadata: &RunMetadata,
+  stream_factory: &RecordStreamFactory,
+) -> Result<OutputSinks, AggregatorError> {
+  let reprocess = run_metadata.reprocess;
+  if output_measurements_to_stdout {
+    return Ok(vec![Box::new(StdoutOutputSink)]);
+  }
+  if let Some(dir) = output_measurements_to_dir {
+    return Ok(vec![Box::new(FileOutputSink::new(
+      &output_dir(dir, reprocess),
+      channel_name,
+      epoch_date_field_name,
+    ))]);
+  }
+  let sinks_str = env::var(OUTPUT_SINKS_ENV_KEY).unwrap_or(DEFAULT_OUTPUT_SINKS.to_string());
+  let sinks: OutputSinks = sinks_str
+    .split(',')
+    .map(|v| v.trim())
+    .filter(|v| !v.is_empty())
+    .map(|name| -> Result<Box<DynOutputSink>, AggregatorError> {
+      if reprocess && matches!(name, "postgres" | "webhook" | "bigquery" | "redshift") {
+        panic!(
+          "output sink '{}' cannot separate reprocessed output, and cannot be used for reprocessing",
+          name
+        );
+      }
+      Ok(match name {
+        "kafka" => Box::new(KafkaOutputSink::new(stream_factory.create(
+          KafkaRecordStreamConfig {
+            component: KafkaComponent::Aggregator,
+            enable_producer: true,
+            enable_consumer: false,
+            topic: match reprocess {
+              true => get_data_channel_reprocess_topic_from_env(channel_name),
+              false => get_data_channel_topic_from_env(true, channel_name),
+            },
+            use_output_group_id: true,
+            group_id_suffix: None,
+            tenant: tenant.map(|v| v.to_string()),
+          },
+          RecordStreamOptions {
+            send_limit: Some(limits.produces.clone()),
+            record_headers: run_metadata.kafka_headers(),
+            ..Default::default()
+          },
+        ))?),
+        "stdout" => Box::new(StdoutOutputSink),
+        "lake" => Box::new(LakeOutputSink {
+          lake: reprocess_lake(DataLake::new(tenant.map(|v| v.to_string()), None), reprocess)
+            .with_object_metadata(run_metadata.object_metadata()),
+          channel_name: channel_name.to_string(),
+        }),
+        "postgres" => Box::new(PostgresOutputSink {
+          db_pool: db_pool.clone(),
+          channel_name: channel_name.to_string(),
+        }),
+        "webhook" => Box::new(WebhookOutputSink {
+          client: reqwest::Client::new(),
+          url: env::var(OUTPUT_WEBHOOK_URL_ENV_KEY).unwrap_or_else(|_| {
+            panic!(
+              "{} must be set for the webhook output sink",
+              OUTPUT_WEBHOOK_URL_ENV_KEY
+            )
+          }),
+          run_metadata: run_metadata.clone(),
+        }),
+        "bigquery" => Box::new(BigQueryOutputSink::from_env()?),
+        "redshift" => Box::new(RedshiftOutputSink::from_env(
+          DataLake::new(tenant.map(|v| v.to_string()), None)
+            .with_object_metadata(run_metadata.object_metadata()),
+          channel_name,
+        )?),
+        "file" => Box::new(FileOutputSink::new(
+          &output_dir(
+            &env::var(OUTPUT_FILE_DIR_ENV_KEY).unwrap_or_else(|_| {
+              panic!(
+                "{} must be set for the file output sink",
+                OUTPUT_FILE_DIR_ENV_KEY
+              )
+            }),
+            reprocess,
+          ),
+          channel_name,
+          epoch_date_field_name,
+        )),
+        _ => panic!("unknown output sink '{}' in {}", name, OUTPUT_SINKS_ENV_KEY),
+      })
+    })
+    .collect::<Result<_, _>>()?;
+  assert!(
+    !sinks.is_empty(),
+    "{} must contain at least one sink",
+    OUTPUT_SINKS_ENV_KEY
+  );
+  info!(
+    "Output sinks: {}",
+    sinks
+      .iter()
+      .map(|v| v.name())
+      .collect::<Vec<_>>()
+      .join(", ")
+  );
+  Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::DBConnectionType;
+  use crate::schema::output_measurements;
+  use diesel::{QueryDsl, RunQueryDsl};
+  use dotenvy::dotenv;
+  use std::ops::DerefMut;
+
+  #[tokio::test]
+  async fn postgres_sink_skips_duplicates() {
+    dotenv().ok();
+    let db_pool = Arc::new(DBPool::new(DBConnectionType::Test));
+    let sinks: OutputSinks = vec![Box::new(PostgresOutputSink {
+      db_pool: db_pool.clone(),
+      channel_name: "typical".to_string(),
+    })];
+    let batch: Vec<OutboxMeasurement> = (1..=2)
+      .map(|id| OutboxMeasurement {
+        id,
+        channel_name: "typical".to_string(),
+        measurement: format!("{{\"id\":{}}}", id).into_bytes(),
+        sent_at: None,
+      })
+      .collect();
+
+    send_to_sinks(&sinks, &batch).await.unwrap();
+    // A batch may be sent again if the outbox flush is interrupted
+    send_to_sinks(&sinks, &batch).await.unwrap();
+
+    let mut conn = db_pool.get().await.unwrap();
+    let stored: Vec<String> = output_measurements::table
+      .select(output_measurements::measurement)
+      .order(output_measurements::outbox_id)
+      .load(conn.deref_mut())
+      .unwrap();
+    assert_eq!(stored, vec!["{\"id\":1}", "{\"id\":2}"]);
+  }
+}
@@ -0,0 +1,293 @@
+//! Local file output sink, for deployments that do not use Kafka on the output side.
+//! Measurements are written as JSON lines or CSV to files partitioned by channel
+//! and epoch date, i.e. `<dir>/<channel>/<epoch date>/measurements-<run>-<index>.jsonl`.
+//! Files are rotated once they reach the max size. CSV files are also rotated if
+//! a measurement has different columns from the rest of the file.
+
+use super::measurement::MeasurementRecord;
+use super::output::OutputSink;
+use super::AggregatorError;
+use crate::models::OutboxMeasurement;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::{from_utf8, FromStr};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+const OUTPUT_FILE_FORMAT_ENV_KEY: &str = "OUTPUT_FILE_FORMAT";
+const DEFAULT_OUTPUT_FILE_FORMAT: &str = "jsonl";
+const OUTPUT_FILE_MAX_BYTES_ENV_KEY: &str = "OUTPUT_FILE_MAX_BYTES";
+const DEFAULT_OUTPUT_FILE_MAX_BYTES: &str = "104857600";
+const UNKNOWN_EPOCH_PARTITION: &str = "unknown";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileOutputFormat {
+  Jsonl,
+  Csv,
+}
+
+impl FromStr for FileOutputFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "jsonl" => Ok(Self::Jsonl),
+      "csv" => Ok(Self::Csv),
+      _ => Err(format!("unknown output file format '{}'", s)),
+    }
+  }
+}
+
+impl FileOutputFormat {
+  fn extension(&self) -> &'static str {
+    match self {
+      Self::Jsonl => "jsonl",
+      Self::Csv => "csv",
+    }
+  }
+}
+
+struct OpenFile {
+  writer: BufWriter<File>,
+  bytes_written: u64,
+  /// Column names of a CSV file
+  columns: Option<Vec<String>>,
+}
+
+#[derive(Default)]
+struct PartitionState {
+  file: Option<OpenFile>,
+  next_index: usize,
+}
+
+pub struct FileOutputSink {
+  dir: PathBuf,
+  format: FileOutputFormat,
+  max_file_bytes: u64,
+  channel_name: String,
+  epoch_date_field_name: String,
+  /// Distinguishes the files of separate runs in the same partition
+  run_id: i64,
+  partitions: Mutex<HashMap<String, PartitionState>>,
+}
+
+fn csv_escape(value: &str) -> String {
+  match value.contains([',', '"', '\n', '\r']) {
+    true => format!("\"{}\"", value.replace('"', "\"\"")),
+    false => value.to_string(),
+  }
+}
+
+/// Returns the CSV column names and values of a measurement.
+fn csv_columns(record: &MeasurementRecord) -> (Vec<String>, Vec<String>) {
+  let mut columns = vec![
+    "schema_version".to_string(),
+    "data_channel".to_string(),
+    "sampling_rate".to_string(),
+    "total".to_string(),
+    "first_submitted_at".to_string(),
+    "last_submitted_at".to_string(),
+  ];
+  let mut values = vec![
+    record.schema_version.to_string(),
+    record.data_channel.clone(),
+    record.sampling_rate.to_string(),
+    record.total.to_string(),
+    record.first_submitted_at.clone().unwrap_or_default(),
+    record.last_submitted_at.clone().unwrap_or_default(),
+  ];
+  for (name, value) in &record.fields {
+    columns.push(name.clone());
+    values.push(match value {
+      Value::String(value) => value.clone(),
+      value => value.to_string(),
+    });
+  }
+  (columns, values)
+}
+
+fn csv_line(values: &[String]) -> String {
+  let values: Vec<String> = values.iter().map(|v| csv_escape(v)).collect();
+  format!("{}\n", values.join(","))
+}
+
+impl FileOutputSink {
+  pub fn new(dir: &str, channel_name: &str, epoch_date_field_name: &str) -> Self {
+    Self {
+      dir: PathBuf::from(dir),
+      format: parse_env_var(OUTPUT_FILE_FORMAT_ENV_KEY, DEFAULT_OUTPUT_FILE_FORMAT),
+      max_file_bytes: parse_env_var(OUTPUT_FILE_MAX_BYTES_ENV_KEY, DEFAULT_OUTPUT_FILE_MAX_BYTES),
+      channel_name: channel_name.to_string(),
+      epoch_date_field_name: epoch_date_field_name.to_string(),
+      run_id: OffsetDateTime::now_utc().unix_timestamp(),
+      partitions: Mutex::new(HashMap::new()),
+    }
+  }
+
+  fn open_file(
+    &self,
+    partition: &str,
+    state: &mut PartitionState,
+    columns: Option<Vec<String>>,
+  ) -> io::Result<OpenFile> {
+    let dir = self.dir.join(&self.channel_name).join(partition);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!(
+      "measurements-{}-{:04}.{}",
+      self.run_id,
+      state.next_index,
+      self.format.extension()
+    ));
+    state.next_index += 1;
+    debug!("Opening output file {}", path.display());
+    let mut file = OpenFile {
+      writer: BufWriter::new(File::create(path)?),
+      bytes_written: 0,
+      columns: None,
+    };
+    if let Some(columns) = columns {
+      let header = csv_line(&columns);
+      file.writer.write_all(header.as_bytes())?;
+      file.bytes_written += header.len() as u64;
+      file.columns = Some(columns);
+    }
+    Ok(file)
+  }
+
+  fn write_measurement(
+    &self,
+    partitions: &mut HashMap<String, PartitionState>,
+    record: &MeasurementRecord,
+    json: &str,
+  ) -> io::Result<()> {
+    let partition = match record.fields.get(&self.epoch_date_field_name) {
+      Some(Value::String(date)) => date.clone(),
+      _ => UNKNOWN_EPOCH_PARTITION.to_string(),
+    };
+    let (columns, line) = match self.format {
+      FileOutputFormat::Jsonl => (None, format!("{}\n", json)),
+      FileOutputFormat::Csv => {
+        let (columns, values) = csv_columns(record);
+        (Some(columns), csv_line(&values))
+      }
+    };
+
+    let state = partitions.entry(partition.clone()).or_default();
+    let needs_rotation = match state.file.as_ref() {
+      None => true,
+      Some(file) => file.bytes_written >= self.max_file_bytes || file.columns != columns,
+    };
+    if needs_rotation {
+      if let Some(mut file) = state.file.take() {
+        file.writer.flush()?;
+      }
+      state.file = Some(self.open_file(&partition, state, columns)?);
+    }
+    let file = state.file.as_mut().unwrap();
+    file.writer.write_all(line.as_bytes())?;
+    file.bytes_written += line.len() as u64;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl OutputSink for FileOutputSink {
+  fn name(&self) -> &'static str {
+    "file"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    let mut partitions = self.partitions.lock().unwrap();
+    for measurement in measurements {
+      let json = from_utf8(&measurement.measurement)?;
+      let record: MeasurementRecord = serde_json::from_str(json)?;
+      self.write_measurement(&mut partitions, &record, json)?;
+    }
+    for file in partitions.values_mut().filter_map(|v| v.file.as_mut()) {
+      file.writer.flush()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn measurement(id: i64, value: Value) -> OutboxMeasurement {
+    OutboxMeasurement {
+      id,
+      channel_name: "typical".to_string(),
+      measurement: serde_json::to_vec(&value).unwrap(),
+      sent_at: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn csv_partitions() {
+    let dir = std::env::temp_dir().join(format!("output-file-test-{}", rand::random::<u64>()));
+    let mut sink = FileOutputSink::new(dir.to_str().unwrap(), "typical", "wos");
+    sink.format = FileOutputFormat::Csv;
+
+    let base = json!({"schema_version": 3, "data_channel": "typical", "sampling_rate": 1.0});
+    let with_fields = |fields: Value| {
+      let mut value = base.clone();
+      value
+        .as_object_mut()
+        .unwrap()
+        .extend(fields.as_object().unwrap().clone());
+      value
+    };
+    sink
+      .send_batch(&[
+        measurement(
+          1,
+          with_fields(json!({"total": 5, "wos": "2024-01-01", "q": "a,b"})),
+        ),
+        measurement(
+          2,
+          with_fields(json!({"total": 2, "wos": "2024-01-01", "q": "c"})),
+        ),
+        measurement(
+          3,
+          with_fields(json!({"total": 1, "wos": "2024-01-08", "r": 1})),
+        ),
+      ])
+      .await
+      .unwrap();
+
+    let read_partition = |date: &str| -> Vec<String> {
+      let mut paths: Vec<_> = fs::read_dir(dir.join("typical").join(date))
+        .unwrap()
+        .map(|v| v.unwrap().path())
+        .collect();
+      paths.sort();
+      paths
+        .into_iter()
+        .map(|v| fs::read_to_string(v).unwrap())
+        .collect()
+    };
+    assert_eq!(
+      read_partition("2024-01-01"),
+      vec![concat!(
+        "schema_version,data_channel,sampling_rate,total,first_submitted_at,last_submitted_at,q,wos\n",
+        "3,typical,1,5,,,\"a,b\",2024-01-01\n",
+        "3,typical,1,2,,,c,2024-01-01\n"
+      )]
+    );
+    assert_eq!(
+      read_partition("2024-01-08"),
+      vec![concat!(
+        "schema_version,data_channel,sampling_rate,total,first_submitted_at,last_submitted_at,r,wos\n",
+        "3,typical,1,1,,,1,2024-01-08\n"
+      )]
+    );
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,303 @@
+//! Data warehouse output sinks, which write measurements directly to a warehouse table
+//! instead of relying on a downstream job to load the output topic or lake.
+//! Each measurement is written as a row with the fields of the measurement,
+//! along with an `outbox_id` column. Since sinks may receive a batch more than once,
+//! duplicate rows should be removed by outbox id when querying the table.
+//!
+//! The BigQuery sink streams rows via the `insertAll` API of `google-cloud-bigquery`,
+//! authenticated with the application default credentials. The outbox id is used as the insert id, so
+//! BigQuery will drop most duplicates on a best-effort basis.
+//!
+//! The Redshift sink stores each batch as a JSON lines object in the lake, under
+//! `warehouse-loads/<channel>/`, and loads the object via `COPY` using an IAM role
+//! that can read the lake bucket. The columns of the table must match the
+//! measurement fields, since the object is loaded with `FORMAT AS JSON 'auto'`.
+
+use super::output::OutputSink;
+use super::AggregatorError;
+use crate::lake::DataLake;
+use crate::models::OutboxMeasurement;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use derive_more::{Display, Error, From};
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use google_cloud_bigquery::client::HttpClientConfig;
+use google_cloud_bigquery::http::bigquery_tabledata_client::BigqueryTabledataClient;
+use google_cloud_bigquery::http::tabledata::insert_all::{
+  InsertAllRequest, InsertAllResponse, Row,
+};
+use serde_json::{Map, Value};
+use std::env;
+use tokio::sync::OnceCell;
+use tokio::task;
+
+const OUTPUT_BIGQUERY_PROJECT_ID_ENV_KEY: &str = "OUTPUT_BIGQUERY_PROJECT_ID";
+const OUTPUT_BIGQUERY_DATASET_ENV_KEY: &str = "OUTPUT_BIGQUERY_DATASET";
+const OUTPUT_BIGQUERY_TABLE_ENV_KEY: &str = "OUTPUT_BIGQUERY_TABLE";
+const OUTPUT_BIGQUERY_ENDPOINT_ENV_KEY: &str = "OUTPUT_BIGQUERY_ENDPOINT";
+const DEFAULT_OUTPUT_BIGQUERY_ENDPOINT: &str = "https://bigquery.googleapis.com";
+const OUTPUT_BIGQUERY_INSERT_BATCH_SIZE_ENV_KEY: &str = "OUTPUT_BIGQUERY_INSERT_BATCH_SIZE";
+const DEFAULT_OUTPUT_BIGQUERY_INSERT_BATCH_SIZE: &str = "500";
+const OUTPUT_REDSHIFT_URL_ENV_KEY: &str = "OUTPUT_REDSHIFT_URL";
+const OUTPUT_REDSHIFT_TABLE_ENV_KEY: &str = "OUTPUT_REDSHIFT_TABLE";
+const OUTPUT_REDSHIFT_IAM_ROLE_ENV_KEY: &str = "OUTPUT_REDSHIFT_IAM_ROLE";
+
+/// Column containing the outbox id of the measurement
+const OUTBOX_ID_COLUMN: &str = "outbox_id";
+
+#[derive(Debug, Display, Error, From)]
+pub enum WarehouseError {
+  #[display(fmt = "{} must be set for the {} output sink", key, sink_name)]
+  #[from(ignore)]
+  MissingConfig {
+    key: &'static str,
+    sink_name: &'static str,
+  },
+  #[display(fmt = "BigQuery request error: {}", _0)]
+  Request(google_cloud_bigquery::http::error::Error),
+  #[display(fmt = "BigQuery auth error: {}", _0)]
+  Auth(google_cloud_auth::error::Error),
+  #[display(fmt = "BigQuery rejected rows: {}", _0)]
+  #[from(ignore)]
+  InsertErrors(#[error(not(source))] String),
+  #[display(fmt = "Redshift connection error: {}", _0)]
+  Connection(diesel::ConnectionError),
+  #[display(fmt = "Redshift query error: {}", _0)]
+  Query(diesel::result::Error),
+  #[display(fmt = "measurement {} is not a JSON object", _0)]
+  #[from(ignore)]
+  InvalidMeasurement(#[error(not(source))] i64),
+}
+
+fn required_env_var(key: &'static str, sink_name: &'static str) -> Result<String, WarehouseError> {
+  env::var(key).map_err(|_| WarehouseError::MissingConfig { key, sink_name })
+}
+
+/// Returns the measurement as a JSON object, with the outbox id column added.
+fn warehouse_row(measurement: &OutboxMeasurement) -> Result<Map<String, Value>, WarehouseError> {
+  let mut row = match serde_json::from_slice(&measurement.measurement) {
+    Ok(Value::Object(row)) => row,
+    _ => return Err(WarehouseError::InvalidMeasurement(measurement.id)),
+  };
+  row.insert(OUTBOX_ID_COLUMN.to_string(), measurement.id.into());
+  Ok(row)
+}
+
+/// Returns an error describing the rejected rows of the response, if any.
+fn check_insert_errors(response: InsertAllResponse) -> Result<(), WarehouseError> {
+  let insert_errors = response.insert_errors.unwrap_or_default();
+  match insert_errors.first() {
+    Some(error) => {
+      let details = error
+        .errors
+        .iter()
+        .map(|v| format!("{}: {}", v.reason, v.message))
+        .collect::<Vec<_>>()
+        .join(", ");
+      Err(WarehouseError::InsertErrors(format!(
+        "{} rows failed, row {}: {}",
+        insert_errors.len(),
+        error.index,
+        details
+      )))
+    }
+    None => Ok(()),
+  }
+}
+
+/// Streams measurements into a BigQuery table.
+pub struct BigQueryOutputSink {
+  /// Created on first use, since loading the credentials is asynchronous
+  client: OnceCell<BigqueryTabledataClient>,
+  endpoint: String,
+  project_id: String,
+  dataset: String,
+  table: String,
+  insert_batch_size: usize,
+}
+
+impl BigQueryOutputSink {
+  pub fn from_env() -> Result<Self, WarehouseError> {
+    Ok(Self {
+      client: OnceCell::new(),
+      endpoint: parse_env_var(
+        OUTPUT_BIGQUERY_ENDPOINT_ENV_KEY,
+        DEFAULT_OUTPUT_BIGQUERY_ENDPOINT,
+      ),
+      project_id: required_env_var(OUTPUT_BIGQUERY_PROJECT_ID_ENV_KEY, "bigquery")?,
+      dataset: required_env_var(OUTPUT_BIGQUERY_DATASET_ENV_KEY, "bigquery")?,
+      table: required_env_var(OUTPUT_BIGQUERY_TABLE_ENV_KEY, "bigquery")?,
+      insert_batch_size: parse_env_var(
+        OUTPUT_BIGQUERY_INSERT_BATCH_SIZE_ENV_KEY,
+        DEFAULT_OUTPUT_BIGQUERY_INSERT_BATCH_SIZE,
+      ),
+    })
+  }
+
+  async fn client(&self) -> Result<&BigqueryTabledataClient, WarehouseError> {
+    self
+      .client
+      .get_or_try_init(|| async {
+        let token_provider = HttpClientConfig::default_token_provider().await?;
+        let client = HttpClientConfig::new(Box::new(token_provider))
+          .with_endpoint(self.endpoint.trim_end_matches('/').to_string())
+          .create_client();
+        Ok(BigqueryTabledataClient::new(client))
+      })
+      .await
+  }
+
+  async fn insert_rows(&self, rows: Vec<Row<Map<String, Value>>>) -> Result<(), WarehouseError> {
+    let request = InsertAllRequest {
+      rows,
+      ..Default::default()
+    };
+    let response = self
+      .client()
+      .await?
+      .insert(&self.project_id, &self.dataset, &self.table, &request)
+      .await?;
+    check_insert_errors(response)
+  }
+}
+
+#[async_trait]
+impl OutputSink for BigQueryOutputSink {
+  fn name(&self) -> &'static str {
+    "bigquery"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    for chunk in measurements.chunks(self.insert_batch_size.max(1)) {
+      let rows = chunk
+        .iter()
+        .map(|v| {
+          Ok(Row {
+            insert_id: Some(v.id.to_string()),
+            json: warehouse_row(v)?,
+          })
+        })
+        .collect::<Result<Vec<_>, WarehouseError>>()?;
+      self.insert_rows(rows).await?;
+    }
+    Ok(())
+  }
+}
+
+/// Loads measurements into a Redshift table via the lake.
+pub struct RedshiftOutputSink {
+  lake: DataLake,
+  channel_name: String,
+  url: String,
+  table: String,
+  iam_role: String,
+}
+
+impl RedshiftOutputSink {
+  pub fn from_env(lake: DataLake, channel_name: &str) -> Result<Self, WarehouseError> {
+    Ok(Self {
+      lake,
+      channel_name: channel_name.to_string(),
+      url: required_env_var(OUTPUT_REDSHIFT_URL_ENV_KEY, "redshift")?,
+      table: required_env_var(OUTPUT_REDSHIFT_TABLE_ENV_KEY, "redshift")?,
+      iam_role: required_env_var(OUTPUT_REDSHIFT_IAM_ROLE_ENV_KEY, "redshift")?,
+    })
+  }
+
+  async fn copy(&self, uri: String) -> Result<(), AggregatorError> {
+    let url = self.url.clone();
+    let statement = format!(
+      "COPY {} FROM '{}' IAM_ROLE '{}' FORMAT AS JSON 'auto'",
+      self.table, uri, self.iam_role
+    );
+    task::spawn_blocking(move || -> Result<(), WarehouseError> {
+      let mut conn = PgConnection::establish(&url)?;
+      conn.batch_execute(&statement)?;
+      Ok(())
+    })
+    .await??;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl OutputSink for RedshiftOutputSink {
+  fn name(&self) -> &'static str {
+    "redshift"
+  }
+
+  async fn send_batch(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    let (first, last) = match (measurements.first(), measurements.last()) {
+      (Some(first), Some(last)) => (first.id, last.id),
+      _ => return Ok(()),
+    };
+    let lines = measurements
+      .iter()
+      .map(|v| Ok(Value::Object(warehouse_row(v)?).to_string()))
+      .collect::<Result<Vec<_>, WarehouseError>>()?;
+    let uri = self
+      .lake
+      .store_warehouse_load(&self.channel_name, first, last, &lines.join("\n"))
+      .await?;
+    self.copy(uri).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn measurement(id: i64, json: &str) -> OutboxMeasurement {
+    OutboxMeasurement {
+      id,
+      channel_name: "typical".to_string(),
+      measurement: json.as_bytes().to_vec(),
+      sent_at: None,
+    }
+  }
+
+  #[test]
+  fn warehouse_rows() {
+    let row = warehouse_row(&measurement(12, r#"{"country":"US","total":3}"#)).unwrap();
+    assert_eq!(
+      Value::Object(row),
+      serde_json::json!({ "country": "US", "total": 3, "outbox_id": 12 })
+    );
+    assert!(matches!(
+      warehouse_row(&measurement(13, "[1, 2]")),
+      Err(WarehouseError::InvalidMeasurement(13))
+    ));
+  }
+
+  #[test]
+  fn insert_errors() {
+    let response: InsertAllResponse = serde_json::from_str(
+      r#"{"kind":"bigquery#tableDataInsertAllResponse","insertErrors":[
+        {"index":1,"errors":[{"reason":"invalid","location":"foo","debugInfo":"",
+        "message":"no such field: foo"}]}]}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+      check_insert_errors(response),
+      Err(WarehouseError::InsertErrors(e)) if e == "1 rows failed, row 1: invalid: no such field: foo"
+    ));
+
+    let response: InsertAllResponse =
+      serde_json::from_str(r#"{"kind":"bigquery#tableDataInsertAllResponse"}"#).unwrap();
+    assert!(check_insert_errors(response).is_ok());
+  }
+
+  #[test]
+  fn missing_config() {
+    env::remove_var(OUTPUT_BIGQUERY_PROJECT_ID_ENV_KEY);
+    assert!(matches!(
+      BigQueryOutputSink::from_env(),
+      Err(WarehouseError::MissingConfig {
+        key: OUTPUT_BIGQUERY_PROJECT_ID_ENV_KEY,
+        sink_name: "bigquery"
+      })
+    ));
+  }
+}
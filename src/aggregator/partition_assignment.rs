@@ -0,0 +1,210 @@
+//! Manual partition assignment for the consumers of the aggregator. By default, the
+//! consumers subscribe to the input topic as members of the consumer group, and the
+//! partitions are distributed by the group coordinator, so the partitions consumed by
+//! each consumer depend on the other members of the group. If `AGGREGATOR_PARTITIONS`
+//! is set, the listed partitions are assigned to the consumers directly, without
+//! joining the group, so that a specific partition can be reprocessed after a failure
+//! without triggering a rebalance. Offsets are still committed to the consumer group.
+//!
+//! Partitions are listed as comma separated numbers or inclusive ranges, i.e. `0-3,8`.
+//! The partitions are distributed across the consumers in round robin order.
+//! Only the Kafka backend supports manual assignment.
+//!
+//! Manually assigned partitions may also be replayed from a given position, i.e. to
+//! aggregate a time range of messages again after a bug fix. If `AGGREGATOR_SEEK_TIMESTAMP`
+//! is set to an RFC 3339 timestamp, each assigned partition is consumed from its first
+//! record at or after the timestamp. If `AGGREGATOR_SEEK_OFFSETS` is set, the listed
+//! partitions are consumed from the given offsets, listed as `<partition>:<offset>`,
+//! i.e. `0:1200,3:560`. The position is set once at startup, and the replayed offsets
+//! are committed as usual.
+
+use crate::record_stream::{RecordStreamArc, RecordStreamError};
+use std::collections::BTreeSet;
+use std::env;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const AGGREGATOR_PARTITIONS_ENV_KEY: &str = "AGGREGATOR_PARTITIONS";
+const AGGREGATOR_SEEK_TIMESTAMP_ENV_KEY: &str = "AGGREGATOR_SEEK_TIMESTAMP";
+const AGGREGATOR_SEEK_OFFSETS_ENV_KEY: &str = "AGGREGATOR_SEEK_OFFSETS";
+
+/// Parses a partition list such as `0-3,8`. The returned partitions are sorted and unique.
+fn parse_partitions(value: &str) -> Result<Vec<i32>, String> {
+  let mut partitions = BTreeSet::new();
+  for item in value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+    let parse = |v: &str| {
+      v.trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|v| *v >= 0)
+        .ok_or_else(|| format!("invalid partition '{}'", v.trim()))
+    };
+    match item.split_once('-') {
+      Some((start, end)) => {
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+          return Err(format!("invalid partition range '{}'", item));
+        }
+        partitions.extend(start..=end);
+      }
+      None => {
+        partitions.insert(parse(item)?);
+      }
+    }
+  }
+  if partitions.is_empty() {
+    return Err("no partitions listed".to_string());
+  }
+  Ok(partitions.into_iter().collect())
+}
+
+/// Returns the partitions listed in `AGGREGATOR_PARTITIONS`, if set.
+pub fn assigned_partitions_from_env() -> Option<Vec<i32>> {
+  let value = env::var(AGGREGATOR_PARTITIONS_ENV_KEY).ok()?;
+  Some(
+    parse_partitions(&value)
+      .unwrap_or_else(|e| panic!("{} is invalid: {}", AGGREGATOR_PARTITIONS_ENV_KEY, e)),
+  )
+}
+
+/// Distributes the partitions across at most `consumer_count` consumers. Consumers
+/// without any partitions are omitted.
+pub fn split_partitions(partitions: &[i32], consumer_count: usize) -> Vec<Vec<i32>> {
+  let mut result = vec![Vec::new(); consumer_count.clamp(1, partitions.len().max(1))];
+  let len = result.len();
+  for (i, partition) in partitions.iter().enumerate() {
+    result[i % len].push(*partition);
+  }
+  result.retain(|v| !v.is_empty());
+  result
+}
+
+/// Position to replay the assigned partitions from.
+#[derive(Debug, PartialEq)]
+pub enum SeekTarget {
+  Timestamp(OffsetDateTime),
+  Offsets(Vec<(i32, i64)>),
+}
+
+/// Parses an offset list such as `0:1200,3:560`.
+fn parse_seek_offsets(value: &str) -> Result<Vec<(i32, i64)>, String> {
+  let offsets = value
+    .split(',')
+    .map(|v| v.trim())
+    .filter(|v| !v.is_empty())
+    .map(|item| {
+      let (partition, offset) = item
+        .split_once(':')
+        .ok_or_else(|| format!("invalid partition offset '{}'", item))?;
+      match (partition.trim().parse(), offset.trim().parse()) {
+        (Ok(partition), Ok(offset)) if partition >= 0 && offset >= 0 => Ok((partition, offset)),
+        _ => Err(format!("invalid partition offset '{}'", item)),
+      }
+    })
+    .collect::<Result<Vec<_>, String>>()?;
+  if offsets.is_empty() {
+    return Err("no offsets listed".to_string());
+  }
+  Ok(offsets)
+}
+
+impl SeekTarget {
+  /// Returns the seek target configured via `AGGREGATOR_SEEK_TIMESTAMP`
+  /// or `AGGREGATOR_SEEK_OFFSETS`, if set.
+  pub fn from_env() -> Option<Self> {
+    let timestamp = env::var(AGGREGATOR_SEEK_TIMESTAMP_ENV_KEY).ok();
+    let offsets = env::var(AGGREGATOR_SEEK_OFFSETS_ENV_KEY).ok();
+    match (timestamp, offsets) {
+      (Some(_), Some(_)) => panic!(
+        "only one of {} and {} may be set",
+        AGGREGATOR_SEEK_TIMESTAMP_ENV_KEY, AGGREGATOR_SEEK_OFFSETS_ENV_KEY
+      ),
+      (Some(timestamp), None) => Some(Self::Timestamp(
+        OffsetDateTime::parse(timestamp.trim(), &Rfc3339).unwrap_or_else(|e| {
+          panic!(
+            "{} should be an RFC 3339 timestamp: {}",
+            AGGREGATOR_SEEK_TIMESTAMP_ENV_KEY, e
+          )
+        }),
+      )),
+      (None, Some(offsets)) => Some(Self::Offsets(
+        parse_seek_offsets(&offsets)
+          .unwrap_or_else(|e| panic!("{} is invalid: {}", AGGREGATOR_SEEK_OFFSETS_ENV_KEY, e)),
+      )),
+      (None, None) => None,
+    }
+  }
+
+  /// Moves the positions of the assigned partitions of the streams. Listed offsets
+  /// are applied to the stream that the partition is assigned to.
+  pub fn apply(&self, streams: &[RecordStreamArc]) -> Result<(), RecordStreamError> {
+    let mut seeked_partitions = BTreeSet::new();
+    for stream in streams {
+      let supported = match self {
+        Self::Timestamp(timestamp) => stream.seek_to_timestamp(*timestamp)?,
+        Self::Offsets(offsets) => {
+          let assigned = stream.assigned_partitions()?;
+          offsets
+            .iter()
+            .filter(|(partition, _)| assigned.contains(partition))
+            .map(|(partition, offset)| {
+              seeked_partitions.insert(*partition);
+              stream.seek(*partition, *offset)
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .all(|v| v)
+        }
+      };
+      assert!(supported, "record stream backend does not support seeking");
+    }
+    if let Self::Offsets(offsets) = self {
+      for (partition, _) in offsets {
+        if !seeked_partitions.contains(partition) {
+          warn!(
+            "Partition {} in {} is not assigned, ignoring",
+            partition, AGGREGATOR_SEEK_OFFSETS_ENV_KEY
+          );
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn partition_lists() {
+    assert_eq!(parse_partitions("3"), Ok(vec![3]));
+    assert_eq!(parse_partitions("8, 0-3,2"), Ok(vec![0, 1, 2, 3, 8]));
+    assert!(parse_partitions("").is_err());
+    assert!(parse_partitions("3-1").is_err());
+    assert!(parse_partitions("-1").is_err());
+    assert!(parse_partitions("a").is_err());
+  }
+
+  #[test]
+  fn split() {
+    assert_eq!(
+      split_partitions(&[0, 1, 2, 3, 8], 2),
+      vec![vec![0, 2, 8], vec![1, 3]]
+    );
+    assert_eq!(split_partitions(&[5], 4), vec![vec![5]]);
+    assert_eq!(split_partitions(&[1, 2], 0), vec![vec![1, 2]]);
+  }
+
+  #[test]
+  fn seek_offsets() {
+    assert_eq!(
+      parse_seek_offsets("0:1200, 3:560"),
+      Ok(vec![(0, 1200), (3, 560)])
+    );
+    assert!(parse_seek_offsets("").is_err());
+    assert!(parse_seek_offsets("3").is_err());
+    assert!(parse_seek_offsets("3:-1").is_err());
+    assert!(parse_seek_offsets("a:1").is_err());
+  }
+}
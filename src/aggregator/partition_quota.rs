@@ -0,0 +1,187 @@
+//! Fair division of the collect budget across input partitions. By default, records are
+//! collected in the order they are fetched, so a hot partition may fill most of the collect
+//! count of an iteration, which skews which tags reach the threshold in early iterations.
+//! If `FAIR_PARTITION_COLLECT` is enabled, each partition may contribute at most a quota of
+//! records per iteration. Partitions of multiple input topics are considered separately.
+//! The quota is the max-min fair share of the collect count, given the lag of each partition: partitions with fewer records than an equal share contribute
+//! all of their records, and the remaining budget is split evenly across the others.
+//!
+//! Once a partition exceeds its quota, it is paused and rewound, so that the remaining
+//! records are consumed by the next iteration. Backends that do not support pausing
+//! are not limited.
+
+use crate::record_stream::{RecordStreamArc, RecordStreamError, TopicPartition};
+use crate::util::parse_env_var;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+const FAIR_PARTITION_COLLECT_ENV_KEY: &str = "FAIR_PARTITION_COLLECT";
+const DEFAULT_FAIR_PARTITION_COLLECT: &str = "false";
+
+#[derive(Default)]
+struct QuotaState {
+  /// Lag of each known partition, at the time it was first seen by the iteration
+  lags: HashMap<TopicPartition, i64>,
+  /// Records admitted from each partition
+  counts: HashMap<TopicPartition, usize>,
+  paused: HashSet<TopicPartition>,
+  quota: usize,
+}
+
+/// Record quotas of the partitions consumed in one iteration.
+pub struct PartitionQuotas {
+  budget: usize,
+  state: Mutex<QuotaState>,
+}
+
+impl PartitionQuotas {
+  pub fn is_enabled() -> bool {
+    parse_env_var::<bool>(
+      FAIR_PARTITION_COLLECT_ENV_KEY,
+      DEFAULT_FAIR_PARTITION_COLLECT,
+    )
+  }
+
+  /// Returns the quotas for the collect budget, if fair partition collection is enabled.
+  pub fn from_env(budget: usize) -> Option<Self> {
+    Self::is_enabled().then(|| Self::new(budget))
+  }
+
+  fn new(budget: usize) -> Self {
+    Self {
+      budget,
+      state: Mutex::new(QuotaState {
+        quota: budget,
+        ..Default::default()
+      }),
+    }
+  }
+
+  fn is_known(&self, topic_partition: &TopicPartition) -> bool {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .lags
+      .contains_key(topic_partition)
+  }
+
+  /// Adds the lags of partitions of the topic that were not seen yet, and recomputes the quota.
+  fn add_partition_lags(&self, topic: &str, partition_lags: &[(i32, i64)]) {
+    let mut state = self.state.lock().unwrap();
+    for (partition, lag) in partition_lags {
+      state
+        .lags
+        .entry((topic.to_string(), *partition))
+        .or_insert(*lag);
+    }
+    let lags: Vec<i64> = state.lags.values().copied().collect();
+    state.quota = fair_quota(&lags, self.budget);
+  }
+
+  /// Counts the record against the quota of the partition. Returns false if
+  /// the quota has been reached, or the partition has been paused.
+  fn admit(&self, topic_partition: &TopicPartition) -> bool {
+    let mut state = self.state.lock().unwrap();
+    if state.paused.contains(topic_partition) {
+      return false;
+    }
+    let quota = state.quota;
+    let count = state.counts.entry(topic_partition.clone()).or_default();
+    if *count >= quota {
+      return false;
+    }
+    *count += 1;
+    true
+  }
+
+  /// Returns true if the record at the offset of the partition should be collected.
+  /// The record stream must consume the topic of the partition. If the quota of the
+  /// partition has been reached, the partition is paused, and the record will be
+  /// consumed again after the partition is resumed.
+  pub fn check_record(
+    &self,
+    rec_stream: &RecordStreamArc,
+    topic_partition: TopicPartition,
+    offset: i64,
+  ) -> Result<bool, RecordStreamError> {
+    if !self.is_known(&topic_partition) {
+      self.add_partition_lags(&topic_partition.0, &rec_stream.partition_lags()?);
+    }
+    if self.admit(&topic_partition) {
+      return Ok(true);
+    }
+    if self.state.lock().unwrap().paused.contains(&topic_partition) {
+      // Records fetched before the partition was paused will be consumed again
+      return Ok(false);
+    }
+    let (topic, partition) = &topic_partition;
+    if !rec_stream.pause_partition(*partition, offset)? {
+      return Ok(true);
+    }
+    debug!(
+      "Paused partition {} of {} at offset {}",
+      partition, topic, offset
+    );
+    self.state.lock().unwrap().paused.insert(topic_partition);
+    Ok(false)
+  }
+
+  /// Returns the amount of partitions that were paused after reaching the quota.
+  pub fn paused_count(&self) -> usize {
+    self.state.lock().unwrap().paused.len()
+  }
+}
+
+/// Returns the max-min fair quota of each partition, so that the quotas limited
+/// by the partition lags add up to the budget. Returns the budget if all
+/// partitions fit within the budget.
+fn fair_quota(lags: &[i64], budget: usize) -> usize {
+  let mut lags: Vec<usize> = lags.iter().map(|v| (*v).max(0) as usize).collect();
+  lags.sort_unstable();
+  let mut remaining = budget;
+  for (i, lag) in lags.iter().enumerate() {
+    let partitions_left = lags.len() - i;
+    if lag.saturating_mul(partitions_left) >= remaining {
+      return remaining.div_ceil(partitions_left);
+    }
+    remaining -= lag;
+  }
+  budget
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fair_quotas() {
+    assert_eq!(fair_quota(&[], 100), 100);
+    assert_eq!(fair_quota(&[10, 20], 100), 100);
+    assert_eq!(fair_quota(&[1000, 1000, 1000, 1000], 100), 25);
+    // Drained partitions leave their share to the hot partition
+    assert_eq!(fair_quota(&[5, 0, 10000], 100), 95);
+    assert_eq!(fair_quota(&[10, 40, 1000, 1000], 100), 30);
+    assert_eq!(fair_quota(&[10, 20, 1000, 1000], 100), 35);
+  }
+
+  fn partition(topic: &str, partition: i32) -> TopicPartition {
+    (topic.to_string(), partition)
+  }
+
+  #[test]
+  fn admit_within_quota() {
+    let quotas = PartitionQuotas::new(4);
+    quotas.add_partition_lags("desktop", &[(0, 100), (1, 100)]);
+    assert!(quotas.admit(&partition("desktop", 0)));
+    assert!(quotas.admit(&partition("desktop", 0)));
+    assert!(!quotas.admit(&partition("desktop", 0)));
+    assert!(quotas.admit(&partition("desktop", 1)));
+
+    // Partitions of a newly seen topic lower the quota
+    quotas.add_partition_lags("android", &[(0, 100), (1, 100)]);
+    assert!(!quotas.admit(&partition("desktop", 1)));
+    assert!(quotas.admit(&partition("android", 0)));
+    assert!(!quotas.admit(&partition("android", 0)));
+  }
+}
@@ -0,0 +1,155 @@
+//! Rewrites stored pending messages in the format configured via `PENDING_MSG_FORMAT`,
+//! so that rows stored in older formats can be converted without waiting for them to be
+//! recovered or expired, i.e. before retiring a format. Each message is decoded with the
+//! format recorded in its row, encoded in the configured format, and encrypted again
+//! if share encryption is enabled.
+
+use super::AggregatorError;
+use crate::encryption::{decrypt_share, encrypt_share};
+use crate::models::{DBConnection, StoredPendingMessage};
+use crate::star::{AppSTARError, StoredMessageFormat};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+const REWRITE_BATCH_SIZE: i64 = 1000;
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct RewriteSummary {
+  pub rewritten_count: usize,
+  /// Amount of messages that could not be decoded, which are left as is
+  pub error_count: usize,
+}
+
+fn rewrite_message(
+  msg: &StoredPendingMessage,
+  format: StoredMessageFormat,
+) -> Result<(Vec<u8>, bool), AppSTARError> {
+  let message = StoredMessageFormat::from_tag(msg.message_format)?
+    .deserialize(&decrypt_share(&msg.message, msg.encrypted)?)?;
+  Ok(encrypt_share(format.serialize(message)?))
+}
+
+/// Rewrites the pending messages of the channel that are stored in other formats.
+/// Each batch of messages is updated within a separate transaction.
+pub async fn rewrite_pending_msgs(
+  conn: Arc<Mutex<DBConnection>>,
+  channel_name: &str,
+  format: StoredMessageFormat,
+) -> Result<RewriteSummary, AggregatorError> {
+  let mut summary = RewriteSummary::default();
+  let mut after_id = 0;
+  loop {
+    let msgs = StoredPendingMessage::list_other_format(
+      conn.clone(),
+      channel_name,
+      format.tag(),
+      after_id,
+      REWRITE_BATCH_SIZE,
+    )
+    .await?;
+    let Some(last_msg) = msgs.last() else {
+      break;
+    };
+    after_id = last_msg.id;
+
+    let mut rewritten_msgs = Vec::with_capacity(msgs.len());
+    for mut msg in msgs {
+      match rewrite_message(&msg, format) {
+        Ok((message, encrypted)) => {
+          msg.message = message;
+          msg.encrypted = encrypted;
+          msg.message_format = format.tag();
+          rewritten_msgs.push(msg);
+        }
+        Err(e) => {
+          warn!("Failed to rewrite pending message {}: {}", msg.id, e);
+          summary.error_count += 1;
+        }
+      }
+    }
+    summary.rewritten_count += rewritten_msgs.len();
+    StoredPendingMessage::update_batch(conn.clone(), rewritten_msgs).await?;
+    info!("Rewrote {} pending messages", summary.rewritten_count);
+  }
+  Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::{BatchInsert, DBConnectionType, DBPool, NewPendingMessage, PendingMessage};
+  use crate::profiler::Profiler;
+  use crate::star::serialize_message_bincode;
+  use crate::star::tests::generate_test_message;
+  use dotenvy::dotenv;
+  use star_constellation::api::NestedMessage;
+  use star_constellation::randomness::testing::LocalFetcher;
+
+  const TEST_CHANNEL_NAME: &str = "rewrite";
+
+  #[tokio::test]
+  async fn rewrite_formats() {
+    dotenv().ok();
+    let profiler = Arc::new(Profiler::default());
+    let db_pool = DBPool::new(DBConnectionType::Test);
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+
+    let fetcher = LocalFetcher::new();
+    let msg = generate_test_message(5, &[b"a|1".to_vec()], &fetcher);
+    let new_msg = |message: Vec<u8>, format: StoredMessageFormat| NewPendingMessage {
+      msg_tag: vec![1],
+      epoch_tag: 5,
+      message,
+      threshold: 10,
+      channel_name: Some(TEST_CHANNEL_NAME.to_string()),
+      encrypted: false,
+      first_submitted_at: None,
+      last_submitted_at: None,
+      message_format: format.tag(),
+    };
+    vec![
+      new_msg(
+        serialize_message_bincode(msg.clone()).unwrap(),
+        StoredMessageFormat::Bincode,
+      ),
+      new_msg(
+        StoredMessageFormat::CompactBincode
+          .serialize(msg.clone())
+          .unwrap(),
+        StoredMessageFormat::CompactBincode,
+      ),
+      new_msg(vec![1, 2, 3], StoredMessageFormat::Bincode),
+      new_msg(
+        StoredMessageFormat::Cbor.serialize(msg.clone()).unwrap(),
+        StoredMessageFormat::Cbor,
+      ),
+    ]
+    .insert_batch(conn.clone(), profiler.clone())
+    .await
+    .unwrap();
+
+    let summary = rewrite_pending_msgs(conn.clone(), TEST_CHANNEL_NAME, StoredMessageFormat::Cbor)
+      .await
+      .unwrap();
+    assert_eq!(
+      summary,
+      RewriteSummary {
+        rewritten_count: 2,
+        error_count: 1,
+      }
+    );
+
+    let stored_msgs = PendingMessage::list(conn.clone(), TEST_CHANNEL_NAME, 5, vec![1], profiler)
+      .await
+      .unwrap();
+    let formats: Vec<i16> = stored_msgs.iter().map(|v| v.message_format).collect();
+    assert_eq!(formats.iter().filter(|v| **v == 2).count(), 3);
+    for stored_msg in stored_msgs.into_iter().filter(|v| v.message_format == 2) {
+      let parsed: NestedMessage = stored_msg.try_into().unwrap();
+      assert_eq!(
+        serialize_message_bincode(parsed).unwrap(),
+        serialize_message_bincode(msg.clone()).unwrap()
+      );
+    }
+  }
+}
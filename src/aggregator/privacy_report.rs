@@ -0,0 +1,204 @@
+//! Per-epoch privacy report, describing the extent of threshold recovery
+//! for an epoch. Reports are generated when epochs expire, and
+//! can also be generated on demand for review.
+
+use super::AggregatorError;
+use crate::epoch::EpochConfig;
+use crate::models::{DBConnection, PendingMessage, RecoveredMessage};
+use crate::profiler::Profiler;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EpochPrivacyReport {
+  pub data_channel: String,
+  pub epoch: u8,
+  pub epoch_date: String,
+  /// Layer depth (starting from 1) mapped to the amount of tags recovered at that depth
+  pub recovered_tags_by_depth: BTreeMap<usize, usize>,
+  /// Layer depth mapped to the amount of messages that were recovered at that depth
+  /// but were not recovered at the next depth. These messages are reported
+  /// as partial measurements when the epoch expires.
+  pub partial_counts_by_depth: BTreeMap<usize, i64>,
+  /// Amount of tags with stored messages that have not met the threshold.
+  /// Once the epoch expires, these messages are suppressed.
+  pub below_threshold_tag_count: usize,
+  pub below_threshold_msg_count: i64,
+  /// Distribution of share counts for below-threshold tags
+  pub below_threshold_share_distribution: Vec<ShareCountBucket>,
+}
+
+/// Share counts are grouped into power-of-two buckets, i.e. 1, 2-3, 4-7.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ShareCountBucket {
+  pub min_shares: i64,
+  pub max_shares: i64,
+  pub tag_count: usize,
+}
+
+fn share_count_bucket_min(count: i64) -> i64 {
+  1i64 << (63 - count.max(1).leading_zeros())
+}
+
+fn tag_depth<'a>(
+  tag: &'a [u8],
+  parents: &HashMap<&'a [u8], Option<&'a [u8]>>,
+  depths: &mut HashMap<&'a [u8], usize>,
+) -> usize {
+  if let Some(depth) = depths.get(tag) {
+    return *depth;
+  }
+  let depth = match parents.get(tag).copied().flatten() {
+    // Treat tags with missing parents as top-level tags
+    Some(parent) if parents.contains_key(parent) => tag_depth(parent, parents, depths) + 1,
+    _ => 1,
+  };
+  depths.insert(tag, depth);
+  depth
+}
+
+impl EpochPrivacyReport {
+  pub fn from_msgs(
+    epoch_config: &EpochConfig,
+    epoch: u8,
+    recovered_msgs: &[RecoveredMessage],
+    pending_counts_by_tag: &[i64],
+  ) -> Self {
+    let parents: HashMap<&[u8], Option<&[u8]>> = recovered_msgs
+      .iter()
+      .map(|msg| {
+        (
+          msg.msg_tag.as_slice(),
+          msg.parent_recovered_msg_tag.as_deref(),
+        )
+      })
+      .collect();
+    let mut depths = HashMap::new();
+    let mut recovered_tags_by_depth = BTreeMap::new();
+    let mut partial_counts_by_depth = BTreeMap::new();
+    for msg in recovered_msgs {
+      let depth = tag_depth(&msg.msg_tag, &parents, &mut depths);
+      *recovered_tags_by_depth.entry(depth).or_default() += 1;
+      if msg.has_children && msg.count > 0 {
+        *partial_counts_by_depth.entry(depth).or_default() += msg.count;
+      }
+    }
+
+    let mut buckets: BTreeMap<i64, usize> = BTreeMap::new();
+    for count in pending_counts_by_tag {
+      *buckets.entry(share_count_bucket_min(*count)).or_default() += 1;
+    }
+
+    Self {
+      data_channel: epoch_config.channel_name.clone(),
+      epoch,
+      epoch_date: epoch_config.get_epoch_survey_date(epoch),
+      recovered_tags_by_depth,
+      partial_counts_by_depth,
+      below_threshold_tag_count: pending_counts_by_tag.len(),
+      below_threshold_msg_count: pending_counts_by_tag.iter().sum(),
+      below_threshold_share_distribution: buckets
+        .into_iter()
+        .map(|(min_shares, tag_count)| ShareCountBucket {
+          min_shares,
+          max_shares: min_shares * 2 - 1,
+          tag_count,
+        })
+        .collect(),
+    }
+  }
+
+  pub async fn generate(
+    conn: Arc<Mutex<DBConnection>>,
+    epoch_config: &EpochConfig,
+    epoch: u8,
+    profiler: Arc<Profiler>,
+  ) -> Result<Self, AggregatorError> {
+    let recovered_msgs = RecoveredMessage::list_epoch(
+      conn.clone(),
+      &epoch_config.channel_name,
+      epoch as i16,
+      profiler.clone(),
+    )
+    .await?;
+    let pending_counts_by_tag =
+      PendingMessage::count_by_tag(conn, &epoch_config.channel_name, epoch as i16, profiler)
+        .await?;
+    Ok(Self::from_msgs(
+      epoch_config,
+      epoch,
+      &recovered_msgs,
+      &pending_counts_by_tag,
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::rollup::RollupRules;
+  use calendar_duration::CalendarDuration;
+
+  fn recovered_msg(
+    tag: u8,
+    parent_tag: Option<u8>,
+    count: i64,
+    has_children: bool,
+  ) -> RecoveredMessage {
+    RecoveredMessage {
+      id: 0,
+      msg_tag: vec![tag; 20],
+      epoch_tag: 2,
+      metric_name: "a".to_string(),
+      metric_value: "1".to_string(),
+      parent_recovered_msg_tag: parent_tag.map(|v| vec![v; 20]),
+      count,
+      key: vec![88; 32],
+      has_children,
+      channel_name: None,
+      first_submitted_at: None,
+      last_submitted_at: None,
+    }
+  }
+
+  #[test]
+  fn build_report() {
+    let epoch_length = CalendarDuration::from("1w");
+    let epoch_config = EpochConfig {
+      channel_name: "typical".to_string(),
+      current_epoch: CurrentEpochInfo::test_info(2, epoch_length),
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    };
+    let recovered_msgs = vec![
+      recovered_msg(3, Some(2), 0, false),
+      recovered_msg(1, None, 5, true),
+      recovered_msg(2, Some(1), 0, true),
+      recovered_msg(4, Some(1), 0, false),
+      recovered_msg(5, None, 0, false),
+    ];
+    let report = EpochPrivacyReport::from_msgs(&epoch_config, 2, &recovered_msgs, &[1, 1, 3, 9]);
+
+    assert_eq!(
+      report.recovered_tags_by_depth,
+      BTreeMap::from([(1, 2), (2, 2), (3, 1)])
+    );
+    assert_eq!(report.partial_counts_by_depth, BTreeMap::from([(1, 5)]));
+    assert_eq!(report.below_threshold_tag_count, 4);
+    assert_eq!(report.below_threshold_msg_count, 14);
+    assert_eq!(
+      report
+        .below_threshold_share_distribution
+        .iter()
+        .map(|b| (b.min_shares, b.max_shares, b.tag_count))
+        .collect::<Vec<_>>(),
+      vec![(1, 1, 2), (2, 3, 1), (8, 15, 1)]
+    );
+  }
+}
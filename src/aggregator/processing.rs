@@ -1,85 +1,335 @@
+use super::epoch_export::export_epoch;
+use super::epoch_snapshot::{check_epoch_finalization, record_finalized_config};
 use super::group::{GroupedMessages, MessageChunk};
+use super::key_cache::RecoveredKeyCache;
+use super::key_export::EpochKeyExporter;
+use super::limits::ConcurrencyLimits;
+use super::outbox::{store_outbox_measurements, MeasurementBuffer, OutboxRelay};
+use super::privacy_report::EpochPrivacyReport;
 use super::recovered::RecoveredMessages;
 use super::report::report_measurements;
+use super::watermark::EpochCloseCheck;
 use super::AggregatorError;
 use crate::aggregator::spot::check_spot_termination_status;
-use crate::aggregator::wait_and_commit_producer;
 use crate::epoch::EpochConfig;
+use crate::lake::DataLake;
 use crate::models::{
   begin_db_transaction, commit_db_transaction, DBConnection, DBPool, DBStorageConnections,
-  MessageWithThreshold, PendingMessage, RecoveredMessage,
+  EpochMeasurement, MessageWithThreshold, PendingMessage, RecoveredMessage,
 };
 use crate::profiler::{Profiler, ProfilerStat};
-use crate::record_stream::{DynRecordStream, RecordStreamArc};
-use crate::star::{recover_key, recover_msgs, AppSTARError, MsgRecoveryInfo};
+use crate::progress::{end_phase, record_progress, start_phase};
+use crate::record_stream::{BatchRecord, DynRecordStream};
+use crate::star::{
+  recover_key, recover_layer_measurement, recover_msgs, AppSTARError, MsgRecoveryInfo,
+};
+use crate::util::parse_env_var;
+use calendar_duration::CalendarDuration;
+use derive_more::Display;
+use futures::stream::{self, StreamExt};
 use star_constellation::api::NestedMessage;
 use star_constellation::Error as ConstellationError;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 use tokio::task::JoinHandle;
 
+const EXPIRED_EPOCH_CONCURRENCY_ENV_KEY: &str = "EXPIRED_EPOCH_CONCURRENCY";
+const EXPIRED_EPOCH_CONCURRENCY_DEFAULT: &str = "2";
+
 pub async fn process_expired_epoch(
   conn: Arc<Mutex<DBConnection>>,
   epoch_config: &EpochConfig,
-  out_stream: Option<&DynRecordStream>,
+  use_outbox: bool,
+  export_lake: Option<&DataLake>,
   profiler: Arc<Profiler>,
   epoch: i16,
 ) -> Result<(), AggregatorError> {
   let mut rec_msgs = RecoveredMessages::default();
   rec_msgs
-    .fetch_all_recovered_with_nonzero_count(conn.clone(), epoch as u8, profiler.clone())
+    .fetch_all_recovered_with_nonzero_count(
+      conn.clone(),
+      &epoch_config.channel_name,
+      epoch as u8,
+      profiler.clone(),
+    )
     .await?;
 
+  let output_buffer = use_outbox.then(MeasurementBuffer::default);
   report_measurements(
     &mut rec_msgs,
     epoch_config,
     epoch as u8,
     true,
-    out_stream,
+    output_buffer.as_ref(),
+  )
+  .await?;
+  if let Some(output_buffer) = output_buffer {
+    store_outbox_measurements(
+      conn.clone(),
+      &epoch_config.channel_name,
+      epoch as u8,
+      output_buffer,
+      profiler.clone(),
+    )
+    .await?;
+  }
+  if let Some(lake) = export_lake {
+    export_epoch(conn.clone(), lake, epoch_config, epoch as u8).await?;
+  }
+  // Retained measurements are deleted even if exports are disabled,
+  // since exports may have been enabled in earlier runs
+  EpochMeasurement::delete_epoch(conn.clone(), &epoch_config.channel_name, epoch).await?;
+  RecoveredMessage::delete_epoch(
+    conn.clone(),
+    &epoch_config.channel_name,
+    epoch,
     profiler.clone(),
   )
   .await?;
-  RecoveredMessage::delete_epoch(conn.clone(), epoch, profiler.clone()).await?;
-  PendingMessage::delete_epoch(conn, epoch, profiler).await?;
+  PendingMessage::delete_epoch(conn, &epoch_config.channel_name, epoch, profiler).await?;
   Ok(())
 }
 
-pub async fn process_expired_epochs(
-  conn: Arc<Mutex<DBConnection>>,
+/// Finalizes a single expired epoch using a dedicated connection.
+/// Returns false if finalization was skipped due to mixed configurations.
+#[allow(clippy::too_many_arguments)]
+async fn finalize_expired_epoch(
+  db_pool: &DBPool,
   epoch_config: &EpochConfig,
-  out_stream: Option<RecordStreamArc>,
+  config_json: &str,
+  force_finalization: bool,
+  limits: &ConcurrencyLimits,
+  outbox_relay: Option<&OutboxRelay>,
+  privacy_report_lake: Option<&DataLake>,
+  export_lake: Option<&DataLake>,
+  key_exporter: Option<&EpochKeyExporter>,
   profiler: Arc<Profiler>,
-) -> Result<(), AggregatorError> {
-  let epochs = RecoveredMessage::list_distinct_epochs(conn.clone()).await?;
-  for epoch in epochs {
-    if !epoch_config.is_epoch_expired(epoch as u8) {
-      continue;
+  epoch: i16,
+) -> Result<bool, AggregatorError> {
+  info!("Detected expired epoch '{}', processing...", epoch);
+  let conn = Arc::new(Mutex::new(db_pool.get().await?));
+
+  if !check_epoch_finalization(
+    conn.clone(),
+    &epoch_config.channel_name,
+    config_json,
+    epoch,
+    force_finalization,
+  )
+  .await?
+  {
+    error!(
+      "Epoch '{}' was processed with a different configuration, skipping finalization. Use --force-epoch-finalization to finalize anyway.",
+      epoch
+    );
+    return Ok(false);
+  }
+
+  // Generate the privacy report before any messages are deleted
+  let report =
+    EpochPrivacyReport::generate(conn.clone(), epoch_config, epoch as u8, profiler.clone()).await?;
+  let report_json = serde_json::to_string(&report)?;
+  match privacy_report_lake {
+    Some(lake) => {
+      lake
+        .store_privacy_report(
+          &epoch_config.channel_name,
+          &report.epoch_date,
+          report.epoch,
+          &report_json,
+        )
+        .await?
     }
-    info!("Detected expired epoch '{}', processing...", epoch);
-    if let Some(out_stream) = out_stream.as_ref() {
-      out_stream.init_producer_queues().await;
-      out_stream.begin_producer_transaction()?;
+    None => info!("Privacy report for epoch '{}': {}", epoch, report_json),
+  }
+  if let Some(key_exporter) = key_exporter {
+    key_exporter
+      .export(conn.clone(), epoch_config, epoch as u8, profiler.clone())
+      .await?;
+  }
+
+  let _db_write_permit = limits.db_writes.acquire().await.unwrap();
+  begin_db_transaction(conn.clone())?;
+  record_finalized_config(conn.clone(), &epoch_config.channel_name, config_json, epoch).await?;
+  process_expired_epoch(
+    conn.clone(),
+    epoch_config,
+    outbox_relay.is_some(),
+    export_lake,
+    profiler,
+    epoch,
+  )
+  .await?;
+  commit_db_transaction(conn)?;
+
+  if let Some(outbox_relay) = outbox_relay {
+    outbox_relay.notify();
+  }
+  Ok(true)
+}
+
+/// Finalizes expired epochs. `config_json` is the effective configuration
+/// used for finalization. Epochs processed with a different configuration
+/// are skipped, unless `force_finalization` is set.
+///
+/// Up to `EXPIRED_EPOCH_CONCURRENCY` epochs are finalized concurrently. Each epoch
+/// is finalized in a separate transaction, on a separate connection.
+/// If a close check is provided, expired epochs that are not closed
+/// by the consumed record watermark are deferred to a later run.
+/// Returns the epochs that were finalized, with the time taken to finalize each epoch.
+#[allow(clippy::too_many_arguments)]
+pub async fn process_expired_epochs(
+  db_pool: &DBPool,
+  epoch_config: &EpochConfig,
+  config_json: &str,
+  force_finalization: bool,
+  limits: &ConcurrencyLimits,
+  outbox_relay: Option<&OutboxRelay>,
+  privacy_report_lake: Option<&DataLake>,
+  export_lake: Option<&DataLake>,
+  key_exporter: Option<&EpochKeyExporter>,
+  close_check: Option<&EpochCloseCheck>,
+  profiler: Arc<Profiler>,
+) -> Result<Vec<(u8, Duration)>, AggregatorError> {
+  let concurrency = parse_env_var::<usize>(
+    EXPIRED_EPOCH_CONCURRENCY_ENV_KEY,
+    EXPIRED_EPOCH_CONCURRENCY_DEFAULT,
+  )
+  .max(1);
+  let expired_epochs: Vec<i16> = RecoveredMessage::list_distinct_epochs(
+    Arc::new(Mutex::new(db_pool.get().await?)),
+    &epoch_config.channel_name,
+  )
+  .await?
+  .into_iter()
+  .filter(|epoch| epoch_config.is_epoch_expired(*epoch as u8))
+  .filter(|epoch| match close_check {
+    Some(close_check) if !close_check.is_epoch_closed(epoch_config, *epoch as u8) => {
+      info!(
+        "Epoch '{}' has expired, but the record watermark has not passed its end; deferring finalization",
+        epoch
+      );
+      false
     }
-    begin_db_transaction(conn.clone())?;
-
-    tokio::select! {
-      res = process_expired_epoch(conn.clone(), epoch_config, out_stream.as_ref().map(|v| v.as_ref()), profiler.clone(), epoch) => {
-        res?
-      },
-      termination_res = check_spot_termination_status(true) => {
-        return Err(termination_res.unwrap_err());
+    _ => true,
+  })
+  .collect();
+  let expired_epoch_count = expired_epochs.len();
+  start_phase("expired_epochs", Some(expired_epoch_count as u64));
+
+  let mut finalizations = stream::iter(expired_epochs)
+    .map(|epoch| {
+      let profiler = profiler.clone();
+      async move {
+        let start_instant = Instant::now();
+        let finalized = finalize_expired_epoch(
+          db_pool,
+          epoch_config,
+          config_json,
+          force_finalization,
+          limits,
+          outbox_relay,
+          privacy_report_lake,
+          export_lake,
+          key_exporter,
+          profiler,
+          epoch,
+        )
+        .await?;
+        Ok::<_, AggregatorError>((epoch, finalized, start_instant.elapsed()))
       }
-    };
+    })
+    .buffer_unordered(concurrency);
+
+  let process_all = async {
+    let mut finalized_epochs = Vec::new();
+    let mut completed_count = 0;
+    while let Some(result) = finalizations.next().await {
+      let (epoch, finalized, elapsed) = result?;
+      completed_count += 1;
+      if finalized {
+        info!(
+          "Finalized expired epoch '{}' in {:.1}s ({}/{})",
+          epoch,
+          elapsed.as_secs_f64(),
+          completed_count,
+          expired_epoch_count
+        );
+        finalized_epochs.push((epoch as u8, elapsed));
+      }
+      record_progress(1);
+    }
+    Ok::<_, AggregatorError>(finalized_epochs)
+  };
 
-    if let Some(out_stream) = out_stream.as_ref() {
-      wait_and_commit_producer(out_stream).await?;
+  let finalized_epochs = tokio::select! {
+    res = process_all => res?,
+    termination_res = check_spot_termination_status(true) => {
+      return Err(termination_res.unwrap_err());
     }
-    commit_db_transaction(conn.clone())?;
+  };
+  end_phase();
+  Ok(finalized_epochs)
+}
+
+/// Deletes pending messages older than the max age, even if their tags
+/// have not met the threshold. If a dead letter stream is provided,
+/// the discarded messages will be sent to it before the deletion is committed.
+/// Returns the number of discarded messages.
+pub async fn discard_aged_pending_msgs(
+  conn: Arc<Mutex<DBConnection>>,
+  epoch_config: &EpochConfig,
+  max_age: CalendarDuration,
+  dlq_stream: Option<&DynRecordStream>,
+  profiler: Arc<Profiler>,
+) -> Result<usize, AggregatorError> {
+  let cutoff = OffsetDateTime::now_utc() - max_age;
+  begin_db_transaction(conn.clone())?;
+  let discarded_msgs = PendingMessage::delete_older_than(
+    conn.clone(),
+    &epoch_config.channel_name,
+    cutoff,
+    profiler.clone(),
+  )
+  .await?;
+  if let Some(dlq_stream) = dlq_stream {
+    let submission_msgs = discarded_msgs
+      .iter()
+      .map(|msg| msg.submission_message())
+      .collect::<Result<Vec<_>, _>>()?;
+    let records: Vec<_> = discarded_msgs
+      .iter()
+      .zip(submission_msgs.iter())
+      .map(|(msg, data)| BatchRecord {
+        data,
+        request_threshold: Some(msg.threshold as usize),
+        channel_name: Some(&epoch_config.channel_name),
+        epoch: Some(msg.epoch_tag as u8),
+        ..Default::default()
+      })
+      .collect();
+    dlq_stream.produce_batch(&records).await?;
   }
-  Ok(())
+  commit_db_transaction(conn)?;
+  profiler
+    .record_range(
+      ProfilerStat::AgedPendingMsgsDiscarded,
+      discarded_msgs.len() as u32,
+      " msgs",
+    )
+    .await;
+  Ok(discarded_msgs.len())
 }
 
+/// Recovered key, along with the messages used for key recovery (if any)
+type RecoveryKeyInfo = (Vec<u8>, Option<Vec<NestedMessage>>);
+
+/// Next layer of grouped messages, pending tags to remove, error count,
+/// and whether any tags were processed
+type LayerResult = (GroupedMessages, Vec<(u8, Vec<u8>)>, usize, bool);
+
 fn drain_chunk_messages_for_threshold(
   chunk: &mut MessageChunk,
   threshold: usize,
@@ -105,7 +355,7 @@ fn get_recovery_key(
   chunk: &mut MessageChunk,
   recovery_threshold: Option<usize>,
   existing_rec_msg: Option<&&mut RecoveredMessage>,
-) -> Result<Option<(Vec<u8>, Option<Vec<NestedMessage>>)>, AggregatorError> {
+) -> Result<Option<RecoveryKeyInfo>, AggregatorError> {
   let mut key_recovery_msgs: Option<Vec<_>> = None;
 
   // if a recovered msg exists, use the key that was already recovered.
@@ -146,11 +396,15 @@ fn get_recovery_key(
   Ok(Some((key, key_recovery_msgs)))
 }
 
+/// For counting-only channels, the measurement of each tag is recovered from a single
+/// message, and all messages of the tag are counted without decrypting the next layer.
 fn process_one_layer(
   grouped_msgs: &mut GroupedMessages,
   rec_msgs: &mut RecoveredMessages,
-) -> Result<(GroupedMessages, Vec<(u8, Vec<u8>)>, usize, bool), AggregatorError> {
-  let mut next_grouped_msgs = GroupedMessages::default();
+  channel_name: &str,
+  counting_only: bool,
+) -> Result<LayerResult, AggregatorError> {
+  let mut next_grouped_msgs = grouped_msgs.next_layer();
   let mut pending_tags_to_remove = Vec::new();
   let mut total_error_count = 0;
   let mut has_processed = false;
@@ -168,6 +422,8 @@ fn process_one_layer(
       }
 
       let has_pending_msgs = chunk.pending_msgs.values().any(|v| !v.is_empty());
+      // All messages in the chunk are drained once the key is available
+      let submission_range = chunk.full_submission_range();
 
       let (key, mut key_recovery_msgs) =
         match get_recovery_key(*epoch, chunk, recovery_threshold, existing_rec_msg.as_ref())? {
@@ -207,7 +463,14 @@ fn process_one_layer(
           measurement,
           next_layer_messages,
           error_count,
-        } = recover_msgs(msgs, &key)?;
+        } = match counting_only {
+          true => MsgRecoveryInfo {
+            measurement: recover_layer_measurement(&msgs, &key)?,
+            next_layer_messages: None,
+            error_count: 0,
+          },
+          false => recover_msgs(msgs, &key)?,
+        };
 
         metric_name = Some(measurement.0);
         metric_value = Some(measurement.1);
@@ -220,14 +483,29 @@ fn process_one_layer(
         // save messages in the next layer in a new GroupedMessages struct
         if let Some(child_msgs) = next_layer_messages {
           for msg in child_msgs {
-            next_grouped_msgs.add(MessageWithThreshold { msg, threshold }, Some(msg_tag));
+            // Child messages inherit the submission times of the parent chunk
+            next_grouped_msgs.add(
+              MessageWithThreshold {
+                msg,
+                threshold,
+                submission_range,
+              },
+              Some(msg_tag),
+            );
           }
         }
       }
 
+      // Messages past the share retention limit are counted without recovery
+      msgs_len += chunk.excess_count;
+      chunk.excess_count = 0;
+
       // create or update recovered msg with new count
       if let Some(rec_msg) = existing_rec_msg {
         rec_msg.count += msgs_len;
+        let mut rec_submission_range = rec_msg.submission_range();
+        rec_submission_range.merge(&submission_range);
+        rec_msg.set_submission_range(rec_submission_range);
       } else {
         rec_msgs.add(RecoveredMessage {
           id: 0,
@@ -239,6 +517,9 @@ fn process_one_layer(
           count: msgs_len,
           key: key.to_vec(),
           has_children,
+          channel_name: Some(channel_name.to_string()),
+          first_submitted_at: submission_range.earliest,
+          last_submitted_at: submission_range.latest,
         });
       }
 
@@ -257,16 +538,54 @@ fn process_one_layer(
   ))
 }
 
+/// Processing phase of a subtask, reported if the subtask fails
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum SubtaskPhase {
+  #[display(fmt = "fetching recovered messages")]
+  FetchRecovered,
+  #[display(fmt = "fetching pending messages")]
+  FetchPending,
+  #[display(fmt = "recovering messages")]
+  Recovery,
+  #[display(fmt = "storing pending messages")]
+  StorePending,
+  #[display(fmt = "deleting pending messages")]
+  DeletePending,
+  #[display(fmt = "reporting measurements")]
+  Report,
+  #[display(fmt = "saving recovered messages")]
+  SaveRecovered,
+}
+
+/// Handle of a spawned subtask, along with context for failure reports
+pub struct Subtask {
+  pub id: usize,
+  pub tag_count: usize,
+  pub phase: Arc<Mutex<SubtaskPhase>>,
+  pub handle: JoinHandle<(i64, usize)>,
+}
+
+fn set_phase(phase: &Mutex<SubtaskPhase>, new_phase: SubtaskPhase) {
+  *phase.lock().unwrap() = new_phase;
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start_subtask(
   id: usize,
   store_conns: Arc<DBStorageConnections>,
   db_pool: Arc<DBPool>,
-  out_stream: Option<RecordStreamArc>,
+  use_outbox: bool,
   mut grouped_msgs: GroupedMessages,
   epoch_config: Arc<EpochConfig>,
+  limits: Arc<ConcurrencyLimits>,
+  key_cache: Option<Arc<RecoveredKeyCache>>,
   profiler: Arc<Profiler>,
-) -> JoinHandle<(i64, usize)> {
-  tokio::spawn(async move {
+) -> Subtask {
+  let tag_count = grouped_msgs.tag_count();
+  let phase = Arc::new(Mutex::new(SubtaskPhase::FetchRecovered));
+  let task_phase = phase.clone();
+  let handle = tokio::spawn(async move {
+    let phase = task_phase;
     let mut pending_tags_to_remove = Vec::new();
 
     let mut rec_msgs = RecoveredMessages::default();
@@ -282,38 +601,75 @@ pub fn start_subtask(
       );
       // Fetch recovered message info (which includes key) for collected tags, if available
       debug!("Task {}: Fetching recovered messages", id);
+      set_phase(&phase, SubtaskPhase::FetchRecovered);
       grouped_msgs
-        .fetch_recovered(db_pool.clone(), &mut rec_msgs, profiler.clone())
+        .fetch_recovered(
+          db_pool.clone(),
+          &epoch_config.channel_name,
+          &mut rec_msgs,
+          key_cache.as_deref(),
+          profiler.clone(),
+        )
         .await
         .unwrap();
 
       // Fetch pending messages for collected tags, if available
       debug!("Task {}: Fetching pending messages", id);
+      set_phase(&phase, SubtaskPhase::FetchPending);
       grouped_msgs
-        .fetch_pending(db_pool.clone(), &mut rec_msgs, profiler.clone())
+        .fetch_pending(
+          db_pool.clone(),
+          &epoch_config.channel_name,
+          &mut rec_msgs,
+          profiler.clone(),
+        )
         .await
         .unwrap();
 
-      let tag_count = grouped_msgs
-        .msg_chunks
-        .values()
-        .map(|c| c.len())
-        .sum::<usize>();
+      let tag_count = grouped_msgs.tag_count();
       debug!(
         "Task {}: Starting actual processing (tag count = {})",
         id, tag_count
       );
+      let excess_count = grouped_msgs.excess_count();
+      set_phase(&phase, SubtaskPhase::Recovery);
+      let recovery_permit = limits.recoveries.acquire().await.unwrap();
       let (new_grouped_msgs, pending_tags_to_remove_chunk, layer_error_count, has_processed) =
-        process_one_layer(&mut grouped_msgs, &mut rec_msgs).unwrap();
+        process_one_layer(
+          &mut grouped_msgs,
+          &mut rec_msgs,
+          &epoch_config.channel_name,
+          epoch_config.counting_only,
+        )
+        .unwrap();
+      drop(recovery_permit);
       error_count += layer_error_count;
+      let counted_excess_count = excess_count - grouped_msgs.excess_count();
+      if counted_excess_count > 0 {
+        profiler
+          .record_range(
+            ProfilerStat::ExcessSharesCounted,
+            counted_excess_count as u32,
+            " msgs",
+          )
+          .await;
+      }
+      if it_count == 1 {
+        // Progress is measured by top-level tags, since the amount of
+        // nested tags is not known in advance
+        record_progress(tag_count as u64);
+      }
 
       pending_tags_to_remove.extend(pending_tags_to_remove_chunk);
 
       debug!("Task {}: Storing new pending messages", id);
+      set_phase(&phase, SubtaskPhase::StorePending);
+      let db_write_permit = limits.db_writes.acquire().await.unwrap();
       grouped_msgs
-        .store_new_pending_msgs(&store_conns, profiler.clone())
+        .store_new_pending_msgs(&store_conns, &epoch_config.channel_name, profiler.clone())
         .await
         .unwrap();
+      drop(db_write_permit);
 
       if !has_processed {
         break;
@@ -323,38 +679,71 @@ pub fn start_subtask(
       grouped_msgs = new_grouped_msgs;
     }
 
+    let db_write_permit = limits.db_writes.acquire().await.unwrap();
     info!("Task {}: Deleting old pending messages", id);
+    set_phase(&phase, SubtaskPhase::DeletePending);
     for (epoch, msg_tag) in pending_tags_to_remove {
-      PendingMessage::delete_tag(store_conns.get(), epoch as i16, msg_tag, profiler.clone())
-        .await
-        .unwrap();
+      PendingMessage::delete_tag(
+        store_conns.get(),
+        &epoch_config.channel_name,
+        epoch as i16,
+        msg_tag,
+        profiler.clone(),
+      )
+      .await
+      .unwrap();
     }
 
     // Check for full recovered measurements, send off measurements to Kafka to be
     // stored in data lake/warehouse
     info!("Task {}: Reporting final measurements", id);
+    set_phase(&phase, SubtaskPhase::Report);
     let rec_epochs: Vec<u8> = rec_msgs.map.keys().cloned().collect();
     let mut measurements_count = 0;
     for epoch in rec_epochs {
+      let output_buffer = use_outbox.then(MeasurementBuffer::default);
       measurements_count += report_measurements(
         &mut rec_msgs,
         epoch_config.as_ref(),
         epoch,
         false,
-        out_stream.as_ref().map(|v| v.as_ref()),
-        profiler.clone(),
+        output_buffer.as_ref(),
       )
       .await
       .unwrap();
+      if let Some(output_buffer) = output_buffer {
+        // Measurements are stored within the storage transactions,
+        // so that they are committed along with the updated counts
+        store_outbox_measurements(
+          store_conns.get(),
+          &epoch_config.channel_name,
+          epoch,
+          output_buffer,
+          profiler.clone(),
+        )
+        .await
+        .unwrap();
+      }
     }
 
     info!("Task {}: Saving recovered messages", id);
+    set_phase(&phase, SubtaskPhase::SaveRecovered);
+    if let Some(key_cache) = key_cache.as_ref() {
+      key_cache.update(&rec_msgs);
+    }
     rec_msgs.save(&store_conns, profiler.clone()).await.unwrap();
+    drop(db_write_permit);
 
     profiler
       .record_range_time(ProfilerStat::TaskProcessingTime, processing_start_instant)
       .await;
 
     (measurements_count, error_count)
-  })
+  });
+  Subtask {
+    id,
+    tag_count,
+    phase,
+    handle,
+  }
 }
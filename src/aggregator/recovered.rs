@@ -55,6 +55,7 @@ impl RecoveredMessages {
   pub async fn fetch_recovered(
     &mut self,
     conn: Arc<Mutex<DBConnection>>,
+    channel_name: &str,
     epoch: u8,
     msg_tags: Vec<Vec<u8>>,
     profiler: Arc<Profiler>,
@@ -62,6 +63,7 @@ impl RecoveredMessages {
     for msg_tags in msg_tags.chunks(FETCH_BATCH_SIZE) {
       let recovered_msgs = RecoveredMessage::list(
         conn.clone(),
+        channel_name,
         epoch as i16,
         msg_tags.to_vec(),
         profiler.clone(),
@@ -77,11 +79,12 @@ impl RecoveredMessages {
   pub async fn fetch_all_recovered_with_nonzero_count(
     &mut self,
     conn: Arc<Mutex<DBConnection>>,
+    channel_name: &str,
     epoch: u8,
     profiler: Arc<Profiler>,
   ) -> Result<(), AggregatorError> {
     let recovered_msgs =
-      RecoveredMessage::list_with_nonzero_count(conn, epoch as i16, profiler).await?;
+      RecoveredMessage::list_with_nonzero_count(conn, channel_name, epoch as i16, profiler).await?;
     for rec_msg in recovered_msgs {
       self.add(rec_msg);
     }
@@ -103,6 +106,7 @@ impl RecoveredMessages {
             store_conns.get(),
             rec_msg.id,
             rec_msg.count,
+            rec_msg.submission_range(),
             profiler.clone(),
           )
           .await?;
@@ -125,6 +129,8 @@ mod tests {
   use crate::models::{DBConnectionType, DBPool};
   use dotenvy::dotenv;
 
+  const TEST_CHANNEL_NAME: &str = "typical";
+
   #[tokio::test]
   async fn add_and_save() {
     dotenv().ok();
@@ -142,6 +148,9 @@ mod tests {
         count: 12,
         key: vec![88; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -153,6 +162,9 @@ mod tests {
         count: 25,
         key: vec![77; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -164,6 +176,9 @@ mod tests {
         count: 7,
         key: vec![99; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
     ];
 
@@ -206,7 +221,13 @@ mod tests {
         vec![55u8; 20],
       ];
       recovered_msgs
-        .fetch_recovered(conn.clone(), epoch, tags, profiler.clone())
+        .fetch_recovered(
+          conn.clone(),
+          TEST_CHANNEL_NAME,
+          epoch,
+          tags,
+          profiler.clone(),
+        )
         .await
         .unwrap();
     }
@@ -242,6 +263,9 @@ mod tests {
         count: 20,
         key: vec![20; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       NewRecoveredMessage {
         msg_tag: vec![60; 20],
@@ -252,6 +276,9 @@ mod tests {
         count: 40,
         key: vec![40; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
     ];
 
@@ -267,11 +294,16 @@ mod tests {
     let mut recovered_msgs = RecoveredMessages::default();
 
     for epoch in 3..=4 {
-      let mut rec_msg =
-        RecoveredMessage::list(conn.clone(), epoch, vec![vec![60; 20]], profiler.clone())
-          .await
-          .unwrap()[0]
-          .clone();
+      let mut rec_msg = RecoveredMessage::list(
+        conn.clone(),
+        TEST_CHANNEL_NAME,
+        epoch,
+        vec![vec![60; 20]],
+        profiler.clone(),
+      )
+      .await
+      .unwrap()[0]
+        .clone();
       rec_msg.count += 5;
       recovered_msgs.add(rec_msg);
     }
@@ -286,15 +318,68 @@ mod tests {
     let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
 
     for epoch in 3..=4 {
-      let rec_msg =
-        RecoveredMessage::list(conn.clone(), epoch, vec![vec![60; 20]], profiler.clone())
-          .await
-          .unwrap()[0]
-          .clone();
+      let rec_msg = RecoveredMessage::list(
+        conn.clone(),
+        TEST_CHANNEL_NAME,
+        epoch,
+        vec![vec![60; 20]],
+        profiler.clone(),
+      )
+      .await
+      .unwrap()[0]
+        .clone();
       assert_eq!(rec_msg.count, rec_msg.key[0] as i64 + 5);
     }
   }
 
+  #[tokio::test]
+  async fn channel_isolation() {
+    dotenv().ok();
+    let profiler = Arc::new(Profiler::default());
+
+    let new_rec_msgs: Vec<NewRecoveredMessage> = [Some("other"), None]
+      .into_iter()
+      .enumerate()
+      .map(|(i, channel_name)| NewRecoveredMessage {
+        msg_tag: vec![70 + i as u8; 20],
+        epoch_tag: 5,
+        metric_name: "test".to_string(),
+        metric_value: "1".to_string(),
+        parent_recovered_msg_tag: None,
+        count: 10,
+        key: vec![10; 32],
+        has_children: false,
+        channel_name: channel_name.map(|v| v.to_string()),
+        first_submitted_at: None,
+        last_submitted_at: None,
+      })
+      .collect();
+
+    let db_pool = Arc::new(DBPool::new(DBConnectionType::Test));
+    let conn = Arc::new(Mutex::new(db_pool.get().await.unwrap()));
+    new_rec_msgs
+      .insert_batch(conn.clone(), profiler.clone())
+      .await
+      .unwrap();
+
+    let mut recovered_msgs = RecoveredMessages::default();
+    recovered_msgs
+      .fetch_recovered(
+        conn.clone(),
+        TEST_CHANNEL_NAME,
+        5,
+        vec![vec![70; 20], vec![71; 20]],
+        profiler.clone(),
+      )
+      .await
+      .unwrap();
+
+    // Messages from other channels should be excluded, legacy messages
+    // without a channel should be included
+    assert!(recovered_msgs.get_mut(5, &[70; 20]).is_none());
+    assert!(recovered_msgs.get_mut(5, &[71; 20]).is_some());
+  }
+
   #[tokio::test]
   async fn parent_tags() {
     dotenv().ok();
@@ -312,6 +397,9 @@ mod tests {
         count: 12,
         key: vec![88; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -323,6 +411,9 @@ mod tests {
         count: 25,
         key: vec![77; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -334,6 +425,9 @@ mod tests {
         count: 7,
         key: vec![99; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
     ];
 
@@ -1,43 +1,47 @@
+use super::measurement::{InvalidMeasurement, MeasurementRecord};
+use super::outbox::MeasurementBuffer;
 use super::recovered::RecoveredMessages;
 use super::AggregatorError;
 use crate::epoch::EpochConfig;
-use crate::profiler::{Profiler, ProfilerStat};
-use crate::record_stream::DynRecordStream;
+use crate::models::SubmissionTimeRange;
 use futures::future::{BoxFuture, FutureExt};
 use serde_json::Value;
-use std::collections::HashMap;
 use std::str::from_utf8;
-use std::sync::Arc;
-use std::time::Instant;
 
 fn build_full_measurement_json(
   metric_chain: Vec<(String, Value)>,
-  epoch_date_field_name: &str,
+  epoch_config: &EpochConfig,
   epoch_start_date: &str,
   count: i64,
+  submission_range: &SubmissionTimeRange,
 ) -> Result<Vec<u8>, AggregatorError> {
-  let mut full_measurement = HashMap::new();
-  for metric in metric_chain {
-    full_measurement.insert(metric.0, metric.1);
-  }
-  full_measurement.insert("total".to_string(), count.into());
-  full_measurement.insert(
-    epoch_date_field_name.to_string(),
-    epoch_start_date.to_string().into(),
-  );
-  Ok(serde_json::to_vec(&full_measurement)?)
+  // metric chain fields + epoch date field
+  let expected_field_count = metric_chain.len() + 1;
+  let record = MeasurementRecord::new(
+    metric_chain,
+    &epoch_config.channel_name,
+    epoch_config.sampling_rate,
+    &epoch_config.epoch_date_field_name,
+    epoch_start_date,
+    count,
+  )
+  .with_submission_range(submission_range);
+  record
+    .validate(expected_field_count)
+    .map_err(|reason| InvalidMeasurement { reason })?;
+  Ok(serde_json::to_vec(&record)?)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn report_measurements_recursive<'a>(
   rec_msgs: &'a mut RecoveredMessages,
   epoch: u8,
-  epoch_date_field_name: &'a str,
+  epoch_config: &'a EpochConfig,
   epoch_start_date: &'a str,
   partial_report: bool,
-  out_stream: Option<&'a DynRecordStream>,
+  output_buffer: Option<&'a MeasurementBuffer>,
   metric_chain: Vec<(String, Value)>,
   parent_msg_tag: Option<Vec<u8>>,
-  profiler: Arc<Profiler>,
 ) -> BoxFuture<'a, Result<i64, AggregatorError>> {
   async move {
     let tags = rec_msgs.get_tags_by_parent(epoch, parent_msg_tag);
@@ -51,7 +55,10 @@ fn report_measurements_recursive<'a>(
       }
 
       let mut metric_chain = metric_chain.clone();
-      metric_chain.push((msg.metric_name.clone(), msg.metric_value.clone().into()));
+      let metric_value = epoch_config
+        .rollup_rules
+        .apply(&msg.metric_name, msg.metric_value.clone());
+      metric_chain.push((msg.metric_name.clone(), metric_value.into()));
 
       // is_msmt_final: true if the current measurement should be reported right now
       // i.e. all layers have been recovered
@@ -59,13 +66,12 @@ fn report_measurements_recursive<'a>(
         let children_rec_count = report_measurements_recursive(
           rec_msgs,
           epoch,
-          epoch_date_field_name,
+          epoch_config,
           epoch_start_date,
           partial_report,
-          out_stream,
+          output_buffer,
           metric_chain.clone(),
           Some(tag),
-          profiler.clone(),
         )
         .await?;
 
@@ -90,19 +96,18 @@ fn report_measurements_recursive<'a>(
         recovered_count += msg.count;
         let full_msmt = build_full_measurement_json(
           metric_chain,
-          epoch_date_field_name,
+          epoch_config,
           epoch_start_date,
           msg.count,
+          &msg.submission_range(),
         )?;
-        let start_instant = Instant::now();
-        match out_stream {
-          Some(o) => o.queue_produce(full_msmt).await?,
+        match output_buffer {
+          Some(b) => b.lock().unwrap().push(full_msmt),
           None => println!("{}", from_utf8(&full_msmt)?),
         };
-        profiler
-          .record_range_time(ProfilerStat::OutStreamProduceTime, start_instant)
-          .await;
         msg.count = 0;
+        // Later reports for the tag will only cover newly counted messages
+        msg.set_submission_range(SubmissionTimeRange::default());
       }
       rec_msgs.add(msg);
     }
@@ -112,29 +117,26 @@ fn report_measurements_recursive<'a>(
   .boxed()
 }
 
+/// If an output buffer is not provided, measurements will be printed to stdout.
 pub async fn report_measurements(
   rec_msgs: &mut RecoveredMessages,
   epoch_config: &EpochConfig,
   epoch: u8,
   partial_report: bool,
-  out_stream: Option<&DynRecordStream>,
-  profiler: Arc<Profiler>,
+  output_buffer: Option<&MeasurementBuffer>,
 ) -> Result<i64, AggregatorError> {
   let epoch_start_date = epoch_config.get_epoch_survey_date(epoch);
-  Ok(
-    report_measurements_recursive(
-      rec_msgs,
-      epoch,
-      &epoch_config.epoch_date_field_name,
-      &epoch_start_date,
-      partial_report,
-      out_stream,
-      Vec::new(),
-      None,
-      profiler,
-    )
-    .await?,
+  report_measurements_recursive(
+    rec_msgs,
+    epoch,
+    epoch_config,
+    &epoch_start_date,
+    partial_report,
+    output_buffer,
+    Vec::new(),
+    None,
   )
+  .await
 }
 
 #[cfg(test)]
@@ -146,15 +148,20 @@ mod tests {
   use super::*;
   use crate::epoch::CurrentEpochInfo;
   use crate::models::RecoveredMessage;
-  use crate::record_stream::TestRecordStream;
+  use crate::rollup::RollupRules;
+  use std::sync::Mutex;
 
   fn test_epoch_config(epoch: u8) -> EpochConfig {
     let epoch_length = CalendarDuration::from("1w");
     EpochConfig {
+      channel_name: "typical".to_string(),
       current_epoch: CurrentEpochInfo::test_info(epoch, epoch_length),
       epoch_date_field_name: "wos".to_string(),
       epoch_length,
       epoch_lifetime_count: 3,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
     }
   }
 
@@ -164,9 +171,8 @@ mod tests {
 
   #[tokio::test]
   async fn full_report() {
-    let record_stream = TestRecordStream::default();
+    let output_buffer = Mutex::new(Vec::new());
     let mut recovered_msgs = RecoveredMessages::default();
-    let profiler = Arc::new(Profiler::default());
 
     let new_rec_msgs = vec![
       RecoveredMessage {
@@ -179,6 +185,9 @@ mod tests {
         count: 22,
         key: vec![88; 32],
         has_children: false,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -190,6 +199,9 @@ mod tests {
         count: 72,
         key: vec![88; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -201,6 +213,9 @@ mod tests {
         count: 25,
         key: vec![77; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -212,6 +227,9 @@ mod tests {
         count: 7,
         key: vec![99; 32],
         has_children: false,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -223,6 +241,9 @@ mod tests {
         count: 10,
         key: vec![99; 32],
         has_children: false,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
     ];
 
@@ -234,24 +255,23 @@ mod tests {
       &test_epoch_config(2),
       2,
       false,
-      Some(&record_stream),
-      profiler,
+      Some(&output_buffer),
     )
     .await
     .unwrap();
 
     assert_eq!(rec_count, 17);
-    let records = parse_and_sort_records(record_stream.records_produced.into_inner());
+    let records = parse_and_sort_records(output_buffer.into_inner().unwrap());
 
     let date = expected_date();
     assert_eq!(records.len(), 2);
     assert_eq!(
       records[0],
-      json!({ "a": "1", "b": "2", "c": "3", "total": 7, "wos": date })
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "b": "2", "c": "3", "total": 7, "wos": date })
     );
     assert_eq!(
       records[1],
-      json!({ "a": "1", "b": "2", "c": "4", "total": 10, "wos": date })
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "b": "2", "c": "4", "total": 10, "wos": date })
     );
 
     let rec_epoch_map = recovered_msgs.map.get(&1).unwrap();
@@ -265,9 +285,8 @@ mod tests {
 
   #[tokio::test]
   async fn partial_report() {
-    let record_stream = TestRecordStream::default();
+    let output_buffer = Mutex::new(Vec::new());
     let mut recovered_msgs = RecoveredMessages::default();
-    let profiler = Arc::new(Profiler::default());
 
     let new_rec_msgs = vec![
       RecoveredMessage {
@@ -280,6 +299,9 @@ mod tests {
         count: 82,
         key: vec![88; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -291,6 +313,9 @@ mod tests {
         count: 27,
         key: vec![77; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -302,6 +327,9 @@ mod tests {
         count: 25,
         key: vec![77; 32],
         has_children: true,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -313,6 +341,9 @@ mod tests {
         count: 0,
         key: vec![99; 32],
         has_children: false,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
       RecoveredMessage {
         id: 0,
@@ -324,6 +355,9 @@ mod tests {
         count: 0,
         key: vec![99; 32],
         has_children: false,
+        channel_name: None,
+        first_submitted_at: None,
+        last_submitted_at: None,
       },
     ];
 
@@ -335,25 +369,27 @@ mod tests {
       &test_epoch_config(2),
       2,
       true,
-      Some(&record_stream),
-      profiler,
+      Some(&output_buffer),
     )
     .await
     .unwrap();
 
-    let records = parse_and_sort_records(record_stream.records_produced.into_inner());
+    let records = parse_and_sort_records(output_buffer.into_inner().unwrap());
 
     let date = expected_date();
     assert_eq!(records.len(), 3);
     assert_eq!(
       records[0],
-      json!({ "a": "1", "b": "3", "total": 25, "wos": date }),
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "b": "3", "total": 25, "wos": date }),
     );
     assert_eq!(
       records[1],
-      json!({ "a": "1", "b": "2", "total": 27, "wos": date })
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "b": "2", "total": 27, "wos": date })
+    );
+    assert_eq!(
+      records[2],
+      json!({ "schema_version": 4, "data_channel": "typical", "sampling_rate": 1.0, "a": "1", "total": 30, "wos": date })
     );
-    assert_eq!(records[2], json!({ "a": "1", "total": 30, "wos": date }));
 
     let rec_epoch_map = recovered_msgs.map.get(&2).unwrap();
     assert_eq!(rec_epoch_map.get(&vec![51; 20]).unwrap().count, 0);
@@ -361,6 +397,81 @@ mod tests {
     assert_eq!(rec_epoch_map.get(&vec![53; 20]).unwrap().count, 0);
   }
 
+  #[tokio::test]
+  async fn report_once() {
+    let output_buffer = Mutex::new(Vec::new());
+    let mut recovered_msgs = RecoveredMessages::default();
+    recovered_msgs.add(RecoveredMessage {
+      id: 0,
+      msg_tag: vec![51; 20],
+      epoch_tag: 2,
+      metric_name: "a".to_string(),
+      metric_value: "1".to_string(),
+      parent_recovered_msg_tag: None,
+      count: 12,
+      key: vec![88; 32],
+      has_children: false,
+      channel_name: None,
+      first_submitted_at: None,
+      last_submitted_at: None,
+    });
+
+    // The reported count is reset, so reporting the same tags again
+    // should not emit duplicate measurements
+    for partial_report in [false, false, true] {
+      report_measurements(
+        &mut recovered_msgs,
+        &test_epoch_config(2),
+        2,
+        partial_report,
+        Some(&output_buffer),
+      )
+      .await
+      .unwrap();
+    }
+
+    let records = parse_and_sort_records(output_buffer.into_inner().unwrap());
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].get("total").unwrap(), 12);
+  }
+
+  #[tokio::test]
+  async fn invalid_measurement() {
+    let output_buffer = Mutex::new(Vec::new());
+    let mut recovered_msgs = RecoveredMessages::default();
+    recovered_msgs.add(RecoveredMessage {
+      id: 0,
+      msg_tag: vec![51; 20],
+      epoch_tag: 2,
+      metric_name: "total".to_string(),
+      metric_value: "1".to_string(),
+      parent_recovered_msg_tag: None,
+      count: 12,
+      key: vec![88; 32],
+      has_children: false,
+      channel_name: None,
+      first_submitted_at: None,
+      last_submitted_at: None,
+    });
+    let result = report_measurements(
+      &mut recovered_msgs,
+      &test_epoch_config(2),
+      2,
+      false,
+      Some(&output_buffer),
+    )
+    .await;
+    assert!(matches!(
+      result,
+      Err(AggregatorError::InvalidMeasurement(_))
+    ));
+    assert!(output_buffer.into_inner().unwrap().is_empty());
+
+    // The count is kept, so that it may be reported later
+    let rec_epoch_map = recovered_msgs.map.get(&2).unwrap();
+    assert_eq!(rec_epoch_map.get(&vec![51; 20]).unwrap().count, 12);
+  }
+
   fn parse_and_sort_records(records: Vec<Vec<u8>>) -> Vec<serde_json::Value> {
     let mut result: Vec<serde_json::Value> = records
       .iter()
@@ -0,0 +1,247 @@
+//! Comparison of the measurement output of two aggregator runs, for validating releases.
+//! Both runs should aggregate the same messages, i.e. mirrored traffic of one epoch
+//! processed by the current and the new release, with their output written via
+//! `--output-measurements-to-dir` or `--output-measurements-to-stdout`.
+//!
+//! Measurements are matched by their channel and attributes, including the epoch date,
+//! and the totals of matching measurements are summed across the output of each run.
+//! A difference in totals is tolerated if it is at most `DIFF_RUNS_ABSOLUTE_TOLERANCE`,
+//! or `DIFF_RUNS_RELATIVE_TOLERANCE` of the larger total. Measurements that are only
+//! present in one run are compared against a total of zero.
+
+use super::measurement::MeasurementRecord;
+use super::AggregatorError;
+use crate::util::parse_env_var;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DIFF_RUNS_ABSOLUTE_TOLERANCE_ENV_KEY: &str = "DIFF_RUNS_ABSOLUTE_TOLERANCE";
+const DEFAULT_DIFF_RUNS_ABSOLUTE_TOLERANCE: &str = "0";
+const DIFF_RUNS_RELATIVE_TOLERANCE_ENV_KEY: &str = "DIFF_RUNS_RELATIVE_TOLERANCE";
+const DEFAULT_DIFF_RUNS_RELATIVE_TOLERANCE: &str = "0";
+const MEASUREMENT_FILE_EXTENSION: &str = "jsonl";
+
+/// Channel and serialized attributes of a measurement
+type MeasurementKey = (String, String);
+/// Attributes and summed totals of measurements
+type MeasurementTotals = BTreeMap<MeasurementKey, (BTreeMap<String, Value>, i64)>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiffTolerance {
+  pub absolute: i64,
+  pub relative: f64,
+}
+
+impl DiffTolerance {
+  fn from_env() -> Self {
+    Self {
+      absolute: parse_env_var(
+        DIFF_RUNS_ABSOLUTE_TOLERANCE_ENV_KEY,
+        DEFAULT_DIFF_RUNS_ABSOLUTE_TOLERANCE,
+      ),
+      relative: parse_env_var(
+        DIFF_RUNS_RELATIVE_TOLERANCE_ENV_KEY,
+        DEFAULT_DIFF_RUNS_RELATIVE_TOLERANCE,
+      ),
+    }
+  }
+
+  fn allows(&self, baseline_total: i64, candidate_total: i64) -> bool {
+    let difference = (baseline_total - candidate_total).abs();
+    let relative_limit = self.relative * baseline_total.max(candidate_total) as f64;
+    difference <= self.absolute || difference as f64 <= relative_limit
+  }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MeasurementDiff {
+  pub data_channel: String,
+  pub fields: BTreeMap<String, Value>,
+  pub baseline_total: i64,
+  pub candidate_total: i64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RunDiffReport {
+  pub baseline_measurements: usize,
+  pub candidate_measurements: usize,
+  pub unchanged: usize,
+  /// Measurements with differences within the tolerance
+  pub within_tolerance: usize,
+  /// Measurements only present in the candidate run
+  pub added: Vec<MeasurementDiff>,
+  /// Measurements only present in the baseline run
+  pub missing: Vec<MeasurementDiff>,
+  pub changed: Vec<MeasurementDiff>,
+  /// True if all differences are within the tolerance
+  pub passed: bool,
+}
+
+/// Returns the measurement files at the path. Directories are searched recursively.
+fn measurement_files(path: &Path) -> Result<Vec<PathBuf>, AggregatorError> {
+  if !path.is_dir() {
+    return Ok(vec![path.to_path_buf()]);
+  }
+  let mut result = Vec::new();
+  for entry in fs::read_dir(path)? {
+    let entry_path = entry?.path();
+    if entry_path.is_dir() {
+      result.extend(measurement_files(&entry_path)?);
+    } else if entry_path.extension().and_then(|v| v.to_str()) == Some(MEASUREMENT_FILE_EXTENSION) {
+      result.push(entry_path);
+    }
+  }
+  result.sort();
+  Ok(result)
+}
+
+/// Parses JSON lines of measurements, and sums the totals of matching measurements.
+/// Lines that are not JSON objects, i.e. log output, are skipped.
+fn sum_measurements<'a>(
+  lines: impl Iterator<Item = &'a str>,
+  totals: &mut MeasurementTotals,
+) -> Result<(), AggregatorError> {
+  for line in lines.map(|v| v.trim()).filter(|v| v.starts_with('{')) {
+    let record: MeasurementRecord = serde_json::from_str(line)?;
+    // Attributes are serialized in key order, so equal attributes serialize equally
+    let key = (record.data_channel, serde_json::to_string(&record.fields)?);
+    totals.entry(key).or_insert((record.fields, 0)).1 += record.total;
+  }
+  Ok(())
+}
+
+fn load_measurements(path: &Path) -> Result<MeasurementTotals, AggregatorError> {
+  let mut totals = BTreeMap::new();
+  for file in measurement_files(path)? {
+    sum_measurements(fs::read_to_string(file)?.lines(), &mut totals)?;
+  }
+  Ok(totals)
+}
+
+fn diff_measurements(
+  baseline: &MeasurementTotals,
+  candidate: &MeasurementTotals,
+  tolerance: DiffTolerance,
+) -> RunDiffReport {
+  let mut report = RunDiffReport {
+    baseline_measurements: baseline.len(),
+    candidate_measurements: candidate.len(),
+    ..Default::default()
+  };
+  let keys: BTreeSet<&MeasurementKey> = baseline.keys().chain(candidate.keys()).collect();
+  for key in keys {
+    let (baseline_entry, candidate_entry) = (baseline.get(key), candidate.get(key));
+    let (baseline_total, candidate_total) = (
+      baseline_entry.map(|(_, total)| *total),
+      candidate_entry.map(|(_, total)| *total),
+    );
+    let diff = MeasurementDiff {
+      data_channel: key.0.clone(),
+      fields: baseline_entry.or(candidate_entry).unwrap().0.clone(),
+      baseline_total: baseline_total.unwrap_or_default(),
+      candidate_total: candidate_total.unwrap_or_default(),
+    };
+    if baseline_total == candidate_total {
+      report.unchanged += 1;
+    } else if tolerance.allows(diff.baseline_total, diff.candidate_total) {
+      report.within_tolerance += 1;
+    } else if baseline_total.is_none() {
+      report.added.push(diff);
+    } else if candidate_total.is_none() {
+      report.missing.push(diff);
+    } else {
+      report.changed.push(diff);
+    }
+  }
+  report.passed = report.added.is_empty() && report.missing.is_empty() && report.changed.is_empty();
+  report
+}
+
+/// Compares the measurement output of two runs, and prints the report as JSON.
+/// Returns true if all differences are within the tolerance.
+pub fn diff_runs(baseline_path: &Path, candidate_path: &Path) -> Result<bool, AggregatorError> {
+  let tolerance = DiffTolerance::from_env();
+  let report = diff_measurements(
+    &load_measurements(baseline_path)?,
+    &load_measurements(candidate_path)?,
+    tolerance,
+  );
+  info!(
+    "Compared {} baseline and {} candidate measurements: {} added, {} missing, {} changed",
+    report.baseline_measurements,
+    report.candidate_measurements,
+    report.added.len(),
+    report.missing.len(),
+    report.changed.len()
+  );
+  println!("{}", serde_json::to_string(&report)?);
+  Ok(report.passed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn measurements(lines: &str) -> MeasurementTotals {
+    let mut totals = BTreeMap::new();
+    sum_measurements(lines.lines(), &mut totals).unwrap();
+    totals
+  }
+
+  #[test]
+  fn diff() {
+    let baseline = measurements(concat!(
+      r#"{"schema_version":4,"data_channel":"typical","sampling_rate":1.0,"total":100,"country":"US","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":4,"data_channel":"typical","sampling_rate":1.0,"total":20,"country":"US","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":4,"data_channel":"typical","sampling_rate":1.0,"total":50,"country":"CA","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":4,"data_channel":"typical","sampling_rate":1.0,"total":30,"country":"FR","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":4,"data_channel":"typical","sampling_rate":1.0,"total":2,"country":"DE","wos":"2024-05-06"}"#,
+    ));
+    let candidate = measurements(concat!(
+      "[2024-05-13T00:00:00Z INFO constellation_processors] Starting aggregation...\n",
+      r#"{"schema_version":5,"data_channel":"typical","sampling_rate":1.0,"total":120,"country":"US","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":5,"data_channel":"typical","sampling_rate":1.0,"total":51,"country":"CA","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":5,"data_channel":"typical","sampling_rate":1.0,"total":40,"country":"FR","wos":"2024-05-06"}"#,
+      "\n",
+      r#"{"schema_version":5,"data_channel":"typical","sampling_rate":1.0,"total":25,"country":"JP","wos":"2024-05-06"}"#,
+    ));
+    assert_eq!(baseline.len(), 4);
+
+    let tolerance = DiffTolerance {
+      absolute: 2,
+      relative: 0.1,
+    };
+    let report = diff_measurements(&baseline, &candidate, tolerance);
+    assert_eq!(report.unchanged, 1);
+    // CA differs by 1, and DE is only missing 2 reports
+    assert_eq!(report.within_tolerance, 2);
+    assert_eq!(report.changed.len(), 1);
+    assert_eq!(report.changed[0].fields["country"], "FR");
+    assert_eq!(report.changed[0].baseline_total, 30);
+    assert_eq!(report.changed[0].candidate_total, 40);
+    assert_eq!(report.added.len(), 1);
+    assert_eq!(report.added[0].fields["country"], "JP");
+    assert!(report.missing.is_empty());
+    assert!(!report.passed);
+
+    let exact = DiffTolerance {
+      absolute: 0,
+      relative: 0.0,
+    };
+    let report = diff_measurements(&baseline, &baseline, exact);
+    assert_eq!(report.unchanged, 4);
+    assert!(report.passed);
+    let report = diff_measurements(&baseline, &candidate, exact);
+    assert_eq!(report.missing.len(), 1);
+    assert_eq!(report.changed.len(), 2);
+  }
+}
@@ -0,0 +1,101 @@
+//! Metadata identifying the aggregator run that produced a batch of measurements,
+//! so that downstream consumers can filter or segregate the output of experimental
+//! configurations. The metadata is attached to each output batch as Kafka record
+//! headers, webhook request headers, or user metadata of lake objects.
+
+use crate::epoch::EpochConfig;
+use serde::Serialize;
+use std::collections::HashMap;
+
+const RUN_ID_HEADER_NAME: &str = "run-id";
+const RUN_METADATA_HEADER_NAME: &str = "run-metadata";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RunMetadata {
+  /// Random identifier of the run
+  pub run_id: String,
+  pub version: &'static str,
+  pub k_threshold: usize,
+  pub sampling_rate: f64,
+  pub epoch_length: String,
+  pub epoch_lifetime_count: usize,
+  /// True if finalized epochs may be reprocessed by the run
+  pub reprocess: bool,
+}
+
+impl RunMetadata {
+  pub fn new(epoch_config: &EpochConfig, k_threshold: usize, reprocess: bool) -> Self {
+    Self {
+      run_id: format!("{:016x}", rand::random::<u64>()),
+      version: env!("CARGO_PKG_VERSION"),
+      k_threshold,
+      sampling_rate: epoch_config.sampling_rate,
+      epoch_length: epoch_config.epoch_length.to_string(),
+      epoch_lifetime_count: epoch_config.epoch_lifetime_count,
+      reprocess,
+    }
+  }
+
+  /// Returns the run id & compact JSON metadata, keyed by header name.
+  pub fn headers(&self) -> Vec<(&'static str, String)> {
+    vec![
+      (RUN_ID_HEADER_NAME, self.run_id.clone()),
+      (
+        RUN_METADATA_HEADER_NAME,
+        serde_json::to_string(self).unwrap(),
+      ),
+    ]
+  }
+
+  pub fn kafka_headers(&self) -> Vec<(String, Vec<u8>)> {
+    self
+      .headers()
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), v.into_bytes()))
+      .collect()
+  }
+
+  pub fn object_metadata(&self) -> HashMap<String, String> {
+    self
+      .headers()
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), v))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metadata_headers() {
+    let metadata = RunMetadata {
+      run_id: "00000000000000ab".to_string(),
+      version: "1.1.0",
+      k_threshold: 50,
+      sampling_rate: 0.5,
+      epoch_length: "1w".to_string(),
+      epoch_lifetime_count: 3,
+      reprocess: false,
+    };
+    assert_eq!(
+      metadata.headers(),
+      vec![
+        ("run-id", "00000000000000ab".to_string()),
+        (
+          "run-metadata",
+          concat!(
+            r#"{"run_id":"00000000000000ab","version":"1.1.0","k_threshold":50,"#,
+            r#""sampling_rate":0.5,"epoch_length":"1w","epoch_lifetime_count":3,"reprocess":false}"#
+          )
+          .to_string()
+        ),
+      ]
+    );
+    assert_eq!(
+      metadata.object_metadata().get("run-id").unwrap(),
+      "00000000000000ab"
+    );
+  }
+}
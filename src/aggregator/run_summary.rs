@@ -0,0 +1,195 @@
+//! Summary of an aggregator run. The summary is logged once the run has finished,
+//! and may be written as JSON to `RUN_SUMMARY_PATH` and/or the lake, so that
+//! orchestration can decide on downstream triggers without parsing the logs.
+//! The summary is written even if the run fails.
+
+use super::AggregatorError;
+use crate::lake::DataLake;
+use crate::util::parse_env_var;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+const RUN_SUMMARY_PATH_ENV_KEY: &str = "RUN_SUMMARY_PATH";
+const STORE_RUN_SUMMARIES_ENV_KEY: &str = "STORE_RUN_SUMMARIES";
+const STORE_RUN_SUMMARIES_DEFAULT: &str = "false";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+  Succeeded,
+  Failed,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct EpochRunSummary {
+  /// Amount of messages consumed in committed iterations
+  pub consumed_count: i64,
+  /// Time taken to finalize the epoch, if it was finalized by the run
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub finalization_secs: Option<f64>,
+}
+
+/// Totals of the processed iterations, logged once all iterations have finished
+#[derive(Serialize)]
+pub struct RunSummary {
+  pub run_id: Option<String>,
+  pub channel_name: String,
+  #[serde(with = "time::serde::rfc3339")]
+  pub started_at: OffsetDateTime,
+  #[serde(with = "time::serde::rfc3339::option")]
+  pub finished_at: Option<OffsetDateTime>,
+  pub duration_secs: f64,
+  pub status: Option<RunStatus>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  /// Kind of failure of the error, see `AggregatorError::category`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error_category: Option<&'static str>,
+  pub iterations: usize,
+  pub consumed_count: usize,
+  pub measurement_count: i64,
+  /// Amount of measurements that failed to be recovered due to deserialization errors
+  pub recovery_error_count: usize,
+  /// Amount of pending messages discarded due to the max age
+  pub discarded_count: usize,
+  /// Amount of measurements sent by the outbox relay
+  pub published_count: usize,
+  /// True if an iteration collected fewer messages than the collect count,
+  /// because the consume timeouts were reached
+  pub drained_early: bool,
+  /// Amount of failed subtasks within the failure tolerance
+  pub failed_task_count: usize,
+  /// True if messages were only collected, since the database was unavailable
+  pub collect_only: bool,
+  pub epochs: BTreeMap<u8, EpochRunSummary>,
+}
+
+impl RunSummary {
+  pub fn new(channel_name: &str) -> Self {
+    Self {
+      run_id: None,
+      channel_name: channel_name.to_string(),
+      started_at: OffsetDateTime::now_utc(),
+      finished_at: None,
+      duration_secs: 0.0,
+      status: None,
+      error: None,
+      error_category: None,
+      iterations: 0,
+      consumed_count: 0,
+      measurement_count: 0,
+      recovery_error_count: 0,
+      discarded_count: 0,
+      published_count: 0,
+      drained_early: false,
+      failed_task_count: 0,
+      collect_only: false,
+      epochs: BTreeMap::new(),
+    }
+  }
+
+  pub fn add_epoch_counts(&mut self, counts: HashMap<u8, i64>) {
+    for (epoch, count) in counts {
+      self.epochs.entry(epoch).or_default().consumed_count += count;
+    }
+  }
+
+  pub fn epoch_finalized(&mut self, epoch: u8, duration: Duration) {
+    self.epochs.entry(epoch).or_default().finalization_secs = Some(duration.as_secs_f64());
+  }
+
+  pub fn finish(&mut self, result: &Result<(), AggregatorError>) {
+    let finished_at = OffsetDateTime::now_utc();
+    self.duration_secs = (finished_at - self.started_at).as_seconds_f64();
+    self.finished_at = Some(finished_at);
+    self.status = Some(match result {
+      Ok(_) => RunStatus::Succeeded,
+      Err(_) => RunStatus::Failed,
+    });
+    self.error = result.as_ref().err().map(|e| e.to_string());
+    self.error_category = result.as_ref().err().map(|e| e.category());
+  }
+
+  /// Writes the summary to `RUN_SUMMARY_PATH`, and stores it in the lake under
+  /// `run-summaries/<channel>/<run id>.json` if `STORE_RUN_SUMMARIES` is enabled.
+  pub async fn write(&self, tenant: Option<&str>) -> Result<(), AggregatorError> {
+    let contents = serde_json::to_string_pretty(self)?;
+    if let Ok(path) = env::var(RUN_SUMMARY_PATH_ENV_KEY) {
+      tokio::fs::write(&path, &contents).await?;
+      info!("Wrote run summary to {}", path);
+    }
+    if parse_env_var::<bool>(STORE_RUN_SUMMARIES_ENV_KEY, STORE_RUN_SUMMARIES_DEFAULT) {
+      let run_id = self.run_id.as_deref().unwrap_or("unknown");
+      DataLake::new(tenant.map(|v| v.to_string()), None)
+        .store_run_summary(&self.channel_name, run_id, &contents)
+        .await?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for RunSummary {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Run summary: {} iterations processed, {} messages consumed, {} measurements reported",
+      self.iterations, self.consumed_count, self.measurement_count
+    )?;
+    if self.drained_early {
+      write!(f, ", input drained before the collect count was reached")?;
+    }
+    if self.collect_only {
+      write!(
+        f,
+        ", messages were only collected since the database was unavailable"
+      )?;
+    }
+    if self.failed_task_count > 0 {
+      write!(
+        f,
+        ", last iteration rolled back after {} tasks failed",
+        self.failed_task_count
+      )?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn summary_json() {
+    let mut summary = RunSummary::new("typical");
+    summary.run_id = Some("abc".to_string());
+    summary.add_epoch_counts(HashMap::from([(3, 10), (4, 2)]));
+    summary.add_epoch_counts(HashMap::from([(3, 5)]));
+    summary.epoch_finalized(2, Duration::from_millis(1500));
+    summary.finish(&Err(AggregatorError::ThresholdTooBig));
+
+    let value = serde_json::to_value(&summary).unwrap();
+    assert_eq!(value["status"], "failed");
+    assert_eq!(value["error"], "Aggregator error: ThresholdTooBig");
+    assert_eq!(value["error_category"], "threshold_too_big");
+    assert_eq!(
+      value["epochs"],
+      serde_json::json!({
+        "2": { "consumed_count": 0, "finalization_secs": 1.5 },
+        "3": { "consumed_count": 15 },
+        "4": { "consumed_count": 2 },
+      })
+    );
+    assert!(value["finished_at"].is_string());
+
+    summary.finish(&Ok(()));
+    let value = serde_json::to_value(&summary).unwrap();
+    assert_eq!(value["status"], "succeeded");
+    assert!(value.get("error").is_none());
+    assert!(value.get("error_category").is_none());
+  }
+}
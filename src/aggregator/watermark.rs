@@ -0,0 +1,217 @@
+//! Watermarking of epoch closure. An expired epoch may still have records in the input
+//! topic if a partition is lagging behind the others. If `EPOCH_CLOSE_WATERMARK` is enabled,
+//! the timestamps of the consumed records are tracked per partition of each input topic,
+//! and an expired epoch is only finalized once the low-watermark, the earliest of the latest
+//! committed record timestamps across assigned partitions, has passed the end of the epoch
+//! plus `EPOCH_CLOSE_GRACE_SECS`.
+//!
+//! Partitions without uncommitted records are considered to be caught up to the current
+//! time. Partitions that have uncommitted records, but from which no records were
+//! consumed by the run, have an unknown position, and defer finalization of all epochs.
+
+use crate::epoch::EpochConfig;
+use crate::record_stream::{ConsumedRecord, PartitionPosition, TopicPartition};
+use crate::util::parse_env_var;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use time::{Duration, OffsetDateTime};
+
+const EPOCH_CLOSE_WATERMARK_ENV_KEY: &str = "EPOCH_CLOSE_WATERMARK";
+const DEFAULT_EPOCH_CLOSE_WATERMARK: &str = "false";
+const EPOCH_CLOSE_GRACE_SECS_ENV_KEY: &str = "EPOCH_CLOSE_GRACE_SECS";
+const DEFAULT_EPOCH_CLOSE_GRACE_SECS: &str = "3600";
+
+/// Latest record timestamps of each partition, consumed in the current
+/// iteration, and committed in previous iterations.
+#[derive(Default)]
+pub struct PartitionWatermarks {
+  consumed: Mutex<HashMap<TopicPartition, OffsetDateTime>>,
+  committed: Mutex<HashMap<TopicPartition, OffsetDateTime>>,
+}
+
+impl PartitionWatermarks {
+  /// Returns the tracker if epoch close watermarking is enabled.
+  pub fn from_env() -> Option<Self> {
+    parse_env_var::<bool>(EPOCH_CLOSE_WATERMARK_ENV_KEY, DEFAULT_EPOCH_CLOSE_WATERMARK)
+      .then(Self::default)
+  }
+
+  pub fn observe(&self, record: &ConsumedRecord) {
+    if let (Some(topic_partition), Some(timestamp)) = (record.topic_partition(), record.timestamp) {
+      let mut consumed = self.consumed.lock().unwrap();
+      let latest = consumed.entry(topic_partition).or_insert(timestamp);
+      *latest = (*latest).max(timestamp);
+    }
+  }
+
+  /// Marks the records consumed in the current iteration as committed.
+  pub fn commit(&self) {
+    let consumed: Vec<_> = self.consumed.lock().unwrap().drain().collect();
+    let mut committed = self.committed.lock().unwrap();
+    for (topic_partition, timestamp) in consumed {
+      let latest = committed.entry(topic_partition).or_insert(timestamp);
+      *latest = (*latest).max(timestamp);
+    }
+  }
+
+  /// Returns the earliest committed record timestamp across the assigned partitions,
+  /// given with their topic, or None if the position of a partition is unknown.
+  /// If no partitions are assigned, the committed partitions are used.
+  pub fn low_watermark(
+    &self,
+    positions: &[(String, PartitionPosition)],
+    now: OffsetDateTime,
+  ) -> Option<OffsetDateTime> {
+    let committed = self.committed.lock().unwrap();
+    if positions.is_empty() {
+      return committed.values().min().copied();
+    }
+    positions
+      .iter()
+      .map(|(topic, position)| match position.lag() {
+        0 => Some(now),
+        _ => committed.get(&(topic.clone(), position.partition)).copied(),
+      })
+      .min()
+      .flatten()
+  }
+}
+
+/// Check whether expired epochs are closed by the consumed record timestamps.
+pub struct EpochCloseCheck {
+  pub low_watermark: Option<OffsetDateTime>,
+  pub grace: Duration,
+}
+
+impl EpochCloseCheck {
+  pub fn new(low_watermark: Option<OffsetDateTime>) -> Self {
+    Self {
+      low_watermark,
+      grace: Duration::seconds(parse_env_var(
+        EPOCH_CLOSE_GRACE_SECS_ENV_KEY,
+        DEFAULT_EPOCH_CLOSE_GRACE_SECS,
+      )),
+    }
+  }
+
+  pub fn is_epoch_closed(&self, epoch_config: &EpochConfig, epoch: u8) -> bool {
+    let epoch_end = epoch_config.get_epoch_start_time(epoch) + epoch_config.epoch_length;
+    self
+      .low_watermark
+      .map(|low_watermark| low_watermark >= epoch_end + self.grace)
+      .unwrap_or(false)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::epoch::CurrentEpochInfo;
+  use crate::rollup::RollupRules;
+  use calendar_duration::CalendarDuration;
+  use time::format_description::well_known::Rfc3339;
+
+  fn time(value: &str) -> OffsetDateTime {
+    OffsetDateTime::parse(value, &Rfc3339).unwrap()
+  }
+
+  fn record(topic: &str, partition: i32, timestamp: &str) -> ConsumedRecord {
+    ConsumedRecord {
+      topic: Some(topic.to_string()),
+      partition: Some(partition),
+      timestamp: Some(time(timestamp)),
+      ..Default::default()
+    }
+  }
+
+  fn position(topic: &str, partition: i32, lag: i64) -> (String, PartitionPosition) {
+    let position = PartitionPosition {
+      partition,
+      committed_offset: Some(100),
+      end_offset: 100 + lag,
+    };
+    (topic.to_string(), position)
+  }
+
+  #[test]
+  fn lagging_partition_holds_watermark() {
+    let now = time("2023-05-10T00:00:00Z");
+    let watermarks = PartitionWatermarks::default();
+    watermarks.observe(&record("desktop", 0, "2023-05-09T00:00:00Z"));
+    watermarks.observe(&record("desktop", 1, "2023-05-01T06:00:00Z"));
+    watermarks.observe(&record("desktop", 1, "2023-05-01T04:00:00Z"));
+    let positions = [
+      position("desktop", 0, 10),
+      position("desktop", 1, 10),
+      position("desktop", 2, 0),
+    ];
+    // Consumed records are not counted until committed
+    assert_eq!(watermarks.low_watermark(&positions, now), None);
+
+    watermarks.commit();
+    assert_eq!(
+      watermarks.low_watermark(&positions, now),
+      Some(time("2023-05-01T06:00:00Z"))
+    );
+    // Unknown position of a lagging partition
+    assert_eq!(
+      watermarks.low_watermark(
+        &[position("desktop", 0, 10), position("desktop", 3, 10)],
+        now
+      ),
+      None
+    );
+    assert_eq!(
+      watermarks.low_watermark(&[position("desktop", 0, 0), position("desktop", 1, 0)], now),
+      Some(now)
+    );
+  }
+
+  #[test]
+  fn partitions_of_multiple_topics() {
+    let now = time("2023-05-10T00:00:00Z");
+    let watermarks = PartitionWatermarks::default();
+    watermarks.observe(&record("desktop", 0, "2023-05-09T00:00:00Z"));
+    watermarks.observe(&record("android", 0, "2023-05-02T00:00:00Z"));
+    watermarks.commit();
+    assert_eq!(
+      watermarks.low_watermark(
+        &[position("desktop", 0, 10), position("android", 0, 10)],
+        now
+      ),
+      Some(time("2023-05-02T00:00:00Z"))
+    );
+    // The same partition number of another topic has an unknown position
+    assert_eq!(
+      watermarks.low_watermark(&[position("desktop", 0, 10), position("ios", 0, 10)], now),
+      None
+    );
+  }
+
+  #[test]
+  fn epoch_closure() {
+    let epoch_length = CalendarDuration::from("1w");
+    let epoch_config = EpochConfig {
+      channel_name: "typical".to_string(),
+      current_epoch: CurrentEpochInfo {
+        epoch: 2,
+        next_epoch_time: time("2023-05-08T13:00:00Z"),
+      },
+      epoch_date_field_name: "wos".to_string(),
+      epoch_length,
+      epoch_lifetime_count: 1,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
+    };
+    // Epoch 1 ends at the start of the current epoch, 2023-05-01T13:00:00Z
+    let check = |low_watermark: Option<&str>| EpochCloseCheck {
+      low_watermark: low_watermark.map(time),
+      grace: Duration::hours(1),
+    };
+    assert!(check(Some("2023-05-01T14:00:00Z")).is_epoch_closed(&epoch_config, 1));
+    assert!(!check(Some("2023-05-01T13:30:00Z")).is_epoch_closed(&epoch_config, 1));
+    assert!(!check(None).is_epoch_closed(&epoch_config, 1));
+    assert!(check(Some("2023-05-01T13:30:00Z")).is_epoch_closed(&epoch_config, 0));
+  }
+}
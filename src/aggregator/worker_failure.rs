@@ -0,0 +1,141 @@
+//! Isolation of aggregator subtask failures. Subtasks are joined individually, so that
+//! a panic in one subtask is reported along with its id, tag count and processing phase,
+//! instead of aborting the join. If the amount of failed subtasks in an iteration exceeds
+//! `AGG_WORKER_FAILURE_TOLERANCE`, the run fails. Otherwise, the iteration is rolled back
+//! and the remaining iterations are skipped, so that the messages of the failed subtasks
+//! are consumed again by the next run.
+
+use super::processing::{Subtask, SubtaskPhase};
+use crate::util::parse_env_var;
+use futures::future::join_all;
+use std::error::Error;
+use std::fmt;
+use tokio::task::JoinError;
+
+const WORKER_FAILURE_TOLERANCE_ENV_KEY: &str = "AGG_WORKER_FAILURE_TOLERANCE";
+const DEFAULT_WORKER_FAILURE_TOLERANCE: &str = "0";
+
+/// Max amount of failed subtasks per iteration that does not fail the run
+pub fn worker_failure_tolerance() -> usize {
+  parse_env_var(
+    WORKER_FAILURE_TOLERANCE_ENV_KEY,
+    DEFAULT_WORKER_FAILURE_TOLERANCE,
+  )
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WorkerFailure {
+  pub id: usize,
+  pub tag_count: usize,
+  pub phase: SubtaskPhase,
+  pub message: String,
+}
+
+impl fmt::Display for WorkerFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "task {} ({} tags) failed while {}: {}",
+      self.id, self.tag_count, self.phase, self.message
+    )
+  }
+}
+
+#[derive(Debug)]
+pub struct WorkerFailures(pub Vec<WorkerFailure>);
+
+impl fmt::Display for WorkerFailures {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} tasks failed", self.0.len())?;
+    for failure in &self.0 {
+      write!(f, "; {}", failure)?;
+    }
+    Ok(())
+  }
+}
+
+impl Error for WorkerFailures {}
+
+fn join_error_message(e: JoinError) -> String {
+  if !e.is_panic() {
+    return e.to_string();
+  }
+  let payload = e.into_panic();
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message.to_string()
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message.clone()
+  } else {
+    "unknown panic".to_string()
+  }
+}
+
+/// Waits for all subtasks to finish. Returns the measurement & error counts
+/// of the successful subtasks, and the failures of the other subtasks.
+pub async fn join_subtasks(tasks: Vec<Subtask>) -> (Vec<(i64, usize)>, Vec<WorkerFailure>) {
+  let contexts: Vec<_> = tasks
+    .iter()
+    .map(|v| (v.id, v.tag_count, v.phase.clone()))
+    .collect();
+  let results = join_all(tasks.into_iter().map(|v| v.handle)).await;
+
+  let mut counts = Vec::new();
+  let mut failures = Vec::new();
+  for ((id, tag_count, phase), result) in contexts.into_iter().zip(results) {
+    match result {
+      Ok(task_counts) => counts.push(task_counts),
+      Err(e) => failures.push(WorkerFailure {
+        id,
+        tag_count,
+        // The phase lock is poisoned if the panic occurred while setting the phase
+        phase: *phase.lock().unwrap_or_else(|e| e.into_inner()),
+        message: join_error_message(e),
+      }),
+    }
+  }
+  (counts, failures)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  fn subtask(id: usize, phase: SubtaskPhase, fail: bool) -> Subtask {
+    Subtask {
+      id,
+      tag_count: 10,
+      phase: Arc::new(Mutex::new(phase)),
+      handle: tokio::spawn(async move {
+        if fail {
+          panic!("test failure {}", id);
+        }
+        (2, 1)
+      }),
+    }
+  }
+
+  #[tokio::test]
+  async fn isolate_panics() {
+    let (counts, failures) = join_subtasks(vec![
+      subtask(0, SubtaskPhase::Report, false),
+      subtask(1, SubtaskPhase::StorePending, true),
+      subtask(2, SubtaskPhase::Report, false),
+    ])
+    .await;
+    assert_eq!(counts, vec![(2, 1), (2, 1)]);
+    assert_eq!(
+      failures,
+      vec![WorkerFailure {
+        id: 1,
+        tag_count: 10,
+        phase: SubtaskPhase::StorePending,
+        message: "test failure 1".to_string(),
+      }]
+    );
+    assert_eq!(
+      WorkerFailures(failures).to_string(),
+      "1 tasks failed; task 1 (10 tags) failed while storing pending messages: test failure 1"
+    );
+  }
+}
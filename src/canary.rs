@@ -0,0 +1,83 @@
+//! Canary channel support. A designated canary channel is expected to receive
+//! a continuous flow of synthetic submissions, with short epochs and a low
+//! k threshold, so that canary measurements appear in the output topic shortly
+//! after submission. The lake sink tracks the time of the last canary measurement,
+//! and reports the canary as stale if no measurement is seen within the max age.
+//! This serves as an end-to-end liveness probe for the whole pipeline.
+
+use crate::prometheus::LakeSinkMetrics;
+use crate::util::parse_env_var;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+const CANARY_CHANNEL_ENV_KEY: &str = "CANARY_CHANNEL";
+const CANARY_K_THRESHOLD_ENV_KEY: &str = "CANARY_K_THRESHOLD";
+const CANARY_K_THRESHOLD_DEFAULT: &str = "2";
+const CANARY_MAX_OUTPUT_AGE_SECS_ENV_KEY: &str = "CANARY_MAX_OUTPUT_AGE_SECS";
+const CANARY_MAX_OUTPUT_AGE_SECS_DEFAULT: &str = "7200";
+const CANARY_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// Returns the name of the canary channel, if configured.
+pub fn canary_channel() -> Option<String> {
+  env::var(CANARY_CHANNEL_ENV_KEY)
+    .ok()
+    .filter(|v| !v.is_empty())
+}
+
+pub fn is_canary_channel(channel_name: &str) -> bool {
+  canary_channel().as_deref() == Some(channel_name)
+}
+
+/// The k threshold used for the canary channel, in place of the default threshold.
+pub fn canary_k_threshold() -> usize {
+  parse_env_var::<usize>(CANARY_K_THRESHOLD_ENV_KEY, CANARY_K_THRESHOLD_DEFAULT)
+}
+
+fn is_output_stale(last_output_timestamp: i64, now_timestamp: i64, max_age_secs: i64) -> bool {
+  now_timestamp - last_output_timestamp > max_age_secs
+}
+
+/// Periodically checks the time of the last canary measurement seen by the lake sink,
+/// and updates the canary staleness metric. Runs until the process exits.
+pub async fn monitor_canary_output(channel_name: String, metrics: Arc<LakeSinkMetrics>) {
+  let max_age_secs = parse_env_var::<i64>(
+    CANARY_MAX_OUTPUT_AGE_SECS_ENV_KEY,
+    CANARY_MAX_OUTPUT_AGE_SECS_DEFAULT,
+  );
+  // Allow the full max age for the first canary measurement to appear
+  metrics.canary_output_received(OffsetDateTime::now_utc().unix_timestamp());
+  let mut interval = tokio::time::interval(Duration::from_secs(CANARY_CHECK_INTERVAL_SECS));
+  let mut was_stale = false;
+  loop {
+    interval.tick().await;
+    let stale = is_output_stale(
+      metrics.canary_last_output_timestamp(),
+      OffsetDateTime::now_utc().unix_timestamp(),
+      max_age_secs,
+    );
+    metrics.set_canary_stale(stale);
+    if stale && !was_stale {
+      error!(
+        "No canary measurements for channel '{}' seen in output within {} seconds",
+        channel_name, max_age_secs
+      );
+    } else if !stale && was_stale {
+      info!("Canary measurements for channel '{}' resumed", channel_name);
+    }
+    was_stale = stale;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn output_staleness() {
+    assert!(!is_output_stale(1000, 1500, 600));
+    assert!(!is_output_stale(1000, 1600, 600));
+    assert!(is_output_stale(1000, 1601, 600));
+  }
+}
@@ -1,9 +1,15 @@
 //! Handles parsing of "channel maps" defined in environment variables.
 //! Each channel map entry is formatted like so: <channel name>=<value for channel>
 //! For example: slow=mos,typical=wos,express=dtos
+//!
+//! List maps may contain multiple values per channel. Entries without a channel name
+//! are appended to the values of the preceding channel, i.e. `typical=a,b,express=c`.
 
 use std::{collections::HashMap, env};
 
+const SAMPLING_RATES_ENV_KEY: &str = "SAMPLING_RATES";
+const COUNTING_ONLY_CHANNELS_ENV_KEY: &str = "COUNTING_ONLY_CHANNELS";
+
 pub fn get_data_channel_map_from_env(env_key: &str, default: &str) -> HashMap<String, String> {
   let env_encoded = env::var(env_key).unwrap_or_else(|_| default.to_string());
 
@@ -34,6 +40,40 @@ pub fn get_data_channel_map_from_env(env_key: &str, default: &str) -> HashMap<St
   map
 }
 
+fn parse_data_channel_list_map(env_key: &str, encoded: &str) -> HashMap<String, Vec<String>> {
+  let mut map: HashMap<String, Vec<String>> = HashMap::new();
+  let mut current_channel: Option<String> = None;
+  for encoded_entry in encoded.split(',').filter(|v| !v.is_empty()) {
+    let (channel_name, value) = match encoded_entry.split_once('=') {
+      Some((channel_name, value)) => (channel_name.to_string(), value),
+      None => (
+        current_channel.clone().unwrap_or_else(|| {
+          panic!(
+            "should be able to parse name from {} env channel setting entry: {}",
+            env_key, encoded_entry
+          )
+        }),
+        encoded_entry,
+      ),
+    };
+    map
+      .entry(channel_name.clone())
+      .or_default()
+      .push(value.to_string());
+    current_channel = Some(channel_name);
+  }
+  map
+}
+
+/// Returns the channel map, with a list of values for each channel.
+pub fn get_data_channel_list_map_from_env(
+  env_key: &str,
+  default: &str,
+) -> HashMap<String, Vec<String>> {
+  let env_encoded = env::var(env_key).unwrap_or_else(|_| default.to_string());
+  parse_data_channel_list_map(env_key, &env_encoded)
+}
+
 pub fn get_data_channel_value_from_env(env_key: &str, default: &str, channel_name: &str) -> String {
   get_data_channel_map_from_env(env_key, default)
     .get(channel_name)
@@ -45,3 +85,59 @@ pub fn get_data_channel_value_from_env(env_key: &str, default: &str, channel_nam
       );
     })
 }
+
+/// Returns the fraction of submissions that should be accepted for the channel.
+/// Channels without an entry in the sampling rate map accept all submissions.
+pub fn get_data_channel_sampling_rate_from_env(channel_name: &str) -> f64 {
+  let sampling_rate = get_data_channel_map_from_env(SAMPLING_RATES_ENV_KEY, "")
+    .get(channel_name)
+    .map(|v| {
+      v.parse::<f64>()
+        .expect("sampling rate should be a decimal number")
+    })
+    .unwrap_or(1.0);
+  assert!(
+    sampling_rate > 0.0 && sampling_rate <= 1.0,
+    "sampling rate for channel {} must be greater than 0 and at most 1",
+    channel_name
+  );
+  sampling_rate
+}
+
+/// Returns true if only the counts of the outer layer measurements
+/// should be reported for the channel.
+pub fn get_data_channel_counting_only_from_env(channel_name: &str) -> bool {
+  get_data_channel_map_from_env(COUNTING_ONLY_CHANNELS_ENV_KEY, "")
+    .get(channel_name)
+    .map(|v| {
+      v.parse::<bool>()
+        .expect("counting-only setting should be true or false")
+    })
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn list_map() {
+    let map = parse_data_channel_list_map(
+      "KAFKA_ENCRYPTED_TOPICS",
+      "typical=p3a-star-enc-desktop,p3a-star-enc-android,express=p3a-star-enc-express",
+    );
+    assert_eq!(map.len(), 2);
+    assert_eq!(
+      map["typical"],
+      vec!["p3a-star-enc-desktop", "p3a-star-enc-android"]
+    );
+    assert_eq!(map["express"], vec!["p3a-star-enc-express"]);
+    assert!(parse_data_channel_list_map("KAFKA_ENCRYPTED_TOPICS", "").is_empty());
+  }
+
+  #[test]
+  #[should_panic]
+  fn list_map_without_channel() {
+    parse_data_channel_list_map("KAFKA_ENCRYPTED_TOPICS", "p3a-star-enc");
+  }
+}
@@ -0,0 +1,182 @@
+//! Channel configuration discovery. If a signing key is configured, the server serves
+//! a JSON document at `GET /info/channels`, describing the parameters of each channel
+//! accepted by the server: the epoch schedule, the k threshold, the sampling rate
+//! and the accepted submission revisions and content types. The document is built
+//! from the same settings used by the server and the aggregator, so that clients do
+//! not need to hardcode parameters that may not match the deployment.
+//!
+//! The document is signed with HMAC-SHA256, and the hex-encoded signature of the
+//! response body is returned in the `brave-p3a-signature` header.
+
+use crate::aggregator::default_k_threshold;
+use crate::channel::{
+  get_data_channel_counting_only_from_env, get_data_channel_sampling_rate_from_env,
+};
+use crate::epoch::{
+  epoch_schedule_origin_from_env, epoch_source_name_from_env, EpochSettings, SCHEDULE_EPOCH_SOURCE,
+};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use time::OffsetDateTime;
+
+const CHANNEL_INFO_KEY_FILE_ENV_KEY: &str = "CHANNEL_INFO_KEY_FILE";
+pub const CHANNEL_INFO_SIGNATURE_HEADER: &str = "brave-p3a-signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChannelInfo {
+  pub epoch_length: String,
+  pub epoch_lifetime_count: usize,
+  pub epoch_date_field_name: String,
+  pub epoch_source: String,
+  /// Start of epoch 0, if the channel uses a fixed epoch schedule
+  #[serde(
+    with = "time::serde::rfc3339::option",
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub epoch_schedule_origin: Option<OffsetDateTime>,
+  pub k_threshold: usize,
+  pub sampling_rate: f64,
+  pub counting_only: bool,
+  /// Minimum revision of submissions, as supplied in the `brave-p3a-version` header
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub min_revision: Option<usize>,
+}
+
+impl ChannelInfo {
+  fn from_env(channel_name: &str, min_revision: Option<usize>) -> Self {
+    let settings = EpochSettings::from_env(channel_name);
+    let epoch_source = epoch_source_name_from_env(channel_name);
+    let epoch_schedule_origin =
+      (epoch_source == SCHEDULE_EPOCH_SOURCE).then(|| epoch_schedule_origin_from_env(channel_name));
+    Self {
+      epoch_length: settings.epoch_length.to_string(),
+      epoch_lifetime_count: settings.epoch_lifetime_count,
+      epoch_date_field_name: settings.epoch_date_field_name,
+      epoch_source,
+      epoch_schedule_origin,
+      k_threshold: default_k_threshold(channel_name),
+      sampling_rate: get_data_channel_sampling_rate_from_env(channel_name),
+      counting_only: get_data_channel_counting_only_from_env(channel_name),
+      min_revision,
+    }
+  }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ThresholdRange {
+  pub min: usize,
+  pub max: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChannelInfoDocument {
+  #[serde(with = "time::serde::rfc3339")]
+  pub generated_at: OffsetDateTime,
+  pub main_channel: String,
+  /// Range of thresholds accepted in the `brave-p3a-constellation-threshold` header
+  pub request_threshold: ThresholdRange,
+  pub accepted_content_types: BTreeSet<String>,
+  pub channels: BTreeMap<String, ChannelInfo>,
+}
+
+/// The serialized channel info document, and its signature.
+pub struct SignedChannelInfo {
+  pub body: String,
+  pub signature: String,
+}
+
+fn sign(key: &[u8], body: &str) -> String {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC should accept any key size");
+  mac.update(body.as_bytes());
+  hex::encode(mac.finalize().into_bytes())
+}
+
+impl SignedChannelInfo {
+  fn new(document: &ChannelInfoDocument, key: &[u8]) -> Self {
+    let body = serde_json::to_string(document).expect("channel info should serialize");
+    let signature = sign(key, &body);
+    Self { body, signature }
+  }
+
+  /// Builds and signs the document for the channels accepted by the server,
+  /// if `CHANNEL_INFO_KEY_FILE` is set.
+  pub fn from_env(
+    channel_names: impl IntoIterator<Item = String>,
+    main_channel: &str,
+    min_revision_map: &HashMap<String, usize>,
+    request_threshold_range: &RangeInclusive<usize>,
+    accepted_content_types: &HashSet<String>,
+  ) -> Option<Self> {
+    let path = env::var(CHANNEL_INFO_KEY_FILE_ENV_KEY).ok()?;
+    let contents = fs::read_to_string(&path)
+      .unwrap_or_else(|e| panic!("failed to read channel info key file {}: {}", path, e));
+    let key = hex::decode(contents.trim()).expect("channel info key file should contain hex");
+    assert!(!key.is_empty(), "channel info key must not be empty");
+
+    let document = ChannelInfoDocument {
+      generated_at: OffsetDateTime::now_utc(),
+      main_channel: main_channel.to_string(),
+      request_threshold: ThresholdRange {
+        min: *request_threshold_range.start(),
+        max: *request_threshold_range.end(),
+      },
+      accepted_content_types: accepted_content_types.iter().cloned().collect(),
+      channels: channel_names
+        .into_iter()
+        .map(|channel_name| {
+          let info =
+            ChannelInfo::from_env(&channel_name, min_revision_map.get(&channel_name).copied());
+          (channel_name, info)
+        })
+        .collect(),
+    };
+    Some(Self::new(&document, &key))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn signed_document() {
+    let document = ChannelInfoDocument {
+      generated_at: OffsetDateTime::from_unix_timestamp(1700000000).unwrap(),
+      main_channel: "typical".to_string(),
+      request_threshold: ThresholdRange { min: 20, max: 50 },
+      accepted_content_types: BTreeSet::from(["text/plain".to_string()]),
+      channels: BTreeMap::from([(
+        "typical".to_string(),
+        ChannelInfo {
+          epoch_length: "1w".to_string(),
+          epoch_lifetime_count: 3,
+          epoch_date_field_name: "wos".to_string(),
+          epoch_source: "randomness".to_string(),
+          epoch_schedule_origin: None,
+          k_threshold: 50,
+          sampling_rate: 1.0,
+          counting_only: false,
+          min_revision: Some(2),
+        },
+      )]),
+    };
+    let signed = SignedChannelInfo::new(&document, b"test key");
+    let value: serde_json::Value = serde_json::from_str(&signed.body).unwrap();
+    assert_eq!(value["generated_at"], "2023-11-14T22:13:20Z");
+    assert_eq!(value["request_threshold"]["max"], 50);
+    assert_eq!(value["channels"]["typical"]["min_revision"], 2);
+    assert!(value["channels"]["typical"]
+      .get("epoch_schedule_origin")
+      .is_none());
+
+    assert_eq!(signed.signature, sign(b"test key", &signed.body));
+    assert_ne!(signed.signature, sign(b"other key", &signed.body));
+  }
+}
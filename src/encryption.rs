@@ -0,0 +1,150 @@
+//! Encryption of pending shares at rest. If a share encryption key is configured,
+//! pending messages are encrypted with AES-256-GCM before they are stored in the database,
+//! and are only decrypted by the aggregator during processing.
+//!
+//! The key may be supplied as a hex-encoded key in a local file, or as a data key
+//! encrypted by AWS KMS, which will be decrypted via KMS at startup.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error};
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, Kms, KmsClient};
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+const SHARE_ENCRYPTION_KEY_FILE_ENV_KEY: &str = "SHARE_ENCRYPTION_KEY_FILE";
+const SHARE_ENCRYPTION_KMS_DATA_KEY_ENV_KEY: &str = "SHARE_ENCRYPTION_KMS_DATA_KEY";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+static SHARE_CIPHER: OnceLock<Option<ShareCipher>> = OnceLock::new();
+
+#[derive(Error, Display, Debug)]
+pub enum ShareEncryptionError {
+  #[display(fmt = "share decryption failed")]
+  Decrypt,
+  #[display(fmt = "share is encrypted, but no share encryption key is configured")]
+  MissingKey,
+}
+
+pub struct ShareCipher {
+  cipher: Aes256Gcm,
+}
+
+impl ShareCipher {
+  pub fn new(key: &[u8]) -> Self {
+    assert_eq!(
+      key.len(),
+      KEY_LENGTH,
+      "share encryption key must be {} bytes",
+      KEY_LENGTH
+    );
+    Self {
+      cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+    }
+  }
+
+  /// Returns the random nonce, followed by the ciphertext.
+  pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut result = nonce.to_vec();
+    result.extend(
+      self
+        .cipher
+        .encrypt(&nonce, plaintext)
+        .expect("share encryption should not fail"),
+    );
+    result
+  }
+
+  pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, ShareEncryptionError> {
+    if data.len() < NONCE_LENGTH {
+      return Err(ShareEncryptionError::Decrypt);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    self
+      .cipher
+      .decrypt(Nonce::from_slice(nonce), ciphertext)
+      .map_err(|_| ShareEncryptionError::Decrypt)
+  }
+}
+
+/// Decrypts the base64 encoded data key via KMS.
+pub async fn decrypt_kms_data_key(encoded_data_key: &str) -> Vec<u8> {
+  let ciphertext_blob = base64_engine::STANDARD
+    .decode(encoded_data_key.trim())
+    .expect("KMS data key should be base64 encoded");
+  let client = KmsClient::new(Region::default());
+  client
+    .decrypt(DecryptRequest {
+      ciphertext_blob: ciphertext_blob.into(),
+      ..Default::default()
+    })
+    .await
+    .expect("should be able to decrypt data key via KMS")
+    .plaintext
+    .expect("KMS decrypt response should contain plaintext key")
+    .to_vec()
+}
+
+/// Loads the share encryption key from the environment, if configured.
+/// Must be called before any pending messages are stored or processed.
+pub async fn init_share_encryption() {
+  let key = if let Ok(path) = env::var(SHARE_ENCRYPTION_KEY_FILE_ENV_KEY) {
+    let contents = fs::read_to_string(&path)
+      .unwrap_or_else(|e| panic!("failed to read share encryption key file {}: {}", path, e));
+    Some(hex::decode(contents.trim()).expect("share encryption key file should contain hex"))
+  } else if let Ok(encoded_data_key) = env::var(SHARE_ENCRYPTION_KMS_DATA_KEY_ENV_KEY) {
+    Some(decrypt_kms_data_key(&encoded_data_key).await)
+  } else {
+    None
+  };
+  if key.is_some() {
+    info!("Share encryption at rest is enabled");
+  }
+  SHARE_CIPHER.get_or_init(|| key.map(|key| ShareCipher::new(&key)));
+}
+
+fn share_cipher() -> Option<&'static ShareCipher> {
+  SHARE_CIPHER.get().and_then(|v| v.as_ref())
+}
+
+/// Encrypts the share if a key is configured. Returns the stored share
+/// data, along with a flag indicating whether the share was encrypted.
+pub fn encrypt_share(share: Vec<u8>) -> (Vec<u8>, bool) {
+  match share_cipher() {
+    Some(cipher) => (cipher.encrypt(&share), true),
+    None => (share, false),
+  }
+}
+
+pub fn decrypt_share(data: &[u8], encrypted: bool) -> Result<Vec<u8>, ShareEncryptionError> {
+  match encrypted {
+    false => Ok(data.to_vec()),
+    true => share_cipher()
+      .ok_or(ShareEncryptionError::MissingKey)?
+      .decrypt(data),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encrypt_decrypt() {
+    let cipher = ShareCipher::new(&[7u8; KEY_LENGTH]);
+    let share = b"test share".to_vec();
+    let encrypted = cipher.encrypt(&share);
+    assert_ne!(&encrypted[NONCE_LENGTH..], share.as_slice());
+    assert_eq!(cipher.decrypt(&encrypted).unwrap(), share);
+
+    let other_cipher = ShareCipher::new(&[8u8; KEY_LENGTH]);
+    assert!(other_cipher.decrypt(&encrypted).is_err());
+    assert!(cipher.decrypt(&encrypted[..4]).is_err());
+  }
+}
@@ -1,9 +1,15 @@
+use async_trait::async_trait;
 use calendar_duration::CalendarDuration;
 use serde::Deserialize;
 use std::env;
+use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
-use crate::channel::get_data_channel_value_from_env;
+use crate::channel::{
+  get_data_channel_counting_only_from_env, get_data_channel_map_from_env,
+  get_data_channel_sampling_rate_from_env, get_data_channel_value_from_env,
+};
+use crate::rollup::RollupRules;
 
 const FIRST_EPOCH: u8 = 0u8;
 const LAST_EPOCH: u8 = 255u8;
@@ -22,6 +28,11 @@ const DEFAULT_EPOCH_LENGTHS: &str = "typical=1w";
 const EPOCH_DATE_FIELD_NAMES_ENV_KEY: &str = "EPOCH_DATE_FIELD_NAMES";
 const DEFAULT_EPOCH_DATE_FIELD_NAMES: &str = "typical=wos";
 
+const EPOCH_SOURCES_ENV_KEY: &str = "EPOCH_SOURCES";
+const EPOCH_SCHEDULE_ORIGINS_ENV_KEY: &str = "EPOCH_SCHEDULE_ORIGINS";
+const RANDOMNESS_SERVER_EPOCH_SOURCE: &str = "randomness";
+pub const SCHEDULE_EPOCH_SOURCE: &str = "schedule";
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CurrentEpochInfo {
@@ -46,7 +57,7 @@ impl CurrentEpochInfo {
     let client = client_builder.build().unwrap();
     let randomness_info_url = reqwest::Url::parse(
       &env::var(RANDOMNESS_HOST_ENV_KEY)
-        .expect(&format!("{} env var not defined", RANDOMNESS_HOST_ENV_KEY)),
+        .unwrap_or_else(|_| panic!("{} env var not defined", RANDOMNESS_HOST_ENV_KEY)),
     )
     .unwrap()
     .join(&path)
@@ -69,15 +80,112 @@ impl CurrentEpochInfo {
   }
 }
 
-pub struct EpochConfig {
-  pub current_epoch: CurrentEpochInfo,
+/// Association of epochs with the randomness used by clients. Deployments may derive
+/// their epochs from a different randomness cadence, so the source is selected per
+/// channel via `EPOCH_SOURCES`.
+#[async_trait]
+pub trait EpochSource {
+  /// Returns the current epoch of the channel, and the start time of the next epoch.
+  async fn current_epoch(
+    &self,
+    channel_name: &str,
+    epoch_length: CalendarDuration,
+  ) -> CurrentEpochInfo;
+}
+
+/// Retrieves the current epoch from the instance info of the randomness server.
+pub struct RandomnessServerEpochSource;
+
+#[async_trait]
+impl EpochSource for RandomnessServerEpochSource {
+  async fn current_epoch(
+    &self,
+    channel_name: &str,
+    _epoch_length: CalendarDuration,
+  ) -> CurrentEpochInfo {
+    CurrentEpochInfo::retrieve(channel_name).await
+  }
+}
+
+/// Derives the current epoch from a fixed schedule, for deployments where the randomness
+/// server info is not available to the processor. Epoch 0 starts at the origin, and each
+/// following epoch starts one epoch length after the previous one, wrapping after epoch 255.
+/// The schedule should match the epoch base time and period of the randomness server.
+pub struct ScheduleEpochSource {
+  pub origin: OffsetDateTime,
+}
+
+impl ScheduleEpochSource {
+  pub fn epoch_at(&self, time: OffsetDateTime, epoch_length: CalendarDuration) -> CurrentEpochInfo {
+    assert!(
+      time >= self.origin,
+      "epoch schedule origin should not be in the future"
+    );
+    let mut epoch_start = self.origin;
+    let mut epoch = FIRST_EPOCH;
+    while epoch_start + epoch_length <= time {
+      epoch_start = epoch_start + epoch_length;
+      epoch = epoch.wrapping_add(1);
+    }
+    CurrentEpochInfo {
+      epoch,
+      next_epoch_time: epoch_start + epoch_length,
+    }
+  }
+}
+
+#[async_trait]
+impl EpochSource for ScheduleEpochSource {
+  async fn current_epoch(
+    &self,
+    _channel_name: &str,
+    epoch_length: CalendarDuration,
+  ) -> CurrentEpochInfo {
+    self.epoch_at(OffsetDateTime::now_utc(), epoch_length)
+  }
+}
+
+/// Returns the epoch source of the channel, as selected by `EPOCH_SOURCES`.
+/// Channels without an entry use the randomness server.
+pub fn epoch_source_from_env(channel_name: &str) -> Box<dyn EpochSource + Send + Sync> {
+  match epoch_source_name_from_env(channel_name).as_str() {
+    SCHEDULE_EPOCH_SOURCE => Box::new(ScheduleEpochSource {
+      origin: epoch_schedule_origin_from_env(channel_name),
+    }),
+    _ => Box::new(RandomnessServerEpochSource),
+  }
+}
+
+/// Returns the name of the epoch source of the channel. Panics if the source is unknown.
+pub fn epoch_source_name_from_env(channel_name: &str) -> String {
+  let source = get_data_channel_map_from_env(EPOCH_SOURCES_ENV_KEY, "")
+    .remove(channel_name)
+    .unwrap_or_else(|| RANDOMNESS_SERVER_EPOCH_SOURCE.to_string());
+  assert!(
+    [RANDOMNESS_SERVER_EPOCH_SOURCE, SCHEDULE_EPOCH_SOURCE].contains(&source.as_str()),
+    "Unknown epoch source '{}' for channel {}",
+    source,
+    channel_name
+  );
+  source
+}
+
+/// Returns the origin of the epoch schedule of the channel.
+pub fn epoch_schedule_origin_from_env(channel_name: &str) -> OffsetDateTime {
+  let origin = get_data_channel_value_from_env(EPOCH_SCHEDULE_ORIGINS_ENV_KEY, "", channel_name);
+  OffsetDateTime::parse(&origin, &Rfc3339)
+    .expect("epoch schedule origin should be an RFC 3339 timestamp")
+}
+
+/// Epoch settings of a channel that do not depend on the current epoch.
+pub struct EpochSettings {
   pub epoch_date_field_name: String,
   pub epoch_length: CalendarDuration,
   pub epoch_lifetime_count: usize,
 }
 
-impl EpochConfig {
-  pub async fn new(test_epoch: Option<u8>, channel_name: &str) -> Self {
+impl EpochSettings {
+  pub fn from_env(channel_name: &str) -> Self {
     let epoch_length = CalendarDuration::from(
       get_data_channel_value_from_env(EPOCH_LENGTHS_ENV_KEY, DEFAULT_EPOCH_LENGTHS, channel_name)
         .as_str(),
@@ -98,15 +206,53 @@ impl EpochConfig {
       DEFAULT_EPOCH_DATE_FIELD_NAMES,
       channel_name,
     );
+    Self {
+      epoch_date_field_name,
+      epoch_length,
+      epoch_lifetime_count,
+    }
+  }
+}
+
+pub struct EpochConfig {
+  pub channel_name: String,
+  pub current_epoch: CurrentEpochInfo,
+  pub epoch_date_field_name: String,
+  pub epoch_length: CalendarDuration,
+  pub epoch_lifetime_count: usize,
+  /// Roll-up rules to apply to recovered attributes before reporting
+  pub rollup_rules: RollupRules,
+  /// Fraction of submissions accepted by the server for the channel
+  pub sampling_rate: f64,
+  /// If true, only the outer layer of messages is recovered, and nested
+  /// measurements are reported as counts of the outer measurement
+  pub counting_only: bool,
+}
+
+impl EpochConfig {
+  pub async fn new(test_epoch: Option<u8>, channel_name: &str) -> Self {
+    let EpochSettings {
+      epoch_date_field_name,
+      epoch_length,
+      epoch_lifetime_count,
+    } = EpochSettings::from_env(channel_name);
     let current_epoch = match test_epoch {
       Some(epoch) => CurrentEpochInfo::test_info(epoch, epoch_length),
-      None => CurrentEpochInfo::retrieve(channel_name).await,
+      None => {
+        epoch_source_from_env(channel_name)
+          .current_epoch(channel_name, epoch_length)
+          .await
+      }
     };
     Self {
+      channel_name: channel_name.to_string(),
       current_epoch,
       epoch_date_field_name,
       epoch_length,
       epoch_lifetime_count,
+      rollup_rules: RollupRules::from_env(channel_name),
+      sampling_rate: get_data_channel_sampling_rate_from_env(channel_name),
+      counting_only: get_data_channel_counting_only_from_env(channel_name),
     }
   }
 
@@ -128,27 +274,34 @@ impl EpochConfig {
     diff >= self.epoch_lifetime_count
   }
 
-  pub fn get_epoch_survey_date(&self, epoch: u8) -> String {
+  /// Returns the start time of the most recent occurrence of the epoch.
+  pub fn get_epoch_start_time(&self, epoch: u8) -> OffsetDateTime {
     let current_epoch_start = self.current_epoch.next_epoch_time - self.epoch_length;
     let epoch_delta = self.current_epoch.epoch.wrapping_sub(epoch);
 
-    let mut epoch_start_date = current_epoch_start;
+    let mut epoch_start_time = current_epoch_start;
     for _ in 0..epoch_delta {
-      epoch_start_date = epoch_start_date - self.epoch_length;
+      epoch_start_time = epoch_start_time - self.epoch_length;
     }
-    epoch_start_date.date().to_string()
+    epoch_start_time
+  }
+
+  pub fn get_epoch_survey_date(&self, epoch: u8) -> String {
+    self.get_epoch_start_time(epoch).date().to_string()
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use super::{CurrentEpochInfo, EpochConfig};
+  use super::{CurrentEpochInfo, EpochConfig, ScheduleEpochSource};
+  use crate::rollup::RollupRules;
   use calendar_duration::CalendarDuration;
   use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
   fn get_epoch_config() -> EpochConfig {
     let epoch_length = CalendarDuration::from("1w");
     EpochConfig {
+      channel_name: "typical".to_string(),
       current_epoch: CurrentEpochInfo {
         epoch: 2,
         next_epoch_time: OffsetDateTime::parse("2023-05-08T13:00:00.000Z", &Rfc3339)
@@ -158,6 +311,9 @@ mod tests {
       epoch_date_field_name: "wos".to_string(),
       epoch_length,
       epoch_lifetime_count: 5,
+      rollup_rules: RollupRules::default(),
+      sampling_rate: 1.0,
+      counting_only: false,
     }
   }
 
@@ -182,4 +338,29 @@ mod tests {
     assert!(epoch_config.is_epoch_expired(253));
     assert!(epoch_config.is_epoch_expired(120));
   }
+
+  #[test]
+  fn scheduled_epochs() {
+    let time = |value: &str| OffsetDateTime::parse(value, &Rfc3339).unwrap();
+    let source = ScheduleEpochSource {
+      origin: time("2023-01-01T00:00:00Z"),
+    };
+    let daily = CalendarDuration::from("1d");
+
+    let info = source.epoch_at(time("2023-01-01T00:00:00Z"), daily);
+    assert_eq!(info.epoch, 0);
+    assert_eq!(info.next_epoch_time, time("2023-01-02T00:00:00Z"));
+
+    let info = source.epoch_at(time("2023-01-03T12:00:00Z"), daily);
+    assert_eq!(info.epoch, 2);
+    assert_eq!(info.next_epoch_time, time("2023-01-04T00:00:00Z"));
+
+    // Epochs wrap after epoch 255
+    let info = source.epoch_at(time("2023-09-15T01:00:00Z"), daily);
+    assert_eq!(info.epoch, 1);
+    assert_eq!(info.next_epoch_time, time("2023-09-16T00:00:00Z"));
+
+    let info = source.epoch_at(time("2023-03-10T00:00:00Z"), CalendarDuration::from("1w"));
+    assert_eq!(info.epoch, 9);
+  }
 }
@@ -0,0 +1,412 @@
+//! Fault injection for record streams, to test the resilience of the aggregator and the
+//! lake sink in CI. If any of the `RECORD_STREAM_FAULT_*` rates are set, the backend of
+//! each record stream is wrapped, so that a fraction of produces and commits fail with a
+//! `fault_injection` error, consumes are delayed by up to
+//! `RECORD_STREAM_FAULT_CONSUME_MAX_DELAY_MS`, and consumed records are delivered twice.
+//! Each rate is the probability of injecting the fault into a single operation or record.
+//! `RECORD_STREAM_FAULT_SEED` makes the injected faults reproducible across runs.
+//!
+//! Faults are injected between the backend and the payload codecs, so duplicated records
+//! are decoded like any other record. Must not be enabled in production.
+
+use crate::record_stream::{
+  BatchRecord, ConsumedRecord, PartitionPosition, RecordStream, RecordStreamArc, RecordStreamError,
+};
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::sleep;
+
+const PRODUCE_FAULT_RATE_ENV_KEY: &str = "RECORD_STREAM_FAULT_PRODUCE_RATE";
+const CONSUME_DELAY_RATE_ENV_KEY: &str = "RECORD_STREAM_FAULT_CONSUME_DELAY_RATE";
+const CONSUME_MAX_DELAY_MS_ENV_KEY: &str = "RECORD_STREAM_FAULT_CONSUME_MAX_DELAY_MS";
+const DEFAULT_CONSUME_MAX_DELAY_MS: &str = "1000";
+const DUPLICATE_RATE_ENV_KEY: &str = "RECORD_STREAM_FAULT_DUPLICATE_RATE";
+const COMMIT_FAULT_RATE_ENV_KEY: &str = "RECORD_STREAM_FAULT_COMMIT_RATE";
+const FAULT_SEED_ENV_KEY: &str = "RECORD_STREAM_FAULT_SEED";
+const DEFAULT_FAULT_RATE: &str = "0";
+
+/// The operation that failed due to an injected fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultOperation {
+  Produce,
+  Commit,
+}
+
+impl FaultOperation {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Produce => "produce",
+      Self::Commit => "commit",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultConfig {
+  /// Probability of failing a produce
+  pub produce_rate: f64,
+  /// Probability of delaying a consume
+  pub consume_delay_rate: f64,
+  /// Upper bound of a consume delay
+  pub consume_max_delay: Duration,
+  /// Probability of delivering a consumed record twice
+  pub duplicate_rate: f64,
+  /// Probability of failing a commit
+  pub commit_rate: f64,
+  /// Seed of the fault decisions, if they should be reproducible
+  pub seed: Option<u64>,
+}
+
+impl FaultConfig {
+  /// Reads the fault settings. Panics if they are invalid.
+  pub fn from_env() -> Self {
+    let config = Self {
+      produce_rate: parse_env_var(PRODUCE_FAULT_RATE_ENV_KEY, DEFAULT_FAULT_RATE),
+      consume_delay_rate: parse_env_var(CONSUME_DELAY_RATE_ENV_KEY, DEFAULT_FAULT_RATE),
+      consume_max_delay: Duration::from_millis(parse_env_var(
+        CONSUME_MAX_DELAY_MS_ENV_KEY,
+        DEFAULT_CONSUME_MAX_DELAY_MS,
+      )),
+      duplicate_rate: parse_env_var(DUPLICATE_RATE_ENV_KEY, DEFAULT_FAULT_RATE),
+      commit_rate: parse_env_var(COMMIT_FAULT_RATE_ENV_KEY, DEFAULT_FAULT_RATE),
+      seed: env::var(FAULT_SEED_ENV_KEY).ok().map(|v| {
+        v.parse()
+          .expect("RECORD_STREAM_FAULT_SEED must be an integer")
+      }),
+    };
+    for (env_key, rate) in [
+      (PRODUCE_FAULT_RATE_ENV_KEY, config.produce_rate),
+      (CONSUME_DELAY_RATE_ENV_KEY, config.consume_delay_rate),
+      (DUPLICATE_RATE_ENV_KEY, config.duplicate_rate),
+      (COMMIT_FAULT_RATE_ENV_KEY, config.commit_rate),
+    ] {
+      assert!(
+        (0.0..=1.0).contains(&rate),
+        "{} must be between 0 and 1",
+        env_key
+      );
+    }
+    config
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.produce_rate > 0.0
+      || self.consume_delay_rate > 0.0
+      || self.duplicate_rate > 0.0
+      || self.commit_rate > 0.0
+  }
+}
+
+/// Wraps the stream with fault injection, if any fault rate is set.
+pub fn with_faults(stream: RecordStreamArc, config: &FaultConfig, topic: &str) -> RecordStreamArc {
+  if !config.is_enabled() {
+    return stream;
+  }
+  warn!("Injecting record stream faults for topic {}", topic);
+  Arc::new(FaultyRecordStream::new(stream, config.clone(), topic))
+}
+
+/// Record stream that injects faults into the operations of the wrapped stream.
+pub struct FaultyRecordStream {
+  inner: RecordStreamArc,
+  config: FaultConfig,
+  topic: String,
+  rng: Mutex<StdRng>,
+  /// Record to deliver again on the next consume
+  duplicate: Mutex<Option<ConsumedRecord>>,
+}
+
+impl FaultyRecordStream {
+  pub fn new(inner: RecordStreamArc, config: FaultConfig, topic: &str) -> Self {
+    let rng = match config.seed {
+      Some(seed) => StdRng::seed_from_u64(seed),
+      None => StdRng::from_entropy(),
+    };
+    Self {
+      inner,
+      config,
+      topic: topic.to_string(),
+      rng: Mutex::new(rng),
+      duplicate: Mutex::new(None),
+    }
+  }
+
+  fn inject(&self, rate: f64) -> bool {
+    rate > 0.0 && self.rng.lock().unwrap().gen_bool(rate)
+  }
+
+  fn fault(&self, operation: FaultOperation) -> RecordStreamError {
+    RecordStreamError::InjectedFault {
+      operation,
+      topic: self.topic.clone(),
+    }
+  }
+
+  fn fail(&self, operation: FaultOperation, rate: f64) -> Result<(), RecordStreamError> {
+    match self.inject(rate) {
+      true => Err(self.fault(operation)),
+      false => Ok(()),
+    }
+  }
+
+  async fn delay_consume(&self) {
+    if self.inject(self.config.consume_delay_rate) {
+      let delay = self
+        .config
+        .consume_max_delay
+        .mul_f64(self.rng.lock().unwrap().gen::<f64>());
+      sleep(delay).await;
+    }
+  }
+}
+
+#[async_trait]
+impl RecordStream for FaultyRecordStream {
+  fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
+    self.inner.init_producer_transactions()
+  }
+
+  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.begin_producer_transaction()
+  }
+
+  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.commit_producer_transaction()
+  }
+
+  fn abort_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.abort_producer_transaction()
+  }
+
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.inner.check_connection().await
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    self.inner.has_assigned_partitions()
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    self.inner.assigned_partitions()
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.committed_offsets()
+  }
+
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.end_offsets()
+  }
+
+  fn partition_positions(&self) -> Result<Vec<PartitionPosition>, RecordStreamError> {
+    self.inner.partition_positions()
+  }
+
+  fn partition_lags(&self) -> Result<Vec<(i32, i64)>, RecordStreamError> {
+    self.inner.partition_lags()
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    self.fail(FaultOperation::Produce, self.config.produce_rate)?;
+    self
+      .inner
+      .produce_with_headers(
+        record,
+        key,
+        request_threshold,
+        channel_name,
+        epoch,
+        extra_headers,
+      )
+      .await
+  }
+
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    // Fails the batch after producing the records before the failed one
+    match records
+      .iter()
+      .position(|_| self.inject(self.config.produce_rate))
+    {
+      Some(failed) => {
+        self.inner.produce_batch(&records[..failed]).await?;
+        Err(self.fault(FaultOperation::Produce))
+      }
+      None => self.inner.produce_batch(records).await,
+    }
+  }
+
+  async fn init_producer_queues(&self) {
+    self.inner.init_producer_queues().await
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    self.fail(FaultOperation::Produce, self.config.produce_rate)?;
+    self.inner.queue_produce(record, key).await
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    self.inner.join_produce_queues().await
+  }
+
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    self.delay_consume().await;
+    if let Some(record) = self.duplicate.lock().unwrap().take() {
+      return Ok(record);
+    }
+    let record = self.inner.consume().await?;
+    if self.inject(self.config.duplicate_rate) {
+      *self.duplicate.lock().unwrap() = Some(record.clone());
+    }
+    Ok(record)
+  }
+
+  async fn consume_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<ConsumedRecord>, RecordStreamError> {
+    self.delay_consume().await;
+    let mut records = Vec::new();
+    if let Some(record) = self.duplicate.lock().unwrap().take() {
+      records.push(record);
+    }
+    for record in self.inner.consume_batch(max_count, max_wait).await? {
+      if self.inject(self.config.duplicate_rate) {
+        records.push(record.clone());
+      }
+      records.push(record);
+    }
+    Ok(records)
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    self.fail(FaultOperation::Commit, self.config.commit_rate)?;
+    self.inner.commit_last_consume().await
+  }
+
+  fn pause_partition(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    self.inner.pause_partition(partition, offset)
+  }
+
+  fn pause(&self) -> Result<bool, RecordStreamError> {
+    self.inner.pause()
+  }
+
+  fn resume(&self) -> Result<(), RecordStreamError> {
+    self.inner.resume()
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    self.fail(FaultOperation::Commit, self.config.commit_rate)?;
+    self.inner.commit_offsets(offsets).await
+  }
+
+  async fn shutdown(&self, commit: bool) -> Result<(), RecordStreamError> {
+    self.inner.shutdown(commit).await
+  }
+
+  fn seek(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    // Records pending redelivery precede the new position
+    self.duplicate.lock().unwrap().take();
+    self.inner.seek(partition, offset)
+  }
+
+  fn seek_to_timestamp(&self, timestamp: OffsetDateTime) -> Result<bool, RecordStreamError> {
+    self.duplicate.lock().unwrap().take();
+    self.inner.seek_to_timestamp(timestamp)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::TestRecordStream;
+
+  fn test_stream(count: u8) -> Arc<TestRecordStream> {
+    Arc::new(TestRecordStream {
+      records_to_consume: tokio::sync::Mutex::new(
+        (0..count)
+          .map(|i| ConsumedRecord {
+            data: vec![i],
+            ..Default::default()
+          })
+          .collect(),
+      ),
+      ..Default::default()
+    })
+  }
+
+  #[tokio::test]
+  async fn injected_faults() {
+    let inner = test_stream(3);
+    let stream = FaultyRecordStream::new(
+      inner.clone(),
+      FaultConfig {
+        produce_rate: 1.0,
+        duplicate_rate: 1.0,
+        commit_rate: 1.0,
+        seed: Some(1),
+        ..Default::default()
+      },
+      "p3a-star-enc",
+    );
+    let err = stream
+      .produce(b"a", None, None, None, None)
+      .await
+      .unwrap_err();
+    assert_eq!(err.category(), "fault_injection");
+    assert!(inner.records_produced.lock().await.is_empty());
+    assert!(stream.commit_last_consume().await.is_err());
+    assert!(stream.commit_offsets(&[(0, 1)]).await.is_err());
+
+    // Every record is delivered twice
+    assert_eq!(stream.consume().await.unwrap().data, vec![0]);
+    assert_eq!(stream.consume().await.unwrap().data, vec![0]);
+    let data: Vec<_> = stream
+      .consume_batch(5, Duration::from_millis(10))
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|v| v.data)
+      .collect();
+    assert_eq!(data, vec![vec![1], vec![1], vec![2], vec![2]]);
+  }
+
+  #[tokio::test]
+  async fn passes_through_without_faults() {
+    let inner = test_stream(2);
+    let config = FaultConfig {
+      consume_delay_rate: 1.0,
+      consume_max_delay: Duration::from_millis(1),
+      seed: Some(1),
+      ..Default::default()
+    };
+    assert!(config.is_enabled());
+    let stream = FaultyRecordStream::new(inner.clone(), config, "p3a-star-enc");
+    stream.produce(b"a", None, None, None, None).await.unwrap();
+    stream.commit_last_consume().await.unwrap();
+    assert_eq!(stream.consume().await.unwrap().data, vec![0]);
+    assert_eq!(stream.consume().await.unwrap().data, vec![1]);
+    assert_eq!(*inner.records_produced.lock().await, vec![b"a".to_vec()]);
+    assert!(!FaultConfig::default().is_enabled());
+  }
+}
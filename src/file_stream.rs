@@ -0,0 +1,434 @@
+//! Record stream backed by local files, selected via `RECORD_STREAM_BACKEND=file`, so that
+//! the full pipeline can be run locally without a Kafka cluster. Records of each topic are
+//! appended to `<dir>/<topic>.ndjson` as JSON lines, containing the base64 encoded record data
+//! and headers. Line numbers are used as offsets, and all records are reported in partition 0.
+//!
+//! The committed offset of each consumer group is persisted to `<dir>/<topic>.<group>.offset`,
+//! so consumption resumes after the last committed record. Consumers in a group do not share
+//! consumption, so only one consumer per group should run at a time.
+
+use crate::record_stream::{
+  consumer_group_id, datetime_from_unix_millis, datetime_to_unix_millis, ConsumedRecord,
+  KafkaRecordStreamConfig, RecordHeaderValues, RecordStream, RecordStreamError,
+  RecordStreamOptions,
+};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error, From};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::sleep;
+
+const FILE_RECORD_STREAM_DIR_ENV_KEY: &str = "FILE_RECORD_STREAM_DIR";
+const DEFAULT_FILE_RECORD_STREAM_DIR: &str = "record-streams";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const STREAM_PARTITION: i32 = 0;
+
+#[derive(Debug, Display, Error, From)]
+pub enum FileStreamError {
+  #[display(fmt = "File record stream IO error: {}", _0)]
+  Io(std::io::Error),
+  #[display(fmt = "File record stream JSON error: {}", _0)]
+  Json(serde_json::Error),
+  #[display(fmt = "File record stream offset error: {}", _0)]
+  Offset(std::num::ParseIntError),
+}
+
+/// A line of the record file
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+  data: String,
+  #[serde(default)]
+  headers: Vec<(String, String)>,
+  /// Time of the append in Unix milliseconds
+  timestamp: i64,
+}
+
+struct ReaderState {
+  reader: BufReader<File>,
+  /// Bytes of the current line that have been read so far
+  line: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ConsumerState {
+  initialized: bool,
+  /// Offset of the next line to be read
+  next_offset: i64,
+  consumed_offset: Option<i64>,
+  committed_offset: Option<i64>,
+}
+
+pub struct FileRecordStream {
+  records_path: PathBuf,
+  offset_path: PathBuf,
+  tenant: Option<String>,
+  enable_producer: bool,
+  record_headers: Vec<(String, Vec<u8>)>,
+  writer: AsyncMutex<Option<File>>,
+  /// Also prevents concurrent consumption
+  reader: AsyncMutex<Option<ReaderState>>,
+  consumer_state: Mutex<ConsumerState>,
+}
+
+impl FileRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let dir: String = parse_env_var(
+      FILE_RECORD_STREAM_DIR_ENV_KEY,
+      DEFAULT_FILE_RECORD_STREAM_DIR,
+    );
+    Self::new_in_dir(Path::new(&dir), stream_config, options)
+  }
+
+  fn new_in_dir(
+    dir: &Path,
+    stream_config: KafkaRecordStreamConfig,
+    options: RecordStreamOptions,
+  ) -> Self {
+    let tenant = stream_config.tenant.as_deref();
+    let topic = tenant_scoped_name(&stream_config.topic, tenant);
+    let group_id = consumer_group_id(&stream_config);
+    Self {
+      records_path: dir.join(format!("{}.ndjson", topic)),
+      offset_path: dir.join(format!("{}.{}.offset", topic, group_id)),
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      record_headers: options.record_headers,
+      writer: AsyncMutex::new(None),
+      reader: AsyncMutex::new(None),
+      consumer_state: Mutex::new(ConsumerState::default()),
+    }
+  }
+
+  /// Creates the record file if it does not exist, so that consumers
+  /// may start before producers.
+  async fn open_for_append(&self) -> Result<File, FileStreamError> {
+    if let Some(dir) = self.records_path.parent() {
+      fs::create_dir_all(dir).await?;
+    }
+    Ok(
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&self.records_path)
+        .await?,
+    )
+  }
+
+  async fn read_committed_offset(&self) -> Result<Option<i64>, FileStreamError> {
+    match fs::read_to_string(&self.offset_path).await {
+      Ok(contents) => Ok(Some(contents.trim().parse()?)),
+      Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn init_reader(&self) -> Result<ReaderState, FileStreamError> {
+    self.open_for_append().await?;
+    let committed_offset = self.read_committed_offset().await?;
+    let reader = BufReader::new(File::open(&self.records_path).await?);
+    let mut state = self.consumer_state.lock().unwrap();
+    state.initialized = true;
+    state.next_offset = 0;
+    state.committed_offset = Some(committed_offset.unwrap_or_default());
+    Ok(ReaderState {
+      reader,
+      line: Vec::new(),
+    })
+  }
+
+  fn parse_record(
+    &self,
+    line: &[u8],
+    offset: i64,
+  ) -> Result<Option<ConsumedRecord>, FileStreamError> {
+    let record: FileRecord = serde_json::from_slice(line)?;
+    let decoded_headers: Vec<_> = record
+      .headers
+      .iter()
+      .filter_map(|(k, v)| Some((k.as_str(), base64_engine::STANDARD.decode(v).ok()?)))
+      .collect();
+    let header_values =
+      RecordHeaderValues::parse(decoded_headers.iter().map(|(k, v)| (*k, v.as_slice())));
+    if !header_values.matches_tenant(self.tenant.as_deref()) {
+      return Ok(None);
+    }
+    let data = match base64_engine::STANDARD.decode(&record.data) {
+      Ok(data) => data,
+      Err(e) => {
+        warn!("Skipping file record with invalid data: {}", e);
+        return Ok(None);
+      }
+    };
+    Ok(Some(ConsumedRecord {
+      data,
+      request_threshold: header_values.request_threshold,
+      channel_name: header_values.channel_name,
+      epoch: header_values.epoch,
+      client_version: header_values.client_version,
+      topic: None,
+      partition: Some(STREAM_PARTITION),
+      offset: Some(offset),
+      submitted_at: datetime_from_unix_millis(
+        header_values.submitted_at.unwrap_or(record.timestamp),
+      ),
+      timestamp: datetime_from_unix_millis(record.timestamp),
+    }))
+  }
+
+  /// Cancel safe, since partially read lines are kept in the reader state.
+  async fn next_record(&self) -> Result<ConsumedRecord, FileStreamError> {
+    let mut reader = self.reader.lock().await;
+    if reader.is_none() {
+      *reader = Some(self.init_reader().await?);
+    }
+    let reader = reader.as_mut().unwrap();
+    loop {
+      reader.reader.read_until(b'\n', &mut reader.line).await?;
+      // Incomplete lines are still being appended
+      if reader.line.last() != Some(&b'\n') {
+        sleep(POLL_INTERVAL).await;
+        continue;
+      }
+      let line = std::mem::take(&mut reader.line);
+      let (offset, committed_offset) = {
+        let mut state = self.consumer_state.lock().unwrap();
+        state.next_offset += 1;
+        (state.next_offset - 1, state.committed_offset)
+      };
+      if offset < committed_offset.unwrap_or_default() {
+        continue;
+      }
+      if let Some(record) = self.parse_record(&line, offset)? {
+        self.consumer_state.lock().unwrap().consumed_offset = Some(offset);
+        return Ok(record);
+      }
+    }
+  }
+
+  async fn commit(&self, offset: i64) -> Result<(), FileStreamError> {
+    let committed_offset = {
+      let state = self.consumer_state.lock().unwrap();
+      state.committed_offset.unwrap_or_default().max(offset + 1)
+    };
+    // Replace the offset file atomically
+    let tmp_path = self.offset_path.with_extension("offset.tmp");
+    fs::write(&tmp_path, committed_offset.to_string()).await?;
+    fs::rename(&tmp_path, &self.offset_path).await?;
+    self.consumer_state.lock().unwrap().committed_offset = Some(committed_offset);
+    Ok(())
+  }
+
+  async fn append(
+    &self,
+    record: &[u8],
+    header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), FileStreamError> {
+    assert!(
+      self.enable_producer,
+      "File record stream producer not enabled"
+    );
+    let mut header_values = header_values;
+    header_values.tenant = self.tenant.clone();
+    let headers = header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .map(|(k, v)| (k, base64_engine::STANDARD.encode(v)))
+      .collect();
+    let mut line = serde_json::to_vec(&FileRecord {
+      data: base64_engine::STANDARD.encode(record),
+      headers,
+      timestamp: datetime_to_unix_millis(OffsetDateTime::now_utc()),
+    })?;
+    line.push(b'\n');
+    let mut writer = self.writer.lock().await;
+    if writer.is_none() {
+      *writer = Some(self.open_for_append().await?);
+    }
+    // Each line is appended with a single write, so that lines of concurrent
+    // producers are not interleaved
+    let file = writer.as_mut().unwrap();
+    file.write_all(&line).await?;
+    file.flush().await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl RecordStream for FileRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.open_for_append().await?;
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(self.consumer_state.lock().unwrap().initialized)
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(match self.consumer_state.lock().unwrap().initialized {
+      true => vec![STREAM_PARTITION],
+      false => Vec::new(),
+    })
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .committed_offset
+        .map(|v| (STREAM_PARTITION, v))
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  /// Counts the complete lines of the record file.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let line_count = match std::fs::read(&self.records_path) {
+      Ok(contents) => contents.iter().filter(|v| **v == b'\n').count() as i64,
+      Err(e) if e.kind() == ErrorKind::NotFound => 0,
+      Err(e) => return Err(FileStreamError::from(e).into()),
+    };
+    Ok(HashMap::from([(STREAM_PARTITION, line_count)]))
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    _key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    self
+      .append(
+        record,
+        RecordHeaderValues {
+          request_threshold,
+          channel_name: channel_name.map(|v| v.to_string()),
+          epoch,
+          ..Default::default()
+        },
+        extra_headers,
+      )
+      .await?;
+    Ok(())
+  }
+
+  /// Queued records are appended directly, since appends are not batched.
+  async fn init_producer_queues(&self) {
+    assert!(
+      self.enable_producer,
+      "File record stream producer not enabled"
+    );
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    _key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    self
+      .append(&record, RecordHeaderValues::default(), &[])
+      .await?;
+    Ok(())
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    Ok(self.next_record().await?)
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let consumed_offset = self.consumer_state.lock().unwrap().consumed_offset;
+    if let Some(offset) = consumed_offset {
+      self.commit(offset).await?;
+    }
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    for (partition, offset) in offsets {
+      if *partition == STREAM_PARTITION {
+        self.commit(*offset).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::KafkaComponent;
+  use rand::random;
+  use tokio::time::timeout;
+
+  fn stream(dir: &Path) -> FileRecordStream {
+    FileRecordStream::new_in_dir(
+      dir,
+      KafkaRecordStreamConfig {
+        component: KafkaComponent::Aggregator,
+        enable_producer: true,
+        enable_consumer: true,
+        topic: "p3a-star-enc".to_string(),
+        use_output_group_id: false,
+        group_id_suffix: None,
+        tenant: None,
+      },
+      RecordStreamOptions::default(),
+    )
+  }
+
+  #[tokio::test]
+  async fn produce_consume_commit() {
+    let dir = std::env::temp_dir().join(format!("file-record-stream-{}", random::<u64>()));
+    let rec_stream = stream(&dir);
+    for i in 0..3u8 {
+      rec_stream
+        .produce(&[i], None, Some(20), Some("typical"), Some(i))
+        .await
+        .unwrap();
+    }
+    let record = rec_stream.consume().await.unwrap();
+    assert_eq!(record.data, vec![0]);
+    assert_eq!(record.request_threshold, Some(20));
+    assert_eq!(record.channel_name.as_deref(), Some("typical"));
+    assert!(record.timestamp.is_some());
+    let record = rec_stream.consume().await.unwrap();
+    assert_eq!((record.offset, record.epoch), (Some(1), Some(1)));
+    rec_stream.commit_last_consume().await.unwrap();
+    assert_eq!(rec_stream.partition_positions().unwrap()[0].lag(), 1);
+
+    // Consumption resumes after the committed offset
+    let rec_stream = stream(&dir);
+    let record = rec_stream.consume().await.unwrap();
+    assert_eq!((record.data, record.offset), (vec![2], Some(2)));
+    assert!(timeout(Duration::from_millis(100), rec_stream.consume())
+      .await
+      .is_err());
+    rec_stream.queue_produce(vec![3], None).await.unwrap();
+    assert_eq!(rec_stream.consume().await.unwrap().data, vec![3]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,246 @@
+//! Gate on the record format version of Kafka topics. If the format of produced records
+//! changes, records written by a newer release may be processed incorrectly by consumers
+//! of an older release during a rolling deployment. If `KAFKA_FORMAT_VERSION_TOPIC` is set,
+//! the format version of each topic is tracked as a control record in that topic, keyed by
+//! the name of the data topic, with the version as the payload. The control topic should
+//! be compacted, so that only the latest marker of each topic is retained.
+//!
+//! When a Kafka record stream is created, the latest marker of its topic is read, and the
+//! process refuses to start if the marker is newer than `RECORD_FORMAT_VERSION`. Producers
+//! raise the marker to their own version before producing, so that older consumers
+//! stop instead of reading records in a format they do not support.
+
+use crate::kafka_topics::ensure_topic;
+use crate::record_stream::{KafkaComponent, KafkaOperation, KafkaRecordStream, RecordStreamError};
+use futures::executor::block_on;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::{Message, Offset, TopicPartitionList};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const KAFKA_FORMAT_VERSION_TOPIC_ENV_KEY: &str = "KAFKA_FORMAT_VERSION_TOPIC";
+/// Version of the record format written and supported by this release.
+/// Must be incremented if records of the new format cannot be read by older releases.
+pub const RECORD_FORMAT_VERSION: u32 = 1;
+const MARKER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Topics that were checked by this process, and whether the marker was raised
+static CHECKED_TOPICS: Mutex<BTreeSet<(String, bool)>> = Mutex::new(BTreeSet::new());
+
+/// Returns the marker version of the topic, if any, given the markers read from the
+/// control topic in order. Markers that cannot be parsed are an error.
+fn latest_marker<'a>(
+  topic: &str,
+  markers: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+) -> Result<Option<u32>, RecordStreamError> {
+  let mut result = None;
+  for (_, payload) in markers.filter(|(key, _)| *key == topic.as_bytes()) {
+    let version = std::str::from_utf8(payload)
+      .ok()
+      .and_then(|v| v.trim().parse::<u32>().ok())
+      .ok_or_else(|| RecordStreamError::FormatMarker {
+        topic: topic.to_string(),
+      })?;
+    result = Some(version);
+  }
+  Ok(result)
+}
+
+/// Checks that the marker version is supported by this release.
+fn check_version(topic: &str, version: Option<u32>) -> Result<(), RecordStreamError> {
+  match version {
+    Some(version) if version > RECORD_FORMAT_VERSION => Err(RecordStreamError::UnsupportedFormat {
+      topic: topic.to_string(),
+      version,
+      supported: RECORD_FORMAT_VERSION,
+    }),
+    _ => Ok(()),
+  }
+}
+
+/// Reads all markers in the control topic, and returns the latest version for the topic.
+fn read_marker(
+  component: KafkaComponent,
+  control_topic: &str,
+  topic: &str,
+) -> Result<Option<u32>, RecordStreamError> {
+  let context = |operation, partition| {
+    move |e| RecordStreamError::kafka(operation, control_topic, partition, e)
+  };
+  let consumer: BaseConsumer = KafkaRecordStream::new_client_config(component)
+    .set("enable.auto.commit", "false")
+    .set("enable.partition.eof", "false")
+    .create()
+    .map_err(context(KafkaOperation::Connect, None))?;
+  let metadata = consumer
+    .fetch_metadata(Some(control_topic), MARKER_READ_TIMEOUT)
+    .map_err(context(KafkaOperation::Metadata, None))?;
+  let partitions: Vec<i32> = metadata
+    .topics()
+    .iter()
+    .filter(|v| v.name() == control_topic)
+    .flat_map(|v| v.partitions().iter().map(|p| p.id()))
+    .collect();
+
+  // Offset of the last record of each partition that contains records
+  let mut remaining = HashMap::new();
+  let mut assignment = TopicPartitionList::new();
+  for partition in partitions {
+    let (low, high) = consumer
+      .fetch_watermarks(control_topic, partition, MARKER_READ_TIMEOUT)
+      .map_err(context(KafkaOperation::Offsets, Some(partition)))?;
+    if high > low {
+      remaining.insert(partition, high - 1);
+      assignment
+        .add_partition_offset(control_topic, partition, Offset::Beginning)
+        .map_err(context(KafkaOperation::Seek, Some(partition)))?;
+    }
+  }
+  if remaining.is_empty() {
+    return Ok(None);
+  }
+  consumer
+    .assign(&assignment)
+    .map_err(context(KafkaOperation::Seek, None))?;
+
+  let mut markers = Vec::new();
+  let started_at = Instant::now();
+  while !remaining.is_empty() {
+    if started_at.elapsed() > MARKER_READ_TIMEOUT {
+      return Err(RecordStreamError::kafka(
+        KafkaOperation::Consume,
+        control_topic,
+        None,
+        KafkaError::MessageConsumption(RDKafkaErrorCode::OperationTimedOut),
+      ));
+    }
+    let msg = match consumer.poll(Duration::from_secs(1)) {
+      None => continue,
+      Some(msg) => msg.map_err(context(KafkaOperation::Consume, None))?,
+    };
+    if let (Some(key), Some(payload)) = (msg.key(), msg.payload()) {
+      markers.push((key.to_vec(), payload.to_vec()));
+    }
+    let last_offset = remaining.get(&msg.partition()).copied();
+    if last_offset.map(|v| msg.offset() >= v).unwrap_or(false) {
+      remaining.remove(&msg.partition());
+    }
+  }
+  latest_marker(
+    topic,
+    markers.iter().map(|(k, v)| (k.as_slice(), v.as_slice())),
+  )
+}
+
+/// Sets the marker of the topic to the version of this release.
+fn write_marker(
+  component: KafkaComponent,
+  control_topic: &str,
+  topic: &str,
+) -> Result<(), RecordStreamError> {
+  let producer: FutureProducer = KafkaRecordStream::new_client_config(component)
+    .create()
+    .map_err(|e| RecordStreamError::kafka(KafkaOperation::Connect, control_topic, None, e))?;
+  let payload = RECORD_FORMAT_VERSION.to_string();
+  let record = FutureRecord::to(control_topic).key(topic).payload(&payload);
+  // Delivery is driven by a thread of the producer, so the runtime is not needed
+  let delivery = producer
+    .send_result(record)
+    .map_err(|(e, _)| RecordStreamError::kafka(KafkaOperation::Produce, control_topic, None, e))?;
+  block_on(delivery)
+    .map_err(|_| {
+      RecordStreamError::kafka(
+        KafkaOperation::Produce,
+        control_topic,
+        None,
+        KafkaError::Canceled,
+      )
+    })?
+    .map_err(|(e, _)| RecordStreamError::kafka(KafkaOperation::Produce, control_topic, None, e))?;
+  info!(
+    "Raised record format version of topic {} to {}",
+    topic, RECORD_FORMAT_VERSION
+  );
+  Ok(())
+}
+
+/// Checks that the record format of the topic is supported, if the format version topic
+/// is configured. Producers raise the format version of the topic to the version of
+/// this release. Each topic is only checked once.
+pub fn check_format_version(
+  component: KafkaComponent,
+  topic: &str,
+  enable_producer: bool,
+) -> Result<(), RecordStreamError> {
+  let control_topic = match env::var(KAFKA_FORMAT_VERSION_TOPIC_ENV_KEY) {
+    Ok(control_topic) => control_topic,
+    Err(_) => return Ok(()),
+  };
+  {
+    let checked_topics = CHECKED_TOPICS.lock().unwrap();
+    if checked_topics.contains(&(topic.to_string(), true))
+      || checked_topics.contains(&(topic.to_string(), enable_producer))
+    {
+      return Ok(());
+    }
+  }
+  ensure_topic(component, &control_topic, &control_topic)?;
+  let version = read_marker(component, &control_topic, topic)?;
+  check_version(topic, version)?;
+  if enable_producer && version.map(|v| v < RECORD_FORMAT_VERSION).unwrap_or(true) {
+    write_marker(component, &control_topic, topic)?;
+  }
+  CHECKED_TOPICS
+    .lock()
+    .unwrap()
+    .insert((topic.to_string(), enable_producer));
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn markers() {
+    let markers: [(&[u8], &[u8]); 3] = [
+      (b"p3a-star-enc", b"1"),
+      (b"p3a-star-out", b"7"),
+      (b"p3a-star-enc", b"2"),
+    ];
+    assert_eq!(
+      latest_marker("p3a-star-enc", markers.into_iter()).unwrap(),
+      Some(2)
+    );
+    assert_eq!(
+      latest_marker("p3a-star-dlq", markers.into_iter()).unwrap(),
+      None
+    );
+    let invalid: [(&[u8], &[u8]); 1] = [(b"p3a-star-enc", b"v2")];
+    assert_eq!(
+      latest_marker("p3a-star-enc", invalid.into_iter())
+        .unwrap_err()
+        .category(),
+      "format_version"
+    );
+
+    assert!(check_version("p3a-star-enc", None).is_ok());
+    assert!(check_version("p3a-star-enc", Some(RECORD_FORMAT_VERSION)).is_ok());
+    let e = check_version("p3a-star-enc", Some(RECORD_FORMAT_VERSION + 1)).unwrap_err();
+    assert_eq!(e.category(), "format_version");
+    assert_eq!(
+      e.to_string(),
+      format!(
+        "Record stream error: topic p3a-star-enc has record format version {}, \
+         this release supports up to {}",
+        RECORD_FORMAT_VERSION + 1,
+        RECORD_FORMAT_VERSION
+      )
+    );
+  }
+}
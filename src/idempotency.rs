@@ -0,0 +1,252 @@
+//! Request-level idempotency for the submission endpoints. Clients may supply an
+//! `Idempotency-Key` header, and retried requests with the same key will receive the
+//! original response without the message being produced again. Keys are reserved
+//! before the message is produced, so that concurrent retries are rejected
+//! with a conflict status while the original request is in progress.
+//!
+//! Keys are remembered in memory for `IDEMPOTENCY_KEY_TTL_SECS`, or in Redis if
+//! the shared `SERVER_REDIS_URL` is set, so that keys are shared between replicas.
+//! If Redis is unavailable, requests are processed without idempotency checks.
+
+use crate::redis::{RedisClient, RedisError};
+use crate::util::parse_env_var;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+const IDEMPOTENCY_KEY_TTL_SECS_ENV_KEY: &str = "IDEMPOTENCY_KEY_TTL_SECS";
+const DEFAULT_IDEMPOTENCY_KEY_TTL_SECS: &str = "86400";
+const IDEMPOTENCY_MAX_KEYS_ENV_KEY: &str = "IDEMPOTENCY_MAX_KEYS";
+const DEFAULT_IDEMPOTENCY_MAX_KEYS: &str = "1000000";
+const REDIS_KEY_PREFIX: &str = "idempotency:";
+
+/// The response of a completed submission, which is returned for retried requests
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredResponse {
+  pub status: u16,
+  pub receipt: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum Entry {
+  InProgress,
+  Completed(StoredResponse),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reservation {
+  /// The key was reserved for this request
+  Reserved,
+  /// A request with the same key is being processed
+  InProgress,
+  /// A request with the same key was completed
+  Completed(StoredResponse),
+  /// The key could not be checked, and the request should be processed as usual
+  Unavailable,
+}
+
+#[derive(Default)]
+struct MemoryStore {
+  entries: HashMap<String, (Instant, Entry)>,
+  /// Keys with their expiry instants, in insertion order.
+  /// Since the TTL is fixed, this is also the order of expiry.
+  expiry_queue: VecDeque<(Instant, String)>,
+}
+
+impl MemoryStore {
+  fn prune(&mut self, max_keys: usize) {
+    let now = Instant::now();
+    while let Some((expires_at, _)) = self.expiry_queue.front() {
+      if *expires_at > now && self.entries.len() < max_keys {
+        break;
+      }
+      let (expires_at, key) = self.expiry_queue.pop_front().unwrap();
+      // The key may have been released and reserved again by a later request
+      if self.entries.get(&key).map(|(v, _)| *v) == Some(expires_at) {
+        self.entries.remove(&key);
+      }
+    }
+  }
+
+  fn insert(&mut self, key: &str, entry: Entry, ttl: Duration) {
+    let expires_at = Instant::now() + ttl;
+    self.entries.insert(key.to_string(), (expires_at, entry));
+    self.expiry_queue.push_back((expires_at, key.to_string()));
+  }
+}
+
+enum Backend {
+  Memory {
+    store: Mutex<MemoryStore>,
+    max_keys: usize,
+  },
+  Redis(Arc<RedisClient>),
+}
+
+pub struct IdempotencyCache {
+  backend: Backend,
+  ttl: Duration,
+}
+
+impl IdempotencyCache {
+  pub fn new_memory(ttl: Duration, max_keys: usize) -> Self {
+    Self {
+      backend: Backend::Memory {
+        store: Mutex::new(MemoryStore::default()),
+        max_keys: max_keys.max(1),
+      },
+      ttl,
+    }
+  }
+
+  /// Uses the shared server Redis client if configured, followed by memory.
+  pub fn from_env(shared_redis: Option<Arc<RedisClient>>) -> Self {
+    let ttl = Duration::from_secs(parse_env_var::<u64>(
+      IDEMPOTENCY_KEY_TTL_SECS_ENV_KEY,
+      DEFAULT_IDEMPOTENCY_KEY_TTL_SECS,
+    ));
+    match shared_redis {
+      Some(redis) => {
+        info!("Using Redis for idempotency keys");
+        Self {
+          backend: Backend::Redis(redis),
+          ttl,
+        }
+      }
+      None => Self::new_memory(
+        ttl,
+        parse_env_var::<usize>(IDEMPOTENCY_MAX_KEYS_ENV_KEY, DEFAULT_IDEMPOTENCY_MAX_KEYS),
+      ),
+    }
+  }
+
+  async fn redis_reserve(
+    &self,
+    client: &RedisClient,
+    key: &str,
+  ) -> Result<Reservation, RedisError> {
+    let key = format!("{}{}", REDIS_KEY_PREFIX, key);
+    let in_progress = serde_json::to_vec(&Entry::InProgress).unwrap();
+    let ttl_ms = self.ttl.as_millis() as u64;
+    if client.set_nx_px(&key, &in_progress, ttl_ms).await? {
+      return Ok(Reservation::Reserved);
+    }
+    Ok(match client.get(&key).await? {
+      // The key expired since the reservation attempt; treat the request as new
+      None => match client.set_nx_px(&key, &in_progress, ttl_ms).await? {
+        true => Reservation::Reserved,
+        false => Reservation::InProgress,
+      },
+      Some(value) => match serde_json::from_slice(&value) {
+        Ok(Entry::Completed(response)) => Reservation::Completed(response),
+        Ok(Entry::InProgress) => Reservation::InProgress,
+        Err(_) => return Err(RedisError::Protocol),
+      },
+    })
+  }
+
+  /// Reserves the key for a new request, or returns the state
+  /// of an existing request with the same key.
+  pub async fn reserve(&self, key: &str) -> Reservation {
+    match &self.backend {
+      Backend::Memory { store, max_keys } => {
+        let mut store = store.lock().unwrap();
+        store.prune(*max_keys);
+        match store.entries.get(key) {
+          Some((_, Entry::InProgress)) => Reservation::InProgress,
+          Some((_, Entry::Completed(response))) => Reservation::Completed(response.clone()),
+          None => {
+            store.insert(key, Entry::InProgress, self.ttl);
+            Reservation::Reserved
+          }
+        }
+      }
+      Backend::Redis(client) => match self.redis_reserve(client, key).await {
+        Ok(reservation) => reservation,
+        Err(e) => {
+          warn!("Failed to check idempotency key: {}", e);
+          Reservation::Unavailable
+        }
+      },
+    }
+  }
+
+  /// Stores the response for a reserved key.
+  pub async fn complete(&self, key: &str, response: StoredResponse) {
+    let entry = Entry::Completed(response);
+    match &self.backend {
+      Backend::Memory { store, .. } => {
+        let mut store = store.lock().unwrap();
+        if let Some((_, stored_entry)) = store.entries.get_mut(key) {
+          *stored_entry = entry;
+        }
+      }
+      Backend::Redis(client) => {
+        let key = format!("{}{}", REDIS_KEY_PREFIX, key);
+        let value = serde_json::to_vec(&entry).unwrap();
+        if let Err(e) = client
+          .set_px(&key, &value, self.ttl.as_millis() as u64)
+          .await
+        {
+          warn!("Failed to store idempotency key response: {}", e);
+        }
+      }
+    }
+  }
+
+  /// Releases a reserved key after a failed request, so that the request can be retried.
+  pub async fn release(&self, key: &str) {
+    match &self.backend {
+      Backend::Memory { store, .. } => {
+        store.lock().unwrap().entries.remove(key);
+      }
+      Backend::Redis(client) => {
+        if let Err(e) = client.del(&format!("{}{}", REDIS_KEY_PREFIX, key)).await {
+          warn!("Failed to release idempotency key: {}", e);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn reserve_complete_release() {
+    let cache = IdempotencyCache::new_memory(Duration::from_secs(60), 2);
+    let response = StoredResponse {
+      status: 204,
+      receipt: Some("receipt".to_string()),
+    };
+
+    assert_eq!(cache.reserve("a").await, Reservation::Reserved);
+    assert_eq!(cache.reserve("a").await, Reservation::InProgress);
+    cache.complete("a", response.clone()).await;
+    assert_eq!(
+      cache.reserve("a").await,
+      Reservation::Completed(response.clone())
+    );
+
+    assert_eq!(cache.reserve("b").await, Reservation::Reserved);
+    cache.release("b").await;
+    assert_eq!(cache.reserve("b").await, Reservation::Reserved);
+
+    // The oldest key is evicted once the max amount of keys is reached
+    assert_eq!(cache.reserve("c").await, Reservation::Reserved);
+    assert_eq!(cache.reserve("a").await, Reservation::Reserved);
+  }
+
+  #[tokio::test]
+  async fn expiry() {
+    let cache = IdempotencyCache::new_memory(Duration::from_millis(10), 100);
+    assert_eq!(cache.reserve("a").await, Reservation::Reserved);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(cache.reserve("a").await, Reservation::Reserved);
+  }
+}
@@ -0,0 +1,170 @@
+//! Static membership of Kafka consumers. If `KAFKA_GROUP_INSTANCE_ID` is set to a name that
+//! is stable across restarts of the process, i.e. the pod name of a stateful set, consumers
+//! that subscribe to a topic join the consumer group as static members. A static member that
+//! restarts and rejoins within the session timeout gets its previous partitions back, without
+//! a rebalance of the group, so the other consumers of the group keep consuming their
+//! partitions instead of re-reading them from the committed offsets. As with the security
+//! settings, the instance id may be overridden for a component via
+//! `KAFKA_<COMPONENT>_GROUP_INSTANCE_ID`.
+//!
+//! Each consumer of a group must have its own instance id, so the `group.instance.id` of a
+//! consumer is the configured id, suffixed with the topic and the index of the consumer
+//! among the consumers of the topic in the process, i.e. `star-agg-0-p3a-star-enc-1`.
+//! Consumers are created in the same order on every start, so each consumer gets the same
+//! id as its predecessor. The index of a dropped consumer is reused by the next consumer
+//! of the topic.
+
+use crate::record_stream::KafkaComponent;
+use rdkafka::config::ClientConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::sync::Mutex;
+
+const GROUP_INSTANCE_ID_SETTING: &str = "GROUP_INSTANCE_ID";
+/// Maximum length of a group instance id accepted by the brokers
+const MAX_GROUP_INSTANCE_ID_LENGTH: usize = 249;
+
+/// Indexes of the instance ids in use, by consumer group and topic
+static RESERVED_INDEXES: Mutex<BTreeMap<(String, String), BTreeSet<usize>>> =
+  Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaGroupConfig {
+  pub instance_id: Option<String>,
+}
+
+impl KafkaGroupConfig {
+  /// Reads the settings of the component, falling back to the shared settings.
+  /// Panics if the instance id is invalid.
+  pub fn from_env(component: KafkaComponent) -> Self {
+    Self::from_lookup(|setting| {
+      env::var(component.env_key(setting))
+        .or_else(|_| env::var(format!("KAFKA_{}", setting)))
+        .ok()
+    })
+  }
+
+  fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+    let instance_id = lookup(GROUP_INSTANCE_ID_SETTING);
+    if let Some(Err(reason)) = instance_id.as_deref().map(validate_instance_id) {
+      panic!("Kafka {} {}", GROUP_INSTANCE_ID_SETTING, reason);
+    }
+    Self { instance_id }
+  }
+
+  /// Reserves the instance id of a new consumer of the topic, if static membership
+  /// is enabled. The id is released once the returned instance is dropped.
+  pub fn reserve_instance(&self, group_id: &str, topic: &str) -> Option<GroupInstance> {
+    let instance_id = self.instance_id.as_ref()?;
+    let key = (group_id.to_string(), topic.to_string());
+    let index = {
+      let mut reserved_indexes = RESERVED_INDEXES.lock().unwrap();
+      let indexes = reserved_indexes.entry(key.clone()).or_default();
+      let index = (0..).find(|i| !indexes.contains(i)).unwrap();
+      indexes.insert(index);
+      index
+    };
+    let id = format!("{}-{}-{}", instance_id, topic, index);
+    if let Err(reason) = validate_instance_id(&id) {
+      panic!("Kafka group instance id {} {}", id, reason);
+    }
+    Some(GroupInstance { key, index, id })
+  }
+}
+
+/// Static membership of a consumer in a group.
+#[derive(Debug)]
+pub struct GroupInstance {
+  key: (String, String),
+  index: usize,
+  pub id: String,
+}
+
+impl GroupInstance {
+  pub fn apply(&self, config: &mut ClientConfig) {
+    config.set("group.instance.id", &self.id);
+  }
+}
+
+impl Drop for GroupInstance {
+  fn drop(&mut self) {
+    let mut reserved_indexes = RESERVED_INDEXES.lock().unwrap();
+    if let Some(indexes) = reserved_indexes.get_mut(&self.key) {
+      indexes.remove(&self.index);
+      if indexes.is_empty() {
+        reserved_indexes.remove(&self.key);
+      }
+    }
+  }
+}
+
+fn validate_instance_id(instance_id: &str) -> Result<(), &'static str> {
+  if instance_id.is_empty() {
+    return Err("must not be empty");
+  }
+  if instance_id.len() > MAX_GROUP_INSTANCE_ID_LENGTH {
+    return Err("must be at most 249 characters");
+  }
+  if !instance_id
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+  {
+    return Err("must only contain ASCII alphanumerics, '.', '_' or '-'");
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn config(vars: &[(&str, &str)]) -> KafkaGroupConfig {
+    let vars: HashMap<_, _> = vars.iter().copied().collect();
+    KafkaGroupConfig::from_lookup(|setting| vars.get(setting).map(|v| v.to_string()))
+  }
+
+  #[test]
+  fn instance_ids() {
+    assert!(config(&[])
+      .reserve_instance("star-agg-enc", "p3a-star-enc")
+      .is_none());
+
+    let config = config(&[("GROUP_INSTANCE_ID", "star-agg-0")]);
+    let first = config
+      .reserve_instance("group-instance-test", "p3a-star-enc")
+      .unwrap();
+    let second = config
+      .reserve_instance("group-instance-test", "p3a-star-enc")
+      .unwrap();
+    let other_topic = config
+      .reserve_instance("group-instance-test", "p3a-star-enc-ios")
+      .unwrap();
+    assert_eq!(first.id, "star-agg-0-p3a-star-enc-0");
+    assert_eq!(second.id, "star-agg-0-p3a-star-enc-1");
+    assert_eq!(other_topic.id, "star-agg-0-p3a-star-enc-ios-0");
+
+    drop(first);
+    let third = config
+      .reserve_instance("group-instance-test", "p3a-star-enc")
+      .unwrap();
+    assert_eq!(third.id, "star-agg-0-p3a-star-enc-0");
+
+    let mut client_config = ClientConfig::new();
+    third.apply(&mut client_config);
+    assert_eq!(
+      client_config.get("group.instance.id"),
+      Some("star-agg-0-p3a-star-enc-0")
+    );
+
+    assert!(validate_instance_id("").is_err());
+    assert!(validate_instance_id("star agg").is_err());
+    assert!(validate_instance_id(&"a".repeat(250)).is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "Kafka GROUP_INSTANCE_ID must not be empty")]
+  fn empty_instance_id() {
+    config(&[("GROUP_INSTANCE_ID", "")]);
+  }
+}
@@ -0,0 +1,190 @@
+//! Rack awareness of Kafka consumers. If `KAFKA_CLIENT_RACK` is set to the rack, i.e. the
+//! availability zone of the process, consumers may fetch from a follower replica in the
+//! same rack instead of the partition leader, which avoids cross-zone traffic. Brokers
+//! must set `broker.rack` and `replica.selector.class` to
+//! `org.apache.kafka.common.replica.RackAwareReplicaSelector`, otherwise the setting has
+//! no effect. As with the security settings, the rack may be overridden for a component
+//! via `KAFKA_<COMPONENT>_CLIENT_RACK`.
+//!
+//! If a rack is set, consumers report statistics every `KAFKA_STATISTICS_INTERVAL_MS`, and
+//! the partitions fetched from the leader and from followers are exported via the
+//! `kafka_fetch_partitions` metric, so that the placement can be verified.
+
+use crate::prometheus::{kafka_metrics, FetchReplica};
+use crate::record_stream::KafkaComponent;
+use rdkafka::config::ClientConfig;
+use rdkafka::Statistics;
+use std::env;
+
+const CLIENT_RACK_SETTING: &str = "CLIENT_RACK";
+const STATISTICS_INTERVAL_MS_SETTING: &str = "STATISTICS_INTERVAL_MS";
+const DEFAULT_STATISTICS_INTERVAL_MS: u64 = 60000;
+/// Maximum length of a rack id accepted by librdkafka
+const MAX_RACK_LENGTH: usize = 255;
+/// Fetch state of partitions that are actively fetched
+const ACTIVE_FETCH_STATE: &str = "active";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRackConfig {
+  pub rack: Option<String>,
+  pub statistics_interval_ms: u64,
+}
+
+impl KafkaRackConfig {
+  /// Reads the settings of the component, falling back to the shared settings.
+  /// Panics if the rack or statistics interval are invalid.
+  pub fn from_env(component: KafkaComponent) -> Self {
+    Self::from_lookup(|setting| {
+      env::var(component.env_key(setting))
+        .or_else(|_| env::var(format!("KAFKA_{}", setting)))
+        .ok()
+    })
+  }
+
+  fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+    let rack = lookup(CLIENT_RACK_SETTING);
+    if let Some(Err(reason)) = rack.as_deref().map(validate_rack) {
+      panic!("Kafka {} {}", CLIENT_RACK_SETTING, reason);
+    }
+    let statistics_interval_ms = lookup(STATISTICS_INTERVAL_MS_SETTING)
+      .map(|v| {
+        v.parse::<u64>().unwrap_or_else(|_| {
+          panic!(
+            "Kafka {} must be a positive integer",
+            STATISTICS_INTERVAL_MS_SETTING
+          )
+        })
+      })
+      .unwrap_or(DEFAULT_STATISTICS_INTERVAL_MS);
+    Self {
+      rack,
+      statistics_interval_ms,
+    }
+  }
+
+  /// Sets the rack of the client, and enables statistics for the fetch metrics.
+  pub fn apply(&self, config: &mut ClientConfig) {
+    if let Some(rack) = self.rack.as_ref() {
+      config.set("client.rack", rack);
+      if self.statistics_interval_ms > 0 {
+        config.set(
+          "statistics.interval.ms",
+          self.statistics_interval_ms.to_string(),
+        );
+      }
+    }
+  }
+}
+
+fn validate_rack(rack: &str) -> Result<(), &'static str> {
+  if rack.is_empty() {
+    return Err("must not be empty");
+  }
+  if rack.len() > MAX_RACK_LENGTH {
+    return Err("must be at most 255 characters");
+  }
+  if !rack.chars().all(|c| c.is_ascii_graphic() && c != ',') {
+    return Err("must only contain printable ASCII characters without commas or spaces");
+  }
+  Ok(())
+}
+
+/// Returns the replica that an assigned partition is fetched from, or None if the
+/// partition is not actively fetched.
+fn fetch_replica(
+  partition: i32,
+  broker: i32,
+  leader: i32,
+  fetch_state: &str,
+) -> Option<FetchReplica> {
+  // Partition -1 is the internal partition of unassigned messages
+  if partition < 0 || broker < 0 || fetch_state != ACTIVE_FETCH_STATE {
+    return None;
+  }
+  Some(match broker == leader {
+    true => FetchReplica::Leader,
+    false => FetchReplica::Follower,
+  })
+}
+
+/// Updates the fetch metrics from the statistics of a client. Statistics of
+/// producers are ignored.
+pub fn record_fetch_statistics(statistics: &Statistics) {
+  let metrics = match kafka_metrics() {
+    Some(metrics) => metrics,
+    None => return,
+  };
+  if statistics.client_type != "consumer" {
+    return;
+  }
+  for (topic, topic_stats) in statistics.topics.iter() {
+    let (mut leader, mut follower) = (0, 0);
+    for partition in topic_stats.partitions.values() {
+      let replica = fetch_replica(
+        partition.partition,
+        partition.broker,
+        partition.leader,
+        &partition.fetch_state,
+      );
+      match replica {
+        Some(FetchReplica::Leader) => leader += 1,
+        Some(FetchReplica::Follower) => follower += 1,
+        None => (),
+      }
+    }
+    metrics.set_fetch_partitions(&statistics.name, topic, FetchReplica::Leader, leader);
+    metrics.set_fetch_partitions(&statistics.name, topic, FetchReplica::Follower, follower);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn config(vars: &[(&str, &str)]) -> KafkaRackConfig {
+    let vars: HashMap<_, _> = vars.iter().copied().collect();
+    KafkaRackConfig::from_lookup(|setting| vars.get(setting).map(|v| v.to_string()))
+  }
+
+  #[test]
+  fn rack_config() {
+    let mut client_config = ClientConfig::new();
+    config(&[]).apply(&mut client_config);
+    assert_eq!(client_config.get("client.rack"), None);
+    assert_eq!(client_config.get("statistics.interval.ms"), None);
+
+    config(&[("CLIENT_RACK", "use1-az1")]).apply(&mut client_config);
+    assert_eq!(client_config.get("client.rack"), Some("use1-az1"));
+    assert_eq!(client_config.get("statistics.interval.ms"), Some("60000"));
+
+    let rack_config = config(&[("CLIENT_RACK", "use1-az2"), ("STATISTICS_INTERVAL_MS", "0")]);
+    let mut client_config = ClientConfig::new();
+    rack_config.apply(&mut client_config);
+    assert_eq!(client_config.get("client.rack"), Some("use1-az2"));
+    assert_eq!(client_config.get("statistics.interval.ms"), None);
+
+    assert!(validate_rack("").is_err());
+    assert!(validate_rack("use1 az1").is_err());
+    assert!(validate_rack("use1-az1,use1-az2").is_err());
+    assert!(validate_rack(&"a".repeat(256)).is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "Kafka CLIENT_RACK must not be empty")]
+  fn empty_rack() {
+    config(&[("CLIENT_RACK", "")]);
+  }
+
+  #[test]
+  fn fetch_replicas() {
+    assert_eq!(fetch_replica(0, 1, 1, "active"), Some(FetchReplica::Leader));
+    assert_eq!(
+      fetch_replica(3, 2, 1, "active"),
+      Some(FetchReplica::Follower)
+    );
+    assert_eq!(fetch_replica(3, 2, 1, "offset-query"), None);
+    assert_eq!(fetch_replica(-1, 1, 1, "active"), None);
+    assert_eq!(fetch_replica(0, -1, 1, "active"), None);
+  }
+}
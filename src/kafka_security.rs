@@ -0,0 +1,162 @@
+//! Security settings of Kafka clients. Each setting may be overridden for a component
+//! via `KAFKA_<COMPONENT>_<SETTING>` (i.e. `KAFKA_SERVER_SASL_USERNAME`), and falls back
+//! to `KAFKA_<SETTING>` otherwise. This allows the server's producers and the consumers
+//! of the aggregator and lake sink to authenticate with separate identities, so that
+//! each identity can be limited to the access required by the component.
+
+use crate::record_stream::KafkaComponent;
+use rdkafka::config::ClientConfig;
+use std::env;
+
+const ENABLE_PLAINTEXT_SETTING: &str = "ENABLE_PLAINTEXT";
+const TLS_CA_CERT_PATH_SETTING: &str = "TLS_CA_CERT_PATH";
+const TLS_CERT_PATH_SETTING: &str = "TLS_CERT_PATH";
+const TLS_KEY_PATH_SETTING: &str = "TLS_KEY_PATH";
+const TLS_KEY_PASSWORD_SETTING: &str = "TLS_KEY_PASSWORD";
+const SASL_MECHANISM_SETTING: &str = "SASL_MECHANISM";
+const SASL_USERNAME_SETTING: &str = "SASL_USERNAME";
+const SASL_PASSWORD_SETTING: &str = "SASL_PASSWORD";
+const SASL_MECHANISMS: [&str; 3] = ["SCRAM-SHA-256", "SCRAM-SHA-512", "PLAIN"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaSaslConfig {
+  pub mechanism: String,
+  pub username: String,
+  pub password: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaSecurityConfig {
+  pub enable_plaintext: bool,
+  pub tls_ca_cert_path: Option<String>,
+  pub tls_cert_path: Option<String>,
+  pub tls_key_path: Option<String>,
+  pub tls_key_password: Option<String>,
+  pub sasl: Option<KafkaSaslConfig>,
+}
+
+impl KafkaSecurityConfig {
+  /// Reads the settings of the component, falling back to the shared settings.
+  /// Panics if the SASL settings are invalid.
+  pub fn from_env(component: KafkaComponent) -> Self {
+    Self::from_lookup(|setting| {
+      env::var(component.env_key(setting))
+        .or_else(|_| env::var(format!("KAFKA_{}", setting)))
+        .ok()
+    })
+  }
+
+  fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+    let sasl = lookup(SASL_MECHANISM_SETTING).map(|mechanism| {
+      let mechanism = mechanism.to_uppercase();
+      if !SASL_MECHANISMS.contains(&mechanism.as_str()) {
+        panic!(
+          "Kafka {} must be one of {}",
+          SASL_MECHANISM_SETTING,
+          SASL_MECHANISMS.join(", ")
+        );
+      }
+      let credential = |setting: &str| {
+        lookup(setting).unwrap_or_else(|| {
+          panic!(
+            "Kafka {} must be defined if {} is set",
+            setting, SASL_MECHANISM_SETTING
+          )
+        })
+      };
+      KafkaSaslConfig {
+        mechanism,
+        username: credential(SASL_USERNAME_SETTING),
+        password: credential(SASL_PASSWORD_SETTING),
+      }
+    });
+    Self {
+      enable_plaintext: lookup(ENABLE_PLAINTEXT_SETTING).as_deref() == Some("true"),
+      tls_ca_cert_path: lookup(TLS_CA_CERT_PATH_SETTING),
+      tls_cert_path: lookup(TLS_CERT_PATH_SETTING),
+      tls_key_path: lookup(TLS_KEY_PATH_SETTING),
+      tls_key_password: lookup(TLS_KEY_PASSWORD_SETTING),
+      sasl,
+    }
+  }
+
+  /// Returns the `security.protocol` of the client, or None for the librdkafka default.
+  fn security_protocol(&self) -> Option<&'static str> {
+    match (&self.sasl, self.enable_plaintext) {
+      (Some(_), true) => Some("sasl_plaintext"),
+      (Some(_), false) => Some("sasl_ssl"),
+      (None, _) if self.tls_cert_path.is_some() => Some("ssl"),
+      (None, true) => Some("plaintext"),
+      (None, false) => None,
+    }
+  }
+
+  pub fn apply(&self, config: &mut ClientConfig) {
+    if let Some(protocol) = self.security_protocol() {
+      config.set("security.protocol", protocol);
+    }
+    if let Some(cert_path) = self.tls_cert_path.as_ref() {
+      config.set("ssl.certificate.location", cert_path);
+    }
+    if let Some(cert_path) = self.tls_ca_cert_path.as_ref() {
+      config.set("ssl.ca.location", cert_path);
+    }
+    if let Some(key_path) = self.tls_key_path.as_ref() {
+      config.set("ssl.key.location", key_path);
+    }
+    if let Some(key_password) = self.tls_key_password.as_ref() {
+      config.set("ssl.key.password", key_password);
+    }
+    if let Some(sasl) = self.sasl.as_ref() {
+      config
+        .set("sasl.mechanism", &sasl.mechanism)
+        .set("sasl.username", &sasl.username)
+        .set("sasl.password", &sasl.password);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn config(vars: &[(&str, &str)]) -> KafkaSecurityConfig {
+    let vars: HashMap<_, _> = vars.iter().copied().collect();
+    KafkaSecurityConfig::from_lookup(|setting| vars.get(setting).map(|v| v.to_string()))
+  }
+
+  #[test]
+  fn security_protocols() {
+    assert_eq!(config(&[]).security_protocol(), None);
+    assert_eq!(
+      config(&[("ENABLE_PLAINTEXT", "true")]).security_protocol(),
+      Some("plaintext")
+    );
+    let mtls = config(&[("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")]);
+    assert_eq!(mtls.security_protocol(), Some("ssl"));
+    assert_eq!(mtls.tls_key_path.as_deref(), Some("key.pem"));
+
+    let sasl = config(&[
+      ("SASL_MECHANISM", "scram-sha-512"),
+      ("SASL_USERNAME", "ingest"),
+      ("SASL_PASSWORD", "secret"),
+      ("TLS_CERT_PATH", "cert.pem"),
+    ]);
+    assert_eq!(sasl.security_protocol(), Some("sasl_ssl"));
+    assert_eq!(
+      sasl.sasl,
+      Some(KafkaSaslConfig {
+        mechanism: "SCRAM-SHA-512".to_string(),
+        username: "ingest".to_string(),
+        password: "secret".to_string(),
+      })
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "Kafka SASL_PASSWORD must be defined")]
+  fn missing_sasl_password() {
+    config(&[("SASL_MECHANISM", "PLAIN"), ("SASL_USERNAME", "ingest")]);
+  }
+}
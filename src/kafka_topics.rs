@@ -0,0 +1,162 @@
+//! Validation of Kafka topics at startup. Before a Kafka record stream is created, the
+//! metadata of its topic is fetched to check that the topic exists, and that it has the
+//! partition count listed for it in `KAFKA_TOPIC_PARTITIONS`, i.e. `p3a-star-enc=16`.
+//! Otherwise, a missing topic would only surface as a timeout on the first produce.
+//!
+//! If `KAFKA_AUTO_CREATE` is enabled, missing topics are created with the listed partition
+//! count and `KAFKA_AUTO_CREATE_REPLICATION`. Broker defaults are used for topics without
+//! a listed partition count, and if the replication factor is not set. Topics are listed
+//! without the tenant prefix. If the metadata cannot be fetched, the check is skipped,
+//! since brokers may still be starting; connectivity is left to the startup checks.
+
+use crate::channel::get_data_channel_map_from_env;
+use crate::record_stream::{KafkaComponent, KafkaRecordStream, RecordStreamError};
+use crate::util::parse_env_var;
+use futures::executor::block_on;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::types::RDKafkaErrorCode;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const KAFKA_TOPIC_PARTITIONS_ENV_KEY: &str = "KAFKA_TOPIC_PARTITIONS";
+const KAFKA_AUTO_CREATE_ENV_KEY: &str = "KAFKA_AUTO_CREATE";
+const DEFAULT_KAFKA_AUTO_CREATE: &str = "false";
+const KAFKA_AUTO_CREATE_REPLICATION_ENV_KEY: &str = "KAFKA_AUTO_CREATE_REPLICATION";
+/// Uses the default replication factor of the broker
+const DEFAULT_KAFKA_AUTO_CREATE_REPLICATION: &str = "-1";
+/// Uses the default partition count of the broker
+const BROKER_DEFAULT_PARTITIONS: i32 = -1;
+const TOPIC_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Topics that were checked by this process
+static CHECKED_TOPICS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Returns the expected partition count of the topic, if listed.
+fn expected_partitions(config_topic: &str) -> Option<usize> {
+  get_data_channel_map_from_env(KAFKA_TOPIC_PARTITIONS_ENV_KEY, "")
+    .get(config_topic)
+    .map(|v| {
+      v.parse().unwrap_or_else(|_| {
+        panic!(
+          "{} entry for topic {} must be a partition count",
+          KAFKA_TOPIC_PARTITIONS_ENV_KEY, config_topic
+        )
+      })
+    })
+}
+
+/// Compares the partition count of an existing topic with the expected count.
+fn check_partitions(
+  topic: &str,
+  expected: Option<usize>,
+  actual: usize,
+) -> Result<(), RecordStreamError> {
+  match expected {
+    Some(expected) if expected != actual => Err(RecordStreamError::TopicPartitions {
+      topic: topic.to_string(),
+      expected,
+      actual,
+    }),
+    _ => Ok(()),
+  }
+}
+
+fn create_topic(
+  component: KafkaComponent,
+  topic: &str,
+  partitions: Option<usize>,
+) -> Result<(), RecordStreamError> {
+  let replication = parse_env_var::<i32>(
+    KAFKA_AUTO_CREATE_REPLICATION_ENV_KEY,
+    DEFAULT_KAFKA_AUTO_CREATE_REPLICATION,
+  );
+  let partitions = partitions
+    .map(|v| v as i32)
+    .unwrap_or(BROKER_DEFAULT_PARTITIONS);
+  let admin: AdminClient<DefaultClientContext> = KafkaRecordStream::new_client_config(component)
+    .create()
+    .map_err(|e| RecordStreamError::topic(topic, e))?;
+  let new_topic = NewTopic::new(topic, partitions, TopicReplication::Fixed(replication));
+  let options = AdminOptions::new().operation_timeout(Some(TOPIC_CHECK_TIMEOUT));
+  // Admin requests are driven by a thread of the client, so the runtime is not needed
+  let result = block_on(admin.create_topics(&[new_topic], &options))
+    .map_err(|e| RecordStreamError::topic(topic, e))?
+    .pop()
+    .expect("create topics should return a result for the topic");
+  match result {
+    // The topic may have been created by another process in the meantime
+    Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {
+      info!(
+        "Created topic {} with {} partitions and replication factor {}",
+        topic, partitions, replication
+      );
+      Ok(())
+    }
+    Err((_, code)) => Err(RecordStreamError::topic(topic, KafkaError::AdminOp(code))),
+  }
+}
+
+/// Checks that the topic exists with the expected partition count, and creates it if
+/// it is missing and auto creation is enabled. `config_topic` is the configured name of
+/// the topic, without the tenant prefix. Each topic is only checked once.
+pub fn ensure_topic(
+  component: KafkaComponent,
+  config_topic: &str,
+  topic: &str,
+) -> Result<(), RecordStreamError> {
+  if CHECKED_TOPICS.lock().unwrap().contains(topic) {
+    return Ok(());
+  }
+  let consumer: BaseConsumer = KafkaRecordStream::new_client_config(component)
+    .create()
+    .map_err(|e| RecordStreamError::topic(topic, e))?;
+  let metadata = match consumer.fetch_metadata(Some(topic), TOPIC_CHECK_TIMEOUT) {
+    Ok(metadata) => metadata,
+    Err(e) => {
+      warn!(
+        "Failed to fetch metadata of topic {}, skipping topic check: {}",
+        topic, e
+      );
+      return Ok(());
+    }
+  };
+  let partition_count = metadata
+    .topics()
+    .iter()
+    .find(|v| v.name() == topic && v.error().is_none())
+    .map(|v| v.partitions().len())
+    .unwrap_or_default();
+  let expected = expected_partitions(config_topic);
+  if partition_count > 0 {
+    check_partitions(topic, expected, partition_count)?;
+  } else if parse_env_var::<bool>(KAFKA_AUTO_CREATE_ENV_KEY, DEFAULT_KAFKA_AUTO_CREATE) {
+    create_topic(component, topic, expected)?;
+  } else {
+    return Err(RecordStreamError::TopicMissing {
+      topic: topic.to_string(),
+    });
+  }
+  CHECKED_TOPICS.lock().unwrap().insert(topic.to_string());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn partition_check() {
+    assert!(check_partitions("p3a-star-enc", None, 3).is_ok());
+    assert!(check_partitions("p3a-star-enc", Some(3), 3).is_ok());
+    let e = check_partitions("p3a-star-enc", Some(16), 3).unwrap_err();
+    assert_eq!(e.category(), "kafka_topic");
+    assert_eq!(
+      e.to_string(),
+      "Record stream error: topic p3a-star-enc has 3 partitions, expected 16"
+    );
+  }
+}
@@ -0,0 +1,810 @@
+//! Record stream backed by AWS Kinesis Data Streams, selected via `RECORD_STREAM_BACKEND=kinesis`.
+//! The topic of the stream config is used as the Kinesis stream name, and shards are mapped
+//! to partitions. Kinesis records do not have headers, so the record data and headers are
+//! wrapped in a bincode envelope.
+//!
+//! Kinesis sequence numbers do not fit in record offsets, so consumed records are assigned
+//! local offsets, which are only valid for the lifetime of the stream. Consumption is
+//! checkpointed by storing the sequence number of the last committed record of each shard
+//! in the data lake, equivalent to a Kafka offset commit. Consumers in a group do not
+//! coordinate shard assignment, so only one consumer per group should run at a time.
+
+use crate::lake::{DataLake, DataLakeError};
+use crate::record_stream::{
+  acquire_send_permit, consumer_group_id, datetime_from_unix_millis, send_to_producer_queue,
+  ConsumedRecord, KafkaRecordStreamConfig, ProducerQueue, ProducerQueueItem, RecordHeaderValues,
+  RecordStream, RecordStreamError, RecordStreamOptions,
+};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use derive_more::{Display, Error, From};
+use futures::future::try_join_all;
+use rand::{random, seq::SliceRandom, thread_rng};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_kinesis::{
+  DescribeStreamSummaryError, DescribeStreamSummaryInput, GetRecordsError, GetRecordsInput,
+  GetShardIteratorError, GetShardIteratorInput, Kinesis, KinesisClient, ListShardsError,
+  ListShardsInput, PutRecordError, PutRecordInput, Record, Shard,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{Mutex as AsyncMutex, RwLock, Semaphore};
+use tokio::time::sleep;
+
+const KINESIS_ENDPOINT_ENV_KEY: &str = "KINESIS_ENDPOINT";
+const KINESIS_GET_RECORDS_LIMIT_ENV_KEY: &str = "KINESIS_GET_RECORDS_LIMIT";
+const DEFAULT_KINESIS_GET_RECORDS_LIMIT: &str = "1000";
+const KINESIS_POLL_INTERVAL_MS_ENV_KEY: &str = "KINESIS_POLL_INTERVAL_MS";
+const DEFAULT_KINESIS_POLL_INTERVAL_MS: &str = "1000";
+const KINESIS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY: &str = "KINESIS_PRODUCE_QUEUE_TASK_COUNT";
+const DEFAULT_KINESIS_PRODUCE_QUEUE_TASK_COUNT: &str = "16";
+const WEB_IDENTITY_ENV_VAR: &str = "AWS_WEB_IDENTITY_TOKEN_FILE";
+
+/// Checkpoint of a closed shard whose records have all been committed
+const SHARD_END_CHECKPOINT: &str = "SHARD_END";
+
+#[derive(Debug, Display, Error, From)]
+pub enum KinesisError {
+  #[display(fmt = "Kinesis describe stream error: {}", _0)]
+  #[from(ignore)]
+  DescribeStream(Box<RusotoError<DescribeStreamSummaryError>>),
+  #[display(fmt = "Kinesis put record error: {}", _0)]
+  #[from(ignore)]
+  PutRecord(Box<RusotoError<PutRecordError>>),
+  #[display(fmt = "Kinesis list shards error: {}", _0)]
+  #[from(ignore)]
+  ListShards(Box<RusotoError<ListShardsError>>),
+  #[display(fmt = "Kinesis get shard iterator error: {}", _0)]
+  #[from(ignore)]
+  GetShardIterator(Box<RusotoError<GetShardIteratorError>>),
+  #[display(fmt = "Kinesis get records error: {}", _0)]
+  #[from(ignore)]
+  GetRecords(Box<RusotoError<GetRecordsError>>),
+  #[display(fmt = "Kinesis record envelope error: {}", _0)]
+  Envelope(bincode::Error),
+  #[display(fmt = "Kinesis checkpoint error: {}", _0)]
+  Checkpoint(DataLakeError),
+}
+
+/// Record data and headers, stored as the data of a Kinesis record
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RecordEnvelope {
+  data: Vec<u8>,
+  headers: Vec<(String, Vec<u8>)>,
+}
+
+fn client_from_env() -> KinesisClient {
+  let region = match env::var(KINESIS_ENDPOINT_ENV_KEY) {
+    Ok(endpoint) => Region::Custom {
+      name: Region::default().name().to_string(),
+      endpoint,
+    },
+    Err(_) => Default::default(),
+  };
+  if env::var(WEB_IDENTITY_ENV_VAR).is_ok() {
+    let provider = rusoto_credential::AutoRefreshingProvider::new(
+      rusoto_sts::WebIdentityProvider::from_k8s_env(),
+    )
+    .unwrap();
+    KinesisClient::new_with(HttpClient::new().unwrap(), provider, region)
+  } else {
+    KinesisClient::new(region)
+  }
+}
+
+async fn put_record(
+  client: &KinesisClient,
+  stream_name: &str,
+  envelope: &RecordEnvelope,
+  partition_key: Option<String>,
+) -> Result<(), KinesisError> {
+  client
+    .put_record(PutRecordInput {
+      stream_name: stream_name.to_string(),
+      data: bincode::serialize(envelope)?.into(),
+      partition_key: partition_key.unwrap_or_else(|| format!("{:016x}", random::<u64>())),
+      ..Default::default()
+    })
+    .await
+    .map_err(|e| KinesisError::PutRecord(Box::new(e)))?;
+  Ok(())
+}
+
+async fn list_shards(
+  client: &KinesisClient,
+  stream_name: &str,
+) -> Result<Vec<Shard>, KinesisError> {
+  let mut shards = Vec::new();
+  let mut next_token = None;
+  loop {
+    // The stream name must not be set if a token is provided
+    let output = client
+      .list_shards(ListShardsInput {
+        stream_name: next_token.is_none().then(|| stream_name.to_string()),
+        next_token,
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| KinesisError::ListShards(Box::new(e)))?;
+    shards.extend(output.shards.unwrap_or_default());
+    next_token = output.next_token;
+    if next_token.is_none() {
+      return Ok(shards);
+    }
+  }
+}
+
+/// Returns an iterator after the sequence number, or at the oldest record of the shard.
+/// Returns None if the shard is closed and has no records after the sequence number.
+async fn get_shard_iterator(
+  client: &KinesisClient,
+  stream_name: &str,
+  shard_id: &str,
+  after_sequence_number: Option<&str>,
+) -> Result<Option<String>, KinesisError> {
+  let output = client
+    .get_shard_iterator(GetShardIteratorInput {
+      stream_name: stream_name.to_string(),
+      shard_id: shard_id.to_string(),
+      shard_iterator_type: match after_sequence_number {
+        Some(_) => "AFTER_SEQUENCE_NUMBER",
+        None => "TRIM_HORIZON",
+      }
+      .to_string(),
+      starting_sequence_number: after_sequence_number.map(|v| v.to_string()),
+      ..Default::default()
+    })
+    .await
+    .map_err(|e| KinesisError::GetShardIterator(Box::new(e)))?;
+  Ok(output.shard_iterator)
+}
+
+/// Returns the partition of the shard, parsed from the numeric suffix of the shard id.
+fn shard_partition(shard_id: &str) -> i32 {
+  shard_id
+    .rsplit('-')
+    .next()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or_default()
+}
+
+/// Returns the listed shards that are not tracked yet, and whose parents have been fully
+/// consumed or are no longer retained, so that records are consumed in order after resharding.
+fn readable_new_shards(listed: &[Shard], closed_shard_ids: &HashSet<String>) -> Vec<Shard> {
+  let listed_ids: HashSet<_> = listed.iter().map(|v| v.shard_id.as_str()).collect();
+  let mut tracked_ids = closed_shard_ids.clone();
+  let mut readable = Vec::new();
+  loop {
+    let mut added = false;
+    for shard in listed {
+      if tracked_ids.contains(&shard.shard_id)
+        || readable
+          .iter()
+          .any(|v: &Shard| v.shard_id == shard.shard_id)
+      {
+        continue;
+      }
+      let parents_consumed = [&shard.parent_shard_id, &shard.adjacent_parent_shard_id]
+        .into_iter()
+        .flatten()
+        .all(|parent| !listed_ids.contains(parent.as_str()) || closed_shard_ids.contains(parent));
+      if parents_consumed {
+        readable.push(shard.clone());
+        tracked_ids.insert(shard.shard_id.clone());
+        added = true;
+      }
+    }
+    if !added {
+      return readable;
+    }
+  }
+}
+
+struct ShardState {
+  shard_id: String,
+  partition: i32,
+  /// None once the shard is closed and all of its records have been fetched
+  iterator: Option<String>,
+  /// Sequence number of the last fetched record, used to renew expired iterators
+  last_fetched_sequence: Option<String>,
+  /// Local offset of the next fetched record
+  next_offset: i64,
+  /// Offset of the last record returned by `consume`
+  consumed_offset: Option<i64>,
+  /// Offset of the next record to consume after the last commit
+  committed_offset: Option<i64>,
+  /// Sequence numbers of fetched records that have not been committed, by local offset
+  uncommitted: BTreeMap<i64, String>,
+}
+
+#[derive(Default)]
+struct ConsumerState {
+  initialized: bool,
+  shards: Vec<ShardState>,
+  buffer: VecDeque<ConsumedRecord>,
+  next_shard: usize,
+  empty_polls: usize,
+}
+
+pub struct KinesisRecordStream {
+  client: KinesisClient,
+  stream_name: String,
+  group_id: String,
+  tenant: Option<String>,
+  enable_producer: bool,
+  /// Stores shard checkpoints; only set if the consumer is enabled
+  checkpoints: Option<DataLake>,
+  consumer_state: Mutex<ConsumerState>,
+  /// Prevents concurrent fetches from the same shard iterator
+  consume_lock: AsyncMutex<()>,
+  get_records_limit: usize,
+  poll_interval: Duration,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  send_limit: Option<Arc<Semaphore>>,
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+impl KinesisRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let tenant = stream_config.tenant.as_deref();
+    Self {
+      client: client_from_env(),
+      stream_name: tenant_scoped_name(&stream_config.topic, tenant),
+      group_id: consumer_group_id(&stream_config),
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      checkpoints: stream_config
+        .enable_consumer
+        .then(|| DataLake::new(stream_config.tenant.clone(), None)),
+      consumer_state: Mutex::new(ConsumerState::default()),
+      consume_lock: AsyncMutex::new(()),
+      get_records_limit: parse_env_var(
+        KINESIS_GET_RECORDS_LIMIT_ENV_KEY,
+        DEFAULT_KINESIS_GET_RECORDS_LIMIT,
+      ),
+      poll_interval: Duration::from_millis(parse_env_var(
+        KINESIS_POLL_INTERVAL_MS_ENV_KEY,
+        DEFAULT_KINESIS_POLL_INTERVAL_MS,
+      )),
+      producer_queues: RwLock::new(Vec::new()),
+      send_limit: options.send_limit,
+      record_headers: Arc::new(options.record_headers),
+    }
+  }
+
+  fn checkpoints(&self) -> &DataLake {
+    self
+      .checkpoints
+      .as_ref()
+      .expect("Kinesis consumer not enabled")
+  }
+
+  fn envelope(
+    &self,
+    record: &[u8],
+    mut header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> RecordEnvelope {
+    header_values.tenant = self.tenant.clone();
+    let headers = header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .collect();
+    RecordEnvelope {
+      data: record.to_vec(),
+      headers,
+    }
+  }
+
+  /// Starts consuming the shards that are not tracked yet, from their checkpoints.
+  async fn refresh_shards(&self) -> Result<(), KinesisError> {
+    let closed_shard_ids: HashSet<String> = {
+      let state = self.consumer_state.lock().unwrap();
+      state
+        .shards
+        .iter()
+        .filter(|v| v.iterator.is_none())
+        .map(|v| v.shard_id.clone())
+        .collect()
+    };
+    let tracked_shard_ids: HashSet<String> = {
+      let state = self.consumer_state.lock().unwrap();
+      state.shards.iter().map(|v| v.shard_id.clone()).collect()
+    };
+    let listed = list_shards(&self.client, &self.stream_name).await?;
+    let new_shards: Vec<_> = readable_new_shards(&listed, &closed_shard_ids)
+      .into_iter()
+      .filter(|v| !tracked_shard_ids.contains(&v.shard_id))
+      .collect();
+
+    let mut new_states = Vec::new();
+    for shard in new_shards {
+      let checkpoint = self
+        .checkpoints()
+        .load_kinesis_checkpoint(&self.group_id, &self.stream_name, &shard.shard_id)
+        .await?;
+      let iterator = match checkpoint.as_deref() {
+        Some(SHARD_END_CHECKPOINT) => None,
+        sequence_number => {
+          get_shard_iterator(
+            &self.client,
+            &self.stream_name,
+            &shard.shard_id,
+            sequence_number,
+          )
+          .await?
+        }
+      };
+      info!(
+        "Kinesis: consuming shard {} of stream {} from {}",
+        shard.shard_id,
+        self.stream_name,
+        checkpoint.as_deref().unwrap_or("oldest record")
+      );
+      new_states.push(ShardState {
+        partition: shard_partition(&shard.shard_id),
+        shard_id: shard.shard_id,
+        iterator,
+        last_fetched_sequence: checkpoint.filter(|v| v != SHARD_END_CHECKPOINT),
+        next_offset: 0,
+        consumed_offset: None,
+        committed_offset: None,
+        uncommitted: BTreeMap::new(),
+      });
+    }
+
+    let has_new_closed_shards = new_states.iter().any(|v| v.iterator.is_none());
+    {
+      let mut state = self.consumer_state.lock().unwrap();
+      state.shards.extend(new_states);
+      state.initialized = true;
+    }
+    if has_new_closed_shards {
+      // Children of shards that were closed in a previous run may now be readable
+      return Box::pin(self.refresh_shards()).await;
+    }
+    Ok(())
+  }
+
+  /// Fetches records from the next open shard into the buffer. Returns false if all
+  /// shards have been polled without receiving any records since the last call that
+  /// returned false, or if all shards are closed.
+  async fn fetch_records(&self) -> Result<bool, KinesisError> {
+    let next = {
+      let mut state = self.consumer_state.lock().unwrap();
+      let open_shards: Vec<_> = (0..state.shards.len())
+        .filter(|i| state.shards[*i].iterator.is_some())
+        .collect();
+      if open_shards.is_empty() || state.empty_polls >= open_shards.len() {
+        state.empty_polls = 0;
+        None
+      } else {
+        let index = open_shards[state.next_shard % open_shards.len()];
+        state.next_shard = state.next_shard.wrapping_add(1);
+        let shard = &state.shards[index];
+        Some((
+          index,
+          shard.shard_id.clone(),
+          shard.iterator.clone().unwrap(),
+          shard.last_fetched_sequence.clone(),
+        ))
+      }
+    };
+    let Some((index, shard_id, iterator, last_fetched_sequence)) = next else {
+      return Ok(false);
+    };
+
+    let output = match self
+      .client
+      .get_records(GetRecordsInput {
+        shard_iterator: iterator,
+        limit: Some(self.get_records_limit as i64),
+      })
+      .await
+    {
+      Ok(output) => output,
+      Err(RusotoError::Service(GetRecordsError::ExpiredIterator(_))) => {
+        debug!("Kinesis: renewing expired iterator of shard {}", shard_id);
+        let iterator = get_shard_iterator(
+          &self.client,
+          &self.stream_name,
+          &shard_id,
+          last_fetched_sequence.as_deref(),
+        )
+        .await?;
+        self.consumer_state.lock().unwrap().shards[index].iterator = iterator;
+        return Ok(true);
+      }
+      Err(RusotoError::Service(GetRecordsError::ProvisionedThroughputExceeded(_))) => {
+        debug!("Kinesis: read throughput exceeded for shard {}", shard_id);
+        return Ok(false);
+      }
+      Err(e) => return Err(KinesisError::GetRecords(Box::new(e))),
+    };
+
+    let mut records = Vec::with_capacity(output.records.len());
+    for record in output.records {
+      let envelope: RecordEnvelope = bincode::deserialize(&record.data)?;
+      records.push((record, envelope));
+    }
+
+    let closed = output.next_shard_iterator.is_none();
+    self.buffer_records(index, output.next_shard_iterator, records);
+    if closed {
+      info!("Kinesis: shard {} is closed", shard_id);
+      self.refresh_shards().await?;
+    }
+    Ok(true)
+  }
+
+  fn buffer_records(
+    &self,
+    index: usize,
+    next_iterator: Option<String>,
+    records: Vec<(Record, RecordEnvelope)>,
+  ) {
+    let mut guard = self.consumer_state.lock().unwrap();
+    let state = &mut *guard;
+    let shard = &mut state.shards[index];
+    shard.iterator = next_iterator;
+    if records.is_empty() {
+      state.empty_polls += 1;
+    } else {
+      state.empty_polls = 0;
+    }
+    for (record, envelope) in records {
+      let offset = shard.next_offset;
+      shard.next_offset += 1;
+      shard
+        .uncommitted
+        .insert(offset, record.sequence_number.clone());
+      shard.last_fetched_sequence = Some(record.sequence_number);
+      let header_values = RecordHeaderValues::parse(
+        envelope
+          .headers
+          .iter()
+          .map(|(k, v)| (k.as_str(), v.as_slice())),
+      );
+      if !header_values.matches_tenant(self.tenant.as_deref()) {
+        continue;
+      }
+      let arrived_at = record
+        .approximate_arrival_timestamp
+        .map(|v| (v * 1000.0) as i64);
+      let submitted_at = header_values.submitted_at.or(arrived_at);
+      state.buffer.push_back(ConsumedRecord {
+        data: envelope.data,
+        request_threshold: header_values.request_threshold,
+        channel_name: header_values.channel_name,
+        epoch: header_values.epoch,
+        client_version: header_values.client_version,
+        topic: None,
+        partition: Some(shard.partition),
+        offset: Some(offset),
+        submitted_at: submitted_at.and_then(datetime_from_unix_millis),
+        timestamp: arrived_at.and_then(datetime_from_unix_millis),
+      });
+    }
+  }
+
+  /// Stores the checkpoints of the committed shards. The shard positions are given
+  /// as the partition, and the offset of the last record to commit.
+  async fn checkpoint(&self, positions: &[(i32, i64)]) -> Result<(), KinesisError> {
+    let mut checkpoints = Vec::new();
+    {
+      let mut state = self.consumer_state.lock().unwrap();
+      for (partition, offset) in positions {
+        let Some(shard) = state.shards.iter_mut().find(|v| v.partition == *partition) else {
+          continue;
+        };
+        let Some(sequence_number) = shard.uncommitted.get(offset).cloned() else {
+          continue;
+        };
+        shard.uncommitted = shard.uncommitted.split_off(&(offset + 1));
+        shard.committed_offset = Some(offset + 1);
+        let checkpoint = match shard.iterator.is_none() && shard.uncommitted.is_empty() {
+          true => SHARD_END_CHECKPOINT.to_string(),
+          false => sequence_number,
+        };
+        checkpoints.push((shard.shard_id.clone(), checkpoint));
+      }
+    }
+    try_join_all(checkpoints.iter().map(|(shard_id, checkpoint)| {
+      self.checkpoints().store_kinesis_checkpoint(
+        &self.group_id,
+        &self.stream_name,
+        shard_id,
+        checkpoint,
+      )
+    }))
+    .await?;
+    trace!("Kinesis: stored checkpoints {:?}", checkpoints);
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl RecordStream for KinesisRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self
+      .client
+      .describe_stream_summary(DescribeStreamSummaryInput {
+        stream_name: self.stream_name.clone(),
+      })
+      .await
+      .map_err(|e| KinesisError::DescribeStream(Box::new(e)))?;
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(state.initialized && !state.shards.is_empty())
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .shards
+        .iter()
+        .filter(|v| v.iterator.is_some() || !v.uncommitted.is_empty())
+        .map(|v| v.partition)
+        .collect(),
+    )
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .shards
+        .iter()
+        .filter_map(|v| Some((v.partition, v.committed_offset?)))
+        .collect(),
+    )
+  }
+
+  /// Only includes the records that have been fetched from the shards.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .shards
+        .iter()
+        .map(|v| (v.partition, v.next_offset))
+        .collect(),
+    )
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "Kinesis producer not enabled");
+    let envelope = self.envelope(
+      record,
+      RecordHeaderValues {
+        request_threshold,
+        channel_name: channel_name.map(|v| v.to_string()),
+        epoch,
+        ..Default::default()
+      },
+      extra_headers,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    put_record(
+      &self.client,
+      &self.stream_name,
+      &envelope,
+      key.map(|v| v.to_string()),
+    )
+    .await?;
+    Ok(())
+  }
+
+  async fn init_producer_queues(&self) {
+    assert!(self.enable_producer, "Kinesis producer not enabled");
+    let task_count = parse_env_var::<usize>(
+      KINESIS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY,
+      DEFAULT_KINESIS_PRODUCE_QUEUE_TASK_COUNT,
+    );
+    let mut producer_queues = self.producer_queues.write().await;
+    for _ in 0..task_count {
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
+      let client = self.client.clone();
+      let stream_name = self.stream_name.clone();
+      let send_limit = self.send_limit.clone();
+      let header_values = RecordHeaderValues {
+        tenant: self.tenant.clone(),
+        ..Default::default()
+      };
+      let headers: Vec<_> = header_values
+        .headers()
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .chain(self.record_headers.iter().cloned())
+        .collect();
+      let handle = tokio::spawn(async move {
+        while let Some((data, key)) = rx.recv().await {
+          let envelope = RecordEnvelope {
+            data,
+            headers: headers.clone(),
+          };
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          put_record(&client, &stream_name, &envelope, key).await?;
+        }
+        Ok(())
+      });
+      producer_queues.push((handle, tx));
+    }
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    let producer_queues = self.producer_queues.read().await;
+    let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
+    send_to_producer_queue(tx, &self.stream_name, (record, key))
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    let mut producer_queues = self.producer_queues.write().await;
+    try_join_all(
+      producer_queues
+        .drain(..)
+        .map(|(handle, _)| handle)
+        .collect::<Vec<_>>(),
+    )
+    .await?
+    .into_iter()
+    .collect::<Result<Vec<()>, RecordStreamError>>()?;
+    Ok(())
+  }
+
+  /// Cancel safe, since fetched records are buffered before the next await.
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    let _consume_guard = self.consume_lock.lock().await;
+    let initialized = self.consumer_state.lock().unwrap().initialized;
+    if !initialized {
+      self.refresh_shards().await?;
+    }
+    loop {
+      {
+        let mut state = self.consumer_state.lock().unwrap();
+        if let Some(record) = state.buffer.pop_front() {
+          if let Some(shard) = state
+            .shards
+            .iter_mut()
+            .find(|v| Some(v.partition) == record.partition)
+          {
+            shard.consumed_offset = record.offset;
+          }
+          return Ok(record);
+        }
+      }
+      if !self.fetch_records().await? {
+        sleep(self.poll_interval).await;
+      }
+    }
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let positions: Vec<_> = {
+      let state = self.consumer_state.lock().unwrap();
+      state
+        .shards
+        .iter()
+        .filter_map(|v| Some((v.partition, v.consumed_offset?)))
+        .collect()
+    };
+    self.checkpoint(&positions).await?;
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    self.checkpoint(offsets).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn shard(shard_id: &str, parent_shard_id: Option<&str>) -> Shard {
+    Shard {
+      shard_id: shard_id.to_string(),
+      parent_shard_id: parent_shard_id.map(|v| v.to_string()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn partition_from_shard_id() {
+    assert_eq!(shard_partition("shardId-000000000012"), 12);
+    assert_eq!(shard_partition("unexpected"), 0);
+  }
+
+  #[test]
+  fn children_after_parents() {
+    let listed = vec![
+      shard("shardId-000000000000", None),
+      shard("shardId-000000000001", Some("shardId-000000000000")),
+      shard("shardId-000000000002", Some("shardId-000000000099")),
+    ];
+    let ids = |shards: Vec<Shard>| shards.into_iter().map(|v| v.shard_id).collect::<Vec<_>>();
+    // The parent of shard 2 is no longer retained
+    assert_eq!(
+      ids(readable_new_shards(&listed, &HashSet::new())),
+      vec!["shardId-000000000000", "shardId-000000000002"]
+    );
+    assert_eq!(
+      ids(readable_new_shards(
+        &listed,
+        &HashSet::from(["shardId-000000000000".to_string()])
+      )),
+      vec!["shardId-000000000001", "shardId-000000000002"]
+    );
+  }
+
+  #[tokio::test]
+  async fn envelope_headers() {
+    let stream = KinesisRecordStream::new(
+      KafkaRecordStreamConfig {
+        component: crate::record_stream::KafkaComponent::Server,
+        enable_producer: true,
+        enable_consumer: false,
+        topic: "p3a-star-enc".to_string(),
+        use_output_group_id: false,
+        group_id_suffix: None,
+        tenant: Some("acme".to_string()),
+      },
+      RecordStreamOptions::default(),
+    );
+    assert_eq!(stream.stream_name, "p3a-star-enc-acme");
+    let envelope = stream.envelope(
+      b"message",
+      RecordHeaderValues {
+        request_threshold: Some(50),
+        channel_name: Some("typical".to_string()),
+        epoch: Some(3),
+        ..Default::default()
+      },
+      &[("redriven-at", vec![1])],
+    );
+    let envelope: RecordEnvelope =
+      bincode::deserialize(&bincode::serialize(&envelope).unwrap()).unwrap();
+    assert_eq!(envelope.data, b"message");
+    assert_eq!(
+      RecordHeaderValues::parse(
+        envelope
+          .headers
+          .iter()
+          .map(|(k, v)| (k.as_str(), v.as_slice()))
+      ),
+      RecordHeaderValues {
+        request_threshold: Some(50),
+        channel_name: Some("typical".to_string()),
+        tenant: Some("acme".to_string()),
+        epoch: Some(3),
+        ..Default::default()
+      }
+    );
+    assert_eq!(envelope.headers.last().unwrap().0, "redriven-at");
+  }
+}
@@ -1,28 +1,403 @@
-use derive_more::{Display, Error, From};
+use crate::prometheus::{LakeMetrics, S3Operation};
+use crate::record_stream::{datetime_from_unix_millis, datetime_to_unix_millis, ConsumedRecord};
+use crate::util::parse_env_var;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use futures::TryStreamExt;
 use rand::random;
 use rusoto_core::{ByteStream, Region, RusotoError};
-use rusoto_s3::{PutObjectError, PutObjectRequest, S3Client, S3};
+use rusoto_s3::{
+  AbortMultipartUploadRequest, CompleteMultipartUploadError, CompleteMultipartUploadRequest,
+  CompletedMultipartUpload, CompletedPart, CreateMultipartUploadError,
+  CreateMultipartUploadRequest, Delete, DeleteObjectsError, DeleteObjectsRequest, GetObjectError,
+  GetObjectRequest, HeadBucketError, HeadBucketRequest, ListObjectsV2Error, ListObjectsV2Request,
+  ObjectIdentifier, PutObjectError, PutObjectRequest, S3Client, UploadPartError, UploadPartRequest,
+  S3,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use time::OffsetDateTime;
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime};
+use tokio_util::sync::CancellationToken;
 
 const S3_ENDPOINT_ENV_VAR: &str = "S3_ENDPOINT";
 const OUTPUT_S3_BUCKET_ENV_KEY: &str = "S3_OUTPUT_BUCKET";
 const WEB_IDENTITY_ENV_VAR: &str = "AWS_WEB_IDENTITY_TOKEN_FILE";
 const DEFAULT_OUTPUT_BUCKET_NAME: &str = "p3a-star-recovered";
+const PRIVACY_REPORT_PREFIX: &str = "privacy-reports";
+const RUN_SUMMARY_PREFIX: &str = "run-summaries";
+const MESSAGE_ARCHIVE_PREFIX: &str = "messages";
+const EPOCH_EXPORT_PREFIX: &str = "epoch-exports";
+const KEY_EXPORT_PREFIX: &str = "key-exports";
+const KINESIS_CHECKPOINT_PREFIX: &str = "kinesis-checkpoints";
+const WAREHOUSE_LOAD_PREFIX: &str = "warehouse-loads";
+pub const RETENTION_AUDIT_PREFIX: &str = "retention-audits";
+const S3_PUT_REQUEST_COST_ENV_KEY: &str = "S3_PUT_REQUEST_COST";
+const DEFAULT_S3_PUT_REQUEST_COST: &str = "0.000005";
+const S3_GET_REQUEST_COST_ENV_KEY: &str = "S3_GET_REQUEST_COST";
+const DEFAULT_S3_GET_REQUEST_COST: &str = "0.0000004";
+const S3_STORAGE_COST_PER_GB_ENV_KEY: &str = "S3_STORAGE_COST_PER_GB";
+const DEFAULT_S3_STORAGE_COST_PER_GB: &str = "0.023";
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+const COMPACTION_TARGET_SIZE_MB_ENV_KEY: &str = "LAKE_COMPACTION_TARGET_SIZE_MB";
+const DEFAULT_COMPACTION_TARGET_SIZE_MB: &str = "128";
+const MANIFEST_FILE_NAME: &str = "_manifest.json";
+const COMPACTED_OBJECT_PREFIX: &str = "compacted-";
+const MAX_DELETE_OBJECTS_PER_REQUEST: usize = 1000;
+const MULTIPART_PART_SIZE_MB_ENV_KEY: &str = "LAKE_MULTIPART_PART_SIZE_MB";
+const DEFAULT_MULTIPART_PART_SIZE_MB: &str = "16";
+// Minimum size of all parts except the last, as required by S3
+const MIN_MULTIPART_PART_SIZE_MB: usize = 5;
 
-#[derive(From, Error, Display, Debug)]
+/// Errors of data lake operations. Errors of object requests carry the full key
+/// of the object, including the tenant prefix.
+#[derive(Error, Debug)]
 pub enum DataLakeError {
-  #[display(fmt = "Upload error: {}", _0)]
-  Upload(RusotoError<PutObjectError>),
+  #[error("Upload error for {key}: {source}")]
+  Upload {
+    key: String,
+    source: Box<RusotoError<PutObjectError>>,
+  },
+  #[error("Multipart upload creation error for {key}: {source}")]
+  CreateMultipart {
+    key: String,
+    source: Box<RusotoError<CreateMultipartUploadError>>,
+  },
+  #[error("Part {part_number} upload error for {key}: {source}")]
+  UploadPart {
+    key: String,
+    part_number: i64,
+    source: Box<RusotoError<UploadPartError>>,
+  },
+  #[error("Multipart upload completion error for {key}: {source}")]
+  CompleteMultipart {
+    key: String,
+    source: Box<RusotoError<CompleteMultipartUploadError>>,
+  },
+  /// The store was cancelled before the object was written
+  #[error("Store cancelled")]
+  Cancelled,
+  #[error("Download error for {key}: {source}")]
+  Download {
+    key: String,
+    source: Box<RusotoError<GetObjectError>>,
+  },
+  #[error("List error for {prefix}: {source}")]
+  List {
+    prefix: String,
+    source: Box<RusotoError<ListObjectsV2Error>>,
+  },
+  #[error("Delete error for {key_count} objects starting at {first_key}: {source}")]
+  Delete {
+    first_key: String,
+    key_count: usize,
+    source: Box<RusotoError<DeleteObjectsError>>,
+  },
+  #[error("Bucket access error for {bucket}: {source}")]
+  Access {
+    bucket: String,
+    source: Box<RusotoError<HeadBucketError>>,
+  },
+  #[error("Download read error for {key}: {source}")]
+  Read { key: String, source: io::Error },
+  #[error("Message archive JSON error in {key}: {source}")]
+  ArchiveJSON {
+    key: String,
+    source: serde_json::Error,
+  },
+  #[error("Message archive base64 error at partition {partition} offset {offset}: {source}")]
+  ArchiveBase64 {
+    partition: i32,
+    offset: i64,
+    source: base64::DecodeError,
+  },
+}
+
+impl DataLakeError {
+  /// Returns a stable name for the kind of failure, for logs and metrics.
+  pub fn category(&self) -> &'static str {
+    match self {
+      Self::Upload { .. }
+      | Self::CreateMultipart { .. }
+      | Self::UploadPart { .. }
+      | Self::CompleteMultipart { .. } => "s3_upload",
+      Self::Cancelled => "cancelled",
+      Self::Download { .. } | Self::Read { .. } => "s3_download",
+      Self::List { .. } => "s3_list",
+      Self::Delete { .. } => "s3_delete",
+      Self::Access { .. } => "s3_access",
+      Self::ArchiveJSON { .. } | Self::ArchiveBase64 { .. } => "archive_decode",
+    }
+  }
+}
+
+/// Encrypted message stored in the message archive, along with
+/// the Kafka metadata of the record.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ArchivedMessage {
+  /// Configured name of the consumed topic; not available in older archives
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub topic: Option<String>,
+  pub partition: i32,
+  pub offset: i64,
+  pub request_threshold: Option<usize>,
+  pub channel_name: Option<String>,
+  pub epoch: Option<u8>,
+  /// Client submission time in Unix milliseconds
+  #[serde(default)]
+  pub submitted_at: Option<i64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub client_version: Option<String>,
+  /// Base64 encoded message
+  pub data: String,
+}
+
+impl ArchivedMessage {
+  /// Returns None if the record does not have a Kafka partition & offset.
+  pub fn from_record(topic: &str, record: &ConsumedRecord) -> Option<Self> {
+    Some(Self {
+      topic: Some(topic.to_string()),
+      partition: record.partition?,
+      offset: record.offset?,
+      request_threshold: record.request_threshold,
+      channel_name: record.channel_name.clone(),
+      epoch: record.epoch,
+      submitted_at: record.submitted_at.map(datetime_to_unix_millis),
+      client_version: record.client_version.clone(),
+      data: base64_engine::STANDARD.encode(&record.data),
+    })
+  }
+
+  pub fn into_record(self) -> Result<ConsumedRecord, DataLakeError> {
+    Ok(ConsumedRecord {
+      data: base64_engine::STANDARD
+        .decode(self.data)
+        .map_err(|source| DataLakeError::ArchiveBase64 {
+          partition: self.partition,
+          offset: self.offset,
+          source,
+        })?,
+      request_threshold: self.request_threshold,
+      channel_name: self.channel_name,
+      epoch: self.epoch,
+      topic: self.topic,
+      partition: Some(self.partition),
+      offset: Some(self.offset),
+      submitted_at: self.submitted_at.and_then(datetime_from_unix_millis),
+      client_version: self.client_version,
+      timestamp: None,
+    })
+  }
+}
+
+fn kinesis_checkpoint_key(group_id: &str, stream_name: &str, shard_id: &str) -> String {
+  format!(
+    "{}/{}/{}/{}",
+    KINESIS_CHECKPOINT_PREFIX, group_id, stream_name, shard_id
+  )
+}
+
+/// Reads archived messages for a channel, in the order that they were archived.
+/// Only archive objects that existed when the reader was created are read.
+/// Objects with checksums in the processed set are skipped.
+pub struct MessageArchiveReader {
+  lake: DataLake,
+  channel_name: String,
+  objects: VecDeque<LakeObject>,
+  records: VecDeque<ArchivedMessage>,
+  current_object: Option<LakeObject>,
+  /// Objects of which all records have been read
+  completed_objects: Vec<LakeObject>,
+}
+
+impl MessageArchiveReader {
+  pub async fn new(
+    lake: DataLake,
+    channel_name: &str,
+    processed_checksums: &HashSet<String>,
+  ) -> Result<Self, DataLakeError> {
+    let mut objects = lake
+      .list(
+        format!("{}/{}/", MESSAGE_ARCHIVE_PREFIX, channel_name),
+        &message_archive_prefix_class(channel_name),
+      )
+      .await?;
+    let listed_count = objects.len();
+    objects.retain(|v| !processed_checksums.contains(v.checksum()));
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    info!(
+      "Found {} message archive objects, {} already processed",
+      listed_count,
+      listed_count - objects.len()
+    );
+    Ok(Self {
+      lake,
+      channel_name: channel_name.to_string(),
+      objects: objects.into(),
+      records: VecDeque::new(),
+      current_object: None,
+      completed_objects: Vec::new(),
+    })
+  }
+
+  /// Returns None once all archive objects have been read.
+  pub async fn next(&mut self) -> Result<Option<ConsumedRecord>, DataLakeError> {
+    while self.records.is_empty() {
+      let object = match self.objects.pop_front() {
+        Some(object) => object,
+        None => return Ok(None),
+      };
+      let contents = self
+        .lake
+        .get(
+          object.key.clone(),
+          &message_archive_prefix_class(&self.channel_name),
+        )
+        .await?;
+      for line in String::from_utf8_lossy(&contents).lines() {
+        if !line.trim().is_empty() {
+          let message =
+            serde_json::from_str(line).map_err(|source| DataLakeError::ArchiveJSON {
+              key: object.key.clone(),
+              source,
+            })?;
+          self.records.push_back(message);
+        }
+      }
+      match self.records.is_empty() {
+        true => self.completed_objects.push(object),
+        false => self.current_object = Some(object),
+      }
+    }
+    let record = self.records.pop_front().unwrap();
+    if self.records.is_empty() {
+      self.completed_objects.extend(self.current_object.take());
+    }
+    record.into_record().map(Some)
+  }
+
+  /// Returns the objects completed since the last call. Should be saved once
+  /// the records read from the objects have been processed.
+  pub fn take_completed_objects(&mut self) -> Vec<LakeObject> {
+    std::mem::take(&mut self.completed_objects)
+  }
+}
+
+fn message_archive_prefix_class(channel_name: &str) -> String {
+  format!("{}/{}", MESSAGE_ARCHIVE_PREFIX, channel_name)
+}
+
+pub struct LakeObject {
+  pub key: String,
+  pub size: usize,
+  /// ETag of the object
+  pub etag: Option<String>,
+  pub last_modified: Option<OffsetDateTime>,
+}
+
+impl LakeObject {
+  /// Returns the ETag of the object, or the key if the ETag is not available.
+  pub fn checksum(&self) -> &str {
+    self.etag.as_deref().unwrap_or(&self.key)
+  }
+}
+
+/// Lists the objects within a date partition, written after each compaction.
+#[derive(Serialize)]
+struct PartitionManifest {
+  data_channel: String,
+  date: String,
+  objects: Vec<String>,
+}
+
+/// Groups small objects into batches, where the total size of each batch
+/// does not exceed the target size. Objects that already meet the target size,
+/// and batches that would only contain a single object, are excluded.
+pub fn plan_compaction(mut objects: Vec<LakeObject>, target_size: usize) -> Vec<Vec<LakeObject>> {
+  objects.sort_by(|a, b| a.key.cmp(&b.key));
+  let mut batches = Vec::new();
+  let mut batch: Vec<LakeObject> = Vec::new();
+  let mut batch_size = 0;
+  for object in objects.into_iter().filter(|v| v.size < target_size) {
+    if batch_size + object.size > target_size {
+      batches.push(std::mem::take(&mut batch));
+      batch_size = 0;
+    }
+    batch_size += object.size;
+    batch.push(object);
+  }
+  batches.push(batch);
+  batches.retain(|v| v.len() > 1);
+  batches
 }
 
 pub struct DataLake {
   s3: S3Client,
   bucket_name: String,
+  tenant: Option<String>,
+  metrics: Option<Arc<LakeMetrics>>,
+  put_request_cost: f64,
+  get_request_cost: f64,
+  storage_cost_per_gb: f64,
+  /// Prefix of stored objects, for keeping reprocessed output apart
+  output_prefix: Option<String>,
+  /// User metadata attached to stored objects
+  object_metadata: Option<HashMap<String, String>>,
+  multipart_part_size: usize,
+  /// Cancels the stores in progress
+  cancel_token: CancellationToken,
+}
+
+/// Aborts a multipart upload that is dropped before completion,
+/// so that uploaded parts are not retained.
+struct MultipartUploadGuard {
+  s3: S3Client,
+  bucket_name: String,
+  key: String,
+  upload_id: Option<String>,
+}
+
+impl MultipartUploadGuard {
+  fn request(&mut self) -> Option<AbortMultipartUploadRequest> {
+    Some(AbortMultipartUploadRequest {
+      bucket: self.bucket_name.clone(),
+      key: self.key.clone(),
+      upload_id: self.upload_id.take()?,
+      ..Default::default()
+    })
+  }
+
+  fn complete(mut self) {
+    self.upload_id = None;
+  }
+
+  async fn abort(mut self) {
+    if let Some(request) = self.request() {
+      if let Err(e) = self.s3.abort_multipart_upload(request).await {
+        warn!("Failed to abort multipart upload of {}: {}", self.key, e);
+      }
+    }
+  }
+}
+
+impl Drop for MultipartUploadGuard {
+  fn drop(&mut self) {
+    if let Some(request) = self.request() {
+      let s3 = self.s3.clone();
+      tokio::spawn(async move {
+        if let Err(e) = s3.abort_multipart_upload(request).await {
+          warn!("Failed to abort dropped multipart upload: {}", e);
+        }
+      });
+    }
+  }
 }
 
 impl DataLake {
-  pub fn new() -> Self {
+  pub fn new(tenant: Option<String>, metrics: Option<Arc<LakeMetrics>>) -> Self {
     let region = match env::var(S3_ENDPOINT_ENV_VAR) {
       Ok(endpoint) => Region::Custom {
         name: "us-west-2".to_string(),
@@ -44,28 +419,666 @@ impl DataLake {
       s3,
       bucket_name: env::var(OUTPUT_S3_BUCKET_ENV_KEY)
         .unwrap_or(DEFAULT_OUTPUT_BUCKET_NAME.to_string()),
+      tenant,
+      metrics,
+      put_request_cost: parse_env_var(S3_PUT_REQUEST_COST_ENV_KEY, DEFAULT_S3_PUT_REQUEST_COST),
+      get_request_cost: parse_env_var(S3_GET_REQUEST_COST_ENV_KEY, DEFAULT_S3_GET_REQUEST_COST),
+      storage_cost_per_gb: parse_env_var(
+        S3_STORAGE_COST_PER_GB_ENV_KEY,
+        DEFAULT_S3_STORAGE_COST_PER_GB,
+      ),
+      output_prefix: None,
+      object_metadata: None,
+      multipart_part_size: parse_env_var::<usize>(
+        MULTIPART_PART_SIZE_MB_ENV_KEY,
+        DEFAULT_MULTIPART_PART_SIZE_MB,
+      )
+      .max(MIN_MULTIPART_PART_SIZE_MB)
+        * 1024
+        * 1024,
+      cancel_token: CancellationToken::new(),
+    }
+  }
+
+  /// Stores in progress fail with `DataLakeError::Cancelled` once the token
+  /// is cancelled. Multipart uploads in progress are aborted.
+  pub fn with_cancel_token(mut self, token: CancellationToken) -> Self {
+    self.cancel_token = token;
+    self
+  }
+
+  /// Attaches the user metadata to every stored object.
+  pub fn with_object_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+    self.object_metadata = Some(metadata);
+    self
+  }
+
+  /// Stores objects under the prefix. Objects are still read without the prefix.
+  pub fn with_output_prefix(mut self, prefix: &str) -> Self {
+    self.output_prefix = Some(prefix.to_string());
+    self
+  }
+
+  /// Checks that the bucket exists and is accessible with the current credentials.
+  pub async fn check_access(&self) -> Result<(), DataLakeError> {
+    self
+      .s3
+      .head_bucket(HeadBucketRequest {
+        bucket: self.bucket_name.clone(),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| DataLakeError::Access {
+        bucket: self.bucket_name.clone(),
+        source: Box::new(e),
+      })?;
+    Ok(())
+  }
+
+  fn full_key(&self, key: String) -> String {
+    match self.tenant.as_ref() {
+      Some(tenant) => format!("{}/{}", tenant, key),
+      None => key,
+    }
+  }
+
+  /// Returns the full key of an object stored via `put`.
+  fn output_key(&self, key: String) -> String {
+    let key = match self.output_prefix.as_ref() {
+      Some(prefix) => format!("{}/{}", prefix, key),
+      None => key,
+    };
+    self.full_key(key)
+  }
+
+  fn record_request(&self, operation: S3Operation, prefix_class: &str, bytes: usize, cost: f64) {
+    if let Some(metrics) = self.metrics.as_ref() {
+      metrics.s3_request(
+        operation,
+        &self.full_key(prefix_class.to_string()),
+        bytes,
+        cost,
+      );
     }
   }
 
+  async fn put(
+    &self,
+    key: String,
+    prefix_class: &str,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let full_key = self.output_key(key);
+    let contents = contents.as_bytes().to_vec();
+    let content_len = contents.len();
+    let request_count = match content_len > self.multipart_part_size {
+      true => self.put_multipart(full_key, contents).await?,
+      false => {
+        let request = self.s3.put_object(PutObjectRequest {
+          acl: Some("bucket-owner-full-control".to_string()),
+          body: Some(ByteStream::from(contents)),
+          bucket: self.bucket_name.clone(),
+          key: full_key.clone(),
+          metadata: self.object_metadata.clone(),
+          ..Default::default()
+        });
+        tokio::select! {
+          biased;
+          _ = self.cancel_token.cancelled() => return Err(DataLakeError::Cancelled),
+          res = request => res.map_err(|e| DataLakeError::Upload {
+            key: full_key,
+            source: Box::new(e),
+          })?,
+        };
+        1
+      }
+    };
+    // Includes the cost of storing the uploaded contents for one month
+    let estimated_cost = self.put_request_cost * request_count as f64
+      + (content_len as f64 / BYTES_PER_GB) * self.storage_cost_per_gb;
+    self.record_request(S3Operation::Put, prefix_class, content_len, estimated_cost);
+    Ok(())
+  }
+
+  /// Uploads the contents in parts. The upload is aborted if a part fails to upload,
+  /// or if the store is cancelled before all parts are uploaded. Returns the amount
+  /// of requests made.
+  async fn put_multipart(
+    &self,
+    full_key: String,
+    contents: Vec<u8>,
+  ) -> Result<usize, DataLakeError> {
+    let output = self
+      .s3
+      .create_multipart_upload(CreateMultipartUploadRequest {
+        acl: Some("bucket-owner-full-control".to_string()),
+        bucket: self.bucket_name.clone(),
+        key: full_key.clone(),
+        metadata: self.object_metadata.clone(),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| DataLakeError::CreateMultipart {
+        key: full_key.clone(),
+        source: Box::new(e),
+      })?;
+    let upload_id = output.upload_id.unwrap_or_default();
+    let guard = MultipartUploadGuard {
+      s3: self.s3.clone(),
+      bucket_name: self.bucket_name.clone(),
+      key: full_key.clone(),
+      upload_id: Some(upload_id.clone()),
+    };
+
+    let upload_parts = async {
+      let mut parts = Vec::new();
+      for (i, chunk) in contents.chunks(self.multipart_part_size).enumerate() {
+        let part_number = i as i64 + 1;
+        let output = self
+          .s3
+          .upload_part(UploadPartRequest {
+            body: Some(ByteStream::from(chunk.to_vec())),
+            bucket: self.bucket_name.clone(),
+            key: full_key.clone(),
+            part_number,
+            upload_id: upload_id.clone(),
+            ..Default::default()
+          })
+          .await
+          .map_err(|e| DataLakeError::UploadPart {
+            key: full_key.clone(),
+            part_number,
+            source: Box::new(e),
+          })?;
+        parts.push(CompletedPart {
+          e_tag: output.e_tag,
+          part_number: Some(part_number),
+        });
+      }
+      Ok::<_, DataLakeError>(parts)
+    };
+    let parts_res = tokio::select! {
+      biased;
+      _ = self.cancel_token.cancelled() => Err(DataLakeError::Cancelled),
+      res = upload_parts => res,
+    };
+    let parts = match parts_res {
+      Ok(parts) => parts,
+      Err(e) => {
+        guard.abort().await;
+        return Err(e);
+      }
+    };
+
+    // Completion is not cancelled, since the object may be written
+    // even if the response is not received
+    let part_count = parts.len();
+    let complete_res = self
+      .s3
+      .complete_multipart_upload(CompleteMultipartUploadRequest {
+        bucket: self.bucket_name.clone(),
+        key: full_key.clone(),
+        upload_id,
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+        ..Default::default()
+      })
+      .await;
+    match complete_res {
+      Ok(_) => guard.complete(),
+      Err(e) => {
+        guard.abort().await;
+        return Err(DataLakeError::CompleteMultipart {
+          key: full_key,
+          source: Box::new(e),
+        });
+      }
+    }
+    Ok(part_count + 2)
+  }
+
+  /// Returns the objects under the prefix. Keys do not include the tenant prefix.
+  async fn list(
+    &self,
+    prefix: String,
+    prefix_class: &str,
+  ) -> Result<Vec<LakeObject>, DataLakeError> {
+    let full_prefix = self.full_key(prefix);
+    let tenant_prefix_len = self
+      .tenant
+      .as_ref()
+      .map(|v| v.len() + 1)
+      .unwrap_or_default();
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+    loop {
+      let output = self
+        .s3
+        .list_objects_v2(ListObjectsV2Request {
+          bucket: self.bucket_name.clone(),
+          prefix: Some(full_prefix.clone()),
+          continuation_token,
+          ..Default::default()
+        })
+        .await
+        .map_err(|e| DataLakeError::List {
+          prefix: full_prefix.clone(),
+          source: Box::new(e),
+        })?;
+      // LIST requests are billed at the same rate as PUT requests
+      self.record_request(S3Operation::List, prefix_class, 0, self.put_request_cost);
+      for object in output.contents.unwrap_or_default() {
+        if let Some(key) = object.key {
+          objects.push(LakeObject {
+            key: key[tenant_prefix_len..].to_string(),
+            size: object.size.unwrap_or_default() as usize,
+            etag: object.e_tag.map(|v| v.trim_matches('"').to_string()),
+            last_modified: object
+              .last_modified
+              .and_then(|v| OffsetDateTime::parse(&v, &Rfc3339).ok()),
+          });
+        }
+      }
+      continuation_token = output.next_continuation_token;
+      if continuation_token.is_none() {
+        break;
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn get(&self, key: String, prefix_class: &str) -> Result<Vec<u8>, DataLakeError> {
+    let full_key = self.full_key(key);
+    let output = self
+      .s3
+      .get_object(GetObjectRequest {
+        bucket: self.bucket_name.clone(),
+        key: full_key.clone(),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| DataLakeError::Download {
+        key: full_key.clone(),
+        source: Box::new(e),
+      })?;
+    let contents = match output.body {
+      Some(body) => body
+        .map_ok(|v| v.to_vec())
+        .try_concat()
+        .await
+        .map_err(|source| DataLakeError::Read {
+          key: full_key,
+          source,
+        })?,
+      None => Vec::new(),
+    };
+    self.record_request(
+      S3Operation::Get,
+      prefix_class,
+      contents.len(),
+      self.get_request_cost,
+    );
+    Ok(contents)
+  }
+
+  async fn delete(&self, keys: Vec<String>, prefix_class: &str) -> Result<(), DataLakeError> {
+    for chunk in keys.chunks(MAX_DELETE_OBJECTS_PER_REQUEST) {
+      let full_keys: Vec<String> = chunk.iter().map(|key| self.full_key(key.clone())).collect();
+      self
+        .s3
+        .delete_objects(DeleteObjectsRequest {
+          bucket: self.bucket_name.clone(),
+          delete: Delete {
+            objects: full_keys
+              .iter()
+              .map(|key| ObjectIdentifier {
+                key: key.clone(),
+                ..Default::default()
+              })
+              .collect(),
+            quiet: Some(true),
+          },
+          ..Default::default()
+        })
+        .await
+        .map_err(|e| DataLakeError::Delete {
+          first_key: full_keys[0].clone(),
+          key_count: full_keys.len(),
+          source: Box::new(e),
+        })?;
+      self.record_request(S3Operation::Delete, prefix_class, 0, 0.0);
+    }
+    Ok(())
+  }
+
   pub async fn store(&self, channel_name: &str, contents: &str) -> Result<(), DataLakeError> {
     let rand_key: u64 = random();
-    let full_key = format!(
+    let key = format!(
       "{}/{}/{}.jsonl",
       OffsetDateTime::now_utc().date(),
       channel_name,
       hex::encode(rand_key.to_le_bytes())
     );
-    let contents = contents.as_bytes().to_vec();
+    self.put(key, channel_name, contents).await
+  }
+
+  /// Stores the checkpoint of a Kinesis shard for the consumer group.
+  pub async fn store_kinesis_checkpoint(
+    &self,
+    group_id: &str,
+    stream_name: &str,
+    shard_id: &str,
+    checkpoint: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = kinesis_checkpoint_key(group_id, stream_name, shard_id);
+    self.put(key, KINESIS_CHECKPOINT_PREFIX, checkpoint).await
+  }
+
+  /// Returns the checkpoint of a Kinesis shard for the consumer group,
+  /// or None if the shard has not been checkpointed.
+  pub async fn load_kinesis_checkpoint(
+    &self,
+    group_id: &str,
+    stream_name: &str,
+    shard_id: &str,
+  ) -> Result<Option<String>, DataLakeError> {
+    let key = kinesis_checkpoint_key(group_id, stream_name, shard_id);
+    match self.get(key, KINESIS_CHECKPOINT_PREFIX).await {
+      Ok(contents) => Ok(Some(String::from_utf8_lossy(&contents).to_string())),
+      Err(DataLakeError::Download { source, .. })
+        if matches!(*source, RusotoError::Service(GetObjectError::NoSuchKey(_))) =>
+      {
+        Ok(None)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  pub async fn store_privacy_report(
+    &self,
+    channel_name: &str,
+    epoch_date: &str,
+    epoch: u8,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = format!(
+      "{}/{}/{}-{}.json",
+      PRIVACY_REPORT_PREFIX, channel_name, epoch_date, epoch
+    );
+    let prefix_class = format!("{}/{}", PRIVACY_REPORT_PREFIX, channel_name);
+    self.put(key, &prefix_class, contents).await
+  }
+
+  pub async fn store_run_summary(
+    &self,
+    channel_name: &str,
+    run_id: &str,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = format!("{}/{}/{}.json", RUN_SUMMARY_PREFIX, channel_name, run_id);
+    let prefix_class = format!("{}/{}", RUN_SUMMARY_PREFIX, channel_name);
+    self.put(key, &prefix_class, contents).await
+  }
+
+  /// Stores an object of a finalized epoch export. Objects of an export
+  /// are stored under `epoch-exports/<channel>/<epoch date>-<epoch>/`.
+  pub async fn store_epoch_export(
+    &self,
+    channel_name: &str,
+    epoch_date: &str,
+    epoch: u8,
+    file_name: &str,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = format!(
+      "{}/{}/{}-{}/{}",
+      EPOCH_EXPORT_PREFIX, channel_name, epoch_date, epoch, file_name
+    );
+    let prefix_class = format!("{}/{}", EPOCH_EXPORT_PREFIX, channel_name);
+    self.put(key, &prefix_class, contents).await
+  }
+
+  /// Stores the encrypted bundle of the recovered keys of an epoch,
+  /// under `key-exports/<channel>/<epoch date>-<epoch>.json`.
+  pub async fn store_key_export(
+    &self,
+    channel_name: &str,
+    epoch_date: &str,
+    epoch: u8,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = format!(
+      "{}/{}/{}-{}.json",
+      KEY_EXPORT_PREFIX, channel_name, epoch_date, epoch
+    );
+    let prefix_class = format!("{}/{}", KEY_EXPORT_PREFIX, channel_name);
+    self.put(key, &prefix_class, contents).await
+  }
+
+  /// Returns all objects of the lake. Keys do not include the tenant prefix.
+  pub async fn list_all_objects(&self) -> Result<Vec<LakeObject>, DataLakeError> {
+    self.list(String::new(), RETENTION_AUDIT_PREFIX).await
+  }
+
+  /// Deletes objects whose retention period has passed.
+  pub async fn purge_objects(&self, keys: Vec<String>) -> Result<(), DataLakeError> {
+    self.delete(keys, RETENTION_AUDIT_PREFIX).await
+  }
+
+  /// Stores the report of a retention run, under `retention-audits/<run id>.json`.
+  pub async fn store_retention_audit(
+    &self,
+    run_id: &str,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let key = format!("{}/{}.json", RETENTION_AUDIT_PREFIX, run_id);
+    self.put(key, RETENTION_AUDIT_PREFIX, contents).await
+  }
+
+  /// Stores a batch of output measurements to be loaded into a data warehouse,
+  /// under `warehouse-loads/<channel>/<first id>-<last id>.jsonl`.
+  /// Returns the `s3://` URI of the stored object.
+  pub async fn store_warehouse_load(
+    &self,
+    channel_name: &str,
+    first_id: i64,
+    last_id: i64,
+    contents: &str,
+  ) -> Result<String, DataLakeError> {
+    let key = format!(
+      "{}/{}/{}-{}.jsonl",
+      WAREHOUSE_LOAD_PREFIX, channel_name, first_id, last_id
+    );
+    let prefix_class = format!("{}/{}", WAREHOUSE_LOAD_PREFIX, channel_name);
+    let uri = format!("s3://{}/{}", self.bucket_name, self.output_key(key.clone()));
+    self.put(key, &prefix_class, contents).await?;
+    Ok(uri)
+  }
+
+  /// Stores a batch of serialized `ArchivedMessage` lines in the message archive.
+  /// Object keys are prefixed with the current time, so that the archive can
+  /// be read in order.
+  pub async fn store_messages(
+    &self,
+    channel_name: &str,
+    contents: &str,
+  ) -> Result<(), DataLakeError> {
+    let rand_key: u64 = random();
+    let key = format!(
+      "{}/{}/{:020}-{}.jsonl",
+      MESSAGE_ARCHIVE_PREFIX,
+      channel_name,
+      OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000,
+      hex::encode(rand_key.to_le_bytes())
+    );
     self
-      .s3
-      .put_object(PutObjectRequest {
-        acl: Some("bucket-owner-full-control".to_string()),
-        body: Some(ByteStream::from(contents)),
-        bucket: self.bucket_name.clone(),
-        key: full_key,
-        ..Default::default()
-      })
-      .await?;
-    Ok(())
+      .put(key, &message_archive_prefix_class(channel_name), contents)
+      .await
+  }
+
+  /// Merges small objects within the date partition of the channel into larger objects,
+  /// and rewrites the partition manifest. Source objects are deleted after
+  /// the merged objects are uploaded.
+  pub async fn compact(&self, channel_name: &str, date: Date) -> Result<(), DataLakeError> {
+    let target_size = parse_env_var::<usize>(
+      COMPACTION_TARGET_SIZE_MB_ENV_KEY,
+      DEFAULT_COMPACTION_TARGET_SIZE_MB,
+    ) * 1024
+      * 1024;
+    let partition_prefix = format!("{}/{}/", date, channel_name);
+    let manifest_key = format!("{}{}", partition_prefix, MANIFEST_FILE_NAME);
+
+    let objects: Vec<LakeObject> = self
+      .list(partition_prefix.clone(), channel_name)
+      .await?
+      .into_iter()
+      .filter(|v| v.key != manifest_key)
+      .collect();
+    let object_count = objects.len();
+    let mut remaining_keys: Vec<String> = objects.iter().map(|v| v.key.clone()).collect();
+
+    let batches = plan_compaction(objects, target_size);
+    info!(
+      "Compacting {} of {} objects in {} into {} objects",
+      batches.iter().map(|v| v.len()).sum::<usize>(),
+      object_count,
+      partition_prefix,
+      batches.len()
+    );
+
+    for batch in batches {
+      let mut contents = Vec::new();
+      for object in &batch {
+        let object_contents =
+          String::from_utf8_lossy(&self.get(object.key.clone(), channel_name).await?)
+            .trim_end()
+            .to_string();
+        if !object_contents.is_empty() {
+          contents.push(object_contents);
+        }
+      }
+      let rand_key: u64 = random();
+      let compacted_key = format!(
+        "{}{}{}.jsonl",
+        partition_prefix,
+        COMPACTED_OBJECT_PREFIX,
+        hex::encode(rand_key.to_le_bytes())
+      );
+      self
+        .put(compacted_key.clone(), channel_name, &contents.join("\n"))
+        .await?;
+
+      // Only delete the source objects once the compacted object is stored,
+      // so that records are never lost if compaction is interrupted.
+      let batch_keys: Vec<String> = batch.into_iter().map(|v| v.key).collect();
+      self.delete(batch_keys.clone(), channel_name).await?;
+      let batch_keys: HashSet<String> = batch_keys.into_iter().collect();
+      remaining_keys.retain(|v| !batch_keys.contains(v));
+      remaining_keys.push(compacted_key);
+    }
+
+    remaining_keys.sort();
+    let manifest = PartitionManifest {
+      data_channel: channel_name.to_string(),
+      date: date.to_string(),
+      objects: remaining_keys,
+    };
+    self
+      .put(
+        manifest_key,
+        channel_name,
+        &serde_json::to_string(&manifest).unwrap(),
+      )
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn object(key: &str, size: usize) -> LakeObject {
+    LakeObject {
+      key: key.to_string(),
+      size,
+      etag: None,
+      last_modified: None,
+    }
+  }
+
+  #[test]
+  fn archived_message_roundtrip() {
+    let record = ConsumedRecord {
+      data: vec![1, 2, 3],
+      request_threshold: Some(20),
+      channel_name: Some("typical".to_string()),
+      epoch: Some(4),
+      topic: None,
+      partition: Some(2),
+      offset: Some(900),
+      submitted_at: datetime_from_unix_millis(1700000000123),
+      client_version: Some("1.2.3".to_string()),
+      timestamp: None,
+    };
+    let line =
+      serde_json::to_string(&ArchivedMessage::from_record("p3a-star-enc", &record).unwrap())
+        .unwrap();
+    let parsed = serde_json::from_str::<ArchivedMessage>(&line)
+      .unwrap()
+      .into_record()
+      .unwrap();
+    assert_eq!(parsed.data, record.data);
+    assert_eq!(parsed.request_threshold, Some(20));
+    assert_eq!(parsed.channel_name.as_deref(), Some("typical"));
+    assert_eq!(parsed.epoch, Some(4));
+    assert_eq!(
+      parsed.topic_partition(),
+      Some(("p3a-star-enc".to_string(), 2))
+    );
+    assert_eq!(parsed.offset, Some(900));
+    assert_eq!(parsed.submitted_at, record.submitted_at);
+    assert_eq!(parsed.client_version.as_deref(), Some("1.2.3"));
+
+    assert!(ArchivedMessage::from_record("p3a-star-enc", &ConsumedRecord::default()).is_none());
+
+    // Older archives do not record the topic
+    let line = r#"{"partition":1,"offset":5,"request_threshold":null,"channel_name":null,"epoch":null,"data":""}"#;
+    let parsed = serde_json::from_str::<ArchivedMessage>(line).unwrap();
+    assert_eq!(parsed.topic, None);
+  }
+
+  #[test]
+  fn compaction_plan() {
+    let objects = vec![
+      object("e", 2),
+      object("a", 4),
+      object("b", 5),
+      object("c", 12),
+      object("d", 3),
+      object("f", 9),
+    ];
+    let batches: Vec<Vec<String>> = plan_compaction(objects, 10)
+      .into_iter()
+      .map(|batch| batch.into_iter().map(|v| v.key).collect())
+      .collect();
+    assert_eq!(
+      batches,
+      vec![
+        vec!["a".to_string(), "b".to_string()],
+        vec!["d".to_string(), "e".to_string()]
+      ]
+    );
+
+    assert!(plan_compaction(vec![object("a", 1)], 10).is_empty());
+    assert!(plan_compaction(Vec::new(), 10).is_empty());
+  }
+
+  #[tokio::test]
+  async fn cancelled_store() {
+    let cancel_token = CancellationToken::new();
+    cancel_token.cancel();
+    let lake = DataLake::new(None, None).with_cancel_token(cancel_token);
+    let e = lake.store("typical", "{}").await.unwrap_err();
+    assert!(matches!(e, DataLakeError::Cancelled));
+    assert_eq!(e.category(), "cancelled");
   }
 }
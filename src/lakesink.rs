@@ -1,96 +1,475 @@
-use crate::lake::{DataLake, DataLakeError};
-use crate::prometheus::DataLakeMetrics;
+use crate::canary::is_canary_channel;
+use crate::lake::{ArchivedMessage, DataLake, DataLakeError};
+use crate::lakesink_transform::{TransformChain, TransformError};
+use crate::memory_watchdog::{memory_pressure, record_intervention, Intervention};
+use crate::pipeline_io::{observe, Endpoint, LakeBatch, Sink, Source};
+use crate::prometheus::{IoOperation, LakeMetrics, LakeSinkMetricLabels, LakeSinkMetrics};
 use crate::record_stream::{
-  DynRecordStream, KafkaRecordStream, KafkaRecordStreamConfig, RecordStream, RecordStreamError,
+  ConsumedRecord, DynRecordStream, KafkaComponent, KafkaRecordStreamConfig, RecordStreamError,
+  RecordStreamFactory, RecordStreamOptions,
 };
 use crate::util::parse_env_var;
-use derive_more::{Display, Error, From};
+use futures::stream::{FuturesOrdered, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
 use std::str::{from_utf8, Utf8Error};
 use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
 
 const BATCH_SIZE_ENV_KEY: &str = "LAKE_SINK_BATCH_SIZE";
 const BATCH_SIZE_DEFAULT: &str = "1000";
-const BATCH_TIMEOUT_SECS: u64 = 45;
+const BATCH_TIMEOUT_SECS_ENV_KEY: &str = "LAKE_SINK_BATCH_TIMEOUT_SECS";
+const BATCH_TIMEOUT_SECS_DEFAULT: &str = "45";
+const ARCHIVE_BATCH_SIZE_ENV_KEY: &str = "LAKE_SINK_ARCHIVE_BATCH_SIZE";
+const ARCHIVE_BATCH_SIZE_DEFAULT: &str = "1000";
+const ARCHIVE_BATCH_TIMEOUT_SECS_ENV_KEY: &str = "LAKE_SINK_ARCHIVE_BATCH_TIMEOUT_SECS";
+const ARCHIVE_BATCH_TIMEOUT_SECS_DEFAULT: &str = "45";
+const MAX_CONCURRENT_UPLOADS_ENV_KEY: &str = "LAKE_SINK_MAX_CONCURRENT_UPLOADS";
+const MAX_CONCURRENT_UPLOADS_DEFAULT: &str = "2";
+const ARCHIVE_MESSAGES_ENV_KEY: &str = "LAKE_SINK_ARCHIVE_MESSAGES";
+const ARCHIVE_MESSAGES_DEFAULT: &str = "false";
+const CONSUMER_COUNT_ENV_KEY: &str = "LAKE_SINK_CONSUMER_COUNT";
+const CONSUMER_COUNT_DEFAULT: &str = "1";
+const SHUTDOWN_TIMEOUT_SECS_ENV_KEY: &str = "LAKE_SINK_SHUTDOWN_TIMEOUT_SECS";
+const SHUTDOWN_TIMEOUT_SECS_DEFAULT: &str = "60";
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Error, From, Display, Debug)]
-#[display(fmt = "Lake sink error: {}")]
+fn record_position(partition: &Option<i32>, offset: &Option<i64>) -> String {
+  match (partition, offset) {
+    (Some(partition), Some(offset)) => format!("partition {} offset {}", partition, offset),
+    _ => "unknown position".to_string(),
+  }
+}
+
+#[derive(Error, Debug)]
 pub enum LakeSinkError {
-  Utf8(Utf8Error),
-  RecordStream(RecordStreamError),
-  Lake(DataLakeError),
+  #[error(
+    "Lake sink error: record at {} is not UTF-8: {source}",
+    record_position(.partition, .offset)
+  )]
+  Utf8 {
+    partition: Option<i32>,
+    offset: Option<i64>,
+    source: Utf8Error,
+  },
+  #[error("Lake sink error: {0}")]
+  RecordStream(#[from] RecordStreamError),
+  #[error("Lake sink error: {0}")]
+  Lake(#[from] DataLakeError),
+  #[error("Lake sink error: {0}")]
+  JSONSerialize(#[from] serde_json::Error),
+  #[error(
+    "Lake sink error: failed to transform record at {}: {source}",
+    record_position(.partition, .offset)
+  )]
+  Transform {
+    partition: Option<i32>,
+    offset: Option<i64>,
+    source: TransformError,
+  },
+}
+
+impl LakeSinkError {
+  fn utf8(record: &ConsumedRecord, source: Utf8Error) -> Self {
+    Self::Utf8 {
+      partition: record.partition,
+      offset: record.offset,
+      source,
+    }
+  }
+
+  /// Returns a stable name for the kind of failure, for logs and metrics.
+  pub fn category(&self) -> &'static str {
+    match self {
+      Self::Utf8 { .. } => "record_encoding",
+      Self::RecordStream(e) => e.category(),
+      Self::Lake(e) => e.category(),
+      Self::JSONSerialize(_) => "serialize",
+      Self::Transform { .. } => "transform",
+    }
+  }
+}
+
+/// If enabled, the lake sink will also store encrypted messages in
+/// the message archive, so that the aggregator can source messages from the lake.
+pub fn message_archive_enabled() -> bool {
+  parse_env_var::<bool>(ARCHIVE_MESSAGES_ENV_KEY, ARCHIVE_MESSAGES_DEFAULT)
+}
+
+/// The kind of topic consumed by a lake sink, which determines
+/// where the records are stored in the data lake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LakeSinkKind {
+  /// Output topic with recovered measurements, stored in date partitions
+  Measurements,
+  /// Encrypted topic, stored in the message archive
+  MessageArchive,
+}
+
+impl LakeSinkKind {
+  fn name(&self) -> &'static str {
+    match self {
+      LakeSinkKind::Measurements => "measurements",
+      LakeSinkKind::MessageArchive => "messages",
+    }
+  }
+}
+
+/// Settings for a single lake sink task. Each task consumes one topic.
+#[derive(Clone)]
+pub struct LakeSinkConfig {
+  pub channel_name: String,
+  pub topic: String,
+  pub kind: LakeSinkKind,
+  pub batch_size: usize,
+  pub batch_timeout: Duration,
+  /// Index of the task among the tasks consuming the topic, used as a metric label
+  pub consumer_index: usize,
+}
+
+impl LakeSinkConfig {
+  /// Creates the sink config, with batch settings for the kind of sink
+  /// loaded from the environment.
+  pub fn new(channel_name: String, topic: String, kind: LakeSinkKind) -> Self {
+    let (batch_size, batch_timeout_secs) = match kind {
+      LakeSinkKind::Measurements => (
+        parse_env_var::<usize>(BATCH_SIZE_ENV_KEY, BATCH_SIZE_DEFAULT),
+        parse_env_var::<u64>(BATCH_TIMEOUT_SECS_ENV_KEY, BATCH_TIMEOUT_SECS_DEFAULT),
+      ),
+      LakeSinkKind::MessageArchive => (
+        parse_env_var::<usize>(ARCHIVE_BATCH_SIZE_ENV_KEY, ARCHIVE_BATCH_SIZE_DEFAULT),
+        parse_env_var::<u64>(
+          ARCHIVE_BATCH_TIMEOUT_SECS_ENV_KEY,
+          ARCHIVE_BATCH_TIMEOUT_SECS_DEFAULT,
+        ),
+      ),
+    };
+    Self {
+      channel_name,
+      topic,
+      kind,
+      batch_size,
+      batch_timeout: Duration::from_secs(batch_timeout_secs),
+      consumer_index: 0,
+    }
+  }
+
+  pub fn with_consumer_index(mut self, consumer_index: usize) -> Self {
+    self.consumer_index = consumer_index;
+    self
+  }
+
+  /// Returns the amount of lake sink tasks to start for the topic. The tasks
+  /// share a consumer group, so that the topic partitions are split between them.
+  pub fn consumer_count(&self, tenant: Option<&str>) -> usize {
+    RecordStreamFactory::from_env().consumer_count(
+      KafkaComponent::LakeSink,
+      &self.topic,
+      tenant,
+      parse_env_var::<usize>(CONSUMER_COUNT_ENV_KEY, CONSUMER_COUNT_DEFAULT).max(1),
+    )
+  }
+
+  fn metric_labels(&self) -> LakeSinkMetricLabels {
+    LakeSinkMetricLabels {
+      sink: self.kind.name().to_string(),
+      channel_name: self.channel_name.clone(),
+      consumer: self.consumer_index.to_string(),
+    }
+  }
+}
+
+/// Returns the JSON lines to store for the batch. Transforms are only applied
+/// to measurements, since archived messages must remain readable by the aggregator.
+fn batch_contents(
+  batch: &[ConsumedRecord],
+  config: &LakeSinkConfig,
+  transforms: &TransformChain,
+) -> Result<String, LakeSinkError> {
+  let lines = if config.kind == LakeSinkKind::MessageArchive {
+    batch
+      .iter()
+      .filter_map(|v| ArchivedMessage::from_record(&config.topic, v))
+      .map(|v| serde_json::to_string(&v))
+      .collect::<Result<Vec<String>, serde_json::Error>>()?
+  } else if transforms.is_empty() {
+    batch
+      .iter()
+      .map(|v| {
+        from_utf8(&v.data)
+          .map(|data| data.to_string())
+          .map_err(|e| LakeSinkError::utf8(v, e))
+      })
+      .collect::<Result<Vec<String>, LakeSinkError>>()?
+  } else {
+    batch
+      .iter()
+      .map(|v| {
+        let data = transforms
+          .apply(&v.data)
+          .map_err(|source| LakeSinkError::Transform {
+            partition: v.partition,
+            offset: v.offset,
+            source,
+          })?;
+        Ok(
+          from_utf8(&data)
+            .map_err(|e| LakeSinkError::utf8(v, e))?
+            .to_string(),
+        )
+      })
+      .collect::<Result<Vec<String>, LakeSinkError>>()?
+  };
+  Ok(lines.join("\n"))
+}
+
+/// A batch that has been stored in the lake, and is awaiting
+/// its consumption commit.
+struct StoredBatch {
+  offsets: Vec<(i32, i64)>,
+  partition_counts: Vec<(i32, usize)>,
+  record_count: usize,
+}
+
+/// Returns the highest offset of each partition in the batch.
+fn batch_offsets(batch: &[ConsumedRecord]) -> Vec<(i32, i64)> {
+  let mut offsets = HashMap::new();
+  for record in batch {
+    if let (Some(partition), Some(offset)) = (record.partition, record.offset) {
+      let max_offset = offsets.entry(partition).or_insert(offset);
+      *max_offset = offset.max(*max_offset);
+    }
+  }
+  let mut offsets: Vec<_> = offsets.into_iter().collect();
+  offsets.sort();
+  offsets
+}
+
+/// Returns the amount of records in each partition of the batch.
+fn batch_partition_counts(batch: &[ConsumedRecord]) -> Vec<(i32, usize)> {
+  let mut counts = HashMap::new();
+  for partition in batch.iter().filter_map(|v| v.partition) {
+    *counts.entry(partition).or_default() += 1;
+  }
+  let mut counts: Vec<_> = counts.into_iter().collect();
+  counts.sort();
+  counts
 }
 
 async fn store_batch(
   lake: &DataLake,
+  config: &LakeSinkConfig,
+  transforms: &TransformChain,
+  batch: Vec<ConsumedRecord>,
+) -> Result<StoredBatch, LakeSinkError> {
+  let contents = batch_contents(&batch, config, transforms)?;
+  let lake_batch = LakeBatch {
+    channel_name: &config.channel_name,
+    kind: config.kind,
+    contents: &contents,
+  };
+  observe(
+    lake.endpoint_name(),
+    IoOperation::Send,
+    lake.send(&lake_batch),
+  )
+  .await?;
+  debug!("Saved batch to lake");
+  Ok(StoredBatch {
+    offsets: batch_offsets(&batch),
+    partition_counts: batch_partition_counts(&batch),
+    record_count: batch.len(),
+  })
+}
+
+async fn commit_batch(
   rec_stream: &DynRecordStream,
-  channel_name: &str,
-  batch: &[Vec<u8>],
-  metrics: &DataLakeMetrics,
+  stored_batch: StoredBatch,
+  metrics: &LakeSinkMetrics,
+  metric_labels: &LakeSinkMetricLabels,
 ) -> Result<(), LakeSinkError> {
-  let json_lines: Vec<String> = batch
-    .iter()
-    .map(|v| from_utf8(v).map(|v| v.to_string()))
-    .collect::<Result<Vec<String>, Utf8Error>>()?;
-  let contents = json_lines.join("\n");
-  lake.store(channel_name, &contents).await?;
-
-  rec_stream.commit_last_consume().await?;
-
-  metrics.records_flushed(batch.len());
-  debug!("Saved batch to lake, committed");
+  rec_stream.commit_offsets(&stored_batch.offsets).await?;
+  metrics.records_flushed(metric_labels, stored_batch.record_count);
+  metrics.partition_records_flushed(metric_labels, &stored_batch.partition_counts);
+  match rec_stream.partition_lags() {
+    Ok(partition_lags) => metrics.set_consumer_lag(metric_labels, &partition_lags),
+    Err(e) => warn!(
+      "Failed to query consumer positions for lag reporting: {}",
+      e
+    ),
+  }
+  debug!("Committed batch");
   Ok(())
 }
 
+/// Waits for the uploads in progress, and commits the stored batches. Uploads that do
+/// not finish within the shutdown timeout are cancelled. Batches following a cancelled
+/// batch are not committed, so that their records are consumed again by the next run.
+/// Returns false if any batch was not committed.
+async fn finish_uploads<F>(
+  rec_stream: &DynRecordStream,
+  uploads: &mut FuturesOrdered<F>,
+  upload_cancel_token: &CancellationToken,
+  metrics: &LakeSinkMetrics,
+  metric_labels: &LakeSinkMetricLabels,
+) -> Result<bool, LakeSinkError>
+where
+  F: Future<Output = Result<StoredBatch, LakeSinkError>>,
+{
+  let shutdown_timeout = Duration::from_secs(parse_env_var(
+    SHUTDOWN_TIMEOUT_SECS_ENV_KEY,
+    SHUTDOWN_TIMEOUT_SECS_DEFAULT,
+  ));
+  let deadline = sleep(shutdown_timeout);
+  tokio::pin!(deadline);
+  let mut uncommitted_count = 0;
+  loop {
+    tokio::select! {
+      stored_batch_res = uploads.next() => match stored_batch_res {
+        None => break,
+        Some(Err(LakeSinkError::Lake(DataLakeError::Cancelled))) => uncommitted_count += 1,
+        Some(stored_batch_res) => {
+          let stored_batch = stored_batch_res?;
+          match uncommitted_count {
+            0 => commit_batch(rec_stream, stored_batch, metrics, metric_labels).await?,
+            _ => uncommitted_count += 1,
+          }
+        }
+      },
+      _ = &mut deadline, if !upload_cancel_token.is_cancelled() => {
+        warn!(
+          "Uploads did not finish within {}s, cancelling {} uploads",
+          shutdown_timeout.as_secs(),
+          uploads.len()
+        );
+        upload_cancel_token.cancel();
+      }
+    }
+  }
+  if uncommitted_count > 0 {
+    warn!(
+      "{} batches were not committed due to cancelled uploads",
+      uncommitted_count
+    );
+  }
+  Ok(uncommitted_count == 0)
+}
+
+/// Batches are uploaded concurrently, up to `LAKE_SINK_MAX_CONCURRENT_UPLOADS`
+/// uploads at a time. Consumption is committed in batch order, so that a batch
+/// is never committed before the uploads of all preceding batches have completed.
+/// Once the max amount of uploads are in progress, the stream is paused until an
+/// upload completes, so that records are not buffered while the lake is slow.
+/// The stream is also paused while the process is under memory pressure.
 pub async fn start_lakesink(
-  channel_name: String,
-  stream_topic: String,
-  metrics: Arc<DataLakeMetrics>,
+  config: LakeSinkConfig,
+  metrics: Arc<LakeSinkMetrics>,
+  lake_metrics: Arc<LakeMetrics>,
   cancel_token: CancellationToken,
   output_measurements_to_stdout: bool,
+  tenant: Option<String>,
 ) -> Result<(), LakeSinkError> {
-  let batch_size = parse_env_var::<usize>(BATCH_SIZE_ENV_KEY, BATCH_SIZE_DEFAULT);
+  let max_concurrent_uploads = parse_env_var::<usize>(
+    MAX_CONCURRENT_UPLOADS_ENV_KEY,
+    MAX_CONCURRENT_UPLOADS_DEFAULT,
+  )
+  .max(1);
 
-  let rec_stream = KafkaRecordStream::new(KafkaRecordStreamConfig {
-    enable_producer: false,
-    enable_consumer: true,
-    topic: stream_topic,
-    use_output_group_id: true,
-  });
+  let rec_stream = RecordStreamFactory::from_env().create(
+    KafkaRecordStreamConfig {
+      component: KafkaComponent::LakeSink,
+      enable_producer: false,
+      enable_consumer: true,
+      topic: config.topic.clone(),
+      // The lake sink consumer group is also used for encrypted topics,
+      // so that archiving does not affect the aggregator's consumer group offsets
+      use_output_group_id: true,
+      group_id_suffix: None,
+      tenant: tenant.clone(),
+    },
+    RecordStreamOptions::default(),
+  );
 
+  let upload_cancel_token = CancellationToken::new();
   let lake = if output_measurements_to_stdout {
     None
   } else {
-    Some(DataLake::new())
+    Some(DataLake::new(tenant, Some(lake_metrics)).with_cancel_token(upload_cancel_token.clone()))
+  };
+  let transforms = match config.kind {
+    LakeSinkKind::Measurements => TransformChain::from_env(),
+    LakeSinkKind::MessageArchive => TransformChain::default(),
   };
-  let mut batch = Vec::with_capacity(batch_size);
-  let batch_timeout = Duration::from_secs(BATCH_TIMEOUT_SECS);
+  let metric_labels = config.metric_labels();
+  let is_canary_output =
+    config.kind == LakeSinkKind::Measurements && is_canary_channel(&config.channel_name);
+  let mut batch = Vec::with_capacity(config.batch_size);
+  // Resolves stored batches in the order that the uploads were started
+  let mut uploads = FuturesOrdered::new();
+  // Set while the stream is paused because the max amount of uploads are in progress.
+  // Paused streams are still polled, so that the consumer remains in its group.
+  let mut paused = false;
+  let mut flushed_for_memory_pressure = false;
   loop {
     tokio::select! {
-      record_res = rec_stream.consume() => {
-        let record = record_res?;
-        metrics.record_received();
+      // Consumes the records available for the batch. The wait for the first record
+      // is not bounded, since it is interrupted by the other branches.
+      records_res = Source::next_batch(
+        rec_stream.as_ref(),
+        config.batch_size.saturating_sub(batch.len()).max(1),
+        Duration::MAX,
+      ), if paused || uploads.len() < max_concurrent_uploads => {
+        let records = records_res?;
+        metrics.records_received(&metric_labels, records.len());
+        if is_canary_output && !records.is_empty() {
+          metrics.canary_output_received(OffsetDateTime::now_utc().unix_timestamp());
+        }
         match lake.as_ref() {
           Some(lake) => {
-            batch.push(record.data);
-            if batch.len() >= batch_size {
-              store_batch(lake, &rec_stream, &channel_name, &batch, &metrics).await?;
-              batch.clear();
+            if paused && !records.is_empty() {
+              // Partitions assigned after pausing are not paused yet
+              rec_stream.pause()?;
+            }
+            batch.extend(records);
+            if batch.len() >= config.batch_size && uploads.len() < max_concurrent_uploads {
+              let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(config.batch_size));
+              uploads.push_back(store_batch(lake, &config, &transforms, full_batch));
             }
           },
+          None if records.is_empty() => (),
           None => {
-            println!("{}", from_utf8(&record.data)?);
-            rec_stream.commit_last_consume().await?;
+            println!("{}", batch_contents(&records, &config, &transforms)?);
+            observe(
+              rec_stream.endpoint_name(),
+              IoOperation::Commit,
+              Source::commit(rec_stream.as_ref()),
+            )
+            .await?;
           }
         };
       },
-      _ = sleep(batch_timeout) => {
+      Some(stored_batch_res) = uploads.next() => {
+        commit_batch(rec_stream.as_ref(), stored_batch_res?, &metrics, &metric_labels).await?;
+        if let Some(lake) = lake.as_ref() {
+          // Records consumed while paused may have filled the batch
+          if batch.len() >= config.batch_size {
+            let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(config.batch_size));
+            uploads.push_back(store_batch(lake, &config, &transforms, full_batch));
+          }
+        }
+      },
+      // Re-evaluates whether the stream should remain paused, since
+      // no records are consumed while paused
+      _ = sleep(PAUSE_CHECK_INTERVAL), if paused => {},
+      _ = sleep(config.batch_timeout), if uploads.len() < max_concurrent_uploads => {
         if let Some(lake) = lake.as_ref() {
           if !batch.is_empty() {
-            store_batch(lake, &rec_stream, &channel_name, &batch, &metrics).await?;
-            batch.clear();
+            let partial_batch = std::mem::take(&mut batch);
+            uploads.push_back(store_batch(lake, &config, &transforms, partial_batch));
           }
         }
       },
@@ -98,12 +477,85 @@ pub async fn start_lakesink(
         info!("Ending lakesink task...");
         if let Some(lake) = lake.as_ref() {
           if !batch.is_empty() {
-            store_batch(lake, &rec_stream, &channel_name, &batch, &metrics).await?;
+            uploads.push_back(store_batch(lake, &config, &transforms, std::mem::take(&mut batch)));
           }
         }
+        let all_committed = finish_uploads(
+          rec_stream.as_ref(),
+          &mut uploads,
+          &upload_cancel_token,
+          &metrics,
+          &metric_labels,
+        )
+        .await?;
+        // Consumption is only committed if no batch was left uncommitted, so that
+        // the records of cancelled batches are consumed again
+        rec_stream.shutdown(all_committed).await?;
         break;
       }
     }
+    let under_memory_pressure = memory_pressure();
+    if let Some(lake) = lake.as_ref() {
+      // The batch is flushed once when memory pressure is first observed
+      if under_memory_pressure
+        && !flushed_for_memory_pressure
+        && !batch.is_empty()
+        && uploads.len() < max_concurrent_uploads
+      {
+        record_intervention(Intervention::FlushBatch);
+        uploads.push_back(store_batch(
+          lake,
+          &config,
+          &transforms,
+          std::mem::take(&mut batch),
+        ));
+        flushed_for_memory_pressure = true;
+      }
+    }
+    if !under_memory_pressure {
+      flushed_for_memory_pressure = false;
+    }
+    let should_pause = under_memory_pressure || uploads.len() >= max_concurrent_uploads;
+    if should_pause && !paused && rec_stream.pause()? {
+      if under_memory_pressure {
+        record_intervention(Intervention::PauseConsumption);
+      }
+      debug!("Paused consumption");
+      paused = true;
+      metrics.set_consumer_paused(&metric_labels, true);
+    } else if !should_pause && paused {
+      rec_stream.resume()?;
+      debug!("Resumed consumption");
+      paused = false;
+      metrics.set_consumer_paused(&metric_labels, false);
+    }
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(partition: i32, offset: i64) -> ConsumedRecord {
+    ConsumedRecord {
+      partition: Some(partition),
+      offset: Some(offset),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn offsets_of_batch() {
+    let batch = vec![
+      record(1, 20),
+      record(0, 5),
+      record(1, 21),
+      record(0, 7),
+      ConsumedRecord::default(),
+    ];
+    assert_eq!(batch_offsets(&batch), vec![(0, 7), (1, 21)]);
+    assert_eq!(batch_partition_counts(&batch), vec![(0, 2), (1, 2)]);
+    assert!(batch_offsets(&[]).is_empty());
+  }
+}
@@ -0,0 +1,245 @@
+//! Payload transforms applied by the lake sink to each measurement before storage,
+//! so that deployments with stricter retention rules can store reduced forms of
+//! the measurements. The chain is configured with `LAKE_SINK_TRANSFORMS` as a
+//! comma-separated list of steps, which are applied in order:
+//!
+//! - `strip:<field>|<field>...` removes the fields from the measurement.
+//! - `redact:<field>|<field>...` replaces the values of the fields with a placeholder.
+//! - `reencode:json` re-serializes the measurement as compact JSON with sorted keys.
+//! - `reencode:base64` stores the record as a base64-encoded JSON string.
+//! - `encrypt` encrypts the record with AES-256-GCM, using the hex-encoded key in
+//!   `LAKE_SINK_ENCRYPTION_KEY_FILE`. It is stored as `{"encrypted":"<base64>"}`,
+//!   where the encoded data contains the nonce followed by the ciphertext.
+//!
+//! Stored records remain valid JSON lines after each step. Field transforms
+//! require the record to be a JSON object, so they must precede `reencode:base64`
+//! and `encrypt` in the chain.
+
+use crate::encryption::ShareCipher;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error, From};
+use serde_json::{json, Map, Value};
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+const LAKE_SINK_TRANSFORMS_ENV_KEY: &str = "LAKE_SINK_TRANSFORMS";
+const LAKE_SINK_ENCRYPTION_KEY_FILE_ENV_KEY: &str = "LAKE_SINK_ENCRYPTION_KEY_FILE";
+const REDACTED_VALUE: &str = "[redacted]";
+
+#[derive(Error, From, Display, Debug)]
+pub enum TransformError {
+  #[display(fmt = "transform JSON error: {}", _0)]
+  Json(serde_json::Error),
+  #[display(fmt = "record is not a JSON object")]
+  NotObject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+  Json,
+  Base64,
+}
+
+/// A step of the transform chain, as parsed from the configuration
+#[derive(Clone, Debug, PartialEq)]
+enum TransformStep {
+  Strip(Vec<String>),
+  Redact(Vec<String>),
+  Reencode(Encoding),
+  Encrypt,
+}
+
+impl FromStr for TransformStep {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (name, arg) = match s.split_once(':') {
+      Some((name, arg)) => (name, Some(arg)),
+      None => (s, None),
+    };
+    let fields = || -> Result<Vec<String>, String> {
+      let fields: Vec<String> = arg
+        .unwrap_or_default()
+        .split('|')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect();
+      match fields.is_empty() {
+        true => Err(format!("transform '{}' requires at least one field", name)),
+        false => Ok(fields),
+      }
+    };
+    match (name, arg) {
+      ("strip", _) => Ok(Self::Strip(fields()?)),
+      ("redact", _) => Ok(Self::Redact(fields()?)),
+      ("reencode", Some("json")) => Ok(Self::Reencode(Encoding::Json)),
+      ("reencode", Some("base64")) => Ok(Self::Reencode(Encoding::Base64)),
+      ("reencode", _) => Err("reencode transform requires 'json' or 'base64'".to_string()),
+      ("encrypt", None) => Ok(Self::Encrypt),
+      _ => Err(format!("unknown transform '{}'", s)),
+    }
+  }
+}
+
+fn parse_steps(config: &str) -> Result<Vec<TransformStep>, String> {
+  config
+    .split(',')
+    .map(|v| v.trim())
+    .filter(|v| !v.is_empty())
+    .map(TransformStep::from_str)
+    .collect()
+}
+
+fn parse_object(data: &[u8]) -> Result<Map<String, Value>, TransformError> {
+  match serde_json::from_slice(data)? {
+    Value::Object(map) => Ok(map),
+    _ => Err(TransformError::NotObject),
+  }
+}
+
+#[derive(Default)]
+pub struct TransformChain {
+  steps: Vec<TransformStep>,
+  cipher: Option<ShareCipher>,
+}
+
+impl TransformChain {
+  fn new(steps: Vec<TransformStep>, cipher: Option<ShareCipher>) -> Self {
+    Self { steps, cipher }
+  }
+
+  /// Loads the chain from `LAKE_SINK_TRANSFORMS`. Panics if the chain is invalid,
+  /// or if the encryption key is required but cannot be loaded.
+  pub fn from_env() -> Self {
+    let config = env::var(LAKE_SINK_TRANSFORMS_ENV_KEY).unwrap_or_default();
+    let steps = parse_steps(&config)
+      .unwrap_or_else(|e| panic!("invalid {}: {}", LAKE_SINK_TRANSFORMS_ENV_KEY, e));
+    let cipher = steps.contains(&TransformStep::Encrypt).then(|| {
+      let path = env::var(LAKE_SINK_ENCRYPTION_KEY_FILE_ENV_KEY).unwrap_or_else(|_| {
+        panic!(
+          "{} must be set for the encrypt transform",
+          LAKE_SINK_ENCRYPTION_KEY_FILE_ENV_KEY
+        )
+      });
+      let contents = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+          "failed to read lake sink encryption key file {}: {}",
+          path, e
+        )
+      });
+      ShareCipher::new(
+        &hex::decode(contents.trim()).expect("lake sink encryption key file should contain hex"),
+      )
+    });
+    if !steps.is_empty() {
+      info!("Lake sink transforms: {}", config);
+    }
+    Self::new(steps, cipher)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.steps.is_empty()
+  }
+
+  /// Applies each step of the chain to the record.
+  pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, TransformError> {
+    let mut data = data.to_vec();
+    for step in &self.steps {
+      data = match step {
+        TransformStep::Strip(fields) => {
+          let mut map = parse_object(&data)?;
+          for field in fields {
+            map.remove(field);
+          }
+          serde_json::to_vec(&map)?
+        }
+        TransformStep::Redact(fields) => {
+          let mut map = parse_object(&data)?;
+          for field in fields {
+            if let Some(value) = map.get_mut(field) {
+              *value = REDACTED_VALUE.into();
+            }
+          }
+          serde_json::to_vec(&map)?
+        }
+        TransformStep::Reencode(Encoding::Json) => {
+          serde_json::to_vec(&serde_json::from_slice::<Value>(&data)?)?
+        }
+        TransformStep::Reencode(Encoding::Base64) => {
+          serde_json::to_vec(&base64_engine::STANDARD.encode(&data))?
+        }
+        TransformStep::Encrypt => {
+          let cipher = self
+            .cipher
+            .as_ref()
+            .expect("cipher should be loaded for encrypt transform");
+          let encrypted = base64_engine::STANDARD.encode(cipher.encrypt(&data));
+          serde_json::to_vec(&json!({ "encrypted": encrypted }))?
+        }
+      };
+    }
+    Ok(data)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_chain() {
+    assert_eq!(
+      parse_steps("strip:a|b, redact:c,reencode:base64,encrypt").unwrap(),
+      vec![
+        TransformStep::Strip(vec!["a".to_string(), "b".to_string()]),
+        TransformStep::Redact(vec!["c".to_string()]),
+        TransformStep::Reencode(Encoding::Base64),
+        TransformStep::Encrypt,
+      ]
+    );
+    assert!(parse_steps("").unwrap().is_empty());
+    assert!(parse_steps("strip").is_err());
+    assert!(parse_steps("reencode:xml").is_err());
+    assert!(parse_steps("compress").is_err());
+  }
+
+  #[test]
+  fn apply_chain() {
+    let record = br#"{"total":2,"country":"CA","os":"linux","wos":"2024-01-01"}"#;
+    let chain = TransformChain::new(
+      parse_steps("strip:os,redact:country|missing").unwrap(),
+      None,
+    );
+    assert_eq!(
+      String::from_utf8(chain.apply(record).unwrap()).unwrap(),
+      r#"{"country":"[redacted]","total":2,"wos":"2024-01-01"}"#
+    );
+
+    let chain = TransformChain::new(parse_steps("reencode:base64").unwrap(), None);
+    let encoded: String = serde_json::from_slice(&chain.apply(record).unwrap()).unwrap();
+    assert_eq!(
+      base64_engine::STANDARD.decode(encoded).unwrap(),
+      record.to_vec()
+    );
+
+    let key = [7u8; 32];
+    let chain = TransformChain::new(
+      parse_steps("strip:os,encrypt").unwrap(),
+      Some(ShareCipher::new(&key)),
+    );
+    let stored: Value = serde_json::from_slice(&chain.apply(record).unwrap()).unwrap();
+    let encrypted = base64_engine::STANDARD
+      .decode(stored["encrypted"].as_str().unwrap())
+      .unwrap();
+    let decrypted = ShareCipher::new(&key).decrypt(&encrypted).unwrap();
+    assert_eq!(parse_object(&decrypted).unwrap().get("os"), None::<&Value>);
+
+    let chain = TransformChain::new(parse_steps("strip:os").unwrap(), None);
+    assert!(matches!(
+      chain.apply(b"[1]"),
+      Err(TransformError::NotObject)
+    ));
+  }
+}
@@ -0,0 +1,56 @@
+//! Library target of the processors, so that the submission server can be
+//! embedded with extra routes and middleware via `server::ServerBuilder`.
+
+pub mod aggregator;
+pub mod canary;
+mod channel;
+mod channel_info;
+mod encryption;
+pub mod epoch;
+mod faulty_stream;
+mod file_stream;
+mod format_version;
+mod idempotency;
+mod kafka_group;
+mod kafka_rack;
+mod kafka_security;
+mod kafka_topics;
+mod kinesis;
+pub mod lake;
+pub mod lakesink;
+mod lakesink_transform;
+pub mod memory_watchdog;
+mod message_key;
+mod models;
+mod nats;
+mod pipeline_io;
+mod produce_rate_limit;
+mod produce_retry;
+mod profiler;
+pub mod progress;
+pub mod prometheus;
+mod pubsub;
+mod rate_limit;
+mod receipt;
+mod record_codec;
+mod record_compression;
+pub mod record_encryption;
+pub mod record_stream;
+mod redis;
+mod redis_stream;
+pub mod retention;
+mod rollup;
+mod schema;
+pub mod server;
+mod sqs;
+mod star;
+pub mod startup;
+mod submission_budget;
+pub mod tenant;
+pub mod util;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate diesel;
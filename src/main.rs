@@ -1,34 +1,45 @@
-mod aggregator;
-mod channel;
-mod epoch;
-mod lake;
-mod lakesink;
-mod models;
-mod profiler;
-mod prometheus;
-mod record_stream;
-mod schema;
-mod server;
-mod star;
-mod util;
-
-use aggregator::start_aggregation;
+use actix_web::web::ServiceConfig;
+use aggregator::{
+  configure_knobs_admin, diff_runs, export_epoch_keys, print_epoch_counts, print_privacy_report,
+  redrive_dead_letters, rewrite_pending_messages, start_aggregation, AggregatorError,
+  AggregatorKnobs, DATABASE_UNAVAILABLE_EXIT_CODE,
+};
+use canary::{canary_channel, monitor_canary_output};
 use clap::{ArgGroup, Parser};
+use constellation_processors::{
+  aggregator, canary, epoch, lake, lakesink, memory_watchdog, progress, prometheus,
+  record_encryption, record_stream, retention, server, startup, tenant, util,
+};
 use dotenvy::dotenv;
 use env_logger::Env;
 use env_logger::Target;
 use epoch::EpochConfig;
 use futures::future::try_join_all;
-use lakesink::start_lakesink;
-use prometheus::{create_metric_server, DataLakeMetrics};
-use prometheus_client::registry::Registry;
-use record_stream::get_data_channel_topic_map_from_env;
+use lake::DataLake;
+use lakesink::{message_archive_enabled, start_lakesink, LakeSinkConfig, LakeSinkKind};
+use memory_watchdog::init_memory_watchdog;
+use progress::{init_progress, ProgressMode};
+use prometheus::{
+  create_metric_server, init_io_metrics, init_kafka_metrics, AggregatorMetrics, IoMetrics,
+  KafkaMetrics, LakeMetrics, LakeSinkMetrics, MemoryMetrics, MetricsRegistry,
+};
+use record_encryption::init_record_encryption;
+use record_stream::{
+  get_data_channel_input_topics_map_from_env, get_data_channel_topic_map_from_env,
+};
+use retention::enforce_retention;
 use server::start_server;
+use startup::wait_for_lake;
 use std::env;
+use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
+use tenant::is_valid_tenant_name;
+use time::format_description::well_known::Iso8601;
+use time::Date;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
+use util::parse_env_var;
 
 use jemallocator::Jemalloc;
 #[global_allocator]
@@ -37,10 +48,9 @@ static GLOBAL: Jemalloc = Jemalloc;
 #[macro_use]
 extern crate log;
 
-#[macro_use]
-extern crate diesel;
-
 const SENTRY_DSN_ENV_KEY: &str = "SENTRY_DSN";
+const BACKGROUND_METRICS_PORT_ENV_KEY: &str = "BACKGROUND_METRICS_PORT";
+const BACKGROUND_METRICS_PORT_DEFAULT: &str = "9089";
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, about)]
@@ -48,7 +58,19 @@ const SENTRY_DSN_ENV_KEY: &str = "SENTRY_DSN";
     ArgGroup::new("process-mode")
       .required(true)
       .multiple(true)
-      .args(&["aggregator", "lake_sink", "server"])
+      .args(&[
+        "aggregator",
+        "lake_sink",
+        "server",
+        "privacy_report_epoch",
+        "epoch_counts",
+        "export_epoch_keys",
+        "dlq_redrive",
+        "rewrite_pending_msgs",
+        "compact_date",
+        "enforce_retention",
+        "diff_runs"
+      ])
 ))]
 struct CliArgs {
   #[clap(short, long, help = "Enable server mode")]
@@ -74,6 +96,13 @@ struct CliArgs {
   )]
   output_measurements_to_stdout: bool,
 
+  #[clap(
+    long,
+    value_name = "DIR",
+    help = "Output aggregated measurements to files in a local directory instead of Kafka"
+  )]
+  output_measurements_to_dir: Option<String>,
+
   #[clap(long, default_value = "16", help = "Worker task count for aggregator")]
   agg_worker_count: usize,
 
@@ -92,6 +121,112 @@ struct CliArgs {
 
   #[clap(long, help = "Current epoch value to use for testing purposes")]
   test_epoch: Option<u8>,
+
+  #[clap(
+    long,
+    help = "Only aggregate messages from this epoch. Messages from other epochs will be discarded."
+  )]
+  target_epoch: Option<u8>,
+
+  #[clap(
+    long,
+    help = "Source messages from the lake message archive before consuming from Kafka. See README for details."
+  )]
+  lake_first: bool,
+
+  #[clap(
+    long,
+    help = "Finalize expired epochs even if they were processed with different configurations"
+  )]
+  force_epoch_finalization: bool,
+
+  #[clap(
+    long,
+    help = "Load recovered messages of the target epochs into memory before the first aggregator iteration"
+  )]
+  warm_start: bool,
+
+  #[clap(
+    long,
+    help = "Allow reprocessing of finalized epochs, i.e. when replaying old data. Output is sent to the reprocessing destinations. See README for details."
+  )]
+  allow_refinalize: bool,
+
+  #[clap(
+    long,
+    value_enum,
+    default_value = "auto",
+    help = "Aggregator progress output, written to stderr"
+  )]
+  progress: ProgressMode,
+
+  #[clap(
+    long,
+    help = "Print the privacy report for an epoch of the main channel, and exit"
+  )]
+  privacy_report_epoch: Option<u8>,
+
+  #[clap(
+    long,
+    help = "Print the pending and recovered message counts of each epoch of the main channel, and exit"
+  )]
+  epoch_counts: bool,
+
+  #[clap(
+    long,
+    help = "Export the recovered keys of an epoch of the main channel to the lake, encrypted via KMS, and exit. See README for details."
+  )]
+  export_epoch_keys: Option<u8>,
+
+  #[clap(
+    long,
+    help = "Re-drive valid records from the dead letter topic of the main channel to its encrypted topic, and exit"
+  )]
+  dlq_redrive: bool,
+
+  #[clap(
+    long,
+    help = "Rewrite the pending messages of the main channel that are stored in formats other than PENDING_MSG_FORMAT, and exit"
+  )]
+  rewrite_pending_msgs: bool,
+
+  #[clap(
+    long,
+    value_parser = parse_date,
+    help = "Compact the data lake objects of the main channel for a date (YYYY-MM-DD), and exit"
+  )]
+  compact_date: Option<Date>,
+
+  #[clap(
+    long,
+    help = "Purge lake objects, database rows and dead letter records older than the retention policy of each channel, and exit. See README for details."
+  )]
+  enforce_retention: bool,
+
+  #[clap(
+    long,
+    requires = "enforce_retention",
+    help = "Only report the data that would be purged by --enforce-retention"
+  )]
+  retention_dry_run: bool,
+
+  #[clap(
+    long,
+    num_args = 2,
+    value_names = ["BASELINE", "CANDIDATE"],
+    help = "Compare the measurement output of two runs (JSON lines files or directories), print the differences, and exit. Exits with an error if the differences exceed the tolerances. See README for details."
+  )]
+  diff_runs: Option<Vec<PathBuf>>,
+
+  #[clap(
+    long,
+    help = "Tenant to run the aggregator or lake sink for. See README for details on tenancy."
+  )]
+  tenant: Option<String>,
+}
+
+fn parse_date(value: &str) -> Result<Date, String> {
+  Date::parse(value, &Iso8601::DATE).map_err(|e| e.to_string())
 }
 
 #[tokio::main]
@@ -103,62 +238,243 @@ async fn main() {
     .target(Target::Stderr)
     .init();
 
-  let _sentry_guard = env::var(SENTRY_DSN_ENV_KEY)
-    .ok()
-    .map(|dsn| sentry::init(dsn));
+  let _sentry_guard = env::var(SENTRY_DSN_ENV_KEY).ok().map(sentry::init);
+
+  if let Some(tenant) = cli_args.tenant.as_ref() {
+    if !is_valid_tenant_name(tenant) {
+      error!("Tenant name must only contain lowercase alphanumeric characters or underscores");
+      process::exit(1);
+    }
+  }
+
+  init_record_encryption().await;
+
+  if let Some(epoch) = cli_args.privacy_report_epoch {
+    let epoch_config = EpochConfig::new(cli_args.test_epoch, &cli_args.main_channel_name).await;
+    print_privacy_report(&epoch_config, epoch, cli_args.tenant.as_deref())
+      .await
+      .unwrap();
+    return;
+  }
+
+  if cli_args.epoch_counts {
+    let epoch_config = EpochConfig::new(cli_args.test_epoch, &cli_args.main_channel_name).await;
+    print_epoch_counts(&epoch_config, cli_args.tenant.as_deref())
+      .await
+      .unwrap();
+    return;
+  }
+
+  if let Some(epoch) = cli_args.export_epoch_keys {
+    let epoch_config = EpochConfig::new(cli_args.test_epoch, &cli_args.main_channel_name).await;
+    export_epoch_keys(&epoch_config, epoch, cli_args.tenant.as_deref())
+      .await
+      .unwrap();
+    return;
+  }
+
+  if cli_args.dlq_redrive {
+    let epoch_config = EpochConfig::new(cli_args.test_epoch, &cli_args.main_channel_name).await;
+    redrive_dead_letters(&epoch_config, cli_args.tenant.as_deref())
+      .await
+      .unwrap();
+    return;
+  }
+
+  if cli_args.rewrite_pending_msgs {
+    rewrite_pending_messages(&cli_args.main_channel_name, cli_args.tenant.as_deref())
+      .await
+      .unwrap();
+    return;
+  }
+
+  if let Some(date) = cli_args.compact_date {
+    DataLake::new(cli_args.tenant.clone(), None)
+      .compact(&cli_args.main_channel_name, date)
+      .await
+      .unwrap();
+    return;
+  }
+
+  if cli_args.enforce_retention {
+    enforce_retention(cli_args.tenant.as_deref(), cli_args.retention_dry_run)
+      .await
+      .unwrap();
+    return;
+  }
+
+  if let Some(paths) = cli_args.diff_runs.as_ref() {
+    if !diff_runs(&paths[0], &paths[1]).unwrap() {
+      process::exit(1);
+    }
+    return;
+  }
 
   let mut dl_tasks = Vec::new();
-  let mut dl_metrics_server: Option<JoinHandle<_>> = None;
+  let mut metrics_server: Option<JoinHandle<_>> = None;
+
+  let modes: Vec<&str> = [
+    (cli_args.server, "server"),
+    (cli_args.lake_sink, "lake-sink"),
+    (cli_args.aggregator, "aggregator"),
+  ]
+  .into_iter()
+  .filter_map(|(enabled, mode)| enabled.then_some(mode))
+  .collect();
+  let mut registry = MetricsRegistry::new(&modes);
+  let dl_metrics = Arc::new(LakeSinkMetrics::default());
+  let lake_metrics = Arc::new(LakeMetrics::default());
+  if cli_args.lake_sink {
+    registry.register(dl_metrics.as_ref());
+    registry.register(lake_metrics.as_ref());
+  }
+  let agg_metrics = Arc::new(AggregatorMetrics::default());
+  if cli_args.aggregator {
+    registry.register(agg_metrics.as_ref());
+  }
+  if cli_args.lake_sink || cli_args.aggregator {
+    let memory_metrics = Arc::new(MemoryMetrics::default());
+    registry.register(memory_metrics.as_ref());
+    init_memory_watchdog(memory_metrics);
+  }
+  let kafka_metrics = Arc::new(KafkaMetrics::default());
+  registry.register(kafka_metrics.as_ref());
+  init_kafka_metrics(kafka_metrics);
+  let io_metrics = Arc::new(IoMetrics::default());
+  registry.register(io_metrics.as_ref());
+  init_io_metrics(io_metrics);
+  // Knobs of the aggregator, adjustable via the admin endpoints of the background listener
+  let knobs = cli_args.aggregator.then(|| {
+    Arc::new(AggregatorKnobs::new(
+      cli_args.agg_worker_count,
+      cli_args.agg_msg_collect_count,
+    ))
+  });
+  // The server exports the metrics of all modes via its own metrics listener.
+  // Background modes need a separate listener if the server is not started,
+  // so that they can be scraped and health checked.
+  let mut server_registry = None;
+  if cli_args.server && !cli_args.aggregator {
+    server_registry = Some(registry);
+  } else if cli_args.lake_sink || cli_args.aggregator {
+    let port = parse_env_var::<u16>(
+      BACKGROUND_METRICS_PORT_ENV_KEY,
+      BACKGROUND_METRICS_PORT_DEFAULT,
+    );
+    let knobs = knobs.clone();
+    let configure = move |config: &mut ServiceConfig| {
+      if let Some(knobs) = knobs.as_ref() {
+        configure_knobs_admin(config, knobs.clone());
+      }
+    };
+    metrics_server = Some(tokio::spawn(
+      create_metric_server(registry, port, configure).unwrap(),
+    ));
+  }
 
   let mut lakesink_cancel_tokens = Vec::new();
   if cli_args.lake_sink {
-    let mut registry = <Registry>::default();
-    let dl_metrics = Arc::new(DataLakeMetrics::default());
-    dl_metrics.register_metrics(&mut registry);
-
-    dl_metrics_server = Some(tokio::spawn(create_metric_server(registry, 9089).unwrap()));
-
-    let data_channel_topic_map = get_data_channel_topic_map_from_env(true);
-
-    for (channel_name, topic_name) in data_channel_topic_map {
-      let dl_metrics = dl_metrics.clone();
-
-      let cancel_token = CancellationToken::new();
-      let cloned_token = cancel_token.clone();
-      dl_tasks.push(tokio::spawn(async move {
-        info!("Starting lake sink for '{}' channel...", channel_name);
-        let res = start_lakesink(
-          channel_name,
-          topic_name,
-          dl_metrics,
-          cloned_token.clone(),
-          cli_args.output_measurements_to_stdout,
-        )
-        .await;
-        if let Err(e) = res {
-          error!("Lake sink task failed: {:?}", e);
-          process::exit(1);
-        }
-      }));
-      lakesink_cancel_tokens.push(cancel_token);
+    let mut lakesink_configs: Vec<LakeSinkConfig> = get_data_channel_topic_map_from_env(true)
+      .into_iter()
+      .map(|(channel_name, topic_name)| {
+        LakeSinkConfig::new(channel_name, topic_name, LakeSinkKind::Measurements)
+      })
+      .collect();
+    if let Some(canary_channel) = canary_channel() {
+      info!("Monitoring canary output for '{}' channel", canary_channel);
+      tokio::spawn(monitor_canary_output(canary_channel, dl_metrics.clone()));
+    }
+    if message_archive_enabled() {
+      lakesink_configs.extend(
+        get_data_channel_input_topics_map_from_env()
+          .into_iter()
+          .flat_map(|(channel_name, topics)| {
+            topics.into_iter().map(move |topic_name| {
+              LakeSinkConfig::new(
+                channel_name.clone(),
+                topic_name,
+                LakeSinkKind::MessageArchive,
+              )
+            })
+          }),
+      );
+    }
+
+    if !cli_args.output_measurements_to_stdout {
+      // Records are not consumed until they can be stored
+      let lake = DataLake::new(cli_args.tenant.clone(), None);
+      if let Err(e) = wait_for_lake(&lake).await {
+        error!("Lake sink startup failed: {}", e);
+        process::exit(1);
+      }
+    }
+
+    for lakesink_config in lakesink_configs {
+      let consumer_count = lakesink_config.consumer_count(cli_args.tenant.as_deref());
+      for consumer_index in 0..consumer_count {
+        let lakesink_config = lakesink_config.clone().with_consumer_index(consumer_index);
+        let dl_metrics = dl_metrics.clone();
+        let lake_metrics = lake_metrics.clone();
+        let tenant = cli_args.tenant.clone();
+
+        let cancel_token = CancellationToken::new();
+        let cloned_token = cancel_token.clone();
+        dl_tasks.push(tokio::spawn(async move {
+          info!(
+            "Starting {:?} lake sink {} for '{}' channel (batch size: {})...",
+            lakesink_config.kind,
+            lakesink_config.consumer_index,
+            lakesink_config.channel_name,
+            lakesink_config.batch_size
+          );
+          let res = start_lakesink(
+            lakesink_config,
+            dl_metrics,
+            lake_metrics,
+            cloned_token.clone(),
+            cli_args.output_measurements_to_stdout,
+            tenant,
+          )
+          .await;
+          if let Err(e) = res {
+            error!("Lake sink task failed [{}]: {}", e.category(), e);
+            process::exit(1);
+          }
+        }));
+        lakesink_cancel_tokens.push(cancel_token);
+      }
     }
   }
 
   if cli_args.aggregator {
+    init_progress(cli_args.progress);
     let epoch_config =
       Arc::new(EpochConfig::new(cli_args.test_epoch, &cli_args.main_channel_name).await);
     start_aggregation(
       &cli_args.main_channel_name,
-      cli_args.agg_worker_count,
-      cli_args.agg_msg_collect_count,
+      knobs.unwrap(),
+      agg_metrics,
       cli_args.agg_iterations,
       cli_args.output_measurements_to_stdout,
+      cli_args.output_measurements_to_dir.as_deref(),
       epoch_config,
+      cli_args.tenant.as_deref(),
+      cli_args.target_epoch,
+      cli_args.lake_first,
+      cli_args.force_epoch_finalization,
+      cli_args.warm_start,
+      cli_args.allow_refinalize,
     )
     .await
-    .unwrap();
+    .unwrap_or_else(|e| {
+      if let AggregatorError::DatabaseUnavailable(_) = e {
+        error!("Aggregation failed [{}]: {}", e.category(), e);
+        process::exit(DATABASE_UNAVAILABLE_EXIT_CODE);
+      }
+      panic!("Aggregation failed [{}]: {}", e.category(), e)
+    });
     if cli_args.lake_sink {
-      dl_metrics_server.unwrap().await.unwrap().unwrap();
+      metrics_server.unwrap().await.unwrap().unwrap();
       lakesink_cancel_tokens.iter().for_each(|t| t.cancel());
       try_join_all(dl_tasks).await.unwrap();
     }
@@ -166,11 +482,15 @@ async fn main() {
   }
 
   if cli_args.server {
-    start_server(cli_args.server_worker_count, cli_args.main_channel_name)
-      .await
-      .unwrap();
+    start_server(
+      cli_args.server_worker_count,
+      cli_args.main_channel_name,
+      server_registry.unwrap(),
+    )
+    .await
+    .unwrap();
   } else if cli_args.lake_sink {
-    dl_metrics_server.unwrap().await.unwrap().unwrap();
+    metrics_server.unwrap().await.unwrap().unwrap();
     lakesink_cancel_tokens.iter().for_each(|t| t.cancel());
     try_join_all(dl_tasks).await.unwrap();
   }
@@ -0,0 +1,173 @@
+//! Memory watchdog for the aggregator and the lake sink. If `MEMORY_LIMIT_MB` is set,
+//! the resident set size of the process is sampled periodically, and the process is
+//! considered to be under memory pressure while the RSS exceeds `MEMORY_PRESSURE_PERCENT`
+//! of the limit. Components react to memory pressure, so that the process is not
+//! OOM-killed by the kernel in the middle of a commit:
+//!
+//! - The aggregator halves the collect count of the next iteration, and stops
+//!   collecting messages for the current iteration.
+//! - The lake sink flushes its current batch, and pauses consumption until the
+//!   pressure subsides.
+//!
+//! Each intervention is logged and counted in the `memory_watchdog_interventions_total`
+//! metric. The RSS is read from `/proc/self/status`, so the watchdog only works on Linux.
+
+use crate::prometheus::MemoryMetrics;
+use crate::util::parse_env_var;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+const MEMORY_LIMIT_MB_ENV_KEY: &str = "MEMORY_LIMIT_MB";
+const MEMORY_PRESSURE_PERCENT_ENV_KEY: &str = "MEMORY_PRESSURE_PERCENT";
+const DEFAULT_MEMORY_PRESSURE_PERCENT: &str = "85";
+const MEMORY_CHECK_INTERVAL_MS_ENV_KEY: &str = "MEMORY_CHECK_INTERVAL_MS";
+const DEFAULT_MEMORY_CHECK_INTERVAL_MS: &str = "1000";
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+static WATCHDOG: OnceLock<MemoryWatchdog> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Intervention {
+  ShrinkCollectCount,
+  EndCollection,
+  FlushBatch,
+  PauseConsumption,
+}
+
+impl Intervention {
+  pub fn action(&self) -> &'static str {
+    match self {
+      Self::ShrinkCollectCount => "shrink_collect_count",
+      Self::EndCollection => "end_collection",
+      Self::FlushBatch => "flush_batch",
+      Self::PauseConsumption => "pause_consumption",
+    }
+  }
+}
+
+struct MemoryWatchdog {
+  pressure_bytes: u64,
+  under_pressure: AtomicBool,
+  metrics: Arc<MemoryMetrics>,
+}
+
+impl MemoryWatchdog {
+  fn check(&self) {
+    let rss_bytes = match read_rss_bytes() {
+      Some(rss_bytes) => rss_bytes,
+      None => return,
+    };
+    self.metrics.set_rss_bytes(rss_bytes);
+    let under_pressure = rss_bytes >= self.pressure_bytes;
+    let was_under_pressure = self.under_pressure.swap(under_pressure, Ordering::Relaxed);
+    match (was_under_pressure, under_pressure) {
+      (false, true) => warn!(
+        "Memory pressure: RSS of {} MB exceeds {} MB",
+        rss_bytes / BYTES_PER_MB,
+        self.pressure_bytes / BYTES_PER_MB
+      ),
+      (true, false) => info!(
+        "Memory pressure subsided: RSS is {} MB",
+        rss_bytes / BYTES_PER_MB
+      ),
+      _ => {}
+    }
+    self.metrics.set_under_pressure(under_pressure);
+  }
+}
+
+/// Returns the value of the `VmRSS` field of a `/proc/<pid>/status` file, in bytes.
+fn parse_vm_rss(status: &str) -> Option<u64> {
+  let kb = status
+    .lines()
+    .find_map(|line| line.strip_prefix("VmRSS:"))?
+    .trim()
+    .strip_suffix("kB")?
+    .trim()
+    .parse::<u64>()
+    .ok()?;
+  Some(kb * 1024)
+}
+
+fn read_rss_bytes() -> Option<u64> {
+  parse_vm_rss(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// Starts sampling the RSS of the process, if `MEMORY_LIMIT_MB` is set.
+/// Must be called within the Tokio runtime.
+pub fn init_memory_watchdog(metrics: Arc<MemoryMetrics>) {
+  let limit_mb = match env::var(MEMORY_LIMIT_MB_ENV_KEY) {
+    Ok(limit_mb) => limit_mb
+      .parse::<u64>()
+      .unwrap_or_else(|_| panic!("{} must be a positive integer", MEMORY_LIMIT_MB_ENV_KEY)),
+    Err(_) => return,
+  };
+  let pressure_percent = parse_env_var::<u64>(
+    MEMORY_PRESSURE_PERCENT_ENV_KEY,
+    DEFAULT_MEMORY_PRESSURE_PERCENT,
+  );
+  assert!(
+    (1..=100).contains(&pressure_percent),
+    "{} must be between 1 and 100",
+    MEMORY_PRESSURE_PERCENT_ENV_KEY
+  );
+  let check_interval = Duration::from_millis(parse_env_var(
+    MEMORY_CHECK_INTERVAL_MS_ENV_KEY,
+    DEFAULT_MEMORY_CHECK_INTERVAL_MS,
+  ));
+  if read_rss_bytes().is_none() {
+    warn!("Process RSS is not available, memory watchdog is disabled");
+    return;
+  }
+  let limit_bytes = limit_mb * BYTES_PER_MB;
+  metrics.set_limit_bytes(limit_bytes);
+  let watchdog = WATCHDOG.get_or_init(|| MemoryWatchdog {
+    pressure_bytes: limit_bytes * pressure_percent / 100,
+    under_pressure: AtomicBool::new(false),
+    metrics,
+  });
+  info!(
+    "Memory watchdog enabled: limit {} MB, pressure at {}%",
+    limit_mb, pressure_percent
+  );
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(check_interval);
+    loop {
+      interval.tick().await;
+      watchdog.check();
+    }
+  });
+}
+
+/// Returns true if the process is under memory pressure, as of the last check.
+pub fn memory_pressure() -> bool {
+  WATCHDOG
+    .get()
+    .map(|v| v.under_pressure.load(Ordering::Relaxed))
+    .unwrap_or(false)
+}
+
+/// Logs and counts an intervention made due to memory pressure.
+pub fn record_intervention(intervention: Intervention) {
+  if let Some(watchdog) = WATCHDOG.get() {
+    warn!("Memory pressure intervention: {}", intervention.action());
+    watchdog.metrics.intervention(intervention.action());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn vm_rss() {
+    let status = "Name:\tconstellation\nVmPeak:\t 2048000 kB\nVmRSS:\t  524288 kB\nThreads:\t12\n";
+    assert_eq!(parse_vm_rss(status), Some(512 * BYTES_PER_MB));
+    assert_eq!(parse_vm_rss("Name:\tconstellation\n"), None);
+    assert!(read_rss_bytes().unwrap() > 0);
+  }
+}
@@ -0,0 +1,74 @@
+//! Keys of produced encrypted messages. By default, messages are produced without
+//! a key, and are spread across all partitions of the encrypted topic. If
+//! `MESSAGE_KEY_MODE` is set, messages are keyed by their epoch or by a prefix
+//! of their STAR tag, so that messages for the same tag land on the same partition.
+//! Aggregator consumers will then receive all messages of a tag, which improves
+//! the locality of grouping and pending message lookups.
+
+use crate::util::parse_env_var;
+use star_constellation::api::NestedMessage;
+use std::str::FromStr;
+
+const MESSAGE_KEY_MODE_ENV_KEY: &str = "MESSAGE_KEY_MODE";
+const DEFAULT_MESSAGE_KEY_MODE: &str = "none";
+const MESSAGE_KEY_TAG_PREFIX_LEN_ENV_KEY: &str = "MESSAGE_KEY_TAG_PREFIX_LEN";
+const DEFAULT_MESSAGE_KEY_TAG_PREFIX_LEN: &str = "8";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKeyMode {
+  None,
+  Epoch,
+  /// Hex encoded prefix of the tag, with the given length in bytes
+  Tag(usize),
+}
+
+impl FromStr for MessageKeyMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Self::None),
+      "epoch" => Ok(Self::Epoch),
+      "tag" => Ok(Self::Tag(parse_env_var(
+        MESSAGE_KEY_TAG_PREFIX_LEN_ENV_KEY,
+        DEFAULT_MESSAGE_KEY_TAG_PREFIX_LEN,
+      ))),
+      _ => Err(format!("unknown message key mode '{}'", s)),
+    }
+  }
+}
+
+impl MessageKeyMode {
+  pub fn from_env() -> Self {
+    parse_env_var(MESSAGE_KEY_MODE_ENV_KEY, DEFAULT_MESSAGE_KEY_MODE)
+  }
+
+  pub fn message_key(&self, message: &NestedMessage) -> Option<String> {
+    self.key(&message.unencrypted_layer.tag, message.epoch)
+  }
+
+  fn key(&self, tag: &[u8], epoch: u8) -> Option<String> {
+    match self {
+      Self::None => None,
+      Self::Epoch => Some(epoch.to_string()),
+      Self::Tag(prefix_len) => Some(hex::encode(&tag[..tag.len().min(*prefix_len)])),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn message_keys() {
+    let tag = [0xab, 0xcd, 0xef, 0x01];
+    assert_eq!(MessageKeyMode::None.key(&tag, 3), None);
+    assert_eq!(MessageKeyMode::Epoch.key(&tag, 3).as_deref(), Some("3"));
+    assert_eq!(MessageKeyMode::Tag(2).key(&tag, 3).as_deref(), Some("abcd"));
+    assert_eq!(
+      MessageKeyMode::Tag(8).key(&tag, 3).as_deref(),
+      Some("abcdef01")
+    );
+  }
+}
@@ -0,0 +1,76 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use crate::schema::epoch_config_snapshots;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use tokio::task;
+
+#[allow(dead_code)]
+#[derive(Queryable, Debug, Clone)]
+pub struct EpochConfigSnapshot {
+  pub channel_name: String,
+  pub epoch: i16,
+  pub config: String,
+  pub created_at: OffsetDateTime,
+  pub finalized: bool,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = epoch_config_snapshots)]
+pub struct NewEpochConfigSnapshot {
+  pub channel_name: String,
+  pub epoch: i16,
+  pub config: String,
+  pub finalized: bool,
+}
+
+impl EpochConfigSnapshot {
+  pub async fn list_for_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    filter_epoch: i16,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::epoch_config_snapshots::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        epoch_config_snapshots
+          .filter(channel_name.eq(filter_channel_name))
+          .filter(epoch.eq(filter_epoch))
+          .order(created_at.asc())
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
+  /// Records the snapshots, if they have not been recorded yet. If a snapshot is
+  /// marked as finalized, the existing snapshot will be marked as finalized as well.
+  pub async fn record_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    snapshots: Vec<NewEpochConfigSnapshot>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      use crate::schema::epoch_config_snapshots::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      for snapshot in snapshots {
+        let is_finalized = snapshot.finalized;
+        let query = diesel::insert_into(epoch_config_snapshots)
+          .values(snapshot)
+          .on_conflict((channel_name, epoch, config));
+        match is_finalized {
+          true => query
+            .do_update()
+            .set(finalized.eq(true))
+            .execute(conn.deref_mut())?,
+          false => query.do_nothing().execute(conn.deref_mut())?,
+        };
+      }
+      Ok(())
+    })
+    .await?
+  }
+}
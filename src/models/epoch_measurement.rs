@@ -0,0 +1,81 @@
+use super::{BatchInsert, DBConnection};
+use crate::models::PgStoreError;
+use crate::profiler::Profiler;
+use crate::schema::epoch_measurements;
+use async_trait::async_trait;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = epoch_measurements)]
+pub struct NewEpochMeasurement {
+  pub channel_name: String,
+  pub epoch: i16,
+  pub measurement: Vec<u8>,
+}
+
+pub struct EpochMeasurement;
+
+impl EpochMeasurement {
+  /// Returns all retained measurements for the epoch, in the order they were reported.
+  pub async fn list_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    filter_epoch: i16,
+  ) -> Result<Vec<Vec<u8>>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::epoch_measurements::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        epoch_measurements
+          .filter(channel_name.eq(filter_channel_name))
+          .filter(epoch.eq(filter_epoch))
+          .order(id.asc())
+          .select(measurement)
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
+  pub async fn delete_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    filter_epoch: i16,
+  ) -> Result<(), PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::epoch_measurements::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      diesel::delete(
+        epoch_measurements
+          .filter(channel_name.eq(filter_channel_name))
+          .filter(epoch.eq(filter_epoch)),
+      )
+      .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?
+  }
+}
+
+#[async_trait]
+impl BatchInsert<NewEpochMeasurement> for Vec<NewEpochMeasurement> {
+  async fn insert_batch(
+    self,
+    conn: Arc<Mutex<DBConnection>>,
+    _profiler: Arc<Profiler>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      diesel::insert_into(epoch_measurements::table)
+        .values(self)
+        .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?
+  }
+}
@@ -13,4 +13,8 @@ pub enum PgStoreError {
   PoolTimeout,
   #[display(fmt = "failed to apply migrations")]
   Migration,
+  #[display(fmt = "connection error: {}", "_0")]
+  Connection(diesel::ConnectionError),
+  #[display(fmt = "database is read-only")]
+  ReadOnly,
 }
@@ -0,0 +1,73 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::{BigInt, Text};
+use diesel::RunQueryDsl;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+/// Tables that are mutated in bulk during aggregation
+pub const MAINTAINED_TABLES: [&str; 4] = [
+  "pending_msgs",
+  "recovered_msgs",
+  "measurement_outbox",
+  "epoch_measurements",
+];
+
+#[derive(QueryableByName, Debug, Clone, PartialEq)]
+pub struct TableStats {
+  #[diesel(sql_type = Text)]
+  pub table_name: String,
+  #[diesel(sql_type = BigInt)]
+  pub live_tuples: i64,
+  #[diesel(sql_type = BigInt)]
+  pub dead_tuples: i64,
+}
+
+impl TableStats {
+  pub fn dead_ratio(&self) -> f64 {
+    match self.live_tuples + self.dead_tuples {
+      0 => 0.0,
+      total => self.dead_tuples as f64 / total as f64,
+    }
+  }
+
+  /// Runs `ANALYZE` on the given tables, so that the planner statistics
+  /// reflect recent bulk mutations. Table names must be trusted.
+  pub async fn analyze(
+    conn: Arc<Mutex<DBConnection>>,
+    tables: &'static [&'static str],
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      conn
+        .deref_mut()
+        .batch_execute(&format!("ANALYZE {}", tables.join(", ")))?;
+      Ok(())
+    })
+    .await?
+  }
+
+  /// Returns the live & dead tuple estimates for the given tables
+  /// in the current schema.
+  pub async fn list(
+    conn: Arc<Mutex<DBConnection>>,
+    tables: &'static [&'static str],
+  ) -> Result<Vec<Self>, PgStoreError> {
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        diesel::sql_query(
+          "SELECT relname::text AS table_name, n_live_tup AS live_tuples, \
+           n_dead_tup AS dead_tuples FROM pg_stat_user_tables \
+           WHERE schemaname = current_schema() AND relname = ANY(string_to_array($1, ',')) \
+           ORDER BY relname",
+        )
+        .bind::<Text, _>(tables.join(","))
+        .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+}
@@ -1,13 +1,31 @@
+mod epoch_config_snapshot;
+mod epoch_measurement;
 mod error;
+mod maintenance;
+mod outbox;
+mod output_measurement;
 mod pending_msg;
+mod processed_archive_object;
+mod processed_offset;
 mod recovered_msg;
+mod retention;
 
-use diesel::connection::TransactionManager;
-use diesel::Connection;
+use diesel::connection::{SimpleConnection, TransactionManager};
+use diesel::dsl::sql;
+use diesel::sql_types::Bool;
+use diesel::{Connection, RunQueryDsl};
+pub use epoch_config_snapshot::*;
+pub use epoch_measurement::*;
 pub use error::*;
+pub use maintenance::*;
+pub use outbox::*;
+pub use output_measurement::*;
 pub use pending_msg::*;
+pub use processed_archive_object::*;
+pub use processed_offset::*;
 use r2d2::ManageConnection;
 pub use recovered_msg::*;
+pub use retention::*;
 
 use async_trait::async_trait;
 use diesel::pg::PgConnection;
@@ -20,6 +38,8 @@ use std::ops::DerefMut;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::task;
 use tokio::time::sleep;
 
 use crate::channel::get_data_channel_value_from_env;
@@ -42,6 +62,36 @@ const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 pub struct MessageWithThreshold {
   pub msg: NestedMessage,
   pub threshold: usize,
+  /// Submission times of the client reports that the message was derived from
+  pub submission_range: SubmissionTimeRange,
+}
+
+/// Earliest and latest client submission times of a group of messages.
+/// Both times are unset if none of the messages have a known submission time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SubmissionTimeRange {
+  pub earliest: Option<OffsetDateTime>,
+  pub latest: Option<OffsetDateTime>,
+}
+
+impl SubmissionTimeRange {
+  pub fn at(time: Option<OffsetDateTime>) -> Self {
+    Self {
+      earliest: time,
+      latest: time,
+    }
+  }
+
+  pub fn merge(&mut self, other: &Self) {
+    self.earliest = match (self.earliest, other.earliest) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    };
+    self.latest = match (self.latest, other.latest) {
+      (Some(a), Some(b)) => Some(a.max(b)),
+      (a, b) => a.or(b),
+    };
+  }
 }
 
 pub type DBConnection = PooledConnection<ConnectionManager<PgConnection>>;
@@ -51,6 +101,8 @@ pub enum DBConnectionType<'a> {
   Test,
   Normal {
     channel_name: &'a str,
+    /// If set, a separate schema will be used for the tenant
+    tenant: Option<&'a str>,
   },
 }
 
@@ -66,7 +118,7 @@ fn get_channel_db_url(conn_type: &DBConnectionType<'_>) -> String {
   let db_url = env::var(env_key).unwrap_or_else(|_| panic!("{} env var must be defined", env_key));
   match conn_type {
     DBConnectionType::Test => db_url,
-    DBConnectionType::Normal { channel_name } => {
+    DBConnectionType::Normal { channel_name, .. } => {
       let database_name = get_data_channel_value_from_env(
         DATABASE_NAMES_ENV_KEY,
         DEFAULT_DATABASE_NAMES,
@@ -77,6 +129,24 @@ fn get_channel_db_url(conn_type: &DBConnectionType<'_>) -> String {
   }
 }
 
+/// Checks that the database of the connection type is reachable and accepts writes,
+/// i.e. that it is not a standby or in a read-only transaction mode.
+pub async fn check_database_writable(conn_type: DBConnectionType<'_>) -> Result<(), PgStoreError> {
+  let db_url = get_channel_db_url(&conn_type);
+  task::spawn_blocking(move || {
+    let mut conn = PgConnection::establish(&db_url)?;
+    let read_only = diesel::select(sql::<Bool>(
+      "pg_is_in_recovery() OR current_setting('transaction_read_only') = 'on'",
+    ))
+    .get_result::<bool>(&mut conn)?;
+    match read_only {
+      true => Err(PgStoreError::ReadOnly),
+      false => Ok(()),
+    }
+  })
+  .await?
+}
+
 impl DBPool {
   pub fn new<'a>(conn_type: DBConnectionType<'a>) -> Self {
     let db_url = get_channel_db_url(&conn_type);
@@ -86,9 +156,24 @@ impl DBPool {
 
     let db_mgr: ConnectionManager<PgConnection> = ConnectionManager::new(db_url);
 
-    db_mgr
+    let tenant = match conn_type {
+      DBConnectionType::Normal { tenant, .. } => tenant,
+      DBConnectionType::Test => None,
+    };
+
+    let mut migration_conn = db_mgr
       .connect()
-      .expect("could not connect to db it run migrations")
+      .expect("could not connect to db it run migrations");
+    if let Some(tenant) = tenant {
+      migration_conn
+        .batch_execute(&format!(
+          "CREATE SCHEMA IF NOT EXISTS {}; {}",
+          tenant,
+          set_search_path_sql(tenant)
+        ))
+        .expect("failed to create tenant schema");
+    }
+    migration_conn
       .run_pending_migrations(MIGRATIONS)
       .expect("failed to run migrations");
 
@@ -99,6 +184,11 @@ impl DBPool {
         .min_idle(Some(1))
         .max_size(1)
     } else {
+      if let Some(tenant) = tenant {
+        builder = builder.connection_customizer(Box::new(TenantConnectionCustomizer {
+          schema: tenant.to_string(),
+        }));
+      }
       builder
         .min_idle(Some(pool_max_size))
         .max_size(pool_max_size)
@@ -179,6 +269,24 @@ pub trait BatchInsert<T> {
   ) -> Result<(), PgStoreError>;
 }
 
+fn set_search_path_sql(schema: &str) -> String {
+  // Schema name is validated as a tenant name, and is safe to include in the query
+  format!("SET search_path TO {}", schema)
+}
+
+#[derive(Debug)]
+pub struct TenantConnectionCustomizer {
+  schema: String,
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for TenantConnectionCustomizer {
+  fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+    conn
+      .batch_execute(&set_search_path_sql(&self.schema))
+      .map_err(diesel::r2d2::Error::QueryError)
+  }
+}
+
 #[derive(Debug)]
 pub struct TestConnectionCustomizer;
 
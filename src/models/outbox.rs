@@ -0,0 +1,139 @@
+use super::{BatchInsert, DBConnection};
+use crate::models::PgStoreError;
+use crate::profiler::{Profiler, ProfilerStat};
+use crate::schema::measurement_outbox;
+use async_trait::async_trait;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use time::OffsetDateTime;
+use tokio::task;
+
+#[allow(dead_code)]
+#[derive(Queryable, Debug, Clone)]
+pub struct OutboxMeasurement {
+  pub id: i64,
+  pub channel_name: String,
+  pub measurement: Vec<u8>,
+  pub sent_at: Option<OffsetDateTime>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = measurement_outbox)]
+pub struct NewOutboxMeasurement {
+  pub channel_name: String,
+  pub measurement: Vec<u8>,
+}
+
+impl OutboxMeasurement {
+  /// Returns the oldest unsent measurements in the outbox for the channel.
+  pub async fn list_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    batch_size: i64,
+    profiler: Arc<Profiler>,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::measurement_outbox::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        measurement_outbox
+          .filter(channel_name.eq(filter_channel_name))
+          .filter(sent_at.is_null())
+          .order(id.asc())
+          .limit(batch_size)
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::OutboxMsmtGet, start_instant)
+      .await;
+    result
+  }
+
+  /// Marks unsent measurements for the channel with an id less than
+  /// or equal to `max_id` as sent.
+  pub async fn mark_sent_up_to(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    max_id: i64,
+    profiler: Arc<Profiler>,
+  ) -> Result<(), PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::measurement_outbox::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      diesel::update(
+        measurement_outbox
+          .filter(channel_name.eq(filter_channel_name))
+          .filter(sent_at.is_null())
+          .filter(id.le(max_id)),
+      )
+      .set(sent_at.eq(diesel::dsl::now))
+      .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::OutboxMsmtMarkSent, start_instant)
+      .await;
+    result
+  }
+
+  /// Deletes measurements for the channel that were sent before the cutoff.
+  /// Returns the number of deleted measurements.
+  pub async fn delete_sent_before(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    cutoff: OffsetDateTime,
+    profiler: Arc<Profiler>,
+  ) -> Result<usize, PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::measurement_outbox::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        diesel::delete(
+          measurement_outbox
+            .filter(channel_name.eq(filter_channel_name))
+            .filter(sent_at.lt(cutoff)),
+        )
+        .execute(conn.deref_mut())?,
+      )
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::OutboxMsmtDelete, start_instant)
+      .await;
+    result
+  }
+}
+
+#[async_trait]
+impl BatchInsert<NewOutboxMeasurement> for Vec<NewOutboxMeasurement> {
+  async fn insert_batch(
+    self,
+    conn: Arc<Mutex<DBConnection>>,
+    profiler: Arc<Profiler>,
+  ) -> Result<(), PgStoreError> {
+    let start_instant = Instant::now();
+    let result = task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      diesel::insert_into(measurement_outbox::table)
+        .values(self)
+        .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::OutboxMsmtInsert, start_instant)
+      .await;
+    result
+  }
+}
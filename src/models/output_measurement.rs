@@ -0,0 +1,33 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use crate::schema::output_measurements;
+use diesel::RunQueryDsl;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = output_measurements)]
+pub struct NewOutputMeasurement {
+  pub channel_name: String,
+  pub outbox_id: i64,
+  pub measurement: String,
+}
+
+impl NewOutputMeasurement {
+  /// Inserts the measurements, skipping measurements that were already inserted.
+  pub async fn insert_new_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    measurements: Vec<Self>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      diesel::insert_into(output_measurements::table)
+        .values(measurements)
+        .on_conflict_do_nothing()
+        .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?
+  }
+}
@@ -1,23 +1,63 @@
 use super::{BatchInsert, DBConnection};
-use crate::models::PgStoreError;
+use crate::encryption::decrypt_share;
+use crate::models::{PgStoreError, SubmissionTimeRange};
 use crate::profiler::{Profiler, ProfilerStat};
 use crate::schema::pending_msgs;
-use crate::star::{parse_message, AppSTARError};
+use crate::star::{serialize_message_bincode, AppSTARError, StoredMessageFormat};
 use async_trait::async_trait;
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::dsl::count_star;
+use diesel::sql_types::{BigInt, SmallInt, Text};
+use diesel::{BoolExpressionMethods, Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
 use star_constellation::api::NestedMessage;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use time::OffsetDateTime;
 use tokio::task;
 
+/// Pending message counts of an epoch. Messages are grouped by tag and threshold.
+#[derive(QueryableByName, Debug, Clone, PartialEq)]
+pub struct PendingEpochCounts {
+  #[diesel(sql_type = SmallInt)]
+  pub epoch_tag: i16,
+  #[diesel(sql_type = BigInt)]
+  pub msg_count: i64,
+  #[diesel(sql_type = BigInt)]
+  pub tag_count: i64,
+  /// Amount of messages in tags that have met their threshold
+  #[diesel(sql_type = BigInt)]
+  pub threshold_met_msg_count: i64,
+}
+
+/// Columns of `PendingMessage`, in the order of its fields
+const PENDING_MESSAGE_COLUMNS: (
+  pending_msgs::epoch_tag,
+  pending_msgs::message,
+  pending_msgs::threshold,
+  pending_msgs::encrypted,
+  pending_msgs::first_submitted_at,
+  pending_msgs::last_submitted_at,
+  pending_msgs::message_format,
+) = (
+  pending_msgs::epoch_tag,
+  pending_msgs::message,
+  pending_msgs::threshold,
+  pending_msgs::encrypted,
+  pending_msgs::first_submitted_at,
+  pending_msgs::last_submitted_at,
+  pending_msgs::message_format,
+);
+
+/// A stored message, loaded with the columns needed to process it.
 #[derive(Queryable, Debug, Clone)]
 pub struct PendingMessage {
-  pub id: i64,
-  pub msg_tag: Vec<u8>,
   pub epoch_tag: i16,
   pub message: Vec<u8>,
   pub threshold: i16,
+  pub encrypted: bool,
+  pub first_submitted_at: Option<OffsetDateTime>,
+  pub last_submitted_at: Option<OffsetDateTime>,
+  pub message_format: i16,
 }
 
 #[derive(Insertable, Clone)]
@@ -27,31 +67,103 @@ pub struct NewPendingMessage {
   pub epoch_tag: i16,
   pub message: Vec<u8>,
   pub threshold: i16,
+  pub channel_name: Option<String>,
+  pub encrypted: bool,
+  pub first_submitted_at: Option<OffsetDateTime>,
+  pub last_submitted_at: Option<OffsetDateTime>,
+  pub message_format: i16,
+}
+
+/// The stored message of a pending message row, for rewriting it in another format.
+#[derive(Queryable, Debug, Clone)]
+pub struct StoredPendingMessage {
+  pub id: i64,
+  pub message: Vec<u8>,
+  pub encrypted: bool,
+  pub message_format: i16,
 }
 
 impl TryInto<NestedMessage> for PendingMessage {
   type Error = AppSTARError;
 
   fn try_into(self) -> Result<NestedMessage, Self::Error> {
-    parse_message(&self.message)
+    StoredMessageFormat::from_tag(self.message_format)?
+      .deserialize(&decrypt_share(&self.message, self.encrypted)?)
   }
 }
 
 impl PendingMessage {
+  /// Returns the message in the encoding of submitted messages,
+  /// decrypting and re-encoding it if necessary.
+  pub fn submission_message(&self) -> Result<Vec<u8>, AppSTARError> {
+    let message = decrypt_share(&self.message, self.encrypted)?;
+    match StoredMessageFormat::from_tag(self.message_format)? {
+      StoredMessageFormat::Bincode => Ok(message),
+      format => serialize_message_bincode(format.deserialize(&message)?),
+    }
+  }
+
+  pub fn submission_range(&self) -> SubmissionTimeRange {
+    SubmissionTimeRange {
+      earliest: self.first_submitted_at,
+      latest: self.last_submitted_at,
+    }
+  }
+
   pub async fn list(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     filter_msg_tag: Vec<u8>,
     profiler: Arc<Profiler>,
   ) -> Result<Vec<Self>, PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::pending_msgs::dsl::*;
       let mut conn = conn.lock().unwrap();
       Ok(
         pending_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
           .filter(epoch_tag.eq(filter_epoch_tag))
           .filter(msg_tag.eq(filter_msg_tag))
+          .select(PENDING_MESSAGE_COLUMNS)
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::PendingMsgGet, start_instant)
+      .await;
+    result
+  }
+
+  /// Returns the amount of pending messages stored for each tag in the epoch.
+  pub async fn count_by_tag(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    filter_epoch_tag: i16,
+    profiler: Arc<Profiler>,
+  ) -> Result<Vec<i64>, PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::pending_msgs::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        pending_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
+          .filter(epoch_tag.eq(filter_epoch_tag))
+          .group_by(msg_tag)
+          .select(count_star())
           .load(conn.deref_mut())?,
       )
     })
@@ -62,17 +174,53 @@ impl PendingMessage {
     result
   }
 
+  /// Returns the pending message counts of each epoch with pending messages.
+  pub async fn counts_by_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+  ) -> Result<Vec<PendingEpochCounts>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        diesel::sql_query(
+          "SELECT epoch_tag, SUM(msg_count)::bigint AS msg_count, \
+           COUNT(DISTINCT msg_tag) AS tag_count, \
+           COALESCE(SUM(msg_count) FILTER (WHERE msg_count >= threshold), 0)::bigint \
+           AS threshold_met_msg_count \
+           FROM (SELECT epoch_tag, msg_tag, threshold, COUNT(*) AS msg_count FROM pending_msgs \
+           WHERE channel_name = $1 OR channel_name IS NULL \
+           GROUP BY epoch_tag, msg_tag, threshold) AS tag_counts \
+           GROUP BY epoch_tag ORDER BY epoch_tag",
+        )
+        .bind::<Text, _>(filter_channel_name)
+        .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
   pub async fn delete_epoch(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     profiler: Arc<Profiler>,
   ) -> Result<(), PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::pending_msgs::dsl::*;
       let mut conn = conn.lock().unwrap();
-      diesel::delete(pending_msgs.filter(epoch_tag.eq(filter_epoch_tag)))
-        .execute(conn.deref_mut())?;
+      diesel::delete(
+        pending_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
+          .filter(epoch_tag.eq(filter_epoch_tag)),
+      )
+      .execute(conn.deref_mut())?;
       Ok(())
     })
     .await?;
@@ -84,16 +232,23 @@ impl PendingMessage {
 
   pub async fn delete_tag(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     filter_msg_tag: Vec<u8>,
     profiler: Arc<Profiler>,
   ) -> Result<(), PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::pending_msgs::dsl::*;
       let mut conn = conn.lock().unwrap();
       diesel::delete(
         pending_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
           .filter(epoch_tag.eq(filter_epoch_tag))
           .filter(msg_tag.eq(filter_msg_tag)),
       )
@@ -106,6 +261,98 @@ impl PendingMessage {
       .await;
     result
   }
+
+  /// Deletes pending messages created before the cutoff time,
+  /// and returns the deleted messages.
+  pub async fn delete_older_than(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    cutoff: OffsetDateTime,
+    profiler: Arc<Profiler>,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::pending_msgs::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        diesel::delete(
+          pending_msgs
+            .filter(
+              channel_name
+                .eq(filter_channel_name)
+                .or(channel_name.is_null()),
+            )
+            .filter(created_at.lt(cutoff)),
+        )
+        .returning(PENDING_MESSAGE_COLUMNS)
+        .get_results(conn.deref_mut())?,
+      )
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::PendingMsgDelete, start_instant)
+      .await;
+    result
+  }
+}
+
+impl StoredPendingMessage {
+  /// Lists the messages of the channel stored in formats other than the given format,
+  /// with ids greater than the given id, in order of id.
+  pub async fn list_other_format(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    format_tag: i16,
+    after_id: i64,
+    limit: i64,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::pending_msgs::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        pending_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
+          .filter(message_format.ne(format_tag))
+          .filter(id.gt(after_id))
+          .order(id)
+          .limit(limit)
+          .select((id, message, encrypted, message_format))
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
+  /// Replaces the stored messages of the rows within a transaction.
+  /// Rows that were deleted in the meantime are skipped.
+  pub async fn update_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    messages: Vec<Self>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      use crate::schema::pending_msgs::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      conn.transaction(|conn| {
+        for msg in messages {
+          diesel::update(pending_msgs.filter(id.eq(msg.id)))
+            .set((
+              message.eq(msg.message),
+              encrypted.eq(msg.encrypted),
+              message_format.eq(msg.message_format),
+            ))
+            .execute(conn)?;
+        }
+        Ok(())
+      })
+    })
+    .await?
+  }
 }
 
 #[async_trait]
@@ -0,0 +1,53 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use crate::schema::processed_archive_objects;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+#[derive(Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = processed_archive_objects)]
+pub struct ProcessedArchiveObject {
+  pub channel_name: String,
+  pub checksum: String,
+  pub object_key: String,
+}
+
+impl ProcessedArchiveObject {
+  /// Returns the checksums of the processed objects of the channel.
+  pub async fn list_checksums(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+  ) -> Result<Vec<String>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::processed_archive_objects::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        processed_archive_objects
+          .filter(channel_name.eq(filter_channel_name))
+          .select(checksum)
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
+  pub async fn insert_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    objects: Vec<Self>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      use crate::schema::processed_archive_objects::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      diesel::insert_into(processed_archive_objects)
+        .values(objects)
+        .on_conflict((channel_name, checksum))
+        .do_nothing()
+        .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?
+  }
+}
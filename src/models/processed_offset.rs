@@ -0,0 +1,56 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use crate::schema::processed_offsets;
+use diesel::upsert::excluded;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = processed_offsets)]
+pub struct ProcessedOffset {
+  pub channel_name: String,
+  pub kafka_partition: i32,
+  pub last_offset: i64,
+  /// Configured name of the input topic; empty for offsets
+  /// stored before topics were tracked
+  pub topic: String,
+}
+
+impl ProcessedOffset {
+  pub async fn list(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      use crate::schema::processed_offsets::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        processed_offsets
+          .filter(channel_name.eq(filter_channel_name))
+          .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
+  pub async fn upsert_batch(
+    conn: Arc<Mutex<DBConnection>>,
+    offsets: Vec<Self>,
+  ) -> Result<(), PgStoreError> {
+    task::spawn_blocking(move || {
+      use crate::schema::processed_offsets::dsl::*;
+      let mut conn = conn.lock().unwrap();
+      diesel::insert_into(processed_offsets)
+        .values(offsets)
+        .on_conflict((channel_name, topic, kafka_partition))
+        .do_update()
+        .set(last_offset.eq(excluded(last_offset)))
+        .execute(conn.deref_mut())?;
+      Ok(())
+    })
+    .await?
+  }
+}
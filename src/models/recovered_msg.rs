@@ -1,14 +1,28 @@
 use super::{BatchInsert, DBConnection};
-use crate::models::PgStoreError;
+use crate::models::{PgStoreError, SubmissionTimeRange};
 use crate::profiler::{Profiler, ProfilerStat};
 use crate::schema::recovered_msgs;
 use async_trait::async_trait;
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::sql_types::{BigInt, SmallInt, Text};
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use time::OffsetDateTime;
 use tokio::task;
 
+/// Recovered message counts of an epoch
+#[derive(QueryableByName, Debug, Clone, PartialEq)]
+pub struct RecoveredEpochCounts {
+  #[diesel(sql_type = SmallInt)]
+  pub epoch_tag: i16,
+  #[diesel(sql_type = BigInt)]
+  pub tag_count: i64,
+  /// Amount of recovered messages that have not been reported yet
+  #[diesel(sql_type = BigInt)]
+  pub unreported_msg_count: i64,
+}
+
 #[derive(Queryable, Clone)]
 pub struct RecoveredMessage {
   pub id: i64,
@@ -20,6 +34,9 @@ pub struct RecoveredMessage {
   pub count: i64,
   pub key: Vec<u8>,
   pub has_children: bool,
+  pub channel_name: Option<String>,
+  pub first_submitted_at: Option<OffsetDateTime>,
+  pub last_submitted_at: Option<OffsetDateTime>,
 }
 
 #[derive(Insertable, Clone)]
@@ -33,6 +50,9 @@ pub struct NewRecoveredMessage {
   pub count: i64,
   pub key: Vec<u8>,
   pub has_children: bool,
+  pub channel_name: Option<String>,
+  pub first_submitted_at: Option<OffsetDateTime>,
+  pub last_submitted_at: Option<OffsetDateTime>,
 }
 
 impl From<RecoveredMessage> for NewRecoveredMessage {
@@ -46,24 +66,47 @@ impl From<RecoveredMessage> for NewRecoveredMessage {
       count: msg.count,
       key: msg.key,
       has_children: msg.has_children,
+      channel_name: msg.channel_name,
+      first_submitted_at: msg.first_submitted_at,
+      last_submitted_at: msg.last_submitted_at,
     }
   }
 }
 
 impl RecoveredMessage {
+  /// Submission times of the messages counted since the last report
+  pub fn submission_range(&self) -> SubmissionTimeRange {
+    SubmissionTimeRange {
+      earliest: self.first_submitted_at,
+      latest: self.last_submitted_at,
+    }
+  }
+
+  pub fn set_submission_range(&mut self, range: SubmissionTimeRange) {
+    self.first_submitted_at = range.earliest;
+    self.last_submitted_at = range.latest;
+  }
+
   pub async fn list(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     filter_msg_tags: Vec<Vec<u8>>,
     profiler: Arc<Profiler>,
   ) -> Result<Vec<RecoveredMessage>, PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::recovered_msgs::dsl::*;
 
       let mut conn = conn.lock().unwrap();
       Ok(
         recovered_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
           .filter(epoch_tag.eq(filter_epoch_tag))
           .filter(msg_tag.eq_any(filter_msg_tags))
           .load(conn.deref_mut())?,
@@ -76,10 +119,12 @@ impl RecoveredMessage {
     result
   }
 
+  /// Updates the count, along with the submission times of the counted messages.
   pub async fn update_count(
     conn: Arc<Mutex<DBConnection>>,
     curr_id: i64,
     new_count: i64,
+    new_submission_range: SubmissionTimeRange,
     profiler: Arc<Profiler>,
   ) -> Result<(), PgStoreError> {
     let start_instant = Instant::now();
@@ -88,7 +133,11 @@ impl RecoveredMessage {
 
       let mut conn = conn.lock().unwrap();
       diesel::update(recovered_msgs.filter(id.eq(curr_id)))
-        .set(count.eq(new_count))
+        .set((
+          count.eq(new_count),
+          first_submitted_at.eq(new_submission_range.earliest),
+          last_submitted_at.eq(new_submission_range.latest),
+        ))
         .execute(conn.deref_mut())?;
 
       Ok(())
@@ -102,15 +151,22 @@ impl RecoveredMessage {
 
   pub async fn list_with_nonzero_count(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     profiler: Arc<Profiler>,
   ) -> Result<Vec<Self>, PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::recovered_msgs::dsl::*;
 
       let mut conn = conn.lock().unwrap();
       let result = recovered_msgs
+        .filter(
+          channel_name
+            .eq(filter_channel_name)
+            .or(channel_name.is_null()),
+        )
         .filter(epoch_tag.eq(filter_epoch_tag))
         .filter(count.gt(0))
         .load(conn.deref_mut())?;
@@ -123,15 +179,73 @@ impl RecoveredMessage {
     result
   }
 
+  pub async fn list_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+    filter_epoch_tag: i16,
+    profiler: Arc<Profiler>,
+  ) -> Result<Vec<Self>, PgStoreError> {
+    let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
+    let result = task::spawn_blocking(move || {
+      use crate::schema::recovered_msgs::dsl::*;
+
+      let mut conn = conn.lock().unwrap();
+      let result = recovered_msgs
+        .filter(
+          channel_name
+            .eq(filter_channel_name)
+            .or(channel_name.is_null()),
+        )
+        .filter(epoch_tag.eq(filter_epoch_tag))
+        .load(conn.deref_mut())?;
+      Ok(result)
+    })
+    .await?;
+    profiler
+      .record_range_time(ProfilerStat::RecoveredMsgGet, start_instant)
+      .await;
+    result
+  }
+
+  /// Returns the recovered message counts of each epoch with recovered messages.
+  pub async fn counts_by_epoch(
+    conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
+  ) -> Result<Vec<RecoveredEpochCounts>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
+    task::spawn_blocking(move || {
+      let mut conn = conn.lock().unwrap();
+      Ok(
+        diesel::sql_query(
+          "SELECT epoch_tag, COUNT(*) AS tag_count, \
+           COALESCE(SUM(count), 0)::bigint AS unreported_msg_count FROM recovered_msgs \
+           WHERE channel_name = $1 OR channel_name IS NULL \
+           GROUP BY epoch_tag ORDER BY epoch_tag",
+        )
+        .bind::<Text, _>(filter_channel_name)
+        .load(conn.deref_mut())?,
+      )
+    })
+    .await?
+  }
+
   pub async fn list_distinct_epochs(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
   ) -> Result<Vec<i16>, PgStoreError> {
+    let filter_channel_name = filter_channel_name.to_string();
     task::spawn_blocking(move || {
       use crate::schema::recovered_msgs::dsl::*;
 
       let mut conn = conn.lock().unwrap();
       Ok(
         recovered_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
           .select(epoch_tag)
           .distinct()
           .load::<i16>(conn.deref_mut())?,
@@ -142,16 +256,26 @@ impl RecoveredMessage {
 
   pub async fn delete_epoch(
     conn: Arc<Mutex<DBConnection>>,
+    filter_channel_name: &str,
     filter_epoch_tag: i16,
     profiler: Arc<Profiler>,
   ) -> Result<(), PgStoreError> {
     let start_instant = Instant::now();
+    let filter_channel_name = filter_channel_name.to_string();
     let result = task::spawn_blocking(move || {
       use crate::schema::recovered_msgs::dsl::*;
 
       let mut conn = conn.lock().unwrap();
-      diesel::delete(recovered_msgs.filter(epoch_tag.eq(filter_epoch_tag)))
-        .execute(conn.deref_mut())?;
+      diesel::delete(
+        recovered_msgs
+          .filter(
+            channel_name
+              .eq(filter_channel_name)
+              .or(channel_name.is_null()),
+          )
+          .filter(epoch_tag.eq(filter_epoch_tag)),
+      )
+      .execute(conn.deref_mut())?;
       Ok(())
     })
     .await?;
@@ -0,0 +1,78 @@
+use super::DBConnection;
+use crate::models::PgStoreError;
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::collections::BTreeMap;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use tokio::task;
+
+/// Deletes the rows of the channel that were created before the cutoff, or counts
+/// them if `dry_run` is set. Returns the amount of expired rows, mapped by table.
+/// Pending messages are dated by their creation time, sent outbox measurements
+/// by the time they were sent, and processed archive objects by the time they
+/// were processed.
+pub async fn purge_expired_rows(
+  conn: Arc<Mutex<DBConnection>>,
+  filter_channel_name: &str,
+  cutoff: OffsetDateTime,
+  dry_run: bool,
+) -> Result<BTreeMap<&'static str, usize>, PgStoreError> {
+  let filter_channel_name = filter_channel_name.to_string();
+  task::spawn_blocking(move || {
+    let mut conn = conn.lock().unwrap();
+    let conn = conn.deref_mut();
+    let mut result = BTreeMap::new();
+
+    {
+      use crate::schema::pending_msgs::dsl::*;
+      let expired = pending_msgs
+        .filter(
+          channel_name
+            .eq(filter_channel_name.clone())
+            .or(channel_name.is_null()),
+        )
+        .filter(created_at.lt(cutoff));
+      let count = match dry_run {
+        true => expired.count().get_result::<i64>(conn)? as usize,
+        false => diesel::delete(expired).execute(conn)?,
+      };
+      result.insert("pending_msgs", count);
+    }
+    {
+      use crate::schema::measurement_outbox::dsl::*;
+      let expired = measurement_outbox
+        .filter(channel_name.eq(filter_channel_name.clone()))
+        .filter(sent_at.lt(cutoff));
+      let count = match dry_run {
+        true => expired.count().get_result::<i64>(conn)? as usize,
+        false => diesel::delete(expired).execute(conn)?,
+      };
+      result.insert("measurement_outbox", count);
+    }
+    {
+      use crate::schema::output_measurements::dsl::*;
+      let expired = output_measurements
+        .filter(channel_name.eq(filter_channel_name.clone()))
+        .filter(created_at.lt(cutoff));
+      let count = match dry_run {
+        true => expired.count().get_result::<i64>(conn)? as usize,
+        false => diesel::delete(expired).execute(conn)?,
+      };
+      result.insert("output_measurements", count);
+    }
+    {
+      use crate::schema::processed_archive_objects::dsl::*;
+      let expired = processed_archive_objects
+        .filter(channel_name.eq(filter_channel_name))
+        .filter(processed_at.lt(cutoff));
+      let count = match dry_run {
+        true => expired.count().get_result::<i64>(conn)? as usize,
+        false => diesel::delete(expired).execute(conn)?,
+      };
+      result.insert("processed_archive_objects", count);
+    }
+    Ok(result)
+  })
+  .await?
+}
@@ -0,0 +1,546 @@
+//! Record stream backed by NATS JetStream, selected via `RECORD_STREAM_BACKEND=nats`.
+//! The topic of the stream config is used as the subject, and as the name of the JetStream
+//! stream capturing the subject. Streams are not created by the processor. Record headers are
+//! sent as NATS message headers, with base64 encoded values.
+//!
+//! Records are consumed from a durable pull consumer named after the consumer group, with
+//! explicit acks. JetStream streams do not have partitions, so all records are reported in
+//! partition 0, and stream sequence numbers are used as offsets. Committing consumption acks
+//! the consumed records up to the committed offset. Records that are not acked within
+//! `NATS_ACK_WAIT_SECS` are redelivered by the server.
+//!
+//! The connection is established on first use, and re-established by the client after
+//! connection errors. The NATS URL is formatted as
+//! `nats://[<user>:<password>@|<token>@]<host>:<port>`.
+
+use crate::record_stream::{
+  acquire_send_permit, consumer_group_id, datetime_from_unix_millis, send_to_producer_queue,
+  ConsumedRecord, KafkaRecordStreamConfig, ProducerQueue, ProducerQueueItem, RecordHeaderValues,
+  RecordStream, RecordStreamError, RecordStreamOptions,
+};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_nats::jetstream::consumer::pull::{self, MessagesError, MessagesErrorKind};
+use async_nats::jetstream::consumer::{AckPolicy, DeliverPolicy, PullConsumer, StreamError};
+use async_nats::jetstream::context::{GetStreamError, PublishError};
+use async_nats::jetstream::message::Acker;
+use async_nats::jetstream::stream::ConsumerError;
+use async_nats::jetstream::{self, Context};
+use async_nats::{ConnectError, HeaderMap};
+use async_trait::async_trait;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error, From};
+use futures::future::try_join_all;
+use futures::StreamExt;
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{Mutex as AsyncMutex, OnceCell, RwLock, Semaphore};
+
+const NATS_URL_ENV_KEY: &str = "NATS_URL";
+const DEFAULT_NATS_URL: &str = "nats://localhost:4222";
+const NATS_FETCH_BATCH_SIZE_ENV_KEY: &str = "NATS_FETCH_BATCH_SIZE";
+const DEFAULT_NATS_FETCH_BATCH_SIZE: &str = "1000";
+const NATS_FETCH_EXPIRES_MS_ENV_KEY: &str = "NATS_FETCH_EXPIRES_MS";
+const DEFAULT_NATS_FETCH_EXPIRES_MS: &str = "1000";
+const NATS_ACK_WAIT_SECS_ENV_KEY: &str = "NATS_ACK_WAIT_SECS";
+const DEFAULT_NATS_ACK_WAIT_SECS: &str = "300";
+const NATS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY: &str = "NATS_PRODUCE_QUEUE_TASK_COUNT";
+const DEFAULT_NATS_PRODUCE_QUEUE_TASK_COUNT: &str = "16";
+
+const STREAM_PARTITION: i32 = 0;
+
+#[derive(Error, From, Display, Debug)]
+pub enum NatsError {
+  #[display(fmt = "NATS connect error: {}", _0)]
+  Connect(ConnectError),
+  #[display(fmt = "JetStream get stream error: {}", _0)]
+  GetStream(GetStreamError),
+  #[display(fmt = "JetStream consumer error: {}", _0)]
+  Consumer(ConsumerError),
+  #[display(fmt = "JetStream pull error: {}", _0)]
+  Pull(StreamError),
+  #[display(fmt = "JetStream messages error: {}", _0)]
+  Messages(MessagesError),
+  #[display(fmt = "JetStream publish error: {}", _0)]
+  Publish(PublishError),
+  #[display(fmt = "JetStream ack error: {}", _0)]
+  #[from(ignore)]
+  Ack(#[error(not(source))] async_nats::Error),
+}
+
+/// Connects on first use, since record streams are created synchronously.
+struct NatsClient {
+  url: String,
+  context: OnceCell<Context>,
+}
+
+impl NatsClient {
+  fn new(url: &str) -> Self {
+    Self {
+      url: url.to_string(),
+      context: OnceCell::new(),
+    }
+  }
+
+  async fn context(&self) -> Result<&Context, NatsError> {
+    self
+      .context
+      .get_or_try_init(|| async { Ok(jetstream::new(async_nats::connect(&self.url).await?)) })
+      .await
+  }
+
+  /// Publishes a record to the stream, and waits for the acknowledgement of JetStream.
+  async fn publish_record(
+    &self,
+    subject: &str,
+    headers: &[(String, String)],
+    data: &[u8],
+  ) -> Result<(), NatsError> {
+    self
+      .context()
+      .await?
+      .publish_with_headers(
+        subject.to_string(),
+        encode_headers(headers),
+        data.to_vec().into(),
+      )
+      .await?
+      .await?;
+    Ok(())
+  }
+}
+
+fn encode_headers(headers: &[(String, String)]) -> HeaderMap {
+  let mut header_map = HeaderMap::new();
+  for (key, value) in headers {
+    header_map.insert(key.as_str(), value.as_str());
+  }
+  header_map
+}
+
+/// Returns the decoded record headers of a message.
+fn decode_headers(headers: Option<&HeaderMap>) -> Vec<(String, Vec<u8>)> {
+  headers
+    .into_iter()
+    .flat_map(|v| v.iter())
+    .filter_map(|(k, values)| {
+      let value = base64_engine::STANDARD
+        .decode(values.first()?.as_str())
+        .ok()?;
+      Some((k.to_string(), value))
+    })
+    .collect()
+}
+
+#[derive(Default)]
+struct ConsumerState {
+  initialized: bool,
+  buffer: VecDeque<ConsumedRecord>,
+  /// Ackers of the delivered records that have not been acked, by stream sequence
+  uncommitted: BTreeMap<i64, Arc<Acker>>,
+  delivered_offset: i64,
+  consumed_offset: Option<i64>,
+  committed_offset: Option<i64>,
+  /// Records in the stream that have not been delivered yet
+  pending: i64,
+}
+
+pub struct NatsRecordStream {
+  client: Arc<NatsClient>,
+  subject: String,
+  stream_name: String,
+  durable_name: String,
+  tenant: Option<String>,
+  enable_producer: bool,
+  consumer_state: Mutex<ConsumerState>,
+  /// Also prevents concurrent consumption
+  pull: AsyncMutex<Option<pull::Stream>>,
+  fetch_batch_size: usize,
+  fetch_expires: Duration,
+  ack_wait: Duration,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  send_limit: Option<Arc<Semaphore>>,
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+impl NatsRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let tenant = stream_config.tenant.as_deref();
+    let subject = tenant_scoped_name(&stream_config.topic, tenant);
+    Self {
+      client: Arc::new(NatsClient::new(&parse_env_var::<String>(
+        NATS_URL_ENV_KEY,
+        DEFAULT_NATS_URL,
+      ))),
+      // Stream names may not contain subject token separators
+      stream_name: subject.replace('.', "_"),
+      subject,
+      durable_name: consumer_group_id(&stream_config),
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      consumer_state: Mutex::new(ConsumerState::default()),
+      pull: AsyncMutex::new(None),
+      fetch_batch_size: parse_env_var(NATS_FETCH_BATCH_SIZE_ENV_KEY, DEFAULT_NATS_FETCH_BATCH_SIZE),
+      fetch_expires: Duration::from_millis(parse_env_var(
+        NATS_FETCH_EXPIRES_MS_ENV_KEY,
+        DEFAULT_NATS_FETCH_EXPIRES_MS,
+      )),
+      ack_wait: Duration::from_secs(parse_env_var(
+        NATS_ACK_WAIT_SECS_ENV_KEY,
+        DEFAULT_NATS_ACK_WAIT_SECS,
+      )),
+      producer_queues: RwLock::new(Vec::new()),
+      send_limit: options.send_limit,
+      record_headers: Arc::new(options.record_headers),
+    }
+  }
+
+  fn message_headers(
+    &self,
+    mut header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Vec<(String, String)> {
+    header_values.tenant = self.tenant.clone();
+    header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .map(|(k, v)| (k, base64_engine::STANDARD.encode(v)))
+      .collect()
+  }
+
+  /// Creates the durable consumer, or retrieves it if it already exists,
+  /// and starts pulling its records.
+  async fn start_pull(&self) -> Result<pull::Stream, NatsError> {
+    let consumer: PullConsumer = self
+      .client
+      .context()
+      .await?
+      .get_stream(&self.stream_name)
+      .await?
+      .get_or_create_consumer(
+        &self.durable_name,
+        pull::Config {
+          durable_name: Some(self.durable_name.clone()),
+          ack_policy: AckPolicy::Explicit,
+          ack_wait: self.ack_wait,
+          deliver_policy: DeliverPolicy::All,
+          filter_subject: self.subject.clone(),
+          // Unlimited, since records remain unacked until consumption is committed
+          max_ack_pending: -1,
+          ..Default::default()
+        },
+      )
+      .await?;
+    {
+      let info = consumer.cached_info();
+      let mut state = self.consumer_state.lock().unwrap();
+      if !state.initialized {
+        state.initialized = true;
+        state.delivered_offset = info.delivered.stream_sequence as i64;
+        state.committed_offset = Some(info.ack_floor.stream_sequence as i64 + 1);
+        state.pending = info.num_pending as i64;
+      }
+    }
+    Ok(
+      consumer
+        .stream()
+        .max_messages_per_batch(self.fetch_batch_size)
+        .expires(self.fetch_expires)
+        .messages()
+        .await?,
+    )
+  }
+
+  fn pop_record(&self) -> Option<ConsumedRecord> {
+    let mut state = self.consumer_state.lock().unwrap();
+    let record = state.buffer.pop_front()?;
+    state.consumed_offset = record.offset;
+    Some(record)
+  }
+
+  fn buffer_record(&self, msg: jetstream::Message) {
+    let (stream_seq, pending, published) = match msg.info() {
+      Ok(info) => (
+        info.stream_sequence as i64,
+        info.pending as i64,
+        info.published,
+      ),
+      Err(_) => {
+        warn!("Skipping NATS message without JetStream metadata");
+        return;
+      }
+    };
+    let mut state = self.consumer_state.lock().unwrap();
+    state.pending = pending;
+    state.delivered_offset = state.delivered_offset.max(stream_seq);
+    // Records that have been redelivered before being acked are already buffered or consumed
+    if state.uncommitted.contains_key(&stream_seq) {
+      return;
+    }
+    let (msg, acker) = msg.split();
+    state.uncommitted.insert(stream_seq, Arc::new(acker));
+    let decoded_headers = decode_headers(msg.headers.as_ref());
+    let header_values = RecordHeaderValues::parse(
+      decoded_headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_slice())),
+    );
+    if !header_values.matches_tenant(self.tenant.as_deref()) {
+      return;
+    }
+    let submitted_at = header_values
+      .submitted_at
+      .and_then(datetime_from_unix_millis)
+      .unwrap_or(published);
+    state.buffer.push_back(ConsumedRecord {
+      data: msg.payload.to_vec(),
+      request_threshold: header_values.request_threshold,
+      channel_name: header_values.channel_name,
+      epoch: header_values.epoch,
+      client_version: header_values.client_version,
+      topic: None,
+      partition: Some(STREAM_PARTITION),
+      offset: Some(stream_seq),
+      submitted_at: Some(submitted_at),
+      timestamp: Some(published),
+    });
+  }
+
+  /// Cancel safe, since pulled records are buffered by the pull stream until the next call.
+  async fn next_record(&self) -> Result<ConsumedRecord, NatsError> {
+    let mut pull = self.pull.lock().await;
+    loop {
+      if let Some(record) = self.pop_record() {
+        return Ok(record);
+      }
+      if pull.is_none() {
+        *pull = Some(self.start_pull().await?);
+      }
+      match pull.as_mut().unwrap().next().await {
+        Some(Ok(msg)) => self.buffer_record(msg),
+        // The pull requests are re-sent by the stream
+        Some(Err(e)) if e.kind() == MessagesErrorKind::MissingHeartbeat => {
+          warn!("NATS pull stream missed heartbeats")
+        }
+        Some(Err(e)) => {
+          *pull = None;
+          return Err(e.into());
+        }
+        None => *pull = None,
+      }
+    }
+  }
+
+  /// Acks the delivered records up to and including the offset.
+  async fn ack(&self, offset: i64) -> Result<(), NatsError> {
+    let ackers: Vec<Arc<Acker>> = {
+      let state = self.consumer_state.lock().unwrap();
+      state
+        .uncommitted
+        .range(..=offset)
+        .map(|(_, v)| v.clone())
+        .collect()
+    };
+    let Some((last_acker, ackers)) = ackers.split_last() else {
+      return Ok(());
+    };
+    for acker in ackers {
+      acker.ack().await.map_err(NatsError::Ack)?;
+    }
+    // Acks are processed in order, so the reply to the last ack confirms all acks
+    last_acker.double_ack().await.map_err(NatsError::Ack)?;
+    let mut state = self.consumer_state.lock().unwrap();
+    state.uncommitted = state.uncommitted.split_off(&(offset + 1));
+    state.committed_offset = Some(state.committed_offset.unwrap_or_default().max(offset + 1));
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl RecordStream for NatsRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self
+      .client
+      .context()
+      .await?
+      .get_stream(&self.stream_name)
+      .await
+      .map_err(NatsError::from)?;
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(self.consumer_state.lock().unwrap().initialized)
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(match self.consumer_state.lock().unwrap().initialized {
+      true => vec![STREAM_PARTITION],
+      false => Vec::new(),
+    })
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .committed_offset
+        .map(|v| (STREAM_PARTITION, v))
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  /// Based on the pending count reported with the last delivered record.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(HashMap::from([(
+      STREAM_PARTITION,
+      state.delivered_offset + 1 + state.pending,
+    )]))
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    _key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "NATS producer not enabled");
+    let headers = self.message_headers(
+      RecordHeaderValues {
+        request_threshold,
+        channel_name: channel_name.map(|v| v.to_string()),
+        epoch,
+        ..Default::default()
+      },
+      extra_headers,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    self
+      .client
+      .publish_record(&self.subject, &headers, record)
+      .await?;
+    Ok(())
+  }
+
+  async fn init_producer_queues(&self) {
+    assert!(self.enable_producer, "NATS producer not enabled");
+    let task_count = parse_env_var::<usize>(
+      NATS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY,
+      DEFAULT_NATS_PRODUCE_QUEUE_TASK_COUNT,
+    );
+    let headers = Arc::new(self.message_headers(RecordHeaderValues::default(), &[]));
+    let mut producer_queues = self.producer_queues.write().await;
+    for _ in 0..task_count {
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
+      let client = self.client.clone();
+      let subject = self.subject.clone();
+      let send_limit = self.send_limit.clone();
+      let headers = headers.clone();
+      let handle = tokio::spawn(async move {
+        // Record keys are not used, since JetStream streams are not partitioned
+        while let Some((data, _)) = rx.recv().await {
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          client.publish_record(&subject, &headers, &data).await?;
+        }
+        Ok(())
+      });
+      producer_queues.push((handle, tx));
+    }
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    let producer_queues = self.producer_queues.read().await;
+    let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
+    send_to_producer_queue(tx, &self.subject, (record, key))
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    let mut producer_queues = self.producer_queues.write().await;
+    try_join_all(
+      producer_queues
+        .drain(..)
+        .map(|(handle, _)| handle)
+        .collect::<Vec<_>>(),
+    )
+    .await?
+    .into_iter()
+    .collect::<Result<Vec<()>, RecordStreamError>>()?;
+    Ok(())
+  }
+
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    Ok(self.next_record().await?)
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let consumed_offset = self.consumer_state.lock().unwrap().consumed_offset;
+    if let Some(offset) = consumed_offset {
+      self.ack(offset).await?;
+    }
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    for (partition, offset) in offsets {
+      if *partition == STREAM_PARTITION {
+        self.ack(*offset).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::KafkaComponent;
+
+  #[test]
+  fn encode_and_decode_headers() {
+    let stream = NatsRecordStream::new(
+      KafkaRecordStreamConfig {
+        component: KafkaComponent::Server,
+        enable_producer: true,
+        enable_consumer: false,
+        topic: "p3a-star-enc".to_string(),
+        use_output_group_id: false,
+        group_id_suffix: None,
+        tenant: Some("acme".to_string()),
+      },
+      RecordStreamOptions::default(),
+    );
+    let headers = encode_headers(&stream.message_headers(
+      RecordHeaderValues {
+        channel_name: Some("typical".to_string()),
+        epoch: Some(3),
+        ..Default::default()
+      },
+      &[],
+    ));
+    let decoded_headers = decode_headers(Some(&headers));
+    let header_values = RecordHeaderValues::parse(
+      decoded_headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_slice())),
+    );
+    assert_eq!(header_values.channel_name.as_deref(), Some("typical"));
+    assert_eq!(header_values.epoch, Some(3));
+    assert!(header_values.matches_tenant(Some("acme")));
+    assert!(decode_headers(None).is_empty());
+  }
+}
@@ -0,0 +1,191 @@
+//! Traits of the I/O layer shared by the pipelines of this crate. Record streams, the
+//! data lake and the output sinks of the aggregator each have an interface suited to
+//! their pipeline; the traits below describe what they have in common, so that a new
+//! pipeline (i.e. a variant of the aggregator with different processing logic) can read
+//! from and write to any of them without depending on their specific interfaces.
+//!
+//! - `Endpoint`: a named external system that can be checked for reachability.
+//! - `Source`: records are read in batches, and committed once they are processed.
+//! - `Sink`: items are written in batches, and the write returns once they are stored.
+//!
+//! Errors of all endpoints implement `PipelineError`, which provides the stable category
+//! of the error. Operations run via `observe` are timed and counted by outcome in the
+//! `io_operation_duration_seconds` metric.
+
+use crate::aggregator::{AggregatorError, DynOutputSink};
+use crate::lake::{DataLake, DataLakeError};
+use crate::lakesink::{LakeSinkError, LakeSinkKind};
+use crate::models::OutboxMeasurement;
+use crate::prometheus::{io_metrics, IoOperation};
+use crate::record_stream::{BatchRecord, ConsumedRecord, DynRecordStream, RecordStreamError};
+use async_trait::async_trait;
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Error of a pipeline endpoint.
+pub trait PipelineError: Error + Send + Sync + 'static {
+  /// Returns a stable name for the kind of failure, for logs and metrics.
+  fn category(&self) -> &'static str;
+}
+
+#[async_trait]
+pub trait Endpoint: Send + Sync {
+  type Error: PipelineError;
+
+  /// Name of the endpoint in logs and metrics.
+  fn endpoint_name(&self) -> &str;
+
+  /// Checks that the external system is reachable and usable.
+  async fn check(&self) -> Result<(), Self::Error> {
+    Ok(())
+  }
+}
+
+#[async_trait]
+pub trait Source: Endpoint {
+  type Item: Send;
+
+  /// Returns up to `max_count` records, waiting up to `max_wait` for the first one.
+  /// The batch is empty if no record is available in time.
+  async fn next_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<Self::Item>, Self::Error>;
+
+  /// Marks the records returned so far as processed, so that they are not read again.
+  async fn commit(&self) -> Result<(), Self::Error>;
+}
+
+#[async_trait]
+pub trait Sink<T: ?Sized + Sync>: Endpoint {
+  /// Writes the items, and returns once all items are stored.
+  async fn send(&self, items: &T) -> Result<(), Self::Error>;
+}
+
+/// Runs an operation of the endpoint, and records its duration and outcome.
+pub async fn observe<T, E, F>(endpoint: &str, operation: IoOperation, future: F) -> Result<T, E>
+where
+  E: PipelineError,
+  F: Future<Output = Result<T, E>>,
+{
+  let started_at = Instant::now();
+  let result = future.await;
+  if let Some(metrics) = io_metrics() {
+    let outcome = result.as_ref().err().map(|e| e.category()).unwrap_or("ok");
+    metrics.operation(endpoint, operation, outcome, started_at.elapsed());
+  }
+  result
+}
+
+impl PipelineError for RecordStreamError {
+  fn category(&self) -> &'static str {
+    RecordStreamError::category(self)
+  }
+}
+
+impl PipelineError for DataLakeError {
+  fn category(&self) -> &'static str {
+    DataLakeError::category(self)
+  }
+}
+
+impl PipelineError for LakeSinkError {
+  fn category(&self) -> &'static str {
+    LakeSinkError::category(self)
+  }
+}
+
+impl PipelineError for AggregatorError {
+  fn category(&self) -> &'static str {
+    AggregatorError::category(self)
+  }
+}
+
+#[async_trait]
+impl Endpoint for DynRecordStream {
+  type Error = RecordStreamError;
+
+  fn endpoint_name(&self) -> &str {
+    "record_stream"
+  }
+
+  async fn check(&self) -> Result<(), RecordStreamError> {
+    self.check_connection().await
+  }
+}
+
+#[async_trait]
+impl Source for DynRecordStream {
+  type Item = ConsumedRecord;
+
+  async fn next_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<ConsumedRecord>, RecordStreamError> {
+    self.consume_batch(max_count, max_wait).await
+  }
+
+  async fn commit(&self) -> Result<(), RecordStreamError> {
+    self.commit_last_consume().await
+  }
+}
+
+#[async_trait]
+impl<'a> Sink<[BatchRecord<'a>]> for DynRecordStream {
+  async fn send(&self, items: &[BatchRecord<'a>]) -> Result<(), RecordStreamError> {
+    self.produce_batch(items).await
+  }
+}
+
+/// Contents of a lake object, i.e. newline delimited records.
+pub struct LakeBatch<'a> {
+  pub channel_name: &'a str,
+  pub kind: LakeSinkKind,
+  pub contents: &'a str,
+}
+
+#[async_trait]
+impl Endpoint for DataLake {
+  type Error = DataLakeError;
+
+  fn endpoint_name(&self) -> &str {
+    "lake"
+  }
+
+  async fn check(&self) -> Result<(), DataLakeError> {
+    self.check_access().await
+  }
+}
+
+#[async_trait]
+impl<'a> Sink<LakeBatch<'a>> for DataLake {
+  async fn send(&self, batch: &LakeBatch<'a>) -> Result<(), DataLakeError> {
+    match batch.kind {
+      LakeSinkKind::MessageArchive => {
+        self
+          .store_messages(batch.channel_name, batch.contents)
+          .await
+      }
+      LakeSinkKind::Measurements => self.store(batch.channel_name, batch.contents).await,
+    }
+  }
+}
+
+#[async_trait]
+impl Endpoint for DynOutputSink {
+  type Error = AggregatorError;
+
+  fn endpoint_name(&self) -> &str {
+    self.name()
+  }
+}
+
+#[async_trait]
+impl Sink<[OutboxMeasurement]> for DynOutputSink {
+  async fn send(&self, measurements: &[OutboxMeasurement]) -> Result<(), AggregatorError> {
+    self.send_batch(measurements).await
+  }
+}
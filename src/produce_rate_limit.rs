@@ -0,0 +1,324 @@
+//! Rate limits of produced records, so that a burst of submissions or re-driven records
+//! cannot overwhelm the brokers. If `KAFKA_<COMPONENT>_PRODUCE_MAX_MESSAGES_PER_SEC` or
+//! `KAFKA_<COMPONENT>_PRODUCE_MAX_BYTES_PER_SEC` is set, the producer of each record stream
+//! of the component is wrapped with token buckets, which hold up to one second of records
+//! or bytes. Produces wait until the buckets have enough tokens for their records, instead
+//! of failing. A record larger than a bucket is produced once the bucket is full, and the
+//! following produces wait until its bytes are paid off.
+//!
+//! The limits apply to each record stream separately, and to all stream backends.
+
+use crate::record_stream::{
+  BatchRecord, ConsumedRecord, KafkaComponent, PartitionPosition, RecordStream, RecordStreamArc,
+  RecordStreamError,
+};
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+
+const PRODUCE_MAX_MESSAGES_PER_SEC_ENV_KEY_SUFFIX: &str = "PRODUCE_MAX_MESSAGES_PER_SEC";
+const PRODUCE_MAX_BYTES_PER_SEC_ENV_KEY_SUFFIX: &str = "PRODUCE_MAX_BYTES_PER_SEC";
+const DEFAULT_PRODUCE_RATE_LIMIT: &str = "0";
+
+/// Max produce rates of a component; zero disables a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProduceRateLimit {
+  pub messages_per_sec: u64,
+  pub bytes_per_sec: u64,
+}
+
+impl ProduceRateLimit {
+  pub fn from_env(component: KafkaComponent) -> Self {
+    Self {
+      messages_per_sec: parse_env_var(
+        &component.env_key(PRODUCE_MAX_MESSAGES_PER_SEC_ENV_KEY_SUFFIX),
+        DEFAULT_PRODUCE_RATE_LIMIT,
+      ),
+      bytes_per_sec: parse_env_var(
+        &component.env_key(PRODUCE_MAX_BYTES_PER_SEC_ENV_KEY_SUFFIX),
+        DEFAULT_PRODUCE_RATE_LIMIT,
+      ),
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.messages_per_sec > 0 || self.bytes_per_sec > 0
+  }
+}
+
+/// Token bucket that is refilled at `rate` tokens per second, up to `rate` tokens.
+struct TokenBucket {
+  rate: f64,
+  tokens: f64,
+  refilled_at: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate: u64, now: Instant) -> Self {
+    Self {
+      rate: rate as f64,
+      tokens: rate as f64,
+      refilled_at: now,
+    }
+  }
+
+  /// Takes the tokens, and returns the time to wait until they are available.
+  /// Missing tokens are taken from future refills, so the next take waits for them.
+  fn take(&mut self, tokens: u64, now: Instant) -> Duration {
+    let elapsed = now.saturating_duration_since(self.refilled_at);
+    self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+    self.refilled_at = now;
+    // Takes larger than the bucket only wait until it is full
+    let needed = (tokens as f64).min(self.rate.max(self.tokens));
+    let wait = Duration::from_secs_f64(((needed - self.tokens) / self.rate).max(0.0));
+    self.tokens -= tokens as f64;
+    wait
+  }
+}
+
+/// Wraps the stream with the rate limit, if enabled.
+pub fn with_produce_rate_limit(
+  stream: RecordStreamArc,
+  limit: ProduceRateLimit,
+) -> RecordStreamArc {
+  if !limit.is_enabled() {
+    return stream;
+  }
+  Arc::new(RateLimitedRecordStream::new(stream, limit))
+}
+
+struct Buckets {
+  messages: Option<TokenBucket>,
+  bytes: Option<TokenBucket>,
+}
+
+/// Record stream that delays produces to the wrapped stream according to the rate limit.
+pub struct RateLimitedRecordStream {
+  inner: RecordStreamArc,
+  buckets: Mutex<Buckets>,
+}
+
+impl RateLimitedRecordStream {
+  pub fn new(inner: RecordStreamArc, limit: ProduceRateLimit) -> Self {
+    let now = Instant::now();
+    let bucket = |rate: u64| (rate > 0).then(|| TokenBucket::new(rate, now));
+    Self {
+      inner,
+      buckets: Mutex::new(Buckets {
+        messages: bucket(limit.messages_per_sec),
+        bytes: bucket(limit.bytes_per_sec),
+      }),
+    }
+  }
+
+  /// Waits until the records may be produced. Produces are admitted in order, since
+  /// the buckets stay locked while waiting.
+  async fn acquire(&self, messages: usize, bytes: usize) {
+    let mut buckets = self.buckets.lock().await;
+    let now = Instant::now();
+    let wait = [
+      buckets
+        .messages
+        .as_mut()
+        .map(|v| v.take(messages as u64, now)),
+      buckets.bytes.as_mut().map(|v| v.take(bytes as u64, now)),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .unwrap_or_default();
+    if !wait.is_zero() {
+      sleep(wait).await;
+    }
+  }
+}
+
+#[async_trait]
+impl RecordStream for RateLimitedRecordStream {
+  fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
+    self.inner.init_producer_transactions()
+  }
+
+  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.begin_producer_transaction()
+  }
+
+  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.commit_producer_transaction()
+  }
+
+  fn abort_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.abort_producer_transaction()
+  }
+
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.inner.check_connection().await
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    self.inner.has_assigned_partitions()
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    self.inner.assigned_partitions()
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.committed_offsets()
+  }
+
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.end_offsets()
+  }
+
+  fn partition_positions(&self) -> Result<Vec<PartitionPosition>, RecordStreamError> {
+    self.inner.partition_positions()
+  }
+
+  fn partition_lags(&self) -> Result<Vec<(i32, i64)>, RecordStreamError> {
+    self.inner.partition_lags()
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    self.acquire(1, record.len()).await;
+    self
+      .inner
+      .produce_with_headers(
+        record,
+        key,
+        request_threshold,
+        channel_name,
+        epoch,
+        extra_headers,
+      )
+      .await
+  }
+
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    self
+      .acquire(records.len(), records.iter().map(|v| v.data.len()).sum())
+      .await;
+    self.inner.produce_batch(records).await
+  }
+
+  async fn init_producer_queues(&self) {
+    self.inner.init_producer_queues().await
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    self.acquire(1, record.len()).await;
+    self.inner.queue_produce(record, key).await
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    self.inner.join_produce_queues().await
+  }
+
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    self.inner.consume().await
+  }
+
+  async fn consume_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<ConsumedRecord>, RecordStreamError> {
+    self.inner.consume_batch(max_count, max_wait).await
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    self.inner.commit_last_consume().await
+  }
+
+  fn pause_partition(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    self.inner.pause_partition(partition, offset)
+  }
+
+  fn pause(&self) -> Result<bool, RecordStreamError> {
+    self.inner.pause()
+  }
+
+  fn resume(&self) -> Result<(), RecordStreamError> {
+    self.inner.resume()
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    self.inner.commit_offsets(offsets).await
+  }
+
+  async fn shutdown(&self, commit: bool) -> Result<(), RecordStreamError> {
+    self.inner.shutdown(commit).await
+  }
+
+  fn seek(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    self.inner.seek(partition, offset)
+  }
+
+  fn seek_to_timestamp(&self, timestamp: OffsetDateTime) -> Result<bool, RecordStreamError> {
+    self.inner.seek_to_timestamp(timestamp)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::TestRecordStream;
+
+  #[test]
+  fn token_bucket() {
+    let start = Instant::now();
+    let ms = |v: u64| start + Duration::from_millis(v);
+    let mut bucket = TokenBucket::new(8, start);
+    // The full bucket admits a second of tokens without waiting
+    assert_eq!(bucket.take(8, start), Duration::ZERO);
+    assert_eq!(bucket.take(2, start), Duration::from_millis(250));
+    // Refills pay off the missing tokens first
+    assert_eq!(bucket.take(2, ms(250)), Duration::from_millis(250));
+    assert_eq!(bucket.take(4, ms(2000)), Duration::ZERO);
+    // Takes larger than the bucket only wait until it is full
+    assert_eq!(bucket.take(24, ms(2000)), Duration::from_millis(500));
+    assert_eq!(bucket.take(1, ms(3000)), Duration::from_millis(1625));
+  }
+
+  #[tokio::test]
+  async fn delays_produces() {
+    let inner = Arc::new(TestRecordStream::default());
+    let stream = with_produce_rate_limit(
+      inner.clone(),
+      ProduceRateLimit {
+        messages_per_sec: 0,
+        bytes_per_sec: 100,
+      },
+    );
+    let start = Instant::now();
+    stream
+      .produce(&[0; 80], None, None, None, None)
+      .await
+      .unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+    stream
+      .produce(&[0; 40], None, None, None, None)
+      .await
+      .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(inner.records_produced.lock().await.len(), 2);
+    assert!(!ProduceRateLimit::default().is_enabled());
+  }
+}
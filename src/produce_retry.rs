@@ -0,0 +1,210 @@
+//! Retries of Kafka produce requests that failed due to transient broker errors, such as
+//! leader elections, unavailable brokers or delivery timeouts. Without retries, these
+//! errors would fail the submission or the worker that produced the record. Up to
+//! `KAFKA_<COMPONENT>_PRODUCE_MAX_ATTEMPTS` attempts are made, waiting an exponentially
+//! increasing, jittered backoff between attempts, starting at
+//! `KAFKA_<COMPONENT>_PRODUCE_RETRY_BACKOFF_MS` and bounded by
+//! `KAFKA_<COMPONENT>_PRODUCE_RETRY_MAX_BACKOFF_MS`.
+//!
+//! A record that timed out may have been stored by the broker, so a retry may store
+//! the record twice. Consumers already handle duplicates of redelivered records.
+
+use crate::prometheus::kafka_metrics;
+use crate::record_stream::KafkaComponent;
+use crate::util::parse_env_var;
+use rand::{thread_rng, Rng};
+use rdkafka::error::KafkaError;
+use rdkafka::types::RDKafkaErrorCode;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const PRODUCE_MAX_ATTEMPTS_ENV_KEY_SUFFIX: &str = "PRODUCE_MAX_ATTEMPTS";
+const DEFAULT_PRODUCE_MAX_ATTEMPTS: &str = "3";
+const PRODUCE_RETRY_BACKOFF_MS_ENV_KEY_SUFFIX: &str = "PRODUCE_RETRY_BACKOFF_MS";
+const DEFAULT_PRODUCE_RETRY_BACKOFF_MS: &str = "100";
+const PRODUCE_RETRY_MAX_BACKOFF_MS_ENV_KEY_SUFFIX: &str = "PRODUCE_RETRY_MAX_BACKOFF_MS";
+const DEFAULT_PRODUCE_RETRY_MAX_BACKOFF_MS: &str = "5000";
+
+/// Error codes of produce requests that may succeed if retried
+const TRANSIENT_ERROR_CODES: [RDKafkaErrorCode; 14] = [
+  RDKafkaErrorCode::BrokerTransportFailure,
+  RDKafkaErrorCode::MessageTimedOut,
+  RDKafkaErrorCode::AllBrokersDown,
+  RDKafkaErrorCode::OperationTimedOut,
+  RDKafkaErrorCode::QueueFull,
+  RDKafkaErrorCode::TimedOutQueue,
+  RDKafkaErrorCode::LeaderNotAvailable,
+  RDKafkaErrorCode::NotLeaderForPartition,
+  RDKafkaErrorCode::RequestTimedOut,
+  RDKafkaErrorCode::BrokerNotAvailable,
+  RDKafkaErrorCode::NetworkException,
+  RDKafkaErrorCode::NotEnoughReplicas,
+  RDKafkaErrorCode::NotEnoughReplicasAfterAppend,
+  RDKafkaErrorCode::KafkaStorageError,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProduceRetryPolicy {
+  pub max_attempts: u32,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl ProduceRetryPolicy {
+  pub fn from_env(component: KafkaComponent) -> Self {
+    let max_attempts = parse_env_var::<u32>(
+      &component.env_key(PRODUCE_MAX_ATTEMPTS_ENV_KEY_SUFFIX),
+      DEFAULT_PRODUCE_MAX_ATTEMPTS,
+    );
+    assert!(
+      max_attempts > 0,
+      "{} must be positive",
+      component.env_key(PRODUCE_MAX_ATTEMPTS_ENV_KEY_SUFFIX)
+    );
+    Self {
+      max_attempts,
+      initial_backoff: Duration::from_millis(parse_env_var(
+        &component.env_key(PRODUCE_RETRY_BACKOFF_MS_ENV_KEY_SUFFIX),
+        DEFAULT_PRODUCE_RETRY_BACKOFF_MS,
+      )),
+      max_backoff: Duration::from_millis(parse_env_var(
+        &component.env_key(PRODUCE_RETRY_MAX_BACKOFF_MS_ENV_KEY_SUFFIX),
+        DEFAULT_PRODUCE_RETRY_MAX_BACKOFF_MS,
+      )),
+    }
+  }
+
+  /// Returns the upper bound of the backoff after the failed attempt, starting at 1.
+  fn max_backoff_after(&self, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    self
+      .initial_backoff
+      .saturating_mul(factor)
+      .min(self.max_backoff)
+  }
+
+  /// Returns a random backoff between half of the upper bound and the upper bound,
+  /// so that producers that failed at the same time do not retry at the same time.
+  fn backoff_after(&self, attempt: u32) -> Duration {
+    let upper = self.max_backoff_after(attempt);
+    upper / 2 + (upper / 2).mul_f64(thread_rng().gen::<f64>())
+  }
+
+  /// Runs the send until it succeeds, fails with a permanent error, or the attempts
+  /// are exhausted. Each retry is logged and counted in the `kafka_produce_retries` metric.
+  pub async fn send<F, Fut>(&self, topic: &str, mut send: F) -> Result<(), KafkaError>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), KafkaError>>,
+  {
+    let mut attempt = 1;
+    loop {
+      let error = match send().await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+      };
+      if attempt >= self.max_attempts || !is_transient(&error) {
+        return Err(error);
+      }
+      let backoff = self.backoff_after(attempt);
+      warn!(
+        "Produce attempt {} to topic {} failed, retrying in {}ms: {}",
+        attempt,
+        topic,
+        backoff.as_millis(),
+        error
+      );
+      if let Some(metrics) = kafka_metrics() {
+        metrics.produce_retry(topic, &error_name(&error));
+      }
+      sleep(backoff).await;
+      attempt += 1;
+    }
+  }
+}
+
+fn is_transient(error: &KafkaError) -> bool {
+  error
+    .rdkafka_error_code()
+    .map(|code| TRANSIENT_ERROR_CODES.contains(&code))
+    .unwrap_or(false)
+}
+
+/// Returns the name of the error code, for the metric labels.
+pub fn error_name(error: &KafkaError) -> String {
+  match error.rdkafka_error_code() {
+    Some(code) => format!("{:?}", code),
+    None => "unknown".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  fn policy(max_attempts: u32) -> ProduceRetryPolicy {
+    ProduceRetryPolicy {
+      max_attempts,
+      initial_backoff: Duration::from_millis(10),
+      max_backoff: Duration::from_millis(35),
+    }
+  }
+
+  #[test]
+  fn backoff() {
+    let policy = policy(5);
+    assert_eq!(policy.max_backoff_after(1), Duration::from_millis(10));
+    assert_eq!(policy.max_backoff_after(2), Duration::from_millis(20));
+    assert_eq!(policy.max_backoff_after(3), Duration::from_millis(35));
+    assert_eq!(policy.max_backoff_after(40), Duration::from_millis(35));
+    for attempt in 1..5 {
+      let backoff = policy.backoff_after(attempt);
+      let upper = policy.max_backoff_after(attempt);
+      assert!(backoff >= upper / 2 && backoff <= upper);
+    }
+  }
+
+  #[tokio::test]
+  async fn retries() {
+    let attempts = AtomicU32::new(0);
+    let result = policy(3)
+      .send("p3a-star-enc", || async {
+        match attempts.fetch_add(1, Ordering::SeqCst) {
+          0 => Err(KafkaError::MessageProduction(
+            RDKafkaErrorCode::NotLeaderForPartition,
+          )),
+          _ => Ok(()),
+        }
+      })
+      .await;
+    assert!(result.is_ok());
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    let attempts = AtomicU32::new(0);
+    let result = policy(3)
+      .send("p3a-star-enc", || async {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Err(KafkaError::MessageProduction(
+          RDKafkaErrorCode::MessageTimedOut,
+        ))
+      })
+      .await;
+    assert!(result.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    // Permanent errors are not retried
+    let attempts = AtomicU32::new(0);
+    let result = policy(3)
+      .send("p3a-star-enc", || async {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Err(KafkaError::MessageProduction(
+          RDKafkaErrorCode::MessageSizeTooLarge,
+        ))
+      })
+      .await;
+    assert!(result.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+  }
+}
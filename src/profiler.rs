@@ -83,6 +83,13 @@ pub enum ProfilerStat {
   RecoveredMsgDelete,
   TagsPerTask,
   OutStreamProduceTime,
+  AgedPendingMsgsDiscarded,
+  OutboxMsmtGet,
+  OutboxMsmtInsert,
+  OutboxMsmtMarkSent,
+  OutboxMsmtDelete,
+  ExcessSharesCounted,
+  ExcessSharesDiscarded,
 }
 
 #[derive(Default)]
@@ -102,18 +109,15 @@ impl Profiler {
     if !self.stats.read().await.contains_key(&key) {
       let mut stats = self.stats.write().await;
       // Check key again to handle potential race condition
-      if !stats.contains_key(&key) {
-        stats.insert(
-          key,
-          Mutex::new(StatInfo::Range {
-            unit,
-            min: u32::MAX,
-            max: 0,
-            sum: 0,
-            entries: BinaryHeap::with_capacity(2000),
-          }),
-        );
-      }
+      stats.entry(key).or_insert_with(|| {
+        Mutex::new(StatInfo::Range {
+          unit,
+          min: u32::MAX,
+          max: 0,
+          sum: 0,
+          entries: BinaryHeap::with_capacity(2000),
+        })
+      });
     }
     let stats = self.stats.read().await;
     let mut stat_info = stats.get(&key).unwrap().lock().await;
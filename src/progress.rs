@@ -0,0 +1,179 @@
+//! Periodic progress output for long-running aggregator phases, so that
+//! operators can follow the progress of interactive runs and backfills.
+//! Progress is written to stderr, either as human-readable text or as JSON lines.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL_SECS: u64 = 5;
+
+static PROGRESS: OnceLock<Progress> = OnceLock::new();
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ProgressMode {
+  /// Text output if stderr is a terminal, no output otherwise
+  Auto,
+  Text,
+  Json,
+  Off,
+}
+
+struct PhaseState {
+  name: &'static str,
+  total: Option<u64>,
+  start_instant: Instant,
+}
+
+struct Progress {
+  json: bool,
+  phase: Mutex<Option<PhaseState>>,
+  processed: AtomicU64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ProgressSnapshot {
+  pub phase: &'static str,
+  pub processed: u64,
+  pub total: Option<u64>,
+  pub rate_per_sec: f64,
+  pub eta_secs: Option<u64>,
+}
+
+impl ProgressSnapshot {
+  fn new(phase: &PhaseState, processed: u64, elapsed: Duration) -> Self {
+    let rate_per_sec = match elapsed.as_secs_f64() {
+      secs if secs > 0.0 => processed as f64 / secs,
+      _ => 0.0,
+    };
+    let eta_secs = phase
+      .total
+      .filter(|_| rate_per_sec > 0.0)
+      .map(|total| (total.saturating_sub(processed) as f64 / rate_per_sec).ceil() as u64);
+    Self {
+      phase: phase.name,
+      processed,
+      total: phase.total,
+      rate_per_sec,
+      eta_secs,
+    }
+  }
+
+  fn to_text(&self) -> String {
+    let mut result = format!("[{}] {}", self.phase, self.processed);
+    if let Some(total) = self.total {
+      result += &format!("/{}", total);
+      if total > 0 {
+        result += &format!(" ({:.1}%)", self.processed as f64 * 100.0 / total as f64);
+      }
+    }
+    result += &format!(", {:.1}/s", self.rate_per_sec);
+    if let Some(eta_secs) = self.eta_secs {
+      result += &format!(", ETA {}m{:02}s", eta_secs / 60, eta_secs % 60);
+    }
+    result
+  }
+}
+
+impl Progress {
+  fn print(&self) {
+    let phase = self.phase.lock().unwrap();
+    if let Some(phase) = phase.as_ref() {
+      let snapshot = ProgressSnapshot::new(
+        phase,
+        self.processed.load(Ordering::Relaxed),
+        phase.start_instant.elapsed(),
+      );
+      match self.json {
+        true => eprintln!("{}", serde_json::to_string(&snapshot).unwrap()),
+        false => eprintln!("{}", snapshot.to_text()),
+      }
+    }
+  }
+}
+
+/// Enables progress output, if applicable for the mode.
+/// Must be called within the Tokio runtime.
+pub fn init_progress(mode: ProgressMode) {
+  let json = match mode {
+    ProgressMode::Auto if io::stderr().is_terminal() => false,
+    ProgressMode::Text => false,
+    ProgressMode::Json => true,
+    _ => return,
+  };
+  let progress = PROGRESS.get_or_init(|| Progress {
+    json,
+    phase: Mutex::new(None),
+    processed: AtomicU64::new(0),
+  });
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(PROGRESS_INTERVAL_SECS));
+    loop {
+      interval.tick().await;
+      progress.print();
+    }
+  });
+}
+
+/// Starts a new phase, replacing the current phase. `total` is the expected
+/// amount of items to process in the phase, if known.
+pub fn start_phase(name: &'static str, total: Option<u64>) {
+  if let Some(progress) = PROGRESS.get() {
+    progress.processed.store(0, Ordering::Relaxed);
+    *progress.phase.lock().unwrap() = Some(PhaseState {
+      name,
+      total,
+      start_instant: Instant::now(),
+    });
+  }
+}
+
+pub fn record_progress(count: u64) {
+  if let Some(progress) = PROGRESS.get() {
+    progress.processed.fetch_add(count, Ordering::Relaxed);
+  }
+}
+
+/// Prints the final progress of the current phase, and ends the phase.
+pub fn end_phase() {
+  if let Some(progress) = PROGRESS.get() {
+    progress.print();
+    *progress.phase.lock().unwrap() = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn snapshot() {
+    let phase = PhaseState {
+      name: "consume",
+      total: Some(1000),
+      start_instant: Instant::now(),
+    };
+    let snapshot = ProgressSnapshot::new(&phase, 250, Duration::from_secs(10));
+    assert_eq!(
+      snapshot,
+      ProgressSnapshot {
+        phase: "consume",
+        processed: 250,
+        total: Some(1000),
+        rate_per_sec: 25.0,
+        eta_secs: Some(30),
+      }
+    );
+    assert_eq!(
+      snapshot.to_text(),
+      "[consume] 250/1000 (25.0%), 25.0/s, ETA 0m30s"
+    );
+
+    let snapshot = ProgressSnapshot::new(&phase, 0, Duration::from_secs(0));
+    assert_eq!(snapshot.eta_secs, None);
+    assert_eq!(snapshot.to_text(), "[consume] 0/1000 (0.0%), 0.0/s");
+  }
+}
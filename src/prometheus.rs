@@ -1,22 +1,93 @@
+//! Metrics of all subsystems are registered in a single `MetricsRegistry`, which is
+//! served by one exporter per process. Each subsystem registers its metrics under its
+//! own namespace, which prefixes the metric names (i.e. `lake_sink_consumer_lag`), and
+//! all metrics are labeled with the modes run by the process and the instance name.
+
 use actix_web::dev::ServiceRequest;
 use actix_web::error::InternalError;
 use actix_web::{dev::Server, web, App, HttpResponse, HttpServer};
 use prometheus_client::encoding::text::encode;
-use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
 use reqwest::StatusCode;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
 use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tokio::sync::Mutex;
 
+const METRICS_INSTANCE_ENV_KEY: &str = "METRICS_INSTANCE";
+const HOSTNAME_ENV_KEY: &str = "HOSTNAME";
+
+/// Metrics of a subsystem, registered under the namespace of the subsystem.
+pub trait SubsystemMetrics {
+  const NAMESPACE: &'static str;
+
+  fn register_metrics(&self, registry: &mut Registry);
+}
+
+/// Registry of the metrics of all subsystems run by the process.
+pub struct MetricsRegistry {
+  registry: Registry,
+}
+
+impl MetricsRegistry {
+  /// Creates a registry with the common labels of all metrics. `modes` lists the
+  /// modes run by the process. The instance name is taken from `METRICS_INSTANCE`,
+  /// or the hostname if unset.
+  pub fn new(modes: &[&str]) -> Self {
+    let instance = env::var(METRICS_INSTANCE_ENV_KEY)
+      .or_else(|_| env::var(HOSTNAME_ENV_KEY))
+      .unwrap_or_default();
+    Self::with_labels(modes.join(","), instance)
+  }
+
+  fn with_labels(mode: String, instance: String) -> Self {
+    let labels: [(Cow<'static, str>, Cow<'static, str>); 2] = [
+      (Cow::Borrowed("mode"), Cow::Owned(mode)),
+      (Cow::Borrowed("instance"), Cow::Owned(instance)),
+    ];
+    Self {
+      registry: Registry::with_labels(labels.into_iter()),
+    }
+  }
+
+  pub fn register<M: SubsystemMetrics>(&mut self, metrics: &M) {
+    metrics.register_metrics(self.registry.sub_registry_with_prefix(M::NAMESPACE));
+  }
+}
+
 pub struct WebMetrics {
   total_requests: Family<TotalMetricLabels, Counter>,
   in_flight_requests: Family<InflightMetricLabels, Gauge>,
   request_duration: Family<TotalMetricLabels, Histogram>,
+  sampled_out_submissions: Family<ChannelMetricLabels, Counter>,
+  budget_exceeded_submissions: Family<BudgetMetricLabels, Counter>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ChannelMetricLabels {
+  channel: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BudgetMetricLabels {
+  channel: String,
+  outcome: BudgetExceededOutcome,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum BudgetExceededOutcome {
+  /// The message was left to the local queue
+  Queued,
+  Rejected,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -51,16 +122,39 @@ impl From<(&InflightMetricLabels, StatusCode)> for TotalMetricLabels {
   }
 }
 
-impl WebMetrics {
-  pub fn new() -> Self {
+impl Default for WebMetrics {
+  fn default() -> Self {
     Self {
       total_requests: Family::default(),
       in_flight_requests: Family::default(),
       request_duration: Family::new_with_constructor(|| {
         Histogram::new(exponential_buckets(0.01, 2., 8))
       }),
+      sampled_out_submissions: Family::default(),
+      budget_exceeded_submissions: Family::default(),
     }
   }
+}
+
+impl WebMetrics {
+  pub fn submission_sampled_out(&self, channel_name: &str) {
+    self
+      .sampled_out_submissions
+      .get_or_create(&ChannelMetricLabels {
+        channel: channel_name.to_string(),
+      })
+      .inc();
+  }
+
+  pub fn submission_budget_exceeded(&self, channel_name: &str, outcome: BudgetExceededOutcome) {
+    self
+      .budget_exceeded_submissions
+      .get_or_create(&BudgetMetricLabels {
+        channel: channel_name.to_string(),
+        outcome,
+      })
+      .inc();
+  }
 
   pub fn request_start(&self, labels: &InflightMetricLabels) {
     self.in_flight_requests.get_or_create(labels).inc();
@@ -87,8 +181,12 @@ impl WebMetrics {
       }
     };
   }
+}
+
+impl SubsystemMetrics for WebMetrics {
+  const NAMESPACE: &'static str = "server";
 
-  pub fn register_metrics(&self, registry: &mut Registry) {
+  fn register_metrics(&self, registry: &mut Registry) {
     registry.register(
       "api_requests",
       "Number of total requests",
@@ -104,26 +202,220 @@ impl WebMetrics {
       "Histogram of latencies for requests",
       self.request_duration.clone(),
     );
+    registry.register(
+      "sampled_out_submissions",
+      "Number of submissions discarded due to the channel sampling rate",
+      self.sampled_out_submissions.clone(),
+    );
+    registry.register(
+      "budget_exceeded_submissions",
+      "Number of submissions not stored within the submission budget",
+      self.budget_exceeded_submissions.clone(),
+    );
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum S3Operation {
+  Put,
+  Get,
+  List,
+  Delete,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct S3RequestMetricLabels {
+  operation: S3Operation,
+  prefix_class: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct S3CostMetricLabels {
+  prefix_class: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LakeSinkMetricLabels {
+  pub sink: String,
+  pub channel_name: String,
+  /// Index of the consumer task within the sinks of the topic
+  pub consumer: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LakeSinkPartitionMetricLabels {
+  sink: String,
+  channel_name: String,
+  consumer: String,
+  partition: String,
+}
+
+impl LakeSinkPartitionMetricLabels {
+  fn new(labels: &LakeSinkMetricLabels, partition: i32) -> Self {
+    Self {
+      sink: labels.sink.clone(),
+      channel_name: labels.channel_name.clone(),
+      consumer: labels.consumer.clone(),
+      partition: partition.to_string(),
+    }
+  }
+}
+
+/// S3 request metrics of the data lake.
+#[derive(Default)]
+pub struct LakeMetrics {
+  s3_requests_total: Family<S3RequestMetricLabels, Counter>,
+  s3_bytes_total: Family<S3RequestMetricLabels, Counter>,
+  s3_estimated_cost: Family<S3CostMetricLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl LakeMetrics {
+  /// Records a completed S3 request. `prefix_class` describes the category
+  /// of keys affected by the request, i.e. the channel name.
+  pub fn s3_request(
+    &self,
+    operation: S3Operation,
+    prefix_class: &str,
+    bytes: usize,
+    estimated_cost: f64,
+  ) {
+    let labels = S3RequestMetricLabels {
+      operation,
+      prefix_class: prefix_class.to_string(),
+    };
+    self.s3_requests_total.get_or_create(&labels).inc();
+    self
+      .s3_bytes_total
+      .get_or_create(&labels)
+      .inc_by(bytes as u64);
+    self
+      .s3_estimated_cost
+      .get_or_create(&S3CostMetricLabels {
+        prefix_class: prefix_class.to_string(),
+      })
+      .inc_by(estimated_cost);
+  }
+}
+
+impl SubsystemMetrics for LakeMetrics {
+  const NAMESPACE: &'static str = "lake";
+
+  fn register_metrics(&self, registry: &mut Registry) {
+    registry.register(
+      "s3_requests",
+      "Number of total S3 requests",
+      self.s3_requests_total.clone(),
+    );
+    registry.register(
+      "s3_bytes",
+      "Number of total bytes transferred in S3 requests",
+      self.s3_bytes_total.clone(),
+    );
+    registry.register(
+      "s3_estimated_cost_usd",
+      "Estimated cost of S3 requests and storage for one month, in USD",
+      self.s3_estimated_cost.clone(),
+    );
   }
 }
 
 #[derive(Default)]
-pub struct DataLakeMetrics {
-  records_saved_total: Counter,
-  batch_record_total: Gauge,
+pub struct LakeSinkMetrics {
+  records_saved_total: Family<LakeSinkMetricLabels, Counter>,
+  batch_record_total: Family<LakeSinkMetricLabels, Gauge>,
+  consumer_lag: Family<LakeSinkMetricLabels, Gauge>,
+  partition_records_saved_total: Family<LakeSinkPartitionMetricLabels, Counter>,
+  partition_consumer_lag: Family<LakeSinkPartitionMetricLabels, Gauge>,
+  /// Partitions with a reported lag for each consumer, so that the
+  /// lag of revoked partitions can be removed
+  lag_partitions: std::sync::Mutex<HashMap<LakeSinkMetricLabels, Vec<i32>>>,
+  consumer_paused: Family<LakeSinkMetricLabels, Gauge>,
+  canary_last_output_timestamp: Gauge,
+  canary_output_stale: Gauge,
 }
 
-impl DataLakeMetrics {
-  pub fn record_received(&self) {
-    self.batch_record_total.inc();
+impl LakeSinkMetrics {
+  pub fn records_received(&self, labels: &LakeSinkMetricLabels, count: usize) {
+    self
+      .batch_record_total
+      .get_or_create(labels)
+      .inc_by(count as i64);
   }
 
-  pub fn records_flushed(&self, count: usize) {
-    self.records_saved_total.inc_by(count as u64);
-    self.batch_record_total.dec_by(count as i64);
+  pub fn records_flushed(&self, labels: &LakeSinkMetricLabels, count: usize) {
+    self
+      .records_saved_total
+      .get_or_create(labels)
+      .inc_by(count as u64);
+    self
+      .batch_record_total
+      .get_or_create(labels)
+      .dec_by(count as i64);
   }
 
-  pub fn register_metrics(&self, registry: &mut Registry) {
+  /// Records the amount of flushed records in each partition, given as (partition, count).
+  pub fn partition_records_flushed(
+    &self,
+    labels: &LakeSinkMetricLabels,
+    partition_counts: &[(i32, usize)],
+  ) {
+    for (partition, count) in partition_counts {
+      self
+        .partition_records_saved_total
+        .get_or_create(&LakeSinkPartitionMetricLabels::new(labels, *partition))
+        .inc_by(*count as u64);
+    }
+  }
+
+  /// Sets the total lag of the consumer, and the lag of each assigned partition,
+  /// given as (partition, lag). Partitions that are no longer assigned are removed.
+  pub fn set_consumer_lag(&self, labels: &LakeSinkMetricLabels, partition_lags: &[(i32, i64)]) {
+    self
+      .consumer_lag
+      .get_or_create(labels)
+      .set(partition_lags.iter().map(|(_, lag)| lag).sum());
+    let mut lag_partitions = self.lag_partitions.lock().unwrap();
+    let prev_partitions = lag_partitions.entry(labels.clone()).or_default();
+    for partition in prev_partitions.iter() {
+      if !partition_lags.iter().any(|(v, _)| v == partition) {
+        self
+          .partition_consumer_lag
+          .remove(&LakeSinkPartitionMetricLabels::new(labels, *partition));
+      }
+    }
+    for (partition, lag) in partition_lags {
+      self
+        .partition_consumer_lag
+        .get_or_create(&LakeSinkPartitionMetricLabels::new(labels, *partition))
+        .set(*lag);
+    }
+    *prev_partitions = partition_lags.iter().map(|(v, _)| *v).collect();
+  }
+
+  pub fn set_consumer_paused(&self, labels: &LakeSinkMetricLabels, paused: bool) {
+    self
+      .consumer_paused
+      .get_or_create(labels)
+      .set(paused as i64);
+  }
+
+  pub fn canary_output_received(&self, timestamp: i64) {
+    self.canary_last_output_timestamp.set(timestamp);
+  }
+
+  pub fn canary_last_output_timestamp(&self) -> i64 {
+    self.canary_last_output_timestamp.get()
+  }
+
+  pub fn set_canary_stale(&self, stale: bool) {
+    self.canary_output_stale.set(stale as i64);
+  }
+}
+
+impl SubsystemMetrics for LakeSinkMetrics {
+  const NAMESPACE: &'static str = "lake_sink";
+
+  fn register_metrics(&self, registry: &mut Registry) {
     registry.register(
       "records_saved_total",
       "Number of total records saved to the data lake",
@@ -134,13 +426,416 @@ impl DataLakeMetrics {
       "Number of total records stored in memory, waiting to be saved to data lake",
       self.batch_record_total.clone(),
     );
+    registry.register(
+      "consumer_lag",
+      "Number of records in the consumed topic that have not been committed by the lake sink",
+      self.consumer_lag.clone(),
+    );
+    registry.register(
+      "partition_records_saved_total",
+      "Number of total records saved to the data lake, for each consumed partition",
+      self.partition_records_saved_total.clone(),
+    );
+    registry.register(
+      "partition_consumer_lag",
+      "Number of records in each assigned partition that have not been committed by the lake sink",
+      self.partition_consumer_lag.clone(),
+    );
+    registry.register(
+      "consumer_paused",
+      "Set to 1 while consumption is paused, because the max amount of uploads are in progress",
+      self.consumer_paused.clone(),
+    );
+    registry.register(
+      "canary_last_output_timestamp",
+      "Unix timestamp of the last canary measurement seen in the output topic",
+      self.canary_last_output_timestamp.clone(),
+    );
+    registry.register(
+      "canary_output_stale",
+      "Set to 1 if no canary measurement was seen within the max age",
+      self.canary_output_stale.clone(),
+    );
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct AggregatorMetricLabels {
+  channel_name: String,
+  /// Index of the input stream consumer
+  consumer: String,
+}
+
+impl AggregatorMetricLabels {
+  pub fn new(channel_name: &str, consumer_index: usize) -> Self {
+    Self {
+      channel_name: channel_name.to_string(),
+      consumer: consumer_index.to_string(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct AggregatorPartitionMetricLabels {
+  channel_name: String,
+  consumer: String,
+  partition: String,
+}
+
+impl AggregatorPartitionMetricLabels {
+  fn new(labels: &AggregatorMetricLabels, partition: i32) -> Self {
+    Self {
+      channel_name: labels.channel_name.clone(),
+      consumer: labels.consumer.clone(),
+      partition: partition.to_string(),
+    }
+  }
+}
+
+/// Input consumer lag of the aggregator, reported after each committed iteration.
+#[derive(Default)]
+pub struct AggregatorMetrics {
+  consumer_lag: Family<AggregatorMetricLabels, Gauge>,
+  partition_consumer_lag: Family<AggregatorPartitionMetricLabels, Gauge>,
+  /// Partitions with a reported lag for each consumer, so that the
+  /// lag of revoked partitions can be removed
+  lag_partitions: std::sync::Mutex<HashMap<AggregatorMetricLabels, Vec<i32>>>,
+}
+
+impl AggregatorMetrics {
+  /// Sets the total lag of the consumer, and the lag of each assigned partition,
+  /// given as (partition, lag). Partitions that are no longer assigned are removed.
+  pub fn set_consumer_lag(&self, labels: &AggregatorMetricLabels, partition_lags: &[(i32, i64)]) {
+    self
+      .consumer_lag
+      .get_or_create(labels)
+      .set(partition_lags.iter().map(|(_, lag)| lag).sum());
+    let mut lag_partitions = self.lag_partitions.lock().unwrap();
+    let prev_partitions = lag_partitions.entry(labels.clone()).or_default();
+    for partition in prev_partitions.iter() {
+      if !partition_lags.iter().any(|(v, _)| v == partition) {
+        self
+          .partition_consumer_lag
+          .remove(&AggregatorPartitionMetricLabels::new(labels, *partition));
+      }
+    }
+    for (partition, lag) in partition_lags {
+      self
+        .partition_consumer_lag
+        .get_or_create(&AggregatorPartitionMetricLabels::new(labels, *partition))
+        .set(*lag);
+    }
+    *prev_partitions = partition_lags.iter().map(|(v, _)| *v).collect();
+  }
+}
+
+impl SubsystemMetrics for AggregatorMetrics {
+  const NAMESPACE: &'static str = "aggregator";
+
+  fn register_metrics(&self, registry: &mut Registry) {
+    registry.register(
+      "consumer_lag",
+      "Number of records in the input topic that have not been committed by the aggregator consumer",
+      self.consumer_lag.clone(),
+    );
+    registry.register(
+      "partition_consumer_lag",
+      "Number of records in each assigned input partition that have not been committed by the aggregator",
+      self.partition_consumer_lag.clone(),
+    );
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MemoryInterventionLabels {
+  action: String,
+}
+
+/// Resident set size of the process, and interventions of the memory watchdog.
+#[derive(Default)]
+pub struct MemoryMetrics {
+  rss_bytes: Gauge,
+  limit_bytes: Gauge,
+  under_pressure: Gauge,
+  interventions_total: Family<MemoryInterventionLabels, Counter>,
+}
+
+impl MemoryMetrics {
+  pub fn set_rss_bytes(&self, bytes: u64) {
+    self.rss_bytes.set(bytes as i64);
+  }
+
+  pub fn set_limit_bytes(&self, bytes: u64) {
+    self.limit_bytes.set(bytes as i64);
+  }
+
+  pub fn set_under_pressure(&self, under_pressure: bool) {
+    self.under_pressure.set(under_pressure as i64);
+  }
+
+  pub fn intervention(&self, action: &str) {
+    self
+      .interventions_total
+      .get_or_create(&MemoryInterventionLabels {
+        action: action.to_string(),
+      })
+      .inc();
+  }
+}
+
+impl SubsystemMetrics for MemoryMetrics {
+  const NAMESPACE: &'static str = "memory_watchdog";
+
+  fn register_metrics(&self, registry: &mut Registry) {
+    registry.register(
+      "rss_bytes",
+      "Resident set size of the process, as of the last check",
+      self.rss_bytes.clone(),
+    );
+    registry.register(
+      "limit_bytes",
+      "Configured memory limit of the process",
+      self.limit_bytes.clone(),
+    );
+    registry.register(
+      "under_pressure",
+      "Set to 1 while the resident set size exceeds the memory pressure threshold",
+      self.under_pressure.clone(),
+    );
+    registry.register(
+      "interventions",
+      "Number of interventions made due to memory pressure, by action",
+      self.interventions_total.clone(),
+    );
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KafkaFetchLabels {
+  client: String,
+  topic: String,
+  replica: FetchReplica,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KafkaProduceRetryLabels {
+  topic: String,
+  error: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KafkaDeliveryLabels {
+  topic: String,
+  /// `ok`, or the error code of the failed delivery
+  outcome: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KafkaPartitionLabels {
+  topic: String,
+  partition: i32,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum FetchReplica {
+  Leader,
+  Follower,
+}
+
+/// Replica placement of the partitions fetched by Kafka consumers, and retries and
+/// deliveries of producers.
+pub struct KafkaMetrics {
+  fetch_partitions: Family<KafkaFetchLabels, Gauge>,
+  produce_retries: Family<KafkaProduceRetryLabels, Counter>,
+  delivery_latency: Family<KafkaDeliveryLabels, Histogram>,
+  delivered_offset: Family<KafkaPartitionLabels, Gauge>,
+}
+
+/// Kafka metrics of the process, which are updated by the Kafka clients
+static KAFKA_METRICS: OnceLock<Arc<KafkaMetrics>> = OnceLock::new();
+
+pub fn init_kafka_metrics(metrics: Arc<KafkaMetrics>) {
+  if KAFKA_METRICS.set(metrics).is_err() {
+    panic!("Kafka metrics should only be initialized once");
+  }
+}
+
+/// Returns the Kafka metrics of the process, if initialized.
+pub fn kafka_metrics() -> Option<&'static KafkaMetrics> {
+  KAFKA_METRICS.get().map(|v| v.as_ref())
+}
+
+impl Default for KafkaMetrics {
+  fn default() -> Self {
+    Self {
+      fetch_partitions: Family::default(),
+      produce_retries: Family::default(),
+      delivery_latency: Family::new_with_constructor(|| {
+        Histogram::new(exponential_buckets(0.001, 2., 16))
+      }),
+      delivered_offset: Family::default(),
+    }
+  }
+}
+
+impl KafkaMetrics {
+  pub fn set_fetch_partitions(&self, client: &str, topic: &str, replica: FetchReplica, count: i64) {
+    self
+      .fetch_partitions
+      .get_or_create(&KafkaFetchLabels {
+        client: client.to_string(),
+        topic: topic.to_string(),
+        replica,
+      })
+      .set(count);
+  }
+
+  pub fn produce_retry(&self, topic: &str, error: &str) {
+    self
+      .produce_retries
+      .get_or_create(&KafkaProduceRetryLabels {
+        topic: topic.to_string(),
+        error: error.to_string(),
+      })
+      .inc();
+  }
+
+  /// Records the delivery of a produced record. `delivered` is the partition and offset
+  /// of the stored record, if the delivery succeeded.
+  pub fn delivery(
+    &self,
+    topic: &str,
+    delivered: Option<(i32, i64)>,
+    outcome: &str,
+    latency: Duration,
+  ) {
+    self
+      .delivery_latency
+      .get_or_create(&KafkaDeliveryLabels {
+        topic: topic.to_string(),
+        outcome: outcome.to_string(),
+      })
+      .observe(latency.as_secs_f64());
+    if let Some((partition, offset)) = delivered {
+      self
+        .delivered_offset
+        .get_or_create(&KafkaPartitionLabels {
+          topic: topic.to_string(),
+          partition,
+        })
+        .set(offset);
+    }
+  }
+}
+
+impl SubsystemMetrics for KafkaMetrics {
+  const NAMESPACE: &'static str = "kafka";
+
+  fn register_metrics(&self, registry: &mut Registry) {
+    registry.register(
+      "fetch_partitions",
+      "Partitions actively fetched by a consumer, by the replica they are fetched from",
+      self.fetch_partitions.clone(),
+    );
+    registry.register(
+      "produce_retries",
+      "Produce requests retried after a transient error, by error code",
+      self.produce_retries.clone(),
+    );
+    registry.register(
+      "produce_delivery_latency_seconds",
+      "Time from enqueuing a produced record until its delivery report, by outcome",
+      self.delivery_latency.clone(),
+    );
+    registry.register(
+      "produce_delivered_offset",
+      "Offset of the last record delivered to each partition",
+      self.delivered_offset.clone(),
+    );
+  }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct IoOperationLabels {
+  endpoint: String,
+  operation: IoOperation,
+  /// `ok`, or the category of the error
+  outcome: String,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum IoOperation {
+  Check,
+  Commit,
+  Send,
+}
+
+/// Durations of the operations of pipeline sources and sinks.
+pub struct IoMetrics {
+  operation_duration: Family<IoOperationLabels, Histogram>,
+}
+
+/// I/O metrics of the process, which are updated by `pipeline_io::observe`
+static IO_METRICS: OnceLock<Arc<IoMetrics>> = OnceLock::new();
+
+pub fn init_io_metrics(metrics: Arc<IoMetrics>) {
+  if IO_METRICS.set(metrics).is_err() {
+    panic!("I/O metrics should only be initialized once");
+  }
+}
+
+/// Returns the I/O metrics of the process, if initialized.
+pub fn io_metrics() -> Option<&'static IoMetrics> {
+  IO_METRICS.get().map(|v| v.as_ref())
+}
+
+impl Default for IoMetrics {
+  fn default() -> Self {
+    Self {
+      operation_duration: Family::new_with_constructor(|| {
+        Histogram::new(exponential_buckets(0.001, 4., 10))
+      }),
+    }
+  }
+}
+
+impl IoMetrics {
+  pub fn operation(
+    &self,
+    endpoint: &str,
+    operation: IoOperation,
+    outcome: &str,
+    duration: Duration,
+  ) {
+    self
+      .operation_duration
+      .get_or_create(&IoOperationLabels {
+        endpoint: endpoint.to_string(),
+        operation,
+        outcome: outcome.to_string(),
+      })
+      .observe(duration.as_secs_f64());
+  }
+}
+
+impl SubsystemMetrics for IoMetrics {
+  const NAMESPACE: &'static str = "io";
+
+  fn register_metrics(&self, registry: &mut Registry) {
+    registry.register(
+      "operation_duration_seconds",
+      "Duration of operations of pipeline sources and sinks, by endpoint and outcome",
+      self.operation_duration.clone(),
+    );
   }
 }
 
-async fn metrics_handler(state: web::Data<Mutex<Registry>>) -> actix_web::Result<HttpResponse> {
+async fn metrics_handler(
+  state: web::Data<Mutex<MetricsRegistry>>,
+) -> actix_web::Result<HttpResponse> {
   let registry = state.lock().await;
   let mut body: String = String::new();
-  encode(&mut body, &registry)
+  encode(&mut body, &registry.registry)
     .map_err(|v| InternalError::new(v, StatusCode::INTERNAL_SERVER_ERROR))?;
   Ok(
     HttpResponse::Ok()
@@ -153,7 +848,16 @@ async fn health_check_handler() -> io::Result<HttpResponse> {
   Ok(HttpResponse::NoContent().finish())
 }
 
-pub fn create_metric_server(registry: Registry, port: u16) -> io::Result<Server> {
+/// Creates the metrics listener. `configure` may register additional services,
+/// such as the admin endpoints of background modes.
+pub fn create_metric_server<F>(
+  registry: MetricsRegistry,
+  port: u16,
+  configure: F,
+) -> io::Result<Server>
+where
+  F: Fn(&mut web::ServiceConfig) + Send + Clone + 'static,
+{
   let state = web::Data::new(Mutex::new(registry));
   Ok(
     HttpServer::new(move || {
@@ -161,8 +865,95 @@ pub fn create_metric_server(registry: Registry, port: u16) -> io::Result<Server>
         .app_data(state.clone())
         .service(web::resource("/metrics").route(web::get().to(metrics_handler)))
         .service(web::resource("/health").route(web::get().to(health_check_handler)))
+        .configure(configure.clone())
     })
     .bind(("0.0.0.0", port))?
     .run(),
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn remove_revoked_partition_lag() {
+    let metrics = LakeSinkMetrics::default();
+    let mut registry = MetricsRegistry::with_labels("lake-sink".to_string(), "a".to_string());
+    registry.register(&metrics);
+    let labels = LakeSinkMetricLabels {
+      sink: "measurements".to_string(),
+      channel_name: "typical".to_string(),
+      consumer: "1".to_string(),
+    };
+    metrics.set_consumer_lag(&labels, &[(0, 5), (3, 2)]);
+    metrics.set_consumer_lag(&labels, &[(3, 4)]);
+
+    let mut output = String::new();
+    encode(&mut output, &registry.registry).unwrap();
+    assert!(output.contains(
+      r#"lake_sink_consumer_lag{mode="lake-sink",instance="a",sink="measurements",channel_name="typical",consumer="1"} 4"#
+    ));
+    assert!(output.contains(
+      r#"lake_sink_partition_consumer_lag{mode="lake-sink",instance="a",sink="measurements",channel_name="typical",consumer="1",partition="3"} 4"#
+    ));
+    assert!(!output.contains(r#"partition="0""#));
+  }
+
+  #[test]
+  fn aggregator_consumer_lag() {
+    let metrics = AggregatorMetrics::default();
+    let mut registry = MetricsRegistry::with_labels("aggregator".to_string(), "a".to_string());
+    registry.register(&metrics);
+    let labels = AggregatorMetricLabels::new("typical", 0);
+    metrics.set_consumer_lag(&labels, &[(1, 7), (2, 3)]);
+    metrics.set_consumer_lag(&labels, &[(2, 1)]);
+
+    let mut output = String::new();
+    encode(&mut output, &registry.registry).unwrap();
+    assert!(output.contains(
+      r#"aggregator_consumer_lag{mode="aggregator",instance="a",channel_name="typical",consumer="0"} 1"#
+    ));
+    assert!(output.contains(
+      r#"aggregator_partition_consumer_lag{mode="aggregator",instance="a",channel_name="typical",consumer="0",partition="2"} 1"#
+    ));
+    assert!(!output.contains(r#"partition="1""#));
+  }
+
+  #[test]
+  fn produce_deliveries() {
+    let metrics = KafkaMetrics::default();
+    let mut registry = MetricsRegistry::with_labels("server".to_string(), "a".to_string());
+    registry.register(&metrics);
+    metrics.delivery(
+      "p3a-star-enc",
+      Some((2, 41)),
+      "ok",
+      Duration::from_millis(3),
+    );
+    metrics.delivery(
+      "p3a-star-enc",
+      Some((2, 42)),
+      "ok",
+      Duration::from_millis(20),
+    );
+    metrics.delivery(
+      "p3a-star-enc",
+      None,
+      "MessageTimedOut",
+      Duration::from_secs(30),
+    );
+
+    let mut output = String::new();
+    encode(&mut output, &registry.registry).unwrap();
+    assert!(output.contains(
+      r#"kafka_produce_delivery_latency_seconds_count{mode="server",instance="a",topic="p3a-star-enc",outcome="ok"} 2"#
+    ));
+    assert!(output.contains(
+      r#"kafka_produce_delivery_latency_seconds_count{mode="server",instance="a",topic="p3a-star-enc",outcome="MessageTimedOut"} 1"#
+    ));
+    assert!(output.contains(
+      r#"kafka_produce_delivered_offset{mode="server",instance="a",topic="p3a-star-enc",partition="2"} 42"#
+    ));
+  }
+}
@@ -0,0 +1,787 @@
+//! Record stream backed by Google Cloud Pub/Sub, selected via `RECORD_STREAM_BACKEND=pubsub`.
+//! The topic of the stream config is used as the Pub/Sub topic id, and records are consumed
+//! from the subscription `<topic>-<consumer group>`. Topics and subscriptions are not created
+//! by the processor. Record headers are sent as message attributes, with base64 encoded values.
+//!
+//! Pub/Sub messages do not have offsets, so consumed records are assigned local offsets, which
+//! are only valid for the lifetime of the stream. Each consumer reports its records in its own
+//! partition, since Pub/Sub distributes the messages of a subscription across its consumers.
+//! Committing consumption acks the consumed records up to the committed offset. The ack deadline
+//! of pulled records is extended until they are committed, so records held by the aggregator
+//! are not redelivered.
+//!
+//! If `PUBSUB_ORDERING_KEYS` is enabled, records are published with ordering keys: the record
+//! key for queued records, or the channel and epoch of the record otherwise. Ordering only
+//! takes effect if message ordering is enabled for the subscription.
+//!
+//! Requests are sent via the gRPC API of `google-cloud-pubsub`, authenticated with the
+//! application default credentials, i.e. the service account of the instance via GKE workload
+//! identity. If `PUBSUB_EMULATOR_HOST` is set, requests are sent to the emulator without
+//! authentication.
+
+use crate::record_stream::{
+  acquire_send_permit, consumer_group_id, datetime_from_unix_millis, send_to_producer_queue,
+  BatchRecord, ConsumedRecord, KafkaRecordStreamConfig, ProducerQueue, ProducerQueueItem,
+  RecordHeaderValues, RecordStream, RecordStreamError, RecordStreamOptions,
+};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error, From};
+use futures::future::try_join_all;
+use google_cloud_gax::grpc::Status;
+use google_cloud_googleapis::pubsub::v1::{
+  ModifyAckDeadlineRequest, PubsubMessage, PullRequest, ReceivedMessage,
+};
+use google_cloud_pubsub::client::{Client, ClientConfig};
+use google_cloud_pubsub::publisher::{Publisher, PublisherConfig};
+use google_cloud_pubsub::subscription::Subscription;
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{Mutex as AsyncMutex, OnceCell, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+const PUBSUB_PROJECT_ID_ENV_KEY: &str = "PUBSUB_PROJECT_ID";
+const PUBSUB_PULL_BATCH_SIZE_ENV_KEY: &str = "PUBSUB_PULL_BATCH_SIZE";
+const DEFAULT_PUBSUB_PULL_BATCH_SIZE: &str = "1000";
+const PUBSUB_ACK_DEADLINE_SECS_ENV_KEY: &str = "PUBSUB_ACK_DEADLINE_SECS";
+const DEFAULT_PUBSUB_ACK_DEADLINE_SECS: &str = "60";
+const PUBSUB_ORDERING_KEYS_ENV_KEY: &str = "PUBSUB_ORDERING_KEYS";
+const DEFAULT_PUBSUB_ORDERING_KEYS: &str = "false";
+const PUBSUB_PUBLISH_BATCH_SIZE_ENV_KEY: &str = "PUBSUB_PUBLISH_BATCH_SIZE";
+const DEFAULT_PUBSUB_PUBLISH_BATCH_SIZE: &str = "100";
+const PUBSUB_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY: &str = "PUBSUB_PRODUCE_QUEUE_TASK_COUNT";
+const DEFAULT_PUBSUB_PRODUCE_QUEUE_TASK_COUNT: &str = "16";
+
+const EMPTY_PULL_BACKOFF: Duration = Duration::from_secs(1);
+/// Limit of ack ids per acknowledge or deadline modification request
+const MAX_ACK_IDS_PER_REQUEST: usize = 1000;
+
+static NEXT_CONSUMER_PARTITION: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Debug, Display, Error, From)]
+pub enum PubSubError {
+  #[display(fmt = "Pub/Sub client error: {}", _0)]
+  Client(google_cloud_pubsub::client::Error),
+  #[display(fmt = "Pub/Sub auth error: {}", _0)]
+  Auth(google_cloud_auth::error::Error),
+  #[display(fmt = "Pub/Sub API error: {}", _0)]
+  #[from(ignore)]
+  Api(Box<Status>),
+  #[display(fmt = "Pub/Sub resource not found: {}", _0)]
+  #[from(ignore)]
+  NotFound(#[error(not(source))] String),
+}
+
+/// Pub/Sub client of the topic and subscription of a stream. Connects on first use,
+/// since record streams are created synchronously.
+struct PubSubClient {
+  project_id: String,
+  topic_id: String,
+  subscription_id: String,
+  client: OnceCell<Client>,
+  publisher: OnceCell<Publisher>,
+}
+
+impl PubSubClient {
+  fn new(project_id: String, topic_id: String, subscription_id: String) -> Self {
+    Self {
+      project_id,
+      topic_id,
+      subscription_id,
+      client: OnceCell::new(),
+      publisher: OnceCell::new(),
+    }
+  }
+
+  /// Requests are sent to `PUBSUB_EMULATOR_HOST` without authentication, if set.
+  async fn client(&self) -> Result<&Client, PubSubError> {
+    self
+      .client
+      .get_or_try_init(|| async {
+        let config = ClientConfig {
+          project_id: Some(self.project_id.clone()),
+          ..Default::default()
+        }
+        .with_auth()
+        .await?;
+        Ok(Client::new(config).await?)
+      })
+      .await
+  }
+
+  async fn subscription(&self) -> Result<Subscription, PubSubError> {
+    Ok(self.client().await?.subscription(&self.subscription_id))
+  }
+
+  async fn check_topic(&self) -> Result<(), PubSubError> {
+    let topic = self.client().await?.topic(&self.topic_id);
+    match topic.exists(None).await {
+      Ok(true) => Ok(()),
+      Ok(false) => Err(PubSubError::NotFound(
+        topic.fully_qualified_name().to_string(),
+      )),
+      Err(e) => Err(PubSubError::Api(Box::new(e))),
+    }
+  }
+
+  async fn check_subscription(&self) -> Result<(), PubSubError> {
+    let subscription = self.subscription().await?;
+    match subscription.exists(None).await {
+      Ok(true) => Ok(()),
+      Ok(false) => Err(PubSubError::NotFound(
+        subscription.fully_qualified_name().to_string(),
+      )),
+      Err(e) => Err(PubSubError::Api(Box::new(e))),
+    }
+  }
+
+  async fn publish(&self, messages: Vec<PubsubMessage>) -> Result<(), PubSubError> {
+    let client = self.client().await?;
+    // Messages are published right away, so the publisher tasks are not used
+    let publisher = self.publisher.get_or_init(|| async {
+      client
+        .topic(&self.topic_id)
+        .new_publisher(Some(PublisherConfig {
+          workers: 1,
+          ..Default::default()
+        }))
+    });
+    publisher
+      .await
+      .publish_immediately(messages, None)
+      .await
+      .map_err(|e| PubSubError::Api(Box::new(e)))?;
+    Ok(())
+  }
+
+  async fn pull(&self, max_messages: usize) -> Result<Vec<ReceivedMessage>, PubSubError> {
+    let subscription = self.subscription().await?;
+    let output = subscription
+      .get_client()
+      .pull(
+        PullRequest {
+          subscription: subscription.fully_qualified_name().to_string(),
+          max_messages: max_messages as i32,
+          ..Default::default()
+        },
+        None,
+      )
+      .await
+      .map_err(|e| PubSubError::Api(Box::new(e)))?;
+    Ok(output.into_inner().received_messages)
+  }
+
+  async fn acknowledge(&self, ack_ids: &[String]) -> Result<(), PubSubError> {
+    let subscription = self.subscription().await?;
+    for ack_ids in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+      subscription
+        .ack(ack_ids.to_vec())
+        .await
+        .map_err(|e| PubSubError::Api(Box::new(e)))?;
+    }
+    Ok(())
+  }
+
+  async fn modify_ack_deadline(
+    &self,
+    ack_ids: &[String],
+    deadline: Duration,
+  ) -> Result<(), PubSubError> {
+    let subscription = self.subscription().await?;
+    for ack_ids in ack_ids.chunks(MAX_ACK_IDS_PER_REQUEST) {
+      subscription
+        .get_client()
+        .modify_ack_deadline(
+          ModifyAckDeadlineRequest {
+            subscription: subscription.fully_qualified_name().to_string(),
+            ack_ids: ack_ids.to_vec(),
+            ack_deadline_seconds: deadline.as_secs() as i32,
+          },
+          None,
+        )
+        .await
+        .map_err(|e| PubSubError::Api(Box::new(e)))?;
+    }
+    Ok(())
+  }
+}
+
+/// Returns the publish time of a received message.
+fn publish_time(message: &PubsubMessage) -> Option<OffsetDateTime> {
+  let timestamp = message.publish_time.as_ref()?;
+  OffsetDateTime::from_unix_timestamp_nanos(
+    timestamp.seconds as i128 * 1_000_000_000 + timestamp.nanos as i128,
+  )
+  .ok()
+}
+
+struct UncommittedMessage {
+  message_id: String,
+  ack_id: String,
+}
+
+#[derive(Default)]
+struct ConsumerState {
+  initialized: bool,
+  buffer: VecDeque<ConsumedRecord>,
+  /// Delivered records that have not been acked, by local offset
+  uncommitted: BTreeMap<i64, UncommittedMessage>,
+  uncommitted_offsets: HashMap<String, i64>,
+  next_offset: i64,
+  consumed_offset: Option<i64>,
+  committed_offset: Option<i64>,
+}
+
+impl ConsumerState {
+  fn buffer_messages(
+    &mut self,
+    messages: Vec<ReceivedMessage>,
+    partition: i32,
+    tenant: Option<&str>,
+  ) {
+    for ReceivedMessage {
+      ack_id, message, ..
+    } in messages
+    {
+      let Some(message) = message else {
+        continue;
+      };
+      let published_at = publish_time(&message);
+      // Records that have been redelivered before being acked are already buffered or
+      // consumed, but only the latest ack id of a message is valid
+      if let Some(offset) = self.uncommitted_offsets.get(&message.message_id) {
+        if let Some(uncommitted) = self.uncommitted.get_mut(offset) {
+          uncommitted.ack_id = ack_id;
+        }
+        continue;
+      }
+      let offset = self.next_offset;
+      self.next_offset += 1;
+      self
+        .uncommitted_offsets
+        .insert(message.message_id.clone(), offset);
+      self.uncommitted.insert(
+        offset,
+        UncommittedMessage {
+          message_id: message.message_id,
+          ack_id,
+        },
+      );
+
+      let decoded_attributes: Vec<_> = message
+        .attributes
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str(), base64_engine::STANDARD.decode(v).ok()?)))
+        .collect();
+      let header_values =
+        RecordHeaderValues::parse(decoded_attributes.iter().map(|(k, v)| (*k, v.as_slice())));
+      if !header_values.matches_tenant(tenant) {
+        continue;
+      }
+      self.buffer.push_back(ConsumedRecord {
+        data: message.data,
+        request_threshold: header_values.request_threshold,
+        channel_name: header_values.channel_name,
+        epoch: header_values.epoch,
+        client_version: header_values.client_version,
+        topic: None,
+        partition: Some(partition),
+        offset: Some(offset),
+        submitted_at: header_values
+          .submitted_at
+          .and_then(datetime_from_unix_millis)
+          .or(published_at),
+        timestamp: published_at,
+      });
+    }
+  }
+
+  /// Returns the ack ids of the uncommitted records up to and including the offset.
+  fn ack_ids(&self, offset: i64) -> Vec<String> {
+    self
+      .uncommitted
+      .range(..=offset)
+      .map(|(_, v)| v.ack_id.clone())
+      .collect()
+  }
+
+  fn mark_committed(&mut self, offset: i64) {
+    let remaining = self.uncommitted.split_off(&(offset + 1));
+    for (_, committed) in std::mem::replace(&mut self.uncommitted, remaining) {
+      self.uncommitted_offsets.remove(&committed.message_id);
+    }
+    self.committed_offset = Some(self.committed_offset.unwrap_or_default().max(offset + 1));
+  }
+}
+
+type PullTask = JoinHandle<Result<Vec<ReceivedMessage>, PubSubError>>;
+
+pub struct PubSubRecordStream {
+  client: Arc<PubSubClient>,
+  topic: String,
+  partition: i32,
+  tenant: Option<String>,
+  enable_producer: bool,
+  enable_consumer: bool,
+  ordering_keys: bool,
+  consumer_state: Arc<Mutex<ConsumerState>>,
+  /// Also prevents concurrent consumption
+  pull: AsyncMutex<Option<PullTask>>,
+  deadline_extension: Mutex<Option<JoinHandle<()>>>,
+  pull_batch_size: usize,
+  ack_deadline: Duration,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  send_limit: Option<Arc<Semaphore>>,
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+impl PubSubRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let project_id = env::var(PUBSUB_PROJECT_ID_ENV_KEY)
+      .unwrap_or_else(|_| panic!("{} should be set", PUBSUB_PROJECT_ID_ENV_KEY));
+    let tenant = stream_config.tenant.as_deref();
+    let topic = tenant_scoped_name(&stream_config.topic, tenant);
+    let group_id = consumer_group_id(&stream_config);
+    Self {
+      client: Arc::new(PubSubClient::new(
+        project_id,
+        topic.clone(),
+        format!("{}-{}", topic, group_id),
+      )),
+      topic,
+      partition: NEXT_CONSUMER_PARTITION.fetch_add(1, Ordering::Relaxed),
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      enable_consumer: stream_config.enable_consumer,
+      ordering_keys: parse_env_var(PUBSUB_ORDERING_KEYS_ENV_KEY, DEFAULT_PUBSUB_ORDERING_KEYS),
+      consumer_state: Arc::new(Mutex::new(ConsumerState::default())),
+      pull: AsyncMutex::new(None),
+      deadline_extension: Mutex::new(None),
+      pull_batch_size: parse_env_var(
+        PUBSUB_PULL_BATCH_SIZE_ENV_KEY,
+        DEFAULT_PUBSUB_PULL_BATCH_SIZE,
+      ),
+      ack_deadline: Duration::from_secs(parse_env_var(
+        PUBSUB_ACK_DEADLINE_SECS_ENV_KEY,
+        DEFAULT_PUBSUB_ACK_DEADLINE_SECS,
+      )),
+      producer_queues: RwLock::new(Vec::new()),
+      send_limit: options.send_limit,
+      record_headers: Arc::new(options.record_headers),
+    }
+  }
+
+  fn outgoing_message(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> PubsubMessage {
+    PubsubMessage {
+      data: record.to_vec(),
+      attributes: self.attributes(
+        RecordHeaderValues {
+          request_threshold,
+          channel_name: channel_name.map(|v| v.to_string()),
+          epoch,
+          ..Default::default()
+        },
+        extra_headers,
+      ),
+      ordering_key: match self.ordering_keys {
+        true => key
+          .map(|v| v.to_string())
+          .or_else(|| ordering_key(channel_name, epoch)),
+        false => None,
+      }
+      .unwrap_or_default(),
+      ..Default::default()
+    }
+  }
+
+  fn attributes(
+    &self,
+    mut header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> HashMap<String, String> {
+    header_values.tenant = self.tenant.clone();
+    header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .map(|(k, v)| (k, base64_engine::STANDARD.encode(v)))
+      .collect()
+  }
+
+  /// Verifies that the subscription exists, and starts extending the ack
+  /// deadline of the uncommitted records.
+  async fn init_consumer(&self) -> Result<(), PubSubError> {
+    self.client.check_subscription().await?;
+    let client = self.client.clone();
+    let consumer_state = Arc::downgrade(&self.consumer_state);
+    let ack_deadline = self.ack_deadline;
+    let handle = tokio::spawn(async move {
+      loop {
+        sleep(ack_deadline / 2).await;
+        let Some(consumer_state) = consumer_state.upgrade() else {
+          return;
+        };
+        let ack_ids = consumer_state.lock().unwrap().ack_ids(i64::MAX);
+        if let Err(e) = client.modify_ack_deadline(&ack_ids, ack_deadline).await {
+          warn!("Failed to extend Pub/Sub ack deadlines: {}", e);
+        }
+      }
+    });
+    *self.deadline_extension.lock().unwrap() = Some(handle);
+    let mut state = self.consumer_state.lock().unwrap();
+    state.initialized = true;
+    state.committed_offset = Some(0);
+    Ok(())
+  }
+
+  /// Pulls records in a separate task, so that pulled records are not lost if
+  /// consumption is cancelled. The ack deadline of the records is extended right away,
+  /// since the default deadline of the subscription may be shorter.
+  fn spawn_pull(&self) -> PullTask {
+    let client = self.client.clone();
+    let pull_batch_size = self.pull_batch_size;
+    let ack_deadline = self.ack_deadline;
+    tokio::spawn(async move {
+      let messages = client.pull(pull_batch_size).await?;
+      let ack_ids: Vec<_> = messages.iter().map(|v| v.ack_id.clone()).collect();
+      if let Err(e) = client.modify_ack_deadline(&ack_ids, ack_deadline).await {
+        warn!("Failed to extend Pub/Sub ack deadlines: {}", e);
+      }
+      Ok(messages)
+    })
+  }
+
+  fn pop_record(&self) -> Option<ConsumedRecord> {
+    let mut state = self.consumer_state.lock().unwrap();
+    let record = state.buffer.pop_front()?;
+    state.consumed_offset = record.offset;
+    Some(record)
+  }
+
+  /// Acks the delivered records up to and including the offset.
+  async fn ack(&self, offset: i64) -> Result<(), PubSubError> {
+    let ack_ids = self.consumer_state.lock().unwrap().ack_ids(offset);
+    self.client.acknowledge(&ack_ids).await?;
+    self.consumer_state.lock().unwrap().mark_committed(offset);
+    Ok(())
+  }
+}
+
+impl Drop for PubSubRecordStream {
+  fn drop(&mut self) {
+    if let Some(handle) = self.deadline_extension.get_mut().unwrap().take() {
+      handle.abort();
+    }
+    if let Some(task) = self.pull.get_mut().take() {
+      task.abort();
+    }
+  }
+}
+
+fn ordering_key(channel_name: Option<&str>, epoch: Option<u8>) -> Option<String> {
+  match (channel_name, epoch) {
+    (Some(channel_name), Some(epoch)) => Some(format!("{}-{}", channel_name, epoch)),
+    (Some(channel_name), None) => Some(channel_name.to_string()),
+    (None, Some(epoch)) => Some(epoch.to_string()),
+    (None, None) => None,
+  }
+}
+
+#[async_trait]
+impl RecordStream for PubSubRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    if self.enable_producer {
+      self.client.check_topic().await?;
+    }
+    if self.enable_consumer {
+      self.client.check_subscription().await?;
+    }
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(self.consumer_state.lock().unwrap().initialized)
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(match self.consumer_state.lock().unwrap().initialized {
+      true => vec![self.partition],
+      false => Vec::new(),
+    })
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .committed_offset
+        .map(|v| (self.partition, v))
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  /// Only includes the records that have been pulled from the subscription.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(HashMap::from([(self.partition, state.next_offset)]))
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "Pub/Sub producer not enabled");
+    let message = self.outgoing_message(
+      record,
+      key,
+      request_threshold,
+      channel_name,
+      epoch,
+      extra_headers,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    self.client.publish(vec![message]).await?;
+    Ok(())
+  }
+
+  /// Publishes the records in requests of up to `PUBSUB_PUBLISH_BATCH_SIZE` records.
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "Pub/Sub producer not enabled");
+    let batch_size = parse_env_var::<usize>(
+      PUBSUB_PUBLISH_BATCH_SIZE_ENV_KEY,
+      DEFAULT_PUBSUB_PUBLISH_BATCH_SIZE,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    for chunk in records.chunks(batch_size.max(1)) {
+      let messages: Vec<_> = chunk
+        .iter()
+        .map(|v| {
+          self.outgoing_message(
+            v.data,
+            v.key,
+            v.request_threshold,
+            v.channel_name,
+            v.epoch,
+            &[],
+          )
+        })
+        .collect();
+      self.client.publish(messages).await?;
+    }
+    Ok(())
+  }
+
+  async fn init_producer_queues(&self) {
+    assert!(self.enable_producer, "Pub/Sub producer not enabled");
+    let task_count = parse_env_var::<usize>(
+      PUBSUB_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY,
+      DEFAULT_PUBSUB_PRODUCE_QUEUE_TASK_COUNT,
+    );
+    let batch_size = parse_env_var::<usize>(
+      PUBSUB_PUBLISH_BATCH_SIZE_ENV_KEY,
+      DEFAULT_PUBSUB_PUBLISH_BATCH_SIZE,
+    );
+    let attributes = Arc::new(self.attributes(RecordHeaderValues::default(), &[]));
+    let mut producer_queues = self.producer_queues.write().await;
+    for _ in 0..task_count {
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
+      let client = self.client.clone();
+      let send_limit = self.send_limit.clone();
+      let attributes = attributes.clone();
+      let ordering_keys = self.ordering_keys;
+      let handle = tokio::spawn(async move {
+        let to_message = |(data, key): ProducerQueueItem| PubsubMessage {
+          data,
+          attributes: attributes.as_ref().clone(),
+          ordering_key: key.filter(|_| ordering_keys).unwrap_or_default(),
+          ..Default::default()
+        };
+        // Queued records are published in batches of the records available
+        while let Some(item) = rx.recv().await {
+          let mut messages = vec![to_message(item)];
+          while messages.len() < batch_size {
+            match rx.try_recv() {
+              Ok(item) => messages.push(to_message(item)),
+              Err(_) => break,
+            }
+          }
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          client.publish(messages).await?;
+        }
+        Ok(())
+      });
+      producer_queues.push((handle, tx));
+    }
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    let producer_queues = self.producer_queues.read().await;
+    let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
+    send_to_producer_queue(tx, &self.topic, (record, key))
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    let mut producer_queues = self.producer_queues.write().await;
+    try_join_all(
+      producer_queues
+        .drain(..)
+        .map(|(handle, _)| handle)
+        .collect::<Vec<_>>(),
+    )
+    .await?
+    .into_iter()
+    .collect::<Result<Vec<()>, RecordStreamError>>()?;
+    Ok(())
+  }
+
+  /// Cancel safe, since the pull task is kept until the next call.
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    let mut pull = self.pull.lock().await;
+    loop {
+      if let Some(record) = self.pop_record() {
+        return Ok(record);
+      }
+      let initialized = self.consumer_state.lock().unwrap().initialized;
+      if !initialized {
+        self.init_consumer().await?;
+      }
+      let task = pull.get_or_insert_with(|| self.spawn_pull());
+      let result = task.await;
+      *pull = None;
+      let messages = result??;
+      if messages.is_empty() {
+        sleep(EMPTY_PULL_BACKOFF).await;
+        continue;
+      }
+      self.consumer_state.lock().unwrap().buffer_messages(
+        messages,
+        self.partition,
+        self.tenant.as_deref(),
+      );
+    }
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let consumed_offset = self.consumer_state.lock().unwrap().consumed_offset;
+    if let Some(offset) = consumed_offset {
+      self.ack(offset).await?;
+    }
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    for (partition, offset) in offsets {
+      if *partition == self.partition {
+        self.ack(*offset).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::datetime_to_unix_millis;
+
+  fn received(message_id: &str, ack_id: &str, data: &[u8]) -> ReceivedMessage {
+    let mut message = PubsubMessage {
+      data: data.to_vec(),
+      attributes: RecordHeaderValues {
+        channel_name: Some("typical".to_string()),
+        epoch: Some(3),
+        ..Default::default()
+      }
+      .headers()
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), base64_engine::STANDARD.encode(v)))
+      .collect(),
+      message_id: message_id.to_string(),
+      publish_time: Some(Default::default()),
+      ..Default::default()
+    };
+    let publish_time = message.publish_time.as_mut().unwrap();
+    publish_time.seconds = 1_682_946_000;
+    publish_time.nanos = 123_456_789;
+    ReceivedMessage {
+      ack_id: ack_id.to_string(),
+      message: Some(message),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn buffer_and_commit_messages() {
+    let mut state = ConsumerState::default();
+    state.buffer_messages(
+      vec![
+        received("1", "ack-1", b"first"),
+        received("2", "ack-2", b"second"),
+        received("3", "ack-3", b"third"),
+      ],
+      4,
+      None,
+    );
+    // Redelivery of an uncommitted message updates its ack id
+    state.buffer_messages(vec![received("2", "ack-2b", b"second")], 4, None);
+    assert_eq!(state.next_offset, 3);
+    assert_eq!(state.buffer.len(), 3);
+
+    let record = state.buffer.pop_front().unwrap();
+    assert_eq!(record.data, b"first");
+    assert_eq!(record.partition, Some(4));
+    assert_eq!(record.offset, Some(0));
+    assert_eq!(record.channel_name.as_deref(), Some("typical"));
+    assert_eq!(record.epoch, Some(3));
+    assert_eq!(
+      record.timestamp.map(datetime_to_unix_millis),
+      Some(1_682_946_000_123)
+    );
+
+    assert_eq!(state.ack_ids(1), vec!["ack-1", "ack-2b"]);
+    state.mark_committed(1);
+    assert_eq!(state.committed_offset, Some(2));
+    assert_eq!(state.ack_ids(i64::MAX), vec!["ack-3"]);
+    // Messages redelivered after being acked are consumed again
+    state.buffer_messages(vec![received("1", "ack-1c", b"first")], 4, None);
+    assert_eq!(state.next_offset, 4);
+  }
+
+  #[test]
+  fn message_ordering_keys() {
+    assert_eq!(
+      ordering_key(Some("typical"), Some(3)).as_deref(),
+      Some("typical-3")
+    );
+    assert_eq!(ordering_key(None, None), None);
+  }
+}
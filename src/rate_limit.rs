@@ -0,0 +1,259 @@
+//! Global rate limit of submissions. If `SERVER_RATE_LIMIT` is set, submissions are
+//! counted in fixed windows of `SERVER_RATE_LIMIT_WINDOW_SECS`, and submissions beyond
+//! the limit within a window are rejected with a 429 status and a `Retry-After` header.
+//!
+//! Counts are kept in Redis if the shared `SERVER_REDIS_URL` is set, by incrementing
+//! a key of the window that expires after the window ends, so that the limit applies
+//! to all replicas. Otherwise, counts are kept in memory, and the limit applies to
+//! each replica. If Redis is unavailable, submissions are accepted.
+
+use crate::redis::RedisClient;
+use crate::util::parse_env_var;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header::RETRY_AFTER, Method, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use derive_more::{Display, Error};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SERVER_RATE_LIMIT_ENV_KEY: &str = "SERVER_RATE_LIMIT";
+/// Disables the rate limit
+const DEFAULT_SERVER_RATE_LIMIT: &str = "0";
+const SERVER_RATE_LIMIT_WINDOW_SECS_ENV_KEY: &str = "SERVER_RATE_LIMIT_WINDOW_SECS";
+const DEFAULT_SERVER_RATE_LIMIT_WINDOW_SECS: &str = "1";
+const REDIS_KEY_PREFIX: &str = "rate_limit:";
+
+#[derive(Error, Display, Debug)]
+#[display(fmt = "Rate limit exceeded, retry later")]
+pub struct RateLimitError {
+  #[error(not(source))]
+  retry_after: Duration,
+}
+
+impl ResponseError for RateLimitError {
+  fn error_response(&self) -> HttpResponse {
+    let retry_after_secs =
+      self.retry_after.as_secs() + (self.retry_after.subsec_nanos() > 0) as u64;
+    HttpResponse::build(self.status_code())
+      .insert_header((RETRY_AFTER, retry_after_secs.max(1)))
+      .body(self.to_string())
+  }
+
+  fn status_code(&self) -> StatusCode {
+    StatusCode::TOO_MANY_REQUESTS
+  }
+}
+
+enum Backend {
+  /// The current window, and the amount of submissions within it
+  Memory(Mutex<(u64, u64)>),
+  Redis(Arc<RedisClient>),
+}
+
+pub struct RateLimiter {
+  backend: Backend,
+  limit: u64,
+  window: Duration,
+}
+
+impl RateLimiter {
+  pub fn new_memory(limit: u64, window: Duration) -> Self {
+    Self {
+      backend: Backend::Memory(Mutex::new((0, 0))),
+      limit,
+      window,
+    }
+  }
+
+  /// Uses the shared server Redis client if configured, followed by memory.
+  /// Returns None if the rate limit is disabled.
+  pub fn from_env(shared_redis: Option<Arc<RedisClient>>) -> Option<Self> {
+    let limit = parse_env_var::<u64>(SERVER_RATE_LIMIT_ENV_KEY, DEFAULT_SERVER_RATE_LIMIT);
+    if limit == 0 {
+      return None;
+    }
+    let window = Duration::from_secs(
+      parse_env_var::<u64>(
+        SERVER_RATE_LIMIT_WINDOW_SECS_ENV_KEY,
+        DEFAULT_SERVER_RATE_LIMIT_WINDOW_SECS,
+      )
+      .max(1),
+    );
+    match shared_redis {
+      Some(redis) => {
+        info!("Using Redis for the global rate limit");
+        Some(Self {
+          backend: Backend::Redis(redis),
+          limit,
+          window,
+        })
+      }
+      None => Some(Self::new_memory(limit, window)),
+    }
+  }
+
+  /// Counts a submission. Returns the remaining time of the window
+  /// if the limit was exceeded within the window.
+  pub async fn check(&self) -> Option<Duration> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    self.check_at(now.as_millis() as u64).await
+  }
+
+  async fn check_at(&self, now_ms: u64) -> Option<Duration> {
+    let window_ms = self.window.as_millis() as u64;
+    let window_index = now_ms / window_ms;
+    let count = match &self.backend {
+      Backend::Memory(state) => {
+        let mut state = state.lock().unwrap();
+        if state.0 != window_index {
+          *state = (window_index, 0);
+        }
+        state.1 += 1;
+        state.1
+      }
+      Backend::Redis(client) => {
+        let key = format!("{}{}", REDIS_KEY_PREFIX, window_index);
+        // The key is kept for an extra window, to allow for clock skew between replicas
+        match client.incr_px(&key, window_ms * 2).await {
+          Ok(count) => count,
+          Err(e) => {
+            warn!("Failed to check rate limit: {}", e);
+            return None;
+          }
+        }
+      }
+    };
+    (count > self.limit).then(|| Duration::from_millis((window_index + 1) * window_ms - now_ms))
+  }
+}
+
+/// Submissions are posted to the root or to the path of a channel.
+fn is_submission_request(request: &ServiceRequest) -> bool {
+  request.method() == Method::POST && !request.path()[1..].contains('/')
+}
+
+/// Middleware that rejects submissions exceeding the rate limit, if enabled.
+#[derive(Clone)]
+pub struct RateLimit {
+  limiter: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimit {
+  pub fn new(limiter: Option<Arc<RateLimiter>>) -> Self {
+    Self { limiter }
+  }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+{
+  type Response = ServiceResponse;
+  type Error = actix_web::Error;
+  type Transform = RateLimitService<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RateLimitService {
+      service: Rc::new(service),
+      limiter: self.limiter.clone(),
+    }))
+  }
+}
+
+pub struct RateLimitService<S> {
+  service: Rc<S>,
+  limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitService<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+{
+  type Response = ServiceResponse;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<ServiceResponse, actix_web::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, request: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let limiter = self
+      .limiter
+      .clone()
+      .filter(|_| is_submission_request(&request));
+    async move {
+      if let Some(limiter) = limiter {
+        if let Some(retry_after) = limiter.check().await {
+          return Err(RateLimitError { retry_after }.into());
+        }
+      }
+      service.call(request).await
+    }
+    .boxed_local()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+  use actix_web::{web, App};
+
+  #[tokio::test]
+  async fn memory_windows() {
+    let limiter = RateLimiter::new_memory(2, Duration::from_secs(10));
+    assert_eq!(limiter.check_at(20_000).await, None);
+    assert_eq!(limiter.check_at(25_000).await, None);
+    assert_eq!(
+      limiter.check_at(26_500).await,
+      Some(Duration::from_millis(3_500))
+    );
+    assert_eq!(limiter.check_at(30_000).await, None);
+  }
+
+  #[tokio::test]
+  async fn redis_unavailable() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let client = Arc::new(RedisClient::new(&format!("redis://{}", addr), 1));
+    let limiter = RateLimiter {
+      backend: Backend::Redis(client),
+      limit: 0,
+      window: Duration::from_secs(1),
+    };
+    assert_eq!(limiter.check().await, None);
+  }
+
+  #[actix_web::test]
+  async fn middleware() {
+    let limiter = RateLimiter::new_memory(1, Duration::from_secs(3600));
+    let app = init_service(
+      App::new()
+        .wrap(RateLimit::new(Some(Arc::new(limiter))))
+        .route("/", web::get().to(HttpResponse::Ok))
+        .route("/{channel}", web::post().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let request = TestRequest::post().uri("/typical").to_request();
+    assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+    let request = TestRequest::post().uri("/typical").to_request();
+    let response = try_call_service(&app, request)
+      .await
+      .unwrap_err()
+      .error_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+
+    // Only submissions are limited
+    let request = TestRequest::get().uri("/").to_request();
+    assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+  }
+}
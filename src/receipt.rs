@@ -0,0 +1,124 @@
+//! Submission receipts. If a receipt key is configured, the server returns a
+//! receipt token for each accepted submission, which contains the SHA-256 digest
+//! of the decoded STAR message, the epoch and the acceptance timestamp, signed
+//! with HMAC-SHA256. Receipts can be verified via the admin endpoint, so that
+//! client teams can confirm whether a submission was accepted.
+//!
+//! Tokens are formatted as `<digest hex>.<epoch>.<unix timestamp>.<signature hex>`.
+
+use derive_more::{Display, Error};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+
+const SUBMISSION_RECEIPT_KEY_FILE_ENV_KEY: &str = "SUBMISSION_RECEIPT_KEY_FILE";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Error, Display, Debug, PartialEq)]
+pub enum ReceiptError {
+  #[display(fmt = "malformed receipt")]
+  Malformed,
+  #[display(fmt = "invalid receipt signature")]
+  BadSignature,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Receipt {
+  pub digest: String,
+  pub epoch: u8,
+  pub timestamp: i64,
+}
+
+pub struct ReceiptSigner {
+  key: Vec<u8>,
+}
+
+impl ReceiptSigner {
+  pub fn new(key: &[u8]) -> Self {
+    assert!(!key.is_empty(), "submission receipt key must not be empty");
+    Self { key: key.to_vec() }
+  }
+
+  /// Loads the receipt key from the hex-encoded key file, if configured.
+  pub fn from_env() -> Option<Self> {
+    let path = env::var(SUBMISSION_RECEIPT_KEY_FILE_ENV_KEY).ok()?;
+    let contents = fs::read_to_string(&path)
+      .unwrap_or_else(|e| panic!("failed to read submission receipt key file {}: {}", path, e));
+    let key = hex::decode(contents.trim()).expect("submission receipt key file should contain hex");
+    Some(Self::new(&key))
+  }
+
+  fn mac(&self, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC should accept any key size");
+    mac.update(payload.as_bytes());
+    mac
+  }
+
+  /// Returns the receipt token for a submitted message.
+  pub fn sign(&self, message: &[u8], epoch: u8, timestamp: i64) -> String {
+    let payload = format!(
+      "{}.{}.{}",
+      hex::encode(Sha256::digest(message)),
+      epoch,
+      timestamp
+    );
+    let signature = self.mac(&payload).finalize().into_bytes();
+    format!("{}.{}", payload, hex::encode(signature))
+  }
+
+  pub fn verify(&self, token: &str) -> Result<Receipt, ReceiptError> {
+    let (payload, signature) = token
+      .trim()
+      .rsplit_once('.')
+      .ok_or(ReceiptError::Malformed)?;
+    let signature = hex::decode(signature).map_err(|_| ReceiptError::Malformed)?;
+    self
+      .mac(payload)
+      .verify(&signature)
+      .map_err(|_| ReceiptError::BadSignature)?;
+
+    let mut fields = payload.split('.');
+    let (digest, epoch, timestamp) = match (fields.next(), fields.next(), fields.next()) {
+      (Some(digest), Some(epoch), Some(timestamp)) => (digest, epoch, timestamp),
+      _ => return Err(ReceiptError::Malformed),
+    };
+    Ok(Receipt {
+      digest: digest.to_string(),
+      epoch: epoch.parse().map_err(|_| ReceiptError::Malformed)?,
+      timestamp: timestamp.parse().map_err(|_| ReceiptError::Malformed)?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sign_verify() {
+    let signer = ReceiptSigner::new(b"test key");
+    let token = signer.sign(b"test message", 4, 1700000000);
+    assert_eq!(
+      signer.verify(&token).unwrap(),
+      Receipt {
+        digest: hex::encode(Sha256::digest(b"test message")),
+        epoch: 4,
+        timestamp: 1700000000,
+      }
+    );
+
+    let tampered_token = token.replacen(".4.", ".5.", 1);
+    assert_eq!(
+      signer.verify(&tampered_token),
+      Err(ReceiptError::BadSignature)
+    );
+    assert_eq!(
+      ReceiptSigner::new(b"other key").verify(&token),
+      Err(ReceiptError::BadSignature)
+    );
+    assert_eq!(signer.verify("invalid"), Err(ReceiptError::Malformed));
+  }
+}
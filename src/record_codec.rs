@@ -0,0 +1,227 @@
+//! Transformations of record payloads, applied by wrapping a record stream. Payload codecs
+//! encode the payload of each produced record, and decode the payloads of consumed records,
+//! i.e. to encrypt or compress them. Each codec marks the payloads it encoded with its own
+//! prefix, and passes through payloads without the prefix, so that records produced before
+//! a codec was enabled can still be consumed.
+
+use crate::record_stream::{
+  BatchRecord, ConsumedRecord, PartitionPosition, RecordStream, RecordStreamArc, RecordStreamError,
+};
+use async_trait::async_trait;
+use derive_more::{Display, Error};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+#[derive(Error, Display, Debug, Clone, Copy, PartialEq)]
+pub enum PayloadError {
+  #[display(fmt = "decryption failed")]
+  Decrypt,
+  #[display(fmt = "decompression failed")]
+  Decompress,
+}
+
+pub trait PayloadCodec: Send + Sync {
+  /// Encodes the payload of a produced record.
+  fn encode<'a>(&self, payload: &'a [u8]) -> Cow<'a, [u8]>;
+
+  /// Decodes the payload of a consumed record.
+  fn decode(&self, payload: Vec<u8>) -> Result<Vec<u8>, PayloadError>;
+}
+
+/// Wraps the stream, so that the payloads of its records are encoded by the codec.
+pub fn with_codec<C: PayloadCodec + 'static>(
+  stream: RecordStreamArc,
+  codec: C,
+  topic: &str,
+) -> RecordStreamArc {
+  Arc::new(CodecRecordStream {
+    inner: stream,
+    codec,
+    topic: topic.to_string(),
+  })
+}
+
+/// Record stream that encodes produced payloads and decodes consumed payloads of
+/// the wrapped stream.
+struct CodecRecordStream<C> {
+  inner: RecordStreamArc,
+  codec: C,
+  topic: String,
+}
+
+impl<C: PayloadCodec> CodecRecordStream<C> {
+  fn decode_record(&self, mut record: ConsumedRecord) -> Result<ConsumedRecord, RecordStreamError> {
+    record.data = self
+      .codec
+      .decode(std::mem::take(&mut record.data))
+      .map_err(|source| RecordStreamError::Payload {
+        topic: self.topic.clone(),
+        partition: record.partition,
+        offset: record.offset,
+        source,
+      })?;
+    Ok(record)
+  }
+}
+
+#[async_trait]
+impl<C: PayloadCodec> RecordStream for CodecRecordStream<C> {
+  fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
+    self.inner.init_producer_transactions()
+  }
+
+  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.begin_producer_transaction()
+  }
+
+  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.commit_producer_transaction()
+  }
+
+  fn abort_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self.inner.abort_producer_transaction()
+  }
+
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.inner.check_connection().await
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    self.inner.has_assigned_partitions()
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    self.inner.assigned_partitions()
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.committed_offsets()
+  }
+
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    self.inner.end_offsets()
+  }
+
+  fn partition_positions(&self) -> Result<Vec<PartitionPosition>, RecordStreamError> {
+    self.inner.partition_positions()
+  }
+
+  fn partition_lags(&self) -> Result<Vec<(i32, i64)>, RecordStreamError> {
+    self.inner.partition_lags()
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    self
+      .inner
+      .produce_with_headers(
+        &self.codec.encode(record),
+        key,
+        request_threshold,
+        channel_name,
+        epoch,
+        extra_headers,
+      )
+      .await
+  }
+
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    let payloads: Vec<_> = records.iter().map(|v| self.codec.encode(v.data)).collect();
+    let sealed_records: Vec<_> = records
+      .iter()
+      .zip(payloads.iter())
+      .map(|(v, payload)| BatchRecord {
+        data: payload.as_ref(),
+        key: v.key,
+        request_threshold: v.request_threshold,
+        channel_name: v.channel_name,
+        epoch: v.epoch,
+      })
+      .collect();
+    self.inner.produce_batch(&sealed_records).await
+  }
+
+  async fn init_producer_queues(&self) {
+    self.inner.init_producer_queues().await
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    // Avoids copying payloads that are passed through
+    let encoded = match self.codec.encode(&record) {
+      Cow::Borrowed(_) => None,
+      Cow::Owned(encoded) => Some(encoded),
+    };
+    self
+      .inner
+      .queue_produce(encoded.unwrap_or(record), key)
+      .await
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    self.inner.join_produce_queues().await
+  }
+
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    self.decode_record(self.inner.consume().await?)
+  }
+
+  async fn consume_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<ConsumedRecord>, RecordStreamError> {
+    self
+      .inner
+      .consume_batch(max_count, max_wait)
+      .await?
+      .into_iter()
+      .map(|v| self.decode_record(v))
+      .collect()
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    self.inner.commit_last_consume().await
+  }
+
+  fn pause_partition(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    self.inner.pause_partition(partition, offset)
+  }
+
+  fn pause(&self) -> Result<bool, RecordStreamError> {
+    self.inner.pause()
+  }
+
+  fn resume(&self) -> Result<(), RecordStreamError> {
+    self.inner.resume()
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    self.inner.commit_offsets(offsets).await
+  }
+
+  async fn shutdown(&self, commit: bool) -> Result<(), RecordStreamError> {
+    self.inner.shutdown(commit).await
+  }
+
+  fn seek(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    self.inner.seek(partition, offset)
+  }
+
+  fn seek_to_timestamp(&self, timestamp: OffsetDateTime) -> Result<bool, RecordStreamError> {
+    self.inner.seek_to_timestamp(timestamp)
+  }
+}
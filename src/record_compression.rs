@@ -0,0 +1,120 @@
+//! Compression of record payloads. If `RECORD_COMPRESSION` is set to `zstd`, record streams
+//! compress the payload of each produced record with zstd at `RECORD_COMPRESSION_LEVEL`,
+//! unless the compressed payload would not be smaller. Compressed payloads start with
+//! `COMPRESSED_MAGIC`, and are decompressed on consume regardless of the setting, so that
+//! consumers can read compressed records before their producers are configured to compress.
+//! If record encryption is enabled, payloads are compressed before they are encrypted.
+
+use crate::record_codec::{with_codec, PayloadCodec, PayloadError};
+use crate::record_stream::RecordStreamArc;
+use crate::util::parse_env_var;
+use std::borrow::Cow;
+
+const RECORD_COMPRESSION_ENV_KEY: &str = "RECORD_COMPRESSION";
+const DEFAULT_RECORD_COMPRESSION: &str = "none";
+const RECORD_COMPRESSION_LEVEL_ENV_KEY: &str = "RECORD_COMPRESSION_LEVEL";
+const DEFAULT_RECORD_COMPRESSION_LEVEL: &str = "3";
+
+/// Prefix of compressed payloads, including the version of the format
+const COMPRESSED_MAGIC: [u8; 4] = [0xff, b'R', b'Z', 1];
+/// Max size of a decompressed payload, which bounds the memory used by invalid payloads
+const MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Compresses payloads at the level, if set.
+struct ZstdCodec {
+  level: Option<i32>,
+}
+
+impl ZstdCodec {
+  /// Reads the compression settings. Panics if they are invalid.
+  fn from_env() -> Self {
+    let level = match parse_env_var::<String>(
+      RECORD_COMPRESSION_ENV_KEY,
+      DEFAULT_RECORD_COMPRESSION,
+    )
+    .as_str()
+    {
+      "none" => None,
+      "zstd" => {
+        let level = parse_env_var::<i32>(
+          RECORD_COMPRESSION_LEVEL_ENV_KEY,
+          DEFAULT_RECORD_COMPRESSION_LEVEL,
+        );
+        let levels = zstd::compression_level_range();
+        assert!(
+          levels.contains(&level),
+          "{} must be between {} and {}",
+          RECORD_COMPRESSION_LEVEL_ENV_KEY,
+          levels.start(),
+          levels.end()
+        );
+        Some(level)
+      }
+      v => panic!(
+        "{} must be none or zstd, got {}",
+        RECORD_COMPRESSION_ENV_KEY, v
+      ),
+    };
+    Self { level }
+  }
+}
+
+impl PayloadCodec for ZstdCodec {
+  fn encode<'a>(&self, payload: &'a [u8]) -> Cow<'a, [u8]> {
+    let level = match self.level {
+      Some(level) => level,
+      None => return Cow::Borrowed(payload),
+    };
+    let compressed =
+      zstd::bulk::compress(payload, level).expect("record compression should not fail");
+    if compressed.len() + COMPRESSED_MAGIC.len() >= payload.len() {
+      return Cow::Borrowed(payload);
+    }
+    let mut result = Vec::with_capacity(COMPRESSED_MAGIC.len() + compressed.len());
+    result.extend_from_slice(&COMPRESSED_MAGIC);
+    result.extend(compressed);
+    Cow::Owned(result)
+  }
+
+  fn decode(&self, payload: Vec<u8>) -> Result<Vec<u8>, PayloadError> {
+    match payload.strip_prefix(&COMPRESSED_MAGIC) {
+      Some(compressed) => zstd::bulk::decompress(compressed, MAX_DECOMPRESSED_BYTES)
+        .map_err(|_| PayloadError::Decompress),
+      None => Ok(payload),
+    }
+  }
+}
+
+/// Wraps the stream with compression, so that compressed payloads are always
+/// decompressed, and produced payloads are compressed if enabled.
+pub fn with_record_compression(stream: RecordStreamArc, topic: &str) -> RecordStreamArc {
+  with_codec(stream, ZstdCodec::from_env(), topic)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn compressed_payloads() {
+    let codec = ZstdCodec { level: Some(3) };
+    let payload = "eyJtZXNzYWdlIjogInRlc3QifQ==".repeat(100).into_bytes();
+    let compressed = codec.encode(&payload).into_owned();
+    assert!(compressed.starts_with(&COMPRESSED_MAGIC));
+    assert!(compressed.len() < payload.len() / 3);
+    assert_eq!(codec.decode(compressed.clone()).unwrap(), payload);
+
+    // Payloads that do not compress are passed through
+    assert!(matches!(codec.encode(b"short"), Cow::Borrowed(b"short")));
+    assert_eq!(codec.decode(b"short".to_vec()).unwrap(), b"short");
+
+    // Compressed payloads are decompressed even if compression is disabled
+    let disabled_codec = ZstdCodec { level: None };
+    assert!(matches!(disabled_codec.encode(&payload), Cow::Borrowed(_)));
+    assert_eq!(disabled_codec.decode(compressed.clone()).unwrap(), payload);
+
+    let mut invalid = compressed;
+    invalid.truncate(COMPRESSED_MAGIC.len() + 4);
+    assert_eq!(codec.decode(invalid), Err(PayloadError::Decompress));
+  }
+}
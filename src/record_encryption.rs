@@ -0,0 +1,143 @@
+//! Envelope encryption of record payloads. If a record encryption key is configured via
+//! `RECORD_ENCRYPTION_KEY_FILE` or `RECORD_ENCRYPTION_KMS_DATA_KEY`, record streams encrypt
+//! the payload of each produced record with AES-256-GCM, and decrypt the payloads of
+//! consumed records, so that STAR messages and measurements are not readable by other
+//! clients of the stream, i.e. if topic ACLs are misconfigured. Record headers are not
+//! encrypted.
+//!
+//! Encrypted payloads start with `ENVELOPE_MAGIC`, followed by the nonce and ciphertext.
+//! Payloads without the magic are passed through, so that records produced before
+//! encryption was enabled can still be consumed.
+
+use crate::encryption::{decrypt_kms_data_key, ShareCipher};
+use crate::record_codec::{with_codec, PayloadCodec, PayloadError};
+use crate::record_stream::RecordStreamArc;
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::sync::{Arc, OnceLock};
+
+const RECORD_ENCRYPTION_KEY_FILE_ENV_KEY: &str = "RECORD_ENCRYPTION_KEY_FILE";
+const RECORD_ENCRYPTION_KMS_DATA_KEY_ENV_KEY: &str = "RECORD_ENCRYPTION_KMS_DATA_KEY";
+
+/// Prefix of encrypted payloads, including the version of the envelope format
+const ENVELOPE_MAGIC: [u8; 4] = [0xff, b'R', b'E', 1];
+
+static RECORD_CIPHER: OnceLock<Option<Arc<ShareCipher>>> = OnceLock::new();
+
+/// Loads the record encryption key from the environment, if configured.
+/// Must be called before any record streams are created.
+pub async fn init_record_encryption() {
+  let key = if let Ok(path) = env::var(RECORD_ENCRYPTION_KEY_FILE_ENV_KEY) {
+    let contents = fs::read_to_string(&path)
+      .unwrap_or_else(|e| panic!("failed to read record encryption key file {}: {}", path, e));
+    Some(hex::decode(contents.trim()).expect("record encryption key file should contain hex"))
+  } else if let Ok(encoded_data_key) = env::var(RECORD_ENCRYPTION_KMS_DATA_KEY_ENV_KEY) {
+    Some(decrypt_kms_data_key(&encoded_data_key).await)
+  } else {
+    None
+  };
+  if key.is_some() {
+    info!("Record encryption is enabled");
+  }
+  RECORD_CIPHER.get_or_init(|| key.map(|key| Arc::new(ShareCipher::new(&key))));
+}
+
+/// Wraps the stream with envelope encryption, if a record encryption key is configured.
+pub fn with_record_encryption(stream: RecordStreamArc, topic: &str) -> RecordStreamArc {
+  match RECORD_CIPHER.get().and_then(|v| v.as_ref()) {
+    Some(cipher) => with_codec(stream, RecordCipher(cipher.clone()), topic),
+    None => stream,
+  }
+}
+
+/// Encrypts payloads with the record encryption key.
+struct RecordCipher(Arc<ShareCipher>);
+
+impl PayloadCodec for RecordCipher {
+  fn encode<'a>(&self, payload: &'a [u8]) -> Cow<'a, [u8]> {
+    let mut result = ENVELOPE_MAGIC.to_vec();
+    result.extend(self.0.encrypt(payload));
+    Cow::Owned(result)
+  }
+
+  fn decode(&self, payload: Vec<u8>) -> Result<Vec<u8>, PayloadError> {
+    match payload.strip_prefix(&ENVELOPE_MAGIC) {
+      Some(sealed) => self.0.decrypt(sealed).map_err(|_| PayloadError::Decrypt),
+      None => Ok(payload),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::{BatchRecord, ConsumedRecord, TestRecordStream};
+  use std::time::Duration;
+  use tokio::sync::Mutex;
+
+  #[tokio::test]
+  async fn encrypted_records() {
+    let inner = Arc::new(TestRecordStream::default());
+    let cipher = Arc::new(ShareCipher::new(&[7u8; 32]));
+    let stream = with_codec(inner.clone(), RecordCipher(cipher.clone()), "p3a-star-enc");
+    stream
+      .produce(b"message", None, None, None, None)
+      .await
+      .unwrap();
+    stream
+      .produce_batch(&[BatchRecord {
+        data: b"batch message",
+        key: None,
+        request_threshold: None,
+        channel_name: None,
+        epoch: None,
+      }])
+      .await
+      .unwrap();
+
+    let produced = std::mem::take(&mut *inner.records_produced.lock().await);
+    assert_eq!(produced.len(), 2);
+    assert!(produced.iter().all(|v| v.starts_with(&ENVELOPE_MAGIC)));
+    assert!(!produced[0].ends_with(b"message"));
+
+    // Records produced before encryption was enabled are passed through
+    let mut records: Vec<_> = produced
+      .into_iter()
+      .chain([b"plaintext".to_vec()])
+      .map(|data| ConsumedRecord {
+        data,
+        ..Default::default()
+      })
+      .collect();
+    *inner.records_to_consume.lock().await = std::mem::take(&mut records);
+    assert_eq!(stream.consume().await.unwrap().data, b"message");
+    let batch = stream
+      .consume_batch(2, Duration::from_millis(10))
+      .await
+      .unwrap();
+    assert_eq!(batch[0].data, b"batch message");
+    assert_eq!(batch[1].data, b"plaintext");
+
+    let other_cipher = RecordCipher(Arc::new(ShareCipher::new(&[8u8; 32])));
+    let other_stream = with_codec(
+      Arc::new(TestRecordStream {
+        records_to_consume: Mutex::new(vec![ConsumedRecord {
+          data: other_cipher.encode(b"message").into_owned(),
+          partition: Some(2),
+          offset: Some(5),
+          ..Default::default()
+        }]),
+        ..Default::default()
+      }),
+      RecordCipher(cipher),
+      "p3a-star-enc",
+    );
+    let e = other_stream.consume().await.err().unwrap();
+    assert_eq!(e.category(), "record_encryption");
+    assert_eq!(
+      e.to_string(),
+      "Record stream error: invalid payload of topic p3a-star-enc partition 2 at offset 5: decryption failed"
+    );
+  }
+}
@@ -1,62 +1,405 @@
 use async_trait::async_trait;
-use derive_more::{Display, Error, From};
+use futures::channel::oneshot::Canceled;
 use futures::future::try_join_all;
+use futures::FutureExt;
 use rand::{seq::SliceRandom, thread_rng};
-use rdkafka::client::ClientContext;
+use rdkafka::admin::{AdminClient, AdminOptions, AlterConfig, ConfigSource, ResourceSpecifier};
+use rdkafka::client::{ClientContext, DefaultClientContext};
 use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{
-  stream_consumer::StreamConsumer, CommitMode, Consumer, ConsumerContext, Rebalance,
+  stream_consumer::StreamConsumer, BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance,
 };
 use rdkafka::error::{KafkaError, KafkaResult};
 use rdkafka::message::{Header, Headers, Message, OwnedHeaders};
 use rdkafka::producer::{future_producer::FutureProducer, FutureRecord, Producer};
 use rdkafka::types::RDKafkaErrorCode;
-use rdkafka::TopicPartitionList;
+use rdkafka::{Offset, Statistics, TopicPartitionList};
 use std::collections::HashMap;
 use std::env;
+use std::fmt::{self, Debug};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::sync::mpsc::{error::SendError, unbounded_channel, UnboundedSender};
-use tokio::sync::{Mutex, RwLock};
+#[cfg(test)]
+use tokio::sync::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::task::{JoinError, JoinHandle};
+#[cfg(test)]
 use tokio::time::sleep;
+use tokio::time::{timeout, Instant};
 
-use crate::channel::{get_data_channel_map_from_env, get_data_channel_value_from_env};
+use crate::channel::{
+  get_data_channel_list_map_from_env, get_data_channel_map_from_env,
+  get_data_channel_value_from_env,
+};
+use crate::faulty_stream::{with_faults, FaultConfig, FaultOperation};
+use crate::file_stream::{FileRecordStream, FileStreamError};
+use crate::format_version::check_format_version;
+use crate::kafka_group::{GroupInstance, KafkaGroupConfig};
+use crate::kafka_rack::{record_fetch_statistics, KafkaRackConfig};
+use crate::kafka_security::KafkaSecurityConfig;
+use crate::kafka_topics::ensure_topic;
+use crate::kinesis::{KinesisError, KinesisRecordStream};
+use crate::nats::{NatsError, NatsRecordStream};
+use crate::produce_rate_limit::{with_produce_rate_limit, ProduceRateLimit};
+use crate::produce_retry::{error_name, ProduceRetryPolicy};
+use crate::prometheus::kafka_metrics;
+use crate::pubsub::{PubSubError, PubSubRecordStream};
+use crate::record_codec::PayloadError;
+use crate::record_compression::with_record_compression;
+use crate::record_encryption::with_record_encryption;
+use crate::redis::RedisError;
+use crate::redis_stream::RedisRecordStream;
+use crate::sqs::{SqsError, SqsRecordStream};
+use crate::tenant::tenant_scoped_name;
 use crate::util::parse_env_var;
 
+const RECORD_STREAM_BACKEND_ENV_KEY: &str = "RECORD_STREAM_BACKEND";
+const DEFAULT_RECORD_STREAM_BACKEND: &str = KAFKA_BACKEND_NAME;
+const KAFKA_BACKEND_NAME: &str = "kafka";
+const KINESIS_BACKEND_NAME: &str = "kinesis";
+const NATS_BACKEND_NAME: &str = "nats";
+const PUBSUB_BACKEND_NAME: &str = "pubsub";
+const SQS_BACKEND_NAME: &str = "sqs";
+const REDIS_BACKEND_NAME: &str = "redis";
+const FILE_BACKEND_NAME: &str = "file";
+
 const KAFKA_ENC_TOPICS_ENV_KEY: &str = "KAFKA_ENCRYPTED_TOPICS";
 const KAFKA_OUT_TOPICS_ENV_KEY: &str = "KAFKA_OUTPUT_TOPICS";
 const DEFAULT_ENC_KAFKA_TOPICS: &str = "typical=p3a-star-enc";
 const DEFAULT_OUT_KAFKA_TOPICS: &str = "typical=p3a-star-out";
+const KAFKA_DLQ_TOPICS_ENV_KEY: &str = "KAFKA_DLQ_TOPICS";
+const KAFKA_AGGREGATOR_GROUP_ID_ENV_KEY: &str = "KAFKA_AGGREGATOR_GROUP_ID";
+const DEFAULT_AGGREGATOR_GROUP_ID: &str = "star-agg-enc";
+const KAFKA_LAKE_SINK_GROUP_ID_ENV_KEY: &str = "KAFKA_LAKE_SINK_GROUP_ID";
+const DEFAULT_LAKE_SINK_GROUP_ID: &str = "star-agg-dec";
+const KAFKA_REPROCESS_OUTPUT_TOPICS_ENV_KEY: &str = "KAFKA_REPROCESS_OUTPUT_TOPICS";
 const KAFKA_BROKERS_ENV_KEY: &str = "KAFKA_BROKERS";
-const KAFKA_ENABLE_PLAINTEXT_ENV_KEY: &str = "KAFKA_ENABLE_PLAINTEXT";
 const KAFKA_PRODUCER_QUEUE_TASK_COUNT_ENV_KEY: &str = "KAFKA_PRODUCE_QUEUE_TASK_COUNT";
-const KAFKA_TLS_CA_CERT_PATH_ENV_KEY: &str = "KAFKA_TLS_CA_CERT_PATH";
-const KAFKA_TLS_CERT_PATH_ENV_KEY: &str = "KAFKA_TLS_CERT_PATH";
-const KAFKA_TLS_KEY_PATH_ENV_KEY: &str = "KAFKA_TLS_KEY_PATH";
 const DEFAULT_KAFKA_PRODUCER_QUEUE_TASK_COUNT: &str = "64";
 
 const KAFKA_INIT_TRX_TIMEOUT_SECS: u64 = 30;
 const KAFKA_COMMIT_TRX_TIMEOUT_SECS: u64 = 60 * 30;
 
-const KAFKA_PRODUCE_TIMEOUT_SECS: u64 = 12;
+const KAFKA_MESSAGE_TIMEOUT_MS_ENV_KEY_SUFFIX: &str = "MESSAGE_TIMEOUT_MS";
+const DEFAULT_KAFKA_MESSAGE_TIMEOUT_MS: &str = "3600000";
+const KAFKA_REQUEST_TIMEOUT_MS_ENV_KEY_SUFFIX: &str = "REQUEST_TIMEOUT_MS";
+const DEFAULT_KAFKA_REQUEST_TIMEOUT_MS: &str = "900000";
+const KAFKA_SEND_TIMEOUT_MS_ENV_KEY_SUFFIX: &str = "SEND_TIMEOUT_MS";
+const DEFAULT_KAFKA_SEND_TIMEOUT_MS: &str = "12000";
+const KAFKA_PRODUCER_QUEUE_MAX_MESSAGES_ENV_KEY_SUFFIX: &str = "PRODUCER_QUEUE_MAX_MESSAGES";
+const DEFAULT_KAFKA_PRODUCER_QUEUE_MAX_MESSAGES: &str = "100000";
+const KAFKA_PRODUCER_QUEUE_MAX_KBYTES_ENV_KEY_SUFFIX: &str = "PRODUCER_QUEUE_MAX_KBYTES";
+const DEFAULT_KAFKA_PRODUCER_QUEUE_MAX_KBYTES: &str = "1048576";
+const KAFKA_PRODUCER_LINGER_MS_ENV_KEY_SUFFIX: &str = "PRODUCER_LINGER_MS";
+const DEFAULT_KAFKA_PRODUCER_LINGER_MS: &str = "5";
+const KAFKA_PRODUCER_BATCH_NUM_MESSAGES_ENV_KEY_SUFFIX: &str = "PRODUCER_BATCH_NUM_MESSAGES";
+const DEFAULT_KAFKA_PRODUCER_BATCH_NUM_MESSAGES: &str = "10000";
+const KAFKA_PRODUCER_COMPRESSION_TYPE_ENV_KEY_SUFFIX: &str = "PRODUCER_COMPRESSION_TYPE";
+const DEFAULT_KAFKA_PRODUCER_COMPRESSION_TYPE: &str = "none";
+const KAFKA_PRODUCER_ACKS_ENV_KEY_SUFFIX: &str = "PRODUCER_ACKS";
+const DEFAULT_KAFKA_PRODUCER_ACKS: &str = "all";
+const KAFKA_CONSUMER_QUEUE_MAX_KBYTES_ENV_KEY_SUFFIX: &str = "CONSUMER_QUEUE_MAX_KBYTES";
+const DEFAULT_KAFKA_CONSUMER_QUEUE_MAX_KBYTES: &str = "300000";
+const KAFKA_CONSUMER_FETCH_MIN_BYTES_ENV_KEY_SUFFIX: &str = "CONSUMER_FETCH_MIN_BYTES";
+const DEFAULT_KAFKA_CONSUMER_FETCH_MIN_BYTES: &str = "1";
+const KAFKA_CONSUMER_FETCH_MAX_BYTES_ENV_KEY_SUFFIX: &str = "CONSUMER_FETCH_MAX_BYTES";
+const DEFAULT_KAFKA_CONSUMER_FETCH_MAX_BYTES: &str = "52428800";
+const KAFKA_CONSUMER_MAX_PARTITION_FETCH_BYTES_ENV_KEY_SUFFIX: &str =
+  "CONSUMER_MAX_PARTITION_FETCH_BYTES";
+const DEFAULT_KAFKA_CONSUMER_MAX_PARTITION_FETCH_BYTES: &str = "1048576";
+const KAFKA_CONSUMER_MAX_POLL_INTERVAL_MS_ENV_KEY_SUFFIX: &str = "CONSUMER_MAX_POLL_INTERVAL_MS";
+const DEFAULT_KAFKA_CONSUMER_SESSION_TIMEOUT_MS: &str = "21000";
+const KAFKA_CONSUMER_SESSION_TIMEOUT_MS_ENV_KEY_SUFFIX: &str = "CONSUMER_SESSION_TIMEOUT_MS";
+const DEFAULT_KAFKA_CONSUMER_MAX_POLL_INTERVAL_MS: &str = "14400000";
+const KAFKA_AUTO_SIZE_CONSUMERS_ENV_KEY_SUFFIX: &str = "AUTO_SIZE_CONSUMERS";
+const DEFAULT_KAFKA_AUTO_SIZE_CONSUMERS: &str = "false";
+
+/// Max time to wait for offset & watermark queries
+const KAFKA_POSITION_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Max time to wait for the partitions to be revoked when leaving the consumer group
+const KAFKA_LEAVE_GROUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of polling the consumer while leaving the group
+const KAFKA_LEAVE_GROUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RETENTION_MS_CONFIG: &str = "retention.ms";
 
 const THRESHOLD_HEADER_NAME: &str = "threshold";
+const CHANNEL_HEADER_NAME: &str = "channel";
+const TENANT_HEADER_NAME: &str = "tenant";
+const EPOCH_HEADER_NAME: &str = "epoch";
+/// Client submission time in Unix milliseconds, for producers that relay
+/// submissions after receiving them. The record timestamp is used otherwise.
+const SUBMITTED_AT_HEADER_NAME: &str = "submitted-at";
+/// Version of the client that submitted the message, as supplied in the submission request
+const CLIENT_VERSION_HEADER_NAME: &str = "client-version";
 
-#[derive(Debug, Display, Error, From)]
-#[display(fmt = "Record stream error: {}")]
+/// The Kafka operation that failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KafkaOperation {
+  Connect,
+  Metadata,
+  Consume,
+  Produce,
+  Commit,
+  Offsets,
+  Pause,
+  Resume,
+  Seek,
+  Configure,
+  Close,
+  Transaction,
+}
+
+impl KafkaOperation {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Connect => "connect",
+      Self::Metadata => "metadata",
+      Self::Consume => "consume",
+      Self::Produce => "produce",
+      Self::Commit => "commit",
+      Self::Offsets => "offsets",
+      Self::Pause => "pause",
+      Self::Resume => "resume",
+      Self::Seek => "seek",
+      Self::Configure => "configure",
+      Self::Close => "close",
+      Self::Transaction => "transaction",
+    }
+  }
+}
+
+impl fmt::Display for KafkaOperation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+fn partition_suffix(partition: &Option<i32>) -> String {
+  partition
+    .map(|v| format!(" partition {}", v))
+    .unwrap_or_default()
+}
+
+/// Errors of the record stream backends. Kafka errors carry the failed operation,
+/// and the topic and partition it applied to.
+#[derive(Debug, Error)]
 pub enum RecordStreamError {
-  Kafka(KafkaError),
-  Deserialize,
-  ProducerNotPresent,
+  #[error(
+    "Record stream error: Kafka {operation} failed for topic {topic}{}: {source}",
+    partition_suffix(.partition)
+  )]
+  Kafka {
+    operation: KafkaOperation,
+    topic: String,
+    partition: Option<i32>,
+    source: KafkaError,
+  },
+  #[error("Record stream error: {0}")]
+  Kinesis(#[from] KinesisError),
+  #[error("Record stream error: {0}")]
+  Nats(#[from] NatsError),
+  #[error("Record stream error: {0}")]
+  PubSub(#[from] PubSubError),
+  #[error("Record stream error: {0}")]
+  Sqs(#[from] SqsError),
+  #[error("Record stream error: {0}")]
+  Redis(#[from] RedisError),
+  #[error("Record stream error: {0}")]
+  File(#[from] FileStreamError),
+  #[error(
+    "Record stream error: invalid payload at topic {topic} partition {partition} offset {offset}"
+  )]
+  Deserialize {
+    topic: String,
+    partition: i32,
+    offset: i64,
+  },
+  #[error(
+    "Record stream error: invalid payload of topic {topic}{}{}: {source}",
+    partition_suffix(.partition),
+    .offset.map(|v| format!(" at offset {}", v)).unwrap_or_default()
+  )]
+  Payload {
+    topic: String,
+    partition: Option<i32>,
+    offset: Option<i64>,
+    source: PayloadError,
+  },
+  #[error("Record stream error: injected {} fault for topic {topic}", .operation.name())]
+  InjectedFault {
+    operation: FaultOperation,
+    topic: String,
+  },
+  #[cfg(test)]
+  #[error("Record stream error: test consume timeout")]
   TestConsumeTimeout,
-  MpscSendError(SendError<Vec<u8>>),
-  Join(JoinError),
+  #[error("Record stream error: producer not enabled for topic {topic}")]
+  ProducerNotPresent { topic: String },
+  #[error("Record stream error: producer queue closed for topic {topic}")]
+  ProducerQueueClosed {
+    topic: String,
+    source: SendError<ProducerQueueItem>,
+  },
+  #[error(
+    "Record stream error: topic {topic} does not exist; create it, or set KAFKA_AUTO_CREATE=true"
+  )]
+  TopicMissing { topic: String },
+  #[error("Record stream error: topic {topic} has {actual} partitions, expected {expected}")]
+  TopicPartitions {
+    topic: String,
+    expected: usize,
+    actual: usize,
+  },
+  #[error(
+    "Record stream error: topic {topic} has record format version {version}, \
+     this release supports up to {supported}"
+  )]
+  UnsupportedFormat {
+    topic: String,
+    version: u32,
+    supported: u32,
+  },
+  #[error("Record stream error: invalid record format version marker for topic {topic}")]
+  FormatMarker { topic: String },
+  #[error("Record stream error: task failed: {0}")]
+  Join(#[from] JoinError),
+}
+
+impl RecordStreamError {
+  pub fn kafka(
+    operation: KafkaOperation,
+    topic: &str,
+    partition: Option<i32>,
+    source: KafkaError,
+  ) -> Self {
+    Self::Kafka {
+      operation,
+      topic: topic.to_string(),
+      partition,
+      source,
+    }
+  }
+
+  /// Returns an error of a topic check or creation.
+  pub fn topic(topic: &str, source: KafkaError) -> Self {
+    Self::kafka(KafkaOperation::Configure, topic, None, source)
+  }
+
+  /// Returns a stable name for the kind of failure, for logs and metrics.
+  pub fn category(&self) -> &'static str {
+    match self {
+      Self::Kafka { operation, .. } => match operation {
+        KafkaOperation::Connect => "kafka_connect",
+        KafkaOperation::Metadata => "kafka_metadata",
+        KafkaOperation::Consume => "kafka_consume",
+        KafkaOperation::Produce => "kafka_produce",
+        KafkaOperation::Commit => "kafka_commit",
+        KafkaOperation::Offsets => "kafka_offsets",
+        KafkaOperation::Pause | KafkaOperation::Resume => "kafka_pause",
+        KafkaOperation::Seek => "kafka_seek",
+        KafkaOperation::Configure => "kafka_configure",
+        KafkaOperation::Close => "kafka_close",
+        KafkaOperation::Transaction => "kafka_transaction",
+      },
+      Self::Kinesis(_) => "kinesis",
+      Self::Nats(_) => "nats",
+      Self::PubSub(_) => "pubsub",
+      Self::Sqs(_) => "sqs",
+      Self::Redis(_) => "redis",
+      Self::File(_) => "file_stream",
+      Self::Deserialize { .. } => "record_deserialize",
+      Self::Payload { source, .. } => match source {
+        PayloadError::Decrypt => "record_encryption",
+        PayloadError::Decompress => "record_compression",
+      },
+      Self::InjectedFault { .. } => "fault_injection",
+      #[cfg(test)]
+      Self::TestConsumeTimeout => "test",
+      Self::ProducerNotPresent { .. } => "producer_missing",
+      Self::ProducerQueueClosed { .. } => "producer_queue",
+      Self::TopicMissing { .. } | Self::TopicPartitions { .. } => "kafka_topic",
+      Self::UnsupportedFormat { .. } | Self::FormatMarker { .. } => "format_version",
+      Self::Join(_) => "task_join",
+    }
+  }
+}
+
+/// Adds the operation, topic and partition to Kafka errors.
+trait KafkaResultExt<T> {
+  fn context(
+    self,
+    operation: KafkaOperation,
+    topic: &str,
+    partition: Option<i32>,
+  ) -> Result<T, RecordStreamError>;
+}
+
+impl<T> KafkaResultExt<T> for KafkaResult<T> {
+  fn context(
+    self,
+    operation: KafkaOperation,
+    topic: &str,
+    partition: Option<i32>,
+  ) -> Result<T, RecordStreamError> {
+    self.map_err(|e| RecordStreamError::kafka(operation, topic, partition, e))
+  }
+}
+
+/// Reports the delivery of a produced record, once it is acknowledged by the brokers or
+/// fails. The latency is the time since the record was enqueued in the producer.
+fn report_delivery(topic: &str, result: Result<(i32, i64), &KafkaError>, latency: Duration) {
+  match result {
+    Ok((partition, offset)) => debug!(
+      "Kafka: delivered record to topic {} partition {} at offset {} in {}ms",
+      topic,
+      partition,
+      offset,
+      latency.as_millis()
+    ),
+    Err(e) => debug!(
+      "Kafka: delivery to topic {} failed after {}ms: {}",
+      topic,
+      latency.as_millis(),
+      e
+    ),
+  }
+  if let Some(metrics) = kafka_metrics() {
+    let outcome = match result {
+      Ok(_) => "ok".to_string(),
+      Err(e) => error_name(e),
+    };
+    metrics.delivery(topic, result.ok(), &outcome, latency);
+  }
+}
+
+/// Sends the record, waiting up to the timeout for space in the producer queue, and
+/// reports its delivery.
+async fn send_record(
+  producer: &FutureProducer<KafkaContext>,
+  record: FutureRecord<'_, str, [u8]>,
+  queue_timeout: Duration,
+) -> Result<(), KafkaError> {
+  let topic = record.topic.to_string();
+  let started_at = Instant::now();
+  let result = producer
+    .send(record, queue_timeout)
+    .await
+    .map_err(|(e, _)| e);
+  report_delivery(&topic, result.as_ref().copied(), started_at.elapsed());
+  result.map(|_| ())
 }
 
 struct KafkaContext;
 
-impl ClientContext for KafkaContext {}
+impl ClientContext for KafkaContext {
+  fn stats(&self, statistics: Statistics) {
+    record_fetch_statistics(&statistics);
+  }
+}
 
 impl ConsumerContext for KafkaContext {
   fn pre_rebalance(&self, rebalance: &Rebalance) {
@@ -72,65 +415,469 @@ impl ConsumerContext for KafkaContext {
   }
 }
 
+/// Configured topic name & partition number, since partition
+/// numbers are only unique within a topic.
+pub type TopicPartition = (String, i32);
+
+#[derive(Default, Clone)]
 pub struct ConsumedRecord {
   pub data: Vec<u8>,
   // Only applicable for the encrypted stream
   pub request_threshold: Option<usize>,
+  // Data channel captured at ingest; only applicable for the encrypted stream
+  pub channel_name: Option<String>,
+  // Message epoch captured at ingest; only applicable for the encrypted stream
+  pub epoch: Option<u8>,
+  // Configured name of the consumed topic; assigned by the aggregator and the message archive
+  pub topic: Option<String>,
+  // Kafka partition & offset of the record; not available for test records
+  pub partition: Option<i32>,
+  pub offset: Option<i64>,
+  // Client submission time; only applicable for the encrypted stream
+  pub submitted_at: Option<OffsetDateTime>,
+  // Client version captured at ingest; only applicable for the encrypted stream
+  pub client_version: Option<String>,
+  // Timestamp assigned to the record by the stream; not available for test records
+  pub timestamp: Option<OffsetDateTime>,
+}
+
+impl ConsumedRecord {
+  /// Returns the topic & partition of the record, if both are known.
+  pub fn topic_partition(&self) -> Option<TopicPartition> {
+    Some((self.topic.clone()?, self.partition?))
+  }
+
+  /// Returns the headers of the submission metadata of the record, so that the
+  /// metadata is preserved if the record is produced to another topic.
+  pub fn metadata_headers(&self) -> Vec<(&'static str, Vec<u8>)> {
+    RecordHeaderValues {
+      submitted_at: self.submitted_at.map(datetime_to_unix_millis),
+      client_version: self.client_version.clone(),
+      ..Default::default()
+    }
+    .headers()
+  }
+}
+
+pub fn datetime_from_unix_millis(millis: i64) -> Option<OffsetDateTime> {
+  OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000).ok()
+}
+
+pub fn datetime_to_unix_millis(time: OffsetDateTime) -> i64 {
+  (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// Consumer position within an assigned partition
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionPosition {
+  pub partition: i32,
+  /// Offset of the next record to consume, if consumption has been committed
+  pub committed_offset: Option<i64>,
+  /// Offset of the next record to be produced to the partition (high watermark)
+  pub end_offset: i64,
+}
+
+impl PartitionPosition {
+  /// Amount of records that have not been committed yet. If nothing
+  /// has been committed for the partition, the end offset is returned.
+  pub fn lag(&self) -> i64 {
+    (self.end_offset - self.committed_offset.unwrap_or(0)).max(0)
+  }
+}
+
+/// Values of the standard record headers
+#[derive(Default, Debug, PartialEq)]
+pub struct RecordHeaderValues {
+  pub request_threshold: Option<usize>,
+  pub channel_name: Option<String>,
+  pub tenant: Option<String>,
+  pub epoch: Option<u8>,
+  /// Client submission time in Unix milliseconds
+  pub submitted_at: Option<i64>,
+  pub client_version: Option<String>,
 }
 
+impl RecordHeaderValues {
+  pub fn parse<'a>(headers: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Self {
+    let mut result = Self::default();
+    for (key, value) in headers {
+      match key {
+        THRESHOLD_HEADER_NAME => {
+          result.request_threshold =
+            Some(u32::from_le_bytes(value.try_into().unwrap_or_default()) as usize);
+        }
+        CHANNEL_HEADER_NAME => {
+          result.channel_name = Some(String::from_utf8_lossy(value).to_string());
+        }
+        TENANT_HEADER_NAME => {
+          result.tenant = Some(String::from_utf8_lossy(value).to_string());
+        }
+        EPOCH_HEADER_NAME => {
+          result.epoch = value.first().copied();
+        }
+        SUBMITTED_AT_HEADER_NAME => {
+          if let Ok(value) = value.try_into() {
+            result.submitted_at = Some(i64::from_le_bytes(value));
+          }
+        }
+        CLIENT_VERSION_HEADER_NAME => {
+          result.client_version = Some(String::from_utf8_lossy(value).to_string());
+        }
+        _ => (),
+      }
+    }
+    result
+  }
+
+  /// Returns the headers for the set values, in little endian encoding where applicable.
+  pub fn headers(&self) -> Vec<(&'static str, Vec<u8>)> {
+    let mut headers = Vec::with_capacity(6);
+    if let Some(threshold) = self.request_threshold {
+      headers.push((
+        THRESHOLD_HEADER_NAME,
+        (threshold as u32).to_le_bytes().to_vec(),
+      ));
+    }
+    if let Some(channel_name) = self.channel_name.as_ref() {
+      headers.push((CHANNEL_HEADER_NAME, channel_name.as_bytes().to_vec()));
+    }
+    if let Some(tenant) = self.tenant.as_ref() {
+      headers.push((TENANT_HEADER_NAME, tenant.as_bytes().to_vec()));
+    }
+    if let Some(epoch) = self.epoch {
+      headers.push((EPOCH_HEADER_NAME, vec![epoch]));
+    }
+    if let Some(submitted_at) = self.submitted_at {
+      headers.push((
+        SUBMITTED_AT_HEADER_NAME,
+        submitted_at.to_le_bytes().to_vec(),
+      ));
+    }
+    if let Some(client_version) = self.client_version.as_ref() {
+      headers.push((
+        CLIENT_VERSION_HEADER_NAME,
+        client_version.as_bytes().to_vec(),
+      ));
+    }
+    headers
+  }
+
+  /// Returns false, and logs a warning, if the record is stamped with a different tenant
+  /// than the tenant of the stream.
+  pub fn matches_tenant(&self, expected_tenant: Option<&str>) -> bool {
+    match (self.tenant.as_deref(), expected_tenant) {
+      (Some(tenant), Some(expected_tenant)) if tenant != expected_tenant => {
+        warn!(
+          "Skipping record stamped with tenant '{}' in topic for tenant '{}'",
+          tenant, expected_tenant
+        );
+        false
+      }
+      _ => true,
+    }
+  }
+}
+
+/// Record to produce, along with an optional record key
+pub type ProducerQueueItem = (Vec<u8>, Option<String>);
+
 #[async_trait]
 pub trait RecordStream {
-  fn init_producer_transactions(&self) -> Result<(), RecordStreamError>;
+  /// Prepares the producer for transactions. Records produced within a transaction
+  /// are only visible to consumers once the transaction is committed. Backends without
+  /// transactions make records visible as they are produced, and ignore the
+  /// transaction methods.
+  fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+
+  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+
+  /// Commits the current transaction. The transaction is aborted if the commit fails.
+  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
 
-  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError>;
+  /// Aborts the current transaction, discarding the records produced within it.
+  fn abort_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
 
-  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError>;
+  /// Checks that the backend is reachable, i.e. by fetching the metadata of the topic.
+  /// May block.
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
 
   fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError>;
 
+  /// Returns the partitions currently assigned to the consumer.
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError>;
+
+  /// Returns the committed offsets of the assigned partitions, mapped by partition.
+  /// Partitions without a committed offset are omitted. May block while
+  /// the offsets are fetched from the cluster.
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError>;
+
+  /// Returns the end offsets of the assigned partitions, mapped by partition.
+  /// May block while the offsets are fetched from the cluster.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError>;
+
+  /// Returns the consumer position of each assigned partition.
+  fn partition_positions(&self) -> Result<Vec<PartitionPosition>, RecordStreamError> {
+    let committed_offsets = self.committed_offsets()?;
+    let end_offsets = self.end_offsets()?;
+    let mut positions: Vec<_> = self
+      .assigned_partitions()?
+      .into_iter()
+      .map(|partition| PartitionPosition {
+        partition,
+        committed_offset: committed_offsets.get(&partition).copied(),
+        end_offset: end_offsets.get(&partition).copied().unwrap_or_default(),
+      })
+      .collect();
+    positions.sort_by_key(|v| v.partition);
+    Ok(positions)
+  }
+
+  /// Returns the lag of each assigned partition, given as (partition, lag). The lag is
+  /// the high watermark minus the committed offset, see `PartitionPosition::lag`.
+  fn partition_lags(&self) -> Result<Vec<(i32, i64)>, RecordStreamError> {
+    Ok(
+      self
+        .partition_positions()?
+        .iter()
+        .map(|v| (v.partition, v.lag()))
+        .collect(),
+    )
+  }
+
+  /// Produces a record. Records with the same key are produced to the same partition,
+  /// if supported by the backend. Records without a key are spread across partitions.
   async fn produce(
     &self,
     record: &[u8],
+    key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+  ) -> Result<(), RecordStreamError> {
+    self
+      .produce_with_headers(record, key, request_threshold, channel_name, epoch, &[])
+      .await
+  }
+
+  /// Produces a record with additional headers, i.e. provenance of re-driven records.
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    key: Option<&str>,
     request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
   ) -> Result<(), RecordStreamError>;
 
+  /// Produces a batch of records, and waits until all records are delivered.
+  /// Backends may send the records concurrently, so the records may be stored in
+  /// a different order. By default, records are produced one at a time.
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    for record in records {
+      self
+        .produce(
+          record.data,
+          record.key,
+          record.request_threshold,
+          record.channel_name,
+          record.epoch,
+        )
+        .await?;
+    }
+    Ok(())
+  }
+
   async fn init_producer_queues(&self);
 
-  async fn queue_produce(&self, record: Vec<u8>) -> Result<(), RecordStreamError>;
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError>;
 
   async fn join_produce_queues(&self) -> Result<(), RecordStreamError>;
 
   async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError>;
 
+  /// Consumes up to `max_count` records. Waits up to `max_wait` for the first record,
+  /// and returns an empty batch if no record is consumed in time. Once the first record
+  /// is consumed, the records that can be consumed without waiting are added to the
+  /// batch, i.e. those already fetched by the consumer. Since the batch never waits
+  /// after its first record, the future may be cancelled like `consume` without losing
+  /// consumed records.
+  async fn consume_batch(
+    &self,
+    max_count: usize,
+    max_wait: Duration,
+  ) -> Result<Vec<ConsumedRecord>, RecordStreamError> {
+    if max_count == 0 {
+      return Ok(Vec::new());
+    }
+    let mut records = match timeout(max_wait, self.consume()).await {
+      Ok(record) => vec![record?],
+      Err(_) => return Ok(Vec::new()),
+    };
+    while records.len() < max_count {
+      match self.consume().now_or_never() {
+        Some(record) => records.push(record?),
+        None => break,
+      }
+    }
+    Ok(records)
+  }
+
   async fn commit_last_consume(&self) -> Result<(), RecordStreamError>;
+
+  /// Stops consumption from the partition until `resume` is called, and
+  /// rewinds the partition so that the record at `offset` is consumed again after
+  /// resuming. The record will not be committed by `commit_last_consume`. Returns
+  /// false if the backend does not support pausing, in which case the record
+  /// must be processed.
+  fn pause_partition(&self, _partition: i32, _offset: i64) -> Result<bool, RecordStreamError> {
+    Ok(false)
+  }
+
+  /// Stops fetching records from all assigned partitions until `resume` is called.
+  /// Consumption continues from the record after the last consumed record once
+  /// resumed. `consume` should still be polled while paused, so that the consumer
+  /// remains in its group. Returns false if the backend does not support pausing.
+  fn pause(&self) -> Result<bool, RecordStreamError> {
+    Ok(false)
+  }
+
+  /// Resumes consumption from all partitions paused via `pause` or `pause_partition`.
+  fn resume(&self) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+
+  /// Commits consumption up to and including the given offsets,
+  /// provided as a list of partitions and offsets.
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError>;
+
+  /// Leaves the consumer group and flushes pending produced records, so that the
+  /// partitions are reassigned without waiting for the session to time out. If `commit`
+  /// is true, the stored offsets, i.e. consumption up to the last consumed record, are
+  /// committed before leaving. The stream must not be used afterwards.
+  async fn shutdown(&self, _commit: bool) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+
+  /// Moves the consumer position of an assigned partition, so that the record at
+  /// `offset` is the next record consumed from the partition. Returns false if the
+  /// backend does not support seeking.
+  fn seek(&self, _partition: i32, _offset: i64) -> Result<bool, RecordStreamError> {
+    Ok(false)
+  }
+
+  /// Moves the consumer position of each assigned partition to the first record
+  /// with a timestamp at or after `timestamp`. Partitions without such a record are
+  /// moved to their end. Returns false if the backend does not support seeking.
+  fn seek_to_timestamp(&self, _timestamp: OffsetDateTime) -> Result<bool, RecordStreamError> {
+    Ok(false)
+  }
+}
+
+/// A record produced via `produce_batch`, with the same values as `produce`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BatchRecord<'a> {
+  pub data: &'a [u8],
+  pub key: Option<&'a str>,
+  pub request_threshold: Option<usize>,
+  pub channel_name: Option<&'a str>,
+  pub epoch: Option<u8>,
 }
 
 pub type DynRecordStream = dyn RecordStream + Send + Sync;
 pub type RecordStreamArc = Arc<DynRecordStream>;
 
+/// The component using a record stream. Producer and consumer settings
+/// can be configured separately for each component.
+#[derive(Clone, Copy, Debug)]
+pub enum KafkaComponent {
+  Server,
+  Aggregator,
+  LakeSink,
+}
+
+impl KafkaComponent {
+  pub fn env_key(&self, suffix: &str) -> String {
+    let component = match self {
+      KafkaComponent::Server => "SERVER",
+      KafkaComponent::Aggregator => "AGGREGATOR",
+      KafkaComponent::LakeSink => "LAKE_SINK",
+    };
+    format!("KAFKA_{}_{}", component, suffix)
+  }
+
+  fn parse_env_var<F>(&self, suffix: &str, default: &str) -> F
+  where
+    F: FromStr,
+    <F as FromStr>::Err: Debug,
+  {
+    parse_env_var(&self.env_key(suffix), default)
+  }
+}
+
 pub struct KafkaRecordStreamConfig {
+  pub component: KafkaComponent,
   pub enable_producer: bool,
   pub enable_consumer: bool,
   pub topic: String,
   pub use_output_group_id: bool,
+  /// Appended to the consumer group, for consumers that must not share the committed
+  /// offsets of the group, i.e. because they skip records that others should process.
+  pub group_id_suffix: Option<String>,
+  /// If set, the topic, consumer group and transactional id will be scoped
+  /// to the tenant, and records will be stamped/filtered by tenant header.
+  pub tenant: Option<String>,
 }
 
+pub type ProducerQueue = (
+  JoinHandle<Result<(), RecordStreamError>>,
+  UnboundedSender<ProducerQueueItem>,
+);
+
 pub struct KafkaRecordStream {
   producer: Option<Arc<FutureProducer<KafkaContext>>>,
   consumer: Option<StreamConsumer<KafkaContext>>,
   topic: String,
-  producer_queues: RwLock<
-    Vec<(
-      JoinHandle<Result<(), RecordStreamError>>,
-      UnboundedSender<Vec<u8>>,
-    )>,
-  >,
+  tenant: Option<String>,
+  /// Max time to wait for a produced record to be enqueued & delivered
+  send_timeout: Duration,
+  /// Retries of records that failed to be delivered due to transient errors
+  produce_retry: ProduceRetryPolicy,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  /// Bounds the amount of concurrent sends, if set
+  send_limit: Option<Arc<Semaphore>>,
+  /// Headers added to every produced record
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+  /// True if the partitions are assigned manually, instead of by the consumer group
+  manual_assignment: bool,
+  /// Static membership of the consumer in the group, if enabled. Declared after the
+  /// consumer, so that the instance id is only released once the consumer is closed.
+  group_instance: Option<GroupInstance>,
 }
 
+/// Returns the topic of each channel. For the encrypted topics, the first
+/// listed topic of each channel is returned, which is the topic produced to.
 pub fn get_data_channel_topic_map_from_env(use_output_topics: bool) -> HashMap<String, String> {
   match use_output_topics {
     true => get_data_channel_map_from_env(KAFKA_OUT_TOPICS_ENV_KEY, DEFAULT_OUT_KAFKA_TOPICS),
-    false => get_data_channel_map_from_env(KAFKA_ENC_TOPICS_ENV_KEY, DEFAULT_ENC_KAFKA_TOPICS),
+    false => get_data_channel_input_topics_map_from_env()
+      .into_iter()
+      .map(|(channel_name, mut topics)| (channel_name, topics.swap_remove(0)))
+      .collect(),
   }
 }
 
@@ -141,158 +888,874 @@ pub fn get_data_channel_topic_from_env(use_output_topic: bool, channel_name: &st
       DEFAULT_OUT_KAFKA_TOPICS,
       channel_name,
     ),
-    false => get_data_channel_value_from_env(
-      KAFKA_ENC_TOPICS_ENV_KEY,
-      DEFAULT_ENC_KAFKA_TOPICS,
-      channel_name,
+    false => get_data_channel_input_topics_from_env(channel_name).swap_remove(0),
+  }
+}
+
+/// Returns all encrypted topics of each channel. A channel may consume
+/// multiple encrypted topics, i.e. `typical=p3a-star-enc-desktop,p3a-star-enc-android`.
+pub fn get_data_channel_input_topics_map_from_env() -> HashMap<String, Vec<String>> {
+  get_data_channel_list_map_from_env(KAFKA_ENC_TOPICS_ENV_KEY, DEFAULT_ENC_KAFKA_TOPICS)
+}
+
+/// Returns all encrypted topics of the channel.
+pub fn get_data_channel_input_topics_from_env(channel_name: &str) -> Vec<String> {
+  get_data_channel_input_topics_map_from_env()
+    .remove(channel_name)
+    .unwrap_or_else(|| {
+      panic!(
+        "{} env channel setting must contain entry for channel: {}",
+        KAFKA_ENC_TOPICS_ENV_KEY, channel_name
+      )
+    })
+}
+
+/// Returns the amount of partitions in the topic, or zero if the topic does not exist.
+pub fn fetch_topic_partition_count(
+  component: KafkaComponent,
+  topic: &str,
+  tenant: Option<&str>,
+) -> Result<usize, RecordStreamError> {
+  let topic = tenant_scoped_name(topic, tenant);
+  let consumer: BaseConsumer = KafkaRecordStream::new_client_config(component)
+    .create()
+    .context(KafkaOperation::Connect, &topic, None)?;
+  let metadata = consumer
+    .fetch_metadata(Some(&topic), KAFKA_POSITION_QUERY_TIMEOUT)
+    .context(KafkaOperation::Metadata, &topic, None)?;
+  Ok(
+    metadata
+      .topics()
+      .iter()
+      .find(|v| v.name() == topic)
+      .map(|v| v.partitions().len())
+      .unwrap_or_default(),
+  )
+}
+
+/// Sets the `retention.ms` config of the topic, and returns the previous retention
+/// of the topic. Other configs overridden for the topic are kept, since altering the
+/// config of a topic resets all configs that are not supplied. If `dry_run` is set,
+/// only the current retention is returned.
+pub async fn set_topic_retention(
+  component: KafkaComponent,
+  topic: &str,
+  tenant: Option<&str>,
+  retention_ms: i64,
+  dry_run: bool,
+) -> Result<Option<i64>, RecordStreamError> {
+  let topic = tenant_scoped_name(topic, tenant);
+  let admin: AdminClient<DefaultClientContext> = KafkaRecordStream::new_client_config(component)
+    .create()
+    .context(KafkaOperation::Connect, &topic, None)?;
+  let options = AdminOptions::new().request_timeout(Some(KAFKA_POSITION_QUERY_TIMEOUT));
+  let resource = admin
+    .describe_configs(&[ResourceSpecifier::Topic(&topic)], &options)
+    .await
+    .context(KafkaOperation::Configure, &topic, None)?
+    .pop()
+    .expect("describe configs should return a result for the topic")
+    .map_err(KafkaError::AdminOp)
+    .context(KafkaOperation::Configure, &topic, None)?;
+  let previous_retention_ms = resource
+    .get(RETENTION_MS_CONFIG)
+    .and_then(|v| v.value.as_ref())
+    .and_then(|v| v.parse().ok());
+  if dry_run || previous_retention_ms == Some(retention_ms) {
+    return Ok(previous_retention_ms);
+  }
+
+  let retention_ms = retention_ms.to_string();
+  let mut alter_config = AlterConfig::new(ResourceSpecifier::Topic(&topic));
+  for entry in &resource.entries {
+    if let (ConfigSource::DynamicTopic, Some(value)) = (&entry.source, entry.value.as_ref()) {
+      alter_config = alter_config.set(&entry.name, value);
+    }
+  }
+  alter_config = alter_config.set(RETENTION_MS_CONFIG, &retention_ms);
+  admin
+    .alter_configs(&[alter_config], &options)
+    .await
+    .context(KafkaOperation::Configure, &topic, None)?
+    .pop()
+    .expect("alter configs should return a result for the topic")
+    .map_err(|(_, code)| KafkaError::AdminOp(code))
+    .context(KafkaOperation::Configure, &topic, None)?;
+  Ok(previous_retention_ms)
+}
+
+/// Checks the configured consumer count against the partition count of the topic,
+/// and returns the amount of consumers to use. Consumers in excess of the partition
+/// count would sit idle, so a warning is logged. If `KAFKA_<COMPONENT>_AUTO_SIZE_CONSUMERS`
+/// is enabled, one consumer is used per partition instead.
+pub fn consumer_count_for_topic(
+  component: KafkaComponent,
+  topic: &str,
+  tenant: Option<&str>,
+  configured_count: usize,
+) -> usize {
+  let auto_size = component.parse_env_var::<bool>(
+    KAFKA_AUTO_SIZE_CONSUMERS_ENV_KEY_SUFFIX,
+    DEFAULT_KAFKA_AUTO_SIZE_CONSUMERS,
+  );
+  let partition_count = match fetch_topic_partition_count(component, topic, tenant) {
+    Ok(count) if count > 0 => count,
+    Ok(_) => {
+      warn!(
+        "Topic {} has no partitions, cannot check consumer count",
+        topic
+      );
+      return configured_count;
+    }
+    Err(e) => {
+      warn!(
+        "Failed to fetch partition count for topic {}, cannot check consumer count: {}",
+        topic, e
+      );
+      return configured_count;
+    }
+  };
+  if auto_size {
+    info!(
+      "Using {} consumers for topic {}, one per partition",
+      partition_count, topic
+    );
+    return partition_count;
+  }
+  if configured_count > partition_count {
+    warn!(
+      "{} consumers configured for topic {} with {} partitions; {} consumers will be idle",
+      configured_count,
+      topic,
+      partition_count,
+      configured_count - partition_count
+    );
+  }
+  configured_count
+}
+
+/// Returns the dead letter topic for the channel, if one is configured.
+pub fn get_data_channel_dlq_topic_from_env(channel_name: &str) -> Option<String> {
+  get_data_channel_map_from_env(KAFKA_DLQ_TOPICS_ENV_KEY, "").remove(channel_name)
+}
+
+/// Returns the topic for output of reprocessing runs. Defaults to the
+/// output topic of the channel, suffixed with `-reprocess`.
+pub fn get_data_channel_reprocess_topic_from_env(channel_name: &str) -> String {
+  get_data_channel_map_from_env(KAFKA_REPROCESS_OUTPUT_TOPICS_ENV_KEY, "")
+    .remove(channel_name)
+    .unwrap_or_else(|| {
+      format!(
+        "{}-reprocess",
+        get_data_channel_topic_from_env(true, channel_name)
+      )
+    })
+}
+
+/// Returns the consumer group of the stream, with the suffix of the stream if set,
+/// scoped to the tenant if set. The output group is the group of the lake sink.
+pub fn consumer_group_id(stream_config: &KafkaRecordStreamConfig) -> String {
+  let (env_key, default) = match stream_config.use_output_group_id {
+    true => (KAFKA_LAKE_SINK_GROUP_ID_ENV_KEY, DEFAULT_LAKE_SINK_GROUP_ID),
+    false => (
+      KAFKA_AGGREGATOR_GROUP_ID_ENV_KEY,
+      DEFAULT_AGGREGATOR_GROUP_ID,
     ),
+  };
+  let mut group_id = parse_env_var::<String>(env_key, default);
+  if let Some(suffix) = stream_config.group_id_suffix.as_ref() {
+    group_id = format!("{}-{}", group_id, suffix);
+  }
+  tenant_scoped_name(&group_id, stream_config.tenant.as_deref())
+}
+
+/// Producer settings that apply to every stream backend, and consumer settings
+/// that are only supported by some backends.
+#[derive(Default, Clone)]
+pub struct RecordStreamOptions {
+  /// Bounds the amount of concurrent sends, if set
+  pub send_limit: Option<Arc<Semaphore>>,
+  /// Headers added to every produced record
+  pub record_headers: Vec<(String, Vec<u8>)>,
+  /// Partitions assigned to the consumer, instead of subscribing as a member
+  /// of the consumer group. Only supported by the Kafka backend.
+  pub assigned_partitions: Option<Vec<i32>>,
+}
+
+pub type RecordStreamConstructor =
+  Arc<dyn Fn(KafkaRecordStreamConfig, RecordStreamOptions) -> RecordStreamArc + Send + Sync>;
+
+/// Creates record streams using the backend selected at runtime. The Kafka, Kinesis, NATS,
+/// Pub/Sub, SQS, Redis and file backends are always available; alternative backends are registered by name via `with_backend`.
+/// Backends interpret the topic, consumer group and tenant settings of the stream config
+/// in their own way.
+#[derive(Clone)]
+pub struct RecordStreamFactory {
+  backend: String,
+  constructors: HashMap<String, RecordStreamConstructor>,
+}
+
+impl RecordStreamFactory {
+  pub fn new(backend: &str) -> Self {
+    let kafka_constructor: RecordStreamConstructor = Arc::new(|config, options| {
+      let mut stream = KafkaRecordStream::new(config, options.assigned_partitions.as_deref())
+        .with_record_headers(options.record_headers);
+      if let Some(send_limit) = options.send_limit {
+        stream = stream.with_send_limit(send_limit);
+      }
+      Arc::new(stream)
+    });
+    let kinesis_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(KinesisRecordStream::new(config, options)));
+    let nats_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(NatsRecordStream::new(config, options)));
+    let pubsub_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(PubSubRecordStream::new(config, options)));
+    let sqs_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(SqsRecordStream::new(config, options)));
+    let redis_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(RedisRecordStream::new(config, options)));
+    let file_constructor: RecordStreamConstructor =
+      Arc::new(|config, options| Arc::new(FileRecordStream::new(config, options)));
+    Self {
+      backend: backend.to_string(),
+      constructors: HashMap::from([
+        (KAFKA_BACKEND_NAME.to_string(), kafka_constructor),
+        (KINESIS_BACKEND_NAME.to_string(), kinesis_constructor),
+        (NATS_BACKEND_NAME.to_string(), nats_constructor),
+        (PUBSUB_BACKEND_NAME.to_string(), pubsub_constructor),
+        (SQS_BACKEND_NAME.to_string(), sqs_constructor),
+        (REDIS_BACKEND_NAME.to_string(), redis_constructor),
+        (FILE_BACKEND_NAME.to_string(), file_constructor),
+      ]),
+    }
+  }
+
+  /// Selects the backend named by `RECORD_STREAM_BACKEND`.
+  pub fn from_env() -> Self {
+    Self::new(&parse_env_var::<String>(
+      RECORD_STREAM_BACKEND_ENV_KEY,
+      DEFAULT_RECORD_STREAM_BACKEND,
+    ))
+  }
+
+  /// Registers an alternative backend, which may then be selected by name.
+  #[allow(dead_code)]
+  pub fn with_backend(mut self, name: &str, constructor: RecordStreamConstructor) -> Self {
+    self.constructors.insert(name.to_string(), constructor);
+    self
+  }
+
+  pub fn backend(&self) -> &str {
+    &self.backend
+  }
+
+  /// Panics if the selected backend has not been registered, or if partitions are
+  /// assigned to a backend other than Kafka.
+  pub fn create(
+    &self,
+    config: KafkaRecordStreamConfig,
+    options: RecordStreamOptions,
+  ) -> RecordStreamArc {
+    if options.assigned_partitions.is_some() && self.backend != KAFKA_BACKEND_NAME {
+      panic!(
+        "Partition assignment is not supported by the {} record stream backend",
+        self.backend
+      );
+    }
+    let constructor = self
+      .constructors
+      .get(&self.backend)
+      .unwrap_or_else(|| panic!("Unknown record stream backend '{}'", self.backend));
+    let topic = tenant_scoped_name(&config.topic, config.tenant.as_deref());
+    let rate_limit = ProduceRateLimit::from_env(config.component);
+    let stream = with_faults(
+      constructor(config, options),
+      &FaultConfig::from_env(),
+      &topic,
+    );
+    // Rate limits apply to the encoded payloads
+    let stream = with_produce_rate_limit(stream, rate_limit);
+    // Payloads are compressed before they are encrypted
+    with_record_compression(with_record_encryption(stream, &topic), &topic)
+  }
+
+  /// Returns the amount of consumers to use for the topic. Only the Kafka backend
+  /// checks the count against the partitions of the topic. The Kinesis and file
+  /// backends always use one consumer.
+  pub fn consumer_count(
+    &self,
+    component: KafkaComponent,
+    topic: &str,
+    tenant: Option<&str>,
+    configured_count: usize,
+  ) -> usize {
+    match self.backend.as_str() {
+      KAFKA_BACKEND_NAME => consumer_count_for_topic(component, topic, tenant, configured_count),
+      KINESIS_BACKEND_NAME => {
+        if configured_count > 1 {
+          warn!(
+            "Kinesis consumers do not coordinate shard assignment, using one consumer for stream {}",
+            topic
+          );
+        }
+        1
+      }
+      FILE_BACKEND_NAME => {
+        if configured_count > 1 {
+          warn!(
+            "File record stream consumers do not share consumption, using one consumer for topic {}",
+            topic
+          );
+        }
+        1
+      }
+      _ => configured_count,
+    }
   }
 }
 
 impl KafkaRecordStream {
-  pub fn new(stream_config: KafkaRecordStreamConfig) -> Self {
-    let group_id = match stream_config.use_output_group_id {
-      true => "star-agg-dec",
-      false => "star-agg-enc",
-    };
+  /// If partitions are assigned, the consumer consumes the partitions from the committed
+  /// offsets of the consumer group, without joining the group.
+  pub fn new(stream_config: KafkaRecordStreamConfig, assigned_partitions: Option<&[i32]>) -> Self {
+    let tenant = stream_config.tenant.as_deref();
+    let group_id = consumer_group_id(&stream_config);
+    let topic = tenant_scoped_name(&stream_config.topic, tenant);
+    let component = stream_config.component;
+    if stream_config.enable_producer || stream_config.enable_consumer {
+      ensure_topic(component, &stream_config.topic, &topic)
+        .unwrap_or_else(|e| panic!("Kafka topic check failed: {}", e));
+      check_format_version(component, &topic, stream_config.enable_producer)
+        .unwrap_or_else(|e| panic!("Record format check failed: {}", e));
+    }
 
     let mut result = Self {
       producer: None,
       consumer: None,
-      topic: stream_config.topic.clone(),
+      topic: topic.clone(),
+      tenant: stream_config.tenant.clone(),
+      send_timeout: Duration::from_millis(component.parse_env_var(
+        KAFKA_SEND_TIMEOUT_MS_ENV_KEY_SUFFIX,
+        DEFAULT_KAFKA_SEND_TIMEOUT_MS,
+      )),
+      produce_retry: ProduceRetryPolicy::from_env(component),
       producer_queues: RwLock::new(Vec::new()),
+      send_limit: None,
+      record_headers: Arc::new(Vec::new()),
+      manual_assignment: assigned_partitions.is_some(),
+      group_instance: None,
     };
     if stream_config.enable_producer {
       let context = KafkaContext;
-      let mut config = Self::new_client_config();
+      let mut config = Self::new_client_config(component);
       let mut config_ref = &mut config;
       if stream_config.use_output_group_id {
-        config_ref = config_ref.set("transactional.id", "main");
+        // The id is scoped to the topic, so that runs producing to
+        // different output topics do not fence each other
+        config_ref = config_ref
+          .set(
+            "transactional.id",
+            tenant_scoped_name(&format!("main-{}", stream_config.topic), tenant),
+          )
+          .set("transaction.timeout.ms", "3600000");
       }
       result.producer = Some(Arc::new(
         config_ref
-          .set("message.timeout.ms", "3600000")
-          .set("transaction.timeout.ms", "3600000")
-          .set("request.timeout.ms", "900000")
+          .set(
+            "message.timeout.ms",
+            component
+              .parse_env_var::<u64>(
+                KAFKA_MESSAGE_TIMEOUT_MS_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_MESSAGE_TIMEOUT_MS,
+              )
+              .to_string(),
+          )
+          .set(
+            "request.timeout.ms",
+            component
+              .parse_env_var::<u64>(
+                KAFKA_REQUEST_TIMEOUT_MS_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_REQUEST_TIMEOUT_MS,
+              )
+              .to_string(),
+          )
           .set("socket.timeout.ms", "300000")
+          .set(
+            "queue.buffering.max.messages",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_PRODUCER_QUEUE_MAX_MESSAGES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_PRODUCER_QUEUE_MAX_MESSAGES,
+              )
+              .to_string(),
+          )
+          .set(
+            "queue.buffering.max.kbytes",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_PRODUCER_QUEUE_MAX_KBYTES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_PRODUCER_QUEUE_MAX_KBYTES,
+              )
+              .to_string(),
+          )
+          .set(
+            "linger.ms",
+            component
+              .parse_env_var::<u64>(
+                KAFKA_PRODUCER_LINGER_MS_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_PRODUCER_LINGER_MS,
+              )
+              .to_string(),
+          )
+          .set(
+            "batch.num.messages",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_PRODUCER_BATCH_NUM_MESSAGES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_PRODUCER_BATCH_NUM_MESSAGES,
+              )
+              .to_string(),
+          )
+          .set(
+            "compression.type",
+            component.parse_env_var::<String>(
+              KAFKA_PRODUCER_COMPRESSION_TYPE_ENV_KEY_SUFFIX,
+              DEFAULT_KAFKA_PRODUCER_COMPRESSION_TYPE,
+            ),
+          )
+          .set(
+            "acks",
+            component.parse_env_var::<String>(
+              KAFKA_PRODUCER_ACKS_ENV_KEY_SUFFIX,
+              DEFAULT_KAFKA_PRODUCER_ACKS,
+            ),
+          )
           .create_with_context(context)
           .unwrap(),
       ));
-      info!("Producing to topic: {}", stream_config.topic);
+      info!("Producing to topic: {}", topic);
     }
     if stream_config.enable_consumer {
       let context = KafkaContext;
-      let mut config = Self::new_client_config();
+      let mut config = Self::new_client_config(component);
+      // Assigned consumers do not join the group, so they have no membership
+      if assigned_partitions.is_none() {
+        result.group_instance =
+          KafkaGroupConfig::from_env(component).reserve_instance(&group_id, &topic);
+        if let Some(group_instance) = result.group_instance.as_ref() {
+          info!(
+            "Joining consumer group {} as static member {}",
+            group_id, group_instance.id
+          );
+          group_instance.apply(&mut config);
+        }
+      }
       result.consumer = Some(
         config
-          .set("group.id", group_id)
+          .set("group.id", &group_id)
           .set("enable.auto.commit", "false")
-          .set("session.timeout.ms", "21000")
-          .set("max.poll.interval.ms", "14400000")
+          .set(
+            "session.timeout.ms",
+            component
+              .parse_env_var::<u64>(
+                KAFKA_CONSUMER_SESSION_TIMEOUT_MS_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_SESSION_TIMEOUT_MS,
+              )
+              .to_string(),
+          )
+          .set(
+            "max.poll.interval.ms",
+            component
+              .parse_env_var::<u64>(
+                KAFKA_CONSUMER_MAX_POLL_INTERVAL_MS_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_MAX_POLL_INTERVAL_MS,
+              )
+              .to_string(),
+          )
           .set("auto.offset.reset", "earliest")
-          .set("queued.max.messages.kbytes", "300000")
+          .set(
+            "fetch.min.bytes",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_CONSUMER_FETCH_MIN_BYTES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_FETCH_MIN_BYTES,
+              )
+              .to_string(),
+          )
+          .set(
+            "fetch.max.bytes",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_CONSUMER_FETCH_MAX_BYTES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_FETCH_MAX_BYTES,
+              )
+              .to_string(),
+          )
+          .set(
+            "max.partition.fetch.bytes",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_CONSUMER_MAX_PARTITION_FETCH_BYTES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_MAX_PARTITION_FETCH_BYTES,
+              )
+              .to_string(),
+          )
+          .set(
+            "queued.max.messages.kbytes",
+            component
+              .parse_env_var::<usize>(
+                KAFKA_CONSUMER_QUEUE_MAX_KBYTES_ENV_KEY_SUFFIX,
+                DEFAULT_KAFKA_CONSUMER_QUEUE_MAX_KBYTES,
+              )
+              .to_string(),
+          )
           .create_with_context(context)
           .unwrap(),
       );
       info!(
         "Consuming from topic: {} (current offsets: {:?})",
-        stream_config.topic,
+        topic,
         result.consumer.as_ref().unwrap().position().unwrap()
       );
-      result
-        .consumer
-        .as_ref()
-        .unwrap()
-        .subscribe(&[&stream_config.topic])
-        .unwrap();
+      let consumer = result.consumer.as_ref().unwrap();
+      match assigned_partitions {
+        Some(partitions) => {
+          let mut assignment = TopicPartitionList::new();
+          for partition in partitions {
+            // An invalid offset resumes from the committed offset of the group
+            assignment
+              .add_partition_offset(&topic, *partition, Offset::Invalid)
+              .unwrap();
+          }
+          info!("Assigned partitions of topic {}: {:?}", topic, partitions);
+          consumer.assign(&assignment).unwrap();
+        }
+        None => consumer.subscribe(&[&topic]).unwrap(),
+      }
     }
     result
   }
 
-  fn new_client_config() -> ClientConfig {
-    let brokers = env::var(KAFKA_BROKERS_ENV_KEY)
-      .unwrap_or_else(|_| panic!("{} env var must be defined", KAFKA_BROKERS_ENV_KEY));
-    let mut result = ClientConfig::new();
-    result.set("bootstrap.servers", brokers);
-    if env::var(KAFKA_ENABLE_PLAINTEXT_ENV_KEY).unwrap_or_default() == "true" {
-      result.set("security.protocol", "plaintext");
+  /// Bounds the amount of concurrent sends by the producer using the semaphore.
+  /// The semaphore may be shared with other streams.
+  pub fn with_send_limit(mut self, send_limit: Arc<Semaphore>) -> Self {
+    self.send_limit = Some(send_limit);
+    self
+  }
+
+  /// Adds the headers to every record produced by the stream.
+  pub fn with_record_headers(mut self, record_headers: Vec<(String, Vec<u8>)>) -> Self {
+    self.record_headers = Arc::new(record_headers);
+    self
+  }
+
+  fn future_record<'a>(
+    &'a self,
+    record: &'a [u8],
+    key: Option<&'a str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> FutureRecord<'a, str, [u8]> {
+    let mut result: FutureRecord<str, [u8]> = FutureRecord::to(&self.topic).payload(record);
+    if let Some(key) = key {
+      result = result.key(key);
+    }
+    let standard_headers = RecordHeaderValues {
+      request_threshold,
+      channel_name: channel_name.map(|v| v.to_string()),
+      tenant: self.tenant.clone(),
+      epoch,
+      ..Default::default()
+    }
+    .headers();
+    let mut headers = OwnedHeaders::new_with_capacity(
+      standard_headers.len() + extra_headers.len() + self.record_headers.len(),
+    );
+    let record_headers = self.record_headers.iter().map(|(k, v)| (k.as_str(), v));
+    for (key, value) in standard_headers
+      .iter()
+      .chain(extra_headers.iter())
+      .map(|(k, v)| (*k, v))
+      .chain(record_headers)
+    {
+      headers = headers.insert(Header {
+        key,
+        value: Some(value.as_slice()),
+      });
     }
-    if let Ok(cert_path) = env::var(KAFKA_TLS_CERT_PATH_ENV_KEY) {
-      result
-        .set("security.protocol", "ssl")
-        .set("ssl.certificate.location", cert_path);
+    if headers.count() > 0 {
+      result = result.headers(headers);
     }
-    if let Ok(cert_path) = env::var(KAFKA_TLS_CA_CERT_PATH_ENV_KEY) {
-      result.set("ssl.ca.location", cert_path);
+    result
+  }
+
+  /// Moves the positions of the partitions. Partitions of subscribed consumers can only
+  /// be moved once they are being fetched, so manually assigned partitions are assigned
+  /// again instead, with the current positions of the other partitions.
+  fn seek_partitions(&self, offsets: &[(i32, Offset)]) -> Result<(), RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    if !self.manual_assignment {
+      for (partition, offset) in offsets {
+        consumer
+          .seek(
+            &self.topic,
+            *partition,
+            *offset,
+            KAFKA_POSITION_QUERY_TIMEOUT,
+          )
+          .context(KafkaOperation::Seek, &self.topic, Some(*partition))?;
+      }
+      return Ok(());
     }
-    if let Ok(key_path) = env::var(KAFKA_TLS_KEY_PATH_ENV_KEY) {
-      result.set("ssl.key.location", key_path);
+    let mut assignment = consumer
+      .position()
+      .context(KafkaOperation::Seek, &self.topic, None)?;
+    for (partition, offset) in offsets {
+      if assignment.find_partition(&self.topic, *partition).is_none() {
+        return Err(RecordStreamError::kafka(
+          KafkaOperation::Seek,
+          &self.topic,
+          Some(*partition),
+          KafkaError::Seek("partition is not assigned".to_string()),
+        ));
+      }
+      assignment
+        .set_partition_offset(&self.topic, *partition, *offset)
+        .context(KafkaOperation::Seek, &self.topic, Some(*partition))?;
     }
+    consumer
+      .assign(&assignment)
+      .context(KafkaOperation::Seek, &self.topic, None)
+  }
+
+  fn transactional_producer(&self) -> Result<&FutureProducer<KafkaContext>, RecordStreamError> {
+    self
+      .producer
+      .as_deref()
+      .ok_or_else(|| RecordStreamError::ProducerNotPresent {
+        topic: self.topic.clone(),
+      })
+  }
+
+  fn produce_error(&self, source: KafkaError) -> RecordStreamError {
+    RecordStreamError::kafka(KafkaOperation::Produce, &self.topic, None, source)
+  }
+
+  /// Creates the base config of a client, with the security and rack settings of the component.
+  pub fn new_client_config(component: KafkaComponent) -> ClientConfig {
+    let brokers = env::var(KAFKA_BROKERS_ENV_KEY)
+      .unwrap_or_else(|_| panic!("{} env var must be defined", KAFKA_BROKERS_ENV_KEY));
+    let mut result = ClientConfig::new();
+    result.set("bootstrap.servers", brokers);
+    KafkaSecurityConfig::from_env(component).apply(&mut result);
+    KafkaRackConfig::from_env(component).apply(&mut result);
     result
   }
 }
 
+/// Sends the record to a producer queue of the topic.
+pub fn send_to_producer_queue(
+  tx: &UnboundedSender<ProducerQueueItem>,
+  topic: &str,
+  item: ProducerQueueItem,
+) -> Result<(), RecordStreamError> {
+  tx.send(item)
+    .map_err(|e| RecordStreamError::ProducerQueueClosed {
+      topic: topic.to_string(),
+      source: e,
+    })
+}
+
+pub async fn acquire_send_permit(
+  send_limit: Option<&Arc<Semaphore>>,
+) -> Option<OwnedSemaphorePermit> {
+  match send_limit {
+    Some(send_limit) => Some(send_limit.clone().acquire_owned().await.unwrap()),
+    None => None,
+  }
+}
+
 #[async_trait]
 impl RecordStream for KafkaRecordStream {
   fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
-    Ok(
-      self
-        .producer
-        .as_ref()
-        .ok_or(RecordStreamError::ProducerNotPresent)?
-        .init_transactions(Duration::from_secs(KAFKA_INIT_TRX_TIMEOUT_SECS))?,
-    )
+    self
+      .transactional_producer()?
+      .init_transactions(Duration::from_secs(KAFKA_INIT_TRX_TIMEOUT_SECS))
+      .context(KafkaOperation::Transaction, &self.topic, None)
   }
 
   fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
-    Ok(
-      self
-        .producer
-        .as_ref()
-        .ok_or(RecordStreamError::ProducerNotPresent)?
-        .begin_transaction()?,
+    self.transactional_producer()?.begin_transaction().context(
+      KafkaOperation::Transaction,
+      &self.topic,
+      None,
     )
   }
 
   fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
-    let producer = self
-      .producer
-      .as_ref()
-      .ok_or(RecordStreamError::ProducerNotPresent)?;
+    let producer = self.transactional_producer()?;
     let timeout = Duration::from_secs(KAFKA_COMMIT_TRX_TIMEOUT_SECS);
     if let Err(e) = producer.commit_transaction(timeout) {
-      producer.abort_transaction(timeout)?;
-      return Err(RecordStreamError::from(e));
+      producer.abort_transaction(timeout).context(
+        KafkaOperation::Transaction,
+        &self.topic,
+        None,
+      )?;
+      return Err(RecordStreamError::kafka(
+        KafkaOperation::Transaction,
+        &self.topic,
+        None,
+        e,
+      ));
     }
     Ok(())
   }
 
+  fn abort_producer_transaction(&self) -> Result<(), RecordStreamError> {
+    self
+      .transactional_producer()?
+      .abort_transaction(Duration::from_secs(KAFKA_COMMIT_TRX_TIMEOUT_SECS))
+      .context(KafkaOperation::Transaction, &self.topic, None)
+  }
+
+  /// Fetches the metadata of the topic using the producer.
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    let producer = self.producer.as_ref().expect("Kafka producer not enabled");
+    producer
+      .client()
+      .fetch_metadata(Some(&self.topic), KAFKA_POSITION_QUERY_TIMEOUT)
+      .context(KafkaOperation::Metadata, &self.topic, None)?;
+    Ok(())
+  }
+
   fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
     if let Some(consumer) = self.consumer.as_ref() {
-      return Ok(consumer.assignment()?.count() > 0);
+      let assignment = consumer
+        .assignment()
+        .context(KafkaOperation::Offsets, &self.topic, None)?;
+      return Ok(assignment.count() > 0);
     }
     Ok(false)
   }
 
-  async fn produce(
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    Ok(
+      consumer
+        .assignment()
+        .context(KafkaOperation::Offsets, &self.topic, None)?
+        .elements_for_topic(&self.topic)
+        .iter()
+        .map(|v| v.partition())
+        .collect(),
+    )
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    Ok(
+      consumer
+        .committed(KAFKA_POSITION_QUERY_TIMEOUT)
+        .context(KafkaOperation::Offsets, &self.topic, None)?
+        .elements_for_topic(&self.topic)
+        .iter()
+        .filter_map(|v| match v.offset() {
+          Offset::Offset(offset) => Some((v.partition(), offset)),
+          _ => None,
+        })
+        .collect(),
+    )
+  }
+
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    self
+      .assigned_partitions()?
+      .into_iter()
+      .map(|partition| {
+        let (_, high) = consumer
+          .fetch_watermarks(&self.topic, partition, KAFKA_POSITION_QUERY_TIMEOUT)
+          .context(KafkaOperation::Offsets, &self.topic, Some(partition))?;
+        Ok((partition, high))
+      })
+      .collect()
+  }
+
+  async fn produce_with_headers(
     &self,
     record: &[u8],
+    key: Option<&str>,
     request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
   ) -> Result<(), RecordStreamError> {
     let producer = self.producer.as_ref().expect("Kafka producer not enabled");
-    let mut record: FutureRecord<str, [u8]> = FutureRecord::to(&self.topic).payload(record);
-    if let Some(threshold) = request_threshold {
-      let threshold = (threshold as u32).to_le_bytes();
-      let headers = OwnedHeaders::new_with_capacity(1).insert(Header {
-        key: THRESHOLD_HEADER_NAME,
-        value: Some(&threshold),
-      });
-      record = record.headers(headers);
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    self
+      .produce_retry
+      .send(&self.topic, || async {
+        let record = self.future_record(
+          record,
+          key,
+          request_threshold,
+          channel_name,
+          epoch,
+          extra_headers,
+        );
+        send_record(producer, record, self.send_timeout).await
+      })
+      .await
+      .map_err(|e| self.produce_error(e))
+  }
+
+  /// Enqueues all records before waiting for their delivery, so that the
+  /// records are sent in as few requests as possible.
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    let producer = self.producer.as_ref().expect("Kafka producer not enabled");
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    let mut deliveries = Vec::with_capacity(records.len());
+    for batch_record in records {
+      let record = self.future_record(
+        batch_record.data,
+        batch_record.key,
+        batch_record.request_threshold,
+        batch_record.channel_name,
+        batch_record.epoch,
+        &[],
+      );
+      match producer.send_result(record) {
+        Ok(delivery) => {
+          let started_at = Instant::now();
+          let topic = self.topic.as_str();
+          deliveries.push(async move {
+            let result = delivery.await?;
+            report_delivery(
+              topic,
+              result.as_ref().copied().map_err(|(e, _)| e),
+              started_at.elapsed(),
+            );
+            Ok::<_, Canceled>(result)
+          });
+        }
+        Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), record)) => {
+          // Waits for space in the producer queue, up to the send timeout
+          send_record(producer, record, self.send_timeout)
+            .await
+            .map_err(|e| self.produce_error(e))?;
+        }
+        Err((e, _)) => return Err(self.produce_error(e)),
+      }
+    }
+    for delivery in try_join_all(deliveries)
+      .await
+      .map_err(|_| self.produce_error(KafkaError::Canceled))?
+    {
+      delivery.map_err(|(e, _)| self.produce_error(e))?;
     }
-    let send_result = producer
-      .send(record, Duration::from_secs(KAFKA_PRODUCE_TIMEOUT_SECS))
-      .await;
-    send_result.map_err(|(e, _)| RecordStreamError::from(e))?;
     Ok(())
   }
 
@@ -303,16 +1766,43 @@ impl RecordStream for KafkaRecordStream {
     );
     let mut producer_queues = self.producer_queues.write().await;
     for _ in 0..task_count {
-      let (tx, mut rx) = unbounded_channel::<Vec<u8>>();
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
       let producer = self.producer.as_ref().unwrap().clone();
       let topic = self.topic.clone();
+      let tenant = self.tenant.clone();
+      let send_timeout = self.send_timeout;
+      let produce_retry = self.produce_retry;
+      let send_limit = self.send_limit.clone();
+      let record_headers = self.record_headers.clone();
       let handle = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-          let record: FutureRecord<str, [u8]> = FutureRecord::to(&topic).payload(&msg);
-          let send_result = producer
-            .send(record, Duration::from_secs(KAFKA_PRODUCE_TIMEOUT_SECS))
-            .await;
-          send_result.map_err(|(e, _)| RecordStreamError::from(e))?;
+        while let Some((msg, key)) = rx.recv().await {
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          produce_retry
+            .send(&topic, || async {
+              let mut record: FutureRecord<str, [u8]> = FutureRecord::to(&topic).payload(&msg);
+              if let Some(key) = key.as_ref() {
+                record = record.key(key);
+              }
+              let mut headers = OwnedHeaders::new_with_capacity(1 + record_headers.len());
+              if let Some(tenant) = tenant.as_ref() {
+                headers = headers.insert(Header {
+                  key: TENANT_HEADER_NAME,
+                  value: Some(tenant.as_bytes()),
+                });
+              }
+              for (key, value) in record_headers.iter() {
+                headers = headers.insert(Header {
+                  key,
+                  value: Some(value.as_slice()),
+                });
+              }
+              if headers.count() > 0 {
+                record = record.headers(headers);
+              }
+              send_record(&producer, record, send_timeout).await
+            })
+            .await
+            .map_err(|e| RecordStreamError::kafka(KafkaOperation::Produce, &topic, None, e))?;
         }
         Ok(())
       });
@@ -320,10 +1810,14 @@ impl RecordStream for KafkaRecordStream {
     }
   }
 
-  async fn queue_produce(&self, record: Vec<u8>) -> Result<(), RecordStreamError> {
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
     let producer_queues = self.producer_queues.read().await;
     let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
-    Ok(tx.send(record)?)
+    send_to_producer_queue(tx, &self.topic, (record, key))
   }
 
   async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
@@ -342,32 +1836,52 @@ impl RecordStream for KafkaRecordStream {
 
   async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
     let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
-    let msg = consumer.recv().await?;
-    let empty = Vec::new();
-    let payload = match msg.payload_view::<[u8]>() {
-      None => Ok(empty.as_slice()),
-      Some(s) => s.map_err(|_| RecordStreamError::Deserialize),
-    }?;
-    let mut request_threshold = None;
-    if let Some(headers) = msg.headers() {
-      let mut it = headers.iter();
-      while let Some(header) = it.next() {
-        if header.key == THRESHOLD_HEADER_NAME {
-          let value = header.value.unwrap_or_default();
-          request_threshold =
-            Some(u32::from_le_bytes(value.try_into().unwrap_or_default()) as usize);
-        }
+    loop {
+      let msg = consumer
+        .recv()
+        .await
+        .context(KafkaOperation::Consume, &self.topic, None)?;
+      let empty = Vec::new();
+      let payload = match msg.payload_view::<[u8]>() {
+        None => Ok(empty.as_slice()),
+        Some(s) => s.map_err(|_| RecordStreamError::Deserialize {
+          topic: self.topic.clone(),
+          partition: msg.partition(),
+          offset: msg.offset(),
+        }),
+      }?;
+      let header_values = RecordHeaderValues::parse(
+        msg
+          .headers()
+          .into_iter()
+          .flat_map(|v| v.iter())
+          .map(|v| (v.key, v.value.unwrap_or_default())),
+      );
+      trace!(
+        "recv partition = {} offset = {}",
+        msg.partition(),
+        msg.offset()
+      );
+      if !header_values.matches_tenant(self.tenant.as_deref()) {
+        continue;
       }
+      let submitted_at = header_values.submitted_at.or(msg.timestamp().to_millis());
+      return Ok(ConsumedRecord {
+        data: payload.to_vec(),
+        request_threshold: header_values.request_threshold,
+        channel_name: header_values.channel_name,
+        epoch: header_values.epoch,
+        client_version: header_values.client_version,
+        topic: None,
+        partition: Some(msg.partition()),
+        offset: Some(msg.offset()),
+        submitted_at: submitted_at.and_then(datetime_from_unix_millis),
+        timestamp: msg
+          .timestamp()
+          .to_millis()
+          .and_then(datetime_from_unix_millis),
+      });
     }
-    trace!(
-      "recv partition = {} offset = {}",
-      msg.partition(),
-      msg.offset()
-    );
-    Ok(ConsumedRecord {
-      data: payload.to_vec(),
-      request_threshold,
-    })
   }
 
   async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
@@ -380,41 +1894,182 @@ impl RecordStream for KafkaRecordStream {
           return Ok(());
         }
       }
-      Err(RecordStreamError::from(e))
+      Err(RecordStreamError::kafka(
+        KafkaOperation::Commit,
+        &self.topic,
+        None,
+        e,
+      ))
     } else {
       Ok(())
     }
   }
+
+  fn pause_partition(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    let mut partition_list = TopicPartitionList::with_capacity(1);
+    partition_list.add_partition(&self.topic, partition);
+    // The partition is paused before seeking, since pausing sets
+    // the fetch position to the offset after the last consumed record
+    consumer
+      .pause(&partition_list)
+      .context(KafkaOperation::Pause, &self.topic, Some(partition))?;
+    consumer
+      .seek(
+        &self.topic,
+        partition,
+        Offset::Offset(offset),
+        KAFKA_POSITION_QUERY_TIMEOUT,
+      )
+      .context(KafkaOperation::Pause, &self.topic, Some(partition))?;
+    // The offset of the record was stored when it was consumed; store the previous
+    // offset, so that the record remains uncommitted
+    consumer
+      .store_offset(&self.topic, partition, offset - 1)
+      .context(KafkaOperation::Pause, &self.topic, Some(partition))?;
+    Ok(true)
+  }
+
+  fn pause(&self) -> Result<bool, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    // Pausing sets the fetch position of each partition to the offset after
+    // the last consumed record, and discards records fetched beyond it
+    consumer
+      .assignment()
+      .and_then(|v| consumer.pause(&v))
+      .context(KafkaOperation::Pause, &self.topic, None)?;
+    Ok(true)
+  }
+
+  fn resume(&self) -> Result<(), RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    consumer
+      .assignment()
+      .and_then(|v| consumer.resume(&v))
+      .context(KafkaOperation::Resume, &self.topic, None)
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    let mut partition_list = TopicPartitionList::with_capacity(offsets.len());
+    for (partition, offset) in offsets {
+      // The committed offset is the offset of the next record to consume
+      partition_list
+        .add_partition_offset(&self.topic, *partition, Offset::Offset(offset + 1))
+        .context(KafkaOperation::Commit, &self.topic, Some(*partition))?;
+    }
+    trace!("committing offsets: {:?}", offsets);
+    consumer.commit(&partition_list, CommitMode::Sync).context(
+      KafkaOperation::Commit,
+      &self.topic,
+      None,
+    )
+  }
+
+  fn seek(&self, partition: i32, offset: i64) -> Result<bool, RecordStreamError> {
+    info!(
+      "Seeking partition {} of topic {} to offset {}",
+      partition, self.topic, offset
+    );
+    self.seek_partitions(&[(partition, Offset::Offset(offset))])?;
+    Ok(true)
+  }
+
+  fn seek_to_timestamp(&self, timestamp: OffsetDateTime) -> Result<bool, RecordStreamError> {
+    let consumer = self.consumer.as_ref().expect("Kafka consumer not enabled");
+    let offsets: Vec<(i32, Offset)> = consumer
+      .offsets_for_timestamp(
+        datetime_to_unix_millis(timestamp),
+        KAFKA_POSITION_QUERY_TIMEOUT,
+      )
+      .context(KafkaOperation::Seek, &self.topic, None)?
+      .elements_for_topic(&self.topic)
+      .iter()
+      .map(|v| (v.partition(), v.offset()))
+      .collect();
+    info!(
+      "Seeking topic {} to {}: {:?}",
+      self.topic, timestamp, offsets
+    );
+    self.seek_partitions(&offsets)?;
+    Ok(true)
+  }
+
+  async fn shutdown(&self, commit: bool) -> Result<(), RecordStreamError> {
+    if let Some(consumer) = self.consumer.as_ref() {
+      if commit {
+        self.commit_last_consume().await?;
+      }
+      if self.manual_assignment {
+        consumer
+          .unassign()
+          .context(KafkaOperation::Close, &self.topic, None)?;
+      } else {
+        info!("Leaving consumer group of topic {}", self.topic);
+        consumer.unsubscribe();
+        // The partitions are revoked while polling the consumer. Records received
+        // in the meantime are not processed, and are consumed again by the next
+        // member of the group.
+        let deadline = Instant::now() + KAFKA_LEAVE_GROUP_TIMEOUT;
+        while Instant::now() < deadline
+          && consumer
+            .assignment()
+            .context(KafkaOperation::Close, &self.topic, None)?
+            .count()
+            > 0
+        {
+          let _ = timeout(KAFKA_LEAVE_GROUP_POLL_INTERVAL, consumer.recv()).await;
+        }
+      }
+    }
+    if let Some(producer) = self.producer.as_ref() {
+      self.join_produce_queues().await?;
+      let producer = producer.clone();
+      let send_timeout = self.send_timeout;
+      tokio::task::spawn_blocking(move || producer.flush(send_timeout))
+        .await?
+        .context(KafkaOperation::Produce, &self.topic, None)?;
+    }
+    Ok(())
+  }
 }
 
+#[cfg(test)]
 #[derive(Default)]
 pub struct TestRecordStream {
-  pub records_to_consume: Mutex<Vec<Vec<u8>>>,
+  pub records_to_consume: Mutex<Vec<ConsumedRecord>>,
   pub records_produced: Mutex<Vec<Vec<u8>>>,
 }
 
+#[cfg(test)]
 #[async_trait]
 impl RecordStream for TestRecordStream {
-  fn init_producer_transactions(&self) -> Result<(), RecordStreamError> {
-    Ok(())
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(true)
   }
 
-  fn begin_producer_transaction(&self) -> Result<(), RecordStreamError> {
-    Ok(())
+  // Test streams do not track consumer positions
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(Vec::new())
   }
 
-  fn commit_producer_transaction(&self) -> Result<(), RecordStreamError> {
-    Ok(())
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    Ok(HashMap::new())
   }
 
-  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
-    Ok(true)
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    Ok(HashMap::new())
   }
 
-  async fn produce(
+  async fn produce_with_headers(
     &self,
     record: &[u8],
+    _key: Option<&str>,
     _request_threshold: Option<usize>,
+    _channel_name: Option<&str>,
+    _epoch: Option<u8>,
+    _extra_headers: &[(&str, Vec<u8>)],
   ) -> Result<(), RecordStreamError> {
     self.records_produced.lock().await.push(record.to_vec());
     Ok(())
@@ -422,8 +2077,12 @@ impl RecordStream for TestRecordStream {
 
   async fn init_producer_queues(&self) {}
 
-  async fn queue_produce(&self, record: Vec<u8>) -> Result<(), RecordStreamError> {
-    self.produce(&record, None).await
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    _key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    self.produce(&record, None, None, None, None).await
   }
 
   async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
@@ -437,13 +2096,148 @@ impl RecordStream for TestRecordStream {
       sleep(Duration::from_secs(90)).await;
       return Err(RecordStreamError::TestConsumeTimeout);
     }
-    Ok(ConsumedRecord {
-      data: records_to_consume.remove(0),
-      request_threshold: None,
-    })
+    Ok(records_to_consume.remove(0))
   }
 
   async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
     Ok(())
   }
+
+  async fn commit_offsets(&self, _offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn select_registered_backend() {
+    let factory = RecordStreamFactory::new("test").with_backend(
+      "test",
+      Arc::new(|config, _| {
+        Arc::new(TestRecordStream {
+          records_to_consume: Mutex::new(vec![ConsumedRecord {
+            data: config.topic.into_bytes(),
+            ..Default::default()
+          }]),
+          ..Default::default()
+        })
+      }),
+    );
+    let stream = factory.create(
+      KafkaRecordStreamConfig {
+        component: KafkaComponent::Aggregator,
+        enable_producer: false,
+        enable_consumer: true,
+        topic: "p3a-star-enc".to_string(),
+        use_output_group_id: false,
+        group_id_suffix: None,
+        tenant: None,
+      },
+      RecordStreamOptions::default(),
+    );
+    assert_eq!(stream.consume().await.unwrap().data, b"p3a-star-enc");
+    assert_eq!(
+      factory.consumer_count(KafkaComponent::Aggregator, "p3a-star-enc", None, 3),
+      3
+    );
+  }
+
+  #[tokio::test]
+  async fn default_consume_batch() {
+    let stream = TestRecordStream {
+      records_to_consume: Mutex::new(
+        (1..=3)
+          .map(|i| ConsumedRecord {
+            data: vec![i],
+            ..Default::default()
+          })
+          .collect(),
+      ),
+      ..Default::default()
+    };
+    let max_wait = Duration::from_millis(10);
+    let data = |records: Vec<ConsumedRecord>| -> Vec<Vec<u8>> {
+      records.into_iter().map(|v| v.data).collect()
+    };
+    assert!(stream.consume_batch(0, max_wait).await.unwrap().is_empty());
+    assert_eq!(
+      data(stream.consume_batch(2, max_wait).await.unwrap()),
+      vec![vec![1], vec![2]]
+    );
+    assert_eq!(
+      data(stream.consume_batch(5, max_wait).await.unwrap()),
+      vec![vec![3]]
+    );
+    assert!(stream.consume_batch(5, max_wait).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn default_produce_batch() {
+    let stream = TestRecordStream::default();
+    let records: Vec<_> = [b"a", b"b", b"c"]
+      .iter()
+      .map(|data| BatchRecord {
+        data: data.as_slice(),
+        epoch: Some(2),
+        ..Default::default()
+      })
+      .collect();
+    stream.produce_batch(&records).await.unwrap();
+    assert_eq!(
+      *stream.records_produced.lock().await,
+      vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    );
+  }
+
+  #[test]
+  fn metadata_headers() {
+    let record = ConsumedRecord {
+      submitted_at: datetime_from_unix_millis(1700000000123),
+      client_version: Some("3".to_string()),
+      epoch: Some(2),
+      ..Default::default()
+    };
+    let headers = record.metadata_headers();
+    let values = RecordHeaderValues::parse(headers.iter().map(|(k, v)| (*k, v.as_slice())));
+    assert_eq!(
+      values,
+      RecordHeaderValues {
+        submitted_at: Some(1700000000123),
+        client_version: Some("3".to_string()),
+        ..Default::default()
+      }
+    );
+  }
+
+  #[test]
+  fn kafka_error_context() {
+    let result: KafkaResult<()> = Err(KafkaError::Canceled);
+    let e = result
+      .context(KafkaOperation::Commit, "p3a-star-enc", Some(3))
+      .unwrap_err();
+    assert_eq!(e.category(), "kafka_commit");
+    assert!(e.to_string().starts_with(
+      "Record stream error: Kafka commit failed for topic p3a-star-enc partition 3: "
+    ));
+  }
+
+  #[test]
+  #[should_panic(expected = "Unknown record stream backend 'missing'")]
+  fn unknown_backend() {
+    RecordStreamFactory::new("missing").create(
+      KafkaRecordStreamConfig {
+        component: KafkaComponent::Aggregator,
+        enable_producer: true,
+        enable_consumer: false,
+        topic: "p3a-star-out".to_string(),
+        use_output_group_id: true,
+        group_id_suffix: None,
+        tenant: None,
+      },
+      RecordStreamOptions::default(),
+    );
+  }
 }
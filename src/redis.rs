@@ -0,0 +1,143 @@
+//! Redis client, used for server state that must be shared between replicas.
+//! Commands are sent via a pool of multiplexed `redis` connections, which are
+//! established on first use and re-established after any connection error.
+//!
+//! The Redis URL is formatted as `redis://[:<password>@]<host>:<port>`.
+
+use crate::util::parse_env_var;
+use derive_more::{Display, Error, From};
+use rand::{thread_rng, Rng};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{AsyncCommands, Client, ExistenceCheck, SetExpiry, SetOptions, Value};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+const SERVER_REDIS_URL_ENV_KEY: &str = "SERVER_REDIS_URL";
+const SERVER_REDIS_POOL_SIZE_ENV_KEY: &str = "SERVER_REDIS_POOL_SIZE";
+const DEFAULT_SERVER_REDIS_POOL_SIZE: &str = "8";
+
+#[derive(Error, From, Display, Debug)]
+pub enum RedisError {
+  #[display(fmt = "Redis error: {}", _0)]
+  Client(redis::RedisError),
+  #[display(fmt = "Redis protocol error")]
+  Protocol,
+}
+
+pub struct RedisClient {
+  client: Client,
+  conns: Vec<OnceCell<ConnectionManager>>,
+}
+
+impl RedisClient {
+  pub fn new(url: &str, pool_size: usize) -> Self {
+    Self {
+      client: Client::open(url).unwrap_or_else(|e| panic!("Invalid Redis URL: {}", e)),
+      conns: (0..pool_size.max(1)).map(|_| OnceCell::new()).collect(),
+    }
+  }
+
+  /// Returns a connection of the pool, which is established on first use.
+  pub async fn connection(&self) -> Result<ConnectionManager, RedisError> {
+    let index = thread_rng().gen_range(0..self.conns.len());
+    let conn = self.conns[index]
+      .get_or_try_init(|| {
+        // Failed connection attempts are returned as errors instead of being retried,
+        // so that callers may fall back without waiting
+        let config = ConnectionManagerConfig::new().set_number_of_retries(0);
+        self.client.get_connection_manager_with_config(config)
+      })
+      .await?;
+    Ok(conn.clone())
+  }
+
+  pub async fn ping(&self) -> Result<(), RedisError> {
+    redis::cmd("PING")
+      .query_async::<()>(&mut self.connection().await?)
+      .await?;
+    Ok(())
+  }
+
+  /// Sets the key if it does not exist, with an expiry. Returns true if the key was set.
+  pub async fn set_nx_px(&self, key: &str, value: &[u8], ttl_ms: u64) -> Result<bool, RedisError> {
+    let options = SetOptions::default()
+      .conditional_set(ExistenceCheck::NX)
+      .with_expiration(SetExpiry::PX(ttl_ms));
+    let reply: Value = self
+      .connection()
+      .await?
+      .set_options(key, value, options)
+      .await?;
+    Ok(reply != Value::Nil)
+  }
+
+  /// Sets the key, with an expiry.
+  pub async fn set_px(&self, key: &str, value: &[u8], ttl_ms: u64) -> Result<(), RedisError> {
+    self
+      .connection()
+      .await?
+      .pset_ex::<_, _, ()>(key, value, ttl_ms)
+      .await?;
+    Ok(())
+  }
+
+  /// Increments the key and sets its expiry, atomically. Returns the incremented value.
+  pub async fn incr_px(&self, key: &str, ttl_ms: u64) -> Result<u64, RedisError> {
+    let (value,): (u64,) = redis::pipe()
+      .atomic()
+      .incr(key, 1)
+      .pexpire(key, ttl_ms as i64)
+      .ignore()
+      .query_async(&mut self.connection().await?)
+      .await?;
+    Ok(value)
+  }
+
+  pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+    Ok(self.connection().await?.get(key).await?)
+  }
+
+  pub async fn del(&self, key: &str) -> Result<(), RedisError> {
+    self.connection().await?.del::<_, ()>(key).await?;
+    Ok(())
+  }
+}
+
+/// Creates the Redis client for state shared between server replicas, if configured.
+/// Server caches fall back to in-memory state if Redis is not configured.
+pub fn shared_redis_client_from_env() -> Option<Arc<RedisClient>> {
+  let url = env::var(SERVER_REDIS_URL_ENV_KEY).ok()?;
+  let pool_size = parse_env_var::<usize>(
+    SERVER_REDIS_POOL_SIZE_ENV_KEY,
+    DEFAULT_SERVER_REDIS_POOL_SIZE,
+  );
+  Some(Arc::new(RedisClient::new(&url, pool_size)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_url() {
+    let client = RedisClient::new("redis://:secret@redis.internal:6380/", 0);
+    let info = client.client.get_connection_info();
+    assert_eq!(info.addr.to_string(), "redis.internal:6380");
+    assert_eq!(info.redis.password.as_deref(), Some("secret"));
+    assert_eq!(client.conns.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn connection_errors() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let client = RedisClient::new(&format!("redis://{}", addr), 1);
+    assert!(matches!(
+      client.get("key").await,
+      Err(RedisError::Client(e)) if e.is_io_error()
+    ));
+  }
+}
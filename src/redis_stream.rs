@@ -0,0 +1,725 @@
+//! Record stream backed by Redis Streams, selected via `RECORD_STREAM_BACKEND=redis`, for
+//! lightweight self-hosted deployments. The topic of the stream config is used as the stream
+//! key. Records are appended via `XADD`, with the record data in the `data` field, and each
+//! record header in a field prefixed with `header:`.
+//!
+//! Records are consumed via `XREADGROUP` in the Redis consumer group of the stream config,
+//! which is created on first use, starting at the beginning of the stream. Stream entry ids
+//! do not fit in record offsets, so consumed records are assigned local offsets, which are
+//! only valid for the lifetime of the stream, and each consumer reports its records in its
+//! own partition. Committing consumption acks the consumed entries up to the committed offset
+//! via `XACK`. Entries that are delivered but not acked remain pending in the group. Pending
+//! entries that have been idle for `REDIS_STREAM_CLAIM_IDLE_MS`, i.e. those of a consumer that
+//! crashed, are claimed via `XAUTOCLAIM` and consumed again; the idle time of the entries held
+//! by a consumer is reset until they are committed, so that they are not claimed by others.
+
+use crate::record_stream::{
+  acquire_send_permit, consumer_group_id, datetime_from_unix_millis, send_to_producer_queue,
+  ConsumedRecord, KafkaRecordStreamConfig, ProducerQueue, ProducerQueueItem, RecordHeaderValues,
+  RecordStream, RecordStreamError, RecordStreamOptions,
+};
+use crate::redis::{RedisClient, RedisError};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use rand::{random, seq::SliceRandom, thread_rng};
+use redis::streams::{
+  StreamAutoClaimOptions, StreamAutoClaimReply, StreamClaimOptions, StreamId, StreamMaxlen,
+  StreamReadOptions, StreamReadReply,
+};
+use redis::{AsyncCommands, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{Mutex as AsyncMutex, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+const REDIS_STREAM_URL_ENV_KEY: &str = "REDIS_STREAM_URL";
+const REDIS_STREAM_POOL_SIZE_ENV_KEY: &str = "REDIS_STREAM_POOL_SIZE";
+const DEFAULT_REDIS_STREAM_POOL_SIZE: &str = "4";
+const REDIS_STREAM_READ_COUNT_ENV_KEY: &str = "REDIS_STREAM_READ_COUNT";
+const DEFAULT_REDIS_STREAM_READ_COUNT: &str = "100";
+const REDIS_STREAM_BLOCK_MS_ENV_KEY: &str = "REDIS_STREAM_BLOCK_MS";
+const DEFAULT_REDIS_STREAM_BLOCK_MS: &str = "5000";
+const REDIS_STREAM_CLAIM_IDLE_MS_ENV_KEY: &str = "REDIS_STREAM_CLAIM_IDLE_MS";
+const DEFAULT_REDIS_STREAM_CLAIM_IDLE_MS: &str = "60000";
+const REDIS_STREAM_MAXLEN_ENV_KEY: &str = "REDIS_STREAM_MAXLEN";
+const DEFAULT_REDIS_STREAM_MAXLEN: &str = "0";
+const REDIS_STREAM_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY: &str = "REDIS_STREAM_PRODUCE_QUEUE_TASK_COUNT";
+const DEFAULT_REDIS_STREAM_PRODUCE_QUEUE_TASK_COUNT: &str = "16";
+const HOSTNAME_ENV_KEY: &str = "HOSTNAME";
+
+const DATA_FIELD: &str = "data";
+const HEADER_FIELD_PREFIX: &str = "header:";
+const BUSY_GROUP_ERROR_CODE: &str = "BUSYGROUP";
+/// Limit of entry ids per `XACK` or `XCLAIM` command
+const MAX_IDS_PER_COMMAND: usize = 1000;
+
+static NEXT_CONSUMER_PARTITION: AtomicI32 = AtomicI32::new(0);
+
+/// Returns the fields of an entry with the record data and headers.
+fn entry_fields(data: &[u8], headers: &[(String, Vec<u8>)]) -> Vec<(String, Vec<u8>)> {
+  let mut fields = vec![(DATA_FIELD.to_string(), data.to_vec())];
+  for (key, value) in headers {
+    fields.push((format!("{}{}", HEADER_FIELD_PREFIX, key), value.clone()));
+  }
+  fields
+}
+
+/// Returns the Unix time in milliseconds of the entry id, i.e. `1682946000123-0`.
+fn entry_id_millis(id: &str) -> Option<i64> {
+  id.split('-').next()?.parse().ok()
+}
+
+struct ConsumerState {
+  initialized: bool,
+  buffer: VecDeque<ConsumedRecord>,
+  /// Ids of delivered entries that have not been acked, by local offset
+  uncommitted: BTreeMap<i64, String>,
+  uncommitted_offsets: HashMap<String, i64>,
+  next_offset: i64,
+  consumed_offset: Option<i64>,
+  committed_offset: Option<i64>,
+  /// Start id of the next `XAUTOCLAIM`
+  claim_cursor: String,
+  last_claim: Option<Instant>,
+}
+
+impl Default for ConsumerState {
+  fn default() -> Self {
+    Self {
+      initialized: false,
+      buffer: VecDeque::new(),
+      uncommitted: BTreeMap::new(),
+      uncommitted_offsets: HashMap::new(),
+      next_offset: 0,
+      consumed_offset: None,
+      committed_offset: None,
+      claim_cursor: "0-0".to_string(),
+      last_claim: None,
+    }
+  }
+}
+
+impl ConsumerState {
+  fn buffer_entries(&mut self, entries: Vec<StreamId>, partition: i32, tenant: Option<&str>) {
+    for entry in entries {
+      // Entries held by this consumer may be claimed by it again, if their idle
+      // time was not reset in time
+      if self.uncommitted_offsets.contains_key(&entry.id) {
+        continue;
+      }
+      let offset = self.next_offset;
+      self.next_offset += 1;
+      self.uncommitted_offsets.insert(entry.id.clone(), offset);
+      self.uncommitted.insert(offset, entry.id.clone());
+
+      // Entries deleted while pending are claimed without fields, and are
+      // acked once the following records are committed
+      if entry.is_empty() {
+        continue;
+      }
+      let headers = entry
+        .map
+        .iter()
+        .filter_map(|(field, value)| match value {
+          Value::BulkString(value) => Some((field.strip_prefix(HEADER_FIELD_PREFIX)?, &value[..])),
+          _ => None,
+        })
+        .collect::<Vec<_>>();
+      let Some(data) = entry.get::<Vec<u8>>(DATA_FIELD) else {
+        warn!("Skipping Redis stream entry {} without data", entry.id);
+        continue;
+      };
+      let header_values = RecordHeaderValues::parse(headers);
+      if !header_values.matches_tenant(tenant) {
+        continue;
+      }
+      let added_at = entry_id_millis(&entry.id);
+      self.buffer.push_back(ConsumedRecord {
+        data,
+        request_threshold: header_values.request_threshold,
+        channel_name: header_values.channel_name,
+        epoch: header_values.epoch,
+        client_version: header_values.client_version,
+        topic: None,
+        partition: Some(partition),
+        offset: Some(offset),
+        submitted_at: header_values
+          .submitted_at
+          .or(added_at)
+          .and_then(datetime_from_unix_millis),
+        timestamp: added_at.and_then(datetime_from_unix_millis),
+      });
+    }
+  }
+
+  /// Returns the ids of the uncommitted entries up to and including the offset.
+  fn entry_ids(&self, offset: i64) -> Vec<String> {
+    self
+      .uncommitted
+      .range(..=offset)
+      .map(|(_, id)| id.clone())
+      .collect()
+  }
+
+  fn mark_committed(&mut self, offset: i64) {
+    let remaining = self.uncommitted.split_off(&(offset + 1));
+    for (_, id) in std::mem::replace(&mut self.uncommitted, remaining) {
+      self.uncommitted_offsets.remove(&id);
+    }
+    self.committed_offset = Some(self.committed_offset.unwrap_or_default().max(offset + 1));
+  }
+}
+
+type ReadTask = JoinHandle<Result<Vec<StreamId>, RedisError>>;
+
+pub struct RedisRecordStream {
+  client: Arc<RedisClient>,
+  /// Used for blocking reads, so that other commands do not wait for them
+  read_client: Arc<RedisClient>,
+  stream_key: String,
+  group_id: String,
+  consumer_name: String,
+  partition: i32,
+  tenant: Option<String>,
+  enable_producer: bool,
+  consumer_state: Arc<Mutex<ConsumerState>>,
+  /// Also prevents concurrent consumption
+  read: AsyncMutex<Option<ReadTask>>,
+  idle_reset: Mutex<Option<JoinHandle<()>>>,
+  read_count: usize,
+  block: Duration,
+  claim_idle: Duration,
+  maxlen: usize,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  send_limit: Option<Arc<Semaphore>>,
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+impl RedisRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let url = env::var(REDIS_STREAM_URL_ENV_KEY)
+      .unwrap_or_else(|_| panic!("{} should be set", REDIS_STREAM_URL_ENV_KEY));
+    let pool_size = parse_env_var::<usize>(
+      REDIS_STREAM_POOL_SIZE_ENV_KEY,
+      DEFAULT_REDIS_STREAM_POOL_SIZE,
+    );
+    let tenant = stream_config.tenant.as_deref();
+    let partition = NEXT_CONSUMER_PARTITION.fetch_add(1, Ordering::Relaxed);
+    let host = env::var(HOSTNAME_ENV_KEY).unwrap_or_else(|_| format!("{:08x}", random::<u32>()));
+    Self {
+      client: Arc::new(RedisClient::new(&url, pool_size)),
+      read_client: Arc::new(RedisClient::new(&url, 1)),
+      stream_key: tenant_scoped_name(&stream_config.topic, tenant),
+      group_id: consumer_group_id(&stream_config),
+      consumer_name: format!("{}-{}", host, partition),
+      partition,
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      consumer_state: Arc::new(Mutex::new(ConsumerState::default())),
+      read: AsyncMutex::new(None),
+      idle_reset: Mutex::new(None),
+      read_count: parse_env_var(
+        REDIS_STREAM_READ_COUNT_ENV_KEY,
+        DEFAULT_REDIS_STREAM_READ_COUNT,
+      ),
+      block: Duration::from_millis(parse_env_var(
+        REDIS_STREAM_BLOCK_MS_ENV_KEY,
+        DEFAULT_REDIS_STREAM_BLOCK_MS,
+      )),
+      claim_idle: Duration::from_millis(parse_env_var(
+        REDIS_STREAM_CLAIM_IDLE_MS_ENV_KEY,
+        DEFAULT_REDIS_STREAM_CLAIM_IDLE_MS,
+      )),
+      maxlen: parse_env_var(REDIS_STREAM_MAXLEN_ENV_KEY, DEFAULT_REDIS_STREAM_MAXLEN),
+      producer_queues: RwLock::new(Vec::new()),
+      send_limit: options.send_limit,
+      record_headers: Arc::new(options.record_headers),
+    }
+  }
+
+  fn headers(
+    &self,
+    mut header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Vec<(String, Vec<u8>)> {
+    header_values.tenant = self.tenant.clone();
+    header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .collect()
+  }
+
+  /// Creates the consumer group if it does not exist, and starts resetting the idle
+  /// time of the uncommitted entries.
+  async fn init_consumer(&self) -> Result<(), RedisError> {
+    let result = self
+      .client
+      .connection()
+      .await?
+      .xgroup_create_mkstream::<_, _, _, ()>(&self.stream_key, &self.group_id, "0")
+      .await;
+    match result {
+      Err(e) if e.code() == Some(BUSY_GROUP_ERROR_CODE) => (),
+      result => {
+        result?;
+        info!(
+          "Redis: created consumer group {} of stream {}",
+          self.group_id, self.stream_key
+        );
+      }
+    }
+    let client = self.client.clone();
+    let stream_key = self.stream_key.clone();
+    let group_id = self.group_id.clone();
+    let consumer_name = self.consumer_name.clone();
+    let consumer_state = Arc::downgrade(&self.consumer_state);
+    let claim_idle = self.claim_idle;
+    let handle = tokio::spawn(async move {
+      loop {
+        sleep(claim_idle / 2).await;
+        let Some(consumer_state) = consumer_state.upgrade() else {
+          return;
+        };
+        let entry_ids = consumer_state.lock().unwrap().entry_ids(i64::MAX);
+        for entry_ids in entry_ids.chunks(MAX_IDS_PER_COMMAND) {
+          // Claiming the entries again resets their idle time
+          let result = async {
+            client
+              .connection()
+              .await?
+              .xclaim_options::<_, _, _, _, _, ()>(
+                &stream_key,
+                &group_id,
+                &consumer_name,
+                0,
+                entry_ids,
+                StreamClaimOptions::default().with_justid(),
+              )
+              .await?;
+            Ok::<_, RedisError>(())
+          }
+          .await;
+          if let Err(e) = result {
+            warn!("Failed to reset idle time of Redis stream entries: {}", e);
+          }
+        }
+      }
+    });
+    *self.idle_reset.lock().unwrap() = Some(handle);
+    let mut state = self.consumer_state.lock().unwrap();
+    state.initialized = true;
+    state.committed_offset = Some(0);
+    Ok(())
+  }
+
+  /// Claims the entries that have been pending for longer than the claim idle time,
+  /// if the last claim was at least one idle time ago.
+  async fn claim_idle_entries(&self) -> Result<Vec<StreamId>, RedisError> {
+    let cursor = {
+      let mut state = self.consumer_state.lock().unwrap();
+      if matches!(state.last_claim, Some(v) if v.elapsed() < self.claim_idle) {
+        return Ok(Vec::new());
+      }
+      state.last_claim = Some(Instant::now());
+      state.claim_cursor.clone()
+    };
+    let reply: StreamAutoClaimReply = self
+      .client
+      .connection()
+      .await?
+      .xautoclaim_options(
+        &self.stream_key,
+        &self.group_id,
+        &self.consumer_name,
+        self.claim_idle.as_millis() as u64,
+        cursor,
+        StreamAutoClaimOptions::default().count(self.read_count),
+      )
+      .await?;
+    if !reply.claimed.is_empty() {
+      info!(
+        "Redis: claimed {} idle entries of stream {}",
+        reply.claimed.len(),
+        self.stream_key
+      );
+    }
+    self.consumer_state.lock().unwrap().claim_cursor = reply.next_stream_id;
+    Ok(reply.claimed)
+  }
+
+  /// Reads new entries in a separate task, so that delivered entries are not lost
+  /// if consumption is cancelled.
+  fn spawn_read(&self) -> ReadTask {
+    let client = self.read_client.clone();
+    let stream_key = self.stream_key.clone();
+    let options = StreamReadOptions::default()
+      .group(&self.group_id, &self.consumer_name)
+      .count(self.read_count)
+      .block(self.block.as_millis() as usize);
+    tokio::spawn(async move {
+      // The reply is nil if no entries were delivered before the block timeout
+      let reply: Option<StreamReadReply> = client
+        .connection()
+        .await?
+        .xread_options(&[stream_key], &[">"], &options)
+        .await?;
+      Ok(
+        reply
+          .into_iter()
+          .flat_map(|v| v.keys)
+          .flat_map(|v| v.ids)
+          .collect(),
+      )
+    })
+  }
+
+  async fn add_entry(
+    client: &RedisClient,
+    stream_key: &str,
+    maxlen: usize,
+    fields: Vec<(String, Vec<u8>)>,
+  ) -> Result<(), RedisError> {
+    let mut conn = client.connection().await?;
+    match maxlen > 0 {
+      true => {
+        conn
+          .xadd_maxlen::<_, _, _, _, ()>(stream_key, StreamMaxlen::Approx(maxlen), "*", &fields)
+          .await?
+      }
+      false => {
+        conn
+          .xadd::<_, _, _, _, ()>(stream_key, "*", &fields)
+          .await?
+      }
+    }
+    Ok(())
+  }
+
+  fn pop_record(&self) -> Option<ConsumedRecord> {
+    let mut state = self.consumer_state.lock().unwrap();
+    let record = state.buffer.pop_front()?;
+    state.consumed_offset = record.offset;
+    Some(record)
+  }
+
+  /// Acks the delivered entries up to and including the offset.
+  async fn ack(&self, offset: i64) -> Result<(), RedisError> {
+    let entry_ids = self.consumer_state.lock().unwrap().entry_ids(offset);
+    for entry_ids in entry_ids.chunks(MAX_IDS_PER_COMMAND) {
+      self
+        .client
+        .connection()
+        .await?
+        .xack::<_, _, _, ()>(&self.stream_key, &self.group_id, entry_ids)
+        .await?;
+    }
+    self.consumer_state.lock().unwrap().mark_committed(offset);
+    Ok(())
+  }
+}
+
+impl Drop for RedisRecordStream {
+  fn drop(&mut self) {
+    if let Some(handle) = self.idle_reset.get_mut().unwrap().take() {
+      handle.abort();
+    }
+    if let Some(task) = self.read.get_mut().take() {
+      task.abort();
+    }
+  }
+}
+
+#[async_trait]
+impl RecordStream for RedisRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.client.ping().await?;
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(self.consumer_state.lock().unwrap().initialized)
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(match self.consumer_state.lock().unwrap().initialized {
+      true => vec![self.partition],
+      false => Vec::new(),
+    })
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .committed_offset
+        .map(|v| (self.partition, v))
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  /// Only includes the entries that have been delivered to the consumer.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(HashMap::from([(self.partition, state.next_offset)]))
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    _key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "Redis stream producer not enabled");
+    let headers = self.headers(
+      RecordHeaderValues {
+        request_threshold,
+        channel_name: channel_name.map(|v| v.to_string()),
+        epoch,
+        ..Default::default()
+      },
+      extra_headers,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    Self::add_entry(
+      &self.client,
+      &self.stream_key,
+      self.maxlen,
+      entry_fields(record, &headers),
+    )
+    .await?;
+    Ok(())
+  }
+
+  async fn init_producer_queues(&self) {
+    assert!(self.enable_producer, "Redis stream producer not enabled");
+    let task_count = parse_env_var::<usize>(
+      REDIS_STREAM_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY,
+      DEFAULT_REDIS_STREAM_PRODUCE_QUEUE_TASK_COUNT,
+    );
+    let headers = Arc::new(self.headers(RecordHeaderValues::default(), &[]));
+    let mut producer_queues = self.producer_queues.write().await;
+    for _ in 0..task_count {
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
+      let client = self.client.clone();
+      let stream_key = self.stream_key.clone();
+      let maxlen = self.maxlen;
+      let send_limit = self.send_limit.clone();
+      let headers = headers.clone();
+      let handle = tokio::spawn(async move {
+        while let Some((data, _)) = rx.recv().await {
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          Self::add_entry(&client, &stream_key, maxlen, entry_fields(&data, &headers)).await?;
+        }
+        Ok(())
+      });
+      producer_queues.push((handle, tx));
+    }
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    let producer_queues = self.producer_queues.read().await;
+    let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
+    send_to_producer_queue(tx, &self.stream_key, (record, key))
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    let mut producer_queues = self.producer_queues.write().await;
+    try_join_all(
+      producer_queues
+        .drain(..)
+        .map(|(handle, _)| handle)
+        .collect::<Vec<_>>(),
+    )
+    .await?
+    .into_iter()
+    .collect::<Result<Vec<()>, RecordStreamError>>()?;
+    Ok(())
+  }
+
+  /// Cancel safe, since the read task is kept until the next call, and claimed
+  /// entries would be claimed again once idle.
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    let mut read = self.read.lock().await;
+    loop {
+      if let Some(record) = self.pop_record() {
+        return Ok(record);
+      }
+      let initialized = self.consumer_state.lock().unwrap().initialized;
+      if !initialized {
+        self.init_consumer().await?;
+      }
+      let mut entries = match read.is_none() {
+        true => self.claim_idle_entries().await?,
+        false => Vec::new(),
+      };
+      if entries.is_empty() {
+        let task = read.get_or_insert_with(|| self.spawn_read());
+        let result = task.await;
+        *read = None;
+        entries = result??;
+      }
+      self.consumer_state.lock().unwrap().buffer_entries(
+        entries,
+        self.partition,
+        self.tenant.as_deref(),
+      );
+    }
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let consumed_offset = self.consumer_state.lock().unwrap().consumed_offset;
+    if let Some(offset) = consumed_offset {
+      self.ack(offset).await?;
+    }
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    for (partition, offset) in offsets {
+      if *partition == self.partition {
+        self.ack(*offset).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::datetime_to_unix_millis;
+
+  fn bulk_value(value: &[u8]) -> Value {
+    Value::BulkString(value.to_vec())
+  }
+
+  fn entry_value(id: &str, fields: Option<&[(String, Vec<u8>)]>) -> Value {
+    Value::Array(vec![
+      bulk_value(id.as_bytes()),
+      match fields {
+        Some(fields) => Value::Array(
+          fields
+            .iter()
+            .flat_map(|(k, v)| [bulk_value(k.as_bytes()), bulk_value(v)])
+            .collect(),
+        ),
+        None => Value::Nil,
+      },
+    ])
+  }
+
+  fn entry(id: &str, fields: Option<&[(String, Vec<u8>)]>) -> StreamId {
+    StreamId {
+      id: id.to_string(),
+      map: fields
+        .unwrap_or_default()
+        .iter()
+        .map(|(k, v)| (k.clone(), bulk_value(v)))
+        .collect(),
+    }
+  }
+
+  fn fields(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let headers: Vec<_> = RecordHeaderValues {
+      channel_name: Some("typical".to_string()),
+      epoch: Some(3),
+      ..Default::default()
+    }
+    .headers()
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    entry_fields(data, &headers)
+  }
+
+  #[test]
+  fn parse_replies() {
+    let reply: Option<StreamReadReply> = redis::from_redis_value(&Value::Nil).unwrap();
+    assert!(reply.is_none());
+    let reply = Value::Array(vec![Value::Array(vec![
+      bulk_value(b"p3a-star-enc"),
+      Value::Array(vec![entry_value(
+        "1682946000123-0",
+        Some(&fields(b"first")),
+      )]),
+    ])]);
+    let reply: StreamReadReply = redis::from_redis_value(&reply).unwrap();
+    let entries = &reply.keys[0].ids;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].id, "1682946000123-0");
+    assert_eq!(
+      entries[0].get::<Vec<u8>>(DATA_FIELD).as_deref(),
+      Some(b"first".as_slice())
+    );
+
+    // Entries deleted while pending are claimed without fields
+    let reply = Value::Array(vec![
+      bulk_value(b"1682946000200-0"),
+      Value::Array(vec![entry_value("1682946000150-0", None)]),
+      Value::Array(Vec::new()),
+    ]);
+    let reply: StreamAutoClaimReply = redis::from_redis_value(&reply).unwrap();
+    assert_eq!(reply.next_stream_id, "1682946000200-0");
+    assert!(reply.claimed[0].is_empty());
+  }
+
+  #[test]
+  fn buffer_and_commit_entries() {
+    let mut state = ConsumerState::default();
+    state.buffer_entries(
+      vec![
+        entry("1682946000123-0", Some(&fields(b"first"))),
+        entry("1682946000123-1", None),
+        entry("1682946000124-0", Some(&fields(b"third"))),
+      ],
+      5,
+      None,
+    );
+    // Entries that are already held are skipped
+    state.buffer_entries(
+      vec![entry("1682946000124-0", Some(&fields(b"third")))],
+      5,
+      None,
+    );
+    assert_eq!(state.next_offset, 3);
+    assert_eq!(state.buffer.len(), 2);
+
+    let record = state.buffer.pop_front().unwrap();
+    assert_eq!(record.data, b"first");
+    assert_eq!(record.partition, Some(5));
+    assert_eq!(record.offset, Some(0));
+    assert_eq!(record.channel_name.as_deref(), Some("typical"));
+    assert_eq!(record.epoch, Some(3));
+    assert_eq!(
+      record.timestamp.map(datetime_to_unix_millis),
+      Some(1_682_946_000_123)
+    );
+    assert_eq!(state.buffer.pop_front().unwrap().offset, Some(2));
+
+    assert_eq!(
+      state.entry_ids(1),
+      vec!["1682946000123-0", "1682946000123-1"]
+    );
+    state.mark_committed(1);
+    assert_eq!(state.committed_offset, Some(2));
+    assert_eq!(state.entry_ids(i64::MAX), vec!["1682946000124-0"]);
+  }
+}
@@ -0,0 +1,304 @@
+//! Enforcement of per-channel data retention. `RETENTION_DAYS` maps each channel to
+//! the amount of days its data may be retained, i.e. `typical=90,express=30`. Running
+//! the processor with `--enforce-retention` purges the data of each listed channel that
+//! is older than its retention period:
+//!
+//! - Lake objects of the channel, including reprocessed output. Objects in date
+//!   partitions are dated by their partition, other objects by their last modification.
+//! - Expired rows of the channel database, see `purge_expired_rows`.
+//! - Records of the dead letter topic of the channel. Kafka does not support deleting
+//!   individual records, so the `retention.ms` config of the topic is set to the
+//!   retention period instead.
+//!
+//! The run is reported as JSON to stdout. Purges are recorded in an audit report stored
+//! in the lake under `retention-audits/`, which is not subject to retention itself.
+//! With `--retention-dry-run`, the expired data is only reported.
+
+use crate::aggregator::REPROCESS_OUTPUT_PREFIX;
+use crate::channel::get_data_channel_map_from_env;
+use crate::lake::{DataLake, DataLakeError, LakeObject, RETENTION_AUDIT_PREFIX};
+use crate::models::{purge_expired_rows, DBConnectionType, DBPool, PgStoreError};
+use crate::record_stream::{
+  get_data_channel_dlq_topic_from_env, set_topic_retention, KafkaComponent, RecordStreamError,
+};
+use derive_more::{Display, Error, From};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Iso8601;
+use time::{Date, Duration, OffsetDateTime};
+
+const RETENTION_DAYS_ENV_KEY: &str = "RETENTION_DAYS";
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Prefixes of objects that are not owned by a single channel, and are never purged
+const UNMANAGED_PREFIXES: [&str; 2] = ["kinesis-checkpoints", RETENTION_AUDIT_PREFIX];
+
+#[derive(Debug, Display, Error, From)]
+pub enum RetentionError {
+  #[display(fmt = "data lake error: {}", _0)]
+  DataLake(DataLakeError),
+  #[display(fmt = "database error: {}", _0)]
+  Database(PgStoreError),
+  #[display(fmt = "{}", _0)]
+  RecordStream(RecordStreamError),
+  #[display(fmt = "JSON serialize error: {}", _0)]
+  JSONSerialize(serde_json::Error),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DlqRetention {
+  pub topic: String,
+  /// Retention of the topic before the run, if known
+  pub previous_retention_ms: Option<i64>,
+  pub retention_ms: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChannelRetentionReport {
+  pub channel_name: String,
+  pub retention_days: u32,
+  #[serde(with = "time::serde::rfc3339")]
+  pub cutoff: OffsetDateTime,
+  pub lake_object_count: usize,
+  pub lake_bytes: usize,
+  pub lake_objects: Vec<String>,
+  /// Amount of expired rows, mapped by table
+  pub db_rows: BTreeMap<&'static str, usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub dlq: Option<DlqRetention>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RetentionReport {
+  #[serde(with = "time::serde::rfc3339")]
+  pub started_at: OffsetDateTime,
+  pub dry_run: bool,
+  pub channels: Vec<ChannelRetentionReport>,
+}
+
+/// Returns the retention period of each channel, in days.
+fn retention_policies_from_env() -> BTreeMap<String, u32> {
+  get_data_channel_map_from_env(RETENTION_DAYS_ENV_KEY, "")
+    .into_iter()
+    .map(|(channel_name, days)| {
+      let days = days
+        .parse::<u32>()
+        .ok()
+        .filter(|v| *v > 0)
+        .unwrap_or_else(|| {
+          panic!(
+            "{} entry for channel {} must be a positive amount of days",
+            RETENTION_DAYS_ENV_KEY, channel_name
+          )
+        });
+      (channel_name, days)
+    })
+    .collect()
+}
+
+/// Returns true if the object belongs to the channel, and only contains data
+/// from before the cutoff.
+fn is_expired_object(object: &LakeObject, channel_name: &str, cutoff: OffsetDateTime) -> bool {
+  let key = object
+    .key
+    .strip_prefix(REPROCESS_OUTPUT_PREFIX)
+    .and_then(|v| v.strip_prefix('/'))
+    .unwrap_or(&object.key);
+  let mut segments = key.split('/');
+  let (first, second) = match (segments.next(), segments.next()) {
+    (Some(first), Some(second)) => (first, second),
+    _ => return false,
+  };
+  if UNMANAGED_PREFIXES.contains(&first) || second != channel_name {
+    return false;
+  }
+  match Date::parse(first, &Iso8601::DATE) {
+    // A date partition contains data up to the end of its date
+    Ok(date) => date < cutoff.date(),
+    Err(_) => object.last_modified.map(|v| v < cutoff).unwrap_or(false),
+  }
+}
+
+async fn enforce_channel_retention(
+  lake: &DataLake,
+  tenant: Option<&str>,
+  lake_objects: &[LakeObject],
+  channel_name: &str,
+  retention_days: u32,
+  started_at: OffsetDateTime,
+  dry_run: bool,
+) -> Result<ChannelRetentionReport, RetentionError> {
+  let cutoff = started_at - Duration::days(retention_days as i64);
+  let expired_objects: Vec<&LakeObject> = lake_objects
+    .iter()
+    .filter(|v| is_expired_object(v, channel_name, cutoff))
+    .collect();
+  let lake_objects: Vec<String> = expired_objects.iter().map(|v| v.key.clone()).collect();
+  if !dry_run && !lake_objects.is_empty() {
+    lake.purge_objects(lake_objects.clone()).await?;
+  }
+
+  let db_pool = DBPool::new(DBConnectionType::Normal {
+    channel_name,
+    tenant,
+  });
+  let conn = Arc::new(Mutex::new(db_pool.get().await?));
+  let db_rows = purge_expired_rows(conn, channel_name, cutoff, dry_run).await?;
+
+  let mut dlq = None;
+  if let Some(topic) = get_data_channel_dlq_topic_from_env(channel_name) {
+    let retention_ms = retention_days as i64 * MILLIS_PER_DAY;
+    let previous_retention_ms = set_topic_retention(
+      KafkaComponent::Aggregator,
+      &topic,
+      tenant,
+      retention_ms,
+      dry_run,
+    )
+    .await?;
+    dlq = Some(DlqRetention {
+      topic,
+      previous_retention_ms,
+      retention_ms,
+    });
+  }
+
+  let report = ChannelRetentionReport {
+    channel_name: channel_name.to_string(),
+    retention_days,
+    cutoff,
+    lake_object_count: lake_objects.len(),
+    lake_bytes: expired_objects.iter().map(|v| v.size).sum(),
+    lake_objects,
+    db_rows,
+    dlq,
+  };
+  info!(
+    "{} data of channel {} older than {}: {} lake objects ({} bytes), rows {:?}",
+    if dry_run { "Found" } else { "Purged" },
+    channel_name,
+    cutoff,
+    report.lake_object_count,
+    report.lake_bytes,
+    report.db_rows
+  );
+  Ok(report)
+}
+
+/// Purges the expired data of each channel with a retention policy, or only reports it
+/// if `dry_run` is set. Prints the report, and stores it in the lake unless `dry_run` is set.
+pub async fn enforce_retention(tenant: Option<&str>, dry_run: bool) -> Result<(), RetentionError> {
+  let policies = retention_policies_from_env();
+  if policies.is_empty() {
+    warn!(
+      "{} is not set, no retention policies to enforce",
+      RETENTION_DAYS_ENV_KEY
+    );
+  }
+  let lake = DataLake::new(tenant.map(|v| v.to_string()), None);
+  let lake_objects = match policies.is_empty() {
+    true => Vec::new(),
+    false => lake.list_all_objects().await?,
+  };
+  let mut report = RetentionReport {
+    started_at: OffsetDateTime::now_utc(),
+    dry_run,
+    channels: Vec::new(),
+  };
+  for (channel_name, retention_days) in policies {
+    report.channels.push(
+      enforce_channel_retention(
+        &lake,
+        tenant,
+        &lake_objects,
+        &channel_name,
+        retention_days,
+        report.started_at,
+        dry_run,
+      )
+      .await?,
+    );
+  }
+
+  let report_json = serde_json::to_string(&report)?;
+  if !dry_run && !report.channels.is_empty() {
+    let run_id = report.started_at.unix_timestamp().to_string();
+    lake.store_retention_audit(&run_id, &report_json).await?;
+  }
+  println!("{}", report_json);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use time::format_description::well_known::Rfc3339;
+
+  fn datetime(value: &str) -> OffsetDateTime {
+    OffsetDateTime::parse(value, &Rfc3339).unwrap()
+  }
+
+  fn object(key: &str, last_modified: Option<OffsetDateTime>) -> LakeObject {
+    LakeObject {
+      key: key.to_string(),
+      size: 10,
+      etag: None,
+      last_modified,
+    }
+  }
+
+  #[test]
+  fn expired_objects() {
+    let cutoff = datetime("2024-05-10T12:00:00Z");
+    let old = Some(datetime("2024-05-01T00:00:00Z"));
+    let recent = Some(datetime("2024-05-11T00:00:00Z"));
+
+    assert!(is_expired_object(
+      &object("2024-05-09/typical/a.jsonl", recent),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("2024-05-10/typical/a.jsonl", old),
+      "typical",
+      cutoff
+    ));
+    assert!(is_expired_object(
+      &object("reprocess/2024-05-01/typical/a.jsonl", None),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("2024-05-01/express/a.jsonl", None),
+      "typical",
+      cutoff
+    ));
+
+    assert!(is_expired_object(
+      &object("messages/typical/a.jsonl", old),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("messages/typical/a.jsonl", recent),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("messages/typical/a.jsonl", None),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("kinesis-checkpoints/typical/stream/shard-0", old),
+      "typical",
+      cutoff
+    ));
+    assert!(!is_expired_object(
+      &object("retention-audits/1714000000.json", old),
+      "1714000000.json",
+      cutoff
+    ));
+  }
+}
@@ -0,0 +1,129 @@
+//! Roll-up rules for recovered attribute values. Rules are applied to each
+//! recovered attribute before the measurement is reported, to reduce the
+//! granularity of values that may increase re-identification risk.
+//!
+//! Rules are defined in a JSON file, which maps metric names to a list of rules.
+//! The rules for a metric are applied in order. For example:
+//!
+//! ```json
+//! {
+//!   "country_code": [{ "type": "allow_list", "values": ["US", "CA"], "replacement": "other" }],
+//!   "version": [{ "type": "truncate_version", "components": 2 }]
+//! }
+//! ```
+
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::channel::get_data_channel_map_from_env;
+
+const ROLLUP_RULES_PATHS_ENV_KEY: &str = "ROLLUP_RULES_PATHS";
+const DEFAULT_REPLACEMENT_VALUE: &str = "other";
+
+fn default_replacement() -> String {
+  DEFAULT_REPLACEMENT_VALUE.to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RollupRule {
+  /// Values that are not in the allow list are replaced with the replacement value
+  AllowList {
+    values: HashSet<String>,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+  },
+  /// Values are replaced according to the mapping. Unmapped values are retained.
+  Map { mapping: HashMap<String, String> },
+  /// Dot-separated version strings are truncated to the given number of components,
+  /// i.e. `1.45.118` is truncated to `1.45` if `components` is 2
+  TruncateVersion { components: usize },
+}
+
+impl RollupRule {
+  fn apply(&self, value: String) -> String {
+    match self {
+      RollupRule::AllowList {
+        values,
+        replacement,
+      } => match values.contains(&value) {
+        true => value,
+        false => replacement.clone(),
+      },
+      RollupRule::Map { mapping } => mapping.get(&value).cloned().unwrap_or(value),
+      RollupRule::TruncateVersion { components } => value
+        .split('.')
+        .take(*components)
+        .collect::<Vec<_>>()
+        .join("."),
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct RollupRules {
+  /// Metric name mapped to the rules for the metric
+  rules: HashMap<String, Vec<RollupRule>>,
+}
+
+impl RollupRules {
+  /// Loads the rules file for the channel, if one is defined in the
+  /// channel map. Returns empty rules otherwise.
+  pub fn from_env(channel_name: &str) -> Self {
+    match get_data_channel_map_from_env(ROLLUP_RULES_PATHS_ENV_KEY, "").get(channel_name) {
+      None => Self::default(),
+      Some(path) => {
+        let contents = fs::read_to_string(path)
+          .unwrap_or_else(|e| panic!("failed to read roll-up rules file {}: {}", path, e));
+        serde_json::from_str(&contents)
+          .unwrap_or_else(|e| panic!("failed to parse roll-up rules file {}: {}", path, e))
+      }
+    }
+  }
+
+  pub fn apply(&self, metric_name: &str, metric_value: String) -> String {
+    match self.rules.get(metric_name) {
+      None => metric_value,
+      Some(rules) => rules
+        .iter()
+        .fold(metric_value, |value, rule| rule.apply(value)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn apply_rules() {
+    let rules: RollupRules = serde_json::from_str(
+      r#"{
+        "country_code": [{ "type": "allow_list", "values": ["US", "CA"] }],
+        "version": [
+          { "type": "map", "mapping": { "nightly": "0.0.0" } },
+          { "type": "truncate_version", "components": 2 }
+        ]
+      }"#,
+    )
+    .unwrap();
+
+    assert_eq!(rules.apply("country_code", "US".to_string()), "US");
+    assert_eq!(rules.apply("country_code", "LI".to_string()), "other");
+    assert_eq!(rules.apply("version", "1.45.118".to_string()), "1.45");
+    assert_eq!(rules.apply("version", "1".to_string()), "1");
+    assert_eq!(rules.apply("version", "nightly".to_string()), "0.0");
+    assert_eq!(rules.apply("platform", "linux".to_string()), "linux");
+  }
+
+  #[test]
+  fn reject_unknown_rules() {
+    assert!(serde_json::from_str::<RollupRules>(r#"{ "a": [{ "type": "hash" }] }"#).is_err());
+    assert!(serde_json::from_str::<RollupRules>(
+      r#"{ "a": [{ "type": "truncate_version", "components": 2, "x": 1 }] }"#
+    )
+    .is_err());
+  }
+}
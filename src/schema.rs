@@ -1,5 +1,46 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    epoch_config_snapshots (channel_name, epoch, config) {
+        #[max_length = 32]
+        channel_name -> Varchar,
+        epoch -> Int2,
+        config -> Text,
+        created_at -> Timestamptz,
+        finalized -> Bool,
+    }
+}
+
+diesel::table! {
+    epoch_measurements (id) {
+        id -> Int8,
+        #[max_length = 32]
+        channel_name -> Varchar,
+        epoch -> Int2,
+        measurement -> Bytea,
+    }
+}
+
+diesel::table! {
+    measurement_outbox (id) {
+        id -> Int8,
+        #[max_length = 32]
+        channel_name -> Varchar,
+        measurement -> Bytea,
+        sent_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    output_measurements (channel_name, outbox_id) {
+        #[max_length = 32]
+        channel_name -> Varchar,
+        outbox_id -> Int8,
+        measurement -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     pending_msgs (id) {
         id -> Int8,
@@ -7,6 +48,33 @@ diesel::table! {
         epoch_tag -> Int2,
         message -> Bytea,
         threshold -> Int2,
+        #[max_length = 32]
+        channel_name -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        encrypted -> Bool,
+        first_submitted_at -> Nullable<Timestamptz>,
+        last_submitted_at -> Nullable<Timestamptz>,
+        message_format -> Int2,
+    }
+}
+
+diesel::table! {
+    processed_archive_objects (channel_name, checksum) {
+        #[max_length = 32]
+        channel_name -> Varchar,
+        checksum -> Text,
+        object_key -> Text,
+        processed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    processed_offsets (channel_name, topic, kafka_partition) {
+        #[max_length = 32]
+        channel_name -> Varchar,
+        kafka_partition -> Int4,
+        last_offset -> Int8,
+        topic -> Text,
     }
 }
 
@@ -23,7 +91,20 @@ diesel::table! {
         count -> Int8,
         key -> Bytea,
         has_children -> Bool,
+        #[max_length = 32]
+        channel_name -> Nullable<Varchar>,
+        first_submitted_at -> Nullable<Timestamptz>,
+        last_submitted_at -> Nullable<Timestamptz>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(pending_msgs, recovered_msgs,);
+diesel::allow_tables_to_appear_in_same_query!(
+  epoch_config_snapshots,
+  epoch_measurements,
+  measurement_outbox,
+  output_measurements,
+  pending_msgs,
+  processed_archive_objects,
+  processed_offsets,
+  recovered_msgs,
+);
@@ -1,15 +1,31 @@
-use crate::channel::get_data_channel_map_from_env;
+use crate::canary::canary_channel;
+use crate::channel::{get_data_channel_map_from_env, get_data_channel_sampling_rate_from_env};
+use crate::channel_info::{SignedChannelInfo, CHANNEL_INFO_SIGNATURE_HEADER};
+use crate::idempotency::{
+  IdempotencyCache, Reservation, StoredResponse, IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LEN,
+};
+use crate::message_key::MessageKeyMode;
 use crate::prometheus::{
-  create_metric_server, InflightMetricLabels, TotalMetricLabels, WebMetrics,
+  create_metric_server, BudgetExceededOutcome, InflightMetricLabels, MetricsRegistry,
+  TotalMetricLabels, WebMetrics,
 };
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::receipt::{ReceiptError, ReceiptSigner};
 use crate::record_stream::{
-  get_data_channel_topic_map_from_env, KafkaRecordStream, KafkaRecordStreamConfig, RecordStream,
+  datetime_to_unix_millis, get_data_channel_topic_map_from_env, KafkaComponent,
+  KafkaRecordStreamConfig, RecordHeaderValues, RecordStreamArc, RecordStreamFactory,
+  RecordStreamOptions,
 };
+use crate::redis::shared_redis_client_from_env;
 use crate::star::{parse_message, AppSTARError};
+use crate::startup::wait_for_record_streams;
+use crate::submission_budget::{BudgetOutcome, SubmissionBudget};
+use crate::tenant::TenantConfig;
 use crate::util::parse_env_var;
+use actix_service::boxed::{self, BoxService};
 use actix_web::HttpRequest;
 use actix_web::{
-  dev::Service,
+  dev::{Service, ServiceRequest, ServiceResponse, Transform},
   error::ResponseError,
   get,
   http::{header::ContentType, StatusCode},
@@ -19,14 +35,22 @@ use actix_web::{
 };
 use base64::{engine::general_purpose as base64_engine, Engine as _};
 use derive_more::{Display, Error, From};
-use futures::{future::try_join, FutureExt};
-use prometheus_client::registry::Registry;
-use reqwest::header::HeaderName;
-use std::collections::HashMap;
+use futures::{
+  future::{try_join, LocalBoxFuture},
+  FutureExt,
+};
+use rand::random;
+use reqwest::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ops::RangeInclusive;
+use std::rc::Rc;
 use std::str::{from_utf8, FromStr, Utf8Error};
 use std::sync::Arc;
 use std::time::Instant;
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
 
 const MIN_CHANNEL_REVISIONS_ENV_KEY: &str = "MIN_CHANNEL_REVISIONS";
 const MIN_REQUEST_K_THRESHOLD_ENV_KEY: &str = "MIN_REQUEST_K_THRESHOLD";
@@ -35,6 +59,14 @@ const MAX_REQUEST_K_THRESHOLD_ENV_KEY: &str = "MAX_REQUEST_K_THRESHOLD";
 const MAX_REQUEST_K_THRESHOLD_DEFAULT: &str = "50";
 const REVISION_HEADER: &str = "brave-p3a-version";
 const THRESHOLD_HEADER: &str = "brave-p3a-constellation-threshold";
+const RECEIPT_HEADER: &str = "brave-p3a-receipt";
+pub const ADMIN_API_KEY_ENV_KEY: &str = "ADMIN_API_KEY";
+const BEARER_PREFIX: &str = "Bearer ";
+const ACCEPTED_CONTENT_TYPES_ENV_KEY: &str = "ACCEPTED_SUBMISSION_CONTENT_TYPES";
+const ACCEPTED_CONTENT_TYPES_DEFAULT: &str = "text/plain,application/octet-stream";
+const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+/// Assumed for requests without a content type, for compatibility with older clients
+const DEFAULT_CONTENT_TYPE: &str = "text/plain";
 
 #[derive(From, Error, Display, Debug)]
 pub enum WebError {
@@ -46,16 +78,45 @@ pub enum WebError {
   STARDecode(AppSTARError),
   #[display(fmt = "Bad k threshold in request header")]
   BadThreshold,
+  #[display(fmt = "Unsupported content type")]
+  UnsupportedContentType,
+  #[display(fmt = "Invalid idempotency key")]
+  BadIdempotencyKey,
+  #[display(fmt = "A request with the same idempotency key is in progress")]
+  IdempotencyConflict,
+  #[display(fmt = "Missing or invalid API key")]
+  Unauthorized,
+  #[display(fmt = "Invalid receipt: {}", _0)]
+  Receipt(ReceiptError),
+  #[display(fmt = "Submission could not be stored in time, retry later")]
+  Unavailable,
   #[display(fmt = "Internal server error")]
   Internal,
 }
 
 pub struct ServerState {
-  pub channel_rec_streams: HashMap<String, KafkaRecordStream>,
+  /// Record streams keyed by tenant name, then by channel name.
+  /// The tenant key is None if tenancy is disabled.
+  pub channel_rec_streams: HashMap<Option<String>, HashMap<String, RecordStreamArc>>,
+  pub tenant_config: Option<TenantConfig>,
   pub web_metrics: Arc<WebMetrics>,
   pub main_channel: String,
   pub min_revision_map: HashMap<String, usize>,
+  /// Fraction of submissions to accept, for each channel
+  pub sampling_rate_map: HashMap<String, f64>,
   pub request_threshold_range: RangeInclusive<usize>,
+  pub canary_channel: Option<String>,
+  /// Signs receipts for accepted submissions, if receipts are enabled
+  pub receipt_signer: Option<ReceiptSigner>,
+  /// API key for admin endpoints. Admin endpoints are disabled if None.
+  pub admin_api_key: Option<String>,
+  /// Content types accepted by the submission endpoints
+  pub accepted_content_types: HashSet<String>,
+  pub idempotency_cache: IdempotencyCache,
+  pub message_key_mode: MessageKeyMode,
+  /// Signed channel configuration document, if channel info is enabled
+  pub channel_info: Option<SignedChannelInfo>,
+  pub submission_budget: SubmissionBudget,
 }
 
 impl ResponseError for WebError {
@@ -70,7 +131,13 @@ impl ResponseError for WebError {
       WebError::STARDecode(_)
       | WebError::Utf8(_)
       | WebError::Base64(_)
-      | WebError::BadThreshold => StatusCode::BAD_REQUEST,
+      | WebError::BadThreshold
+      | WebError::Receipt(_)
+      | WebError::BadIdempotencyKey => StatusCode::BAD_REQUEST,
+      WebError::IdempotencyConflict => StatusCode::CONFLICT,
+      WebError::UnsupportedContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      WebError::Unauthorized => StatusCode::UNAUTHORIZED,
+      WebError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
       WebError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
     }
   }
@@ -95,18 +162,78 @@ fn extract_and_parse_header<T: FromStr>(
     .and_then(|v| v.to_str().unwrap_or_default().parse::<T>().ok())
 }
 
+/// Returns true if the request supplies the admin API key as a bearer token.
+/// The digests of the keys are compared in constant time, so that neither the
+/// content nor the length of the admin API key can be inferred from response times.
+pub fn is_admin_request(request: &HttpRequest, admin_api_key: &str) -> bool {
+  let Some(api_key) = request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix(BEARER_PREFIX))
+    .map(|v| v.trim())
+  else {
+    return false;
+  };
+  Sha256::digest(api_key.as_bytes())
+    .ct_eq(&Sha256::digest(admin_api_key.as_bytes()))
+    .into()
+}
+
+fn resolve_tenant(request: &HttpRequest, state: &ServerState) -> Result<Option<String>, WebError> {
+  match state.tenant_config.as_ref() {
+    None => Ok(None),
+    Some(tenant_config) => request
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| tenant_config.resolve(v))
+      .map(|v| Some(v.to_string()))
+      .ok_or(WebError::Unauthorized),
+  }
+}
+
+/// Returns the serialized STAR message in the request body. Messages posted as
+/// `application/octet-stream` are used as-is, other messages are base64 decoded.
+fn decode_submission(
+  body: &[u8],
+  request: &HttpRequest,
+  state: &ServerState,
+) -> Result<Vec<u8>, WebError> {
+  let content_type = request
+    .headers()
+    .get(CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.split(';').next())
+    .map(|v| v.trim().to_ascii_lowercase())
+    .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+  if !state.accepted_content_types.contains(&content_type) {
+    return Err(WebError::UnsupportedContentType);
+  }
+  match content_type == BINARY_CONTENT_TYPE {
+    true => Ok(body.to_vec()),
+    false => Ok(base64_engine::STANDARD.decode(from_utf8(body)?.trim())?),
+  }
+}
+
 async fn handle_measurement_submit(
   body: web::Bytes,
   request: HttpRequest,
   state: &ServerState,
   channel_name: &String,
 ) -> Result<impl Responder, WebError> {
-  match state.channel_rec_streams.get(channel_name) {
+  let tenant = resolve_tenant(&request, state)?;
+  let rec_stream = state
+    .channel_rec_streams
+    .get(&tenant)
+    .and_then(|streams| streams.get(channel_name));
+  match rec_stream {
     None => Ok(HttpResponse::NotFound().finish()),
     Some(rec_stream) => {
-      let body_str = from_utf8(&body)?.trim();
-      let bincode_msg = base64_engine::STANDARD.decode(body_str)?;
-      parse_message(&bincode_msg)?;
+      let bincode_msg = decode_submission(&body, &request, state)?;
+      let message = parse_message(&bincode_msg)?;
+      let epoch = message.epoch;
+      let message_key = state.message_key_mode.message_key(&message);
 
       if let Some(min_revision) = state.min_revision_map.get(channel_name) {
         let req_revision: usize =
@@ -118,18 +245,161 @@ async fn handle_measurement_submit(
       }
       let threshold: Option<usize> = extract_and_parse_header(&request, THRESHOLD_HEADER);
       if let Some(threshold) = threshold {
-        if !state.request_threshold_range.contains(&threshold) {
+        // The canary channel may use thresholds below the minimum
+        let min_threshold = match state.canary_channel.as_ref() == Some(channel_name) {
+          true => 1,
+          false => *state.request_threshold_range.start(),
+        };
+        if threshold < min_threshold || threshold > *state.request_threshold_range.end() {
           return Err(WebError::BadThreshold);
         }
       }
 
-      match rec_stream.produce(&bincode_msg, threshold).await {
-        Err(e) => {
-          error!("Failed to push message: {}", e);
-          Err(WebError::Internal)
+      let client_version: Option<String> = extract_and_parse_header(&request, REVISION_HEADER);
+
+      let idempotency_key = extract_idempotency_key(&request, tenant.as_deref(), channel_name)?;
+      let idempotency_key = match idempotency_key {
+        None => None,
+        Some(key) => match state.idempotency_cache.reserve(&key).await {
+          Reservation::Reserved => Some(key),
+          Reservation::Unavailable => None,
+          Reservation::InProgress => return Err(WebError::IdempotencyConflict),
+          Reservation::Completed(response) => return Ok(stored_response_to_http(response)),
+        },
+      };
+
+      let result = submit_message(
+        state,
+        rec_stream,
+        channel_name,
+        &bincode_msg,
+        message_key.as_deref(),
+        threshold,
+        epoch,
+        client_version,
+      )
+      .await;
+      if let Some(key) = idempotency_key.as_ref() {
+        match result.as_ref() {
+          Ok(response) => {
+            state
+              .idempotency_cache
+              .complete(key, response.clone())
+              .await
+          }
+          Err(_) => state.idempotency_cache.release(key).await,
         }
-        Ok(_) => Ok(HttpResponse::NoContent().finish()),
       }
+      Ok(stored_response_to_http(result?))
+    }
+  }
+}
+
+/// Returns the idempotency key of the request, if supplied. The key is scoped
+/// to the tenant and channel, so that keys of separate tenants cannot collide.
+fn extract_idempotency_key(
+  request: &HttpRequest,
+  tenant: Option<&str>,
+  channel_name: &str,
+) -> Result<Option<String>, WebError> {
+  let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+    None => return Ok(None),
+    Some(key) => key.to_str().map_err(|_| WebError::BadIdempotencyKey)?,
+  };
+  if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+    return Err(WebError::BadIdempotencyKey);
+  }
+  Ok(Some(format!(
+    "{}:{}:{}",
+    tenant.unwrap_or_default(),
+    channel_name,
+    key
+  )))
+}
+
+fn stored_response_to_http(response: StoredResponse) -> HttpResponse {
+  let mut result =
+    HttpResponse::build(StatusCode::from_u16(response.status).unwrap_or(StatusCode::NO_CONTENT));
+  if let Some(receipt) = response.receipt {
+    result.insert_header((RECEIPT_HEADER, receipt));
+  }
+  result.finish()
+}
+
+/// Produces the message, along with the submission time and client version headers.
+/// The produce is limited by the submission budget, if configured.
+#[allow(clippy::too_many_arguments)]
+async fn submit_message(
+  state: &ServerState,
+  rec_stream: &RecordStreamArc,
+  channel_name: &str,
+  bincode_msg: &[u8],
+  message_key: Option<&str>,
+  threshold: Option<usize>,
+  epoch: u8,
+  client_version: Option<String>,
+) -> Result<StoredResponse, WebError> {
+  if let Some(sampling_rate) = state.sampling_rate_map.get(channel_name) {
+    if random::<f64>() >= *sampling_rate {
+      // Respond with success, so that clients do not retry the submission
+      state.web_metrics.submission_sampled_out(channel_name);
+      return Ok(StoredResponse {
+        status: StatusCode::NO_CONTENT.as_u16(),
+        receipt: None,
+      });
+    }
+  }
+
+  let metadata_headers = RecordHeaderValues {
+    submitted_at: Some(datetime_to_unix_millis(OffsetDateTime::now_utc())),
+    client_version,
+    ..Default::default()
+  }
+  .headers();
+  // The produce may outlive the request if the budget is exceeded, so it owns its inputs
+  let delivery = {
+    let rec_stream = rec_stream.clone();
+    let record = bincode_msg.to_vec();
+    let message_key = message_key.map(|v| v.to_string());
+    let channel_name = channel_name.to_string();
+    async move {
+      rec_stream
+        .produce_with_headers(
+          &record,
+          message_key.as_deref(),
+          threshold,
+          Some(&channel_name),
+          Some(epoch),
+          &metadata_headers,
+        )
+        .await
+    }
+  };
+  match state.submission_budget.deliver(delivery).await {
+    Err(e) => {
+      error!("Failed to push message: {}", e);
+      Err(WebError::Internal)
+    }
+    Ok(BudgetOutcome::Exceeded) => {
+      warn!("Submission budget exceeded for channel {}", channel_name);
+      state
+        .web_metrics
+        .submission_budget_exceeded(channel_name, BudgetExceededOutcome::Rejected);
+      Err(WebError::Unavailable)
+    }
+    Ok(outcome) => {
+      if outcome == BudgetOutcome::Queued {
+        state
+          .web_metrics
+          .submission_budget_exceeded(channel_name, BudgetExceededOutcome::Queued);
+      }
+      Ok(StoredResponse {
+        status: StatusCode::NO_CONTENT.as_u16(),
+        receipt: state.receipt_signer.as_ref().map(|receipt_signer| {
+          let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+          receipt_signer.sign(bincode_msg, epoch, timestamp)
+        }),
+      })
     }
   }
 }
@@ -153,93 +423,433 @@ async fn main_handler(
   handle_measurement_submit(body, request, state.as_ref(), &state.main_channel).await
 }
 
-pub async fn start_server(worker_count: usize, main_channel: String) -> std::io::Result<()> {
-  let channel_rec_streams = get_data_channel_topic_map_from_env(false)
+/// Verifies a submission receipt token supplied in the request body, and returns
+/// the receipt contents. Requires the admin API key as a bearer token.
+#[post("/admin/receipts/verify")]
+async fn verify_receipt_handler(
+  body: web::Bytes,
+  request: HttpRequest,
+  state: Data<ServerState>,
+) -> Result<impl Responder, WebError> {
+  let (admin_api_key, receipt_signer) =
+    match (state.admin_api_key.as_ref(), state.receipt_signer.as_ref()) {
+      (Some(admin_api_key), Some(receipt_signer)) => (admin_api_key, receipt_signer),
+      _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+  if !is_admin_request(&request, admin_api_key) {
+    return Err(WebError::Unauthorized);
+  }
+  let receipt = receipt_signer.verify(from_utf8(&body)?)?;
+  Ok(HttpResponse::Ok().json(receipt))
+}
+
+/// Returns the signed configuration document of the accepted channels.
+#[get("/info/channels")]
+async fn channel_info_handler(state: Data<ServerState>) -> HttpResponse {
+  match state.channel_info.as_ref() {
+    Some(channel_info) => HttpResponse::Ok()
+      .content_type(ContentType::json())
+      .insert_header((
+        CHANNEL_INFO_SIGNATURE_HEADER,
+        channel_info.signature.as_str(),
+      ))
+      .body(channel_info.body.clone()),
+    None => HttpResponse::NotFound().finish(),
+  }
+}
+
+fn create_channel_rec_streams(
+  stream_factory: &RecordStreamFactory,
+  tenant: Option<&str>,
+) -> HashMap<String, RecordStreamArc> {
+  get_data_channel_topic_map_from_env(false)
     .into_iter()
     .map(|(channel_name, topic)| {
       (
         channel_name,
-        KafkaRecordStream::new(KafkaRecordStreamConfig {
-          enable_producer: true,
-          enable_consumer: false,
-          topic,
-          use_output_group_id: false,
-        }),
+        stream_factory.create(
+          KafkaRecordStreamConfig {
+            component: KafkaComponent::Server,
+            enable_producer: true,
+            enable_consumer: false,
+            topic,
+            use_output_group_id: false,
+            group_id_suffix: None,
+            tenant: tenant.map(|v| v.to_string()),
+          },
+          RecordStreamOptions::default(),
+        ),
       )
     })
-    .collect();
+    .collect()
+}
 
-  let min_revision_map = get_data_channel_map_from_env(MIN_CHANNEL_REVISIONS_ENV_KEY, "")
-    .into_iter()
-    .map(|(channel, value)| {
-      (
-        channel,
-        value
-          .parse::<usize>()
-          .expect("minimum channel revision should be non-negative integer"),
-      )
-    })
-    .collect();
-
-  let min_request_threshold = parse_env_var(
-    MIN_REQUEST_K_THRESHOLD_ENV_KEY,
-    MIN_REQUEST_K_THRESHOLD_DEFAULT,
-  );
-  let max_request_threshold = parse_env_var(
-    MAX_REQUEST_K_THRESHOLD_ENV_KEY,
-    MAX_REQUEST_K_THRESHOLD_DEFAULT,
-  );
-
-  let state = Data::new(ServerState {
-    channel_rec_streams,
-    web_metrics: Arc::new(WebMetrics::new()),
-    main_channel,
-    min_revision_map,
-    request_threshold_range: min_request_threshold..=max_request_threshold,
-  });
-
-  let mut registry = <Registry>::default();
-  state.web_metrics.register_metrics(&mut registry);
-  let metric_server = create_metric_server(registry, 9090)?;
-
-  info!("Starting server...");
-  let main_server = HttpServer::new(move || {
-    App::new()
-      .app_data(state.clone())
-      .wrap_fn(|request, srv| {
-        let web_metrics = request
-          .app_data::<Data<ServerState>>()
-          .unwrap()
-          .web_metrics
-          .clone();
-
-        let inflight_metric_labels = InflightMetricLabels::from(&request);
-        web_metrics.request_start(&inflight_metric_labels);
-
-        let start_time = Instant::now();
-
-        srv.call(request).map(move |result| {
-          let status_code = match result.as_ref() {
-            Ok(response) => response.status(),
-            Err(err) => err.as_response_error().status_code(),
-          };
-          let total_metric_labels = TotalMetricLabels::from((&inflight_metric_labels, status_code));
-          web_metrics.request_end(
-            &inflight_metric_labels,
-            &total_metric_labels,
-            start_time.elapsed(),
-          );
-
-          result
+/// Configures additional routes of the server, via `App::configure`.
+pub type RouteConfig = Arc<dyn Fn(&mut web::ServiceConfig) + Send + Sync>;
+/// Service wrapped by the middleware registered via `ServerBuilder::with_middleware`,
+/// which includes the core handlers and the extra routes.
+pub type ServerService = BoxService<ServiceRequest, ServiceResponse, actix_web::Error>;
+
+/// Middleware of any type, so that multiple middleware may be registered.
+trait ServerTransform {
+  fn new_transform(
+    &self,
+    service: ServerService,
+  ) -> LocalBoxFuture<'static, Result<ServerService, ()>>;
+}
+
+impl<T> ServerTransform for T
+where
+  T: Transform<
+    ServerService,
+    ServiceRequest,
+    Response = ServiceResponse,
+    Error = actix_web::Error,
+    InitError = (),
+  >,
+  T::Transform: 'static,
+  T::Future: 'static,
+{
+  fn new_transform(
+    &self,
+    service: ServerService,
+  ) -> LocalBoxFuture<'static, Result<ServerService, ()>> {
+    let transform = Transform::new_transform(self, service);
+    async move { Ok(boxed::service(transform.await?)) }.boxed_local()
+  }
+}
+
+/// Creates the middleware for each server worker.
+type MiddlewareFactory = Arc<dyn Fn() -> Box<dyn ServerTransform> + Send + Sync>;
+
+/// Wraps the app in the registered middleware, via `App::wrap`.
+/// Middleware registered first is the outermost.
+#[derive(Clone)]
+struct ServerMiddleware {
+  transforms: Rc<Vec<Box<dyn ServerTransform>>>,
+}
+
+impl ServerMiddleware {
+  fn new(factories: &[MiddlewareFactory]) -> Self {
+    Self {
+      transforms: Rc::new(factories.iter().map(|factory| factory()).collect()),
+    }
+  }
+}
+
+impl<S> Transform<S, ServiceRequest> for ServerMiddleware
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+{
+  type Response = ServiceResponse;
+  type Error = actix_web::Error;
+  type Transform = ServerService;
+  type InitError = ();
+  type Future = LocalBoxFuture<'static, Result<ServerService, ()>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    let transforms = self.transforms.clone();
+    async move {
+      let mut service = boxed::service(service);
+      for transform in transforms.iter().rev() {
+        service = transform.new_transform(service).await?;
+      }
+      Ok(service)
+    }
+    .boxed_local()
+  }
+}
+
+/// Builder for the submission server, which allows embedders to register extra routes
+/// and middleware (i.e. auth, logging, tenant resolution) around the core handlers.
+pub struct ServerBuilder {
+  worker_count: usize,
+  main_channel: String,
+  route_configs: Vec<RouteConfig>,
+  middleware_factories: Vec<MiddlewareFactory>,
+  metrics_registry: Option<MetricsRegistry>,
+}
+
+impl ServerBuilder {
+  pub fn new(worker_count: usize, main_channel: String) -> Self {
+    Self {
+      worker_count,
+      main_channel,
+      route_configs: Vec::new(),
+      middleware_factories: Vec::new(),
+      metrics_registry: None,
+    }
+  }
+
+  /// Registers the server metrics in the given registry, so that the metrics of
+  /// other modes run by the process are exported by the server's metrics listener.
+  pub fn with_metrics_registry(mut self, registry: MetricsRegistry) -> Self {
+    self.metrics_registry = Some(registry);
+    self
+  }
+
+  /// Registers extra routes. Routes are registered after the core handlers,
+  /// so they cannot replace the core routes.
+  pub fn with_routes(mut self, config: RouteConfig) -> Self {
+    self.route_configs.push(config);
+    self
+  }
+
+  /// Registers actix-web middleware, which wraps the core handlers and the extra routes.
+  /// The factory is called for each server worker. Middleware registered first runs
+  /// first, and requests rejected by middleware are included in the request metrics.
+  /// Middleware may attach values to the request extensions, such as a resolved tenant
+  /// or authenticated principal, for use by extra routes. Middleware that changes the
+  /// response body type, such as `middleware::Logger`, should be wrapped in
+  /// `middleware::Compat`.
+  pub fn with_middleware<F, M>(mut self, factory: F) -> Self
+  where
+    F: Fn() -> M + Send + Sync + 'static,
+    M: Transform<
+        ServerService,
+        ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+      > + 'static,
+    M::Transform: 'static,
+    M::Future: 'static,
+  {
+    self
+      .middleware_factories
+      .push(Arc::new(move || Box::new(factory())));
+    self
+  }
+
+  pub async fn start(self) -> std::io::Result<()> {
+    let ServerBuilder {
+      worker_count,
+      main_channel,
+      route_configs,
+      middleware_factories,
+      metrics_registry,
+    } = self;
+    let tenant_config = TenantConfig::from_env();
+    let stream_factory = RecordStreamFactory::from_env();
+    info!("Using {} record stream backend", stream_factory.backend());
+    let channel_rec_streams = match tenant_config.as_ref() {
+      None => HashMap::from([(None, create_channel_rec_streams(&stream_factory, None))]),
+      Some(tenant_config) => tenant_config
+        .tenant_names()
+        .into_iter()
+        .map(|tenant| {
+          let streams = create_channel_rec_streams(&stream_factory, Some(&tenant));
+          (Some(tenant), streams)
         })
+        .collect(),
+    };
+
+    // Requests are not accepted until the submissions can be produced
+    wait_for_record_streams(channel_rec_streams.iter().flat_map(|(tenant, streams)| {
+      streams.iter().map(move |(channel_name, stream)| {
+        let name = match tenant {
+          Some(tenant) => format!(
+            "Producer for '{}' channel of tenant {}",
+            channel_name, tenant
+          ),
+          None => format!("Producer for '{}' channel", channel_name),
+        };
+        (name, stream.as_ref())
+      })
+    }))
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))?;
+
+    let min_revision_map = get_data_channel_map_from_env(MIN_CHANNEL_REVISIONS_ENV_KEY, "")
+      .into_iter()
+      .map(|(channel, value)| {
+        (
+          channel,
+          value
+            .parse::<usize>()
+            .expect("minimum channel revision should be non-negative integer"),
+        )
+      })
+      .collect();
+
+    let sampling_rate_map = get_data_channel_topic_map_from_env(false)
+      .into_keys()
+      .map(|channel_name| {
+        let sampling_rate = get_data_channel_sampling_rate_from_env(&channel_name);
+        (channel_name, sampling_rate)
       })
-      .service(ident_handler)
-      .service(channel_handler)
-      .service(main_handler)
-  })
-  .workers(worker_count)
-  .bind(("0.0.0.0", 8080))?
-  .run();
-
-  try_join(metric_server, main_server).await.map(|_| ())
+      .filter(|(_, sampling_rate)| *sampling_rate < 1.0)
+      .collect();
+
+    let min_request_threshold = parse_env_var(
+      MIN_REQUEST_K_THRESHOLD_ENV_KEY,
+      MIN_REQUEST_K_THRESHOLD_DEFAULT,
+    );
+    let max_request_threshold = parse_env_var(
+      MAX_REQUEST_K_THRESHOLD_ENV_KEY,
+      MAX_REQUEST_K_THRESHOLD_DEFAULT,
+    );
+
+    let accepted_content_types: HashSet<String> = env::var(ACCEPTED_CONTENT_TYPES_ENV_KEY)
+      .unwrap_or(ACCEPTED_CONTENT_TYPES_DEFAULT.to_string())
+      .split(',')
+      .map(|v| v.trim().to_ascii_lowercase())
+      .filter(|v| !v.is_empty())
+      .collect();
+    let request_threshold_range = min_request_threshold..=max_request_threshold;
+    let channel_info = SignedChannelInfo::from_env(
+      get_data_channel_topic_map_from_env(false).into_keys(),
+      &main_channel,
+      &min_revision_map,
+      &request_threshold_range,
+      &accepted_content_types,
+    );
+
+    let shared_redis = shared_redis_client_from_env();
+    match shared_redis.is_some() {
+      true => info!("Using Redis for state shared between server replicas"),
+      false => info!("Shared Redis not configured, server state will be kept in memory"),
+    }
+
+    let state = Data::new(ServerState {
+      channel_rec_streams,
+      tenant_config,
+      web_metrics: Arc::new(WebMetrics::default()),
+      main_channel,
+      min_revision_map,
+      sampling_rate_map,
+      request_threshold_range,
+      canary_channel: canary_channel(),
+      receipt_signer: ReceiptSigner::from_env(),
+      admin_api_key: env::var(ADMIN_API_KEY_ENV_KEY).ok(),
+      accepted_content_types,
+      idempotency_cache: IdempotencyCache::from_env(shared_redis.clone()),
+      message_key_mode: MessageKeyMode::from_env(),
+      channel_info,
+      submission_budget: SubmissionBudget::from_env(),
+    });
+
+    let mut registry = metrics_registry.unwrap_or_else(|| MetricsRegistry::new(&["server"]));
+    registry.register(state.web_metrics.as_ref());
+    let metric_server = create_metric_server(registry, 9090, |_| {})?;
+
+    let rate_limiter = RateLimiter::from_env(shared_redis).map(Arc::new);
+
+    info!("Starting server...");
+    let route_configs = Arc::new(route_configs);
+    let middleware_factories = Arc::new(middleware_factories);
+    let main_server = HttpServer::new(move || {
+      let route_configs = route_configs.clone();
+      App::new()
+        .app_data(state.clone())
+        .wrap(ServerMiddleware::new(&middleware_factories))
+        .wrap(RateLimit::new(rate_limiter.clone()))
+        .wrap_fn(|request, srv| {
+          let web_metrics = request
+            .app_data::<Data<ServerState>>()
+            .unwrap()
+            .web_metrics
+            .clone();
+
+          let inflight_metric_labels = InflightMetricLabels::from(&request);
+          web_metrics.request_start(&inflight_metric_labels);
+
+          let start_time = Instant::now();
+
+          srv.call(request).map(move |result| {
+            let status_code = match result.as_ref() {
+              Ok(response) => response.status(),
+              Err(err) => err.as_response_error().status_code(),
+            };
+            let total_metric_labels =
+              TotalMetricLabels::from((&inflight_metric_labels, status_code));
+            web_metrics.request_end(
+              &inflight_metric_labels,
+              &total_metric_labels,
+              start_time.elapsed(),
+            );
+
+            result
+          })
+        })
+        .service(ident_handler)
+        .service(verify_receipt_handler)
+        .service(channel_info_handler)
+        .service(channel_handler)
+        .service(main_handler)
+        .configure(|config| {
+          for route_config in route_configs.iter() {
+            route_config(config);
+          }
+        })
+    })
+    .workers(worker_count)
+    .bind(("0.0.0.0", 8080))?
+    .run();
+
+    try_join(metric_server, main_server).await.map(|_| ())
+  }
+}
+
+pub async fn start_server(
+  worker_count: usize,
+  main_channel: String,
+  metrics_registry: MetricsRegistry,
+) -> std::io::Result<()> {
+  ServerBuilder::new(worker_count, main_channel)
+    .with_metrics_registry(metrics_registry)
+    .start()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::body::BoxBody;
+  use actix_web::error::ErrorForbidden;
+  use actix_web::middleware::{from_fn, Next};
+  use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+  use std::sync::Mutex;
+
+  #[actix_web::test]
+  async fn middleware_order() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let recorder = |name: &'static str| {
+      let calls = calls.clone();
+      move || {
+        let calls = calls.clone();
+        from_fn(move |request: ServiceRequest, next: Next<BoxBody>| {
+          calls.lock().unwrap().push(name);
+          async move {
+            match request.path() {
+              "/rejected" => Err(ErrorForbidden(name)),
+              _ => next.call(request).await,
+            }
+          }
+        })
+      }
+    };
+    let builder = ServerBuilder::new(1, "typical".to_string())
+      .with_middleware(recorder("outer"))
+      .with_middleware(recorder("inner"));
+    let app = init_service(
+      App::new()
+        .wrap(ServerMiddleware::new(&builder.middleware_factories))
+        .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let response = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(*calls.lock().unwrap(), vec!["outer", "inner"]);
+
+    calls.lock().unwrap().clear();
+    let request = TestRequest::get().uri("/rejected").to_request();
+    let error = try_call_service(&app, request).await.unwrap_err();
+    assert_eq!(
+      error.as_response_error().status_code(),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(*calls.lock().unwrap(), vec!["outer"]);
+  }
 }
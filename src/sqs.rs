@@ -0,0 +1,909 @@
+//! Record stream backed by Amazon SQS, selected via `RECORD_STREAM_BACKEND=sqs`, for small
+//! deployments where Kafka is not available. The topic of the stream config is used as the
+//! queue name. Queues are not created by the processor. SQS messages only support a few
+//! attributes, so the record data and headers are wrapped in a bincode envelope, sent as
+//! the base64 encoded message body.
+//!
+//! SQS queues do not have consumer groups: the messages of a queue are distributed among
+//! all of its consumers. SQS messages do not have offsets, so consumed records are assigned
+//! local offsets, which are only valid for the lifetime of the stream, and each consumer
+//! reports its records in its own partition. Received messages are hidden from other
+//! consumers for `SQS_VISIBILITY_TIMEOUT_SECS`, and the timeout is extended until they are
+//! committed. Committing consumption deletes the consumed messages up to the committed
+//! offset. Messages that are not deleted, i.e. after a crash, become visible again once
+//! their timeout passes, and are redelivered.
+//!
+//! Requests cost the same regardless of the amount of messages they contain, so messages
+//! are received with long polling, up to `SQS_RECEIVE_BATCH_SIZE` messages at a time, and
+//! queued or batched records are sent in batches.
+
+use crate::record_stream::{
+  acquire_send_permit, datetime_from_unix_millis, send_to_producer_queue, BatchRecord,
+  ConsumedRecord, KafkaRecordStreamConfig, ProducerQueue, ProducerQueueItem, RecordHeaderValues,
+  RecordStream, RecordStreamError, RecordStreamOptions,
+};
+use crate::tenant::tenant_scoped_name;
+use crate::util::parse_env_var;
+use async_trait::async_trait;
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use derive_more::{Display, Error, From};
+use futures::future::try_join_all;
+use rand::{seq::SliceRandom, thread_rng};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_sqs::{
+  BatchResultErrorEntry, ChangeMessageVisibilityBatchError, ChangeMessageVisibilityBatchRequest,
+  ChangeMessageVisibilityBatchRequestEntry, DeleteMessageBatchError, DeleteMessageBatchRequest,
+  DeleteMessageBatchRequestEntry, GetQueueUrlError, GetQueueUrlRequest, Message,
+  ReceiveMessageError, ReceiveMessageRequest, SendMessageBatchError, SendMessageBatchRequest,
+  SendMessageBatchRequestEntry, SendMessageError, SendMessageRequest, Sqs, SqsClient,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{Mutex as AsyncMutex, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+const SQS_ENDPOINT_ENV_KEY: &str = "SQS_ENDPOINT";
+const SQS_RECEIVE_BATCH_SIZE_ENV_KEY: &str = "SQS_RECEIVE_BATCH_SIZE";
+const DEFAULT_SQS_RECEIVE_BATCH_SIZE: &str = "10";
+const SQS_WAIT_TIME_SECS_ENV_KEY: &str = "SQS_WAIT_TIME_SECS";
+const DEFAULT_SQS_WAIT_TIME_SECS: &str = "20";
+const SQS_VISIBILITY_TIMEOUT_SECS_ENV_KEY: &str = "SQS_VISIBILITY_TIMEOUT_SECS";
+const DEFAULT_SQS_VISIBILITY_TIMEOUT_SECS: &str = "60";
+const SQS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY: &str = "SQS_PRODUCE_QUEUE_TASK_COUNT";
+const DEFAULT_SQS_PRODUCE_QUEUE_TASK_COUNT: &str = "16";
+const WEB_IDENTITY_ENV_VAR: &str = "AWS_WEB_IDENTITY_TOKEN_FILE";
+
+/// Limit of entries per batch request, and of messages per receive request
+const MAX_BATCH_ENTRIES: usize = 10;
+/// Limit of the total message size of a send batch
+const MAX_BATCH_BYTES: usize = 256 * 1024;
+/// Limit of the long polling wait time of receive requests
+const MAX_WAIT_TIME_SECS: u64 = 20;
+const SENT_TIMESTAMP_ATTRIBUTE: &str = "SentTimestamp";
+/// Only applies if long polling is disabled
+const EMPTY_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+static NEXT_CONSUMER_PARTITION: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Debug, Display, Error, From)]
+pub enum SqsError {
+  #[display(fmt = "SQS get queue URL error: {}", _0)]
+  #[from(ignore)]
+  GetQueueUrl(Box<RusotoError<GetQueueUrlError>>),
+  #[display(fmt = "SQS queue URL missing for queue {}", _0)]
+  #[from(ignore)]
+  MissingQueueUrl(#[error(not(source))] String),
+  #[display(fmt = "SQS send message error: {}", _0)]
+  #[from(ignore)]
+  SendMessage(Box<RusotoError<SendMessageError>>),
+  #[display(fmt = "SQS send message batch error: {}", _0)]
+  #[from(ignore)]
+  SendMessageBatch(Box<RusotoError<SendMessageBatchError>>),
+  #[display(fmt = "SQS receive message error: {}", _0)]
+  #[from(ignore)]
+  ReceiveMessage(Box<RusotoError<ReceiveMessageError>>),
+  #[display(fmt = "SQS delete message batch error: {}", _0)]
+  #[from(ignore)]
+  DeleteMessageBatch(Box<RusotoError<DeleteMessageBatchError>>),
+  #[display(fmt = "SQS change message visibility batch error: {}", _0)]
+  #[from(ignore)]
+  ChangeMessageVisibilityBatch(Box<RusotoError<ChangeMessageVisibilityBatchError>>),
+  #[display(fmt = "SQS batch entry failed: {}: {}", code, message)]
+  #[from(ignore)]
+  BatchEntry { code: String, message: String },
+  #[display(fmt = "SQS record envelope error: {}", _0)]
+  Envelope(bincode::Error),
+}
+
+/// Record data and headers, stored as the body of an SQS message
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RecordEnvelope {
+  data: Vec<u8>,
+  headers: Vec<(String, Vec<u8>)>,
+}
+
+impl RecordEnvelope {
+  fn message_body(&self) -> Result<String, SqsError> {
+    Ok(base64_engine::STANDARD.encode(bincode::serialize(self)?))
+  }
+}
+
+/// SQS client with a cache of queue URLs
+struct QueueClient {
+  client: SqsClient,
+  /// Queue URLs by queue name
+  queue_urls: Mutex<HashMap<String, String>>,
+}
+
+impl QueueClient {
+  fn from_env() -> Self {
+    let region = match env::var(SQS_ENDPOINT_ENV_KEY) {
+      Ok(endpoint) => Region::Custom {
+        name: Region::default().name().to_string(),
+        endpoint,
+      },
+      Err(_) => Default::default(),
+    };
+    let client = if env::var(WEB_IDENTITY_ENV_VAR).is_ok() {
+      let provider = rusoto_credential::AutoRefreshingProvider::new(
+        rusoto_sts::WebIdentityProvider::from_k8s_env(),
+      )
+      .unwrap();
+      SqsClient::new_with(HttpClient::new().unwrap(), provider, region)
+    } else {
+      SqsClient::new(region)
+    };
+    Self {
+      client,
+      queue_urls: Mutex::new(HashMap::new()),
+    }
+  }
+
+  async fn queue_url(&self, queue_name: &str) -> Result<String, SqsError> {
+    if let Some(queue_url) = self.queue_urls.lock().unwrap().get(queue_name) {
+      return Ok(queue_url.clone());
+    }
+    let queue_url = self
+      .client
+      .get_queue_url(GetQueueUrlRequest {
+        queue_name: queue_name.to_string(),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| SqsError::GetQueueUrl(Box::new(e)))?
+      .queue_url
+      .ok_or_else(|| SqsError::MissingQueueUrl(queue_name.to_string()))?;
+    self
+      .queue_urls
+      .lock()
+      .unwrap()
+      .insert(queue_name.to_string(), queue_url.clone());
+    Ok(queue_url)
+  }
+
+  async fn send_message(
+    &self,
+    queue_name: &str,
+    envelope: &RecordEnvelope,
+  ) -> Result<(), SqsError> {
+    let queue_url = self.queue_url(queue_name).await?;
+    self
+      .client
+      .send_message(SendMessageRequest {
+        queue_url,
+        message_body: envelope.message_body()?,
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| SqsError::SendMessage(Box::new(e)))?;
+    Ok(())
+  }
+
+  async fn send_messages(&self, queue_name: &str, bodies: Vec<String>) -> Result<(), SqsError> {
+    let queue_url = self.queue_url(queue_name).await?;
+    for entries in send_batches(bodies) {
+      let output = self
+        .client
+        .send_message_batch(SendMessageBatchRequest {
+          queue_url: queue_url.clone(),
+          entries,
+        })
+        .await
+        .map_err(|e| SqsError::SendMessageBatch(Box::new(e)))?;
+      check_batch_result(output.failed)?;
+    }
+    Ok(())
+  }
+
+  async fn receive_messages(
+    &self,
+    queue_name: &str,
+    max_messages: usize,
+    wait_time: Duration,
+    visibility_timeout: Duration,
+  ) -> Result<Vec<Message>, SqsError> {
+    let queue_url = self.queue_url(queue_name).await?;
+    let output = self
+      .client
+      .receive_message(ReceiveMessageRequest {
+        queue_url,
+        max_number_of_messages: Some(max_messages as i64),
+        wait_time_seconds: Some(wait_time.as_secs() as i64),
+        visibility_timeout: Some(visibility_timeout.as_secs() as i64),
+        attribute_names: Some(vec![SENT_TIMESTAMP_ATTRIBUTE.to_string()]),
+        ..Default::default()
+      })
+      .await
+      .map_err(|e| SqsError::ReceiveMessage(Box::new(e)))?;
+    Ok(output.messages.unwrap_or_default())
+  }
+
+  /// Deletes the messages, or changes their visibility timeout if set.
+  async fn update_messages(
+    &self,
+    queue_name: &str,
+    receipt_handles: &[String],
+    visibility_timeout: Option<Duration>,
+  ) -> Result<(), SqsError> {
+    if receipt_handles.is_empty() {
+      return Ok(());
+    }
+    let queue_url = self.queue_url(queue_name).await?;
+    for receipt_handles in receipt_handles.chunks(MAX_BATCH_ENTRIES) {
+      let ids_and_handles = receipt_handles
+        .iter()
+        .enumerate()
+        .map(|(i, receipt_handle)| (i.to_string(), receipt_handle.clone()));
+      let failed = match visibility_timeout {
+        Some(visibility_timeout) => {
+          self
+            .client
+            .change_message_visibility_batch(ChangeMessageVisibilityBatchRequest {
+              queue_url: queue_url.clone(),
+              entries: ids_and_handles
+                .map(
+                  |(id, receipt_handle)| ChangeMessageVisibilityBatchRequestEntry {
+                    id,
+                    receipt_handle,
+                    visibility_timeout: Some(visibility_timeout.as_secs() as i64),
+                  },
+                )
+                .collect(),
+            })
+            .await
+            .map_err(|e| SqsError::ChangeMessageVisibilityBatch(Box::new(e)))?
+            .failed
+        }
+        None => {
+          self
+            .client
+            .delete_message_batch(DeleteMessageBatchRequest {
+              queue_url: queue_url.clone(),
+              entries: ids_and_handles
+                .map(|(id, receipt_handle)| DeleteMessageBatchRequestEntry { id, receipt_handle })
+                .collect(),
+            })
+            .await
+            .map_err(|e| SqsError::DeleteMessageBatch(Box::new(e)))?
+            .failed
+        }
+      };
+      check_batch_result(failed)?;
+    }
+    Ok(())
+  }
+}
+
+/// Fails unless all entries of a batch request succeeded.
+fn check_batch_result(failed: Vec<BatchResultErrorEntry>) -> Result<(), SqsError> {
+  match failed.into_iter().next() {
+    Some(failed) => Err(SqsError::BatchEntry {
+      code: failed.code,
+      message: failed.message.unwrap_or_default(),
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Groups the message bodies into send batches, within the entry and size limits of a batch.
+fn send_batches(bodies: Vec<String>) -> Vec<Vec<SendMessageBatchRequestEntry>> {
+  let mut batches: Vec<Vec<SendMessageBatchRequestEntry>> = Vec::new();
+  let mut batch_bytes = 0;
+  for message_body in bodies {
+    let full = batches
+      .last()
+      .map(|v| v.len() >= MAX_BATCH_ENTRIES || batch_bytes + message_body.len() > MAX_BATCH_BYTES);
+    if full.unwrap_or(true) {
+      batches.push(Vec::new());
+      batch_bytes = 0;
+    }
+    let batch = batches.last_mut().unwrap();
+    batch_bytes += message_body.len();
+    batch.push(SendMessageBatchRequestEntry {
+      id: batch.len().to_string(),
+      message_body,
+      ..Default::default()
+    });
+  }
+  batches
+}
+
+struct UncommittedMessage {
+  message_id: String,
+  receipt_handle: String,
+}
+
+#[derive(Default)]
+struct ConsumerState {
+  initialized: bool,
+  buffer: VecDeque<ConsumedRecord>,
+  /// Received messages that have not been deleted, by local offset
+  uncommitted: BTreeMap<i64, UncommittedMessage>,
+  uncommitted_offsets: HashMap<String, i64>,
+  next_offset: i64,
+  consumed_offset: Option<i64>,
+  committed_offset: Option<i64>,
+}
+
+impl ConsumerState {
+  fn buffer_messages(&mut self, messages: Vec<Message>, partition: i32, tenant: Option<&str>) {
+    for message in messages {
+      let (Some(message_id), Some(receipt_handle)) = (message.message_id, message.receipt_handle)
+      else {
+        warn!("Skipping SQS message without id or receipt handle");
+        continue;
+      };
+      // Messages that became visible again before being deleted are already buffered
+      // or consumed, but only the latest receipt handle of a message is valid
+      if let Some(offset) = self.uncommitted_offsets.get(&message_id) {
+        if let Some(uncommitted) = self.uncommitted.get_mut(offset) {
+          uncommitted.receipt_handle = receipt_handle;
+        }
+        continue;
+      }
+      let offset = self.next_offset;
+      self.next_offset += 1;
+      self.uncommitted_offsets.insert(message_id.clone(), offset);
+      self.uncommitted.insert(
+        offset,
+        UncommittedMessage {
+          message_id,
+          receipt_handle,
+        },
+      );
+
+      let envelope = match base64_engine::STANDARD
+        .decode(message.body.unwrap_or_default())
+        .map_err(|e| e.to_string())
+        .and_then(|v| bincode::deserialize::<RecordEnvelope>(&v).map_err(|e| e.to_string()))
+      {
+        Ok(envelope) => envelope,
+        Err(e) => {
+          warn!("Skipping SQS message with invalid body: {}", e);
+          continue;
+        }
+      };
+      let header_values = RecordHeaderValues::parse(
+        envelope
+          .headers
+          .iter()
+          .map(|(k, v)| (k.as_str(), v.as_slice())),
+      );
+      if !header_values.matches_tenant(tenant) {
+        continue;
+      }
+      let sent_at = message
+        .attributes
+        .as_ref()
+        .and_then(|v| v.get(SENT_TIMESTAMP_ATTRIBUTE))
+        .and_then(|v| v.parse::<i64>().ok());
+      let submitted_at = header_values.submitted_at.or(sent_at);
+      self.buffer.push_back(ConsumedRecord {
+        data: envelope.data,
+        request_threshold: header_values.request_threshold,
+        channel_name: header_values.channel_name,
+        epoch: header_values.epoch,
+        client_version: header_values.client_version,
+        topic: None,
+        partition: Some(partition),
+        offset: Some(offset),
+        submitted_at: submitted_at.and_then(datetime_from_unix_millis),
+        timestamp: sent_at.and_then(datetime_from_unix_millis),
+      });
+    }
+  }
+
+  /// Returns the receipt handles of the uncommitted messages up to and including the offset.
+  fn receipt_handles(&self, offset: i64) -> Vec<String> {
+    self
+      .uncommitted
+      .range(..=offset)
+      .map(|(_, v)| v.receipt_handle.clone())
+      .collect()
+  }
+
+  fn mark_committed(&mut self, offset: i64) {
+    let remaining = self.uncommitted.split_off(&(offset + 1));
+    for (_, committed) in std::mem::replace(&mut self.uncommitted, remaining) {
+      self.uncommitted_offsets.remove(&committed.message_id);
+    }
+    self.committed_offset = Some(self.committed_offset.unwrap_or_default().max(offset + 1));
+  }
+}
+
+type ReceiveTask = JoinHandle<Result<Vec<Message>, SqsError>>;
+
+pub struct SqsRecordStream {
+  client: Arc<QueueClient>,
+  queue_name: String,
+  partition: i32,
+  tenant: Option<String>,
+  enable_producer: bool,
+  consumer_state: Arc<Mutex<ConsumerState>>,
+  /// Also prevents concurrent consumption
+  receive: AsyncMutex<Option<ReceiveTask>>,
+  visibility_extension: Mutex<Option<JoinHandle<()>>>,
+  receive_batch_size: usize,
+  wait_time: Duration,
+  visibility_timeout: Duration,
+  producer_queues: RwLock<Vec<ProducerQueue>>,
+  send_limit: Option<Arc<Semaphore>>,
+  record_headers: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+impl SqsRecordStream {
+  pub fn new(stream_config: KafkaRecordStreamConfig, options: RecordStreamOptions) -> Self {
+    let receive_batch_size = parse_env_var::<usize>(
+      SQS_RECEIVE_BATCH_SIZE_ENV_KEY,
+      DEFAULT_SQS_RECEIVE_BATCH_SIZE,
+    );
+    assert!(
+      (1..=MAX_BATCH_ENTRIES).contains(&receive_batch_size),
+      "{} must be between 1 and {}",
+      SQS_RECEIVE_BATCH_SIZE_ENV_KEY,
+      MAX_BATCH_ENTRIES
+    );
+    let wait_time_secs =
+      parse_env_var::<u64>(SQS_WAIT_TIME_SECS_ENV_KEY, DEFAULT_SQS_WAIT_TIME_SECS);
+    assert!(
+      wait_time_secs <= MAX_WAIT_TIME_SECS,
+      "{} must be at most {}",
+      SQS_WAIT_TIME_SECS_ENV_KEY,
+      MAX_WAIT_TIME_SECS
+    );
+    Self {
+      client: Arc::new(QueueClient::from_env()),
+      queue_name: tenant_scoped_name(&stream_config.topic, stream_config.tenant.as_deref()),
+      partition: NEXT_CONSUMER_PARTITION.fetch_add(1, Ordering::Relaxed),
+      tenant: stream_config.tenant.clone(),
+      enable_producer: stream_config.enable_producer,
+      consumer_state: Arc::new(Mutex::new(ConsumerState::default())),
+      receive: AsyncMutex::new(None),
+      visibility_extension: Mutex::new(None),
+      receive_batch_size,
+      wait_time: Duration::from_secs(wait_time_secs),
+      visibility_timeout: Duration::from_secs(parse_env_var(
+        SQS_VISIBILITY_TIMEOUT_SECS_ENV_KEY,
+        DEFAULT_SQS_VISIBILITY_TIMEOUT_SECS,
+      )),
+      producer_queues: RwLock::new(Vec::new()),
+      send_limit: options.send_limit,
+      record_headers: Arc::new(options.record_headers),
+    }
+  }
+
+  fn envelope(
+    &self,
+    record: &[u8],
+    mut header_values: RecordHeaderValues,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> RecordEnvelope {
+    header_values.tenant = self.tenant.clone();
+    let headers = header_values
+      .headers()
+      .into_iter()
+      .chain(extra_headers.iter().cloned())
+      .map(|(k, v)| (k.to_string(), v))
+      .chain(self.record_headers.iter().cloned())
+      .collect();
+    RecordEnvelope {
+      data: record.to_vec(),
+      headers,
+    }
+  }
+
+  /// Verifies that the queue exists, and starts extending the visibility timeout
+  /// of the uncommitted messages.
+  async fn init_consumer(&self) -> Result<(), SqsError> {
+    self.client.queue_url(&self.queue_name).await?;
+    let client = self.client.clone();
+    let queue_name = self.queue_name.clone();
+    let consumer_state = Arc::downgrade(&self.consumer_state);
+    let visibility_timeout = self.visibility_timeout;
+    let handle = tokio::spawn(async move {
+      loop {
+        sleep(visibility_timeout / 2).await;
+        let Some(consumer_state) = consumer_state.upgrade() else {
+          return;
+        };
+        let receipt_handles = consumer_state.lock().unwrap().receipt_handles(i64::MAX);
+        if let Err(e) = client
+          .update_messages(&queue_name, &receipt_handles, Some(visibility_timeout))
+          .await
+        {
+          warn!("Failed to extend SQS visibility timeouts: {}", e);
+        }
+      }
+    });
+    *self.visibility_extension.lock().unwrap() = Some(handle);
+    let mut state = self.consumer_state.lock().unwrap();
+    state.initialized = true;
+    state.committed_offset = Some(0);
+    Ok(())
+  }
+
+  /// Receives messages in a separate task, so that received messages are not lost
+  /// if consumption is cancelled.
+  fn spawn_receive(&self) -> ReceiveTask {
+    let client = self.client.clone();
+    let queue_name = self.queue_name.clone();
+    let receive_batch_size = self.receive_batch_size;
+    let wait_time = self.wait_time;
+    let visibility_timeout = self.visibility_timeout;
+    tokio::spawn(async move {
+      client
+        .receive_messages(
+          &queue_name,
+          receive_batch_size,
+          wait_time,
+          visibility_timeout,
+        )
+        .await
+    })
+  }
+
+  fn pop_record(&self) -> Option<ConsumedRecord> {
+    let mut state = self.consumer_state.lock().unwrap();
+    let record = state.buffer.pop_front()?;
+    state.consumed_offset = record.offset;
+    Some(record)
+  }
+
+  /// Deletes the received messages up to and including the offset.
+  async fn delete(&self, offset: i64) -> Result<(), SqsError> {
+    let receipt_handles = self.consumer_state.lock().unwrap().receipt_handles(offset);
+    self
+      .client
+      .update_messages(&self.queue_name, &receipt_handles, None)
+      .await?;
+    self.consumer_state.lock().unwrap().mark_committed(offset);
+    Ok(())
+  }
+}
+
+impl Drop for SqsRecordStream {
+  fn drop(&mut self) {
+    if let Some(handle) = self.visibility_extension.get_mut().unwrap().take() {
+      handle.abort();
+    }
+    if let Some(task) = self.receive.get_mut().take() {
+      task.abort();
+    }
+  }
+}
+
+#[async_trait]
+impl RecordStream for SqsRecordStream {
+  async fn check_connection(&self) -> Result<(), RecordStreamError> {
+    self.client.queue_url(&self.queue_name).await?;
+    Ok(())
+  }
+
+  fn has_assigned_partitions(&self) -> Result<bool, RecordStreamError> {
+    Ok(self.consumer_state.lock().unwrap().initialized)
+  }
+
+  fn assigned_partitions(&self) -> Result<Vec<i32>, RecordStreamError> {
+    Ok(match self.consumer_state.lock().unwrap().initialized {
+      true => vec![self.partition],
+      false => Vec::new(),
+    })
+  }
+
+  fn committed_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(
+      state
+        .committed_offset
+        .map(|v| (self.partition, v))
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  /// Only includes the messages that have been received from the queue.
+  fn end_offsets(&self) -> Result<HashMap<i32, i64>, RecordStreamError> {
+    let state = self.consumer_state.lock().unwrap();
+    Ok(HashMap::from([(self.partition, state.next_offset)]))
+  }
+
+  async fn produce_with_headers(
+    &self,
+    record: &[u8],
+    _key: Option<&str>,
+    request_threshold: Option<usize>,
+    channel_name: Option<&str>,
+    epoch: Option<u8>,
+    extra_headers: &[(&str, Vec<u8>)],
+  ) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "SQS producer not enabled");
+    let envelope = self.envelope(
+      record,
+      RecordHeaderValues {
+        request_threshold,
+        channel_name: channel_name.map(|v| v.to_string()),
+        epoch,
+        ..Default::default()
+      },
+      extra_headers,
+    );
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    self
+      .client
+      .send_message(&self.queue_name, &envelope)
+      .await?;
+    Ok(())
+  }
+
+  /// Sends the records in batches of up to 10 records.
+  async fn produce_batch(&self, records: &[BatchRecord<'_>]) -> Result<(), RecordStreamError> {
+    assert!(self.enable_producer, "SQS producer not enabled");
+    let bodies = records
+      .iter()
+      .map(|v| {
+        self
+          .envelope(
+            v.data,
+            RecordHeaderValues {
+              request_threshold: v.request_threshold,
+              channel_name: v.channel_name.map(|v| v.to_string()),
+              epoch: v.epoch,
+              ..Default::default()
+            },
+            &[],
+          )
+          .message_body()
+      })
+      .collect::<Result<Vec<_>, SqsError>>()?;
+    let _permit = acquire_send_permit(self.send_limit.as_ref()).await;
+    self.client.send_messages(&self.queue_name, bodies).await?;
+    Ok(())
+  }
+
+  async fn init_producer_queues(&self) {
+    assert!(self.enable_producer, "SQS producer not enabled");
+    let task_count = parse_env_var::<usize>(
+      SQS_PRODUCE_QUEUE_TASK_COUNT_ENV_KEY,
+      DEFAULT_SQS_PRODUCE_QUEUE_TASK_COUNT,
+    );
+    let header_values = RecordHeaderValues {
+      tenant: self.tenant.clone(),
+      ..Default::default()
+    };
+    let headers: Arc<Vec<_>> = Arc::new(
+      header_values
+        .headers()
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .chain(self.record_headers.iter().cloned())
+        .collect(),
+    );
+    let mut producer_queues = self.producer_queues.write().await;
+    for _ in 0..task_count {
+      let (tx, mut rx) = unbounded_channel::<ProducerQueueItem>();
+      let client = self.client.clone();
+      let queue_name = self.queue_name.clone();
+      let send_limit = self.send_limit.clone();
+      let headers = headers.clone();
+      let handle = tokio::spawn(async move {
+        let to_body = |(data, _): ProducerQueueItem| {
+          RecordEnvelope {
+            data,
+            headers: headers.as_ref().clone(),
+          }
+          .message_body()
+        };
+        // Queued records are sent in batches of the records available
+        while let Some(item) = rx.recv().await {
+          let mut bodies = vec![to_body(item)?];
+          while bodies.len() < MAX_BATCH_ENTRIES {
+            match rx.try_recv() {
+              Ok(item) => bodies.push(to_body(item)?),
+              Err(_) => break,
+            }
+          }
+          let _permit = acquire_send_permit(send_limit.as_ref()).await;
+          client.send_messages(&queue_name, bodies).await?;
+        }
+        Ok(())
+      });
+      producer_queues.push((handle, tx));
+    }
+  }
+
+  async fn queue_produce(
+    &self,
+    record: Vec<u8>,
+    key: Option<String>,
+  ) -> Result<(), RecordStreamError> {
+    let producer_queues = self.producer_queues.read().await;
+    let (_, tx) = producer_queues.choose(&mut thread_rng()).unwrap();
+    send_to_producer_queue(tx, &self.queue_name, (record, key))
+  }
+
+  async fn join_produce_queues(&self) -> Result<(), RecordStreamError> {
+    let mut producer_queues = self.producer_queues.write().await;
+    try_join_all(
+      producer_queues
+        .drain(..)
+        .map(|(handle, _)| handle)
+        .collect::<Vec<_>>(),
+    )
+    .await?
+    .into_iter()
+    .collect::<Result<Vec<()>, RecordStreamError>>()?;
+    Ok(())
+  }
+
+  /// Cancel safe, since the receive task is kept until the next call.
+  async fn consume(&self) -> Result<ConsumedRecord, RecordStreamError> {
+    let mut receive = self.receive.lock().await;
+    loop {
+      if let Some(record) = self.pop_record() {
+        return Ok(record);
+      }
+      let initialized = self.consumer_state.lock().unwrap().initialized;
+      if !initialized {
+        self.init_consumer().await?;
+      }
+      let task = receive.get_or_insert_with(|| self.spawn_receive());
+      let result = task.await;
+      *receive = None;
+      let messages = result??;
+      if messages.is_empty() {
+        if self.wait_time.is_zero() {
+          sleep(EMPTY_RECEIVE_BACKOFF).await;
+        }
+        continue;
+      }
+      self.consumer_state.lock().unwrap().buffer_messages(
+        messages,
+        self.partition,
+        self.tenant.as_deref(),
+      );
+    }
+  }
+
+  async fn commit_last_consume(&self) -> Result<(), RecordStreamError> {
+    let consumed_offset = self.consumer_state.lock().unwrap().consumed_offset;
+    if let Some(offset) = consumed_offset {
+      self.delete(offset).await?;
+    }
+    Ok(())
+  }
+
+  async fn commit_offsets(&self, offsets: &[(i32, i64)]) -> Result<(), RecordStreamError> {
+    for (partition, offset) in offsets {
+      if *partition == self.partition {
+        self.delete(*offset).await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Makes the uncommitted messages visible again, so that other consumers
+  /// receive them without waiting for their visibility timeout.
+  async fn shutdown(&self, commit: bool) -> Result<(), RecordStreamError> {
+    if commit {
+      self.commit_last_consume().await?;
+    }
+    if let Some(handle) = self.visibility_extension.lock().unwrap().take() {
+      handle.abort();
+    }
+    let receipt_handles = self
+      .consumer_state
+      .lock()
+      .unwrap()
+      .receipt_handles(i64::MAX);
+    self
+      .client
+      .update_messages(&self.queue_name, &receipt_handles, Some(Duration::ZERO))
+      .await?;
+    if self.enable_producer {
+      self.join_produce_queues().await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record_stream::datetime_to_unix_millis;
+
+  fn message(message_id: &str, receipt_handle: &str, data: &[u8]) -> Message {
+    let envelope = RecordEnvelope {
+      data: data.to_vec(),
+      headers: RecordHeaderValues {
+        channel_name: Some("typical".to_string()),
+        epoch: Some(3),
+        ..Default::default()
+      }
+      .headers()
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), v))
+      .collect(),
+    };
+    Message {
+      message_id: Some(message_id.to_string()),
+      receipt_handle: Some(receipt_handle.to_string()),
+      body: Some(envelope.message_body().unwrap()),
+      attributes: Some(HashMap::from([(
+        SENT_TIMESTAMP_ATTRIBUTE.to_string(),
+        "1682946000123".to_string(),
+      )])),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn batch_entry_failures() {
+    assert!(check_batch_result(Vec::new()).is_ok());
+    let err = check_batch_result(vec![BatchResultErrorEntry {
+      id: "0".to_string(),
+      code: "InternalError".to_string(),
+      message: None,
+      sender_fault: false,
+    }])
+    .unwrap_err();
+    assert!(matches!(err, SqsError::BatchEntry { code, .. } if code == "InternalError"));
+  }
+
+  #[test]
+  fn buffer_and_commit_messages() {
+    let mut state = ConsumerState::default();
+    state.buffer_messages(
+      vec![
+        message("1", "handle-1", b"first"),
+        message("2", "handle-2", b"second"),
+        message("3", "handle-3", b"third"),
+      ],
+      2,
+      None,
+    );
+    // Redelivery of an uncommitted message updates its receipt handle
+    state.buffer_messages(vec![message("2", "handle-2b", b"second")], 2, None);
+    assert_eq!(state.next_offset, 3);
+    assert_eq!(state.buffer.len(), 3);
+
+    let record = state.buffer.pop_front().unwrap();
+    assert_eq!(record.data, b"first");
+    assert_eq!(record.partition, Some(2));
+    assert_eq!(record.offset, Some(0));
+    assert_eq!(record.channel_name.as_deref(), Some("typical"));
+    assert_eq!(record.epoch, Some(3));
+    assert_eq!(
+      record.timestamp.map(datetime_to_unix_millis),
+      Some(1_682_946_000_123)
+    );
+
+    assert_eq!(state.receipt_handles(1), vec!["handle-1", "handle-2b"]);
+    state.mark_committed(1);
+    assert_eq!(state.committed_offset, Some(2));
+    assert_eq!(state.receipt_handles(i64::MAX), vec!["handle-3"]);
+  }
+
+  #[test]
+  fn batches_within_limits() {
+    let batches = send_batches(vec!["a".to_string(); 23]);
+    assert_eq!(
+      batches.iter().map(|v| v.len()).collect::<Vec<_>>(),
+      vec![10, 10, 3]
+    );
+    assert_eq!(batches[1][9].id, "9");
+
+    let large = "a".repeat(100 * 1024);
+    let batches = send_batches(vec![large.clone(), large.clone(), large, "a".to_string()]);
+    assert_eq!(
+      batches.iter().map(|v| v.len()).collect::<Vec<_>>(),
+      vec![2, 2]
+    );
+    assert!(send_batches(Vec::new()).is_empty());
+  }
+}
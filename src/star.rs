@@ -1,12 +1,20 @@
 use derive_more::{Display, Error, From};
 use rand::seq::IteratorRandom;
 use rand::thread_rng;
+use serde::Deserialize;
 use star_constellation::api::{
   key_recover, recover, NestedMessage, PartialMeasurement, SerializableNestedMessage,
 };
 use star_constellation::Error as ConstellationError;
+
+use crate::encryption::ShareEncryptionError;
+use crate::util::parse_env_var;
+use bincode::Options;
 use std::cmp::min;
-use std::str::{from_utf8, Utf8Error};
+use std::str::{from_utf8, FromStr, Utf8Error};
+
+const PENDING_MSG_FORMAT_ENV_KEY: &str = "PENDING_MSG_FORMAT";
+const DEFAULT_PENDING_MSG_FORMAT: &str = "bincode";
 
 #[derive(Error, From, Display, Debug)]
 pub enum AppSTARError {
@@ -18,6 +26,18 @@ pub enum AppSTARError {
   Delimiter,
   #[display(fmt = "failed to recover messages")]
   Recovery(ConstellationError),
+  #[display(fmt = "failed to decrypt stored share: {}", _0)]
+  ShareEncryption(ShareEncryptionError),
+  #[display(fmt = "failed to encode cbor: {}", _0)]
+  CborEncode(ciborium::ser::Error<std::io::Error>),
+  #[display(fmt = "failed to decode cbor: {}", _0)]
+  CborDecode(ciborium::de::Error<std::io::Error>),
+  #[display(fmt = "failed to encode bincode v2: {}", _0)]
+  BincodeV2Encode(bincode2::error::EncodeError),
+  #[display(fmt = "failed to decode bincode v2: {}", _0)]
+  BincodeV2Decode(bincode2::error::DecodeError),
+  #[display(fmt = "unknown stored message format {}", _0)]
+  UnknownMessageFormat(#[error(not(source))] i16),
 }
 
 pub struct MsgRecoveryInfo {
@@ -31,13 +51,116 @@ pub fn parse_message(bincode_msg: &[u8]) -> Result<NestedMessage, AppSTARError>
   Ok(NestedMessage::try_from(smsg)?)
 }
 
+/// Leading fields of a serialized message, borrowed from the serialized bytes.
+#[derive(Deserialize)]
+struct MessageHeader<'a> {
+  epoch: u8,
+  #[serde(borrow)]
+  unencrypted_layer: LayerHeader<'a>,
+}
+
+#[derive(Deserialize)]
+struct LayerHeader<'a> {
+  _ciphertext: &'a [u8],
+  _share: &'a [u8],
+  tag: &'a [u8],
+}
+
+/// Reads the epoch and tag of a serialized message, without parsing the rest of the message.
+pub fn parse_message_epoch_tag(bincode_msg: &[u8]) -> Result<(u8, &[u8]), AppSTARError> {
+  let header: MessageHeader = bincode::deserialize(bincode_msg)?;
+  Ok((header.epoch, header.unencrypted_layer.tag))
+}
+
 pub fn serialize_message_bincode(message: NestedMessage) -> Result<Vec<u8>, AppSTARError> {
   let smsg = SerializableNestedMessage::from(message);
   Ok(bincode::serialize(&smsg)?)
 }
 
+/// Serialization format of messages stored in the pending message table. Each row
+/// records the format tag of its message, so that the format used for new rows
+/// can be changed without rewriting existing rows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoredMessageFormat {
+  /// Same encoding as submitted messages
+  Bincode,
+  /// Bincode with variable-length integers, which shortens length prefixes
+  CompactBincode,
+  /// CBOR, which is self-describing and readable by other tools
+  Cbor,
+  /// Bincode 2 with the standard configuration (variable-length integers)
+  BincodeV2,
+}
+
+impl FromStr for StoredMessageFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "bincode" => Ok(Self::Bincode),
+      "compact-bincode" => Ok(Self::CompactBincode),
+      "cbor" => Ok(Self::Cbor),
+      "bincode-v2" => Ok(Self::BincodeV2),
+      _ => Err(format!("unknown stored message format '{}'", s)),
+    }
+  }
+}
+
+impl StoredMessageFormat {
+  /// Returns the format to use for new pending messages.
+  pub fn from_env() -> Self {
+    parse_env_var(PENDING_MSG_FORMAT_ENV_KEY, DEFAULT_PENDING_MSG_FORMAT)
+  }
+
+  pub fn from_tag(tag: i16) -> Result<Self, AppSTARError> {
+    match tag {
+      0 => Ok(Self::Bincode),
+      1 => Ok(Self::CompactBincode),
+      2 => Ok(Self::Cbor),
+      3 => Ok(Self::BincodeV2),
+      _ => Err(AppSTARError::UnknownMessageFormat(tag)),
+    }
+  }
+
+  pub fn tag(&self) -> i16 {
+    match self {
+      Self::Bincode => 0,
+      Self::CompactBincode => 1,
+      Self::Cbor => 2,
+      Self::BincodeV2 => 3,
+    }
+  }
+
+  pub fn serialize(&self, message: NestedMessage) -> Result<Vec<u8>, AppSTARError> {
+    let smsg = SerializableNestedMessage::from(message);
+    match self {
+      Self::Bincode => Ok(bincode::serialize(&smsg)?),
+      Self::CompactBincode => Ok(bincode::options().serialize(&smsg)?),
+      Self::Cbor => {
+        let mut data = Vec::new();
+        ciborium::into_writer(&smsg, &mut data)?;
+        Ok(data)
+      }
+      Self::BincodeV2 => Ok(bincode2::serde::encode_to_vec(
+        &smsg,
+        bincode2::config::standard(),
+      )?),
+    }
+  }
+
+  pub fn deserialize(&self, data: &[u8]) -> Result<NestedMessage, AppSTARError> {
+    let smsg: SerializableNestedMessage = match self {
+      Self::Bincode => return parse_message(data),
+      Self::CompactBincode => bincode::options().deserialize(data)?,
+      Self::Cbor => ciborium::from_reader(data)?,
+      Self::BincodeV2 => bincode2::serde::decode_from_slice(data, bincode2::config::standard())?.0,
+    };
+    Ok(NestedMessage::try_from(smsg)?)
+  }
+}
+
 fn get_measurement_contents(m: &PartialMeasurement) -> Result<(String, String), AppSTARError> {
-  let mstr = from_utf8(m.measurement.0.get(0).unwrap().as_slice())?;
+  let mstr = from_utf8(m.measurement.0.first().unwrap().as_slice())?;
   let mstr_spl: Vec<&str> = mstr.split('|').collect();
   if mstr_spl.len() != 2 {
     Err(AppSTARError::Delimiter)
@@ -110,7 +233,7 @@ pub fn recover_msgs(
 
   match pms.iter().find(|v| v.is_ok()) {
     None => {
-      let first_error = pms.iter().next().unwrap().clone().unwrap_err();
+      let first_error = pms.first().unwrap().clone().unwrap_err();
       for result in pms {
         if let Err(e) = result {
           debug!("recovery failure: {}", e);
@@ -126,6 +249,27 @@ pub fn recover_msgs(
   }
 }
 
+/// Recovers the measurement of the current layer from the first message that
+/// can be decrypted, without decrypting the other messages or the next layers.
+pub fn recover_layer_measurement(
+  messages: &[NestedMessage],
+  key: &[u8],
+) -> Result<(String, String), AppSTARError> {
+  let mut first_error = None;
+  for msg in messages {
+    match recover(&[&msg.unencrypted_layer], key).remove(0) {
+      Ok(measurement) => return get_measurement_contents(&measurement),
+      Err(e) => {
+        debug!("recovery failure: {}", e);
+        first_error.get_or_insert(e);
+      }
+    }
+  }
+  Err(AppSTARError::Recovery(
+    first_error.unwrap_or(ConstellationError::ShareRecovery),
+  ))
+}
+
 #[cfg(test)]
 pub mod tests {
   use super::*;
@@ -133,7 +277,6 @@ pub mod tests {
   use star_constellation::api::client;
   use star_constellation::randomness::testing::LocalFetcher as RandomnessFetcher;
   use std::env;
-  use std::str::FromStr;
 
   pub fn generate_test_message(
     epoch: u8,
@@ -159,4 +302,51 @@ pub mod tests {
       bincode::deserialize(&serialized_msg_bytes).unwrap();
     NestedMessage::try_from(serialized_msg).unwrap()
   }
+
+  #[test]
+  fn layer_measurement_recovery() {
+    let fetcher = RandomnessFetcher::new();
+    let msgs: Vec<_> = (0..50)
+      .map(|_| generate_test_message(2, &[b"a|1".to_vec(), b"b|2".to_vec()], &fetcher))
+      .collect();
+    let key = recover_key(&msgs, 2, 50).unwrap();
+    assert_eq!(
+      recover_layer_measurement(&msgs, &key).unwrap(),
+      ("a".to_string(), "1".to_string())
+    );
+    assert!(recover_layer_measurement(&msgs[..1], &[0; 16]).is_err());
+  }
+
+  #[test]
+  fn stored_message_formats() {
+    let fetcher = RandomnessFetcher::new();
+    let msg = generate_test_message(2, &[b"a|1".to_vec(), b"b|2".to_vec()], &fetcher);
+    let bincode_msg = serialize_message_bincode(msg.clone()).unwrap();
+    for format in [
+      StoredMessageFormat::Bincode,
+      StoredMessageFormat::CompactBincode,
+      StoredMessageFormat::Cbor,
+      StoredMessageFormat::BincodeV2,
+    ] {
+      let format = StoredMessageFormat::from_tag(format.tag()).unwrap();
+      let stored = format.serialize(msg.clone()).unwrap();
+      if matches!(
+        format,
+        StoredMessageFormat::CompactBincode | StoredMessageFormat::BincodeV2
+      ) {
+        assert!(stored.len() < bincode_msg.len());
+      }
+      let parsed = format.deserialize(&stored).unwrap();
+      assert_eq!(serialize_message_bincode(parsed).unwrap(), bincode_msg);
+    }
+    assert!(StoredMessageFormat::from_tag(9).is_err());
+    assert_eq!(
+      "compact-bincode".parse::<StoredMessageFormat>(),
+      Ok(StoredMessageFormat::CompactBincode)
+    );
+    assert_eq!(
+      "bincode-v2".parse::<StoredMessageFormat>(),
+      Ok(StoredMessageFormat::BincodeV2)
+    );
+  }
 }
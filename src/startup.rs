@@ -0,0 +1,164 @@
+//! Startup checks for external dependencies. Modes that accept data wait until the
+//! systems used to persist the data are reachable, so that the process does not accept
+//! traffic that it cannot persist. Checks are retried until the startup timeout of the mode.
+//! The aggregator also waits until its database accepts writes, before consuming messages.
+
+use crate::lake::DataLake;
+use crate::models::{check_database_writable, DBConnectionType};
+use crate::pipeline_io::{observe, Endpoint};
+use crate::prometheus::IoOperation;
+use crate::record_stream::DynRecordStream;
+use crate::util::parse_env_var;
+use derive_more::{Display, Error};
+use std::fmt::Display as FmtDisplay;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const SERVER_STARTUP_TIMEOUT_SECS_ENV_KEY: &str = "SERVER_STARTUP_TIMEOUT_SECS";
+const LAKE_SINK_STARTUP_TIMEOUT_SECS_ENV_KEY: &str = "LAKE_SINK_STARTUP_TIMEOUT_SECS";
+const AGGREGATOR_STARTUP_TIMEOUT_SECS_ENV_KEY: &str = "AGGREGATOR_STARTUP_TIMEOUT_SECS";
+const DEFAULT_STARTUP_TIMEOUT_SECS: &str = "60";
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Error, Display, Debug)]
+#[display(
+  fmt = "{} was not ready within {}s: {}",
+  name,
+  "timeout.as_secs()",
+  last_error
+)]
+pub struct StartupError {
+  name: String,
+  timeout: Duration,
+  last_error: String,
+}
+
+/// Runs the check until it succeeds, or until the timeout is reached.
+pub async fn wait_until_ready<F, Fut, E>(
+  name: &str,
+  timeout: Duration,
+  retry_interval: Duration,
+  mut check: F,
+) -> Result<(), StartupError>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<(), E>>,
+  E: FmtDisplay,
+{
+  let deadline = Instant::now() + timeout;
+  loop {
+    let last_error = match check().await {
+      Ok(()) => {
+        info!("{} is ready", name);
+        return Ok(());
+      }
+      Err(e) => e.to_string(),
+    };
+    if Instant::now() + retry_interval > deadline {
+      return Err(StartupError {
+        name: name.to_string(),
+        timeout,
+        last_error,
+      });
+    }
+    warn!("{} is not ready, retrying: {}", name, last_error);
+    sleep(retry_interval).await;
+  }
+}
+
+/// Runs the check of the endpoint until it succeeds, or until the timeout is reached.
+async fn wait_for_endpoint<E: Endpoint + ?Sized>(
+  name: &str,
+  timeout: Duration,
+  endpoint: &E,
+) -> Result<(), StartupError> {
+  wait_until_ready(name, timeout, STARTUP_RETRY_INTERVAL, || {
+    observe(
+      endpoint.endpoint_name(),
+      IoOperation::Check,
+      endpoint.check(),
+    )
+  })
+  .await
+}
+
+/// Waits until the backend of each named stream is reachable.
+pub async fn wait_for_record_streams<'a>(
+  streams: impl IntoIterator<Item = (String, &'a DynRecordStream)>,
+) -> Result<(), StartupError> {
+  let timeout = Duration::from_secs(parse_env_var(
+    SERVER_STARTUP_TIMEOUT_SECS_ENV_KEY,
+    DEFAULT_STARTUP_TIMEOUT_SECS,
+  ));
+  for (name, stream) in streams {
+    wait_for_endpoint(&name, timeout, stream).await?;
+  }
+  Ok(())
+}
+
+/// Waits until the output bucket of the lake is accessible.
+pub async fn wait_for_lake(lake: &DataLake) -> Result<(), StartupError> {
+  let timeout = Duration::from_secs(parse_env_var(
+    LAKE_SINK_STARTUP_TIMEOUT_SECS_ENV_KEY,
+    DEFAULT_STARTUP_TIMEOUT_SECS,
+  ));
+  wait_for_endpoint("Data lake", timeout, lake).await
+}
+
+/// Waits until the database of the channel is reachable and writable.
+pub async fn wait_for_database(
+  channel_name: &str,
+  tenant: Option<&str>,
+) -> Result<(), StartupError> {
+  let timeout = Duration::from_secs(parse_env_var(
+    AGGREGATOR_STARTUP_TIMEOUT_SECS_ENV_KEY,
+    DEFAULT_STARTUP_TIMEOUT_SECS,
+  ));
+  wait_until_ready("Database", timeout, STARTUP_RETRY_INTERVAL, || {
+    check_database_writable(DBConnectionType::Normal {
+      channel_name,
+      tenant,
+    })
+  })
+  .await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn retry_checks() {
+    let mut attempts = 0;
+    wait_until_ready(
+      "test dependency",
+      Duration::from_secs(5),
+      Duration::from_millis(10),
+      || {
+        attempts += 1;
+        let result = match attempts {
+          1 | 2 => Err("unreachable"),
+          _ => Ok(()),
+        };
+        async move { result }
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(attempts, 3);
+
+    let err = wait_until_ready(
+      "test dependency",
+      Duration::from_millis(50),
+      Duration::from_millis(10),
+      || async { Err::<(), _>("unreachable") },
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "test dependency was not ready within 0s: unreachable"
+    );
+  }
+}
@@ -0,0 +1,179 @@
+//! End-to-end budget for submissions. By default, a submission is held until the record
+//! stream confirms delivery of the message, which may take up to the send timeout of the
+//! producer (12 seconds for Kafka) if the brokers are slow or unavailable. If
+//! `SUBMISSION_BUDGET_MS` is set, the server waits for the delivery for at most the budget.
+//!
+//! When the budget is exceeded, the delivery continues in the background if the local
+//! queue is enabled via `SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES` and has room for the message,
+//! and the submission is accepted as usual. Otherwise, the submission is rejected with
+//! a 503 status, so that the client retries later. Queued messages are kept in memory,
+//! so they are lost if the server stops before they are delivered. A rejected message
+//! may still be delivered, since it cannot be removed from the producer once enqueued.
+
+use crate::record_stream::RecordStreamError;
+use crate::util::parse_env_var;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+const SUBMISSION_BUDGET_MS_ENV_KEY: &str = "SUBMISSION_BUDGET_MS";
+/// Disables the budget
+const DEFAULT_SUBMISSION_BUDGET_MS: &str = "0";
+const SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES_ENV_KEY: &str = "SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES";
+/// Disables the local queue
+const DEFAULT_SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES: &str = "0";
+
+#[derive(Debug, PartialEq)]
+pub enum BudgetOutcome {
+  /// The message was delivered within the budget
+  Delivered,
+  /// The budget was exceeded, and the delivery continues in the local queue
+  Queued,
+  /// The budget was exceeded, and the local queue is disabled or full
+  Exceeded,
+}
+
+pub struct SubmissionBudget {
+  budget: Option<Duration>,
+  /// Permits for messages in the local queue, if enabled
+  local_queue: Option<Arc<Semaphore>>,
+}
+
+impl SubmissionBudget {
+  pub fn new(budget: Option<Duration>, local_queue_max_messages: usize) -> Self {
+    Self {
+      budget,
+      local_queue: (local_queue_max_messages > 0)
+        .then(|| Arc::new(Semaphore::new(local_queue_max_messages))),
+    }
+  }
+
+  pub fn from_env() -> Self {
+    let budget_ms =
+      parse_env_var::<u64>(SUBMISSION_BUDGET_MS_ENV_KEY, DEFAULT_SUBMISSION_BUDGET_MS);
+    let local_queue_max_messages = parse_env_var::<usize>(
+      SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES_ENV_KEY,
+      DEFAULT_SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES,
+    );
+    if budget_ms == 0 && local_queue_max_messages > 0 {
+      warn!(
+        "{} is set without {}, the local queue is not used",
+        SUBMISSION_LOCAL_QUEUE_MAX_MESSAGES_ENV_KEY, SUBMISSION_BUDGET_MS_ENV_KEY
+      );
+    }
+    Self::new(
+      (budget_ms > 0).then(|| Duration::from_millis(budget_ms)),
+      local_queue_max_messages,
+    )
+  }
+
+  /// Waits for the delivery of a message within the budget. Delivery errors within
+  /// the budget are returned, errors of queued deliveries are only logged.
+  pub async fn deliver<F>(&self, delivery: F) -> Result<BudgetOutcome, RecordStreamError>
+  where
+    F: Future<Output = Result<(), RecordStreamError>> + Send + 'static,
+  {
+    let budget = match self.budget {
+      None => return delivery.await.map(|_| BudgetOutcome::Delivered),
+      Some(budget) => budget,
+    };
+    let mut handle = tokio::spawn(delivery);
+    if let Ok(result) = timeout(budget, &mut handle).await {
+      return result
+        .expect("delivery task should not panic")
+        .map(|_| BudgetOutcome::Delivered);
+    }
+    let permit = self
+      .local_queue
+      .as_ref()
+      .and_then(|v| v.clone().try_acquire_owned().ok());
+    match permit {
+      Some(permit) => {
+        tokio::spawn(async move {
+          if let Ok(Err(e)) = handle.await {
+            error!("Failed to push queued message: {}", e);
+          }
+          drop(permit);
+        });
+        Ok(BudgetOutcome::Queued)
+      }
+      None => {
+        handle.abort();
+        Ok(BudgetOutcome::Exceeded)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::sync::oneshot;
+  use tokio::time::sleep;
+
+  #[tokio::test]
+  async fn budget() {
+    let budget = SubmissionBudget::new(Some(Duration::from_millis(50)), 1);
+    assert_eq!(
+      budget.deliver(async { Ok(()) }).await.unwrap(),
+      BudgetOutcome::Delivered
+    );
+    assert!(budget
+      .deliver(async {
+        Err(RecordStreamError::TopicMissing {
+          topic: "p3a-star-enc".to_string(),
+        })
+      })
+      .await
+      .is_err());
+
+    let (tx, rx) = oneshot::channel();
+    let slow_delivery = async move {
+      sleep(Duration::from_millis(200)).await;
+      tx.send(()).unwrap();
+      Ok(())
+    };
+    assert_eq!(
+      budget.deliver(slow_delivery).await.unwrap(),
+      BudgetOutcome::Queued
+    );
+    // The local queue is full until the queued message is delivered
+    let never_delivered = sleep(Duration::from_secs(60));
+    assert_eq!(
+      budget
+        .deliver(async move {
+          never_delivered.await;
+          Ok(())
+        })
+        .await
+        .unwrap(),
+      BudgetOutcome::Exceeded
+    );
+    rx.await.unwrap();
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(
+      budget
+        .deliver(async {
+          sleep(Duration::from_millis(100)).await;
+          Ok(())
+        })
+        .await
+        .unwrap(),
+      BudgetOutcome::Queued
+    );
+
+    let unlimited = SubmissionBudget::new(None, 0);
+    assert_eq!(
+      unlimited
+        .deliver(async {
+          sleep(Duration::from_millis(100)).await;
+          Ok(())
+        })
+        .await
+        .unwrap(),
+      BudgetOutcome::Delivered
+    );
+  }
+}
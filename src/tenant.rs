@@ -0,0 +1,100 @@
+//! Multi-tenant isolation. When tenancy is enabled, each tenant uses separate
+//! Kafka topics and consumer groups (suffixed with the tenant name),
+//! a separate data lake prefix and a separate Postgres schema.
+//! The server resolves the tenant via the API key supplied in the request's
+//! `Authorization: Bearer <key>` header. Other components are run for a single
+//! tenant, and ignore records stamped with a different tenant header.
+
+use std::collections::HashMap;
+
+use crate::channel::get_data_channel_map_from_env;
+
+const TENANT_API_KEYS_ENV_KEY: &str = "TENANT_API_KEYS";
+const BEARER_PREFIX: &str = "Bearer ";
+
+pub struct TenantConfig {
+  /// API key mapped to tenant name
+  tenants_by_api_key: HashMap<String, String>,
+}
+
+impl TenantConfig {
+  /// Returns None if no tenants are configured, which indicates that
+  /// tenancy is disabled.
+  pub fn from_env() -> Option<Self> {
+    let tenant_api_keys = get_data_channel_map_from_env(TENANT_API_KEYS_ENV_KEY, "");
+    if tenant_api_keys.is_empty() {
+      return None;
+    }
+    let mut tenants_by_api_key = HashMap::new();
+    for (tenant, api_key) in tenant_api_keys {
+      assert!(
+        is_valid_tenant_name(&tenant),
+        "tenant name '{}' must only contain lowercase alphanumeric characters or underscores",
+        tenant
+      );
+      assert!(
+        tenants_by_api_key.insert(api_key, tenant).is_none(),
+        "each tenant must have a unique API key"
+      );
+    }
+    Some(Self { tenants_by_api_key })
+  }
+
+  pub fn tenant_names(&self) -> Vec<String> {
+    self.tenants_by_api_key.values().cloned().collect()
+  }
+
+  /// Resolves the tenant name from the value of an `Authorization` header.
+  pub fn resolve(&self, authorization: &str) -> Option<&str> {
+    let api_key = authorization.strip_prefix(BEARER_PREFIX)?.trim();
+    self.tenants_by_api_key.get(api_key).map(|v| v.as_str())
+  }
+}
+
+/// Tenant names are used in topic names, lake prefixes and database schema
+/// names, so they are restricted to a conservative character set.
+pub fn is_valid_tenant_name(tenant: &str) -> bool {
+  !tenant.is_empty()
+    && tenant
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+pub fn tenant_scoped_name(name: &str, tenant: Option<&str>) -> String {
+  match tenant {
+    Some(tenant) => format!("{}-{}", name, tenant),
+    None => name.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_tenant() {
+    let config = TenantConfig {
+      tenants_by_api_key: HashMap::from([
+        ("key1".to_string(), "team_a".to_string()),
+        ("key2".to_string(), "team_b".to_string()),
+      ]),
+    };
+    assert_eq!(config.resolve("Bearer key1"), Some("team_a"));
+    assert_eq!(config.resolve("Bearer key2"), Some("team_b"));
+    assert_eq!(config.resolve("Bearer key3"), None);
+    assert_eq!(config.resolve("key1"), None);
+  }
+
+  #[test]
+  fn tenant_names() {
+    assert!(is_valid_tenant_name("team_a1"));
+    assert!(!is_valid_tenant_name(""));
+    assert!(!is_valid_tenant_name("Team"));
+    assert!(!is_valid_tenant_name("team;drop"));
+    assert_eq!(tenant_scoped_name("p3a-star-enc", None), "p3a-star-enc");
+    assert_eq!(
+      tenant_scoped_name("p3a-star-enc", Some("team_a")),
+      "p3a-star-enc-team_a"
+    );
+  }
+}